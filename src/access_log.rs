@@ -0,0 +1,232 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::config::AccessLogConfig;
+
+// A handle to send access logs to a dedicated thread which writes them into
+// the file.
+//
+// Writing and rotating the file may block.  So, they are done outside the
+// request path.  Access logs are dropped when the queue is full.
+#[derive(Clone)]
+pub struct AccessLogWriter(mpsc::SyncSender<String>);
+
+impl AccessLogWriter {
+    const MAX_QUEUED_LINES: usize = 1000;
+
+    pub fn spawn(config: &AccessLogConfig) -> Self {
+        let file = AccessLogFile::new(config);
+        let (sender, receiver) = mpsc::sync_channel(Self::MAX_QUEUED_LINES);
+        // The thread stops when all handles are dropped.
+        std::thread::spawn(move || write_lines(file, receiver));
+        AccessLogWriter(sender)
+    }
+
+    pub fn write_line(&self, line: String) {
+        match self.0.try_send(line) {
+            Ok(_) => (),
+            Err(mpsc::TrySendError::Full(_)) =>
+                log::warn!("Dropped an access log: too many queued lines"),
+            Err(mpsc::TrySendError::Disconnected(_)) =>
+                log::error!("Dropped an access log: the writer has stopped"),
+        }
+    }
+}
+
+fn write_lines(mut file: AccessLogFile, receiver: mpsc::Receiver<String>) {
+    for line in receiver.iter() {
+        if let Err(err) = file.write_line(&line) {
+            log::error!("Failed to write an access log: {}", err);
+        }
+    }
+}
+
+// An access log file which is rotated by its size and age.
+//
+// When the file is rotated, `access.log` is renamed to `access.log.1`,
+// `access.log.1` is renamed to `access.log.2`, and so on.  Files beyond
+// `max-files` are removed.
+pub struct AccessLogFile {
+    path: PathBuf,
+    max_size: u64,
+    rotation_interval: Option<Duration>,
+    max_files: usize,
+    file: Option<File>,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl AccessLogFile {
+    pub fn new(config: &AccessLogConfig) -> Self {
+        let rotation_interval = if config.rotation_interval > 0 {
+            Some(Duration::from_millis(config.rotation_interval))
+        } else {
+            None
+        };
+        AccessLogFile {
+            path: PathBuf::from(&config.path),
+            max_size: config.max_size,
+            rotation_interval,
+            max_files: config.max_files,
+            file: None,
+            size: 0,
+            opened_at: Instant::now(),
+        }
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let data = format!("{}\n", line);
+        if self.file.is_none() {
+            self.open()?;
+        }
+        if self.needs_rotation(data.len() as u64) {
+            self.rotate()?;
+            self.open()?;
+        }
+        self.file.as_mut().unwrap().write_all(data.as_bytes())?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    fn needs_rotation(&self, len: u64) -> bool {
+        // A line larger than `max_size` is written into an empty file.
+        if self.max_size > 0 && self.size > 0 &&
+            self.size + len > self.max_size {
+            return true;
+        }
+        match self.rotation_interval {
+            Some(interval) => self.opened_at.elapsed() >= interval,
+            None => false,
+        }
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.opened_at = Instant::now();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        if self.max_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for n in (1..self.max_files).rev() {
+            let src = self.rotated_path(n);
+            if src.exists() {
+                fs::rename(src, self.rotated_path(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        log::info!("Rotated {}", self.path.display());
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = create_dir("size");
+        let mut log = AccessLogFile::new(&AccessLogConfig {
+            path: dir.join("access.log").to_str().unwrap().to_string(),
+            max_size: 9,
+            rotation_interval: 0,
+            max_files: 2,
+        });
+
+        log.write_line("1234").unwrap();
+        log.write_line("567").unwrap();
+        assert_eq!(read(&dir, "access.log"), "1234\n567\n");
+
+        log.write_line("89").unwrap();
+        assert_eq!(read(&dir, "access.log"), "89\n");
+        assert_eq!(read(&dir, "access.log.1"), "1234\n567\n");
+
+        log.write_line("abcdefghij").unwrap();
+        log.write_line("k").unwrap();
+        assert_eq!(read(&dir, "access.log"), "k\n");
+        assert_eq!(read(&dir, "access.log.1"), "abcdefghij\n");
+        assert_eq!(read(&dir, "access.log.2"), "89\n");
+
+        // The oldest file is removed.
+        log.write_line("l").unwrap();
+        log.write_line("mnopqrst").unwrap();
+        assert_eq!(read(&dir, "access.log"), "mnopqrst\n");
+        assert_eq!(read(&dir, "access.log.1"), "k\nl\n");
+        assert_eq!(read(&dir, "access.log.2"), "abcdefghij\n");
+        assert!(!dir.join("access.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_by_interval() {
+        let dir = create_dir("interval");
+        let mut log = AccessLogFile::new(&AccessLogConfig {
+            path: dir.join("access.log").to_str().unwrap().to_string(),
+            max_size: 0,
+            rotation_interval: 60_000,
+            max_files: 1,
+        });
+
+        log.write_line("1").unwrap();
+        log.write_line("2").unwrap();
+        assert_eq!(read(&dir, "access.log"), "1\n2\n");
+
+        log.opened_at = Instant::now() - Duration::from_secs(60);
+        log.write_line("3").unwrap();
+        assert_eq!(read(&dir, "access.log"), "3\n");
+        assert_eq!(read(&dir, "access.log.1"), "1\n2\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_lines() {
+        let dir = create_dir("writer");
+        let file = AccessLogFile::new(&AccessLogConfig {
+            path: dir.join("access.log").to_str().unwrap().to_string(),
+            max_size: 0,
+            rotation_interval: 0,
+            max_files: 1,
+        });
+
+        let (sender, receiver) = mpsc::sync_channel(2);
+        sender.send("1".to_string()).unwrap();
+        sender.send("2".to_string()).unwrap();
+        drop(sender);
+        write_lines(file, receiver);
+        assert_eq!(read(&dir, "access.log"), "1\n2\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn create_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "mirakc-access-log-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read(dir: &Path, filename: &str) -> String {
+        fs::read_to_string(dir.join(filename)).unwrap()
+    }
+}
@@ -35,6 +35,10 @@ pub async fn track_airtime(
     let user = TunerUser {
         info: TunerUserInfo::Tracker { stream_id },
         priority: (-1).into(),
+        // The end time may change while tracking.
+        expected_end_at: Some(program.start_at + program.duration),
+        drop_policy: Default::default(),
+        timeshift: None,
     };
 
     let mut stream = tuner_manager.send(StartStreamingMessage {
@@ -0,0 +1,226 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use actix::prelude::*;
+use chrono::{DateTime, Duration};
+use log;
+use tokio::io::AsyncWriteExt;
+use tokio::stream::StreamExt;
+
+use crate::config::{ArchiveConfig, Config};
+use crate::datetime_ext::*;
+use crate::epg::EpgChannel;
+use crate::error::Error;
+use crate::tuner::*;
+
+pub fn start(
+    config: Arc<Config>,
+    tuner_manager: Addr<TunerManager>,
+) -> Addr<Archiver> {
+    Archiver::new(config, tuner_manager).start()
+}
+
+// An archiver relays TS packets of channels defined in `archives` to segmented
+// files continuously.  Each archive is restarted when it stops for some
+// reason.
+pub struct Archiver {
+    config: Arc<Config>,
+    tuner_manager: Addr<TunerManager>,
+}
+
+impl Archiver {
+    const LABEL: &'static str = "archiver";
+
+    const RETRY_SECS: u64 = 10;
+
+    pub fn new(
+        config: Arc<Config>,
+        tuner_manager: Addr<TunerManager>,
+    ) -> Self {
+        Archiver { config, tuner_manager }
+    }
+
+    fn start_archiving(&mut self, index: usize, ctx: &mut Context<Self>) {
+        let archive = self.config.archives[index].clone();
+
+        log::info!("archive#{}: Start archiving {} into {}...",
+                   archive.name, archive.channel, archive.dir);
+
+        let fut = Self::archive(
+            self.config.clone(), self.tuner_manager.clone(), archive);
+
+        actix::fut::wrap_future::<_, Self>(fut)
+            .then(move |result, act, ctx| {
+                let name = &act.config.archives[index].name;
+                match result {
+                    Ok(()) => log::warn!("archive#{}: Stream ended", name),
+                    Err(err) => log::error!("archive#{}: {}", name, err),
+                }
+                log::info!("archive#{}: Restart in {}s",
+                           name, Self::RETRY_SECS);
+                ctx.run_later(
+                    std::time::Duration::from_secs(Self::RETRY_SECS),
+                    move |act, ctx| act.start_archiving(index, ctx));
+                actix::fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    async fn archive(
+        config: Arc<Config>,
+        tuner_manager: Addr<TunerManager>,
+        archive: ArchiveConfig,
+    ) -> Result<(), Error> {
+        let channel = config.channels.iter()
+            .filter(|config| !config.disabled)
+            .find(|config| {
+                config.channel_type == archive.channel_type &&
+                    config.channel == archive.channel
+            })
+            .cloned()
+            .map(EpgChannel::from)
+            .ok_or(Error::ChannelNotFound)?;
+
+        tokio::fs::create_dir_all(&archive.dir).await?;
+
+        // Use the lowest priority so that other users can grab the tuner.
+        let user = TunerUser {
+            info: TunerUserInfo::Job {
+                name: format!("{}/{}", Self::LABEL, archive.name),
+            },
+            priority: (-1).into(),
+            // Never ends.
+            expected_end_at: None,
+            drop_policy: Default::default(),
+            timeshift: None,
+        };
+
+        let mut stream = tuner_manager.send(StartStreamingMessage {
+            channel,
+            user,
+        }).await??;
+
+        let segment_duration =
+            Duration::milliseconds(archive.segment_duration as i64);
+
+        loop {
+            let start = Jst::now();
+            let path = Path::new(&archive.dir)
+                .join(Self::make_segment_name(&archive.name, start));
+            log::debug!("archive#{}: Writing into {}...",
+                        archive.name, path.display());
+
+            let mut file = tokio::fs::File::create(&path).await?;
+            while Jst::now() - start < segment_duration {
+                match stream.next().await {
+                    Some(chunk) => file.write_all(&chunk?).await?,
+                    None => return Ok(()),
+                }
+            }
+            drop(file);
+
+            if archive.max_segments > 0 {
+                let dir = archive.dir.clone();
+                let name = archive.name.clone();
+                let max_segments = archive.max_segments;
+                // Listing and removing files block the thread.
+                let result = tokio::task::spawn_blocking(move || {
+                    Self::remove_old_segments(&dir, &name, max_segments)
+                }).await;
+                result.map_err(
+                    |err| io::Error::new(io::ErrorKind::Other, err))??;
+            }
+        }
+    }
+
+    // Segment names are sorted in chronological order.
+    fn make_segment_name(name: &str, start: DateTime<Jst>) -> String {
+        format!("{}-{}.m2ts", name, start.format("%Y%m%d%H%M%S"))
+    }
+
+    // Segments created in previous runs are also removed.
+    fn remove_old_segments(
+        dir: &str,
+        name: &str,
+        max_segments: usize,
+    ) -> Result<(), Error> {
+        let prefix = format!("{}-", name);
+        let mut segments: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                let filename = path.file_name()
+                    .and_then(|filename| filename.to_str())
+                    .unwrap_or_default();
+                // Don't match segments of another archive named "<name>-*".
+                filename.starts_with(&prefix) &&
+                    filename.ends_with(".m2ts") &&
+                    filename[prefix.len()..(filename.len() - 5)]
+                        .chars().all(|c| c.is_ascii_digit())
+            })
+            .collect();
+        if segments.len() <= max_segments {
+            return Ok(());
+        }
+        segments.sort();
+        let n = segments.len() - max_segments;
+        for path in segments.into_iter().take(n) {
+            log::debug!("archive#{}: Remove {}", name, path.display());
+            std::fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Actor for Archiver {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        log::debug!("Started");
+        for index in 0..self.config.archives.len() {
+            self.start_archiving(index, ctx);
+        }
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        log::debug!("Stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_make_segment_name() {
+        let start = Jst.ymd(2020, 1, 2).and_hms(3, 4, 5);
+        assert_eq!(Archiver::make_segment_name("x", start),
+                   "x-20200102030405.m2ts");
+    }
+
+    #[test]
+    fn test_remove_old_segments() {
+        let dir = std::env::temp_dir()
+            .join(format!("mirakc-archiver-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let filenames = [
+            "x-1.m2ts", "x-2.m2ts", "x-3.m2ts", "x-y-1.m2ts", "y-1.m2ts",
+        ];
+        for filename in filenames.iter() {
+            std::fs::File::create(dir.join(filename)).unwrap();
+        }
+
+        let result = Archiver::remove_old_segments(
+            dir.to_str().unwrap(), "x", 2);
+        assert!(result.is_ok());
+        assert!(!dir.join("x-1.m2ts").exists());
+        assert!(dir.join("x-2.m2ts").exists());
+        assert!(dir.join("x-3.m2ts").exists());
+        assert!(dir.join("x-y-1.m2ts").exists());
+        assert!(dir.join("y-1.m2ts").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+use serde::Serialize;
+
+// Names of genres in content_nibble_level_1 and content_nibble_level_2 of the
+// content descriptor, defined in ARIB STD-B10 Part 2 Annex H.
+//
+// Reserved codes are not included.
+#[derive(Serialize)]
+pub struct MajorGenre {
+    pub lv1: u8,
+    pub name: &'static str,
+    #[serde(rename = "subgenres")]
+    pub minors: &'static [MinorGenre],
+}
+
+#[derive(Serialize)]
+pub struct MinorGenre {
+    pub lv2: u8,
+    pub name: &'static str,
+}
+
+pub fn lv1_name(lv1: u8) -> Option<&'static str> {
+    find(lv1).map(|major| major.name)
+}
+
+pub fn lv2_name(lv1: u8, lv2: u8) -> Option<&'static str> {
+    find(lv1)?.minors
+        .iter()
+        .find(|minor| minor.lv2 == lv2)
+        .map(|minor| minor.name)
+}
+
+fn find(lv1: u8) -> Option<&'static MajorGenre> {
+    GENRES.iter().find(|major| major.lv1 == lv1)
+}
+
+pub static GENRES: &[MajorGenre] = &[
+    MajorGenre {
+        lv1: 0x0,
+        name: "ニュース／報道",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "定時・総合" },
+            MinorGenre { lv2: 0x1, name: "天気" },
+            MinorGenre { lv2: 0x2, name: "特集・ドキュメント" },
+            MinorGenre { lv2: 0x3, name: "政治・国会" },
+            MinorGenre { lv2: 0x4, name: "経済・市況" },
+            MinorGenre { lv2: 0x5, name: "海外・国際" },
+            MinorGenre { lv2: 0x6, name: "解説" },
+            MinorGenre { lv2: 0x7, name: "討論・会談" },
+            MinorGenre { lv2: 0x8, name: "報道特番" },
+            MinorGenre { lv2: 0x9, name: "ローカル・地域" },
+            MinorGenre { lv2: 0xA, name: "交通" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0x1,
+        name: "スポーツ",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "スポーツニュース" },
+            MinorGenre { lv2: 0x1, name: "野球" },
+            MinorGenre { lv2: 0x2, name: "サッカー" },
+            MinorGenre { lv2: 0x3, name: "ゴルフ" },
+            MinorGenre { lv2: 0x4, name: "その他の球技" },
+            MinorGenre { lv2: 0x5, name: "相撲・格闘技" },
+            MinorGenre { lv2: 0x6, name: "オリンピック・国際大会" },
+            MinorGenre { lv2: 0x7, name: "マラソン・陸上・水泳" },
+            MinorGenre { lv2: 0x8, name: "モータースポーツ" },
+            MinorGenre { lv2: 0x9, name: "マリン・ウィンタースポーツ" },
+            MinorGenre { lv2: 0xA, name: "競馬・公営競技" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0x2,
+        name: "情報／ワイドショー",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "芸能・ワイドショー" },
+            MinorGenre { lv2: 0x1, name: "ファッション" },
+            MinorGenre { lv2: 0x2, name: "暮らし・住まい" },
+            MinorGenre { lv2: 0x3, name: "健康・医療" },
+            MinorGenre { lv2: 0x4, name: "ショッピング・通販" },
+            MinorGenre { lv2: 0x5, name: "グルメ・料理" },
+            MinorGenre { lv2: 0x6, name: "イベント" },
+            MinorGenre { lv2: 0x7, name: "番組紹介・お知らせ" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0x3,
+        name: "ドラマ",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "国内ドラマ" },
+            MinorGenre { lv2: 0x1, name: "海外ドラマ" },
+            MinorGenre { lv2: 0x2, name: "時代劇" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0x4,
+        name: "音楽",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "国内ロック・ポップス" },
+            MinorGenre { lv2: 0x1, name: "海外ロック・ポップス" },
+            MinorGenre { lv2: 0x2, name: "クラシック・オペラ" },
+            MinorGenre { lv2: 0x3, name: "ジャズ・フュージョン" },
+            MinorGenre { lv2: 0x4, name: "歌謡曲・演歌" },
+            MinorGenre { lv2: 0x5, name: "ライブ・コンサート" },
+            MinorGenre { lv2: 0x6, name: "ランキング・リクエスト" },
+            MinorGenre { lv2: 0x7, name: "カラオケ・のど自慢" },
+            MinorGenre { lv2: 0x8, name: "民謡・邦楽" },
+            MinorGenre { lv2: 0x9, name: "童謡・キッズ" },
+            MinorGenre { lv2: 0xA, name: "民族音楽・ワールドミュージック" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0x5,
+        name: "バラエティ",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "クイズ" },
+            MinorGenre { lv2: 0x1, name: "ゲーム" },
+            MinorGenre { lv2: 0x2, name: "トークバラエティ" },
+            MinorGenre { lv2: 0x3, name: "お笑い・コメディ" },
+            MinorGenre { lv2: 0x4, name: "音楽バラエティ" },
+            MinorGenre { lv2: 0x5, name: "旅バラエティ" },
+            MinorGenre { lv2: 0x6, name: "料理バラエティ" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0x6,
+        name: "映画",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "洋画" },
+            MinorGenre { lv2: 0x1, name: "邦画" },
+            MinorGenre { lv2: 0x2, name: "アニメ" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0x7,
+        name: "アニメ／特撮",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "国内アニメ" },
+            MinorGenre { lv2: 0x1, name: "海外アニメ" },
+            MinorGenre { lv2: 0x2, name: "特撮" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0x8,
+        name: "ドキュメンタリー／教養",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "社会・時事" },
+            MinorGenre { lv2: 0x1, name: "歴史・紀行" },
+            MinorGenre { lv2: 0x2, name: "自然・動物・環境" },
+            MinorGenre { lv2: 0x3, name: "宇宙・科学・医学" },
+            MinorGenre { lv2: 0x4, name: "カルチャー・伝統文化" },
+            MinorGenre { lv2: 0x5, name: "文学・文芸" },
+            MinorGenre { lv2: 0x6, name: "スポーツ" },
+            MinorGenre { lv2: 0x7, name: "ドキュメンタリー全般" },
+            MinorGenre { lv2: 0x8, name: "インタビュー・討論" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0x9,
+        name: "劇場／公演",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "現代劇・新劇" },
+            MinorGenre { lv2: 0x1, name: "ミュージカル" },
+            MinorGenre { lv2: 0x2, name: "ダンス・バレエ" },
+            MinorGenre { lv2: 0x3, name: "落語・演芸" },
+            MinorGenre { lv2: 0x4, name: "歌舞伎・古典" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0xA,
+        name: "趣味／教育",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "旅・釣り・アウトドア" },
+            MinorGenre { lv2: 0x1, name: "園芸・ペット・手芸" },
+            MinorGenre { lv2: 0x2, name: "音楽・美術・工芸" },
+            MinorGenre { lv2: 0x3, name: "囲碁・将棋" },
+            MinorGenre { lv2: 0x4, name: "麻雀・パチンコ" },
+            MinorGenre { lv2: 0x5, name: "車・オートバイ" },
+            MinorGenre { lv2: 0x6, name: "コンピュータ・ＴＶゲーム" },
+            MinorGenre { lv2: 0x7, name: "会話・語学" },
+            MinorGenre { lv2: 0x8, name: "幼児・小学生" },
+            MinorGenre { lv2: 0x9, name: "中学生・高校生" },
+            MinorGenre { lv2: 0xA, name: "大学生・受験" },
+            MinorGenre { lv2: 0xB, name: "生涯教育・資格" },
+            MinorGenre { lv2: 0xC, name: "教育問題" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0xB,
+        name: "福祉",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "高齢者" },
+            MinorGenre { lv2: 0x1, name: "障害者" },
+            MinorGenre { lv2: 0x2, name: "社会福祉" },
+            MinorGenre { lv2: 0x3, name: "ボランティア" },
+            MinorGenre { lv2: 0x4, name: "手話" },
+            MinorGenre { lv2: 0x5, name: "文字（字幕）" },
+            MinorGenre { lv2: 0x6, name: "音声解説" },
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0xE,
+        name: "拡張",
+        minors: &[
+            MinorGenre { lv2: 0x0, name: "BS/地上デジタル放送用番組付属情報" },
+            MinorGenre { lv2: 0x1, name: "広帯域CSデジタル放送用拡張" },
+            MinorGenre { lv2: 0x2, name: "衛星デジタル音声放送用拡張" },
+            MinorGenre { lv2: 0x3, name: "サーバー型番組付属情報" },
+            MinorGenre { lv2: 0x4, name: "IP放送用番組付属情報" },
+        ],
+    },
+    MajorGenre {
+        lv1: 0xF,
+        name: "その他",
+        minors: &[
+            MinorGenre { lv2: 0xF, name: "その他" },
+        ],
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lv1_name() {
+        assert_eq!(lv1_name(0x0), Some("ニュース／報道"));
+        assert_eq!(lv1_name(0x7), Some("アニメ／特撮"));
+        assert_eq!(lv1_name(0xC), None);
+    }
+
+    #[test]
+    fn test_lv2_name() {
+        assert_eq!(lv2_name(0x1, 0x1), Some("野球"));
+        assert_eq!(lv2_name(0x6, 0x2), Some("アニメ"));
+        assert_eq!(lv2_name(0x1, 0xB), None);
+        assert_eq!(lv2_name(0xC, 0x0), None);
+    }
+}
@@ -1,6 +1,10 @@
+use std::cmp;
+use std::collections::VecDeque;
 use std::fmt;
 use std::io;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
 use actix::prelude::*;
@@ -12,78 +16,532 @@ use tokio::io::AsyncRead;
 use tokio::sync::mpsc;
 
 use crate::chunk_stream::ChunkStream;
+use crate::config::{BroadcasterConfig, DropPolicy, ZeroReadPolicy};
+use crate::error::Error;
+use crate::metrics;
+use crate::psi_cache::PsiCache;
+use crate::tuner::StopStreamingMessage;
 use crate::tuner::TunerSessionId as BroadcasterId;
 use crate::tuner::TunerSubscriptionId as SubscriberId;
 
 struct Subscriber {
     id: SubscriberId,
     sender: mpsc::Sender<Bytes>,
+    drop_policy: DropPolicy,
+    num_dropped: usize,  // since the last warning
+    dropped_bytes: u64,  // in total
+    last_warned: Option<Instant>,
+    paused: bool,
+    stop_streaming: Option<Recipient<StopStreamingMessage>>,
+    // Shared with the stream of the subscriber.
+    failed: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    fn new(
+        id: SubscriberId,
+        sender: mpsc::Sender<Bytes>,
+        drop_policy: DropPolicy,
+        stop_streaming: Option<Recipient<StopStreamingMessage>>,
+        failed: Arc<AtomicBool>,
+    ) -> Self {
+        Subscriber {
+            id,
+            sender,
+            drop_policy,
+            num_dropped: 0,
+            dropped_bytes: 0,
+            last_warned: None,
+            paused: false,
+            stop_streaming,
+            failed,
+        }
+    }
+
+    // Returns the number of dropped chunks to be logged, or `None` if a
+    // warning has been logged within the interval.  The first drop is always
+    // logged.
+    fn drop_chunk(
+        &mut self,
+        chunk_size: usize,
+        interval: Duration,
+    ) -> Option<usize> {
+        self.num_dropped += 1;
+        self.dropped_bytes += chunk_size as u64;
+        match self.last_warned {
+            Some(last_warned) if last_warned.elapsed() < interval => None,
+            _ => {
+                self.last_warned = Some(Instant::now());
+                let num_dropped = self.num_dropped;
+                self.num_dropped = 0;
+                Some(num_dropped)
+            }
+        }
+    }
+}
+
+// Keeps chunks received within the duration for subscribers which want to
+// start from a past point.  Old chunks are evicted when the total size exceeds
+// `max_bytes` even if they are still within the duration.
+struct TimeshiftBuffer {
+    duration: Duration,
+    max_bytes: u64,
+    chunks: VecDeque<(Instant, Bytes)>,
+    total_bytes: u64,
+}
+
+impl TimeshiftBuffer {
+    fn new(duration: Duration, max_bytes: u64) -> Self {
+        TimeshiftBuffer {
+            duration,
+            max_bytes,
+            chunks: VecDeque::new(),
+            total_bytes: 0,
+        }
+    }
+
+    fn push(&mut self, chunk: Bytes) {
+        let now = Instant::now();
+        self.total_bytes += chunk.len() as u64;
+        self.chunks.push_back((now, chunk));
+        while let Some((received, chunk)) = self.chunks.front() {
+            if self.total_bytes <= self.max_bytes &&
+                now.duration_since(*received) <= self.duration {
+                break;
+            }
+            self.total_bytes -= chunk.len() as u64;
+            self.chunks.pop_front();
+        }
+    }
+
+    // Returns chunks received after `now - offset`, and true if some of them
+    // have already been evicted.
+    fn chunks_since(&self, offset: Duration) -> (Vec<Bytes>, bool) {
+        let oldest_age = match self.chunks.front() {
+            Some((received, _)) => received.elapsed(),
+            None => return (Vec::new(), offset > Duration::from_secs(0)),
+        };
+        let chunks = self.chunks.iter()
+            .filter(|(received, _)| received.elapsed() <= offset)
+            .map(|(_, chunk)| chunk.clone())
+            .collect();
+        (chunks, offset > oldest_age)
+    }
+}
+
+// Respawns the source of a broadcaster which has exited with an error.
+pub trait SourceRestarter {
+    // Returns `Some(true)` if the source has exited with an error,
+    // `Some(false)` if it has reached the end of the stream normally, or
+    // `None` if it's still exiting.  This must not block.
+    fn has_failed(&mut self) -> Option<bool>;
+
+    fn restart(&mut self) -> Result<Box<dyn AsyncRead + Unpin>, Error>;
 }
 
 pub struct Broadcaster {
     id: BroadcasterId,
     subscribers: Vec<Subscriber>,
     time_limit: Duration,
+    drop_log_interval: Duration,
     last_received: Instant,
+    zero_read_policy: ZeroReadPolicy,
+    max_chunks: usize,
+    chunk_size: usize,
+    source: Option<SpawnHandle>,
+    restarter: Option<Box<dyn SourceRestarter>>,
+    max_restarts: usize,
+    num_restarts: usize,
+    timeshift: Option<TimeshiftBuffer>,
+    psi_cache: Option<PsiCache>,
+    send_cached_psi: bool,
 }
 
 impl Broadcaster {
-    // large enough for 10 sec buffering.
+    // large enough for 10 sec buffering.  Used if the
+    // `broadcaster.max-buffered-chunks` config is not specified.
     const MAX_CHUNKS: usize = 1000;
 
-    // 32 KiB, large enough for 10 ms buffering.
+    // 32 KiB, large enough for 10 ms buffering.  Used if the
+    // `broadcaster.chunk-size` config is not specified.
     const CHUNK_SIZE: usize = 4096 * 8;
 
+    // 64 MiB, around 30 sec of a 16 Mbps stream.  Used if the
+    // `broadcaster.timeshift-max-bytes` config is not specified.
+    const TIMESHIFT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+    // Used only for `ZeroReadPolicy::Retry`.
+    const ZERO_READ_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+    // The exit status of the source is polled at this interval until the
+    // timeout expires, without blocking the event loop.
+    const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+    const EXIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+    // The delay before restarting the source is doubled on every restart.
+    const RESTART_INITIAL_DELAY: Duration = Duration::from_millis(100);
+    const RESTART_MAX_DELAY: Duration = Duration::from_secs(5);
+
     pub fn new<R>(
         id: BroadcasterId,
         source: R,
         time_limit: u64,
+        drop_log_interval: u64,
+        zero_read_policy: ZeroReadPolicy,
+        config: &BroadcasterConfig,
         ctx: &mut Context<Self>,
     ) -> Self
     where
         R: AsyncRead + Unpin + 'static,
     {
-        let stream = ChunkStream::new(source, Self::CHUNK_SIZE);
-        let _ = Self::add_stream(stream, ctx);
+        let max_chunks = config.max_buffered_chunks.unwrap_or(Self::MAX_CHUNKS);
+        let chunk_size = config.chunk_size.unwrap_or(Self::CHUNK_SIZE);
+        let source = Self::add_source(
+            source, zero_read_policy, chunk_size, ctx);
+        let timeshift = config.timeshift_duration.map(|duration| {
+            TimeshiftBuffer::new(
+                Duration::from_millis(duration),
+                config.timeshift_max_bytes.unwrap_or(Self::TIMESHIFT_MAX_BYTES))
+        });
+        let reinsertion_interval =
+            config.psi_reinsertion_interval.map(Duration::from_millis);
+        let psi_cache = if config.psi_cache || reinsertion_interval.is_some() {
+            Some(PsiCache::new(reinsertion_interval))
+        } else {
+            None
+        };
         Self {
             id,
             subscribers: Vec::new(),
             time_limit: Duration::from_millis(time_limit),
+            drop_log_interval: Duration::from_millis(drop_log_interval),
             last_received: Instant::now(),
+            zero_read_policy,
+            max_chunks,
+            chunk_size,
+            source: Some(source),
+            restarter: None,
+            max_restarts: 0,
+            num_restarts: 0,
+            timeshift,
+            psi_cache,
+            send_cached_psi: config.psi_cache,
+        }
+    }
+
+    // The source is restarted up to `max_restarts` times when it exits with
+    // an error.  The broadcaster stops when it reaches the end of the stream
+    // normally.
+    pub fn with_restarter(
+        mut self,
+        restarter: Box<dyn SourceRestarter>,
+        max_restarts: usize,
+    ) -> Self {
+        self.restarter = Some(restarter);
+        self.max_restarts = max_restarts;
+        self
+    }
+
+    fn add_source<R>(
+        source: R,
+        zero_read_policy: ZeroReadPolicy,
+        chunk_size: usize,
+        ctx: &mut Context<Self>,
+    ) -> SpawnHandle
+    where
+        R: AsyncRead + Unpin + 'static,
+    {
+        // With `ZeroReadPolicy::Retry`, `finished()` is never called and the
+        // broadcaster stops when `check_timeout()` detects no packet for the
+        // time limit.
+        match zero_read_policy {
+            ZeroReadPolicy::Eof => Self::add_stream(
+                ChunkStream::new(source, chunk_size), ctx),
+            ZeroReadPolicy::Retry => Self::add_stream(
+                ChunkStream::with_retry(source, chunk_size,
+                                        Self::ZERO_READ_RETRY_INTERVAL),
+                ctx),
+        }
+    }
+
+    // Polls the exit status of the source until it's determined or the
+    // deadline expires.  The source is treated as finished normally if the
+    // deadline expires.
+    fn wait_for_exit(&mut self, deadline: Instant, ctx: &mut Context<Self>) {
+        let failed = match self.restarter {
+            Some(ref mut restarter) => restarter.has_failed(),
+            None => Some(false),
+        };
+        match failed {
+            Some(true) => {
+                log::error!("{}: The source exited with an error", self.id);
+                self.restart_or_fail(ctx);
+            }
+            None if Instant::now() < deadline => {
+                ctx.run_later(Self::EXIT_POLL_INTERVAL, move |this, ctx| {
+                    this.wait_for_exit(deadline, ctx);
+                });
+            }
+            _ => {
+                log::debug!("{}: EOS reached, stop", self.id);
+                ctx.stop();
+            }
+        }
+    }
+
+    // Subscribers are kept while restarting the source.  The old source is
+    // detached at once so that errors from it are never handled after
+    // restarting.
+    fn restart_or_fail(&mut self, ctx: &mut Context<Self>) {
+        if self.restarter.is_none() || self.num_restarts >= self.max_restarts {
+            self.fail(ctx);
+            return;
+        }
+        if let Some(handle) = self.source.take() {
+            ctx.cancel_future(handle);
+        }
+        let delay = self.restart_delay();
+        log::warn!("{}: Restart the source in {}",
+                   self.id, humantime::format_duration(delay));
+        // Not to stop in `check_timeout()` while waiting.
+        self.last_received = Instant::now();
+        ctx.run_later(delay, Self::restart);
+    }
+
+    fn restart_delay(&self) -> Duration {
+        let factor = 2u32.saturating_pow(self.num_restarts as u32);
+        cmp::min(Self::RESTART_INITIAL_DELAY * factor, Self::RESTART_MAX_DELAY)
+    }
+
+    fn restart(&mut self, ctx: &mut Context<Self>) {
+        let result = match self.restarter {
+            Some(ref mut restarter) => restarter.restart(),
+            None => {
+                self.fail(ctx);
+                return;
+            }
+        };
+        match result {
+            Ok(source) => {
+                self.num_restarts += 1;
+                log::warn!("{}: Restarted the source ({}/{})",
+                           self.id, self.num_restarts, self.max_restarts);
+                self.last_received = Instant::now();
+                self.source = Some(Self::add_source(
+                    source, self.zero_read_policy, self.chunk_size, ctx));
+            }
+            Err(err) => {
+                log::error!("{}: Failed to restart the source, stop: {}",
+                            self.id, err);
+                self.fail(ctx);
+            }
+        }
+    }
+
+    // Stops due to a failure of the source.  Unlike other cases, the streams
+    // of the subscribers end with an error so that they can distinguish the
+    // failure from the end of the stream.
+    fn fail(&mut self, ctx: &mut Context<Self>) {
+        for subscriber in self.subscribers.iter() {
+            subscriber.failed.store(true, Ordering::SeqCst);
+        }
+        ctx.stop();
+    }
+
+    // The cached PAT and PMT packets are sent to the subscriber before live
+    // chunks so that it can start decoding without waiting for the next ones.
+    // The channel has extra capacity for them.
+    fn subscribe(
+        &mut self,
+        id: SubscriberId,
+        drop_policy: DropPolicy,
+        stop_streaming: Option<Recipient<StopStreamingMessage>>,
+    ) -> BroadcasterStream {
+        let (mut sender, receiver) = mpsc::channel(self.max_chunks + 1);
+        if self.send_cached_psi {
+            if let Some(packets) =
+                self.psi_cache.as_ref().and_then(|cache| cache.packets()) {
+                let _ = sender.try_send(packets);
+            }
         }
+        let failed = Arc::new(AtomicBool::new(false));
+        self.subscribers.push(Subscriber::new(
+            id, sender, drop_policy, stop_streaming, failed.clone()));
+        BroadcasterStream::new(receiver, failed)
     }
 
-    fn subscribe(&mut self, id: SubscriberId) -> BroadcasterStream {
-        let (sender, receiver) = mpsc::channel(Self::MAX_CHUNKS);
-        self.subscribers.push(Subscriber { id, sender });
-        BroadcasterStream::new(receiver)
+    // Buffered chunks are sent to the subscriber before live chunks.  The
+    // channel has extra capacity for them so that none of them are dropped.
+    // The cached PAT and PMT packets are not sent because buffered chunks
+    // contain their own.
+    fn subscribe_from(
+        &mut self,
+        id: SubscriberId,
+        drop_policy: DropPolicy,
+        offset: Duration,
+        stop_streaming: Option<Recipient<StopStreamingMessage>>,
+    ) -> BroadcasterStream {
+        let (chunks, truncated) = match self.timeshift {
+            Some(ref timeshift) => timeshift.chunks_since(offset),
+            None => {
+                log::warn!("{}: Timeshift is disabled, start {} from live",
+                           self.id, id);
+                return self.subscribe(id, drop_policy, stop_streaming);
+            }
+        };
+        if truncated {
+            log::warn!("{}: Chunks in the last {} are not retained, start {} \
+                        from the oldest available chunk",
+                       self.id, humantime::format_duration(offset), id);
+        }
+        let (mut sender, receiver) =
+            mpsc::channel(self.max_chunks + chunks.len());
+        for chunk in chunks.into_iter() {
+            let _ = sender.try_send(chunk);
+        }
+        let failed = Arc::new(AtomicBool::new(false));
+        self.subscribers.push(Subscriber::new(
+            id, sender, drop_policy, stop_streaming, failed.clone()));
+        BroadcasterStream::new(receiver, failed)
     }
 
+    // The sender is dropped here, and the stream of the subscriber ends after
+    // it receives buffered chunks.
     fn unsubscribe(&mut self, id: SubscriberId) {
         // Log warning message if the user haven't subscribed.
+        for subscriber in self.subscribers.iter() {
+            if subscriber.id == id && subscriber.num_dropped > 0 {
+                log::warn!("{}: Dropped {} chunks for {} since the last \
+                            warning", self.id, subscriber.num_dropped, id);
+            }
+            if subscriber.id == id && subscriber.dropped_bytes > 0 {
+                log::warn!("{}: Dropped {} bytes in total for {}",
+                           self.id, subscriber.dropped_bytes, id);
+            }
+        }
         self.subscribers.retain(|subscriber| subscriber.id != id);
     }
 
-    fn broadcast(&mut self, chunk: Bytes) {
+    // Unsubscribes a subscriber closed by the broadcaster itself, and notifies
+    // the tuner manager of it.  Otherwise, the tuner session would keep the
+    // subscription until the client drops the stream.
+    fn close_subscriber(&mut self, id: SubscriberId) {
+        let stop_streaming = self.subscribers
+            .iter()
+            .find(|subscriber| subscriber.id == id)
+            .and_then(|subscriber| subscriber.stop_streaming.clone());
+        self.unsubscribe(id);
+        if let Some(recipient) = stop_streaming {
+            let _ = recipient.do_send(StopStreamingMessage { id });
+        }
+    }
+
+    // Chunks are dropped without buffering while a subscriber is paused so
+    // that it receives live data after resumed.  A paused subscriber is kept
+    // and the tuner session is never closed for it.
+    fn set_paused(&mut self, id: SubscriberId, paused: bool) {
         for subscriber in self.subscribers.iter_mut() {
+            if subscriber.id == id {
+                subscriber.paused = paused;
+            }
+        }
+    }
+
+    // Returns senders of subscribers with `DropPolicy::Block` which have no
+    // space for the chunk.  The caller has to send the chunk to them.
+    fn broadcast(
+        &mut self,
+        chunk: Bytes,
+    ) -> Vec<(SubscriberId, mpsc::Sender<Bytes>)> {
+        let mut closed = Vec::new();
+        let mut blocked = Vec::new();
+        for subscriber in self.subscribers.iter_mut() {
+            if subscriber.paused {
+                log::trace!("{}: Paused {}, drop the chunk",
+                            self.id, subscriber.id);
+                continue;
+            }
             let chunk_size = chunk.len();
             match subscriber.sender.try_send(chunk.clone()) {
                 Ok(_) => {
                     log::trace!("{}: Sent a chunk of {} bytes to {}",
                                 self.id, chunk_size, subscriber.id);
                 },
-                Err(mpsc::error::TrySendError::Full(_)) => {
-                    log::warn!("{}: No space for {}, drop the chunk",
+                Err(mpsc::error::TrySendError::Full(_))
+                    if subscriber.drop_policy == DropPolicy::Block => {
+                    log::trace!("{}: No space for {}, wait",
+                                self.id, subscriber.id);
+                    blocked.push((subscriber.id, subscriber.sender.clone()));
+                }
+                Err(mpsc::error::TrySendError::Full(_))
+                    if subscriber.drop_policy == DropPolicy::CloseStream => {
+                    subscriber.dropped_bytes += chunk_size as u64;
+                    metrics::add_dropped_chunk(chunk_size);
+                    log::warn!("{}: No space for {}, close the stream",
                                self.id, subscriber.id);
+                    closed.push(subscriber.id);
+                }
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    metrics::add_dropped_chunk(chunk_size);
+                    match subscriber.drop_chunk(
+                        chunk_size, self.drop_log_interval) {
+                        Some(1) => {
+                            log::warn!("{}: No space for {}, drop the chunk",
+                                       self.id, subscriber.id);
+                        }
+                        Some(n) => {
+                            log::warn!("{}: No space for {}, dropped {} \
+                                        chunks in the last {}",
+                                       self.id, subscriber.id, n,
+                                       humantime::format_duration(
+                                           self.drop_log_interval));
+                        }
+                        None => (),
+                    }
                 }
                 Err(mpsc::error::TrySendError::Closed(_)) => {
-                    log::debug!("{}: Closed by {}, wait for unsubscribe",
+                    log::debug!("{}: Closed by {}, unsubscribe",
                                 self.id, subscriber.id);
+                    closed.push(subscriber.id);
                 }
             }
         }
 
+        // Unsubscribe immediately without waiting for UnsubscribeMessage.
+        // The client has gone and there is no need to keep its sender.
+        for id in closed.into_iter() {
+            self.close_subscriber(id);
+        }
+
         self.last_received = Instant::now();
+
+        blocked
+    }
+
+    // Stops handling chunks from the source and messages until the blocked
+    // subscribers receive the chunk.  So, the source is also blocked.
+    fn wait_for_subscribers(
+        &mut self,
+        blocked: Vec<(SubscriberId, mpsc::Sender<Bytes>)>,
+        chunk: Bytes,
+        ctx: &mut Context<Self>,
+    ) {
+        let fut = async move {
+            let mut closed = Vec::new();
+            for (id, mut sender) in blocked.into_iter() {
+                if sender.send(chunk.clone()).await.is_err() {
+                    closed.push(id);
+                }
+            }
+            closed
+        };
+        ctx.wait(fut.into_actor(self).map(|closed, act, _| {
+            for id in closed.into_iter() {
+                log::debug!("{}: Closed by {}, unsubscribe", act.id, id);
+                act.close_subscriber(id);
+            }
+            act.last_received = Instant::now();
+        }));
     }
 
     fn check_timeout(&mut self, ctx: &mut Context<Self>) {
@@ -91,7 +549,7 @@ impl Broadcaster {
         if  elapsed > self.time_limit {
             log::error!("{}: No packet from the tuner for {}, stop",
                         self.id, humantime::format_duration(elapsed));
-            ctx.stop();
+            self.fail(ctx);
         }
     }
 }
@@ -112,7 +570,10 @@ impl Actor for Broadcaster {
 // subscribe
 
 pub struct SubscribeMessage {
-    pub id: SubscriberId
+    pub id: SubscriberId,
+    pub drop_policy: DropPolicy,
+    // Notified when the broadcaster closes the subscription.
+    pub stop_streaming: Option<Recipient<StopStreamingMessage>>,
 }
 
 impl fmt::Display for SubscribeMessage {
@@ -146,7 +607,44 @@ impl Handler<SubscribeMessage> for Broadcaster {
         _: &mut Self::Context
     ) -> Self::Result {
         log::debug!("{}", msg);
-        self.subscribe(msg.id)
+        self.subscribe(msg.id, msg.drop_policy, msg.stop_streaming)
+    }
+}
+
+// subscribe from
+
+// Subscribes with chunks received after `now - offset` in the timeshift buffer.
+// Works like `SubscribeMessage` if the timeshift buffer is disabled.
+pub struct SubscribeFromMessage {
+    pub id: SubscriberId,
+    pub drop_policy: DropPolicy,
+    pub offset: Duration,
+    // Same as `SubscribeMessage::stop_streaming`.
+    pub stop_streaming: Option<Recipient<StopStreamingMessage>>,
+}
+
+impl fmt::Display for SubscribeFromMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Subscribe with {} from {} ago",
+               self.id, humantime::format_duration(self.offset))
+    }
+}
+
+impl Message for SubscribeFromMessage {
+    type Result = BroadcasterStream;
+}
+
+impl Handler<SubscribeFromMessage> for Broadcaster {
+    type Result = BroadcasterStream;
+
+    fn handle(
+        &mut self,
+        msg: SubscribeFromMessage,
+        _: &mut Self::Context
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.subscribe_from(
+            msg.id, msg.drop_policy, msg.offset, msg.stop_streaming)
     }
 }
 
@@ -179,40 +677,163 @@ impl Handler<UnsubscribeMessage> for Broadcaster {
     }
 }
 
+// stop
+
+// Stops the broadcaster immediately.  All subscribers receive the end of the
+// stream without waiting for the source to reach EOF.
+pub struct StopMessage;
+
+impl fmt::Display for StopMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Stop")
+    }
+}
+
+impl Message for StopMessage {
+    type Result = ();
+}
+
+impl Handler<StopMessage> for Broadcaster {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: StopMessage,
+        ctx: &mut Self::Context
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        ctx.stop();
+    }
+}
+
+// pause subscriber
+
+pub struct PauseSubscriberMessage {
+    pub id: SubscriberId
+}
+
+impl fmt::Display for PauseSubscriberMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pause {}", self.id)
+    }
+}
+
+impl Message for PauseSubscriberMessage {
+    type Result = ();
+}
+
+impl Handler<PauseSubscriberMessage> for Broadcaster {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: PauseSubscriberMessage,
+        _: &mut Self::Context
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.set_paused(msg.id, true)
+    }
+}
+
+// resume subscriber
+
+pub struct ResumeSubscriberMessage {
+    pub id: SubscriberId
+}
+
+impl fmt::Display for ResumeSubscriberMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Resume {}", self.id)
+    }
+}
+
+impl Message for ResumeSubscriberMessage {
+    type Result = ();
+}
+
+impl Handler<ResumeSubscriberMessage> for Broadcaster {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ResumeSubscriberMessage,
+        _: &mut Self::Context
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.set_paused(msg.id, false)
+    }
+}
+
 // stream handler
 
 impl StreamHandler<io::Result<Bytes>> for Broadcaster {
     fn handle(&mut self, chunk: io::Result<Bytes>, ctx: &mut Context<Self>) {
         match chunk {
             Ok(chunk) => {
-                self.broadcast(chunk);
+                // PAT and PMT packets may be reinserted into the chunk.
+                let chunk = match self.psi_cache {
+                    Some(ref mut psi_cache) => psi_cache.feed_chunk(chunk),
+                    None => chunk,
+                };
+                if let Some(ref mut timeshift) = self.timeshift {
+                    timeshift.push(chunk.clone());
+                }
+                let blocked = self.broadcast(chunk.clone());
+                if !blocked.is_empty() {
+                    self.wait_for_subscribers(blocked, chunk, ctx);
+                }
             }
             Err(err) => {
-                log::error!("{}: Error, stop: {}", self.id, err);
-                ctx.stop();
+                log::error!("{}: Error: {}", self.id, err);
+                self.restart_or_fail(ctx);
             }
         }
     }
 
     fn finished(&mut self, ctx: &mut Context<Self>) {
-        log::debug!("{}: EOS reached, stop", self.id);
-        ctx.stop();
+        self.wait_for_exit(Instant::now() + Self::EXIT_TIMEOUT, ctx);
     }
 }
 
 // stream
 
-pub struct BroadcasterStream(mpsc::Receiver<Bytes>);
+// The stream ends with an error if the broadcaster stopped due to a failure of
+// the source.  Otherwise, it simply ends.
+pub struct BroadcasterStream {
+    rx: mpsc::Receiver<Bytes>,
+    // A chunk received in `wait_for_data()`.
+    pending: Option<Bytes>,
+    failed: Arc<AtomicBool>,
+}
 
 impl BroadcasterStream {
-    fn new(rx: mpsc::Receiver<Bytes>) -> Self {
-        Self(rx)
+    fn new(rx: mpsc::Receiver<Bytes>, failed: Arc<AtomicBool>) -> Self {
+        Self { rx, pending: None, failed }
+    }
+
+    // Waits until the first chunk comes without consuming it.  Returns `false`
+    // if the stream ends before any chunk comes.
+    pub async fn wait_for_data(&mut self) -> bool {
+        if self.pending.is_none() {
+            self.pending = self.rx.recv().await;
+        }
+        self.pending.is_some()
     }
 
     #[cfg(test)]
     pub fn new_for_test() -> (mpsc::Sender<Bytes>, Self) {
+        let (tx, _, stream) = Self::new_for_failure_test();
+        (tx, stream)
+    }
+
+    // The stream ends with an error if the flag is set before the sender is
+    // dropped.
+    #[cfg(test)]
+    pub fn new_for_failure_test(
+    ) -> (mpsc::Sender<Bytes>, Arc<AtomicBool>, Self) {
         let (tx, rx) = mpsc::channel(10);
-        (tx, BroadcasterStream::new(rx))
+        let failed = Arc::new(AtomicBool::new(false));
+        (tx, failed.clone(), BroadcasterStream::new(rx, failed))
     }
 }
 
@@ -223,16 +844,27 @@ impl Stream for BroadcasterStream {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context
     ) -> std::task::Poll<Option<Self::Item>> {
-        Pin::new(&mut self.0)
-            .poll_next(cx)
-            .map(|item| item.map(|chunk| Ok(chunk)))
+        if let Some(chunk) = self.pending.take() {
+            return std::task::Poll::Ready(Some(Ok(chunk)));
+        }
+        let item = match Pin::new(&mut self.rx).poll_next(cx) {
+            std::task::Poll::Ready(item) => item,
+            std::task::Poll::Pending => return std::task::Poll::Pending,
+        };
+        match item {
+            Some(chunk) => std::task::Poll::Ready(Some(Ok(chunk))),
+            // Reported only once.
+            None if self.failed.swap(false, Ordering::SeqCst) =>
+                std::task::Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::Other, "The source has failed")))),
+            None => std::task::Poll::Ready(None),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::cmp;
     use std::pin::Pin;
     use std::task::{Poll, Context};
     use bytes::Buf;
@@ -244,15 +876,21 @@ mod tests {
         let (mut tx, rx) = mpsc::channel(1);
 
         let broadcaster = Broadcaster::create(|ctx| {
-            Broadcaster::new(Default::default(), DataSource(rx), 1000, ctx)
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 1000, 1000,
+                ZeroReadPolicy::Eof, &Default::default(), ctx)
         });
 
         let mut stream1 = broadcaster.send(SubscribeMessage {
-            id: SubscriberId::new(Default::default(), 1)
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
         }).await.unwrap();
 
         let mut stream2 = broadcaster.send(SubscribeMessage {
-            id: SubscriberId::new(Default::default(), 2)
+            id: SubscriberId::new(Default::default(), 2),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
         }).await.unwrap();
 
         let _ = tx.send(Bytes::from("hello")).await;
@@ -269,15 +907,21 @@ mod tests {
         let (mut tx, rx) = mpsc::channel(1);
 
         let broadcaster = Broadcaster::create(|ctx| {
-            Broadcaster::new(Default::default(), DataSource(rx), 1000, ctx)
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 1000, 1000,
+                ZeroReadPolicy::Eof, &Default::default(), ctx)
         });
 
         let mut stream1 = broadcaster.send(SubscribeMessage {
-            id: SubscriberId::new(Default::default(), 1)
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
         }).await.unwrap();
 
         let mut stream2 = broadcaster.send(SubscribeMessage {
-            id: SubscriberId::new(Default::default(), 2)
+            id: SubscriberId::new(Default::default(), 2),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
         }).await.unwrap();
 
         broadcaster.send(UnsubscribeMessage {
@@ -294,17 +938,365 @@ mod tests {
     }
 
     #[actix_rt::test]
-    async fn test_timeout() {
+    async fn test_stop() {
+        let (_tx, rx) = mpsc::channel::<Bytes>(1);
+
+        let broadcaster = Broadcaster::create(|ctx| {
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 1000, 1000,
+                ZeroReadPolicy::Eof, &Default::default(), ctx)
+        });
+
+        let mut stream = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
+        broadcaster.send(StopMessage).await.unwrap();
+
+        // The stream ends even though the source is still open.
+        let chunk = stream.next().await;
+        assert!(chunk.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_pause_resume() {
         let (mut tx, rx) = mpsc::channel(1);
 
         let broadcaster = Broadcaster::create(|ctx| {
-            Broadcaster::new(Default::default(), DataSource(rx), 50, ctx)
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 1000, 1000,
+                ZeroReadPolicy::Eof, &Default::default(), ctx)
         });
 
         let mut stream1 = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
+        let mut stream2 = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 2),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
+        broadcaster.send(PauseSubscriberMessage {
+            id: SubscriberId::new(Default::default(), 1)
+        }).await.unwrap();
+
+        let _ = tx.send(Bytes::from("hello")).await;
+
+        let chunk = stream2.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from("hello"));
+
+        broadcaster.send(ResumeSubscriberMessage {
             id: SubscriberId::new(Default::default(), 1)
         }).await.unwrap();
 
+        let _ = tx.send(Bytes::from("world")).await;
+
+        // The chunk sent while paused has been dropped.
+        let chunk = stream1.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from("world"));
+
+        let chunk = stream2.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from("world"));
+    }
+
+    #[actix_rt::test]
+    async fn test_subscribe_from() {
+        let (mut tx, rx) = mpsc::channel(1);
+
+        let config = BroadcasterConfig {
+            timeshift_duration: Some(60_000),
+            timeshift_max_bytes: Some(10),
+            ..Default::default()
+        };
+        let broadcaster = Broadcaster::create(|ctx| {
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 1000, 1000,
+                ZeroReadPolicy::Eof, &config, ctx)
+        });
+
+        let mut stream1 = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
+        let _ = tx.send(Bytes::from("hello")).await;
+        let _ = tx.send(Bytes::from("world")).await;
+        let _ = tx.send(Bytes::from("!")).await;
+
+        let chunk = stream1.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from("hello"));
+        let chunk = stream1.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from("world"));
+        let chunk = stream1.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from("!"));
+
+        // "hello" has been evicted in order to keep the buffer within 10 bytes.
+        let mut stream2 = broadcaster.send(SubscribeFromMessage {
+            id: SubscriberId::new(Default::default(), 2),
+            drop_policy: DropPolicy::DropChunk,
+            offset: Duration::from_secs(60),
+            stop_streaming: None,
+        }).await.unwrap();
+
+        let _ = tx.send(Bytes::from("live")).await;
+
+        let chunk = stream2.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from("world"));
+        let chunk = stream2.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from("!"));
+        let chunk = stream2.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from("live"));
+    }
+
+    #[actix_rt::test]
+    async fn test_psi_cache() {
+        let (mut tx, rx) = mpsc::channel(1);
+
+        let config = BroadcasterConfig {
+            psi_cache: true,
+            ..Default::default()
+        };
+        let broadcaster = Broadcaster::create(|ctx| {
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 1000, 1000,
+                ZeroReadPolicy::Eof, &config, ctx)
+        });
+
+        let mut stream1 = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
+        // A PAT packet without PMT PIDs.
+        let mut pat = vec![0xFF; 188];
+        pat[..17].copy_from_slice(&[
+            0x47, 0x40, 0x00, 0x10, 0x00,
+            0x00, 0xB0, 0x09, 0x00, 0x01, 0xC1, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,  // CRC_32 is not checked
+        ]);
+        let pat = Bytes::from(pat);
+
+        let _ = tx.send(pat.clone()).await;
+        let chunk = stream1.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), pat);
+
+        let mut stream2 = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 2),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
+        let _ = tx.send(Bytes::from("live")).await;
+
+        // The cached PAT is sent before live chunks.
+        let chunk = stream2.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), pat);
+        let chunk = stream2.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from("live"));
+    }
+
+    #[actix_rt::test]
+    async fn test_psi_reinsertion() {
+        let (mut tx, rx) = mpsc::channel(1);
+
+        let config = BroadcasterConfig {
+            psi_reinsertion_interval: Some(500),
+            ..Default::default()
+        };
+        let broadcaster = Broadcaster::create(|ctx| {
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 1000, 1000,
+                ZeroReadPolicy::Eof, &config, ctx)
+        });
+
+        let mut stream = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
+        // A PAT packet without PMT PIDs.
+        let mut pat = vec![0xFF; 188];
+        pat[..17].copy_from_slice(&[
+            0x47, 0x40, 0x00, 0x10, 0x00,
+            0x00, 0xB0, 0x09, 0x00, 0x01, 0xC1, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00,  // CRC_32 is not checked
+        ]);
+        let pat = Bytes::from(pat);
+        let mut other = vec![0xFF; 188];
+        other[..4].copy_from_slice(&[0x47, 0x01, 0x11, 0x10]);
+        let other = Bytes::from(other);
+
+        let _ = tx.send(pat.clone()).await;
+        let chunk = stream.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), pat);
+
+        // The PAT is not reinserted until the interval elapses.
+        let _ = tx.send(other.clone()).await;
+        let chunk = stream.next().await;
+        assert_eq!(chunk.unwrap().unwrap(), other);
+
+        tokio::time::delay_for(Duration::from_millis(600)).await;
+
+        // The PAT appears again after the interval.
+        let _ = tx.send(other.clone()).await;
+        let chunk = stream.next().await;
+        let mut expected = pat.to_vec();
+        expected.extend_from_slice(&other);
+        assert_eq!(chunk.unwrap().unwrap(), Bytes::from(expected));
+    }
+
+    #[actix_rt::test]
+    async fn test_zero_read() {
+        let (mut tx, rx) = mpsc::channel(1);
+
+        let broadcaster = Broadcaster::create(|ctx| {
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 1000, 1000,
+                ZeroReadPolicy::Eof, &Default::default(), ctx)
+        });
+
+        let mut stream1 = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
+        // An empty chunk causes a zero-length read.
+        let _ = tx.send(Bytes::new()).await;
+
+        let chunk = stream1.next().await;
+        assert!(chunk.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_zero_read_resume() {
+        let (mut tx, rx) = mpsc::channel(1);
+
+        let broadcaster = Broadcaster::create(|ctx| {
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 1000, 1000,
+                ZeroReadPolicy::Retry, &Default::default(), ctx)
+        });
+
+        let mut stream1 = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
+        let _ = tx.send(Bytes::new()).await;
+        let _ = tx.send(Bytes::from("hello")).await;
+
+        let chunk = stream1.next().await;
+        assert!(chunk.is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_client_dropped() {
+        type Mock = actix::actors::mocker::Mocker<crate::tuner::TunerManager>;
+
+        let (notified_tx, mut notified_rx) = mpsc::unbounded_channel();
+        let mock = Mock::mock(Box::new(move |msg, _| {
+            if let Some(msg) = msg.downcast_ref::<StopStreamingMessage>() {
+                let _ = notified_tx.send(msg.id);
+                Box::new(Some(()))
+            } else {
+                unimplemented!();
+            }
+        })).start();
+
+        let mut broadcaster = create_broadcaster();
+
+        let stream1 = broadcaster.subscribe(
+            SubscriberId::new(Default::default(), 1), DropPolicy::DropChunk,
+            Some(mock.recipient()));
+        let mut stream2 = broadcaster.subscribe(
+            SubscriberId::new(Default::default(), 2), DropPolicy::DropChunk,
+            None);
+        assert_eq!(broadcaster.subscribers.len(), 2);
+
+        // Simulate an abrupt disconnection.
+        drop(stream1);
+
+        broadcaster.broadcast(Bytes::from("hello"));
+        assert_eq!(broadcaster.subscribers.len(), 1);
+        assert!(broadcaster.subscribers[0].id ==
+                SubscriberId::new(Default::default(), 2));
+
+        let chunk = stream2.next().await;
+        assert!(chunk.is_some());
+
+        // The tuner manager is notified of the closed subscription.
+        assert!(notified_rx.recv().await ==
+                Some(SubscriberId::new(Default::default(), 1)));
+    }
+
+    #[actix_rt::test]
+    async fn test_drop_policy() {
+        let mut broadcaster = create_broadcaster();
+
+        let _stream1 = broadcaster.subscribe(
+            SubscriberId::new(Default::default(), 1), DropPolicy::DropChunk,
+            None);
+        let mut stream2 = broadcaster.subscribe(
+            SubscriberId::new(Default::default(), 2), DropPolicy::CloseStream,
+            None);
+        let _stream3 = broadcaster.subscribe(
+            SubscriberId::new(Default::default(), 3), DropPolicy::Block,
+            None);
+
+        for _ in 0..Broadcaster::MAX_CHUNKS {
+            let blocked = broadcaster.broadcast(Bytes::from("hello"));
+            assert!(blocked.is_empty());
+        }
+
+        // No space for all subscribers.
+        let blocked = broadcaster.broadcast(Bytes::from("hello"));
+        assert_eq!(blocked.len(), 1);
+        assert!(blocked[0].0 == SubscriberId::new(Default::default(), 3));
+
+        // The chunk has been dropped for the first subscriber.
+        assert_eq!(broadcaster.subscribers.len(), 2);
+        assert!(broadcaster.subscribers[0].id ==
+                SubscriberId::new(Default::default(), 1));
+        assert_eq!(broadcaster.subscribers[0].dropped_bytes, 5);
+        assert!(broadcaster.subscribers[1].id ==
+                SubscriberId::new(Default::default(), 3));
+        assert_eq!(broadcaster.subscribers[1].dropped_bytes, 0);
+
+        // The stream of the second subscriber ends after buffered chunks.
+        let mut num_chunks = 0;
+        while let Some(_) = stream2.next().await {
+            num_chunks += 1;
+        }
+        assert_eq!(num_chunks, Broadcaster::MAX_CHUNKS);
+    }
+
+    #[actix_rt::test]
+    async fn test_timeout() {
+        let (mut tx, rx) = mpsc::channel(1);
+
+        let broadcaster = Broadcaster::create(|ctx| {
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 50, 1000,
+                ZeroReadPolicy::Eof, &Default::default(), ctx)
+        });
+
+        let mut stream1 = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
         let _ = tx.send(Bytes::from("hello")).await;
 
         let chunk = stream1.next().await;
@@ -317,10 +1309,202 @@ mod tests {
 
         let _ = tx.send(Bytes::from("hello")).await;
 
+        // The stream ends with an error.
+        let chunk = stream1.next().await;
+        assert!(chunk.unwrap().is_err());
         let chunk = stream1.next().await;
         assert!(chunk.is_none());
     }
 
+    #[actix_rt::test]
+    async fn test_wait_for_data() {
+        let (mut tx, mut stream) = BroadcasterStream::new_for_test();
+
+        let _ = tx.send(Bytes::from("hello")).await;
+        assert!(stream.wait_for_data().await);
+        // The chunk is not consumed.
+        assert!(stream.wait_for_data().await);
+        assert_eq!(stream.next().await.unwrap().unwrap(), Bytes::from("hello"));
+
+        drop(tx);
+        assert!(!stream.wait_for_data().await);
+    }
+
+    #[actix_rt::test]
+    async fn test_restart() {
+        let (tx, rx) = mpsc::channel(1);
+        let (mut tx2, rx2) = mpsc::channel(1);
+
+        let restarter = FakeRestarter {
+            failed: true,
+            sources: vec![DataSource(rx2)],
+        };
+        let broadcaster = Broadcaster::create(|ctx| {
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 1000, 1000,
+                ZeroReadPolicy::Eof, &Default::default(), ctx)
+                .with_restarter(Box::new(restarter), 1)
+        });
+
+        let mut stream1 = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
+        // The first source exits with an error.
+        drop(tx);
+
+        let _ = tx2.send(Bytes::from("hello")).await;
+        let chunk = stream1.next().await;
+        assert!(chunk.is_some());
+
+        // No more restart.  The stream ends with an error.
+        drop(tx2);
+        let chunk = stream1.next().await;
+        assert!(chunk.unwrap().is_err());
+        let chunk = stream1.next().await;
+        assert!(chunk.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_restart_eos() {
+        let (tx, rx) = mpsc::channel(1);
+        let (_tx2, rx2) = mpsc::channel(1);
+
+        // The source is not restarted when it reaches EOS normally.
+        let restarter = FakeRestarter {
+            failed: false,
+            sources: vec![DataSource(rx2)],
+        };
+        let broadcaster = Broadcaster::create(|ctx| {
+            Broadcaster::new(
+                Default::default(), DataSource(rx), 1000, 1000,
+                ZeroReadPolicy::Eof, &Default::default(), ctx)
+                .with_restarter(Box::new(restarter), 1)
+        });
+
+        let mut stream1 = broadcaster.send(SubscribeMessage {
+            id: SubscriberId::new(Default::default(), 1),
+            drop_policy: DropPolicy::DropChunk,
+            stop_streaming: None,
+        }).await.unwrap();
+
+        drop(tx);
+
+        let chunk = stream1.next().await;
+        assert!(chunk.is_none());
+    }
+
+    #[test]
+    fn test_restart_delay() {
+        let mut broadcaster = create_broadcaster();
+        assert_eq!(broadcaster.restart_delay(), Duration::from_millis(100));
+        broadcaster.num_restarts = 1;
+        assert_eq!(broadcaster.restart_delay(), Duration::from_millis(200));
+        broadcaster.num_restarts = 10;
+        assert_eq!(broadcaster.restart_delay(), Duration::from_secs(5));
+        broadcaster.num_restarts = 100;
+        assert_eq!(broadcaster.restart_delay(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_timeshift_buffer() {
+        let mut timeshift = TimeshiftBuffer::new(Duration::from_secs(60), 8);
+
+        let (chunks, truncated) =
+            timeshift.chunks_since(Duration::from_secs(1));
+        assert!(chunks.is_empty());
+        assert!(truncated);
+
+        timeshift.push(Bytes::from("1234"));
+        timeshift.push(Bytes::from("5678"));
+        assert_eq!(timeshift.total_bytes, 8);
+
+        let (chunks, _) = timeshift.chunks_since(Duration::from_secs(1));
+        assert_eq!(chunks, vec![Bytes::from("1234"), Bytes::from("5678")]);
+
+        timeshift.push(Bytes::from("9"));
+        assert_eq!(timeshift.total_bytes, 5);
+
+        let (chunks, _) = timeshift.chunks_since(Duration::from_secs(1));
+        assert_eq!(chunks, vec![Bytes::from("5678"), Bytes::from("9")]);
+
+        let (chunks, truncated) =
+            timeshift.chunks_since(Duration::from_secs(0));
+        assert!(chunks.is_empty());
+        assert!(!truncated);
+
+        let mut timeshift = TimeshiftBuffer::new(Duration::from_secs(0), 1024);
+        timeshift.push(Bytes::from("1234"));
+        std::thread::sleep(Duration::from_millis(10));
+        timeshift.push(Bytes::from("5678"));
+        assert_eq!(timeshift.chunks.len(), 1);
+        assert_eq!(timeshift.total_bytes, 4);
+    }
+
+    #[test]
+    fn test_drop_chunk() {
+        let (sender, _receiver) = mpsc::channel(1);
+        let mut subscriber = Subscriber::new(
+            SubscriberId::new(Default::default(), 1), sender,
+            DropPolicy::DropChunk);
+
+        // The first drop is always logged.
+        let interval = Duration::from_secs(60);
+        assert_eq!(subscriber.drop_chunk(10, interval), Some(1));
+        assert_eq!(subscriber.drop_chunk(10, interval), None);
+        assert_eq!(subscriber.drop_chunk(10, interval), None);
+        assert_eq!(subscriber.num_dropped, 2);
+
+        // Drops are summarized after the interval.
+        let interval = Duration::from_secs(0);
+        assert_eq!(subscriber.drop_chunk(10, interval), Some(3));
+        assert_eq!(subscriber.num_dropped, 0);
+        assert_eq!(subscriber.drop_chunk(10, interval), Some(1));
+
+        // Dropped bytes are never reset.
+        assert_eq!(subscriber.dropped_bytes, 50);
+    }
+
+    fn create_broadcaster() -> Broadcaster {
+        Broadcaster {
+            id: Default::default(),
+            subscribers: Vec::new(),
+            time_limit: Duration::from_millis(1000),
+            drop_log_interval: Duration::from_millis(1000),
+            last_received: Instant::now(),
+            zero_read_policy: ZeroReadPolicy::Eof,
+            max_chunks: Broadcaster::MAX_CHUNKS,
+            chunk_size: Broadcaster::CHUNK_SIZE,
+            source: None,
+            restarter: None,
+            max_restarts: 0,
+            num_restarts: 0,
+            timeshift: None,
+            psi_cache: None,
+            send_cached_psi: false,
+        }
+    }
+
+    struct FakeRestarter {
+        failed: bool,
+        sources: Vec<DataSource>,
+    }
+
+    impl SourceRestarter for FakeRestarter {
+        fn has_failed(&mut self) -> Option<bool> {
+            Some(self.failed)
+        }
+
+        fn restart(&mut self) -> Result<Box<dyn AsyncRead + Unpin>, Error> {
+            match self.sources.pop() {
+                Some(source) => Ok(Box::new(source)),
+                None => Err(Error::SessionNotFound),
+            }
+        }
+    }
+
     // we can use `futures::stream::repeat(1)` as data source in tests once
     // actix/actix/pull/363 is release.
     struct DataSource(mpsc::Receiver<Bytes>);
@@ -1,24 +1,48 @@
+use std::collections::VecDeque;
+use std::future::Future;
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use bytes::{Bytes, BytesMut};
 use tokio::io::AsyncRead;
 use tokio::stream::Stream;
+use tokio::time::{Delay, Instant};
 
 // ChunkStream provides a stream of data chunks with a specific maximum size.
 //
 // There is a similar types in tokio like tokio_codec::FramedRead, but these
 // types don't have methods to change the internal chunk size
 // `INITIAL_CAPACITY` that is fixed to 8 KB.
+//
+// A zero-length read is treated as EOF by default.  When a retry interval is
+// specified, the reader is polled again after the interval instead, for
+// sources which may resume after returning no data.  In this case, the stream
+// never ends and the consumer has to stop reading by itself.
 pub struct ChunkStream<R> {
     reader: R,
     chunk_size: usize,
+    retry_interval: Option<Duration>,
+    delay: Option<Delay>,
 }
 
 impl<R> ChunkStream<R> {
     pub fn new(reader: R, chunk_size: usize) -> Self {
-        ChunkStream { reader, chunk_size }
+        ChunkStream { reader, chunk_size, retry_interval: None, delay: None }
+    }
+
+    pub fn with_retry(
+        reader: R,
+        chunk_size: usize,
+        retry_interval: Duration,
+    ) -> Self {
+        ChunkStream {
+            reader,
+            chunk_size,
+            retry_interval: Some(retry_interval),
+            delay: None,
+        }
     }
 }
 
@@ -32,15 +56,30 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut Context
     ) -> Poll<Option<Self::Item>> {
+        if let Some(ref mut delay) = self.delay {
+            if Pin::new(delay).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
         let mut chunk = BytesMut::with_capacity(self.chunk_size);
         loop {
             match Pin::new(&mut self.reader).poll_read_buf(cx, &mut chunk) {
                 Poll::Ready(Ok(0)) => {
-                    if chunk.is_empty() {
-                        return Poll::Ready(None);
-                    } else {
+                    if !chunk.is_empty() {
                         return Poll::Ready(Some(Ok(chunk.freeze())));
                     }
+                    match self.retry_interval {
+                        Some(interval) => {
+                            // Register the waker with the delay.
+                            let mut delay = tokio::time::delay_for(interval);
+                            if Pin::new(&mut delay).poll(cx).is_pending() {
+                                self.delay = Some(delay);
+                                return Poll::Pending;
+                            }
+                        }
+                        None => return Poll::Ready(None),
+                    }
                 }
                 Poll::Ready(Ok(_)) => {
                     if chunk.len() >= self.chunk_size {
@@ -61,3 +100,427 @@ where
         }
     }
 }
+
+// CoalescedChunkStream coalesces small chunks from a stream into a chunk
+// having at least `size` bytes, in order to reduce the number of writes to a
+// socket.
+//
+// Coalesced data is flushed when `timeout` passes since the first chunk was
+// buffered, even if the data is smaller than `size`.  Chunks are passed
+// through without buffering if `size` is 0.
+pub struct CoalescedChunkStream<S> {
+    stream: S,
+    size: usize,
+    timeout: Duration,
+    buf: BytesMut,
+    delay: Option<Delay>,
+    ended: bool,
+}
+
+impl<S> CoalescedChunkStream<S> {
+    pub fn new(stream: S, size: usize, timeout: Duration) -> Self {
+        CoalescedChunkStream {
+            stream,
+            size,
+            timeout,
+            buf: BytesMut::new(),
+            delay: None,
+            ended: false,
+        }
+    }
+
+    fn flush(&mut self) -> Poll<Option<io::Result<Bytes>>> {
+        self.delay = None;
+        Poll::Ready(Some(Ok(self.buf.split().freeze())))
+    }
+}
+
+impl<S> Stream for CoalescedChunkStream<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context
+    ) -> Poll<Option<Self::Item>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+        loop {
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    if self.buf.is_empty() {
+                        if chunk.len() >= self.size {
+                            return Poll::Ready(Some(Ok(chunk)));
+                        }
+                        self.delay = Some(tokio::time::delay_for(self.timeout));
+                    }
+                    self.buf.extend_from_slice(&chunk);
+                    if self.buf.len() >= self.size {
+                        return self.flush();
+                    }
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Poll::Ready(None) => {
+                    self.ended = true;
+                    if self.buf.is_empty() {
+                        return Poll::Ready(None);
+                    } else {
+                        return self.flush();
+                    }
+                }
+                Poll::Pending => {
+                    let expired = match self.delay {
+                        Some(ref mut delay) =>
+                            Pin::new(delay).poll(cx).is_ready(),
+                        None => false,
+                    };
+                    if expired {
+                        return self.flush();
+                    }
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+// JitterBufferStream absorbs bursts of chunks from a stream which delivers
+// data unevenly.
+//
+// Chunks are not delivered until at least `size` bytes are buffered or `time`
+// passes since the first chunk was buffered, whichever comes first.  After
+// that, chunks are paced at the average rate of incoming data so that the
+// buffered data works as a cushion.  The cushion is rebuilt in the same way
+// when the buffer runs out.  Buffered chunks are delivered without pacing
+// when the stream ends.
+//
+// The upstream is not polled while the buffer holds `capacity()` bytes or
+// more, so that a slow client exerts backpressure on the upstream instead of
+// growing the buffer without bound.
+//
+// Chunks are passed through without buffering if both `size` and `time` are
+// 0.
+pub struct JitterBufferStream<S> {
+    stream: S,
+    size: usize,
+    time: Duration,
+    capacity: usize,
+    chunks: VecDeque<Bytes>,
+    buffered: usize,
+    received: u64,
+    received_since: Option<Instant>,
+    sent: u64,
+    sending_since: Option<Instant>,
+    filling_since: Option<Instant>,
+    delay: Option<Delay>,
+    ended: bool,
+}
+
+impl<S> JitterBufferStream<S> {
+    const CAPACITY_FACTOR: usize = 4;
+    // Used when only `time` is specified.
+    const DEFAULT_CAPACITY: usize = 8 * 1024 * 1024;  // 8MiB
+
+    pub fn new(stream: S, size: usize, time: Duration) -> Self {
+        let capacity = if size > 0 {
+            size.saturating_mul(Self::CAPACITY_FACTOR)
+        } else {
+            Self::DEFAULT_CAPACITY
+        };
+        JitterBufferStream {
+            stream,
+            size,
+            time,
+            capacity,
+            chunks: VecDeque::new(),
+            buffered: 0,
+            received: 0,
+            received_since: None,
+            sent: 0,
+            sending_since: None,
+            filling_since: None,
+            delay: None,
+            ended: false,
+        }
+    }
+
+    fn is_disabled(&self) -> bool {
+        self.size == 0 && self.time == Duration::from_secs(0)
+    }
+
+    fn push(&mut self, chunk: Bytes) {
+        let now = Instant::now();
+        if self.received_since.is_none() {
+            self.received_since = Some(now);
+        }
+        if self.sending_since.is_none() && self.filling_since.is_none() {
+            self.filling_since = Some(now);
+        }
+        self.received += chunk.len() as u64;
+        self.buffered += chunk.len();
+        self.chunks.push_back(chunk);
+    }
+
+    fn pop(&mut self) -> Option<Bytes> {
+        let chunk = self.chunks.pop_front()?;
+        self.buffered -= chunk.len();
+        self.sent += chunk.len() as u64;
+        Some(chunk)
+    }
+
+    // Returns the time when the next chunk can be sent, or `None` if more
+    // chunks have to be buffered.
+    fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        match self.sending_since {
+            Some(sending_since) => {
+                let received_since = self.received_since?;
+                if self.received == 0 {
+                    return Some(now);
+                }
+                let elapsed = now.duration_since(received_since);
+                Some(sending_since + elapsed.mul_f64(
+                    self.sent as f64 / self.received as f64))
+            }
+            None => {
+                if self.size > 0 && self.buffered >= self.size {
+                    return Some(now);
+                }
+                if self.time > Duration::from_secs(0) {
+                    return self.filling_since.map(|since| since + self.time);
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<S> Stream for JitterBufferStream<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context
+    ) -> Poll<Option<Self::Item>> {
+        if self.is_disabled() {
+            return Pin::new(&mut self.stream).poll_next(cx);
+        }
+        // Buffer chunks available at this point as long as the buffer has room.
+        while !self.ended && self.buffered < self.capacity {
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => self.push(chunk),
+                Poll::Ready(Some(Err(err))) => {
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Poll::Ready(None) => self.ended = true,
+                Poll::Pending => break,
+            }
+        }
+        if self.ended {
+            self.delay = None;
+            return Poll::Ready(self.pop().map(Ok));
+        }
+        if self.chunks.is_empty() {
+            // Rebuild the cushion.
+            self.sending_since = None;
+            self.delay = None;
+            return Poll::Pending;
+        }
+        loop {
+            let now = Instant::now();
+            let deadline = match self.next_deadline(now) {
+                Some(deadline) => deadline,
+                None => return Poll::Pending,
+            };
+            if deadline <= now {
+                if self.sending_since.is_none() {
+                    self.filling_since = None;
+                    self.sending_since = Some(now);
+                    self.sent = 0;
+                }
+                self.delay = None;
+                return Poll::Ready(self.pop().map(Ok));
+            }
+            // Register the waker with the delay.
+            let mut delay = tokio::time::delay_until(deadline);
+            if Pin::new(&mut delay).poll(cx).is_pending() {
+                self.delay = Some(delay);
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::stream::StreamExt;
+
+    #[actix_rt::test]
+    async fn test_chunk_stream() {
+        // The first empty read is treated as EOF.
+        let reader = ZeroReadSource::new(&["", "12"]);
+        let chunks: Vec<Bytes> = ChunkStream::new(reader, 10)
+            .map(|result| result.unwrap())
+            .collect().await;
+        assert!(chunks.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_chunk_stream_retry() {
+        let reader = ZeroReadSource::new(&["", "12", "", "34"]);
+        let mut stream = ChunkStream::with_retry(
+            reader, 10, Duration::from_millis(10));
+        assert_eq!(stream.next().await.unwrap().unwrap(), Bytes::from("12"));
+        assert_eq!(stream.next().await.unwrap().unwrap(), Bytes::from("34"));
+        // The stream never ends.
+        let result = tokio::time::timeout(
+            Duration::from_millis(50), stream.next()).await;
+        assert!(result.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_coalesced_chunk_stream() {
+        let stream = futures::stream::iter(vec![
+            Ok(Bytes::from("12")),
+            Ok(Bytes::from("34")),
+            Ok(Bytes::from("5")),
+        ]);
+        let stream = CoalescedChunkStream::new(
+            stream, 3, Duration::from_secs(60));
+        let chunks: Vec<Bytes> = stream
+            .map(|result| result.unwrap())
+            .collect().await;
+        assert_eq!(chunks, vec![Bytes::from("1234"), Bytes::from("5")]);
+    }
+
+    #[actix_rt::test]
+    async fn test_coalesced_chunk_stream_pass_through() {
+        let stream = futures::stream::iter(vec![
+            Ok(Bytes::from("12")),
+            Ok(Bytes::from("34")),
+        ]);
+        let stream = CoalescedChunkStream::new(
+            stream, 0, Duration::from_secs(60));
+        let chunks: Vec<Bytes> = stream
+            .map(|result| result.unwrap())
+            .collect().await;
+        assert_eq!(chunks, vec![Bytes::from("12"), Bytes::from("34")]);
+    }
+
+    #[actix_rt::test]
+    async fn test_coalesced_chunk_stream_timeout() {
+        let stream = futures::stream::iter(vec![Ok(Bytes::from("1"))])
+            .chain(futures::stream::pending());
+        let mut stream = CoalescedChunkStream::new(
+            stream, 3, Duration::from_millis(10));
+        let result = tokio::time::timeout(
+            Duration::from_secs(1), stream.next()).await;
+        assert_eq!(result.unwrap().unwrap().unwrap(), Bytes::from("1"));
+    }
+
+    #[actix_rt::test]
+    async fn test_jitter_buffer_stream_pass_through() {
+        let stream = futures::stream::iter(vec![
+            Ok(Bytes::from("12")),
+            Ok(Bytes::from("34")),
+        ]);
+        let stream = JitterBufferStream::new(
+            stream, 0, Duration::from_secs(0));
+        let chunks: Vec<Bytes> = stream
+            .map(|result| result.unwrap())
+            .collect().await;
+        assert_eq!(chunks, vec![Bytes::from("12"), Bytes::from("34")]);
+    }
+
+    #[actix_rt::test]
+    async fn test_jitter_buffer_stream_size() {
+        let stream = futures::stream::iter(vec![
+            Ok(Bytes::from("12")),
+            Ok(Bytes::from("34")),
+        ]).chain(futures::stream::pending());
+        let mut stream = JitterBufferStream::new(
+            stream, 4, Duration::from_secs(0));
+        let result = tokio::time::timeout(
+            Duration::from_secs(1), stream.next()).await;
+        assert_eq!(result.unwrap().unwrap().unwrap(), Bytes::from("12"));
+
+        let stream = futures::stream::iter(vec![Ok(Bytes::from("12"))])
+            .chain(futures::stream::pending());
+        let mut stream = JitterBufferStream::new(
+            stream, 4, Duration::from_secs(0));
+        let result = tokio::time::timeout(
+            Duration::from_millis(50), stream.next()).await;
+        assert!(result.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_jitter_buffer_stream_time() {
+        let stream = futures::stream::iter(vec![Ok(Bytes::from("1"))])
+            .chain(futures::stream::pending());
+        let mut stream = JitterBufferStream::new(
+            stream, 0, Duration::from_millis(50));
+        let start = Instant::now();
+        let result = tokio::time::timeout(
+            Duration::from_secs(1), stream.next()).await;
+        assert_eq!(result.unwrap().unwrap().unwrap(), Bytes::from("1"));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[actix_rt::test]
+    async fn test_jitter_buffer_stream_end() {
+        let stream = futures::stream::iter(vec![
+            Ok(Bytes::from("12")),
+            Ok(Bytes::from("34")),
+        ]);
+        let stream = JitterBufferStream::new(
+            stream, 100, Duration::from_secs(60));
+        let chunks: Vec<Bytes> = stream
+            .map(|result| result.unwrap())
+            .collect().await;
+        assert_eq!(chunks, vec![Bytes::from("12"), Bytes::from("34")]);
+    }
+
+    #[actix_rt::test]
+    async fn test_jitter_buffer_stream_capacity() {
+        // An upstream which always has a chunk ready.
+        let stream = futures::stream::iter(
+            std::iter::repeat_with(|| Ok(Bytes::from("12"))));
+        let mut stream = JitterBufferStream::new(
+            stream, 4, Duration::from_secs(60));
+        let result = tokio::time::timeout(
+            Duration::from_secs(1), stream.next()).await;
+        assert_eq!(result.unwrap().unwrap().unwrap(), Bytes::from("12"));
+        assert!(stream.buffered <= stream.capacity);
+        assert_eq!(stream.capacity, 16);
+    }
+
+    // Returns an empty read for an empty string, and after all strings have
+    // been read.
+    struct ZeroReadSource(VecDeque<&'static str>);
+
+    impl ZeroReadSource {
+        fn new(data: &[&'static str]) -> Self {
+            ZeroReadSource(data.iter().cloned().collect())
+        }
+    }
+
+    impl AsyncRead for ZeroReadSource {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context,
+            buf: &mut [u8]
+        ) -> Poll<io::Result<usize>> {
+            let data = self.0.pop_front().unwrap_or("");
+            buf[..data.len()].copy_from_slice(data.as_bytes());
+            Poll::Ready(Ok(data.len()))
+        }
+    }
+}
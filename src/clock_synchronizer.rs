@@ -5,19 +5,21 @@ use failure::Error;
 use log;
 use serde::Deserialize;
 use serde_json;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 #[cfg(test)]
 use serde::Serialize;
 
-use crate::command_util;
+use crate::datetime_ext::Jst;
 use crate::epg::*;
+use crate::job::JobContext;
 use crate::models::*;
 use crate::tuner::*;
 
 pub struct ClockSynchronizer {
     command: String,
     channels: Vec<EpgChannel>,
+    context: JobContext,
     stream_manager: Recipient<StartStreamingMessage>,
 }
 
@@ -25,14 +27,25 @@ pub struct ClockSynchronizer {
 //       EitCollector and ServiceScanner.
 
 impl ClockSynchronizer {
-    const LABEL: &'static str = "clock-synchronizer";
+    pub const LABEL: &'static str = "clock-synchronizer";
 
     pub fn new(
         command: String,
         channels: Vec<EpgChannel>,
         stream_manager: Recipient<StartStreamingMessage>,
     ) -> Self {
-        ClockSynchronizer { command, channels, stream_manager }
+        ClockSynchronizer {
+            command,
+            channels,
+            context: JobContext::new(Self::LABEL),
+            stream_manager,
+        }
+    }
+
+    // `JobContext::new(Self::LABEL)` is used by default.
+    pub fn with_context(mut self, context: JobContext) -> Self {
+        self.context = context;
+        self
     }
 
     pub async fn sync_clocks(
@@ -43,8 +56,10 @@ impl ClockSynchronizer {
         let mut results = Vec::new();
 
         for channel in self.channels.iter() {
+            let _permit = self.context.acquire_tuner().await;
             let result = match Self::sync_clocks_in_channel(
-                &channel, &self.command, &self.stream_manager).await {
+                &channel, &self.command, &self.context,
+                &self.stream_manager).await {
                 Ok(clocks) => {
                     let mut map = HashMap::new();
                     for clock in clocks.into_iter() {
@@ -70,19 +85,14 @@ impl ClockSynchronizer {
     async fn sync_clocks_in_channel(
         channel: &EpgChannel,
         command: &str,
+        context: &JobContext,
         stream_manager: &Recipient<StartStreamingMessage>,
     ) -> Result<Vec<SyncClock>, Error> {
         log::debug!("Synchronizing clocks in {}...", channel.name);
 
-        let user = TunerUser {
-            info: TunerUserInfo::Job { name: Self::LABEL.to_string() },
-            priority: (-1).into(),
-        };
-
-        let stream = stream_manager.send(StartStreamingMessage {
-            channel: channel.clone(),
-            user
-        }).await??;
+        let user = context.make_user(None);
+        let stream =
+            context.start_streaming(channel, &user, stream_manager).await?;
 
         let template = mustache::compile_str(command)?;
         let data = mustache::MapBuilder::new()
@@ -91,15 +101,14 @@ impl ClockSynchronizer {
             .build();
         let cmd = template.render_data_to_string(&data)?;
 
-        let mut pipeline = command_util::spawn_pipeline(
-            vec![cmd], stream.id())?;
+        let mut pipeline =
+            context.spawn_pipeline(channel, vec![cmd], stream.id()).await?;
 
         let (input, mut output) = pipeline.take_endpoints().unwrap();
 
         let handle = tokio::spawn(stream.pipe(input));
 
-        let mut buf = Vec::new();
-        output.read_to_end(&mut buf).await?;
+        let result = Self::read_clocks(&mut output).await;
 
         // Explicitly dropping the output of the pipeline is needed.  The output
         // holds the child processes and it kills them when dropped.
@@ -109,15 +118,78 @@ impl ClockSynchronizer {
         // streaming in the next iteration.
         let _ = handle.await;
 
-        failure::ensure!(!buf.is_empty(), "No clock, maybe out of service");
+        let clocks = result?;
+        log::debug!("Synchronized {} clocks in {}",
+                    clocks.len(), channel.name);
 
-        let clocks: Vec<SyncClock> = serde_json::from_slice(&buf)?;
-        log::debug!("Synchronized {} clocks in {}", clocks.len(), channel.name);
+        Ok(clocks)
+    }
+
+    // Clocks are parsed as soon as they arrive, and each of them is stamped
+    // with the time when it arrives unless the command outputs `syncedAt`.
+    // Stamping them after the command exits would shift the offsets by the
+    // time to terminate the command.
+    async fn read_clocks<R>(output: &mut R) -> Result<Vec<SyncClock>, Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = Vec::new();
+        let mut received = false;
+        let mut clocks = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            let n = output.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            let now = Jst::now().timestamp_millis();
+            received = true;
+            buf.extend_from_slice(&chunk[..n]);
+            let mut iter = serde_json::Deserializer::from_slice(&buf)
+                .into_iter::<SyncClockOutput>();
+            loop {
+                match iter.next() {
+                    Some(Ok(output)) => {
+                        for mut clock in output.into_clocks() {
+                            clock.clock.synced_at.get_or_insert(now);
+                            clocks.push(clock);
+                        }
+                    }
+                    Some(Err(err)) if err.is_eof() => break,
+                    Some(Err(err)) => return Err(err.into()),
+                    None => break,
+                }
+            }
+            let consumed = iter.byte_offset();
+            buf.drain(..consumed);
+        }
+
+        failure::ensure!(received, "No clock, maybe out of service");
+        failure::ensure!(buf.iter().all(u8::is_ascii_whitespace),
+                         "Incomplete output");
 
         Ok(clocks)
     }
 }
 
+// The command outputs either a JSON array of clocks or JSON lines each of
+// which contains a clock.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SyncClockOutput {
+    Clocks(Vec<SyncClock>),
+    Clock(SyncClock),
+}
+
+impl SyncClockOutput {
+    fn into_clocks(self) -> Vec<SyncClock> {
+        match self {
+            SyncClockOutput::Clocks(clocks) => clocks,
+            SyncClockOutput::Clock(clock) => vec![clock],
+        }
+    }
+}
+
 #[derive(Clone, Deserialize)]
 #[cfg_attr(test, derive(Serialize))]
 #[serde(rename_all = "camelCase")]
@@ -131,6 +203,8 @@ struct SyncClock {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Arc;
+    use tokio::sync::Semaphore;
     use crate::broadcaster::BroadcasterStream;
     use crate::error::Error;
     use crate::mpeg_ts_stream::MpegTsStream;
@@ -165,7 +239,7 @@ mod tests {
             nid: 1.into(),
             tsid: 2.into(),
             sid: 3.into(),
-            clock: Clock { pcr: 10, time: 11 },
+            clock: Clock { pcr: 10, time: 11, synced_at: None },
         }];
 
         let cmd = format!(
@@ -175,6 +249,8 @@ mod tests {
         let results = sync.sync_clocks().await;
         assert!(results[0].1.is_some());
         assert_eq!(results[0].1.as_ref().unwrap().len(), 1);
+        assert!(results[0].1.as_ref().unwrap().values()
+                .all(|clock| clock.synced_at.is_some()));
 
         // Emulate out of services by using `false`
         let cmd = "false".to_string();
@@ -182,5 +258,129 @@ mod tests {
             cmd, channels.clone(), mock.clone().recipient());
         let results = sync.sync_clocks().await;
         assert!(results[0].1.is_none());
+
+        // Incomplete output.
+        let cmd = "echo '[{'".to_string();
+        let sync = ClockSynchronizer::new(
+            cmd, channels.clone(), mock.clone().recipient());
+        let results = sync.sync_clocks().await;
+        assert!(results[0].1.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_read_clocks() {
+        let clock = |sid: u16, synced_at: Option<i64>| SyncClock {
+            nid: 1.into(),
+            tsid: 2.into(),
+            sid: sid.into(),
+            clock: Clock { pcr: 10, time: 11, synced_at },
+        };
+
+        // Each line is stamped when it arrives.
+        let script = format!(
+            "echo {}; sleep 1; echo {}",
+            shell_words::quote(&serde_json::to_string(&clock(3, None))
+                               .unwrap()),
+            shell_words::quote(&serde_json::to_string(&vec![clock(4, None)])
+                               .unwrap()));
+        let mut child = tokio::process::Command::new("sh")
+            .arg("-c").arg(&script)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let clocks =
+            ClockSynchronizer::read_clocks(&mut stdout).await.unwrap();
+        assert_eq!(clocks.len(), 2);
+        let first = clocks[0].clock.synced_at.unwrap();
+        let second = clocks[1].clock.synced_at.unwrap();
+        assert!(second - first >= 500);
+
+        // `syncedAt` from the command is used as it is.
+        let json = serde_json::to_vec(&clock(3, Some(1))).unwrap();
+        let clocks = ClockSynchronizer::read_clocks(&mut json.as_slice())
+            .await.unwrap();
+        assert_eq!(clocks[0].clock.synced_at, Some(1));
+
+        let result = ClockSynchronizer::read_clocks(&mut &b""[..]).await;
+        assert!(result.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_sync_clocks_with_label() {
+        let mock = Mock::mock(Box::new(|msg, ctx| {
+            if let Some(msg) = msg.downcast_ref::<StartStreamingMessage>() {
+                match msg.user.info {
+                    TunerUserInfo::Job { ref name } =>
+                        assert_eq!(name, "sync-clocks-gr"),
+                    _ => panic!("Must be a job"),
+                }
+                let (_, stream) = BroadcasterStream::new_for_test();
+                let result: Result<_, Error> = Ok(MpegTsStream::new(
+                    Default::default(), stream, ctx.address().recipient()));
+                Box::new(Some(result))
+            } else if let Some(_) = msg.downcast_ref::<StopStreamingMessage>() {
+                Box::new(Some(()))
+            } else {
+                unimplemented!();
+            }
+        })).start();
+
+        let channels = vec![EpgChannel {
+            name: "channel".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "0".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        }];
+
+        let sync = ClockSynchronizer::new(
+            "echo '[]'".to_string(), channels, mock.clone().recipient())
+            .with_context(JobContext::new(ClockSynchronizer::LABEL)
+                          .with_label(Some("sync-clocks-gr".to_string())));
+        let results = sync.sync_clocks().await;
+        assert!(results[0].1.is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_sync_clocks_with_tuner_semaphore() {
+        let mock = Mock::mock(Box::new(|msg, ctx| {
+            if let Some(_) = msg.downcast_ref::<StartStreamingMessage>() {
+                let (_, stream) = BroadcasterStream::new_for_test();
+                let result: Result<_, Error> = Ok(MpegTsStream::new(
+                    Default::default(), stream, ctx.address().recipient()));
+                Box::new(Some(result))
+            } else if let Some(_) = msg.downcast_ref::<StopStreamingMessage>() {
+                Box::new(Some(()))
+            } else {
+                unimplemented!();
+            }
+        })).start();
+
+        let channels = vec![EpgChannel {
+            name: "channel".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "0".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        }];
+
+        // No tuner is available for jobs.
+        let semaphore = Arc::new(Semaphore::new(0));
+        let sync = ClockSynchronizer::new(
+            "echo '[]'".to_string(), channels, mock.clone().recipient())
+            .with_context(JobContext::new(ClockSynchronizer::LABEL)
+                          .with_tuner_semaphore(semaphore.clone()));
+        let handle = tokio::spawn(sync.sync_clocks());
+        tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+        assert_eq!(semaphore.available_permits(), 0);
+
+        semaphore.add_permits(1);
+        let results = handle.await.unwrap();
+        assert!(results[0].1.is_some());
+        // The permit has been released.
+        assert_eq!(semaphore.available_permits(), 1);
     }
 }
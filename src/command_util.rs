@@ -3,20 +3,45 @@ use std::fmt;
 use std::io;
 use std::marker::{Copy, Unpin};
 use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::pin::Pin;
-use std::process::{Command, Child, ChildStdin, ChildStdout, Stdio};
+use std::process::{
+    Command, Child, ChildStderr, ChildStdin, ChildStdout, Stdio,
+};
 use std::task::{Poll, Context};
+use std::time::{Duration, Instant};
 
 use failure::Fail;
 use tokio::prelude::*;
 use tokio::io::BufReader;
 use tokio::sync::broadcast;
 
+use crate::config::ProcessLimitsConfig;
 use crate::tokio_snippet;
 
 pub fn spawn_process(
     command: &str,
     input: Stdio,
+) -> Result<Child, Error> {
+    spawn_process_internal(
+        command, input, false, None, &ProcessLimitsConfig::default())
+}
+
+// The stderr of the process is piped if `capture_stderr` is true.  In this
+// case, the caller must read it.
+//
+// The process joins the process group `pgid` if it's specified.  A new process
+// group is created if `pgid` is 0.
+//
+// The niceness and rlimits in `limits` are applied before the command is
+// executed.  The process is moved into the cgroup after it has been spawned.
+fn spawn_process_internal(
+    command: &str,
+    input: Stdio,
+    capture_stderr: bool,
+    pgid: Option<libc::pid_t>,
+    limits: &ProcessLimitsConfig,
 ) -> Result<Child, Error> {
     let words = match shell_words::split(command) {
         Ok(words) => words,
@@ -26,20 +51,67 @@ pub fn spawn_process(
     let (prog, args) = words.split_first().unwrap();
     let debug_child_process =
         env::var_os("MIRAKC_DEBUG_CHILD_PROCESS").is_some();
-    let stderr = if debug_child_process {
+    let stderr = if debug_child_process || capture_stderr {
         Stdio::piped()
     } else {
         Stdio::null()
     };
-    let mut child = Command::new(prog)
-        .args(args)
-        .stdin(input)
-        .stdout(Stdio::piped())
-        .stderr(stderr)
-        .spawn()
+    let mut cmd = Command::new(prog);
+    cmd.args(args).stdin(input).stdout(Stdio::piped()).stderr(stderr);
+    // `limits.nice` is an increment to the niceness of this process like
+    // nice(1).  The resulting niceness is computed before fork() because
+    // getpriority() is not async-signal-safe.
+    let nice = limits.nice.map(|increment| {
+        // Safe because getpriority() has no side effect.
+        unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) + increment }
+    });
+    let max_memory = limits.max_memory;
+    let max_cpu_time = limits.max_cpu_time;
+    if pgid.is_some() || nice.is_some() || max_memory.is_some() ||
+        max_cpu_time.is_some() {
+        // Safe because setpgid(), setpriority() and setrlimit() are
+        // async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(pgid) = pgid {
+                    if libc::setpgid(0, pgid) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(max) = max_memory {
+                    let limit = libc::rlimit {
+                        rlim_cur: max as libc::rlim_t,
+                        rlim_max: max as libc::rlim_t,
+                    };
+                    if libc::setrlimit(libc::RLIMIT_AS, &limit) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(max) = max_cpu_time {
+                    let limit = libc::rlimit {
+                        rlim_cur: max as libc::rlim_t,
+                        rlim_max: max as libc::rlim_t,
+                    };
+                    if libc::setrlimit(libc::RLIMIT_CPU, &limit) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+    let mut child = cmd.spawn()
         .map_err(|err| Error::UnableToSpawn(command.to_string(), err))?;
+    if let Some(ref cgroup) = limits.cgroup {
+        move_into_cgroup(cgroup, child.id());
+    }
     if cfg!(not(test)) {
-        if debug_child_process {
+        if debug_child_process && !capture_stderr {
             let prog = prog.to_string();
             let child_id = child.id();
             let child_stderr = tokio_snippet::stdio(child.stderr.take())
@@ -65,6 +137,23 @@ pub fn spawn_process(
     Ok(child)
 }
 
+// The process is not killed on failure because it has already been spawned.
+#[cfg(target_os = "linux")]
+fn move_into_cgroup(cgroup: &str, pid: u32) {
+    let path = Path::new(cgroup).join("cgroup.procs");
+    match std::fs::write(&path, pid.to_string()) {
+        Ok(_) => log::debug!("Moved {} into {}", pid, cgroup),
+        Err(err) => log::warn!("Failed to move {} into {}: {}",
+                               pid, cgroup, err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn move_into_cgroup(cgroup: &str, pid: u32) {
+    log::warn!("Cannot move {} into {}: cgroups are supported only on Linux",
+               pid, cgroup);
+}
+
 // Spawn processes for input commands and build a pipeline, then returns it.
 // Input and output endpoints can be took from the pipeline only once
 // respectively.
@@ -75,9 +164,69 @@ pub fn spawn_pipeline<T>(
 where
     T: Copy + fmt::Display + Unpin
 {
-    let mut pipeline = CommandPipeline::new(id);
-    for command in commands.into_iter() {
-        pipeline.spawn(command)?;
+    build_pipeline(commands, id, false, &ProcessLimitsConfig::default())
+}
+
+// Same as `spawn_pipeline()` except that `limits` are applied to all processes
+// in the pipeline.  `limits.kill_grace_period` is used when the pipeline is
+// dropped.
+pub fn spawn_pipeline_with_limits<T>(
+    commands: Vec<String>,
+    id: T,
+    limits: &ProcessLimitsConfig,
+) -> Result<CommandPipeline<T>, Error>
+where
+    T: Copy + fmt::Display + Unpin
+{
+    build_pipeline(commands, id, false, limits)
+}
+
+// Same as `spawn_pipeline_with_limits()` except that each command can have its
+// own limits.  `limits` is used for a command without its own limits, and
+// `limits.kill_grace_period` is always used when the pipeline is dropped.
+pub fn spawn_pipeline_with_command_limits<T>(
+    commands: Vec<(String, Option<ProcessLimitsConfig>)>,
+    id: T,
+    limits: &ProcessLimitsConfig,
+) -> Result<CommandPipeline<T>, Error>
+where
+    T: Copy + fmt::Display + Unpin
+{
+    let mut pipeline = CommandPipeline::new(
+        id, Duration::from_millis(limits.kill_grace_period));
+    for (command, command_limits) in commands.into_iter() {
+        pipeline.spawn(
+            command, false, command_limits.as_ref().unwrap_or(limits))?;
+    }
+    Ok(pipeline)
+}
+
+// Same as `spawn_pipeline_with_limits()` except that the stderr of the first
+// command is piped.  It can be took from the pipeline by `take_stderr()`.
+pub fn spawn_pipeline_with_stderr<T>(
+    commands: Vec<String>,
+    id: T,
+    limits: &ProcessLimitsConfig,
+) -> Result<CommandPipeline<T>, Error>
+where
+    T: Copy + fmt::Display + Unpin
+{
+    build_pipeline(commands, id, true, limits)
+}
+
+fn build_pipeline<T>(
+    commands: Vec<String>,
+    id: T,
+    capture_stderr: bool,
+    limits: &ProcessLimitsConfig,
+) -> Result<CommandPipeline<T>, Error>
+where
+    T: Copy + fmt::Display + Unpin
+{
+    let mut pipeline = CommandPipeline::new(
+        id, Duration::from_millis(limits.kill_grace_period));
+    for (i, command) in commands.into_iter().enumerate() {
+        pipeline.spawn(command, capture_stderr && i == 0, limits)?;
     }
     Ok(pipeline)
 }
@@ -104,7 +253,12 @@ where
     sender: broadcast::Sender<()>,
     stdin: Option<ChildStdin>,
     stdout: Option<ChildStdout>,
+    stderr: Option<ChildStderr>,
     commands: Vec<CommandData>,
+    // All processes in the pipeline belong to the process group of the first
+    // process, so that processes spawned by them can also be killed.
+    pgid: Option<libc::pid_t>,
+    kill_grace_period: Duration,
 }
 
 struct CommandData {
@@ -116,25 +270,38 @@ impl<T> CommandPipeline<T>
 where
     T: Copy + fmt::Display + Unpin
 {
-    fn new(id: T) -> Self {
+    fn new(id: T, kill_grace_period: Duration) -> Self {
         let (sender, _) = broadcast::channel(1);
         Self {
             id,
             sender,
             stdin: None,
             stdout: None,
+            stderr: None,
             commands: Vec::new(),
+            pgid: None,
+            kill_grace_period,
         }
     }
 
-    fn spawn(&mut self, command: String) -> Result<(), Error> {
+    fn spawn(
+        &mut self,
+        command: String,
+        capture_stderr: bool,
+        limits: &ProcessLimitsConfig,
+    ) -> Result<(), Error> {
         let input = if self.stdout.is_none() {
             Stdio::piped()
         } else {
             Stdio::from(self.stdout.take().unwrap())
         };
 
-        let mut process = spawn_process(&command, input)?;
+        let mut process = spawn_process_internal(
+            &command, input, capture_stderr, Some(self.pgid.unwrap_or(0)),
+            limits)?;
+        if self.pgid.is_none() {
+            self.pgid = Some(process.id() as libc::pid_t);
+        }
         log::debug!("{}: Spawned {}: `{}`",
                     self.id, process.id(), command);
 
@@ -142,6 +309,9 @@ where
             self.stdin = process.stdin.take();
         }
         self.stdout = process.stdout.take();
+        if capture_stderr {
+            self.stderr = process.stderr.take();
+        }
         self.commands.push(CommandData { command, process });
 
         Ok(())
@@ -154,6 +324,53 @@ where
             .collect()
     }
 
+    // Returns `Some(true)` if one of the commands has exited with an error,
+    // `Some(false)` if all of them have exited successfully, or `None` if some
+    // of them are still running.
+    //
+    // The commands may still be exiting when the output of the pipeline
+    // reaches EOF.  This function never blocks, so the caller has to poll it
+    // until the result is determined.  The processes are not reaped like
+    // `terminate_processes()`.
+    pub fn has_failed(&self) -> Option<bool> {
+        let mut running = false;
+        for data in self.commands.iter() {
+            match exit_failure(data.process.id()) {
+                Some(true) => {
+                    log::debug!("{}: {} exited with an error: `{}`",
+                                self.id, data.process.id(), data.command);
+                    return Some(true);
+                }
+                Some(false) => (),
+                None => running = true,
+            }
+        }
+        if running {
+            None
+        } else {
+            Some(false)
+        }
+    }
+
+    // Polls `has_failed()` until the result is determined or `timeout`
+    // elapses.  Returns `true` only if all the commands have exited
+    // successfully.
+    pub async fn wait_for_success(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.has_failed() {
+                Some(failed) => return !failed,
+                None if Instant::now() >= deadline => {
+                    log::debug!("{}: Still running after {:?}",
+                                self.id, timeout);
+                    return false;
+                }
+                None => tokio::time::delay_for(
+                    Duration::from_millis(10)).await,
+            }
+        }
+    }
+
     pub fn take_endpoints(
         &mut self
     ) -> Result<(CommandPipelineInput<T>, CommandPipelineOutput<T>), Error> {
@@ -168,6 +385,16 @@ where
         Ok((input, output))
     }
 
+    // Returns `None` if the stderr is not piped or has already been took.
+    pub fn take_stderr(
+        &mut self
+    ) -> Result<Option<impl AsyncRead + Unpin>, Error> {
+        match self.stderr.take() {
+            Some(stderr) => Ok(Some(Self::make_childio_async(Some(stderr))?)),
+            None => Ok(None),
+        }
+    }
+
     fn make_childio_async<F: AsRawFd>(
         childio: Option<F>
     ) -> Result<tokio_snippet::ChildIo<F>, Error> {
@@ -183,14 +410,102 @@ where
     T: Copy + fmt::Display + Unpin
 {
     fn drop(&mut self) {
-        // Always kill the processes and ignore the error.  Because there is no
-        // method to check whether the process is alive or dead.
-        for data in self.commands.iter_mut() {
-            let _ = data.process.kill();
-            let _ = data.process.wait();
-            log::debug!("{}: Killed {}: `{}`",
-                        self.id, data.process.id(), data.command);
+        let pgid = match self.pgid {
+            Some(pgid) => pgid,
+            None => return,
+        };
+        let id = self.id;
+        let commands = std::mem::take(&mut self.commands);
+        if self.kill_grace_period == Duration::from_secs(0) {
+            kill_processes(id, pgid, commands);
+        } else {
+            // Don't block the current thread during the grace period.
+            let id = id.to_string();
+            let grace_period = self.kill_grace_period;
+            std::thread::spawn(move || {
+                terminate_processes(id, pgid, &commands, grace_period);
+                kill_processes(id, pgid, commands);
+            });
+        }
+    }
+}
+
+// Sends SIGTERM to the process group, and then waits for the processes to exit
+// until the grace period expires.
+//
+// The processes are not reaped here so that the process group ID is never
+// reused before SIGKILL is sent to the process group.
+fn terminate_processes<T: fmt::Display>(
+    id: T,
+    pgid: libc::pid_t,
+    commands: &[CommandData],
+    grace_period: Duration,
+) {
+    log::debug!("{}: Terminating processes in group {}...", id, pgid);
+    unsafe {
+        libc::killpg(pgid, libc::SIGTERM);
+    }
+    let deadline = Instant::now() + grace_period;
+    while Instant::now() < deadline {
+        if commands.iter().all(|data| has_exited(data.process.id())) {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    log::warn!("{}: Processes in group {} still alive after {}ms",
+               id, pgid, grace_period.as_millis());
+}
+
+// Always kill the processes and ignore the error.  Because there is no method
+// to check whether the process is alive or dead.
+//
+// SIGKILL is also sent to the process group in order to kill processes spawned
+// by the commands.
+fn kill_processes<T: fmt::Display>(
+    id: T,
+    pgid: libc::pid_t,
+    commands: Vec<CommandData>,
+) {
+    unsafe {
+        libc::killpg(pgid, libc::SIGKILL);
+    }
+    for mut data in commands.into_iter() {
+        let _ = data.process.kill();
+        let _ = data.process.wait();
+        log::debug!("{}: Killed {}: `{}`",
+                    id, data.process.id(), data.command);
+    }
+}
+
+// Checks whether the process has exited without reaping it.
+fn has_exited(pid: u32) -> bool {
+    unsafe {
+        let mut info: libc::siginfo_t = std::mem::zeroed();
+        let result = libc::waitid(
+            libc::P_PID, pid as libc::id_t, &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT);
+        // si_pid is 0 if the process is still running.
+        result < 0 || info.si_pid() != 0
+    }
+}
+
+// Checks whether the process has exited with an error without reaping it.
+// Returns `None` if the process is still running.
+fn exit_failure(pid: u32) -> Option<bool> {
+    unsafe {
+        let mut info: libc::siginfo_t = std::mem::zeroed();
+        let result = libc::waitid(
+            libc::P_PID, pid as libc::id_t, &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT);
+        if result < 0 {
+            // Already reaped.  There is no way to get the exit status.
+            return Some(false);
+        }
+        // si_pid is 0 if the process is still running.
+        if info.si_pid() == 0 {
+            return None;
         }
+        Some(info.si_code != libc::CLD_EXITED || info.si_status() != 0)
     }
 }
 
@@ -369,6 +684,171 @@ mod tests {
         assert_eq!(0, result.unwrap());
     }
 
+    #[tokio::test]
+    async fn test_pipeline_kill_process_group() {
+        use std::io::BufRead;
+
+        // The shell ignores SIGTERM and spawns a grandchild process which
+        // is not a direct child of the pipeline.
+        let limits = ProcessLimitsConfig {
+            kill_grace_period: 100,
+            ..Default::default()
+        };
+        let mut pipeline = spawn_pipeline_with_limits(vec![
+            "sh -c 'trap \"\" TERM; sleep 10 & echo $!; wait'".to_string(),
+        ], 0, &limits).unwrap();
+
+        let stdout = pipeline.stdout.take().unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(stdout).read_line(&mut line).unwrap();
+        let pid: u32 = line.trim().parse().unwrap();
+        assert!(is_alive(pid));
+
+        let start = Instant::now();
+        drop(pipeline);
+        while is_alive(pid) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    fn is_alive(pid: u32) -> bool {
+        // A zombie process is treated as dead.
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => !stat.contains(") Z "),
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_with_stderr() {
+        let mut pipeline = spawn_pipeline_with_stderr(vec![
+            "sh -c 'echo hello >&2'".to_string(),
+            "cat".to_string(),
+        ], 0, &Default::default()).unwrap();
+        let mut stderr = pipeline.take_stderr().unwrap().unwrap();
+        assert!(pipeline.take_stderr().unwrap().is_none());
+
+        let mut buf = String::new();
+        let result = stderr.read_to_string(&mut buf).await;
+        assert!(result.is_ok());
+        assert_eq!(buf, "hello\n");
+
+        let mut pipeline = spawn_pipeline(vec!["cat".to_string()], 0).unwrap();
+        assert!(pipeline.take_stderr().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_with_limits() {
+        let limits = ProcessLimitsConfig {
+            nice: Some(5),
+            max_cpu_time: Some(60),
+            ..Default::default()
+        };
+        let mut pipeline = spawn_pipeline_with_limits(vec![
+            "sh -c 'nice; ulimit -t'".to_string(),
+            "cat".to_string(),
+        ], 0, &limits).unwrap();
+        let (_, mut output) = pipeline.take_endpoints().unwrap();
+
+        let mut buf = String::new();
+        let result = output.read_to_string(&mut buf).await;
+        assert!(result.is_ok());
+        // The niceness is incremented from the current one, and never exceeds
+        // 19.
+        let nice = (current_niceness() + 5).min(19);
+        assert_eq!(buf, format!("{}\n60\n", nice));
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_with_command_limits() {
+        let limits = ProcessLimitsConfig {
+            max_cpu_time: Some(60),
+            ..Default::default()
+        };
+        let mut pipeline = spawn_pipeline_with_command_limits(vec![
+            ("sh -c 'ulimit -t'".to_string(), None),
+            ("sh -c 'cat; ulimit -t'".to_string(), Some(ProcessLimitsConfig {
+                max_cpu_time: Some(30),
+                ..Default::default()
+            })),
+        ], 0, &limits).unwrap();
+        let (_, mut output) = pipeline.take_endpoints().unwrap();
+
+        let mut buf = String::new();
+        let result = output.read_to_string(&mut buf).await;
+        assert!(result.is_ok());
+        assert_eq!(buf, "60\n30\n");
+    }
+
+    fn current_niceness() -> i32 {
+        unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) }
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_has_failed() {
+        async fn wait_for_exit(pipeline: &CommandPipeline<u8>) -> bool {
+            loop {
+                if let Some(failed) = pipeline.has_failed() {
+                    return failed;
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        }
+
+        let mut pipeline = spawn_pipeline(vec![
+            "sh -c 'exit 1'".to_string(),
+            "cat".to_string(),
+        ], 0).unwrap();
+        let (_, mut output) = pipeline.take_endpoints().unwrap();
+        let mut buf = Vec::new();
+        let _ = output.read_to_end(&mut buf).await;
+        assert!(wait_for_exit(&pipeline).await);
+
+        let mut pipeline = spawn_pipeline(vec![
+            "sh -c 'exit 0'".to_string(),
+            "cat".to_string(),
+        ], 0).unwrap();
+        let (_, mut output) = pipeline.take_endpoints().unwrap();
+        let mut buf = Vec::new();
+        let _ = output.read_to_end(&mut buf).await;
+        assert!(!wait_for_exit(&pipeline).await);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_wait_for_success() {
+        let timeout = Duration::from_secs(1);
+
+        let mut pipeline = spawn_pipeline(vec![
+            "sh -c 'exit 0'".to_string(),
+            "cat".to_string(),
+        ], 0).unwrap();
+        let (_, mut output) = pipeline.take_endpoints().unwrap();
+        let mut buf = Vec::new();
+        let _ = output.read_to_end(&mut buf).await;
+        assert!(pipeline.wait_for_success(timeout).await);
+
+        let mut pipeline = spawn_pipeline(vec![
+            "sh -c 'exit 1'".to_string(),
+            "cat".to_string(),
+        ], 0).unwrap();
+        let (_, mut output) = pipeline.take_endpoints().unwrap();
+        let mut buf = Vec::new();
+        let _ = output.read_to_end(&mut buf).await;
+        assert!(!pipeline.wait_for_success(timeout).await);
+
+        // The output is closed but the command is still running.
+        let mut pipeline = spawn_pipeline(vec![
+            "sh -c 'exec >&-; sleep 10'".to_string(),
+        ], 0).unwrap();
+        let (_, mut output) = pipeline.take_endpoints().unwrap();
+        let mut buf = Vec::new();
+        let _ = output.read_to_end(&mut buf).await;
+        assert!(!pipeline.wait_for_success(
+            Duration::from_millis(100)).await);
+    }
+
     #[tokio::test]
     async fn test_pipeline_input_dropped() {
         let mut pipeline = spawn_pipeline(vec!["cat".to_string()], 0).unwrap();
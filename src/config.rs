@@ -1,38 +1,157 @@
 use std::collections::HashMap;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::SystemTime;
 
+use cron;
 use num_cpus;
 use serde::Deserialize;
 use serde_yaml;
 
-use crate::models::{ChannelType, ServiceId};
+use crate::models::{ChannelType, MirakurunServiceId, ServiceId};
 
 pub fn load(config_path: &str) -> Arc<Config> {
-    const SERVER_STREAM_TIME_LIMIT_MIN: u64 = 15_000;
+    try_load(config_path).unwrap_or_else(|err| panic!("{}", err))
+}
 
-    let reader = File::open(config_path)
-        .unwrap_or_else(|err| {
-            panic!("Failed to open {}: {}", config_path, err);
-        });
-    let mut config: Config = serde_yaml::from_reader(reader)
-        .unwrap_or_else(|err| {
-            panic!("Failed to parse {}: {}", config_path, err);
-        });
+// Used for reloading the config at runtime.  Unlike `load()`, an error is
+// returned so that the running config can be kept when the new one is invalid.
+pub fn try_load(config_path: &str) -> Result<Arc<Config>, String> {
+    const SERVER_STREAM_TIME_LIMIT_MIN: u64 = 15_000;
+    // The size of a TS packet.
+    const BROADCASTER_CHUNK_SIZE_MIN: usize = 188;
+
+    let cwd = std::env::current_dir()
+        .map_err(|err| {
+            format!("Failed to get the current directory: {}", err)
+        })?;
+    let config_path = resolve_path(config_path, &cwd);
+    log::info!("Loading {}...", config_path.display());
+
+    let reader = File::open(&config_path)
+        .map_err(|err| {
+            format!("Failed to open {}: {}", config_path.display(), err)
+        })?;
+    let mut value: serde_yaml::Value = serde_yaml::from_reader(reader)
+        .map_err(|err| {
+            format!("Failed to parse {}: {}", config_path.display(), err)
+        })?;
+    expand_env_vars(&mut value, &|name| std::env::var(name).ok())
+        .map_err(|err| {
+            format!("Failed to expand environment variables in {}: {}",
+                    config_path.display(), err)
+        })?;
+    let mut config: Config = serde_yaml::from_value(value)
+        .map_err(|err| {
+            format!("Failed to parse {}: {}", config_path.display(), err)
+        })?;
     if config.server.stream_time_limit < SERVER_STREAM_TIME_LIMIT_MIN {
         log::warn!("server.stream_time_limit must be larger than {0}, \
                     reset it to {0}", SERVER_STREAM_TIME_LIMIT_MIN);
         config.server.stream_time_limit = SERVER_STREAM_TIME_LIMIT_MIN;
     }
+    if let Some(chunk_size) = config.broadcaster.chunk_size {
+        if chunk_size < BROADCASTER_CHUNK_SIZE_MIN {
+            return Err(format!("broadcaster.chunk-size must be {} or larger",
+                               BROADCASTER_CHUNK_SIZE_MIN));
+        }
+    }
+    if config.broadcaster.max_buffered_chunks == Some(0) {
+        return Err("broadcaster.max-buffered-chunks must be larger than 0"
+                   .to_string());
+    }
+    if config.broadcaster.timeshift_max_bytes == Some(0) {
+        return Err("broadcaster.timeshift-max-bytes must be larger than 0"
+                   .to_string());
+    }
+    config.jobs.validate()?;
+    config.validate_fallbacks()?;
 
-    config.last_modified = std::fs::metadata(config_path)
+    config.last_modified = std::fs::metadata(&config_path)
         .map(|metadata| metadata.modified().ok()).ok().flatten();
-    Arc::new(config)
+    Ok(Arc::new(config))
+}
+
+// Expands `${VAR}` and `${VAR:-default}` in string values.  `$${` is replaced
+// with `${` without expansion.
+//
+// Mapping keys and non-string values are kept as they are.
+fn expand_env_vars<F>(
+    value: &mut serde_yaml::Value,
+    lookup: &F,
+) -> Result<(), String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match value {
+        serde_yaml::Value::String(s) => {
+            *s = expand_env_vars_in_str(s, lookup)?;
+        }
+        serde_yaml::Value::Sequence(seq) => {
+            for v in seq.iter_mut() {
+                expand_env_vars(v, lookup)?;
+            }
+        }
+        serde_yaml::Value::Mapping(map) => {
+            for (_, v) in map.iter_mut() {
+                expand_env_vars(v, lookup)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn expand_env_vars_in_str<F>(s: &str, lookup: &F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find("${") {
+        if rest[..pos].ends_with('$') {
+            result.push_str(&rest[..(pos - 1)]);
+            result.push_str("${");
+            rest = &rest[(pos + 2)..];
+            continue;
+        }
+        result.push_str(&rest[..pos]);
+        rest = &rest[(pos + 2)..];
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => return Err(format!("Unclosed variable in '{}'", s)),
+        };
+        let (name, default) = match rest[..end].find(":-") {
+            Some(i) => (&rest[..i], Some(&rest[(i + 2)..end])),
+            None => (&rest[..end], None),
+        };
+        if name.is_empty() {
+            return Err(format!("Empty variable name in '{}'", s));
+        }
+        match lookup(name).or_else(|| default.map(str::to_string)) {
+            Some(v) => result.push_str(&v),
+            None => return Err(format!("{} is not defined", name)),
+        }
+        rest = &rest[(end + 1)..];
+    }
+    result.push_str(rest);
+    Ok(result)
 }
 
 // result
 
+// A relative path is resolved against the current directory.
+fn resolve_path(path: &str, cwd: &Path) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        cwd.join(path)
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
@@ -48,6 +167,10 @@ pub struct Config {
     #[serde(default)]
     pub tuners: Vec<TunerConfig>,
     #[serde(default)]
+    pub tuner_sharing: TunerSharingConfig,
+    #[serde(default)]
+    pub broadcaster: BroadcasterConfig,
+    #[serde(default)]
     pub filters: FiltersConfig,
     #[serde(default)]
     pub pre_filters: HashMap<String, FilterConfig>,
@@ -58,15 +181,188 @@ pub struct Config {
     #[serde(default)]
     pub recorder: RecorderConfig,
     #[serde(default)]
+    pub archives: Vec<ArchiveConfig>,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
     pub mirakurun: MirakurunConfig,
+    #[serde(default)]
+    pub debug: DebugConfig,
 }
 
-#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+impl Config {
+    pub fn has_enabled_tuners(&self) -> bool {
+        self.tuners.iter().any(|config| !config.disabled)
+    }
+
+    // Returns names of default pre-filters for a channel or a service in the
+    // channel.  Channel defaults come first, then service defaults.
+    //
+    // Services are identified by NID and SID because the same SID may be used
+    // in different networks.
+    //
+    // Default filters are used only for streaming and recording.  Commands of
+    // background jobs read only PSI/SI tables which are not scrambled.
+    pub fn default_pre_filters(
+        &self,
+        channel_type: ChannelType,
+        channel: &str,
+        service: Option<MirakurunServiceId>,
+    ) -> Vec<String> {
+        let mut names = Vec::new();
+        if let Some(config) = self.find_channel(channel_type, channel) {
+            names.extend_from_slice(&config.pre_filters);
+        }
+        if let Some(config) = service.and_then(|id| {
+            self.filters.find_service_default_filters(id)
+        }) {
+            names.extend_from_slice(&config.pre_filters);
+        }
+        names
+    }
+
+    // Returns names of default post-filters for a channel or a service in the
+    // channel.  Service defaults come first, then channel defaults.
+    pub fn default_post_filters(
+        &self,
+        channel_type: ChannelType,
+        channel: &str,
+        service: Option<MirakurunServiceId>,
+    ) -> Vec<String> {
+        let mut names = Vec::new();
+        if let Some(config) = service.and_then(|id| {
+            self.filters.find_service_default_filters(id)
+        }) {
+            names.extend_from_slice(&config.post_filters);
+        }
+        if let Some(config) = self.find_channel(channel_type, channel) {
+            names.extend_from_slice(&config.post_filters);
+        }
+        names
+    }
+
+    // A fallback channel must be another enabled channel.  Checked here so
+    // that a typo is detected when the config is loaded.
+    fn validate_fallbacks(&self) -> Result<(), String> {
+        for config in self.channels.iter().filter(|config| !config.disabled) {
+            let fallback = match config.fallback {
+                Some(ref fallback) => fallback,
+                None => continue,
+            };
+            if fallback.channel_type == config.channel_type &&
+                fallback.channel == config.channel {
+                return Err(format!(
+                    "channels[{}].fallback must be another channel",
+                    config.name));
+            }
+            if self.find_channel(
+                fallback.channel_type, &fallback.channel).is_none() {
+                return Err(format!(
+                    "channels[{}].fallback: No enabled channel for {}/{}",
+                    config.name, fallback.channel_type, fallback.channel));
+            }
+        }
+        Ok(())
+    }
+
+    fn find_channel(
+        &self,
+        channel_type: ChannelType,
+        channel: &str,
+    ) -> Option<&ChannelConfig> {
+        self.channels.iter()
+            .filter(|config| !config.disabled)
+            .find(|config| {
+                config.channel_type == channel_type && config.channel == channel
+            })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
 pub struct EpgConfig {
     #[serde(default)]
     pub cache_dir: Option<String>,
+    #[serde(default = "EpgConfig::default_cache_save_interval")]
+    pub cache_save_interval: u64,
+    #[serde(default)]
+    pub max_schedule_days: Option<u32>,
+    #[serde(default)]
+    pub xmltv: Option<XmltvConfig>,
+    #[serde(default)]
+    pub zero_duration_events: ZeroDurationEvents,
+    #[serde(default = "EpgConfig::default_max_eit_line_length")]
+    pub max_eit_line_length: usize,
+    #[serde(default)]
+    pub enrichment_file: Option<String>,
+    #[serde(default)]
+    pub thumbnails: Option<ThumbnailsConfig>,
+    #[serde(default)]
+    pub drop_invalid_sections: bool,
+    #[serde(default)]
+    pub unavailable_until_ready: bool,
+}
+
+impl EpgConfig {
+    fn default_cache_save_interval() -> u64 {
+        60 * 60 * 1000  // 1h
+    }
+
+    fn default_max_eit_line_length() -> usize {
+        1024 * 1024  // 1MiB
+    }
+}
+
+impl Default for EpgConfig {
+    fn default() -> Self {
+        EpgConfig {
+            cache_dir: None,
+            cache_save_interval: Self::default_cache_save_interval(),
+            max_schedule_days: None,
+            xmltv: None,
+            zero_duration_events: Default::default(),
+            max_eit_line_length: Self::default_max_eit_line_length(),
+            enrichment_file: None,
+            thumbnails: None,
+            drop_invalid_sections: false,
+            unavailable_until_ready: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct ThumbnailsConfig {
+    pub dir: String,
+    #[serde(default)]
+    pub command: String,
+}
+
+// How to handle events having zero or undefined durations.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ZeroDurationEvents {
+    Keep,
+    Skip,
+    // Extended to the start time of the next event.
+    Fill,
+}
+
+impl Default for ZeroDurationEvents {
+    fn default() -> Self {
+        ZeroDurationEvents::Keep
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct XmltvConfig {
+    pub path: String,
+    // XMLTV channel ID -> Mirakurun service ID
+    pub channels: HashMap<String, MirakurunServiceId>,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -83,6 +379,22 @@ pub struct ServerConfig {
     pub stream_chunk_size: usize,
     #[serde(default = "ServerConfig::default_stream_time_limit")]
     pub stream_time_limit: u64,
+    #[serde(default)]
+    pub stream_idle_timeout: u64,
+    #[serde(default)]
+    pub stream_stats_retention: u64,
+    #[serde(default)]
+    pub stream_coalesce_size: usize,
+    #[serde(default = "ServerConfig::default_stream_coalesce_timeout")]
+    pub stream_coalesce_timeout: u64,
+    #[serde(default)]
+    pub stream_jitter_buffer_size: usize,
+    #[serde(default)]
+    pub stream_jitter_buffer_time: u64,
+    #[serde(default)]
+    pub access_log: Option<AccessLogConfig>,
+    #[serde(default)]
+    pub log_session_label: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -114,6 +426,10 @@ impl ServerConfig {
     fn default_stream_time_limit() -> u64 {
         16 * 1000  // 16s
     }
+
+    fn default_stream_coalesce_timeout() -> u64 {
+        100  // 100ms
+    }
 }
 
 impl Default for ServerConfig {
@@ -124,10 +440,41 @@ impl Default for ServerConfig {
             stream_max_chunks: Self::default_stream_max_chunks(),
             stream_chunk_size: Self::default_stream_chunk_size(),
             stream_time_limit: Self::default_stream_time_limit(),
+            stream_idle_timeout: 0,
+            stream_stats_retention: 0,
+            stream_coalesce_size: 0,
+            stream_coalesce_timeout: Self::default_stream_coalesce_timeout(),
+            stream_jitter_buffer_size: 0,
+            stream_jitter_buffer_time: 0,
+            access_log: None,
+            log_session_label: false,
         }
     }
 }
 
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub path: String,
+    #[serde(default = "AccessLogConfig::default_max_size")]
+    pub max_size: u64,
+    #[serde(default)]
+    pub rotation_interval: u64,
+    #[serde(default = "AccessLogConfig::default_max_files")]
+    pub max_files: usize,
+}
+
+impl AccessLogConfig {
+    fn default_max_size() -> u64 {
+        10 * 1024 * 1024  // 10MiB
+    }
+
+    fn default_max_files() -> usize {
+        5
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
@@ -144,6 +491,25 @@ pub struct ChannelConfig {
     pub excluded_services: Vec<ServiceId>,
     #[serde(default)]
     pub disabled: bool,
+    #[serde(default)]
+    pub fallback: Option<FallbackChannelConfig>,
+    #[serde(default)]
+    pub epg_priority: i32,
+    #[serde(default)]
+    pub expected_services: Vec<ServiceId>,
+    #[serde(default)]
+    pub pre_filters: Vec<String>,
+    #[serde(default)]
+    pub post_filters: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct FallbackChannelConfig {
+    #[serde(rename = "type")]
+    pub channel_type: ChannelType,
+    pub channel: String,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -158,12 +524,134 @@ pub struct TunerConfig {
     pub time_limit: u64,
     #[serde(default)]
     pub disabled: bool,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default = "TunerConfig::default_drop_log_interval")]
+    pub drop_log_interval: u64,
+    #[serde(default)]
+    pub signal_stats_pattern: Option<String>,
+    #[serde(default)]
+    pub zero_read_policy: ZeroReadPolicy,
+    #[serde(default)]
+    pub max_restarts: usize,
+    #[serde(default)]
+    pub startup_timeout: u64,
+    #[serde(default)]
+    pub process_limits: ProcessLimitsConfig,
 }
 
 impl TunerConfig {
     fn default_time_limit() -> u64 {
         30 * 1000  // 30s
     }
+
+    fn default_drop_log_interval() -> u64 {
+        10 * 1000  // 10s
+    }
+}
+
+// How to handle a zero-length read from the output of a tuner command.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ZeroReadPolicy {
+    // Treated as EOF.
+    Eof,
+    // Keep reading until the time limit of the tuner expires.
+    Retry,
+}
+
+impl Default for ZeroReadPolicy {
+    fn default() -> Self {
+        ZeroReadPolicy::Eof
+    }
+}
+
+// How to handle a subscriber which cannot receive chunks as fast as a tuner
+// outputs them.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DropPolicy {
+    // Drop chunks for the subscriber and keep streaming.
+    DropChunk,
+    // Stop streaming for the subscriber.
+    CloseStream,
+    // Wait until the subscriber receives the chunk.  Other subscribers in the
+    // same tuner session also wait.
+    Block,
+}
+
+impl Default for DropPolicy {
+    fn default() -> Self {
+        DropPolicy::DropChunk
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct TunerSharingConfig {
+    #[serde(default)]
+    pub policy: TunerSharingPolicy,
+    // Whether a tuner used only by lower priority users can be taken over.
+    #[serde(default = "TunerSharingConfig::default_preemption")]
+    pub preemption: bool,
+    // Whether sessions of channels changed by reloading the config are
+    // stopped.
+    #[serde(default)]
+    pub stop_changed_channels: bool,
+}
+
+impl TunerSharingConfig {
+    fn default_preemption() -> bool {
+        true
+    }
+}
+
+impl Default for TunerSharingConfig {
+    fn default() -> Self {
+        TunerSharingConfig {
+            policy: Default::default(),
+            preemption: Self::default_preemption(),
+            stop_changed_channels: false,
+        }
+    }
+}
+
+// Which tuner is used when a tuner already activated for the channel can be
+// shared but a tuner having a higher priority is available.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TunerSharingPolicy {
+    // Share the tuner already activated.
+    PreferShare,
+    // Activate the tuner having the higher priority.
+    PreferPreferredTuner,
+}
+
+impl Default for TunerSharingPolicy {
+    fn default() -> Self {
+        TunerSharingPolicy::PreferShare
+    }
+}
+
+// Buffering in a broadcaster which delivers data from a tuner to subscribers.
+// Built-in values are used for unspecified properties.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct BroadcasterConfig {
+    #[serde(default)]
+    pub max_buffered_chunks: Option<usize>,
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    #[serde(default)]
+    pub timeshift_duration: Option<u64>,
+    #[serde(default)]
+    pub timeshift_max_bytes: Option<u64>,
+    #[serde(default)]
+    pub psi_cache: bool,
+    #[serde(default)]
+    pub psi_reinsertion_interval: Option<u64>,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -174,16 +662,33 @@ pub struct FiltersConfig {
     pub tuner_filter: FilterConfig,
     #[serde(default = "FiltersConfig::default_service_filter")]
     pub service_filter: FilterConfig,
+    #[serde(default)]
+    pub builtin_service_filter: bool,
     #[serde(default = "FiltersConfig::default_program_filter")]
     pub program_filter: FilterConfig,
     #[serde(default)]
     pub decode_filter: FilterConfig,
+    #[serde(default)]
+    pub service_decode_filters: Vec<ServiceDecodeFilterConfig>,
+    #[serde(default)]
+    pub service_default_filters: Vec<ServiceDefaultFiltersConfig>,
+    #[serde(default)]
+    pub multi_service_filter: FilterConfig,
+    #[serde(default = "FiltersConfig::default_fmp4_filter")]
+    pub fmp4_filter: FilterConfig,
+    #[serde(default = "FiltersConfig::default_max_pipeline_stages")]
+    pub max_pipeline_stages: usize,
+    #[serde(default)]
+    pub runtime_update: bool,
+    #[serde(default)]
+    pub process_limits: ProcessLimitsConfig,
 }
 
 impl FiltersConfig {
     fn default_service_filter() -> FilterConfig {
         FilterConfig {
-            command: "mirakc-arib filter-service --sid={{sid}}".to_string()
+            command: "mirakc-arib filter-service --sid={{sid}}".to_string(),
+            process_limits: None,
         }
     }
 
@@ -201,9 +706,43 @@ impl FiltersConfig {
         FilterConfig {
             command: "mirakc-arib filter-program --sid={{sid}} --eid={{eid}} \
                       --clock-pcr={{clock_pcr}} --clock-time={{clock_time}} \
-                      --end-margin=2000".to_string()
+                      --end-margin=2000".to_string(),
+            process_limits: None,
+        }
+    }
+
+    fn default_fmp4_filter() -> FilterConfig {
+        // Broadcast video streams are encoded with MPEG-2 in Japan which no
+        // browser can decode.  So, the video stream has to be re-encoded.
+        FilterConfig {
+            command: "ffmpeg -loglevel error -i pipe:0 \
+                      -c:v libx264 -preset veryfast -c:a aac \
+                      -f mp4 \
+                      -movflags frag_keyframe+empty_moov+default_base_moof \
+                      pipe:1".to_string(),
+            process_limits: None,
         }
     }
+
+    fn default_max_pipeline_stages() -> usize {
+        32
+    }
+
+    pub fn find_service_decode_filter(
+        &self,
+        sid: ServiceId,
+    ) -> Option<&ServiceDecodeFilterConfig> {
+        self.service_decode_filters.iter()
+            .find(|config| config.services.contains(&sid))
+    }
+
+    pub fn find_service_default_filters(
+        &self,
+        id: MirakurunServiceId,
+    ) -> Option<&ServiceDefaultFiltersConfig> {
+        self.service_default_filters.iter()
+            .find(|config| config.services.contains(&id))
+    }
 }
 
 impl Default for FiltersConfig {
@@ -211,18 +750,71 @@ impl Default for FiltersConfig {
         FiltersConfig {
             tuner_filter: Default::default(),
             service_filter: Self::default_service_filter(),
+            builtin_service_filter: false,
             decode_filter: Default::default(),
             program_filter: Self::default_program_filter(),
+            service_decode_filters: Default::default(),
+            service_default_filters: Default::default(),
+            multi_service_filter: Default::default(),
+            fmp4_filter: Self::default_fmp4_filter(),
+            max_pipeline_stages: Self::default_max_pipeline_stages(),
+            runtime_update: false,
+            process_limits: Default::default(),
         }
     }
 }
 
+// Resource limits applied to spawned processes.  Limits not specified are
+// inherited from mirakc.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct ProcessLimitsConfig {
+    #[serde(default)]
+    pub nice: Option<i32>,
+    // RLIMIT_AS in bytes.
+    #[serde(default)]
+    pub max_memory: Option<u64>,
+    // RLIMIT_CPU in seconds.
+    #[serde(default)]
+    pub max_cpu_time: Option<u64>,
+    // A cgroup v2 directory.  Linux only.
+    #[serde(default)]
+    pub cgroup: Option<String>,
+    // A grace period in milliseconds between SIGTERM and SIGKILL.
+    #[serde(default)]
+    pub kill_grace_period: u64,
+}
+
 #[derive(Clone, Debug, Default, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
 pub struct FilterConfig {
     #[serde(default)]
     pub command: String,
+    // Used instead of `filters.process-limits` if specified.
+    #[serde(default)]
+    pub process_limits: Option<ProcessLimitsConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct ServiceDecodeFilterConfig {
+    pub services: Vec<ServiceId>,
+    pub command: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct ServiceDefaultFiltersConfig {
+    // Mirakurun service IDs, which consist of NID and SID.
+    pub services: Vec<MirakurunServiceId>,
+    #[serde(default)]
+    pub pre_filters: Vec<String>,
+    #[serde(default)]
+    pub post_filters: Vec<String>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, PartialEq)]
@@ -233,6 +825,9 @@ pub struct PostFilterConfig {
     pub command: String,
     #[serde(default)]
     pub content_type: Option<String>,
+    // Used instead of `filters.process-limits` if specified.
+    #[serde(default)]
+    pub process_limits: Option<ProcessLimitsConfig>,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -245,15 +840,58 @@ pub struct JobsConfig {
     pub sync_clocks: JobConfig,
     #[serde(default = "JobsConfig::default_update_schedules")]
     pub update_schedules: JobConfig,
+    #[serde(default)]
+    pub update_recent_schedules: Option<JobConfig>,
+    #[serde(default = "JobsConfig::default_max_tuners")]
+    pub max_tuners: usize,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub persist_paused: bool,
 }
 
 impl JobsConfig {
+    fn default_max_tuners() -> usize {
+        1
+    }
+
+    // Schedules are checked here so that an invalid schedule is detected
+    // before the job manager uses it.
+    fn validate(&self) -> Result<(), String> {
+        let jobs = [
+            ("scan-services", Some(&self.scan_services)),
+            ("sync-clocks", Some(&self.sync_clocks)),
+            ("update-schedules", Some(&self.update_schedules)),
+            ("update-recent-schedules", self.update_recent_schedules.as_ref()),
+        ];
+        for (name, job) in jobs.iter() {
+            if let Some(job) = job {
+                cron::Schedule::from_str(&job.schedule).map_err(|err| {
+                    format!("Invalid schedule for jobs.{}: {}", name, err)
+                })?;
+                // Only the scan-services job can stop processing a channel in
+                // the middle.  Reject it instead of silently ignoring it.
+                if *name != "scan-services" && job.timeout.is_some() {
+                    return Err(format!(
+                        "jobs.{}.timeout is not supported", name));
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn default_scan_services() -> JobConfig {
         JobConfig {
             command: "mirakc-arib scan-services\
                       {{#sids}} --sids={{.}}{{/sids}}\
                       {{#xsids}} --xsids={{.}}{{/xsids}}".to_string(),
             schedule: "0 31 5 * * * *".to_string(),
+            timeout: None,
+            tables: None,
+            present_following: false,
+            fresh_window: None,
+            label: None,
+            max_concurrency: 1,
         }
     }
 
@@ -263,6 +901,12 @@ impl JobsConfig {
                       {{#sids}} --sids={{.}}{{/sids}}\
                       {{#xsids}} --xsids={{.}}{{/xsids}}".to_string(),
             schedule: "0 3 12 * * * *".to_string(),
+            timeout: None,
+            tables: None,
+            present_following: false,
+            fresh_window: None,
+            label: None,
+            max_concurrency: 1,
         }
     }
 
@@ -272,6 +916,12 @@ impl JobsConfig {
                       {{#sids}} --sids={{.}}{{/sids}}\
                       {{#xsids}} --xsids={{.}}{{/xsids}}".to_string(),
             schedule: "0 7,37 * * * * *".to_string(),
+            timeout: None,
+            tables: None,
+            present_following: false,
+            fresh_window: None,
+            label: None,
+            max_concurrency: 1,
         }
     }
 }
@@ -282,6 +932,10 @@ impl Default for JobsConfig {
             scan_services: Self::default_scan_services(),
             sync_clocks: Self::default_sync_clocks(),
             update_schedules: Self::default_update_schedules(),
+            update_recent_schedules: None,
+            max_tuners: Self::default_max_tuners(),
+            retry: Default::default(),
+            persist_paused: false,
         }
     }
 }
@@ -292,50 +946,323 @@ impl Default for JobsConfig {
 pub struct JobConfig {
     pub command: String,
     pub schedule: String,
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub tables: Option<Vec<usize>>,
+    #[serde(default)]
+    pub present_following: bool,
+    #[serde(default)]
+    pub fresh_window: Option<u64>,
+    // Used as the name of the tuner user.  The default label of each job is
+    // used if it's not specified.
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default = "JobConfig::default_max_concurrency")]
+    pub max_concurrency: usize,
+}
+
+impl JobConfig {
+    fn default_max_concurrency() -> usize {
+        1
+    }
 }
 
+// Retry of transient failures in acquiring a tuner and spawning a command for
+// each channel in a job.  See `retry::retry()`.
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
-pub struct RecorderConfig {
-    pub track_airtime_command: String,
+pub struct RetryConfig {
+    #[serde(default = "RetryConfig::default_max_attempts")]
+    pub max_attempts: usize,
+    #[serde(default = "RetryConfig::default_initial_interval")]
+    pub initial_interval: u64,
+    #[serde(default = "RetryConfig::default_max_interval")]
+    pub max_interval: u64,
 }
 
-impl Default for RecorderConfig {
-    fn default() -> Self {
-        RecorderConfig {
-            track_airtime_command: "mirakc-arib track-airtime \
-                                    --sid={{sid}} --eid={{eid}}".to_string(),
+impl RetryConfig {
+    // Used by collectors created without the config.
+    pub fn no_retry() -> Self {
+        RetryConfig {
+            max_attempts: 1,
+            ..Default::default()
         }
     }
-}
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
-#[serde(rename_all = "kebab-case")]
-#[serde(deny_unknown_fields)]
-pub struct MirakurunConfig {
-    #[serde(default = "MirakurunConfig::default_openapi_json")]
-    pub openapi_json: String,
-}
+    fn default_max_attempts() -> usize {
+        3
+    }
 
-impl MirakurunConfig {
-    fn default_openapi_json() -> String {
-        "/etc/mirakurun.openapi.json".to_string()
+    fn default_initial_interval() -> u64 {
+        1000  // 1s
+    }
+
+    fn default_max_interval() -> u64 {
+        30 * 1000  // 30s
     }
 }
 
-impl Default for MirakurunConfig {
+impl Default for RetryConfig {
     fn default() -> Self {
-        MirakurunConfig {
-            openapi_json: Self::default_openapi_json(),
+        RetryConfig {
+            max_attempts: Self::default_max_attempts(),
+            initial_interval: Self::default_initial_interval(),
+            max_interval: Self::default_max_interval(),
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct RecorderConfig {
+    #[serde(default = "RecorderConfig::default_track_airtime_command")]
+    pub track_airtime_command: String,
+    #[serde(default)]
+    pub records_dir: Option<String>,
+    #[serde(default)]
+    pub pcr_index: bool,
+    #[serde(default = "RecorderConfig::default_check_interval")]
+    pub check_interval: u64,
+    #[serde(default)]
+    pub conflict_policy: RecordingConflictPolicy,
+    #[serde(default)]
+    pub retention: u64,
+    #[serde(default)]
+    pub delete_expired_files: bool,
+    #[serde(default)]
+    pub retry_hook: String,
+    #[serde(default)]
+    pub retry_webhook: Option<String>,
+    #[serde(default)]
+    pub drop_policy: DropPolicy,
+}
+
+impl RecorderConfig {
+    fn default_track_airtime_command() -> String {
+        "mirakc-arib track-airtime --sid={{sid}} --eid={{eid}}".to_string()
+    }
+
+    fn default_check_interval() -> u64 {
+        60 * 60 * 1000  // 1h
+    }
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        RecorderConfig {
+            track_airtime_command: Self::default_track_airtime_command(),
+            records_dir: None,
+            pcr_index: false,
+            check_interval: Self::default_check_interval(),
+            conflict_policy: Default::default(),
+            retention: 0,
+            delete_expired_files: false,
+            retry_hook: String::new(),
+            retry_webhook: None,
+            drop_policy: Default::default(),
+        }
+    }
+}
+
+// Which schedule wins when the number of overlapping schedules exceeds the
+// number of enabled tuners.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordingConflictPolicy {
+    // A schedule added by a rule with a higher priority wins.
+    Priority,
+    // A schedule starting earlier wins.
+    StartTime,
+    // Schedules already added win.
+    RejectNew,
+}
+
+impl Default for RecordingConflictPolicy {
+    fn default() -> Self {
+        RecordingConflictPolicy::Priority
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub channel_type: ChannelType,
+    pub channel: String,
+    pub dir: String,
+    #[serde(default = "ArchiveConfig::default_segment_duration")]
+    pub segment_duration: u64,
+    #[serde(default)]
+    pub max_segments: usize,
+}
+
+impl ArchiveConfig {
+    fn default_segment_duration() -> u64 {
+        60 * 60 * 1000  // 1h
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default = "HealthConfig::default_interval")]
+    pub interval: u64,
+    #[serde(default = "HealthConfig::default_timeout")]
+    pub timeout: u64,
+}
+
+impl HealthConfig {
+    fn default_interval() -> u64 {
+        10 * 1000  // 10s
+    }
+
+    fn default_timeout() -> u64 {
+        5 * 1000  // 5s
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            file: None,
+            interval: Self::default_interval(),
+            timeout: Self::default_timeout(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct MirakurunConfig {
+    #[serde(default = "MirakurunConfig::default_openapi_json")]
+    pub openapi_json: String,
+}
+
+impl MirakurunConfig {
+    fn default_openapi_json() -> String {
+        "/etc/mirakurun.openapi.json".to_string()
+    }
+}
+
+impl Default for MirakurunConfig {
+    fn default() -> Self {
+        MirakurunConfig {
+            openapi_json: Self::default_openapi_json(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct DebugConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "DebugConfig::default_time_limit")]
+    pub time_limit: u64,
+    #[serde(default)]
+    pub psi_filter: FilterConfig,
+}
+
+impl DebugConfig {
+    fn default_time_limit() -> u64 {
+        5 * 1000  // 5s
+    }
+}
+
+impl Default for DebugConfig {
+    fn default() -> Self {
+        DebugConfig {
+            enabled: false,
+            time_limit: Self::default_time_limit(),
+            psi_filter: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_path() {
+        let cwd = Path::new("/cwd");
+        assert_eq!(resolve_path("/etc/mirakc/config.yml", cwd),
+                   PathBuf::from("/etc/mirakc/config.yml"));
+        assert_eq!(resolve_path("config.yml", cwd),
+                   PathBuf::from("/cwd/config.yml"));
+        assert_eq!(resolve_path("../config.yml", cwd),
+                   PathBuf::from("/cwd/../config.yml"));
+    }
+
+    #[test]
+    fn test_expand_env_vars_in_str() {
+        let lookup = |name: &str| match name {
+            "VAR" => Some("value".to_string()),
+            "EMPTY" => Some("".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(expand_env_vars_in_str("", &lookup).unwrap(), "");
+        assert_eq!(expand_env_vars_in_str("no var", &lookup).unwrap(),
+                   "no var");
+        assert_eq!(expand_env_vars_in_str("${VAR}", &lookup).unwrap(),
+                   "value");
+        assert_eq!(expand_env_vars_in_str("a${VAR}b${VAR}", &lookup).unwrap(),
+                   "avaluebvalue");
+        assert_eq!(expand_env_vars_in_str("${VAR:-x}", &lookup).unwrap(),
+                   "value");
+        assert_eq!(expand_env_vars_in_str("${EMPTY:-x}", &lookup).unwrap(),
+                   "");
+        assert_eq!(expand_env_vars_in_str("${UNDEF:-x}", &lookup).unwrap(),
+                   "x");
+        assert_eq!(expand_env_vars_in_str("${UNDEF:-}", &lookup).unwrap(),
+                   "");
+        assert_eq!(expand_env_vars_in_str("$${VAR}", &lookup).unwrap(),
+                   "${VAR}");
+        assert_eq!(expand_env_vars_in_str("$VAR {{x}}", &lookup).unwrap(),
+                   "$VAR {{x}}");
+
+        assert!(expand_env_vars_in_str("${UNDEF}", &lookup).is_err());
+        assert!(expand_env_vars_in_str("${VAR", &lookup).is_err());
+        assert!(expand_env_vars_in_str("${}", &lookup).is_err());
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        let lookup = |name: &str| match name {
+            "DEV" => Some("/dev/px4video2".to_string()),
+            _ => None,
+        };
+
+        let mut value = serde_yaml::from_str::<serde_yaml::Value>(r#"
+            tuners:
+              - name: ${DEV}
+                types: [GR]
+                command: recpt1 --device ${DEV} {{channel}} - -
+        "#).unwrap();
+        assert!(expand_env_vars(&mut value, &lookup).is_ok());
+        let config = serde_yaml::from_value::<Config>(value).unwrap();
+        assert_eq!(config.tuners[0].name, "/dev/px4video2");
+        assert_eq!(config.tuners[0].command,
+                   "recpt1 --device /dev/px4video2 {{channel}} - -");
+
+        let mut value = serde_yaml::from_str::<serde_yaml::Value>(r#"
+            tuners:
+              - name: ${UNDEF}
+        "#).unwrap();
+        assert!(expand_env_vars(&mut value, &lookup).is_err());
+    }
+
     #[test]
     fn test_config() {
         assert_eq!(
@@ -349,6 +1276,77 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_config_has_enabled_tuners() {
+        let config = serde_yaml::from_str::<Config>("{}").unwrap();
+        assert!(!config.has_enabled_tuners());
+
+        let config = serde_yaml::from_str::<Config>(r#"
+            tuners:
+              - name: x
+                types: [GR]
+                command: open-tuner
+                disabled: true
+        "#).unwrap();
+        assert!(!config.has_enabled_tuners());
+
+        let config = serde_yaml::from_str::<Config>(r#"
+            tuners:
+              - name: x
+                types: [GR]
+                command: open-tuner
+                disabled: true
+              - name: y
+                types: [GR]
+                command: open-tuner
+        "#).unwrap();
+        assert!(config.has_enabled_tuners());
+    }
+
+    #[test]
+    fn test_config_default_filters() {
+        let config = serde_yaml::from_str::<Config>(r#"
+            channels:
+              - name: x
+                type: GR
+                channel: '1'
+                pre-filters: [ch-pre]
+                post-filters: [ch-post]
+              - name: y
+                type: GR
+                channel: '2'
+            filters:
+              service-default-filters:
+                - services: [100001]  # NID=1, SID=1
+                  pre-filters: [sv-pre]
+                  post-filters: [sv-post]
+        "#).unwrap();
+        let service: MirakurunServiceId = 100001.into();
+
+        assert_eq!(config.default_pre_filters(ChannelType::GR, "1", None),
+                   vec!["ch-pre".to_string()]);
+        assert_eq!(config.default_post_filters(ChannelType::GR, "1", None),
+                   vec!["ch-post".to_string()]);
+        assert_eq!(
+            config.default_pre_filters(ChannelType::GR, "1", Some(service)),
+            vec!["ch-pre".to_string(), "sv-pre".to_string()]);
+        assert_eq!(
+            config.default_post_filters(ChannelType::GR, "1", Some(service)),
+            vec!["sv-post".to_string(), "ch-post".to_string()]);
+        assert_eq!(
+            config.default_pre_filters(ChannelType::GR, "2", Some(service)),
+            vec!["sv-pre".to_string()]);
+        assert!(
+            config.default_pre_filters(
+                ChannelType::GR, "2", Some(100002.into())).is_empty());
+        // The same SID in another network.
+        assert!(
+            config.default_pre_filters(
+                ChannelType::GR, "2", Some(200001.into())).is_empty());
+        assert!(config.default_pre_filters(ChannelType::BS, "1", None)
+                .is_empty());
+    }
+
     #[test]
     fn test_epg_config() {
         assert_eq!(
@@ -361,8 +1359,208 @@ mod tests {
             "#).unwrap(),
             EpgConfig {
                 cache_dir: Some("/path/to/epg".to_string()),
+                cache_save_interval:
+                    EpgConfig::default_cache_save_interval(),
+                max_schedule_days: None,
+                xmltv: None,
+                zero_duration_events: ZeroDurationEvents::Keep,
+                max_eit_line_length:
+                    EpgConfig::default_max_eit_line_length(),
+                enrichment_file: None,
+                thumbnails: None,
+                drop_invalid_sections: false,
+                unavailable_until_ready: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<EpgConfig>(r#"
+                max-schedule-days: 4
+            "#).unwrap(),
+            EpgConfig {
+                cache_dir: None,
+                cache_save_interval:
+                    EpgConfig::default_cache_save_interval(),
+                max_schedule_days: Some(4),
+                xmltv: None,
+                zero_duration_events: ZeroDurationEvents::Keep,
+                max_eit_line_length:
+                    EpgConfig::default_max_eit_line_length(),
+                enrichment_file: None,
+                thumbnails: None,
+                drop_invalid_sections: false,
+                unavailable_until_ready: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<EpgConfig>(r#"
+                xmltv:
+                  path: /path/to/xmltv.xml
+                  channels:
+                    nhk.jp: 3273601024
+            "#).unwrap(),
+            EpgConfig {
+                cache_dir: None,
+                cache_save_interval:
+                    EpgConfig::default_cache_save_interval(),
+                max_schedule_days: None,
+                xmltv: Some(XmltvConfig {
+                    path: "/path/to/xmltv.xml".to_string(),
+                    channels: vec![
+                        ("nhk.jp".to_string(),
+                         MirakurunServiceId::from(3273601024)),
+                    ].into_iter().collect(),
+                }),
+                zero_duration_events: ZeroDurationEvents::Keep,
+                max_eit_line_length:
+                    EpgConfig::default_max_eit_line_length(),
+                enrichment_file: None,
+                thumbnails: None,
+                drop_invalid_sections: false,
+                unavailable_until_ready: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<EpgConfig>(r#"
+                zero-duration-events: fill
+            "#).unwrap(),
+            EpgConfig {
+                cache_dir: None,
+                cache_save_interval:
+                    EpgConfig::default_cache_save_interval(),
+                max_schedule_days: None,
+                xmltv: None,
+                zero_duration_events: ZeroDurationEvents::Fill,
+                max_eit_line_length:
+                    EpgConfig::default_max_eit_line_length(),
+                enrichment_file: None,
+                thumbnails: None,
+                drop_invalid_sections: false,
+                unavailable_until_ready: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<EpgConfig>(r#"
+                max-eit-line-length: 4096
+            "#).unwrap(),
+            EpgConfig {
+                cache_dir: None,
+                cache_save_interval:
+                    EpgConfig::default_cache_save_interval(),
+                max_schedule_days: None,
+                xmltv: None,
+                zero_duration_events: ZeroDurationEvents::Keep,
+                max_eit_line_length: 4096,
+                enrichment_file: None,
+                thumbnails: None,
+                drop_invalid_sections: false,
+                unavailable_until_ready: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<EpgConfig>(r#"
+                enrichment-file: /path/to/enrichment.json
+            "#).unwrap(),
+            EpgConfig {
+                cache_dir: None,
+                cache_save_interval:
+                    EpgConfig::default_cache_save_interval(),
+                max_schedule_days: None,
+                xmltv: None,
+                zero_duration_events: ZeroDurationEvents::Keep,
+                max_eit_line_length:
+                    EpgConfig::default_max_eit_line_length(),
+                enrichment_file: Some("/path/to/enrichment.json".to_string()),
+                thumbnails: None,
+                drop_invalid_sections: false,
+                unavailable_until_ready: false,
             });
 
+        assert_eq!(
+            serde_yaml::from_str::<EpgConfig>(r#"
+                thumbnails:
+                  dir: /path/to/thumbnails
+            "#).unwrap(),
+            EpgConfig {
+                cache_dir: None,
+                cache_save_interval:
+                    EpgConfig::default_cache_save_interval(),
+                max_schedule_days: None,
+                xmltv: None,
+                zero_duration_events: ZeroDurationEvents::Keep,
+                max_eit_line_length:
+                    EpgConfig::default_max_eit_line_length(),
+                enrichment_file: None,
+                thumbnails: Some(ThumbnailsConfig {
+                    dir: "/path/to/thumbnails".to_string(),
+                    command: "".to_string(),
+                }),
+                drop_invalid_sections: false,
+                unavailable_until_ready: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<EpgConfig>(r#"
+                drop-invalid-sections: true
+            "#).unwrap(),
+            EpgConfig {
+                cache_dir: None,
+                cache_save_interval:
+                    EpgConfig::default_cache_save_interval(),
+                max_schedule_days: None,
+                xmltv: None,
+                zero_duration_events: ZeroDurationEvents::Keep,
+                max_eit_line_length:
+                    EpgConfig::default_max_eit_line_length(),
+                enrichment_file: None,
+                thumbnails: None,
+                drop_invalid_sections: true,
+                unavailable_until_ready: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<EpgConfig>(r#"
+                cache-save-interval: 0
+            "#).unwrap(),
+            EpgConfig {
+                cache_save_interval: 0,
+                ..Default::default()
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<EpgConfig>(r#"
+                unavailable-until-ready: true
+            "#).unwrap(),
+            EpgConfig {
+                cache_dir: None,
+                cache_save_interval:
+                    EpgConfig::default_cache_save_interval(),
+                max_schedule_days: None,
+                xmltv: None,
+                zero_duration_events: ZeroDurationEvents::Keep,
+                max_eit_line_length:
+                    EpgConfig::default_max_eit_line_length(),
+                enrichment_file: None,
+                thumbnails: None,
+                drop_invalid_sections: false,
+                unavailable_until_ready: true,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ThumbnailsConfig>(r#"
+                dir: /path/to/thumbnails
+                command: extract --dir={{dir}}
+            "#).unwrap(),
+            ThumbnailsConfig {
+                dir: "/path/to/thumbnails".to_string(),
+                command: "extract --dir={{dir}}".to_string(),
+            });
+
+        let result = serde_yaml::from_str::<EpgConfig>(r#"
+            xmltv:
+              path: /path/to/xmltv.xml
+        "#);
+        assert!(result.is_err());
+
         let result = serde_yaml::from_str::<EpgConfig>(r#"
             unknown:
               property: value
@@ -389,6 +1587,15 @@ mod tests {
                 stream_max_chunks: ServerConfig::default_stream_max_chunks(),
                 stream_chunk_size: ServerConfig::default_stream_chunk_size(),
                 stream_time_limit: ServerConfig::default_stream_time_limit(),
+                stream_idle_timeout: 0,
+                stream_stats_retention: 0,
+                stream_coalesce_size: 0,
+                stream_coalesce_timeout:
+                    ServerConfig::default_stream_coalesce_timeout(),
+                stream_jitter_buffer_size: 0,
+                stream_jitter_buffer_time: 0,
+                access_log: None,
+                log_session_label: false,
             });
 
         assert_eq!(
@@ -404,6 +1611,15 @@ mod tests {
                 stream_max_chunks: ServerConfig::default_stream_max_chunks(),
                 stream_chunk_size: ServerConfig::default_stream_chunk_size(),
                 stream_time_limit: ServerConfig::default_stream_time_limit(),
+                stream_idle_timeout: 0,
+                stream_stats_retention: 0,
+                stream_coalesce_size: 0,
+                stream_coalesce_timeout:
+                    ServerConfig::default_stream_coalesce_timeout(),
+                stream_jitter_buffer_size: 0,
+                stream_jitter_buffer_time: 0,
+                access_log: None,
+                log_session_label: false,
             });
 
         assert_eq!(
@@ -421,6 +1637,15 @@ mod tests {
                 stream_max_chunks: ServerConfig::default_stream_max_chunks(),
                 stream_chunk_size: ServerConfig::default_stream_chunk_size(),
                 stream_time_limit: ServerConfig::default_stream_time_limit(),
+                stream_idle_timeout: 0,
+                stream_stats_retention: 0,
+                stream_coalesce_size: 0,
+                stream_coalesce_timeout:
+                    ServerConfig::default_stream_coalesce_timeout(),
+                stream_jitter_buffer_size: 0,
+                stream_jitter_buffer_time: 0,
+                access_log: None,
+                log_session_label: false,
             });
 
         assert_eq!(
@@ -433,6 +1658,15 @@ mod tests {
                 stream_max_chunks: ServerConfig::default_stream_max_chunks(),
                 stream_chunk_size: ServerConfig::default_stream_chunk_size(),
                 stream_time_limit: ServerConfig::default_stream_time_limit(),
+                stream_idle_timeout: 0,
+                stream_stats_retention: 0,
+                stream_coalesce_size: 0,
+                stream_coalesce_timeout:
+                    ServerConfig::default_stream_coalesce_timeout(),
+                stream_jitter_buffer_size: 0,
+                stream_jitter_buffer_time: 0,
+                access_log: None,
+                log_session_label: false,
             });
 
         assert_eq!(
@@ -445,6 +1679,15 @@ mod tests {
                 stream_max_chunks: 1000,
                 stream_chunk_size: ServerConfig::default_stream_chunk_size(),
                 stream_time_limit: ServerConfig::default_stream_time_limit(),
+                stream_idle_timeout: 0,
+                stream_stats_retention: 0,
+                stream_coalesce_size: 0,
+                stream_coalesce_timeout:
+                    ServerConfig::default_stream_coalesce_timeout(),
+                stream_jitter_buffer_size: 0,
+                stream_jitter_buffer_time: 0,
+                access_log: None,
+                log_session_label: false,
             });
 
         assert_eq!(
@@ -457,6 +1700,15 @@ mod tests {
                 stream_max_chunks: ServerConfig::default_stream_max_chunks(),
                 stream_chunk_size: 10000,
                 stream_time_limit: ServerConfig::default_stream_time_limit(),
+                stream_idle_timeout: 0,
+                stream_stats_retention: 0,
+                stream_coalesce_size: 0,
+                stream_coalesce_timeout:
+                    ServerConfig::default_stream_coalesce_timeout(),
+                stream_jitter_buffer_size: 0,
+                stream_jitter_buffer_time: 0,
+                access_log: None,
+                log_session_label: false,
             });
 
         assert_eq!(
@@ -469,6 +1721,141 @@ mod tests {
                 stream_max_chunks: ServerConfig::default_stream_max_chunks(),
                 stream_chunk_size: ServerConfig::default_stream_chunk_size(),
                 stream_time_limit: 10000,
+                stream_idle_timeout: 0,
+                stream_stats_retention: 0,
+                stream_coalesce_size: 0,
+                stream_coalesce_timeout:
+                    ServerConfig::default_stream_coalesce_timeout(),
+                stream_jitter_buffer_size: 0,
+                stream_jitter_buffer_time: 0,
+                access_log: None,
+                log_session_label: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ServerConfig>(r#"
+                stream-idle-timeout: 10000
+            "#).unwrap(),
+            ServerConfig {
+                addrs: ServerConfig::default_addrs(),
+                workers: ServerConfig::default_workers(),
+                stream_max_chunks: ServerConfig::default_stream_max_chunks(),
+                stream_chunk_size: ServerConfig::default_stream_chunk_size(),
+                stream_time_limit: ServerConfig::default_stream_time_limit(),
+                stream_idle_timeout: 10000,
+                stream_stats_retention: 0,
+                stream_coalesce_size: 0,
+                stream_coalesce_timeout:
+                    ServerConfig::default_stream_coalesce_timeout(),
+                stream_jitter_buffer_size: 0,
+                stream_jitter_buffer_time: 0,
+                access_log: None,
+                log_session_label: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ServerConfig>(r#"
+                stream-stats-retention: 60000
+            "#).unwrap(),
+            ServerConfig {
+                addrs: ServerConfig::default_addrs(),
+                workers: ServerConfig::default_workers(),
+                stream_max_chunks: ServerConfig::default_stream_max_chunks(),
+                stream_chunk_size: ServerConfig::default_stream_chunk_size(),
+                stream_time_limit: ServerConfig::default_stream_time_limit(),
+                stream_idle_timeout: 0,
+                stream_stats_retention: 60000,
+                stream_coalesce_size: 0,
+                stream_coalesce_timeout:
+                    ServerConfig::default_stream_coalesce_timeout(),
+                stream_jitter_buffer_size: 0,
+                stream_jitter_buffer_time: 0,
+                access_log: None,
+                log_session_label: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ServerConfig>(r#"
+                stream-coalesce-size: 131072
+                stream-coalesce-timeout: 50
+            "#).unwrap(),
+            ServerConfig {
+                addrs: ServerConfig::default_addrs(),
+                workers: ServerConfig::default_workers(),
+                stream_max_chunks: ServerConfig::default_stream_max_chunks(),
+                stream_chunk_size: ServerConfig::default_stream_chunk_size(),
+                stream_time_limit: ServerConfig::default_stream_time_limit(),
+                stream_idle_timeout: 0,
+                stream_stats_retention: 0,
+                stream_coalesce_size: 131072,
+                stream_coalesce_timeout: 50,
+                stream_jitter_buffer_size: 0,
+                stream_jitter_buffer_time: 0,
+                access_log: None,
+                log_session_label: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ServerConfig>(r#"
+                stream-jitter-buffer-size: 1048576
+                stream-jitter-buffer-time: 1000
+            "#).unwrap(),
+            ServerConfig {
+                addrs: ServerConfig::default_addrs(),
+                workers: ServerConfig::default_workers(),
+                stream_max_chunks: ServerConfig::default_stream_max_chunks(),
+                stream_chunk_size: ServerConfig::default_stream_chunk_size(),
+                stream_time_limit: ServerConfig::default_stream_time_limit(),
+                stream_idle_timeout: 0,
+                stream_stats_retention: 0,
+                stream_coalesce_size: 0,
+                stream_coalesce_timeout:
+                    ServerConfig::default_stream_coalesce_timeout(),
+                stream_jitter_buffer_size: 1048576,
+                stream_jitter_buffer_time: 1000,
+                access_log: None,
+                log_session_label: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ServerConfig>(r#"
+                access-log:
+                  path: /var/log/mirakc/access.log
+                  rotation-interval: 86400000
+            "#).unwrap(),
+            ServerConfig {
+                addrs: ServerConfig::default_addrs(),
+                workers: ServerConfig::default_workers(),
+                stream_max_chunks: ServerConfig::default_stream_max_chunks(),
+                stream_chunk_size: ServerConfig::default_stream_chunk_size(),
+                stream_time_limit: ServerConfig::default_stream_time_limit(),
+                stream_idle_timeout: 0,
+                stream_stats_retention: 0,
+                stream_coalesce_size: 0,
+                stream_coalesce_timeout:
+                    ServerConfig::default_stream_coalesce_timeout(),
+                stream_jitter_buffer_size: 0,
+                stream_jitter_buffer_time: 0,
+                access_log: Some(AccessLogConfig {
+                    path: "/var/log/mirakc/access.log".to_string(),
+                    max_size: AccessLogConfig::default_max_size(),
+                    rotation_interval: 86400000,
+                    max_files: AccessLogConfig::default_max_files(),
+                }),
+                log_session_label: false,
+            });
+
+        assert!(serde_yaml::from_str::<ServerConfig>(r#"
+            access-log: {}
+        "#).is_err());
+
+        assert_eq!(
+            serde_yaml::from_str::<ServerConfig>(r#"
+                log-session-label: true
+            "#).unwrap(),
+            ServerConfig {
+                log_session_label: true,
+                ..Default::default()
             });
 
         let result = serde_yaml::from_str::<ServerConfig>(r#"
@@ -496,6 +1883,11 @@ mod tests {
                 services: vec![],
                 excluded_services: vec![],
                 disabled: false,
+                fallback: None,
+                epg_priority: 0,
+                expected_services: vec![],
+                pre_filters: vec![],
+                post_filters: vec![],
             });
 
         assert_eq!(
@@ -513,6 +1905,11 @@ mod tests {
                 services: vec![],
                 excluded_services: vec![],
                 disabled: false,
+                fallback: None,
+                epg_priority: 0,
+                expected_services: vec![],
+                pre_filters: vec![],
+                post_filters: vec![],
             });
 
         assert_eq!(
@@ -530,6 +1927,11 @@ mod tests {
                 services: vec![],
                 excluded_services: vec![],
                 disabled: true,
+                fallback: None,
+                epg_priority: 0,
+                expected_services: vec![],
+                pre_filters: vec![],
+                post_filters: vec![],
             });
 
         assert_eq!(
@@ -547,9 +1949,117 @@ mod tests {
                 services: vec![],
                 excluded_services: vec![100.into()],
                 disabled: false,
+                fallback: None,
+                epg_priority: 0,
+                expected_services: vec![],
+                pre_filters: vec![],
+                post_filters: vec![],
             });
 
-        assert!(
+        assert_eq!(
+            serde_yaml::from_str::<ChannelConfig>(r#"
+                name: x
+                type: GR
+                channel: y
+                fallback:
+                  type: GR
+                  channel: z
+            "#).unwrap(),
+            ChannelConfig {
+                name: "x".to_string(),
+                channel_type: ChannelType::GR,
+                channel: "y".to_string(),
+                extra_args: "".to_string(),
+                services: vec![],
+                excluded_services: vec![],
+                disabled: false,
+                fallback: Some(FallbackChannelConfig {
+                    channel_type: ChannelType::GR,
+                    channel: "z".to_string(),
+                }),
+                epg_priority: 0,
+                expected_services: vec![],
+                pre_filters: vec![],
+                post_filters: vec![],
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ChannelConfig>(r#"
+                name: x
+                type: GR
+                channel: y
+                epg-priority: 1
+            "#).unwrap(),
+            ChannelConfig {
+                name: "x".to_string(),
+                channel_type: ChannelType::GR,
+                channel: "y".to_string(),
+                extra_args: "".to_string(),
+                services: vec![],
+                excluded_services: vec![],
+                disabled: false,
+                fallback: None,
+                epg_priority: 1,
+                expected_services: vec![],
+                pre_filters: vec![],
+                post_filters: vec![],
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ChannelConfig>(r#"
+                name: x
+                type: GR
+                channel: y
+                expected-services: [1, 2]
+            "#).unwrap(),
+            ChannelConfig {
+                name: "x".to_string(),
+                channel_type: ChannelType::GR,
+                channel: "y".to_string(),
+                extra_args: "".to_string(),
+                services: vec![],
+                excluded_services: vec![],
+                disabled: false,
+                fallback: None,
+                epg_priority: 0,
+                expected_services: vec![1.into(), 2.into()],
+                pre_filters: vec![],
+                post_filters: vec![],
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ChannelConfig>(r#"
+                name: x
+                type: GR
+                channel: y
+                pre-filters: [a]
+                post-filters: [b]
+            "#).unwrap(),
+            ChannelConfig {
+                name: "x".to_string(),
+                channel_type: ChannelType::GR,
+                channel: "y".to_string(),
+                extra_args: "".to_string(),
+                services: vec![],
+                excluded_services: vec![],
+                disabled: false,
+                fallback: None,
+                epg_priority: 0,
+                expected_services: vec![],
+                pre_filters: vec!["a".to_string()],
+                post_filters: vec!["b".to_string()],
+            });
+
+        assert!(
+            serde_yaml::from_str::<ChannelConfig>(r#"
+                name: x
+                type: GR
+                channel: y
+                fallback:
+                  channel: z
+            "#).is_err());
+
+        assert!(
             serde_yaml::from_str::<ChannelConfig>(r#"
                 name: x
                 type: WOWOW
@@ -582,6 +2092,13 @@ mod tests {
                 command: "open tuner".to_string(),
                 time_limit: TunerConfig::default_time_limit(),
                 disabled: false,
+                priority: 0,
+                drop_log_interval: TunerConfig::default_drop_log_interval(),
+                signal_stats_pattern: None,
+                zero_read_policy: ZeroReadPolicy::Eof,
+                max_restarts: 0,
+                startup_timeout: 0,
+                process_limits: Default::default(),
             });
 
         assert_eq!(
@@ -600,6 +2117,13 @@ mod tests {
                 command: "open tuner".to_string(),
                 time_limit: 1,
                 disabled: false,
+                priority: 0,
+                drop_log_interval: TunerConfig::default_drop_log_interval(),
+                signal_stats_pattern: None,
+                zero_read_policy: ZeroReadPolicy::Eof,
+                max_restarts: 0,
+                startup_timeout: 0,
+                process_limits: Default::default(),
             });
 
         assert_eq!(
@@ -618,8 +2142,210 @@ mod tests {
                 command: "open tuner".to_string(),
                 time_limit: TunerConfig::default_time_limit(),
                 disabled: true,
+                priority: 0,
+                drop_log_interval: TunerConfig::default_drop_log_interval(),
+                signal_stats_pattern: None,
+                zero_read_policy: ZeroReadPolicy::Eof,
+                max_restarts: 0,
+                startup_timeout: 0,
+                process_limits: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<TunerConfig>(r#"
+                name: x
+                types: [GR, BS, CS, SKY]
+                command: open tuner
+                priority: 1
+            "#).unwrap(),
+            TunerConfig {
+                name: "x".to_string(),
+                channel_types: vec![ChannelType::GR,
+                                    ChannelType::BS,
+                                    ChannelType::CS,
+                                    ChannelType::SKY],
+                command: "open tuner".to_string(),
+                time_limit: TunerConfig::default_time_limit(),
+                disabled: false,
+                priority: 1,
+                drop_log_interval: TunerConfig::default_drop_log_interval(),
+                signal_stats_pattern: None,
+                zero_read_policy: ZeroReadPolicy::Eof,
+                max_restarts: 0,
+                startup_timeout: 0,
+                process_limits: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<TunerConfig>(r#"
+                name: x
+                types: [GR, BS, CS, SKY]
+                command: open tuner
+                drop-log-interval: 1000
+            "#).unwrap(),
+            TunerConfig {
+                name: "x".to_string(),
+                channel_types: vec![ChannelType::GR,
+                                    ChannelType::BS,
+                                    ChannelType::CS,
+                                    ChannelType::SKY],
+                command: "open tuner".to_string(),
+                time_limit: TunerConfig::default_time_limit(),
+                disabled: false,
+                priority: 0,
+                drop_log_interval: 1000,
+                signal_stats_pattern: None,
+                zero_read_policy: ZeroReadPolicy::Eof,
+                max_restarts: 0,
+                startup_timeout: 0,
+                process_limits: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<TunerConfig>(r#"
+                name: x
+                types: [GR, BS, CS, SKY]
+                command: open tuner
+                signal-stats-pattern: 'SNR: (?P<snr>[0-9.]+)'
+            "#).unwrap(),
+            TunerConfig {
+                name: "x".to_string(),
+                channel_types: vec![ChannelType::GR,
+                                    ChannelType::BS,
+                                    ChannelType::CS,
+                                    ChannelType::SKY],
+                command: "open tuner".to_string(),
+                time_limit: TunerConfig::default_time_limit(),
+                disabled: false,
+                priority: 0,
+                drop_log_interval: TunerConfig::default_drop_log_interval(),
+                signal_stats_pattern: Some("SNR: (?P<snr>[0-9.]+)".to_string()),
+                zero_read_policy: ZeroReadPolicy::Eof,
+                max_restarts: 0,
+                startup_timeout: 0,
+                process_limits: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<TunerConfig>(r#"
+                name: x
+                types: [GR, BS, CS, SKY]
+                command: open tuner
+                zero-read-policy: retry
+            "#).unwrap(),
+            TunerConfig {
+                name: "x".to_string(),
+                channel_types: vec![ChannelType::GR,
+                                    ChannelType::BS,
+                                    ChannelType::CS,
+                                    ChannelType::SKY],
+                command: "open tuner".to_string(),
+                time_limit: TunerConfig::default_time_limit(),
+                disabled: false,
+                priority: 0,
+                drop_log_interval: TunerConfig::default_drop_log_interval(),
+                signal_stats_pattern: None,
+                zero_read_policy: ZeroReadPolicy::Retry,
+                max_restarts: 0,
+                startup_timeout: 0,
+                process_limits: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<TunerConfig>(r#"
+                name: x
+                types: [GR, BS, CS, SKY]
+                command: open tuner
+                max-restarts: 3
+            "#).unwrap(),
+            TunerConfig {
+                name: "x".to_string(),
+                channel_types: vec![ChannelType::GR,
+                                    ChannelType::BS,
+                                    ChannelType::CS,
+                                    ChannelType::SKY],
+                command: "open tuner".to_string(),
+                time_limit: TunerConfig::default_time_limit(),
+                disabled: false,
+                priority: 0,
+                drop_log_interval: TunerConfig::default_drop_log_interval(),
+                signal_stats_pattern: None,
+                zero_read_policy: ZeroReadPolicy::Eof,
+                max_restarts: 3,
+                startup_timeout: 0,
+                process_limits: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<TunerConfig>(r#"
+                name: x
+                types: [GR, BS, CS, SKY]
+                command: open tuner
+                startup-timeout: 5000
+            "#).unwrap(),
+            TunerConfig {
+                name: "x".to_string(),
+                channel_types: vec![ChannelType::GR,
+                                    ChannelType::BS,
+                                    ChannelType::CS,
+                                    ChannelType::SKY],
+                command: "open tuner".to_string(),
+                time_limit: TunerConfig::default_time_limit(),
+                disabled: false,
+                priority: 0,
+                drop_log_interval: TunerConfig::default_drop_log_interval(),
+                signal_stats_pattern: None,
+                zero_read_policy: ZeroReadPolicy::Eof,
+                max_restarts: 0,
+                startup_timeout: 5000,
+                process_limits: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<TunerConfig>(r#"
+                name: x
+                types: [GR, BS, CS, SKY]
+                command: open tuner
+                process-limits:
+                  nice: 10
+                  max-memory: 268435456
+                  max-cpu-time: 3600
+                  cgroup: /sys/fs/cgroup/mirakc
+                  kill-grace-period: 1000
+            "#).unwrap(),
+            TunerConfig {
+                name: "x".to_string(),
+                channel_types: vec![ChannelType::GR,
+                                    ChannelType::BS,
+                                    ChannelType::CS,
+                                    ChannelType::SKY],
+                command: "open tuner".to_string(),
+                time_limit: TunerConfig::default_time_limit(),
+                disabled: false,
+                priority: 0,
+                drop_log_interval: TunerConfig::default_drop_log_interval(),
+                signal_stats_pattern: None,
+                zero_read_policy: ZeroReadPolicy::Eof,
+                max_restarts: 0,
+                startup_timeout: 0,
+                process_limits: ProcessLimitsConfig {
+                    nice: Some(10),
+                    max_memory: Some(268435456),
+                    max_cpu_time: Some(3600),
+                    cgroup: Some("/sys/fs/cgroup/mirakc".to_string()),
+                    kill_grace_period: 1000,
+                },
             });
 
+        assert!(
+            serde_yaml::from_str::<TunerConfig>(r#"
+                name: x
+                types: [GR]
+                command: open tuner
+                process-limits:
+                  unknown: 1
+            "#).is_err());
+
         assert!(
             serde_yaml::from_str::<TunerConfig>(r#"
                 name: x
@@ -646,10 +2372,22 @@ mod tests {
                   command: filter
             "#).unwrap(),
             FiltersConfig {
-                tuner_filter: FilterConfig { command: "filter".to_string() },
+                tuner_filter: FilterConfig {
+                    command: "filter".to_string(),
+                    process_limits: None,
+                },
                 service_filter: FiltersConfig::default_service_filter(),
+                builtin_service_filter: false,
                 decode_filter: Default::default(),
                 program_filter: FiltersConfig::default_program_filter(),
+                service_decode_filters: vec![],
+                service_default_filters: vec![],
+                multi_service_filter: Default::default(),
+                fmp4_filter: FiltersConfig::default_fmp4_filter(),
+                max_pipeline_stages:
+                    FiltersConfig::default_max_pipeline_stages(),
+                runtime_update: false,
+                process_limits: Default::default(),
             });
 
         assert_eq!(
@@ -659,9 +2397,21 @@ mod tests {
             "#).unwrap(),
             FiltersConfig {
                 tuner_filter: Default::default(),
-                service_filter: FilterConfig { command: "filter".to_string() },
+                service_filter: FilterConfig {
+                    command: "filter".to_string(),
+                    process_limits: None,
+                },
+                builtin_service_filter: false,
                 decode_filter: Default::default(),
                 program_filter: FiltersConfig::default_program_filter(),
+                service_decode_filters: vec![],
+                service_default_filters: vec![],
+                multi_service_filter: Default::default(),
+                fmp4_filter: FiltersConfig::default_fmp4_filter(),
+                max_pipeline_stages:
+                    FiltersConfig::default_max_pipeline_stages(),
+                runtime_update: false,
+                process_limits: Default::default(),
             });
 
         assert_eq!(
@@ -672,8 +2422,20 @@ mod tests {
             FiltersConfig {
                 tuner_filter: Default::default(),
                 service_filter: FiltersConfig::default_service_filter(),
-                decode_filter: FilterConfig { command: "filter".to_string() },
+                builtin_service_filter: false,
+                decode_filter: FilterConfig {
+                    command: "filter".to_string(),
+                    process_limits: None,
+                },
                 program_filter: FiltersConfig::default_program_filter(),
+                service_decode_filters: vec![],
+                service_default_filters: vec![],
+                multi_service_filter: Default::default(),
+                fmp4_filter: FiltersConfig::default_fmp4_filter(),
+                max_pipeline_stages:
+                    FiltersConfig::default_max_pipeline_stages(),
+                runtime_update: false,
+                process_limits: Default::default(),
             });
 
         assert_eq!(
@@ -684,51 +2446,303 @@ mod tests {
             FiltersConfig {
                 tuner_filter: Default::default(),
                 service_filter: FiltersConfig::default_service_filter(),
+                builtin_service_filter: false,
                 decode_filter: Default::default(),
-                program_filter: FilterConfig { command: "filter".to_string() },
+                program_filter: FilterConfig {
+                    command: "filter".to_string(),
+                    process_limits: None,
+                },
+                service_decode_filters: vec![],
+                service_default_filters: vec![],
+                multi_service_filter: Default::default(),
+                fmp4_filter: FiltersConfig::default_fmp4_filter(),
+                max_pipeline_stages:
+                    FiltersConfig::default_max_pipeline_stages(),
+                runtime_update: false,
+                process_limits: Default::default(),
             });
 
-        let result = serde_yaml::from_str::<FiltersConfig>(r#"
-            unknown:
-              property: value
-        "#);
-        assert!(result.is_err());
-    }
-
-    #[test]
-    fn test_filter_config() {
-        assert_eq!(
-            serde_yaml::from_str::<FilterConfig>("{}").unwrap(),
-            Default::default());
-
         assert_eq!(
-            serde_yaml::from_str::<FilterConfig>(r#"
-                command: filter
+            serde_yaml::from_str::<FiltersConfig>(r#"
+                service-decode-filters:
+                  - services: [1, 2]
+                    command: filter
             "#).unwrap(),
-            FilterConfig {
-                command: "filter".to_string(),
+            FiltersConfig {
+                tuner_filter: Default::default(),
+                service_filter: FiltersConfig::default_service_filter(),
+                builtin_service_filter: false,
+                decode_filter: Default::default(),
+                program_filter: FiltersConfig::default_program_filter(),
+                service_decode_filters: vec![ServiceDecodeFilterConfig {
+                    services: vec![1.into(), 2.into()],
+                    command: "filter".to_string(),
+                }],
+                service_default_filters: vec![],
+                multi_service_filter: Default::default(),
+                fmp4_filter: FiltersConfig::default_fmp4_filter(),
+                max_pipeline_stages:
+                    FiltersConfig::default_max_pipeline_stages(),
+                runtime_update: false,
+                process_limits: Default::default(),
             });
 
-        let result = serde_yaml::from_str::<FilterConfig>(r#"
-            unknown:
-              property: value
-        "#);
-        assert!(result.is_err());
-    }
-
-    #[test]
-    fn test_post_filter_config() {
         assert_eq!(
-            serde_yaml::from_str::<PostFilterConfig>("{}").unwrap(),
-            Default::default());
+            serde_yaml::from_str::<FiltersConfig>(r#"
+                service-default-filters:
+                  - services: [100001, 100002]
+                    pre-filters: [a]
+                  - services: [200001]
+                    post-filters: [b]
+            "#).unwrap(),
+            FiltersConfig {
+                tuner_filter: Default::default(),
+                service_filter: FiltersConfig::default_service_filter(),
+                builtin_service_filter: false,
+                decode_filter: Default::default(),
+                program_filter: FiltersConfig::default_program_filter(),
+                service_decode_filters: vec![],
+                service_default_filters: vec![
+                    ServiceDefaultFiltersConfig {
+                        services: vec![100001.into(), 100002.into()],
+                        pre_filters: vec!["a".to_string()],
+                        post_filters: vec![],
+                    },
+                    ServiceDefaultFiltersConfig {
+                        services: vec![200001.into()],
+                        pre_filters: vec![],
+                        post_filters: vec!["b".to_string()],
+                    },
+                ],
+                multi_service_filter: Default::default(),
+                fmp4_filter: FiltersConfig::default_fmp4_filter(),
+                max_pipeline_stages:
+                    FiltersConfig::default_max_pipeline_stages(),
+                runtime_update: false,
+                process_limits: Default::default(),
+            });
 
         assert_eq!(
-            serde_yaml::from_str::<PostFilterConfig>(r#"
-                command: filter
+            serde_yaml::from_str::<FiltersConfig>(r#"
+                multi-service-filter:
+                  command: filter
             "#).unwrap(),
-            PostFilterConfig {
-                command: "filter".to_string(),
-                content_type: None,
+            FiltersConfig {
+                tuner_filter: Default::default(),
+                service_filter: FiltersConfig::default_service_filter(),
+                builtin_service_filter: false,
+                decode_filter: Default::default(),
+                program_filter: FiltersConfig::default_program_filter(),
+                service_decode_filters: vec![],
+                service_default_filters: vec![],
+                multi_service_filter:
+                    FilterConfig {
+                        command: "filter".to_string(),
+                        process_limits: None,
+                    },
+                fmp4_filter: FiltersConfig::default_fmp4_filter(),
+                max_pipeline_stages:
+                    FiltersConfig::default_max_pipeline_stages(),
+                runtime_update: false,
+                process_limits: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<FiltersConfig>(r#"
+                max-pipeline-stages: 4
+            "#).unwrap(),
+            FiltersConfig {
+                tuner_filter: Default::default(),
+                service_filter: FiltersConfig::default_service_filter(),
+                builtin_service_filter: false,
+                decode_filter: Default::default(),
+                program_filter: FiltersConfig::default_program_filter(),
+                service_decode_filters: vec![],
+                service_default_filters: vec![],
+                multi_service_filter: Default::default(),
+                fmp4_filter: FiltersConfig::default_fmp4_filter(),
+                max_pipeline_stages: 4,
+                runtime_update: false,
+                process_limits: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<FiltersConfig>(r#"
+                process-limits:
+                  kill-grace-period: 1000
+            "#).unwrap(),
+            FiltersConfig {
+                tuner_filter: Default::default(),
+                service_filter: FiltersConfig::default_service_filter(),
+                builtin_service_filter: false,
+                decode_filter: Default::default(),
+                program_filter: FiltersConfig::default_program_filter(),
+                service_decode_filters: vec![],
+                service_default_filters: vec![],
+                multi_service_filter: Default::default(),
+                fmp4_filter: FiltersConfig::default_fmp4_filter(),
+                max_pipeline_stages:
+                    FiltersConfig::default_max_pipeline_stages(),
+                runtime_update: false,
+                process_limits: ProcessLimitsConfig {
+                    kill_grace_period: 1000,
+                    ..Default::default()
+                },
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<FiltersConfig>(r#"
+                builtin-service-filter: true
+            "#).unwrap(),
+            FiltersConfig {
+                builtin_service_filter: true,
+                ..Default::default()
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<FiltersConfig>(r#"
+                runtime-update: true
+            "#).unwrap(),
+            FiltersConfig {
+                tuner_filter: Default::default(),
+                service_filter: FiltersConfig::default_service_filter(),
+                builtin_service_filter: false,
+                decode_filter: Default::default(),
+                program_filter: FiltersConfig::default_program_filter(),
+                service_decode_filters: vec![],
+                service_default_filters: vec![],
+                multi_service_filter: Default::default(),
+                fmp4_filter: FiltersConfig::default_fmp4_filter(),
+                max_pipeline_stages:
+                    FiltersConfig::default_max_pipeline_stages(),
+                runtime_update: true,
+                process_limits: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<FiltersConfig>(r#"
+                process-limits:
+                  nice: 19
+            "#).unwrap(),
+            FiltersConfig {
+                tuner_filter: Default::default(),
+                service_filter: FiltersConfig::default_service_filter(),
+                builtin_service_filter: false,
+                decode_filter: Default::default(),
+                program_filter: FiltersConfig::default_program_filter(),
+                service_decode_filters: vec![],
+                service_default_filters: vec![],
+                multi_service_filter: Default::default(),
+                fmp4_filter: FiltersConfig::default_fmp4_filter(),
+                max_pipeline_stages:
+                    FiltersConfig::default_max_pipeline_stages(),
+                runtime_update: false,
+                process_limits: ProcessLimitsConfig {
+                    nice: Some(19),
+                    ..Default::default()
+                },
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<FiltersConfig>(r#"
+                fmp4-filter:
+                  command: filter
+            "#).unwrap(),
+            FiltersConfig {
+                tuner_filter: Default::default(),
+                service_filter: FiltersConfig::default_service_filter(),
+                builtin_service_filter: false,
+                decode_filter: Default::default(),
+                program_filter: FiltersConfig::default_program_filter(),
+                service_decode_filters: vec![],
+                service_default_filters: vec![],
+                multi_service_filter: Default::default(),
+                fmp4_filter: FilterConfig {
+                    command: "filter".to_string(),
+                    process_limits: None,
+                },
+                max_pipeline_stages:
+                    FiltersConfig::default_max_pipeline_stages(),
+                runtime_update: false,
+                process_limits: Default::default(),
+            });
+
+        let result = serde_yaml::from_str::<FiltersConfig>(r#"
+            unknown:
+              property: value
+        "#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_find_service_decode_filter() {
+        let config = serde_yaml::from_str::<FiltersConfig>(r#"
+            service-decode-filters:
+              - services: [1, 2]
+                command: filter1
+              - services: [3]
+                command: filter3
+        "#).unwrap();
+        let find = |sid: u16| {
+            config.find_service_decode_filter(sid.into())
+                .map(|config| config.command.as_str())
+        };
+        assert_eq!(find(1), Some("filter1"));
+        assert_eq!(find(2), Some("filter1"));
+        assert_eq!(find(3), Some("filter3"));
+        assert_eq!(find(4), None);
+    }
+
+    #[test]
+    fn test_filter_config() {
+        assert_eq!(
+            serde_yaml::from_str::<FilterConfig>("{}").unwrap(),
+            Default::default());
+
+        assert_eq!(
+            serde_yaml::from_str::<FilterConfig>(r#"
+                command: filter
+            "#).unwrap(),
+            FilterConfig {
+                command: "filter".to_string(),
+                process_limits: None,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<FilterConfig>(r#"
+                command: filter
+                process-limits:
+                  nice: 10
+            "#).unwrap(),
+            FilterConfig {
+                command: "filter".to_string(),
+                process_limits: Some(ProcessLimitsConfig {
+                    nice: Some(10),
+                    ..Default::default()
+                }),
+            });
+
+        let result = serde_yaml::from_str::<FilterConfig>(r#"
+            unknown:
+              property: value
+        "#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_post_filter_config() {
+        assert_eq!(
+            serde_yaml::from_str::<PostFilterConfig>("{}").unwrap(),
+            Default::default());
+
+        assert_eq!(
+            serde_yaml::from_str::<PostFilterConfig>(r#"
+                command: filter
+            "#).unwrap(),
+            PostFilterConfig {
+                command: "filter".to_string(),
+                content_type: None,
+                process_limits: None,
             });
 
         assert_eq!(
@@ -739,6 +2753,7 @@ mod tests {
             PostFilterConfig {
                 command: "filter".to_string(),
                 content_type: Some("video/mp4".to_string()),
+                process_limits: None,
             });
 
         let result = serde_yaml::from_str::<PostFilterConfig>(r#"
@@ -764,9 +2779,19 @@ mod tests {
                 scan_services: JobConfig {
                     command: "job".to_string(),
                     schedule: "*".to_string(),
+                    timeout: None,
+                    tables: None,
+                    present_following: false,
+                    fresh_window: None,
+                    label: None,
+                    max_concurrency: 1,
                 },
                 sync_clocks: JobsConfig::default_sync_clocks(),
                 update_schedules: JobsConfig::default_update_schedules(),
+                update_recent_schedules: None,
+                max_tuners: JobsConfig::default_max_tuners(),
+                retry: Default::default(),
+                persist_paused: false,
             });
 
         assert_eq!(
@@ -780,8 +2805,18 @@ mod tests {
                 sync_clocks: JobConfig {
                     command: "job".to_string(),
                     schedule: "*".to_string(),
+                    timeout: None,
+                    tables: None,
+                    present_following: false,
+                    fresh_window: None,
+                    label: None,
+                    max_concurrency: 1,
                 },
                 update_schedules: JobsConfig::default_update_schedules(),
+                update_recent_schedules: None,
+                max_tuners: JobsConfig::default_max_tuners(),
+                retry: Default::default(),
+                persist_paused: false,
             });
 
         assert_eq!(
@@ -796,7 +2831,107 @@ mod tests {
                 update_schedules: JobConfig {
                     command: "job".to_string(),
                     schedule: "*".to_string(),
+                    timeout: None,
+                    tables: None,
+                    present_following: false,
+                    fresh_window: None,
+                    label: None,
+                    max_concurrency: 1,
+                },
+                update_recent_schedules: None,
+                max_tuners: JobsConfig::default_max_tuners(),
+                retry: Default::default(),
+                persist_paused: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<JobsConfig>(r#"
+                update-recent-schedules:
+                  command: job
+                  schedule: '*'
+                  tables: [0, 8]
+            "#).unwrap(),
+            JobsConfig {
+                scan_services: JobsConfig::default_scan_services(),
+                sync_clocks: JobsConfig::default_sync_clocks(),
+                update_schedules: JobsConfig::default_update_schedules(),
+                update_recent_schedules: Some(JobConfig {
+                    command: "job".to_string(),
+                    schedule: "*".to_string(),
+                    timeout: None,
+                    tables: Some(vec![0, 8]),
+                    present_following: false,
+                    fresh_window: None,
+                    label: None,
+                    max_concurrency: 1,
+                }),
+                max_tuners: JobsConfig::default_max_tuners(),
+                retry: Default::default(),
+                persist_paused: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<JobsConfig>(r#"
+                update-recent-schedules:
+                  command: job
+                  schedule: '*'
+                  tables: []
+                  present-following: true
+            "#).unwrap(),
+            JobsConfig {
+                scan_services: JobsConfig::default_scan_services(),
+                sync_clocks: JobsConfig::default_sync_clocks(),
+                update_schedules: JobsConfig::default_update_schedules(),
+                update_recent_schedules: Some(JobConfig {
+                    command: "job".to_string(),
+                    schedule: "*".to_string(),
+                    timeout: None,
+                    tables: Some(vec![]),
+                    present_following: true,
+                    fresh_window: None,
+                    label: None,
+                    max_concurrency: 1,
+                }),
+                max_tuners: JobsConfig::default_max_tuners(),
+                retry: Default::default(),
+                persist_paused: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<JobsConfig>(r#"
+                max-tuners: 2
+            "#).unwrap(),
+            JobsConfig {
+                scan_services: JobsConfig::default_scan_services(),
+                sync_clocks: JobsConfig::default_sync_clocks(),
+                update_schedules: JobsConfig::default_update_schedules(),
+                update_recent_schedules: None,
+                max_tuners: 2,
+                retry: Default::default(),
+                persist_paused: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<JobsConfig>(r#"
+                retry:
+                  max-attempts: 5
+            "#).unwrap(),
+            JobsConfig {
+                retry: RetryConfig {
+                    max_attempts: 5,
+                    initial_interval: RetryConfig::default_initial_interval(),
+                    max_interval: RetryConfig::default_max_interval(),
                 },
+                ..Default::default()
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<JobsConfig>(r#"
+                persist-paused: true
+            "#).unwrap(),
+            JobsConfig {
+                persist_paused: true,
+                ..Default::default()
             });
 
         let result = serde_yaml::from_str::<JobsConfig>(r#"
@@ -806,6 +2941,138 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_retry_config() {
+        assert_eq!(
+            serde_yaml::from_str::<RetryConfig>("{}").unwrap(),
+            Default::default());
+
+        assert_eq!(
+            serde_yaml::from_str::<RetryConfig>(r#"
+                max-attempts: 1
+                initial-interval: 500
+                max-interval: 2000
+            "#).unwrap(),
+            RetryConfig {
+                max_attempts: 1,
+                initial_interval: 500,
+                max_interval: 2000,
+            });
+
+        let result = serde_yaml::from_str::<RetryConfig>(r#"
+            unknown:
+              property: value
+        "#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_validate_fallbacks() {
+        let config: Config = serde_yaml::from_str(r#"
+            channels:
+              - name: ch1
+                type: GR
+                channel: '1'
+                fallback:
+                  type: GR
+                  channel: '2'
+              - name: ch2
+                type: GR
+                channel: '2'
+        "#).unwrap();
+        assert!(config.validate_fallbacks().is_ok());
+
+        // No such channel.
+        let config: Config = serde_yaml::from_str(r#"
+            channels:
+              - name: ch1
+                type: GR
+                channel: '1'
+                fallback:
+                  type: BS
+                  channel: '2'
+              - name: ch2
+                type: GR
+                channel: '2'
+        "#).unwrap();
+        assert!(config.validate_fallbacks().is_err());
+
+        // A disabled channel.
+        let config: Config = serde_yaml::from_str(r#"
+            channels:
+              - name: ch1
+                type: GR
+                channel: '1'
+                fallback:
+                  type: GR
+                  channel: '2'
+              - name: ch2
+                type: GR
+                channel: '2'
+                disabled: true
+        "#).unwrap();
+        assert!(config.validate_fallbacks().is_err());
+
+        // The channel itself.
+        let config: Config = serde_yaml::from_str(r#"
+            channels:
+              - name: ch1
+                type: GR
+                channel: '1'
+                fallback:
+                  type: GR
+                  channel: '1'
+        "#).unwrap();
+        assert!(config.validate_fallbacks().is_err());
+    }
+
+    #[test]
+    fn test_jobs_config_validate() {
+        let mut config = JobsConfig::default();
+        assert!(config.validate().is_ok());
+
+        config.update_recent_schedules = Some(JobConfig {
+            schedule: "invalid".to_string(),
+            ..config.update_schedules.clone()
+        });
+        assert!(config.validate().is_err());
+
+        let mut config = JobsConfig::default();
+        config.scan_services.timeout = Some(30000);
+        assert!(config.validate().is_ok());
+
+        config.sync_clocks.timeout = Some(30000);
+        assert!(config.validate().is_err());
+
+        let mut config = JobsConfig::default();
+        config.update_schedules.timeout = Some(30000);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_try_load() {
+        let path = std::env::temp_dir().join(format!(
+            "mirakc-config-test-{}.yml", std::process::id()));
+        let path_str = path.to_str().unwrap();
+
+        std::fs::write(&path, "channels: []\n").unwrap();
+        assert!(try_load(path_str).is_ok());
+
+        std::fs::write(&path, "unknown: 1\n").unwrap();
+        assert!(try_load(path_str).is_err());
+
+        std::fs::write(&path, r#"
+            jobs:
+              sync-clocks:
+                command: ''
+                schedule: 'invalid'
+        "#).unwrap();
+        assert!(try_load(path_str).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(try_load(path_str).is_err());
+    }
+
     #[test]
     fn test_job_config() {
         assert!(serde_yaml::from_str::<JobConfig>("{}").is_err());
@@ -814,6 +3081,91 @@ mod tests {
         assert!(
             serde_yaml::from_str::<JobConfig>(r#"{"schedule":""}"#).is_err());
 
+        assert_eq!(
+            serde_yaml::from_str::<JobConfig>(r#"
+                command: job
+                schedule: '*'
+                timeout: 1000
+            "#).unwrap(),
+            JobConfig {
+                command: "job".to_string(),
+                schedule: "*".to_string(),
+                timeout: Some(1000),
+                tables: None,
+                present_following: false,
+                fresh_window: None,
+                label: None,
+                max_concurrency: 1,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<JobConfig>(r#"
+                command: job
+                schedule: '*'
+                tables: [0]
+            "#).unwrap(),
+            JobConfig {
+                command: "job".to_string(),
+                schedule: "*".to_string(),
+                timeout: None,
+                tables: Some(vec![0]),
+                present_following: false,
+                fresh_window: None,
+                label: None,
+                max_concurrency: 1,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<JobConfig>(r#"
+                command: job
+                schedule: '*'
+                fresh-window: 3600000
+            "#).unwrap(),
+            JobConfig {
+                command: "job".to_string(),
+                schedule: "*".to_string(),
+                timeout: None,
+                tables: None,
+                present_following: false,
+                fresh_window: Some(3600000),
+                label: None,
+                max_concurrency: 1,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<JobConfig>(r#"
+                command: job
+                schedule: '*'
+                label: epg-gr
+            "#).unwrap(),
+            JobConfig {
+                command: "job".to_string(),
+                schedule: "*".to_string(),
+                timeout: None,
+                tables: None,
+                present_following: false,
+                fresh_window: None,
+                label: Some("epg-gr".to_string()),
+                max_concurrency: 1,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<JobConfig>(r#"
+                command: job
+                schedule: '*'
+                max-concurrency: 4
+            "#).unwrap(),
+            JobConfig {
+                command: "job".to_string(),
+                schedule: "*".to_string(),
+                timeout: None,
+                tables: None,
+                present_following: false,
+                fresh_window: None,
+                label: None,
+                max_concurrency: 4,
+            });
+
         let result = serde_yaml::from_str::<JobConfig>(r#"
             unknown:
               property: value
@@ -821,6 +3173,340 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_recorder_config() {
+        assert_eq!(
+            serde_yaml::from_str::<RecorderConfig>("{}").unwrap(),
+            Default::default());
+
+        assert_eq!(
+            serde_yaml::from_str::<RecorderConfig>(r#"
+                records-dir: /path/to/records
+            "#).unwrap(),
+            RecorderConfig {
+                track_airtime_command:
+                    RecorderConfig::default_track_airtime_command(),
+                records_dir: Some("/path/to/records".to_string()),
+                pcr_index: false,
+                check_interval: RecorderConfig::default_check_interval(),
+                conflict_policy: RecordingConflictPolicy::Priority,
+                retention: 0,
+                delete_expired_files: false,
+                retry_hook: String::new(),
+                retry_webhook: None,
+                drop_policy: DropPolicy::DropChunk,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<RecorderConfig>(r#"
+                pcr-index: true
+            "#).unwrap(),
+            RecorderConfig {
+                track_airtime_command:
+                    RecorderConfig::default_track_airtime_command(),
+                records_dir: None,
+                pcr_index: true,
+                check_interval: RecorderConfig::default_check_interval(),
+                conflict_policy: RecordingConflictPolicy::Priority,
+                retention: 0,
+                delete_expired_files: false,
+                retry_hook: String::new(),
+                retry_webhook: None,
+                drop_policy: DropPolicy::DropChunk,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<RecorderConfig>(r#"
+                check-interval: 0
+            "#).unwrap(),
+            RecorderConfig {
+                track_airtime_command:
+                    RecorderConfig::default_track_airtime_command(),
+                records_dir: None,
+                pcr_index: false,
+                check_interval: 0,
+                conflict_policy: RecordingConflictPolicy::Priority,
+                retention: 0,
+                delete_expired_files: false,
+                retry_hook: String::new(),
+                retry_webhook: None,
+                drop_policy: DropPolicy::DropChunk,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<RecorderConfig>(r#"
+                conflict-policy: start-time
+            "#).unwrap(),
+            RecorderConfig {
+                track_airtime_command:
+                    RecorderConfig::default_track_airtime_command(),
+                records_dir: None,
+                pcr_index: false,
+                check_interval: RecorderConfig::default_check_interval(),
+                conflict_policy: RecordingConflictPolicy::StartTime,
+                retention: 0,
+                delete_expired_files: false,
+                retry_hook: String::new(),
+                retry_webhook: None,
+                drop_policy: DropPolicy::DropChunk,
+            });
+
+        assert!(
+            serde_yaml::from_str::<RecorderConfig>(r#"
+                conflict-policy: unknown
+            "#).is_err());
+
+        assert_eq!(
+            serde_yaml::from_str::<RecorderConfig>(r#"
+                retention: 86400000
+                delete-expired-files: true
+            "#).unwrap(),
+            RecorderConfig {
+                track_airtime_command:
+                    RecorderConfig::default_track_airtime_command(),
+                records_dir: None,
+                pcr_index: false,
+                check_interval: RecorderConfig::default_check_interval(),
+                conflict_policy: RecordingConflictPolicy::Priority,
+                retention: 86400000,
+                delete_expired_files: true,
+                retry_hook: String::new(),
+                retry_webhook: None,
+                drop_policy: DropPolicy::DropChunk,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<RecorderConfig>(r#"
+                retry-hook: notify {{program_id}}
+            "#).unwrap(),
+            RecorderConfig {
+                track_airtime_command:
+                    RecorderConfig::default_track_airtime_command(),
+                records_dir: None,
+                pcr_index: false,
+                check_interval: RecorderConfig::default_check_interval(),
+                conflict_policy: RecordingConflictPolicy::Priority,
+                retention: 0,
+                delete_expired_files: false,
+                retry_hook: "notify {{program_id}}".to_string(),
+                retry_webhook: None,
+                drop_policy: DropPolicy::DropChunk,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<RecorderConfig>(r#"
+                retry-webhook: http://notify.local/retried
+            "#).unwrap(),
+            RecorderConfig {
+                track_airtime_command:
+                    RecorderConfig::default_track_airtime_command(),
+                records_dir: None,
+                pcr_index: false,
+                check_interval: RecorderConfig::default_check_interval(),
+                conflict_policy: RecordingConflictPolicy::Priority,
+                retention: 0,
+                delete_expired_files: false,
+                retry_hook: String::new(),
+                retry_webhook: Some("http://notify.local/retried".to_string()),
+                drop_policy: DropPolicy::DropChunk,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<RecorderConfig>(r#"
+                drop-policy: block
+            "#).unwrap(),
+            RecorderConfig {
+                track_airtime_command:
+                    RecorderConfig::default_track_airtime_command(),
+                records_dir: None,
+                pcr_index: false,
+                check_interval: RecorderConfig::default_check_interval(),
+                conflict_policy: RecordingConflictPolicy::Priority,
+                retention: 0,
+                delete_expired_files: false,
+                retry_hook: String::new(),
+                retry_webhook: None,
+                drop_policy: DropPolicy::Block,
+            });
+
+        assert!(
+            serde_yaml::from_str::<RecorderConfig>(r#"
+                drop-policy: unknown
+            "#).is_err());
+
+        let result = serde_yaml::from_str::<RecorderConfig>(r#"
+            unknown:
+              property: value
+        "#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_archive_config() {
+        assert!(serde_yaml::from_str::<ArchiveConfig>("{}").is_err());
+
+        assert_eq!(
+            serde_yaml::from_str::<ArchiveConfig>(r#"
+                name: x
+                type: GR
+                channel: y
+                dir: /path/to/archive
+            "#).unwrap(),
+            ArchiveConfig {
+                name: "x".to_string(),
+                channel_type: ChannelType::GR,
+                channel: "y".to_string(),
+                dir: "/path/to/archive".to_string(),
+                segment_duration: ArchiveConfig::default_segment_duration(),
+                max_segments: 0,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ArchiveConfig>(r#"
+                name: x
+                type: GR
+                channel: y
+                dir: /path/to/archive
+                segment-duration: 60000
+                max-segments: 24
+            "#).unwrap(),
+            ArchiveConfig {
+                name: "x".to_string(),
+                channel_type: ChannelType::GR,
+                channel: "y".to_string(),
+                dir: "/path/to/archive".to_string(),
+                segment_duration: 60000,
+                max_segments: 24,
+            });
+
+        let result = serde_yaml::from_str::<ArchiveConfig>(r#"
+            unknown:
+              property: value
+        "#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_health_config() {
+        assert_eq!(
+            serde_yaml::from_str::<HealthConfig>("{}").unwrap(),
+            Default::default());
+
+        assert_eq!(
+            serde_yaml::from_str::<HealthConfig>(r#"
+                file: /run/mirakc/health
+                interval: 1000
+                timeout: 500
+            "#).unwrap(),
+            HealthConfig {
+                file: Some("/run/mirakc/health".to_string()),
+                interval: 1000,
+                timeout: 500,
+            });
+
+        let result = serde_yaml::from_str::<HealthConfig>(r#"
+            unknown:
+              property: value
+        "#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_tuner_sharing_config() {
+        assert_eq!(
+            serde_yaml::from_str::<TunerSharingConfig>("{}").unwrap(),
+            Default::default());
+
+        assert_eq!(
+            serde_yaml::from_str::<TunerSharingConfig>(r#"
+                policy: prefer-share
+            "#).unwrap(),
+            TunerSharingConfig {
+                policy: TunerSharingPolicy::PreferShare,
+                preemption: true,
+                stop_changed_channels: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<TunerSharingConfig>(r#"
+                policy: prefer-preferred-tuner
+            "#).unwrap(),
+            TunerSharingConfig {
+                policy: TunerSharingPolicy::PreferPreferredTuner,
+                preemption: true,
+                stop_changed_channels: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<TunerSharingConfig>(r#"
+                preemption: false
+            "#).unwrap(),
+            TunerSharingConfig {
+                policy: TunerSharingPolicy::PreferShare,
+                preemption: false,
+                stop_changed_channels: false,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<TunerSharingConfig>(r#"
+                stop-changed-channels: true
+            "#).unwrap(),
+            TunerSharingConfig {
+                policy: TunerSharingPolicy::PreferShare,
+                preemption: true,
+                stop_changed_channels: true,
+            });
+
+        let result = serde_yaml::from_str::<TunerSharingConfig>(r#"
+            policy: unknown
+        "#);
+        assert!(result.is_err());
+
+        let result = serde_yaml::from_str::<TunerSharingConfig>(r#"
+            unknown:
+              property: value
+        "#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_broadcaster_config() {
+        assert_eq!(
+            serde_yaml::from_str::<BroadcasterConfig>("{}").unwrap(),
+            BroadcasterConfig {
+                max_buffered_chunks: None,
+                chunk_size: None,
+                timeshift_duration: None,
+                timeshift_max_bytes: None,
+                psi_cache: false,
+                psi_reinsertion_interval: None,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<BroadcasterConfig>(r#"
+                max-buffered-chunks: 100
+                chunk-size: 188
+                timeshift-duration: 30000
+                timeshift-max-bytes: 67108864
+                psi-cache: true
+                psi-reinsertion-interval: 1000
+            "#).unwrap(),
+            BroadcasterConfig {
+                max_buffered_chunks: Some(100),
+                chunk_size: Some(188),
+                timeshift_duration: Some(30000),
+                timeshift_max_bytes: Some(67108864),
+                psi_cache: true,
+                psi_reinsertion_interval: Some(1000),
+            });
+
+        let result = serde_yaml::from_str::<BroadcasterConfig>(r#"
+            unknown:
+              property: value
+        "#);
+        assert!(result.is_err());
+    }
+
     #[test]
     fn test_mirakurun_config() {
         assert_eq!(
@@ -841,4 +3527,33 @@ mod tests {
         "#);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_debug_config() {
+        assert_eq!(
+            serde_yaml::from_str::<DebugConfig>("{}").unwrap(),
+            Default::default());
+
+        assert_eq!(
+            serde_yaml::from_str::<DebugConfig>(r#"
+                enabled: true
+                time-limit: 1000
+                psi-filter:
+                  command: dump-psi
+            "#).unwrap(),
+            DebugConfig {
+                enabled: true,
+                time_limit: 1000,
+                psi_filter: FilterConfig {
+                    command: "dump-psi".to_string(),
+                    process_limits: None,
+                },
+            });
+
+        let result = serde_yaml::from_str::<DebugConfig>(r#"
+            unknown:
+              property: value
+        "#);
+        assert!(result.is_err());
+    }
 }
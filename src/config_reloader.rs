@@ -0,0 +1,353 @@
+use std::fmt;
+use std::sync::Arc;
+
+use actix::prelude::*;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::{self, Config};
+use crate::eit_feeder::EitFeeder;
+use crate::epg::{Epg, EpgChannel};
+use crate::job::JobManager;
+use crate::tuner::{StopSessionsMessage, TunerManager};
+
+pub fn start(
+    config_path: String,
+    config: Arc<Config>,
+    tuner_manager: Addr<TunerManager>,
+    epg: Addr<Epg>,
+    eit_feeder: Addr<EitFeeder>,
+    job_manager: Addr<JobManager>,
+) -> Addr<ConfigReloader> {
+    ConfigReloader::new(
+        config_path, config, tuner_manager, epg, eit_feeder, job_manager)
+        .start()
+}
+
+// Reloads the config file when SIGHUP is received.
+//
+// Only `channels` and `jobs` are reloaded.  Changes in other properties are
+// ignored until mirakc restarts, so that tuners, the web server and other
+// subsystems keep running with the current config.
+//
+// The current config is kept if the new config file is invalid.
+//
+// Sessions of changed channels keep running with the old definitions unless
+// `tuner-sharing.stop-changed-channels` is enabled.
+pub struct ConfigReloader {
+    config_path: String,
+    config: Arc<Config>,
+    tuner_manager: Addr<TunerManager>,
+    epg: Addr<Epg>,
+    eit_feeder: Addr<EitFeeder>,
+    job_manager: Addr<JobManager>,
+}
+
+impl ConfigReloader {
+    fn new(
+        config_path: String,
+        config: Arc<Config>,
+        tuner_manager: Addr<TunerManager>,
+        epg: Addr<Epg>,
+        eit_feeder: Addr<EitFeeder>,
+        job_manager: Addr<JobManager>,
+    ) -> Self {
+        ConfigReloader {
+            config_path, config, tuner_manager, epg, eit_feeder, job_manager,
+        }
+    }
+
+    fn reload(&mut self) {
+        let new_config = match config::try_load(&self.config_path) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("Failed to reload, keep the current config: {}",
+                            err);
+                return;
+            }
+        };
+
+        let config = match Self::merge(&self.config, &new_config) {
+            Some(config) => Arc::new(config),
+            None => {
+                log::info!("No reloadable change in the config");
+                return;
+            }
+        };
+
+        if config.channels != self.config.channels {
+            log::info!("Reload channels");
+            let changed = Self::collect_changed_channels(&self.config, &config);
+            if self.config.tuner_sharing.stop_changed_channels &&
+                !changed.is_empty() {
+                log::info!("Stop sessions of {} changed channels",
+                           changed.len());
+                self.tuner_manager.do_send(
+                    StopSessionsMessage { channels: changed });
+            }
+        }
+        if config.jobs != self.config.jobs {
+            log::info!("Reload jobs");
+        }
+
+        self.config = config.clone();
+        self.epg.do_send(ReloadConfigMessage { config: config.clone() });
+        self.eit_feeder.do_send(ReloadConfigMessage { config: config.clone() });
+        self.job_manager.do_send(ReloadConfigMessage { config });
+    }
+
+    // Returns the current config updated with reloadable properties in the new
+    // config, or `None` if there is no change in them.
+    fn merge(current: &Config, new: &Config) -> Option<Config> {
+        let mut merged = current.clone();
+        merged.channels = new.channels.clone();
+        merged.jobs = new.jobs.clone();
+
+        let mut ignored = new.clone();
+        ignored.channels = current.channels.clone();
+        ignored.jobs = current.jobs.clone();
+        ignored.last_modified = current.last_modified;
+        if ignored != *current {
+            log::warn!("Changes other than channels and jobs will be applied \
+                        after restart");
+        }
+        for changes in Self::collect_restart_only_changes(current, new) {
+            log::warn!("{} will be applied after restart", changes);
+        }
+
+        if merged == *current {
+            None
+        } else {
+            merged.last_modified = new.last_modified;
+            Some(merged)
+        }
+    }
+
+    // Some components keep the config loaded at startup even though they use
+    // reloadable properties.  Returns descriptions of changes which are not
+    // applied until restart for that reason.
+    fn collect_restart_only_changes(
+        current: &Config,
+        new: &Config,
+    ) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+
+        // The job manager creates the semaphore at startup.
+        if current.jobs.max_tuners != new.jobs.max_tuners {
+            changes.push("Changes in jobs.max-tuners");
+        }
+
+        // The Web API looks up these properties in the config loaded at
+        // startup.
+        let props = |config: &Config| config.channels.iter()
+            .map(|channel| (channel.channel_type, channel.channel.clone(),
+                            channel.fallback.clone(),
+                            channel.pre_filters.clone(),
+                            channel.post_filters.clone()))
+            .collect::<Vec<_>>();
+        if props(current) != props(new) {
+            changes.push("Changes in fallback, pre-filters and post-filters \
+                          of channels");
+        }
+
+        // The archiver looks up channels in the config loaded at startup.
+        let changed = Self::collect_changed_channels(current, new);
+        let archived = current.archives.iter().any(|archive| {
+            changed.iter().any(|channel| {
+                channel.channel_type == archive.channel_type &&
+                    channel.channel == archive.channel
+            })
+        });
+        if archived {
+            changes.push("Changes in channels used by archives");
+        }
+
+        changes
+    }
+
+    // Returns the current definitions of channels removed or modified in the
+    // new config.
+    fn collect_changed_channels(
+        current: &Config,
+        new: &Config,
+    ) -> Vec<EpgChannel> {
+        current.channels.iter()
+            .filter(|channel| !new.channels.contains(channel))
+            .cloned()
+            .map(EpgChannel::from)
+            .collect()
+    }
+}
+
+impl Actor for ConfigReloader {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        log::debug!("Started");
+        match signal(SignalKind::hangup()) {
+            Ok(stream) => {
+                Self::add_stream(stream, ctx);
+            }
+            Err(err) => {
+                log::error!("Failed to listen for SIGHUP, reloading the \
+                             config is disabled: {}", err);
+            }
+        }
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        log::debug!("Stopped");
+    }
+}
+
+impl StreamHandler<()> for ConfigReloader {
+    fn handle(&mut self, _: (), _: &mut Context<Self>) {
+        log::info!("SIGHUP received, reload {}", self.config_path);
+        self.reload();
+    }
+}
+
+// reload config
+
+// Sent to actors which can apply changes in the config without restarting.
+pub struct ReloadConfigMessage {
+    pub config: Arc<Config>,
+}
+
+impl fmt::Display for ReloadConfigMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ReloadConfig")
+    }
+}
+
+impl Message for ReloadConfigMessage {
+    type Result = ();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let current: Config = serde_yaml::from_str(r#"
+            channels:
+              - name: ch1
+                type: GR
+                channel: '1'
+            tuners:
+              - name: tuner
+                types: [GR]
+                command: ''
+        "#).unwrap();
+
+        assert!(ConfigReloader::merge(&current, &current).is_none());
+
+        // Changes in tuners are ignored.
+        let mut new = current.clone();
+        new.tuners.clear();
+        assert!(ConfigReloader::merge(&current, &new).is_none());
+
+        // Changes in channels and jobs are merged.
+        new.channels[0].disabled = true;
+        new.jobs.sync_clocks.schedule = "0 0 * * * * *".to_string();
+        let merged = ConfigReloader::merge(&current, &new).unwrap();
+        assert!(merged.channels[0].disabled);
+        assert_eq!(merged.jobs.sync_clocks.schedule, "0 0 * * * * *");
+        assert_eq!(merged.tuners.len(), 1);
+    }
+
+    #[test]
+    fn test_collect_restart_only_changes() {
+        let current: Config = serde_yaml::from_str(r#"
+            channels:
+              - name: ch1
+                type: GR
+                channel: '1'
+              - name: ch2
+                type: GR
+                channel: '2'
+            archives:
+              - name: archive
+                type: GR
+                channel: '2'
+                dir: /tmp
+        "#).unwrap();
+
+        let changes =
+            ConfigReloader::collect_restart_only_changes(&current, &current);
+        assert!(changes.is_empty());
+
+        // Job schedules are reloadable.
+        let mut new = current.clone();
+        new.jobs.sync_clocks.schedule = "0 0 * * * * *".to_string();
+        let changes =
+            ConfigReloader::collect_restart_only_changes(&current, &new);
+        assert!(changes.is_empty());
+
+        let mut new = current.clone();
+        new.jobs.max_tuners = 2;
+        let changes =
+            ConfigReloader::collect_restart_only_changes(&current, &new);
+        assert_eq!(changes.len(), 1);
+
+        let mut new = current.clone();
+        new.channels[0].pre_filters = vec!["a".to_string()];
+        let changes =
+            ConfigReloader::collect_restart_only_changes(&current, &new);
+        assert_eq!(changes.len(), 1);
+
+        // ch1 is not used by archives.
+        let mut new = current.clone();
+        new.channels[0].extra_args = "--lnb".to_string();
+        let changes =
+            ConfigReloader::collect_restart_only_changes(&current, &new);
+        assert!(changes.is_empty());
+
+        let mut new = current.clone();
+        new.channels[1].extra_args = "--lnb".to_string();
+        let changes =
+            ConfigReloader::collect_restart_only_changes(&current, &new);
+        assert_eq!(changes.len(), 1);
+    }
+
+    #[test]
+    fn test_collect_changed_channels() {
+        let current: Config = serde_yaml::from_str(r#"
+            channels:
+              - name: ch1
+                type: GR
+                channel: '1'
+              - name: ch2
+                type: GR
+                channel: '2'
+              - name: ch3
+                type: GR
+                channel: '3'
+        "#).unwrap();
+
+        assert!(ConfigReloader::collect_changed_channels(&current, &current)
+                .is_empty());
+
+        // ch1 is unchanged, ch2 is modified, ch3 is removed and ch4 is added.
+        let new: Config = serde_yaml::from_str(r#"
+            channels:
+              - name: ch1
+                type: GR
+                channel: '1'
+              - name: ch2
+                type: GR
+                channel: '2'
+                extra-args: '--lnb 15'
+              - name: ch4
+                type: GR
+                channel: '4'
+        "#).unwrap();
+
+        let changed: Vec<String> =
+            ConfigReloader::collect_changed_channels(&current, &new)
+            .into_iter()
+            .map(|channel| channel.name)
+            .collect();
+        assert_eq!(changed, vec!["ch2", "ch3"]);
+    }
+}
@@ -1,22 +1,28 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::HashSet;
 use std::fmt;
+use std::io;
 use std::sync::Arc;
 
 use actix::prelude::*;
 use chrono::{DateTime, Duration};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use indexmap::IndexMap;
 use log;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use tokio::prelude::*;
-use tokio::io::BufReader;
+use tokio::io::{AsyncBufRead, BufReader};
 
-use crate::config::Config;
+use crate::config::{Config, JobConfig, ThumbnailsConfig};
+use crate::config_reloader::ReloadConfigMessage;
 use crate::datetime_ext::*;
 use crate::error::Error;
 use crate::epg::*;
+use crate::job::JobContext;
 use crate::models::*;
+use crate::thumbnail;
 use crate::tuner::*;
-use crate::command_util;
 
 pub fn start(
     config: Arc<Config>,
@@ -42,13 +48,60 @@ impl EitFeeder {
     }
 
     async fn feed_eit_sections(
-        command: String,
+        config: Arc<Config>,
         tuner_manager: Addr<TunerManager>,
         epg: Addr<Epg>,
+        job: JobConfig,
+        force: bool,
+        context: JobContext,
     ) -> Result<(), Error> {
-        let services = epg.send(QueryServicesMessage).await??;
+        let mut services = epg.send(QueryServicesMessage).await??;
+
+        // Services whose schedules have been fully collected recently are
+        // skipped in a full run.  A channel is skipped if all services in it
+        // are skipped.
+        if let Some(window) = job.fresh_window {
+            if !force && job.tables.is_none() {
+                let since = Jst::now() - Duration::milliseconds(window as i64);
+                let fresh: HashSet<ServiceTriple> = epg
+                    .send(QueryFreshServicesMessage { since }).await??
+                    .into_iter()
+                    .collect();
+                let n = services.len();
+                services.retain(|sv| !fresh.contains(&sv.triple()));
+                log::info!("Skip {} services collected since {}",
+                           n - services.len(), since);
+            }
+        }
+
+        let channels = Self::make_channels(&config, &services);
+
+        // Never use more tuners than defined.
+        let num_tuners = config.tuners.iter()
+            .filter(|tuner| !tuner.disabled)
+            .count();
+        let max_concurrency = job.max_concurrency.min(num_tuners);
+
+        EitCollector::new(job.command, channels, config.epg.max_schedule_days,
+                          config.epg.max_eit_line_length, job.tables,
+                          tuner_manager.recipient(), epg)
+            .with_present_following(job.present_following)
+            .with_max_concurrency(max_concurrency)
+            .with_context(context)
+            .with_thumbnails(config.epg.thumbnails.clone())
+            .collect_schedules().await?;
+
+        Ok(())
+    }
 
-        let mut map: HashMap<NetworkId, EpgChannel> = HashMap::new();
+    // Channels are sorted by `epg-priority` in descending order.  Channels
+    // having the same priority are kept in the config order.
+    fn make_channels(
+        config: &Config,
+        services: &[EpgService],
+    ) -> Vec<EpgChannel> {
+        // Use IndexMap in order to keep the order of services.
+        let mut map: IndexMap<NetworkId, EpgChannel> = IndexMap::new();
         for sv in services.iter() {
             map.entry(sv.nid)
                 .and_modify(|ch| ch.services.push(sv.sid))
@@ -61,10 +114,22 @@ impl EitFeeder {
                     excluded_services: vec![],
                 });
         }
-        let channels = map.values().cloned().collect();
 
-        EitCollector::new(command, channels, tuner_manager, epg)
-            .collect_schedules().await
+        let mut channels: Vec<EpgChannel> = map.into_iter()
+            .map(|(_, ch)| ch)
+            .collect();
+        // `sort_by_key()` is stable.
+        channels.sort_by_key(|ch| {
+            let priority = config.channels.iter()
+                .find(|config| {
+                    config.channel_type == ch.channel_type &&
+                        config.channel == ch.channel
+                })
+                .map(|config| config.epg_priority)
+                .unwrap_or_default();
+            Reverse(priority)
+        });
+        channels
     }
 }
 
@@ -80,13 +145,45 @@ impl Actor for EitFeeder {
     }
 }
 
+// reload config
+
+// The new config is used from the next FeedEitSectionsMessage.  Running jobs
+// are not affected.
+impl Handler<ReloadConfigMessage> for EitFeeder {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ReloadConfigMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.config = msg.config;
+    }
+}
+
 // feed eit sections
 
-pub struct FeedEitSectionsMessage;
+// The job config is used for selecting the command and EIT tables to process.
+pub struct FeedEitSectionsMessage {
+    pub job: JobConfig,
+    // `fresh-window` in the job config is ignored if `true`.
+    pub force: bool,
+    // Holds the tuner semaphore shared with other jobs.
+    pub context: JobContext,
+}
 
 impl fmt::Display for FeedEitSectionsMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "FeedEitSections")
+        match self.job.tables {
+            Some(ref tables) =>
+                write!(f, "FeedEitSections for tables {:?}", tables)?,
+            None => write!(f, "FeedEitSections")?,
+        }
+        if self.force {
+            write!(f, " (forced)")?;
+        }
+        Ok(())
     }
 }
 
@@ -104,8 +201,8 @@ impl Handler<FeedEitSectionsMessage> for EitFeeder {
     ) -> Self::Result {
         log::debug!("{}", msg);
         let fut = Box::pin(Self::feed_eit_sections(
-            self.config.jobs.update_schedules.command.clone(),
-            self.tuner_manager.clone(), self.epg.clone()));
+            self.config.clone(), self.tuner_manager.clone(), self.epg.clone(),
+            msg.job, msg.force, msg.context));
         Response::fut(fut)
     }
 }
@@ -115,7 +212,14 @@ impl Handler<FeedEitSectionsMessage> for EitFeeder {
 pub struct EitCollector {
     command: String,
     channels: Vec<EpgChannel>,
-    tuner_manager: Addr<TunerManager>,
+    max_schedule_days: Option<u32>,
+    max_line_length: usize,
+    tables: Option<Vec<usize>>,
+    present_following: bool,
+    max_concurrency: usize,
+    context: JobContext,
+    thumbnails: Option<ThumbnailsConfig>,
+    tuner_manager: Recipient<StartStreamingMessage>,
     epg: Addr<Epg>,
 }
 
@@ -123,83 +227,193 @@ pub struct EitCollector {
 //       ClockSynchronizer and ServiceScanner.
 
 impl EitCollector {
-    const LABEL: &'static str = "eit-collector";
+    pub const LABEL: &'static str = "eit-collector";
     const UPDATE_CHUNK_SIZE: usize = 32;
+    const EXIT_WAIT_TIMEOUT: std::time::Duration =
+        std::time::Duration::from_secs(1);
 
     pub fn new(
         command: String,
         channels: Vec<EpgChannel>,
-        tuner_manager: Addr<TunerManager>,
+        max_schedule_days: Option<u32>,
+        max_line_length: usize,
+        tables: Option<Vec<usize>>,
+        tuner_manager: Recipient<StartStreamingMessage>,
         epg: Addr<Epg>,
     ) -> Self {
-        EitCollector { command, channels, tuner_manager, epg }
+        EitCollector {
+            command,
+            channels,
+            max_schedule_days,
+            max_line_length,
+            tables,
+            present_following: false,
+            max_concurrency: 1,
+            context: JobContext::new(Self::LABEL),
+            thumbnails: None,
+            tuner_manager,
+            epg,
+        }
+    }
+
+    // EIT[p/f] sections are ignored by default.
+    pub fn with_present_following(mut self, present_following: bool) -> Self {
+        self.present_following = present_following;
+        self
+    }
+
+    // Channels are processed one by one by default.  A value of `0` is treated
+    // as `1`.
+    pub fn with_max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency.max(1);
+        self
+    }
+
+    // `JobContext::new(Self::LABEL)` is used by default.
+    pub fn with_context(mut self, context: JobContext) -> Self {
+        self.context = context;
+        self
+    }
+
+    // Thumbnails are extracted by a filter inserted in front of the command
+    // in each channel.  No thumbnail is extracted by default.
+    pub fn with_thumbnails(
+        mut self,
+        thumbnails: Option<ThumbnailsConfig>,
+    ) -> Self {
+        self.thumbnails = thumbnails;
+        self
     }
 
     pub async fn collect_schedules(
         self
     ) -> Result<(), Error> {
-        log::info!("Collecting EIT sections...");
-        let mut num_sections = 0;
-        for channel in self.channels.iter() {
-            num_sections += Self::collect_eits_in_channel(
-                &channel, &self.command, &self.tuner_manager, &self.epg).await?;
-        }
+        log::info!("Collecting EIT sections in up to {} channels at a time...",
+                   self.max_concurrency);
+        let this = &self;
+        // Stats are collected in the completion order.  Sort them in the order
+        // of the channels.
+        let mut stats: Vec<(usize, EitCollectionStats)> =
+            stream::iter(self.channels.iter().enumerate())
+            .map(|(i, channel)| async move {
+                let _permit = this.context.acquire_tuner().await;
+                let started_at = Jst::now();
+                let n = Self::collect_eits_in_channel(
+                    channel, &this.command,
+                    this.max_schedule_days, this.max_line_length,
+                    this.tables.as_deref(), this.present_following,
+                    this.thumbnails.as_ref(),
+                    &this.context, &this.tuner_manager, &this.epg).await?;
+                Ok::<_, Error>((i, EitCollectionStats {
+                    channel_name: channel.name.clone(),
+                    channel_type: channel.channel_type,
+                    channel: channel.channel.clone(),
+                    started_at,
+                    duration: Jst::now() - started_at,
+                    num_sections: n,
+                }))
+            })
+            .buffer_unordered(self.max_concurrency)
+            .try_collect()
+            .await?;
+        stats.sort_by_key(|(i, _)| *i);
+        let stats: Vec<EitCollectionStats> = stats.into_iter()
+            .map(|(_, stats)| stats)
+            .collect();
+        let num_sections: usize = stats.iter()
+            .map(|stats| stats.num_sections)
+            .sum();
         log::info!("Collected {} EIT sections", num_sections);
+        // Only stats of the last successful run are kept.
+        self.epg.do_send(UpdateEitCollectionStatsMessage { stats });
         Ok(())
     }
 
     async fn collect_eits_in_channel(
         channel: &EpgChannel,
         command: &str,
-        tuner_manager: &Addr<TunerManager>,
+        max_schedule_days: Option<u32>,
+        max_line_length: usize,
+        tables: Option<&[usize]>,
+        present_following: bool,
+        thumbnails: Option<&ThumbnailsConfig>,
+        context: &JobContext,
+        tuner_manager: &Recipient<StartStreamingMessage>,
         epg: &Addr<Epg>,
     ) -> Result<usize, Error> {
         log::debug!("Collecting EIT sections in {}...", channel.name);
 
-        let user = TunerUser {
-            info: TunerUserInfo::Job { name: Self::LABEL.to_string() },
-            priority: (-1).into(),
-        };
-
-        let stream = tuner_manager.send(StartStreamingMessage {
-            channel: channel.clone(),
-            user
-        }).await??;
+        let user = context.make_user(None);
+        let stream = context.start_streaming(
+            channel, &user, tuner_manager).await?;
 
-        let template = mustache::compile_str(command)?;
-        let data = mustache::MapBuilder::new()
-            .insert("sids", &channel.services)?
-            .insert("xsids", &channel.excluded_services)?
-            .build();
-        let cmd = template.render_data_to_string(&data)?;
+        let mut cmds = Vec::new();
+        if let Some(thumbnails) = thumbnails {
+            let filter = thumbnail::make_filter(thumbnails, channel)?;
+            cmds.extend(filter);
+        }
+        cmds.push(Self::make_command(
+            command, channel, tables, present_following)?);
 
-        let mut pipeline = command_util::spawn_pipeline(
-            vec![cmd], stream.id())?;
+        let mut pipeline =
+            context.spawn_pipeline(channel, cmds, stream.id()).await?;
 
         let (input, output) = pipeline.take_endpoints().unwrap();
 
         let handle = tokio::spawn(stream.pipe(input));
 
         let mut reader = BufReader::new(output);
-        let mut json = String::new();
+        let mut json = Vec::new();
         let mut num_sections = 0;
         let mut triples = HashSet::new();
         let mut sections = Vec::with_capacity(Self::UPDATE_CHUNK_SIZE);
-        while reader.read_line(&mut json).await? > 0 {
-            let eit = serde_json::from_str::<EitSection>(&json)?;
+        // Only EIT[p/f] sections are processed when no EIT[schedule] table is
+        // specified.  In this case, the collection stops without waiting for
+        // the command to exit once the present and following events of all
+        // services have been collected.
+        let pf_only = present_following &&
+            tables.map_or(false, |tables| tables.is_empty());
+        let mut pf_sections = HashSet::new();
+        let mut stopped = false;
+        while Self::read_line(&mut reader, &mut json, max_line_length).await? {
+            let eit = serde_json::from_slice::<EitSection>(&json)?;
+            if !Self::is_within_horizon(&eit, max_schedule_days) {
+                continue;
+            }
+            if !Self::is_target_table(&eit, tables, present_following) {
+                continue;
+            }
+            if eit.is_present_following() {
+                pf_sections.insert((eit.service_id, eit.section_number));
+            }
             triples.insert(eit.service_triple());
             sections.push(eit);
             if sections.len() == Self::UPDATE_CHUNK_SIZE {
                 epg.do_send(UpdateSchedulesMessage { sections });
                 sections = Vec::with_capacity(32);
             }
-            json.clear();
             num_sections += 1;
+            if pf_only && Self::has_present_following(channel, &pf_sections) {
+                log::debug!("Collected EIT[p/f] of all services in {}",
+                            channel.name);
+                stopped = true;
+                break;
+            }
         }
         if !sections.is_empty() {
             epg.do_send(UpdateSchedulesMessage { sections });
         }
 
+        // EOF is also reached when the command is killed or crashes in the
+        // middle of the collection.  Sections which haven't been collected in
+        // this case must not be removed from the EPG.
+        let succeeded = stopped || pipeline.wait_for_success(
+            Self::EXIT_WAIT_TIMEOUT).await;
+        if !succeeded {
+            log::warn!("{}: The command didn't exit successfully, \
+                        keep stale EIT sections", channel.name);
+        }
+
         // Explicitly dropping the output of the pipeline is needed.  The output
         // holds the child processes and it kills them when dropped.
         drop(pipeline);
@@ -210,6 +424,7 @@ impl EitCollector {
 
         epg.do_send(FlushSchedulesMessage {
             triples: triples.into_iter().collect(),
+            complete: succeeded && tables.is_none(),
         });
 
         log::debug!("Collected {} EIT sections in {}",
@@ -217,6 +432,122 @@ impl EitCollector {
 
         Ok(num_sections)
     }
+
+    // Reads a line into `buf`.  A line longer than `max_len` bytes is skipped
+    // without buffering the whole line.  Returns `false` at EOF.
+    async fn read_line<R>(
+        reader: &mut R,
+        buf: &mut Vec<u8>,
+        max_len: usize,
+    ) -> io::Result<bool>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        // Including the newline.
+        let limit = max_len as u64 + 1;
+        loop {
+            buf.clear();
+            if (&mut *reader).take(limit).read_until(b'\n', buf).await? == 0 {
+                return Ok(false);
+            }
+            if (buf.len() as u64) < limit || buf.ends_with(b"\n") {
+                return Ok(true);
+            }
+            log::error!("Skip a line longer than {} bytes", max_len);
+            loop {
+                buf.clear();
+                let n = (&mut *reader).take(limit)
+                    .read_until(b'\n', buf).await?;
+                if n == 0 {
+                    return Ok(false);
+                }
+                if buf.ends_with(b"\n") {
+                    break;
+                }
+            }
+        }
+    }
+
+    // The selection of tables is passed to the command so that it can skip
+    // sections not to be processed.
+    fn make_command(
+        command: &str,
+        channel: &EpgChannel,
+        tables: Option<&[usize]>,
+        present_following: bool,
+    ) -> Result<String, Error> {
+        let template = mustache::compile_str(command)?;
+        let data = mustache::MapBuilder::new()
+            .insert("sids", &channel.services)?
+            .insert("xsids", &channel.excluded_services)?
+            .insert("tables", &tables.unwrap_or_default())?
+            .insert("present_following", &present_following)?
+            .build();
+        Ok(template.render_data_to_string(&data)?)
+    }
+
+    // Returns `true` if sections of the present and following events of all
+    // services in the channel have been collected.
+    fn has_present_following(
+        channel: &EpgChannel,
+        pf_sections: &HashSet<(ServiceId, u8)>,
+    ) -> bool {
+        !channel.services.is_empty() &&
+            channel.services.iter().all(|sid| {
+                pf_sections.contains(&(*sid, 0)) &&
+                    pf_sections.contains(&(*sid, 1))
+            })
+    }
+
+    // Each EIT[schedule] table covers 4 days.  The first table in each group
+    // of 8 tables starts from the midnight of the current day.  EIT[p/f]
+    // sections are always within the horizon.
+    fn is_within_horizon(
+        section: &EitSection,
+        max_schedule_days: Option<u32>,
+    ) -> bool {
+        if section.is_present_following() {
+            return true;
+        }
+        match max_schedule_days {
+            Some(days) => {
+                let max_tables = (days as usize + 3) / 4;
+                section.table_index() % 8 < max_tables
+            }
+            None => true,
+        }
+    }
+
+    // All EIT[schedule] tables are processed when no table is specified.
+    // EIT[p/f] sections are processed only when `present_following` is
+    // `true`.
+    fn is_target_table(
+        section: &EitSection,
+        tables: Option<&[usize]>,
+        present_following: bool,
+    ) -> bool {
+        if section.is_present_following() {
+            return present_following;
+        }
+        match tables {
+            Some(tables) => tables.contains(&section.table_index()),
+            None => true,
+        }
+    }
+}
+
+#[derive(Clone)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EitCollectionStats {
+    pub channel_name: String,
+    pub channel_type: ChannelType,
+    pub channel: String,
+    #[serde(with = "serde_jst")]
+    pub started_at: DateTime<Jst>,
+    #[serde(with = "serde_duration_in_millis")]
+    pub duration: Duration,
+    pub num_sections: usize,
 }
 
 #[derive(Clone)]
@@ -232,9 +563,18 @@ pub struct EitSection {
     pub segment_last_section_number: u8,
     pub version_number: u8,
     pub events: Vec<EitEvent>,
+    // The result of the CRC check if the command provides it.
+    #[serde(default)]
+    pub crc_valid: Option<bool>,
 }
 
 impl EitSection {
+    // EIT[p/f] actual (0x4E) and other (0x4F).
+    pub fn is_present_following(&self) -> bool {
+        self.table_id == 0x4E || self.table_id == 0x4F
+    }
+
+    // Must not be called for EIT[p/f] sections.
     pub fn table_index(&self) -> usize {
         self.table_id as usize - 0x50
     }
@@ -268,9 +608,26 @@ pub struct EitEvent {
     #[serde(with = "serde_duration_in_millis")]
     pub duration: Duration,
     pub scrambled: bool,
+    #[serde(serialize_with = "serialize_descriptors")]
     pub descriptors: Vec<EitDescriptor>,
 }
 
+// Descriptors are serialized in a fixed order of their types so that equal
+// events are always serialized into the same JSON.  The sort is stable, so
+// descriptors of the same type keep their original order, which is
+// significant for components.
+fn serialize_descriptors<S>(
+    descriptors: &[EitDescriptor],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let mut sorted: Vec<&EitDescriptor> = descriptors.iter().collect();
+    sorted.sort_by_key(|desc| desc.sort_key());
+    serializer.collect_seq(sorted)
+}
+
 impl EitEvent {
     pub fn end_time(&self) -> DateTime<Jst> {
         self.start_time + self.duration
@@ -294,11 +651,15 @@ pub enum EitDescriptor {
     Component {
         stream_content: u8,
         component_type: u8,
+        #[serde(default)]
+        component_tag: Option<u8>,
     },
     #[serde(rename_all = "camelCase")]
     AudioComponent {
         component_type: u8,
         sampling_rate: u8,
+        #[serde(default)]
+        component_tag: Option<u8>,
     },
     #[serde(rename_all = "camelCase")]
     Content {
@@ -308,4 +669,402 @@ pub enum EitDescriptor {
     ExtendedEvent {
         items: Vec<(String, String)>,
     },
+    // Linked events such as shared events and relayed events.  The original
+    // network ID and the transport stream ID of the section are used for
+    // group types which don't have them in the descriptor.
+    #[serde(rename_all = "camelCase")]
+    EventGroup {
+        group_type: u8,
+        events: Vec<(NetworkId, TransportStreamId, ServiceId, EventId)>,
+    },
+}
+
+impl EitDescriptor {
+    fn sort_key(&self) -> u8 {
+        match self {
+            EitDescriptor::ShortEvent { .. } => 0,
+            EitDescriptor::Component { .. } => 1,
+            EitDescriptor::AudioComponent { .. } => 2,
+            EitDescriptor::Content { .. } => 3,
+            EitDescriptor::ExtendedEvent { .. } => 4,
+            EitDescriptor::EventGroup { .. } => 5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::broadcaster::BroadcasterStream;
+    use crate::config::ChannelConfig;
+    use crate::mpeg_ts_stream::MpegTsStream;
+
+    type Mock = actix::actors::mocker::Mocker<TunerManager>;
+
+    #[test]
+    fn test_make_channels() {
+        let mut config = Config::default();
+        config.channels = vec![
+            create_channel_config("1", 0),
+            create_channel_config("2", 1),
+            create_channel_config("3", 0),
+            create_channel_config("4", 1),
+        ];
+
+        let services: Vec<EpgService> = config.channels.iter()
+            .enumerate()
+            .map(|(i, ch)| create_service(i as u16 + 1, ch))
+            .collect();
+
+        let channels = EitFeeder::make_channels(&config, &services);
+        let iter = channels.iter().map(|ch| ch.channel.as_str());
+        assert!(iter.eq(["2", "4", "1", "3"].iter().cloned()));
+    }
+
+    #[test]
+    fn test_is_within_horizon() {
+        // table_id: 0x50..0x57 (index: 0..7)
+        assert!(EitCollector::is_within_horizon(&create_section(0x50), None));
+        assert!(EitCollector::is_within_horizon(&create_section(0x57), None));
+
+        assert!(EitCollector::is_within_horizon(
+            &create_section(0x50), Some(1)));
+        assert!(!EitCollector::is_within_horizon(
+            &create_section(0x51), Some(1)));
+
+        assert!(EitCollector::is_within_horizon(
+            &create_section(0x51), Some(8)));
+        assert!(!EitCollector::is_within_horizon(
+            &create_section(0x52), Some(8)));
+
+        // table_id: 0x58..0x5F (index: 8..15)
+        assert!(EitCollector::is_within_horizon(
+            &create_section(0x58), Some(4)));
+        assert!(!EitCollector::is_within_horizon(
+            &create_section(0x59), Some(4)));
+
+        assert!(!EitCollector::is_within_horizon(
+            &create_section(0x50), Some(0)));
+
+        // EIT[p/f]
+        assert!(EitCollector::is_within_horizon(
+            &create_section(0x4E), Some(0)));
+    }
+
+    #[test]
+    fn test_is_target_table() {
+        assert!(EitCollector::is_target_table(
+            &create_section(0x50), None, false));
+        assert!(EitCollector::is_target_table(
+            &create_section(0x5F), None, false));
+
+        let tables = [0, 8];
+        assert!(EitCollector::is_target_table(
+            &create_section(0x50), Some(&tables), false));
+        assert!(!EitCollector::is_target_table(
+            &create_section(0x51), Some(&tables), false));
+        assert!(EitCollector::is_target_table(
+            &create_section(0x58), Some(&tables), false));
+
+        assert!(!EitCollector::is_target_table(
+            &create_section(0x50), Some(&[]), false));
+
+        // EIT[p/f]
+        assert!(!EitCollector::is_target_table(
+            &create_section(0x4E), None, false));
+        assert!(EitCollector::is_target_table(
+            &create_section(0x4E), None, true));
+        assert!(EitCollector::is_target_table(
+            &create_section(0x4F), Some(&[]), true));
+        assert!(!EitCollector::is_target_table(
+            &create_section(0x50), Some(&[]), true));
+    }
+
+    #[test]
+    fn test_make_command() {
+        let channel = EpgChannel {
+            name: "channel".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "0".to_string(),
+            extra_args: "".to_string(),
+            services: vec![1.into()],
+            excluded_services: vec![],
+        };
+        let command = "collect{{#sids}} --sids={{.}}{{/sids}}\
+                       {{#tables}} --tables={{.}}{{/tables}}\
+                       {{#present_following}} --pf{{/present_following}}";
+
+        assert_eq!(
+            EitCollector::make_command(command, &channel, None, false)
+                .unwrap(),
+            "collect --sids=1");
+        assert_eq!(
+            EitCollector::make_command(command, &channel, Some(&[0, 8]), true)
+                .unwrap(),
+            "collect --sids=1 --tables=0 --tables=8 --pf");
+    }
+
+    #[test]
+    fn test_has_present_following() {
+        let mut channel = EpgChannel {
+            name: "channel".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "0".to_string(),
+            extra_args: "".to_string(),
+            services: vec![1.into(), 2.into()],
+            excluded_services: vec![],
+        };
+
+        let mut pf_sections = HashSet::new();
+        pf_sections.insert((ServiceId::from(1), 0));
+        pf_sections.insert((ServiceId::from(1), 1));
+        pf_sections.insert((ServiceId::from(2), 0));
+        assert!(!EitCollector::has_present_following(&channel, &pf_sections));
+
+        pf_sections.insert((ServiceId::from(2), 1));
+        assert!(EitCollector::has_present_following(&channel, &pf_sections));
+
+        channel.services.clear();
+        assert!(!EitCollector::has_present_following(&channel, &pf_sections));
+    }
+
+    #[actix_rt::test]
+    async fn test_collect_schedules_concurrently() {
+        let mock = Mock::mock(Box::new(|msg, ctx| {
+            if let Some(_) = msg.downcast_ref::<StartStreamingMessage>() {
+                let (_, stream) = BroadcasterStream::new_for_test();
+                let result: Result<_, Error> = Ok(MpegTsStream::new(
+                    Default::default(), stream, ctx.address().recipient()));
+                Box::new(Some(result))
+            } else if let Some(_) = msg.downcast_ref::<StopStreamingMessage>() {
+                Box::new(Some(()))
+            } else {
+                unimplemented!();
+            }
+        })).start();
+
+        let dir = std::env::temp_dir().join(format!(
+            "mirakc-eit-concurrency-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("running")).unwrap();
+
+        let channels: Vec<EpgChannel> = (1..=4u16)
+            .map(|sid| EpgChannel {
+                name: format!("channel{}", sid),
+                channel_type: ChannelType::GR,
+                channel: sid.to_string(),
+                extra_args: "".to_string(),
+                services: vec![sid.into()],
+                excluded_services: vec![],
+            })
+            .collect();
+
+        // A section of the service 65535, which is replaced with the SID of
+        // each channel.
+        let section = serde_json::json!({
+            "originalNetworkId": 1,
+            "transportStreamId": 2,
+            "serviceId": 65535,
+            "tableId": 0x50,
+            "sectionNumber": 0,
+            "lastSectionNumber": 0,
+            "segmentLastSectionNumber": 0,
+            "versionNumber": 0,
+            "events": [],
+        });
+        std::fs::write(dir.join("section"), section.to_string()).unwrap();
+
+        // Each command records the number of commands running at the same
+        // time, and then outputs the section.
+        let cmd = r#"sh -c "d=DIR; s={{#sids}}{{.}}{{/sids}};
+            mkdir $d/running/$s; ls $d/running | wc -l >>$d/counts;
+            sleep 0.5; rmdir $d/running/$s; sed s/65535/$s/ $d/section""#
+            .replace("DIR", dir.to_str().unwrap());
+
+        let epg = crate::epg::start(Arc::new(Config::default()));
+        let collector = EitCollector::new(
+            cmd, channels.clone(), None, 4096, None, mock.clone().recipient(),
+            epg.clone())
+            .with_max_concurrency(2);
+        assert!(collector.collect_schedules().await.is_ok());
+
+        let counts: Vec<usize> = std::fs::read_to_string(dir.join("counts"))
+            .unwrap()
+            .lines()
+            .map(|line| line.trim().parse().unwrap())
+            .collect();
+        assert_eq!(counts.len(), 4);
+        assert_eq!(counts.iter().max(), Some(&2));
+
+        // Stats of all channels are fed in the order of the channels.
+        let status = epg.send(QueryEpgStatusMessage).await.unwrap().unwrap();
+        assert_eq!(status.eit_collection.len(), 4);
+        for (stats, channel) in status.eit_collection.iter().zip(&channels) {
+            assert_eq!(stats.channel, channel.channel);
+            assert_eq!(stats.num_sections, 1);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_read_line() {
+        let data = b"{}\n0123456789\n{\"a\":1}\nabcdefghij";
+        let mut r = BufReader::with_capacity(4, &data[..]);
+        let mut buf = Vec::new();
+
+        assert!(EitCollector::read_line(&mut r, &mut buf, 8).await.unwrap());
+        assert_eq!(buf, b"{}\n");
+
+        // The over-long line is skipped.
+        assert!(EitCollector::read_line(&mut r, &mut buf, 8).await.unwrap());
+        assert_eq!(buf, b"{\"a\":1}\n");
+
+        // The over-long last line without a newline is skipped.
+        assert!(!EitCollector::read_line(&mut r, &mut buf, 8).await.unwrap());
+
+        let data = b"12345678";
+        let mut r = BufReader::new(&data[..]);
+        assert!(EitCollector::read_line(&mut r, &mut buf, 8).await.unwrap());
+        assert_eq!(buf, b"12345678");
+        assert!(!EitCollector::read_line(&mut r, &mut buf, 8).await.unwrap());
+    }
+
+    #[test]
+    fn test_eit_event_serialization_is_stable() {
+        let short_event = EitDescriptor::ShortEvent {
+            event_name: "name".to_string(),
+            text: "text".to_string(),
+        };
+        let component = |tag| EitDescriptor::Component {
+            stream_content: 1,
+            component_type: 0xB3,
+            component_tag: Some(tag),
+        };
+        let content = EitDescriptor::Content {
+            nibbles: vec![(1, 2, 3, 4)],
+        };
+
+        let event1 = create_event(vec![
+            content.clone(), component(0), short_event.clone(), component(1),
+        ]);
+        let event2 = create_event(vec![
+            short_event.clone(), component(0), component(1), content.clone(),
+        ]);
+        assert_eq!(serde_json::to_string(&event1).unwrap(),
+                   serde_json::to_string(&event2).unwrap());
+
+        // Descriptors of the same type keep their order.
+        let event3 = create_event(vec![
+            short_event.clone(), component(1), component(0), content.clone(),
+        ]);
+        assert_ne!(serde_json::to_string(&event1).unwrap(),
+                   serde_json::to_string(&event3).unwrap());
+    }
+
+    #[test]
+    fn test_eit_section_with_event_group() {
+        // An event shared with other services in the same multi-channel
+        // service group.
+        let section: EitSection = serde_json::from_str(r#"{
+            "originalNetworkId": 32736,
+            "transportStreamId": 32736,
+            "serviceId": 1024,
+            "tableId": 80,
+            "sectionNumber": 0,
+            "lastSectionNumber": 248,
+            "segmentLastSectionNumber": 0,
+            "versionNumber": 12,
+            "events": [{
+                "eventId": 21847,
+                "startTime": 1577836800000,
+                "duration": 1800000,
+                "scrambled": false,
+                "descriptors": [{
+                    "$type": "ShortEvent",
+                    "eventName": "ニュース",
+                    "text": ""
+                }, {
+                    "$type": "EventGroup",
+                    "groupType": 1,
+                    "events": [
+                        [32736, 32736, 1025, 21847],
+                        [32736, 32736, 1026, 21847]
+                    ]
+                }]
+            }]
+        }"#).unwrap();
+
+        let descriptors = &section.events[0].descriptors;
+        assert_eq!(descriptors.len(), 2);
+        match descriptors[1] {
+            EitDescriptor::EventGroup { group_type, ref events } => {
+                assert_eq!(group_type, 1);
+                assert_eq!(events.len(), 2);
+                assert_eq!(events[0], (32736.into(), 32736.into(),
+                                       1025.into(), 21847.into()));
+                assert_eq!(events[1].2, 1026.into());
+            }
+            _ => panic!("Not an EventGroup descriptor"),
+        }
+    }
+
+    fn create_channel_config(
+        channel: &str,
+        epg_priority: i32,
+    ) -> ChannelConfig {
+        ChannelConfig {
+            name: channel.to_string(),
+            channel_type: ChannelType::GR,
+            channel: channel.to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+            disabled: false,
+            fallback: None,
+            epg_priority,
+            expected_services: vec![],
+            pre_filters: vec![],
+            post_filters: vec![],
+        }
+    }
+
+    fn create_section(table_id: u16) -> EitSection {
+        EitSection {
+            original_network_id: 1.into(),
+            transport_stream_id: 2.into(),
+            service_id: 3.into(),
+            table_id,
+            section_number: 0,
+            last_section_number: 0,
+            segment_last_section_number: 0,
+            version_number: 0,
+            events: vec![],
+            crc_valid: None,
+        }
+    }
+
+    fn create_event(descriptors: Vec<EitDescriptor>) -> EitEvent {
+        EitEvent {
+            event_id: 1.into(),
+            start_time: Jst.ymd(2020, 1, 1).and_hms(0, 0, 0),
+            duration: Duration::minutes(30),
+            scrambled: false,
+            descriptors,
+        }
+    }
+
+    fn create_service(nid: u16, config: &ChannelConfig) -> EpgService {
+        EpgService {
+            nid: nid.into(),
+            tsid: 0.into(),
+            sid: 1.into(),
+            service_type: 1,
+            logo_id: 0,
+            remote_control_key_id: 0,
+            name: config.name.clone(),
+            network_name: None,
+            channel: EpgChannel::from(config.clone()),
+        }
+    }
 }
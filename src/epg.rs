@@ -1,9 +1,9 @@
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufWriter, Write};
 use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use actix::prelude::*;
@@ -11,12 +11,17 @@ use chrono::{DateTime, Duration, TimeZone};
 use indexmap::IndexMap;
 use log;
 use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
 
-use crate::config::{Config, ChannelConfig};
+use crate::config::{Config, ChannelConfig, ZeroDurationEvents};
+use crate::config_reloader::ReloadConfigMessage;
 use crate::datetime_ext::*;
 use crate::eit_feeder::*;
 use crate::error::Error;
 use crate::models::*;
+use crate::service_scanner::ServiceScanStats;
+use crate::thumbnail;
+use crate::xmltv::{self, XmltvProgramme};
 
 pub fn start(config: Arc<Config>) -> Addr<Epg> {
     // Start on a new Arbiter instead of the system Arbiter.
@@ -29,12 +34,54 @@ pub fn start(config: Arc<Config>) -> Addr<Epg> {
     Epg::start_in_arbiter(&Arbiter::new(), |_| Epg::new(config))
 }
 
+// Bumped when the format of cache files changes incompatibly.  Cache files
+// saved with another version are ignored instead of being loaded.
+const CACHE_VERSION: u32 = 1;
+
+// Only the version is deserialized first so that data in an incompatible cache
+// file is never deserialized.
+#[derive(Deserialize)]
+struct CacheHeader {
+    version: u32,
+}
+
+#[derive(Deserialize)]
+struct CacheFile<T> {
+    data: T,
+}
+
+#[derive(Serialize)]
+struct CacheFileRef<'a, T> {
+    version: u32,
+    data: &'a T,
+}
+
 pub struct Epg {
     config: Arc<Config>,
     services: IndexMap<ServiceTriple, EpgService>,  // keeps insertion order
     clocks: HashMap<ServiceTriple, Clock>,
     schedules: HashMap<ServiceTriple, EpgSchedule>,
     airtimes: HashMap<EventQuad, Airtime>,
+    eit_collection_stats: Vec<EitCollectionStats>,
+    service_scan_stats: Vec<ServiceScanStats>,
+    // Becomes true when schedules are loaded from the cache or the EIT
+    // collection completes once.  Never goes back to false.
+    ready: bool,
+    // The number of sections which failed the CRC check since started.
+    invalid_sections: HashMap<ServiceTriple, usize>,
+    changes: EpgChangeLog,
+    // True while some XMLTV channels are not mapped to any service.  The
+    // XMLTV file is imported again when services are updated.
+    xmltv_pending: bool,
+}
+
+// The offset of the broadcast time (TOT/TDT) from the system clock in ms.  A
+// positive value means that the broadcast time is ahead of the system clock.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClockOffset {
+    pub nid: NetworkId,
+    pub synced_at: i64,
+    pub offset: i64,
 }
 
 pub struct Airtime {
@@ -50,6 +97,12 @@ impl Epg {
             clocks: HashMap::new(),
             schedules: HashMap::new(),
             airtimes: HashMap::new(),
+            eit_collection_stats: Vec::new(),
+            service_scan_stats: Vec::new(),
+            ready: false,
+            invalid_sections: HashMap::new(),
+            changes: Default::default(),
+            xmltv_pending: false,
         }
     }
 
@@ -84,6 +137,35 @@ impl Epg {
         }
     }
 
+    // Services in removed or changed channels are dropped immediately in the
+    // same way as `load_services()`.  Services in new channels are added when
+    // the scan-services job runs.
+    fn reload_config(&mut self, config: Arc<Config>) {
+        self.config = config;
+
+        let channels: Vec<EpgChannel> = self.config.channels.iter()
+            .filter(|config| !config.disabled)
+            .cloned()
+            .map(EpgChannel::from)
+            .collect();
+
+        let num_services = self.services.len();
+        self.services.retain(|_, sv| {
+            let not_changed = channels.iter().any(|ch| ch == &sv.channel);
+            if !not_changed {  // if changed
+                log::info!("Drop service#{} ({}) due to changes of the \
+                            channel config", sv.triple(), sv.name);
+            }
+            not_changed
+        });
+
+        if self.services.len() != num_services {
+            if let Err(err) = self.save_services() {
+                log::error!("Failed to save services: {}", err);
+            }
+        }
+    }
+
     fn update_clocks(
         &mut self,
         results: Vec<(EpgChannel, Option<HashMap<ServiceTriple, Clock>>)>,
@@ -120,9 +202,109 @@ impl Epg {
         }
     }
 
+    fn mark_schedules_collected(
+        &mut self,
+        triples: &[ServiceTriple],
+        timestamp: DateTime<Jst>,
+    ) {
+        for triple in triples.iter() {
+            if let Some(schedule) = self.schedules.get_mut(triple) {
+                schedule.collected_at = Some(timestamp);
+            }
+        }
+    }
+
+    // Returns services whose schedules were fully collected at or after
+    // `since`.
+    fn collect_fresh_services(
+        &self,
+        since: DateTime<Jst>,
+    ) -> Vec<ServiceTriple> {
+        self.schedules
+            .values()
+            .filter(|schedule| match schedule.collected_at {
+                Some(collected_at) => collected_at >= since,
+                None => false,
+            })
+            .map(|schedule| schedule.service_triple)
+            .collect()
+    }
+
+    // Returns the number of invalid sections for each service in the order of
+    // services.
+    fn collect_invalid_section_stats(&self) -> Vec<InvalidSectionStats> {
+        self.services
+            .keys()
+            .filter_map(|triple| {
+                self.invalid_sections.get(triple).map(|&num_sections| {
+                    InvalidSectionStats {
+                        service_id: (*triple).into(),
+                        num_sections,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    // Returns the offset of the broadcast time from the system clock for each
+    // network.  The offset is computed from the latest clock in the network.
+    fn collect_clock_offsets(&self) -> Vec<ClockOffset> {
+        let mut offsets: HashMap<NetworkId, ClockOffset> = HashMap::new();
+        for (triple, clock) in self.clocks.iter() {
+            let synced_at = match clock.synced_at {
+                Some(synced_at) => synced_at,
+                None => continue,  // loaded from an old cache
+            };
+            match offsets.get(&triple.nid()) {
+                Some(offset) if offset.synced_at >= synced_at => (),
+                _ => {
+                    offsets.insert(triple.nid(), ClockOffset {
+                        nid: triple.nid(),
+                        synced_at,
+                        offset: clock.time - synced_at,
+                    });
+                }
+            }
+        }
+        let mut offsets: Vec<ClockOffset> =
+            offsets.into_iter().map(|(_, offset)| offset).collect();
+        offsets.sort_by_key(|offset| offset.nid.value());
+        offsets
+    }
+
+    // Unlike `update_clocks()`, clocks of channels not contained in `results`
+    // are kept as they are.
+    fn merge_clocks(
+        &mut self,
+        results: Vec<(EpgChannel, Option<HashMap<ServiceTriple, Clock>>)>,
+    ) {
+        for (channel, result) in results.into_iter() {
+            // Reuse old clocks if failed to synchronize clocks.
+            if let Some(new_clocks) = result {
+                let services = &self.services;
+                self.clocks.retain(|triple, _| {
+                    match services.get(triple) {
+                        Some(service) => service.channel != channel,
+                        None => true,
+                    }
+                });
+                self.clocks.extend(new_clocks);
+            }
+        }
+
+        match self.save_clocks() {
+            Ok(_) => (),
+            Err(err) => log::error!("Failed to save clocks: {}", err),
+        }
+    }
+
     fn update_schedules(&mut self, sections: Vec<EitSection>) {
         self.prepare_schedules(Jst::now());
-        for section in sections.into_iter() {
+        // Invalid sections are dropped before removing stale sections.
+        // Otherwise, a broken version number of an invalid section may remove
+        // valid sections.
+        let sections = self.drop_invalid_sections(sections);
+        for section in Self::remove_stale_sections(sections).into_iter() {
             let triple = section.service_triple();
             self.schedules.entry(triple).and_modify(move |sched| {
                 sched.update(section);
@@ -130,20 +312,266 @@ impl Epg {
         }
     }
 
+    // Counts sections which failed the CRC check, and drops them if
+    // `epg.drop-invalid-sections` is enabled.
+    fn drop_invalid_sections(
+        &mut self,
+        sections: Vec<EitSection>,
+    ) -> Vec<EitSection> {
+        let drop = self.config.epg.drop_invalid_sections;
+        let invalid_sections = &mut self.invalid_sections;
+        sections
+            .into_iter()
+            .filter(|section| {
+                if section.crc_valid != Some(false) {
+                    return true;
+                }
+                let triple = section.service_triple();
+                *invalid_sections.entry(triple).or_default() += 1;
+                if drop {
+                    log::debug!("Dropped invalid section#{:02X}:{:02X} of \
+                                 schedule#{}", section.table_id,
+                                section.section_number, triple);
+                }
+                !drop
+            })
+            .collect()
+    }
+
+    // Removes sections superseded by a section with a newer version in the same
+    // batch.  A section stored in the schedules is always replaced with a
+    // section in a later batch regardless of its version, because the same
+    // section number is reused for a different time slot on the next day.
+    fn remove_stale_sections(sections: Vec<EitSection>) -> Vec<EitSection> {
+        let mut versions = HashMap::new();
+        for section in sections.iter() {
+            let key = (section.service_triple(), section.table_id,
+                       section.section_number);
+            versions
+                .entry(key)
+                .and_modify(|version| {
+                    if is_newer_version(section.version_number, *version) {
+                        *version = section.version_number;
+                    }
+                })
+                .or_insert(section.version_number);
+        }
+        sections
+            .into_iter()
+            .filter(|section| {
+                let key = (section.service_triple(), section.table_id,
+                           section.section_number);
+                let stale = versions[&key] != section.version_number;
+                if stale {
+                    log::debug!(
+                        "Removed stale section#{:02X}:{:02X} (v{}) of \
+                         schedule#{}", section.table_id,
+                        section.section_number, section.version_number,
+                        section.service_triple());
+                }
+                !stale
+            })
+            .collect()
+    }
+
+    // Returns the earliest program which matches the rule and starts at or
+    // after `now`.
+    fn find_next_program(
+        &self,
+        rule: &EpgProgramRule,
+        now: DateTime<Jst>,
+    ) -> Option<EpgProgram> {
+        self.schedules
+            .values()
+            .flat_map(|schedule| schedule.programs.values())
+            .filter(|program| program.start_at >= now)
+            .filter(|program| rule.matches(program))
+            // Use the program ID for stable results.
+            .min_by_key(|program| {
+                let id = MirakurunProgramId::from(program.quad);
+                (program.start_at, id.value())
+            })
+            .cloned()
+    }
+
+    // Returns programs matching the rule, sorted by their start times.  Up to
+    // `limit` programs are returned after skipping the first `offset` ones.
+    fn search_programs(
+        &self,
+        rule: &EpgProgramRule,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<EpgProgram> {
+        let mut programs: Vec<&EpgProgram> = self.schedules
+            .values()
+            .flat_map(|schedule| schedule.programs.values())
+            .filter(|program| rule.matches(program))
+            .collect();
+        // Use the program ID for stable pagination.
+        programs.sort_by_key(|program| {
+            let id = MirakurunProgramId::from(program.quad);
+            (program.start_at, id.value())
+        });
+        programs.into_iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    // Returns programs on air at `now` which have the genre, in a single pass
+    // over the schedules.  All programs on air are returned if `genre` is
+    // `None`.
+    fn find_onair_programs(
+        &self,
+        genre: Option<u8>,
+        now: DateTime<Jst>,
+    ) -> Vec<EpgProgram> {
+        let rule = EpgProgramRule { genre, ..Default::default() };
+        let mut programs: Vec<EpgProgram> = self.schedules
+            .values()
+            .flat_map(|schedule| schedule.programs.values())
+            .filter(|program| program.start_at <= now && now < program.end_at())
+            .filter(|program| rule.matches(program))
+            .cloned()
+            .collect();
+        programs.sort_by_key(|program| {
+            MirakurunProgramId::from(program.quad).value()
+        });
+        programs
+    }
+
+    // Returns a program covering `at` for each service.  Overnight events are
+    // included in the programs of each schedule.  When programs overlap, the
+    // one which starts last is returned.
+    fn find_programs_at(&self, at: DateTime<Jst>) -> Vec<EpgProgram> {
+        let mut programs: Vec<EpgProgram> = self.schedules
+            .values()
+            .filter_map(|schedule| {
+                schedule.programs.values()
+                    .filter(|program| {
+                        program.start_at <= at && at < program.end_at()
+                    })
+                    .max_by_key(|program| {
+                        let id = MirakurunProgramId::from(program.quad);
+                        (program.start_at, id.value())
+                    })
+            })
+            .cloned()
+            .collect();
+        programs.sort_by_key(|program| {
+            MirakurunProgramId::from(program.quad).value()
+        });
+        programs
+    }
+
+    // Returns programs overlapping the day starting at `midnight` for each
+    // target service in the order of services.  Overnight events started in
+    // the previous day are included as carry-ins because they're kept in the
+    // programs of each schedule.  All exportable services are targeted if
+    // `services` is `None`.  At most `limit` services after skipping `offset`
+    // services are returned.
+    fn collect_guide(
+        &self,
+        midnight: DateTime<Jst>,
+        services: Option<&[MirakurunServiceId]>,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<(EpgService, Vec<EpgProgram>)> {
+        let end = midnight + Duration::days(1);
+        self.services
+            .values()
+            .filter(|sv| sv.is_exportable())
+            .filter(|sv| match services {
+                Some(ids) => ids.contains(&sv.triple().into()),
+                None => true,
+            })
+            .skip(offset)
+            .take(limit)
+            .map(|sv| {
+                let mut programs: Vec<EpgProgram> =
+                    match self.schedules.get(&sv.triple()) {
+                        Some(schedule) => schedule.programs
+                            .values()
+                            .filter(|program| {
+                                program.start_at < end &&
+                                    program.end_at() > midnight
+                            })
+                            .cloned()
+                            .collect(),
+                        None => Vec::new(),
+                    };
+                programs.sort_by_key(|program| {
+                    let id = MirakurunProgramId::from(program.quad);
+                    (program.start_at, id.value())
+                });
+                (sv.clone(), programs)
+            })
+            .collect()
+    }
+
+    // Programs are sorted by their IDs so that the same programs are always
+    // returned in the same order regardless of the order of HashMap entries.
+    fn collect_all_programs(&self) -> Vec<EpgProgram> {
+        let mut programs: Vec<EpgProgram> = self.schedules
+            .values()
+            .flat_map(|schedule| schedule.programs.values())
+            .cloned()
+            .collect();
+        programs.sort_by_key(|program| {
+            MirakurunProgramId::from(program.quad).value()
+        });
+        programs
+    }
+
+    // Returns programs added or modified after `since`, and IDs of programs
+    // removed after `since`.
+    fn collect_changed_programs(
+        &self,
+        since: DateTime<Jst>,
+    ) -> (Vec<EpgProgram>, Vec<EventQuad>) {
+        // Clone only changed programs.
+        let mut programs: Vec<EpgProgram> = self.schedules
+            .values()
+            .flat_map(|schedule| schedule.programs.values())
+            .filter(|program| self.changes.is_modified(&program.quad, since))
+            .cloned()
+            .collect();
+        programs.sort_by_key(|program| {
+            MirakurunProgramId::from(program.quad).value()
+        });
+        (programs, self.changes.collect_removed(since))
+    }
+
+    // Programs are re-collected only for the specified services.  Schedules
+    // of other services are never touched, so that collections running for
+    // different services concurrently don't prune each other's programs.
     fn flush_schedules(&mut self, triples: Vec<ServiceTriple>) {
+        let now = Jst::now();
+        self.changes.purge(now);
         for triple in triples.iter() {
-            let num_programs = match self.schedules.get_mut(triple) {
-                Some(schedule) => {
-                    schedule.collect_programs();
-                    schedule.programs.len()
+            let schedule = match self.schedules.get_mut(triple) {
+                Some(schedule) => schedule,
+                None => {
+                    log::debug!("No schedule for service#{}, skip", triple);
+                    continue;
                 }
-                None => 0,
             };
+            let old_programs = std::mem::take(&mut schedule.programs);
+            schedule.collect_programs(self.config.epg.zero_duration_events);
+            self.changes.update(&old_programs, &schedule.programs, now);
+            let num_programs = schedule.programs.len();
             if num_programs > 0 {
-                let service = self.services.get(triple)
-                    .expect("Service must exist");
-                log::info!("Collected {} programs of {} ({})",
-                           num_programs, service.name, triple);
+                // The service may have been removed by a scan which ran
+                // while collecting EIT sections.
+                match self.services.get(triple) {
+                    Some(service) =>
+                        log::info!("Collected {} programs of {} ({})",
+                                   num_programs, service.name, triple),
+                    None =>
+                        log::info!("Collected {} programs of service#{}",
+                                   num_programs, triple),
+                }
             }
         }
     }
@@ -172,11 +600,58 @@ impl Epg {
 
         // Removing "garbage" schedules.
         for id in unused_ids.iter() {
-            self.schedules.remove(&id);
+            if let Some(sched) = self.schedules.remove(&id) {
+                self.changes.update(
+                    &sched.programs, &HashMap::new(), timestamp);
+            }
             log::debug!("Removed schedule#{}", id);
         }
     }
 
+    // Returns `None` if the cache file is broken or has been saved in an old
+    // format or with another version.
+    fn load_cache<T>(json_path: &Path) -> Result<Option<T>, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let json = std::fs::read(json_path)?;
+        let version = match serde_json::from_slice::<CacheHeader>(&json) {
+            Ok(header) => header.version,
+            Err(_) => {
+                log::warn!("Ignore {} which is broken or saved in an old \
+                            format", json_path.display());
+                return Ok(None);
+            }
+        };
+        if version != CACHE_VERSION {
+            log::warn!("Ignore {} saved with version {}, expected {}",
+                       json_path.display(), version, CACHE_VERSION);
+            return Ok(None);
+        }
+        let cache: CacheFile<T> = serde_json::from_slice(&json)?;
+        Ok(Some(cache.data))
+    }
+
+    // Data is written into a temporary file which is renamed to the cache file
+    // after it has been fully written, so that the cache file is never left
+    // partially written even if mirakc crashes while saving.
+    fn save_cache<T>(json_path: &Path, data: &T) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let tmp_path = json_path.with_extension("json.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &CacheFileRef {
+            version: CACHE_VERSION,
+            data,
+        })?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        std::fs::rename(&tmp_path, json_path)?;
+        Ok(())
+    }
+
     // Must be called before other load functions.
     fn load_services(&mut self) -> Result<(), Error> {
         let channels: Vec<EpgChannel> = self.config.channels.iter()
@@ -190,9 +665,11 @@ impl Epg {
                 let json_path = PathBuf::from(cache_dir).join("services.json");
                 log::debug!("Loading schedules from {}...",
                             json_path.display());
-                let reader = BufReader::new(File::open(&json_path)?);
                 let services: IndexMap<ServiceTriple, EpgService> =
-                    serde_json::from_reader(reader)?;
+                    match Self::load_cache(&json_path)? {
+                        Some(services) => services,
+                        None => return Ok(()),
+                    };
                 // Drop a service if the channel of the service has been
                 // changed.
                 self.services = services
@@ -223,9 +700,11 @@ impl Epg {
             Some(ref cache_dir) => {
                 let json_path = PathBuf::from(cache_dir).join("clocks.json");
                 log::debug!("Loading clocks from {}...", json_path.display());
-                let reader = BufReader::new(File::open(&json_path)?);
                 let clocks: HashMap<ServiceTriple, Clock> =
-                    serde_json::from_reader(reader)?;
+                    match Self::load_cache(&json_path)? {
+                        Some(clocks) => clocks,
+                        None => return Ok(()),
+                    };
                 // Drop a clock if the service triple of the clock is not
                 // contained in `self::services`.
                 self.clocks = clocks
@@ -253,9 +732,11 @@ impl Epg {
             Some(ref cache_dir) => {
                 let json_path = PathBuf::from(cache_dir).join("schedules.json");
                 log::debug!("Loading schedules from {}...", json_path.display());
-                let reader = BufReader::new(File::open(&json_path)?);
                 let schedules: HashMap<ServiceTriple, EpgSchedule> =
-                    serde_json::from_reader(reader)?;
+                    match Self::load_cache(&json_path)? {
+                        Some(schedules) => schedules,
+                        None => return Ok(()),
+                    };
                 // Drop a clock if the service triple of the clock is not
                 // contained in `self::services`.
                 self.schedules = schedules
@@ -270,6 +751,9 @@ impl Epg {
                     })
                     .collect();
                 log::info!("Loaded schedules for {} services", self.schedules.len());
+                if !self.schedules.is_empty() {
+                    self.ready = true;
+                }
             }
             None => {
                 log::warn!("No epg.cache-dir specified, skip to load");
@@ -283,8 +767,7 @@ impl Epg {
             Some(ref cache_dir) => {
                 let json_path = PathBuf::from(cache_dir).join("services.json");
                 log::debug!("Saving services into {}...", json_path.display());
-                let writer = BufWriter::new(File::create(&json_path)?);
-                serde_json::to_writer(writer, &self.services)?;
+                Self::save_cache(&json_path, &self.services)?;
                 log::info!("Saved {} services", self.services.len());
             }
             None => {
@@ -299,8 +782,7 @@ impl Epg {
             Some(ref cache_dir) => {
                 let json_path = PathBuf::from(cache_dir).join("clocks.json");
                 log::debug!("Saving clocks into {}...", json_path.display());
-                let writer = BufWriter::new(File::create(&json_path)?);
-                serde_json::to_writer(writer, &self.clocks)?;
+                Self::save_cache(&json_path, &self.clocks)?;
                 log::info!("Saved {} clocks", self.clocks.len());
             }
             None => {
@@ -310,13 +792,42 @@ impl Epg {
         Ok(())
     }
 
+    // Thumbnails of programs removed from the EPG are also removed so that the
+    // storage is bounded by the retention window of the EPG.
+    //
+    // Walking the directory may take a long time.  It's performed on a thread
+    // for blocking operations so that the actor is not blocked.
+    fn prune_thumbnails(&self) {
+        let dir = match self.config.epg.thumbnails {
+            Some(ref config) => config.dir.clone(),
+            None => return,
+        };
+        let ids: HashSet<u64> = self.schedules
+            .values()
+            .flat_map(|schedule| schedule.programs.values())
+            .map(|program| MirakurunProgramId::from(program.quad).value())
+            .collect();
+        actix::spawn(async move {
+            let result = tokio::task::spawn_blocking(move || {
+                thumbnail::prune_thumbnails(&dir, &ids)
+            }).await;
+            match result {
+                Ok(Ok(0)) => (),
+                Ok(Ok(n)) => log::info!("Removed {} thumbnails", n),
+                Ok(Err(err)) =>
+                    log::error!("Failed to prune thumbnails: {}", err),
+                Err(err) =>
+                    log::error!("Failed to prune thumbnails: {}", err),
+            }
+        });
+    }
+
     fn save_schedules(&self) -> Result<(), Error> {
         match self.config.epg.cache_dir {
             Some(ref cache_dir) => {
                 let json_path = PathBuf::from(cache_dir).join("schedules.json");
                 log::debug!("Saving schedules into {}...", json_path.display());
-                let writer = BufWriter::new(File::create(&json_path)?);
-                serde_json::to_writer(writer, &self.schedules)?;
+                Self::save_cache(&json_path, &self.schedules)?;
                 log::info!("Saved schedules for {} services",
                            self.schedules.len());
             }
@@ -329,17 +840,192 @@ impl Epg {
     }
 
     fn collect_programs(&mut self) {
+        let policy = self.config.epg.zero_duration_events;
+        let now = Jst::now();
         for schedule in self.schedules.values_mut() {
-            schedule.collect_programs();
+            schedule.collect_programs(policy);
+            self.changes.update(&HashMap::new(), &schedule.programs, now);
+        }
+    }
+
+    // XMLTV has no event ID.  Temporary IDs are assigned from the end of the
+    // range of event IDs.  0xFFFF is reserved and never assigned.
+    const XMLTV_EID_BASE: u16 = 0xF000;
+    const XMLTV_EID_LAST: u16 = 0xFFFE;
+
+    // Returns an event ID which is not used in the schedule.  The search starts
+    // from the ID next to the last assigned one, and wraps around at the end of
+    // the range.  Returns `None` if all IDs in the range are used.
+    fn next_xmltv_eid(schedule: &EpgSchedule) -> Option<EventId> {
+        let n = (Self::XMLTV_EID_LAST - Self::XMLTV_EID_BASE + 1) as usize;
+        let start = schedule.programs.len() % n;
+        (0..n)
+            .map(|i| Self::XMLTV_EID_BASE + ((start + i) % n) as u16)
+            .map(EventId::from)
+            .find(|eid| !schedule.programs.contains_key(eid))
+    }
+
+    // Imported programs are used only for services which have no program.
+    // They're replaced with collected programs when `flush_schedules()` is
+    // called for the services.
+    fn import_xmltv(&mut self) -> Result<(), Error> {
+        let config = match self.config.epg.xmltv {
+            Some(ref config) => config.clone(),
+            None => return Ok(()),
+        };
+        log::debug!("Importing programs from {}...", config.path);
+        let xml = std::fs::read_to_string(&config.path)?;
+        let programmes = xmltv::parse(&xml)?;
+        let num_programs = self.import_xmltv_programmes(
+            &config.channels, programmes);
+        log::info!("Imported {} programs from {}", num_programs, config.path);
+        Ok(())
+    }
+
+    fn import_xmltv_programmes(
+        &mut self,
+        channels: &HashMap<String, MirakurunServiceId>,
+        programmes: Vec<XmltvProgramme>,
+    ) -> usize {
+        let mut triples = HashMap::new();
+        for (id, service_id) in channels.iter() {
+            match self.find_service(service_id.nid(), service_id.sid()) {
+                Ok(service) => {
+                    triples.insert(id.as_str(), service.triple());
+                }
+                Err(err) => {
+                    log::warn!("XMLTV channel {} is not mapped to any service: \
+                                {}", id, err);
+                }
+            }
+        }
+        // Services may not have been scanned yet on the first boot.
+        self.xmltv_pending = triples.len() < channels.len();
+
+        let collected: HashSet<ServiceTriple> = self.schedules.iter()
+            .filter(|(_, schedule)| !schedule.programs.is_empty())
+            .map(|(&triple, _)| triple)
+            .collect();
+
+        let now = Jst::now();
+        let mut num_programs = 0;
+        for programme in programmes.into_iter() {
+            let triple = match triples.get(programme.channel.as_str()) {
+                Some(&triple) => triple,
+                None => continue,
+            };
+            if collected.contains(&triple) {
+                continue;
+            }
+            if programme.stop < programme.start {
+                log::warn!("Skip a programme of XMLTV channel {} which stops \
+                            before it starts", programme.channel);
+                continue;
+            }
+            let schedule = self.schedules
+                .entry(triple)
+                .or_insert(EpgSchedule::new(triple));
+            let eid = match Self::next_xmltv_eid(schedule) {
+                Some(eid) => eid,
+                None => {
+                    log::warn!("Too many programmes for XMLTV channel {}, \
+                                skip", programme.channel);
+                    continue;
+                }
+            };
+            let mut program = EpgProgram::new((triple, eid).into());
+            program.start_at = programme.start;
+            program.duration = programme.stop - programme.start;
+            program.name = programme.title;
+            program.description = programme.desc;
+            self.changes.modified.insert(program.quad, now);
+            schedule.programs.insert(eid, program);
+            num_programs += 1;
         }
+        num_programs
+    }
+
+    // Services having the same NID and SID may exist in different transport
+    // streams.  The caller has to specify the TSID in this case.
+    fn find_service(
+        &self,
+        nid: NetworkId,
+        sid: ServiceId,
+    ) -> Result<&EpgService, Error> {
+        let mut services = self.services
+            .values()
+            .filter(|sv| sv.nid == nid && sv.sid == sid);
+        let service = services.next().ok_or(Error::ServiceNotFound)?;
+        if services.next().is_some() {
+            return Err(Error::BadRequest(format!(
+                "Multiple services found for ({}, {}), specify TSID",
+                nid, sid)));
+        }
+        Ok(service)
+    }
+
+    // Services having the same NID and SID may exist.  Use the first one which
+    // has the event.  Mirakurun program IDs don't contain the TSID, so the
+    // ambiguity is logged instead of failing the lookup.
+    fn find_program(
+        &self,
+        nid: NetworkId,
+        sid: ServiceId,
+        eid: EventId,
+    ) -> Result<EpgProgram, Error> {
+        let mut programs = self.services
+            .values()
+            .filter(|sv| sv.nid == nid && sv.sid == sid)
+            .filter_map(|sv| self.schedules.get(&sv.triple()))
+            .filter_map(|schedule| schedule.programs.get(&eid));
+        let mut program = programs.next()
+            .cloned()
+            .ok_or(Error::ProgramNotFound)?;
+        if let Some(other) = programs.next() {
+            log::warn!("Multiple programs found for ({}, {}, {}), \
+                        use {} instead of {}",
+                       nid, sid, eid, program.quad, other.quad);
+        }
+        if let Some(airtime) = self.airtimes.get(&program.quad) {
+            program.start_at = airtime.start_time;
+            program.duration = airtime.duration;
+        }
+        Ok(program)
+    }
+
+    // Networks are listed in the order of services.  The name of a network is
+    // taken from the first service having it.
+    fn collect_networks(&self) -> Vec<EpgNetwork> {
+        let mut networks: IndexMap<NetworkId, Option<String>> =
+            IndexMap::new();
+        for service in self.services.values().filter(|sv| sv.is_exportable()) {
+            let name = networks.entry(service.nid).or_insert(None);
+            if name.is_none() {
+                *name = service.network_name.clone();
+            }
+        }
+        networks.into_iter()
+            .map(|(network_id, name)| EpgNetwork { network_id, name })
+            .collect()
     }
 }
 
 impl Actor for Epg {
     type Context = Context<Self>;
 
-    fn started(&mut self, _: &mut Self::Context) {
+    fn started(&mut self, ctx: &mut Self::Context) {
         log::debug!("Started");
+        // Schedules are also saved when jobs updating them finish.  Saving them
+        // periodically keeps sections collected in a long-running job.
+        let interval = self.config.epg.cache_save_interval;
+        if self.config.epg.cache_dir.is_some() && interval > 0 {
+            ctx.run_interval(
+                std::time::Duration::from_millis(interval), |act, _| {
+                    if let Err(err) = act.save_schedules() {
+                        log::error!("Failed to save schedules: {}", err);
+                    }
+                });
+        }
         if let Err(err) = self.load_services() {
             log::error!("Failed to load services: {}", err);
         }
@@ -350,6 +1036,9 @@ impl Actor for Epg {
             log::error!("Failed to load schedules: {}", err);
         }
         self.collect_programs();
+        if let Err(err) = self.import_xmltv() {
+            log::error!("Failed to import XMLTV: {}", err);
+        }
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
@@ -357,6 +1046,21 @@ impl Actor for Epg {
     }
 }
 
+// reload config
+
+impl Handler<ReloadConfigMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ReloadConfigMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.reload_config(msg.config);
+    }
+}
+
 // query channels
 
 pub struct QueryChannelsMessage;
@@ -471,11 +1175,39 @@ impl Handler<QueryServicesMessage> for Epg {
     }
 }
 
+// query networks
+
+pub struct QueryNetworksMessage;
+
+impl fmt::Display for QueryNetworksMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryNetworks")
+    }
+}
+
+impl Message for QueryNetworksMessage {
+    type Result = Result<Vec<EpgNetwork>, Error>;
+}
+
+impl Handler<QueryNetworksMessage> for Epg {
+    type Result = Result<Vec<EpgNetwork>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryNetworksMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        Ok(self.collect_networks())
+    }
+}
+
 // query service
 
 pub enum QueryServiceMessage {
     // For Mirakurun-compatible Web API
     ByNidSid { nid: NetworkId, sid: ServiceId },
+    ByTriple { triple: ServiceTriple },
 }
 
 impl fmt::Display for QueryServiceMessage {
@@ -483,6 +1215,8 @@ impl fmt::Display for QueryServiceMessage {
         match self {
             QueryServiceMessage::ByNidSid { nid, sid } =>
                 write!(f, "QueryService By ({}, {})", nid, sid),
+            QueryServiceMessage::ByTriple { triple } =>
+                write!(f, "QueryService By {}", triple),
         }
     }
 }
@@ -502,9 +1236,11 @@ impl Handler<QueryServiceMessage> for Epg {
         log::debug!("{}", msg);
         match msg {
             QueryServiceMessage::ByNidSid { nid, sid } => {
+                self.find_service(nid, sid).map(|sv| sv.clone())
+            }
+            QueryServiceMessage::ByTriple { triple } => {
                 self.services
-                    .values()
-                    .find(|sv| sv.nid == nid && sv.sid == sid)
+                    .get(&triple)
                     .cloned()
                     .ok_or(Error::ServiceNotFound)
             }
@@ -541,786 +1277,2239 @@ impl Handler<QueryClockMessage> for Epg {
     }
 }
 
-// query programs
+// query clock offsets
 
-pub struct QueryProgramsMessage;
+pub struct QueryClockOffsetsMessage;
 
-impl fmt::Display for QueryProgramsMessage {
+impl fmt::Display for QueryClockOffsetsMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "QueryPrograms")
+        write!(f, "QueryClockOffsets")
     }
 }
 
-impl Message for QueryProgramsMessage {
-    type Result = Result<Vec<EpgProgram>, Error>;
+impl Message for QueryClockOffsetsMessage {
+    type Result = Result<Vec<ClockOffset>, Error>;
 }
 
-impl Handler<QueryProgramsMessage> for Epg {
-    type Result = Result<Vec<EpgProgram>, Error>;
+impl Handler<QueryClockOffsetsMessage> for Epg {
+    type Result = Result<Vec<ClockOffset>, Error>;
 
     fn handle(
         &mut self,
-        msg: QueryProgramsMessage,
+        msg: QueryClockOffsetsMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
-        let mut programs = Vec::new();
-        for schedule in self.schedules.values() {
-            programs.extend(schedule.programs.values().cloned())
-        }
-        Ok(programs)
+        Ok(self.collect_clock_offsets())
     }
 }
 
-// query program
+// query fresh services
 
-pub enum QueryProgramMessage {
-    // For Mirakurun-compatible Web API
-    ByNidSidEid { nid: NetworkId, sid: ServiceId, eid: EventId },
+pub struct QueryFreshServicesMessage {
+    pub since: DateTime<Jst>,
 }
 
-impl fmt::Display for QueryProgramMessage {
+impl fmt::Display for QueryFreshServicesMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            QueryProgramMessage::ByNidSidEid { nid, sid, eid } =>
-                write!(f, "QueryProgram By ({}, {}, {})", nid, sid, eid),
-        }
+        write!(f, "QueryFreshServices since {}", self.since)
     }
 }
 
-impl Message for QueryProgramMessage {
-    type Result = Result<EpgProgram, Error>;
+impl Message for QueryFreshServicesMessage {
+    type Result = Result<Vec<ServiceTriple>, Error>;
 }
 
-impl Handler<QueryProgramMessage> for Epg {
-    type Result = Result<EpgProgram, Error>;
+impl Handler<QueryFreshServicesMessage> for Epg {
+    type Result = Result<Vec<ServiceTriple>, Error>;
 
     fn handle(
         &mut self,
-        msg: QueryProgramMessage,
+        msg: QueryFreshServicesMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
-        match msg {
-            QueryProgramMessage::ByNidSidEid { nid, sid, eid } => {
-                let triple = self.services
-                    .values()
-                    .find(|sv| sv.nid == nid && sv.sid == sid)
-                    .map(|sv| sv.triple())
-                    .ok_or(Error::ProgramNotFound)?;
-                let schedule = self.schedules.get(&triple)
-                    .ok_or(Error::ProgramNotFound)?;
-                schedule.programs.get(&eid).cloned()
-                    .map(|mut prog| {
-                        if let Some(airtime) = self.airtimes.get(&prog.quad) {
-                            prog.start_at = airtime.start_time;
-                            prog.duration = airtime.duration;
-                        }
-                        prog
-                    })
-                    .ok_or(Error::ProgramNotFound)
-            }
-        }
+        Ok(self.collect_fresh_services(msg.since))
     }
 }
 
-// update services
+// query programs
 
-pub struct UpdateServicesMessage {
-    pub results: Vec<(EpgChannel, Option<IndexMap<ServiceTriple, EpgService>>)>,
-}
+pub struct QueryProgramsMessage;
 
-impl fmt::Display for UpdateServicesMessage {
+impl fmt::Display for QueryProgramsMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "UpdateServices")
+        write!(f, "QueryPrograms")
     }
 }
 
-impl Message for UpdateServicesMessage {
-    type Result = ();
+impl Message for QueryProgramsMessage {
+    type Result = Result<Vec<EpgProgram>, Error>;
 }
 
-impl Handler<UpdateServicesMessage> for Epg {
-    type Result = ();
+impl Handler<QueryProgramsMessage> for Epg {
+    type Result = Result<Vec<EpgProgram>, Error>;
 
     fn handle(
         &mut self,
-        msg: UpdateServicesMessage,
+        msg: QueryProgramsMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
-        self.update_services(msg.results);
+        Ok(self.collect_all_programs())
     }
 }
 
-// update clocks
+// query changed programs
 
-pub struct UpdateClocksMessage {
-    pub results: Vec<(EpgChannel, Option<HashMap<ServiceTriple, Clock>>)>,
+pub struct QueryChangedProgramsMessage {
+    pub since: DateTime<Jst>,
 }
 
-impl fmt::Display for UpdateClocksMessage {
+impl fmt::Display for QueryChangedProgramsMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "UpdateClocks")
+        write!(f, "QueryChangedPrograms since {}", self.since)
     }
 }
 
-impl Message for UpdateClocksMessage {
-    type Result = ();
+impl Message for QueryChangedProgramsMessage {
+    type Result = Result<(Vec<EpgProgram>, Vec<EventQuad>), Error>;
 }
 
-impl Handler<UpdateClocksMessage> for Epg {
-    type Result = ();
+impl Handler<QueryChangedProgramsMessage> for Epg {
+    type Result = Result<(Vec<EpgProgram>, Vec<EventQuad>), Error>;
 
     fn handle(
         &mut self,
-        msg: UpdateClocksMessage,
+        msg: QueryChangedProgramsMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
-        self.update_clocks(msg.results);
+        if !self.changes.is_tracked_since(msg.since) {
+            return Err(Error::ChangesNotTracked);
+        }
+        Ok(self.collect_changed_programs(msg.since))
     }
 }
 
-// update schedules
+// query programs by services
 
-pub struct UpdateSchedulesMessage {
-    pub sections: Vec<EitSection>,
+pub struct QueryProgramsByServicesMessage {
+    pub services: Vec<MirakurunServiceId>,
 }
 
-impl fmt::Display for UpdateSchedulesMessage {
+impl fmt::Display for QueryProgramsByServicesMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "UpdateSchedules with {} EIT sections", self.sections.len())
+        write!(f, "QueryPrograms by {} services", self.services.len())
     }
 }
 
-impl Message for UpdateSchedulesMessage {
-    type Result = ();
+impl Message for QueryProgramsByServicesMessage {
+    type Result = Result<Vec<(MirakurunServiceId, Vec<EpgProgram>)>, Error>;
 }
 
-impl Handler<UpdateSchedulesMessage> for Epg {
-    type Result = ();
+impl Handler<QueryProgramsByServicesMessage> for Epg {
+    type Result = Result<Vec<(MirakurunServiceId, Vec<EpgProgram>)>, Error>;
 
     fn handle(
         &mut self,
-        msg: UpdateSchedulesMessage,
+        msg: QueryProgramsByServicesMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
-        self.update_schedules(msg.sections);
+        let results = msg.services
+            .into_iter()
+            .map(|id| {
+                // An empty list is returned for an unknown service.
+                let mut programs: Vec<EpgProgram> = self.schedules
+                    .iter()
+                    .filter(|(triple, _)| {
+                        MirakurunServiceId::from(**triple) == id
+                    })
+                    .flat_map(|(_, schedule)| schedule.programs.values())
+                    .cloned()
+                    .collect();
+                programs.sort_by_key(|program| {
+                    let id = MirakurunProgramId::from(program.quad);
+                    (program.start_at, id.value())
+                });
+                (id, programs)
+            })
+            .collect();
+        Ok(results)
     }
 }
 
-// flush schedules
+// query program
 
-pub struct FlushSchedulesMessage {
-    pub triples: Vec<ServiceTriple>,
+pub enum QueryProgramMessage {
+    // For Mirakurun-compatible Web API
+    ByNidSidEid { nid: NetworkId, sid: ServiceId, eid: EventId },
 }
 
-impl fmt::Display for FlushSchedulesMessage {
+impl fmt::Display for QueryProgramMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let triples: Vec<String> = self.triples
-            .iter()
-            .map(|triple| triple.to_string())
-            .collect();
-        write!(f, "FlushSchedules for [{}]", triples.as_slice().join(", "))
+        match self {
+            QueryProgramMessage::ByNidSidEid { nid, sid, eid } =>
+                write!(f, "QueryProgram By ({}, {}, {})", nid, sid, eid),
+        }
     }
 }
 
-impl Message for FlushSchedulesMessage {
-    type Result = ();
+impl Message for QueryProgramMessage {
+    type Result = Result<EpgProgram, Error>;
 }
 
-impl Handler<FlushSchedulesMessage> for Epg {
-    type Result = ();
+impl Handler<QueryProgramMessage> for Epg {
+    type Result = Result<EpgProgram, Error>;
 
     fn handle(
         &mut self,
-        msg: FlushSchedulesMessage,
+        msg: QueryProgramMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
-        self.flush_schedules(msg.triples);
+        match msg {
+            QueryProgramMessage::ByNidSidEid { nid, sid, eid } =>
+                self.find_program(nid, sid, eid),
+        }
     }
 }
 
-// save schedules
+// query next program
 
-pub struct SaveSchedulesMessage;
+pub struct QueryNextProgramMessage {
+    pub rule: EpgProgramRule,
+}
 
-impl fmt::Display for SaveSchedulesMessage {
+impl fmt::Display for QueryNextProgramMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "SaveSchedules")
+        write!(f, "QueryNextProgram by {:?}", self.rule)
     }
 }
 
-impl Message for SaveSchedulesMessage {
-    type Result = ();
+impl Message for QueryNextProgramMessage {
+    type Result = Result<Option<EpgProgram>, Error>;
 }
 
-impl Handler<SaveSchedulesMessage> for Epg {
-    type Result = ();
+impl Handler<QueryNextProgramMessage> for Epg {
+    type Result = Result<Option<EpgProgram>, Error>;
 
     fn handle(
         &mut self,
-        msg: SaveSchedulesMessage,
+        msg: QueryNextProgramMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
-        match self.save_schedules() {
-            Ok(_) => (),
-            Err(err) => log::error!("Failed to save schedules: {}", err),
-        }
+        Ok(self.find_next_program(&msg.rule, Jst::now()))
     }
 }
 
-// update airtime
+// search programs
 
-pub struct UpdateAirtimeMessage {
-    pub quad: EventQuad,
-    pub airtime: Airtime,
+pub struct SearchProgramsMessage {
+    pub rule: EpgProgramRule,
+    pub offset: usize,
+    pub limit: usize,
 }
 
-impl fmt::Display for UpdateAirtimeMessage {
+impl fmt::Display for SearchProgramsMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "UpdateAirtime of {}", self.quad)
+        write!(f, "SearchPrograms by {:?} offset={} limit={}",
+               self.rule, self.offset, self.limit)
     }
 }
 
-impl Message for UpdateAirtimeMessage {
-    type Result = ();
+impl Message for SearchProgramsMessage {
+    type Result = Result<Vec<EpgProgram>, Error>;
 }
 
-impl Handler<UpdateAirtimeMessage> for Epg {
-    type Result = ();
+impl Handler<SearchProgramsMessage> for Epg {
+    type Result = Result<Vec<EpgProgram>, Error>;
 
     fn handle(
         &mut self,
-        msg: UpdateAirtimeMessage,
+        msg: SearchProgramsMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
-        self.airtimes.insert(msg.quad, msg.airtime);
+        Ok(self.search_programs(&msg.rule, msg.offset, msg.limit))
     }
 }
 
-// remove airtime
+// query on-air programs
 
-pub struct RemoveAirtimeMessage {
-    pub quad: EventQuad,
+pub struct QueryOnAirProgramsMessage {
+    pub genre: Option<u8>,
 }
 
-impl fmt::Display for RemoveAirtimeMessage {
+impl fmt::Display for QueryOnAirProgramsMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "RemoveAirtime of {}", self.quad)
+        write!(f, "QueryOnAirPrograms by genre {:?}", self.genre)
     }
 }
 
-impl Message for RemoveAirtimeMessage {
-    type Result = ();
+impl Message for QueryOnAirProgramsMessage {
+    type Result = Result<Vec<EpgProgram>, Error>;
 }
 
-impl Handler<RemoveAirtimeMessage> for Epg {
-    type Result = ();
+impl Handler<QueryOnAirProgramsMessage> for Epg {
+    type Result = Result<Vec<EpgProgram>, Error>;
 
     fn handle(
         &mut self,
-        msg: RemoveAirtimeMessage,
+        msg: QueryOnAirProgramsMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
-        self.airtimes.remove(&msg.quad);
+        Ok(self.find_onair_programs(msg.genre, Jst::now()))
     }
 }
 
-#[derive(Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct EpgSchedule {
-    service_triple: ServiceTriple,
-    // In Japan, only the following indexes are used:
-    //
-    //    0 | 8 | 16 | 24 => the former 4 days of 8 days schedule
-    //    1 | 9 | 17 | 25 => the later 4 days of 8 days schedule
-    tables: [Option<Box<EpgTable>>; 32],
-    overnight_events: Vec<EitEvent>,
-    #[serde(with = "serde_jst")]
-    updated_at: DateTime<Jst>,
-    #[serde(skip)]
-    programs: HashMap<EventId, EpgProgram>,
+// query programs at
+
+pub struct QueryProgramsAtMessage {
+    pub at: DateTime<Jst>,
 }
 
-impl EpgSchedule {
-    fn new(triple: ServiceTriple) -> EpgSchedule {
-        EpgSchedule {
-            service_triple: triple,
-            tables: Default::default(),
-            overnight_events: Vec::new(),
-            updated_at: Jst::now(),
-            programs: HashMap::new(),
-        }
+impl fmt::Display for QueryProgramsAtMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryProgramsAt {}", self.at)
     }
+}
 
-    fn update(&mut self, section: EitSection) {
-        let i = section.table_index();
-        if self.tables[i].is_none() {
-            self.tables[i] = Some(Box::new(EpgTable::default()));
-        }
-        self.tables[i].as_mut().unwrap().update(section);
-    }
+impl Message for QueryProgramsAtMessage {
+    type Result = Result<Vec<EpgProgram>, Error>;
+}
 
-    fn save_overnight_events(&mut self, midnight: DateTime<Jst>) {
-        let mut events = Vec::new();
-        for table in self.tables.iter() {
-            if let Some(ref table) = table {
-                events = table.collect_overnight_events(midnight, events);
-            }
-        }
-        log::debug!("Saved {} overnight events of schedule#{}",
-                    events.len(), self.service_triple);
-        self.overnight_events = events;
-    }
+impl Handler<QueryProgramsAtMessage> for Epg {
+    type Result = Result<Vec<EpgProgram>, Error>;
 
-    fn collect_programs(&mut self) {
-        let mut programs = HashMap::new();
-        for event in self.overnight_events.iter() {
-            let quad = EventQuad::from(
-                (self.service_triple, EventId::from(event.event_id)));
-            programs
-                .entry(event.event_id)
-                .or_insert(EpgProgram::new(quad))
-                .update(event);
-        }
-        for table in self.tables.iter() {
-            if let Some(table) = table {
-                table.collect_programs(self.service_triple, &mut programs)
-            }
-        }
-        self.programs = programs;
+    fn handle(
+        &mut self,
+        msg: QueryProgramsAtMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        Ok(self.find_programs_at(msg.at))
     }
 }
 
-#[derive(Default)]
-#[derive(Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-// A table contains TV program information about 4 days of a TV program
-// schedule.
-struct EpgTable {
-    // Segments are stored in chronological order.
-    //
-    // The first 8 consecutive segments contains TV program information for the
-    // first day.
-    segments: [EpgSegment; 32],
+// query guide
+
+pub struct QueryGuideMessage {
+    pub midnight: DateTime<Jst>,
+    pub services: Option<Vec<MirakurunServiceId>>,
+    pub offset: usize,
+    pub limit: usize,
 }
 
-impl EpgTable {
-    fn update(&mut self, section: EitSection) {
-        let i = section.segment_index();
-        self.segments[i].update(section);
+impl fmt::Display for QueryGuideMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryGuide for {}", self.midnight.date())
     }
+}
 
-    fn collect_overnight_events(
-        &self,
-        midnight: DateTime<Jst>,
-        mut events: Vec<EitEvent>
-    ) -> Vec<EitEvent> {
-        for segment in self.segments.iter() {
-            events = segment.collect_overnight_events(midnight, events);
-        }
-        events
+impl Message for QueryGuideMessage {
+    type Result = Result<Vec<(EpgService, Vec<EpgProgram>)>, Error>;
+}
+
+impl Handler<QueryGuideMessage> for Epg {
+    type Result = Result<Vec<(EpgService, Vec<EpgProgram>)>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryGuideMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        Ok(self.collect_guide(
+            msg.midnight, msg.services.as_deref(), msg.offset, msg.limit))
     }
+}
 
-    fn collect_programs(
-        &self,
-        triple: ServiceTriple,
-        programs: &mut HashMap<EventId, EpgProgram>) {
-        for segment in self.segments.iter() {
-            segment.collect_programs(triple, programs)
-        }
+// query epg ready
+
+pub struct QueryEpgReadyMessage;
+
+impl fmt::Display for QueryEpgReadyMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryEpgReady")
     }
 }
 
-#[derive(Default)]
-#[derive(Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-// A segment contains TV program information about 3 hours of a TV program
-// schedule.
-struct EpgSegment {
-    // Sections are stored in chronological order.
-    sections: [Option<EpgSection>; 8],
+impl Message for QueryEpgReadyMessage {
+    type Result = Result<bool, Error>;
 }
 
-impl EpgSegment {
-    fn update(&mut self, section: EitSection) {
-        let n = section.last_section_index() + 1;
-        for i in n..8 {
-            self.sections[i] = None;
-        }
+impl Handler<QueryEpgReadyMessage> for Epg {
+    type Result = Result<bool, Error>;
 
-        let i = section.section_index();
-        self.sections[i] = Some(EpgSection::from(section));
+    fn handle(
+        &mut self,
+        msg: QueryEpgReadyMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        Ok(self.ready)
     }
+}
 
-    fn collect_overnight_events(
-        &self,
-        midnight: DateTime<Jst>,
-        events: Vec<EitEvent>
-    ) -> Vec<EitEvent> {
-        self.sections
-            .iter()
-            .filter(|section| section.is_some())
-            .map(|section| section.as_ref().unwrap())
-            .fold(events, |events_, section| {
-                section.collect_overnight_events(midnight, events_)
-            })
+// query epg status
+
+pub struct QueryEpgStatusMessage;
+
+impl fmt::Display for QueryEpgStatusMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryEpgStatus")
     }
+}
 
-    fn collect_programs(
-        &self,
-        triple: ServiceTriple,
-        programs: &mut HashMap<EventId, EpgProgram>) {
-        for section in self.sections.iter() {
-            if let Some(section) = section {
-                section.collect_programs(triple, programs)
-            }
-        }
+impl Message for QueryEpgStatusMessage {
+    type Result = Result<EpgStatus, Error>;
+}
+
+impl Handler<QueryEpgStatusMessage> for Epg {
+    type Result = Result<EpgStatus, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryEpgStatusMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        Ok(EpgStatus {
+            ready: self.ready,
+            eit_collection: self.eit_collection_stats.clone(),
+            service_scan: self.service_scan_stats.clone(),
+            invalid_sections: self.collect_invalid_section_stats(),
+        })
     }
 }
 
-#[derive(Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct EpgSection {
-    version: u8,
-    // Events are stored in chronological order.
-    events: Vec<EitEvent>,
+// update services
+
+pub struct UpdateServicesMessage {
+    pub results: Vec<(EpgChannel, Option<IndexMap<ServiceTriple, EpgService>>)>,
 }
 
-impl EpgSection {
-    fn collect_overnight_events(
-        &self,
-        midnight: DateTime<Jst>,
-        mut events: Vec<EitEvent>
-    ) -> Vec<EitEvent> {
-        for event in self.events.iter() {
-            if event.is_overnight_event(midnight) {
-                events.push(event.clone());
+impl fmt::Display for UpdateServicesMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UpdateServices")
+    }
+}
+
+impl Message for UpdateServicesMessage {
+    type Result = ();
+}
+
+impl Handler<UpdateServicesMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: UpdateServicesMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.update_services(msg.results);
+        if self.xmltv_pending {
+            if let Err(err) = self.import_xmltv() {
+                log::error!("Failed to import XMLTV: {}", err);
             }
         }
-        events
     }
+}
 
-    fn collect_programs(
-        &self,
-        triple: ServiceTriple,
-        programs: &mut HashMap<EventId, EpgProgram>) {
-        for event in self.events.iter() {
-            let quad = EventQuad::from((triple, EventId::from(event.event_id)));
-            programs
-                .entry(event.event_id)
-                .or_insert(EpgProgram::new(quad))
-                .update(event);
-        }
+// update clocks
+
+pub struct UpdateClocksMessage {
+    pub results: Vec<(EpgChannel, Option<HashMap<ServiceTriple, Clock>>)>,
+}
+
+impl fmt::Display for UpdateClocksMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UpdateClocks")
     }
 }
 
-impl From<EitSection> for EpgSection {
-    fn from(section: EitSection) -> Self {
-        EpgSection {
-            version: section.version_number,
-            events: section.events,
-        }
+impl Message for UpdateClocksMessage {
+    type Result = ();
+}
+
+impl Handler<UpdateClocksMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: UpdateClocksMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.update_clocks(msg.results);
     }
 }
 
-#[derive(Clone, PartialEq)]
-#[derive(Deserialize, Serialize)]
-pub struct EpgChannel {
-    pub name: String,
-    #[serde(rename = "type")]
-    pub channel_type: ChannelType,
-    pub channel: String,
-    #[serde(default)]
-    pub extra_args: String,
-    pub services: Vec<ServiceId>,
-    pub excluded_services: Vec<ServiceId>,
+// merge clocks
+
+pub struct MergeClocksMessage {
+    pub results: Vec<(EpgChannel, Option<HashMap<ServiceTriple, Clock>>)>,
 }
 
-impl fmt::Display for EpgChannel {
+impl fmt::Display for MergeClocksMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}/{}", self.channel_type, self.channel)
+        write!(f, "MergeClocks of {} channels", self.results.len())
     }
 }
 
-impl From<ChannelConfig> for EpgChannel {
-    fn from(config: ChannelConfig) -> Self {
-        EpgChannel {
-            name: config.name,
-            channel_type: config.channel_type,
-            channel: config.channel,
-            extra_args: config.extra_args,
-            services: config.services,
-            excluded_services: config.excluded_services,
-        }
+impl Message for MergeClocksMessage {
+    type Result = ();
+}
+
+impl Handler<MergeClocksMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: MergeClocksMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.merge_clocks(msg.results);
     }
 }
 
-#[derive(Clone, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct EpgService {
-    pub nid: NetworkId,
-    pub tsid: TransportStreamId,
-    pub sid: ServiceId,
-    #[serde(rename = "type")]
-    pub service_type: u16,
-    #[serde(default)]
-    pub logo_id: i16,
-    #[serde(default)]
-    pub remote_control_key_id: u16,
-    pub name: String,
-    pub channel: EpgChannel,
+// update schedules
+
+pub struct UpdateSchedulesMessage {
+    pub sections: Vec<EitSection>,
 }
 
-impl EpgService {
-    pub fn triple(&self) -> ServiceTriple {
-        ServiceTriple::new(self.nid, self.tsid, self.sid)
+impl fmt::Display for UpdateSchedulesMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UpdateSchedules with {} EIT sections", self.sections.len())
     }
+}
 
-    fn is_exportable(&self) -> bool {
-        if !self.channel.services.is_empty() {
-            if !self.channel.services.contains(&self.sid) {
-                return false;
-            }
-        }
-        if self.channel.excluded_services.contains(&self.sid) {
-            return false;
-        }
-        true
+impl Message for UpdateSchedulesMessage {
+    type Result = ();
+}
+
+impl Handler<UpdateSchedulesMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: UpdateSchedulesMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.update_schedules(msg.sections);
     }
 }
 
-impl Into<MirakurunChannelService> for EpgService {
-    fn into(self) -> MirakurunChannelService {
-        MirakurunChannelService {
-            id: self.triple().into(),
-            service_id: self.sid,
-            network_id: self.nid,
-            name: self.name,
-        }
+// flush schedules
+
+pub struct FlushSchedulesMessage {
+    pub triples: Vec<ServiceTriple>,
+    // `true` if all EIT[schedule] tables of the services have been collected.
+    pub complete: bool,
+}
+
+impl fmt::Display for FlushSchedulesMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let triples: Vec<String> = self.triples
+            .iter()
+            .map(|triple| triple.to_string())
+            .collect();
+        write!(f, "FlushSchedules for [{}]", triples.as_slice().join(", "))
     }
 }
 
-#[derive(Clone)]
-pub struct EpgProgram {
-    pub quad: EventQuad,
-    pub start_at: DateTime<Jst>,
-    pub duration: Duration,
-    pub scrambled: bool,
-    pub name: Option<String>,
-    pub description: Option<String>,
-    pub extended: Option<IndexMap<String, String>>,
-    pub video: Option<EpgVideoInfo>,
-    pub audio: Option<EpgAudioInfo>,
-    pub genres: Option<Vec<EpgGenre>>,
+impl Message for FlushSchedulesMessage {
+    type Result = ();
 }
 
-impl EpgProgram {
-    pub fn new(quad: EventQuad) -> Self {
-        Self {
-            quad: quad,
-            start_at: Jst.timestamp(0, 0),
-            duration: Duration::minutes(0),
-            scrambled: false,
-            name: None,
-            description: None,
-            extended: None,
-            video: None,
-            audio: None,
-            genres: None,
+impl Handler<FlushSchedulesMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: FlushSchedulesMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        if msg.complete {
+            self.mark_schedules_collected(&msg.triples, Jst::now());
         }
+        self.flush_schedules(msg.triples);
     }
+}
 
-    fn _end_at(&self) -> DateTime<Jst> {
-        self.start_at + self.duration
+// save schedules
+
+pub struct SaveSchedulesMessage;
+
+impl fmt::Display for SaveSchedulesMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SaveSchedules")
     }
+}
 
-    fn update(&mut self, event: &EitEvent) {
-        self.start_at = event.start_time.clone();
-        self.duration = event.duration.clone();
-        self.scrambled = event.scrambled;
-        for desc in event.descriptors.iter() {
-            match desc {
-                EitDescriptor::ShortEvent { event_name, text } => {
-                    self.name = Some(event_name.clone());
-                    self.description = Some(text.clone());
-                }
-                EitDescriptor::Component { stream_content, component_type } => {
-                    self.video = Some(
-                        EpgVideoInfo::new(*stream_content, *component_type));
-                }
-                EitDescriptor::AudioComponent {
-                    component_type, sampling_rate } => {
-                    self.audio = Some(
-                        EpgAudioInfo::new(*component_type, *sampling_rate));
-                }
-                EitDescriptor::Content { nibbles } => {
-                    self.genres = Some(nibbles.iter()
-                                       .map(|nibble| EpgGenre::new(*nibble))
-                                       .collect());
-                }
-                EitDescriptor::ExtendedEvent { items } => {
-                    let mut map = IndexMap::new();
-                    map.extend(items.clone());
-                    self.extended = Some(map);
+impl Message for SaveSchedulesMessage {
+    type Result = ();
+}
+
+impl Handler<SaveSchedulesMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: SaveSchedulesMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        match self.save_schedules() {
+            Ok(_) => (),
+            Err(err) => log::error!("Failed to save schedules: {}", err),
+        }
+        self.prune_thumbnails();
+    }
+}
+
+// update eit collection stats
+
+pub struct UpdateEitCollectionStatsMessage {
+    pub stats: Vec<EitCollectionStats>,
+}
+
+impl fmt::Display for UpdateEitCollectionStatsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UpdateEitCollectionStats for {} channels",
+               self.stats.len())
+    }
+}
+
+impl Message for UpdateEitCollectionStatsMessage {
+    type Result = ();
+}
+
+impl Handler<UpdateEitCollectionStatsMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: UpdateEitCollectionStatsMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.eit_collection_stats = msg.stats;
+        self.ready = true;
+    }
+}
+
+// update service scan stats
+
+pub struct UpdateServiceScanStatsMessage {
+    pub stats: Vec<ServiceScanStats>,
+}
+
+impl fmt::Display for UpdateServiceScanStatsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UpdateServiceScanStats for {} channels",
+               self.stats.len())
+    }
+}
+
+impl Message for UpdateServiceScanStatsMessage {
+    type Result = ();
+}
+
+impl Handler<UpdateServiceScanStatsMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: UpdateServiceScanStatsMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.service_scan_stats = msg.stats;
+    }
+}
+
+// update airtime
+
+pub struct UpdateAirtimeMessage {
+    pub quad: EventQuad,
+    pub airtime: Airtime,
+}
+
+impl fmt::Display for UpdateAirtimeMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UpdateAirtime of {}", self.quad)
+    }
+}
+
+impl Message for UpdateAirtimeMessage {
+    type Result = ();
+}
+
+impl Handler<UpdateAirtimeMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: UpdateAirtimeMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.airtimes.insert(msg.quad, msg.airtime);
+    }
+}
+
+// remove airtime
+
+pub struct RemoveAirtimeMessage {
+    pub quad: EventQuad,
+}
+
+impl fmt::Display for RemoveAirtimeMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RemoveAirtime of {}", self.quad)
+    }
+}
+
+impl Message for RemoveAirtimeMessage {
+    type Result = ();
+}
+
+impl Handler<RemoveAirtimeMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: RemoveAirtimeMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.airtimes.remove(&msg.quad);
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EpgSchedule {
+    service_triple: ServiceTriple,
+    // In Japan, only the following indexes are used:
+    //
+    //    0 | 8 | 16 | 24 => the former 4 days of 8 days schedule
+    //    1 | 9 | 17 | 25 => the later 4 days of 8 days schedule
+    tables: [Option<Box<EpgTable>>; 32],
+    // Events in EIT[p/f] sections.  The first one contains the present event
+    // and the second one contains the following event.  They take precedence
+    // over the same events in `tables`.
+    #[serde(default)]
+    present_following: [Vec<EitEvent>; 2],
+    overnight_events: Vec<EitEvent>,
+    #[serde(with = "serde_jst")]
+    updated_at: DateTime<Jst>,
+    #[serde(skip)]
+    programs: HashMap<EventId, EpgProgram>,
+    // When all EIT[schedule] tables of the service were collected last time.
+    // This is not saved in the cache.
+    #[serde(skip)]
+    collected_at: Option<DateTime<Jst>>,
+}
+
+impl EpgSchedule {
+    fn new(triple: ServiceTriple) -> EpgSchedule {
+        EpgSchedule {
+            service_triple: triple,
+            tables: Default::default(),
+            present_following: Default::default(),
+            overnight_events: Vec::new(),
+            updated_at: Jst::now(),
+            programs: HashMap::new(),
+            collected_at: None,
+        }
+    }
+
+    fn update(&mut self, section: EitSection) {
+        if section.is_present_following() {
+            let i = section.section_number as usize;
+            if i < self.present_following.len() {
+                self.present_following[i] = section.events;
+            }
+            return;
+        }
+        let i = section.table_index();
+        if self.tables[i].is_none() {
+            self.tables[i] = Some(Box::new(EpgTable::default()));
+        }
+        self.tables[i].as_mut().unwrap().update(section);
+    }
+
+    fn save_overnight_events(&mut self, midnight: DateTime<Jst>) {
+        let mut events = Vec::new();
+        for table in self.tables.iter() {
+            if let Some(ref table) = table {
+                events = table.collect_overnight_events(midnight, events);
+            }
+        }
+        log::debug!("Saved {} overnight events of schedule#{}",
+                    events.len(), self.service_triple);
+        self.overnight_events = events;
+    }
+
+    fn collect_programs(&mut self, policy: ZeroDurationEvents) {
+        let mut programs = HashMap::new();
+        for event in self.overnight_events.iter() {
+            let quad = EventQuad::from(
+                (self.service_triple, EventId::from(event.event_id)));
+            programs
+                .entry(event.event_id)
+                .or_insert(EpgProgram::new(quad))
+                .update(event);
+        }
+        for table in self.tables.iter() {
+            if let Some(table) = table {
+                table.collect_programs(self.service_triple, &mut programs)
+            }
+        }
+        for event in self.present_following.iter().flatten() {
+            let quad = EventQuad::from(
+                (self.service_triple, EventId::from(event.event_id)));
+            programs
+                .entry(event.event_id)
+                .or_insert(EpgProgram::new(quad))
+                .update(event);
+        }
+        Self::handle_zero_duration_programs(&mut programs, policy);
+        self.programs = programs;
+    }
+
+    // ARIB STD-B10 uses a duration filled with 1s as an undefined duration.
+    // It's decoded into zero, the same as an event with no length.
+    fn handle_zero_duration_programs(
+        programs: &mut HashMap<EventId, EpgProgram>,
+        policy: ZeroDurationEvents,
+    ) {
+        match policy {
+            ZeroDurationEvents::Keep => (),
+            ZeroDurationEvents::Skip => {
+                programs.retain(|_, program| {
+                    program.duration > Duration::zero()
+                });
+            }
+            ZeroDurationEvents::Fill => {
+                let mut start_times: Vec<DateTime<Jst>> = programs.values()
+                    .map(|program| program.start_at)
+                    .collect();
+                start_times.sort();
+                for program in programs.values_mut() {
+                    if program.duration > Duration::zero() {
+                        continue;
+                    }
+                    // Programs without a following program are kept as they
+                    // are.
+                    let next = start_times.iter()
+                        .find(|start_at| **start_at > program.start_at);
+                    if let Some(next) = next {
+                        program.duration = *next - program.start_at;
+                    }
                 }
             }
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use chrono::{Date, TimeZone};
+#[derive(Default)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+// A table contains TV program information about 4 days of a TV program
+// schedule.
+struct EpgTable {
+    // Segments are stored in chronological order.
+    //
+    // The first 8 consecutive segments contains TV program information for the
+    // first day.
+    segments: [EpgSegment; 32],
+}
+
+impl EpgTable {
+    fn update(&mut self, section: EitSection) {
+        let i = section.segment_index();
+        self.segments[i].update(section);
+    }
+
+    fn collect_overnight_events(
+        &self,
+        midnight: DateTime<Jst>,
+        mut events: Vec<EitEvent>
+    ) -> Vec<EitEvent> {
+        for segment in self.segments.iter() {
+            events = segment.collect_overnight_events(midnight, events);
+        }
+        events
+    }
+
+    fn collect_programs(
+        &self,
+        triple: ServiceTriple,
+        programs: &mut HashMap<EventId, EpgProgram>) {
+        for segment in self.segments.iter() {
+            segment.collect_programs(triple, programs)
+        }
+    }
+}
+
+#[derive(Default)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+// A segment contains TV program information about 3 hours of a TV program
+// schedule.
+struct EpgSegment {
+    // Sections are stored in chronological order.
+    sections: [Option<EpgSection>; 8],
+}
+
+impl EpgSegment {
+    fn update(&mut self, section: EitSection) {
+        let n = section.last_section_index() + 1;
+        for i in n..8 {
+            self.sections[i] = None;
+        }
+
+        let i = section.section_index();
+        self.sections[i] = Some(EpgSection::from(section));
+    }
+
+    fn collect_overnight_events(
+        &self,
+        midnight: DateTime<Jst>,
+        events: Vec<EitEvent>
+    ) -> Vec<EitEvent> {
+        self.sections
+            .iter()
+            .filter(|section| section.is_some())
+            .map(|section| section.as_ref().unwrap())
+            .fold(events, |events_, section| {
+                section.collect_overnight_events(midnight, events_)
+            })
+    }
+
+    fn collect_programs(
+        &self,
+        triple: ServiceTriple,
+        programs: &mut HashMap<EventId, EpgProgram>) {
+        for section in self.sections.iter() {
+            if let Some(section) = section {
+                section.collect_programs(triple, programs)
+            }
+        }
+    }
+}
+
+// `version_number` is a 5-bit counter which wraps around from 31 to 0.  A
+// version is treated as newer when it's ahead of the other by 1 to 15 in modulo
+// 32 arithmetic.
+fn is_newer_version(version: u8, other: u8) -> bool {
+    let diff = version.wrapping_sub(other) & 0x1F;
+    diff > 0 && diff < 0x10
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EpgSection {
+    version: u8,
+    // Events are stored in chronological order.
+    events: Vec<EitEvent>,
+}
+
+impl EpgSection {
+    fn collect_overnight_events(
+        &self,
+        midnight: DateTime<Jst>,
+        mut events: Vec<EitEvent>
+    ) -> Vec<EitEvent> {
+        for event in self.events.iter() {
+            if event.is_overnight_event(midnight) {
+                events.push(event.clone());
+            }
+        }
+        events
+    }
+
+    fn collect_programs(
+        &self,
+        triple: ServiceTriple,
+        programs: &mut HashMap<EventId, EpgProgram>) {
+        for event in self.events.iter() {
+            let quad = EventQuad::from((triple, EventId::from(event.event_id)));
+            programs
+                .entry(event.event_id)
+                .or_insert(EpgProgram::new(quad))
+                .update(event);
+        }
+    }
+}
+
+impl From<EitSection> for EpgSection {
+    fn from(section: EitSection) -> Self {
+        EpgSection {
+            version: section.version_number,
+            events: section.events,
+        }
+    }
+}
+
+#[derive(Clone)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EpgStatus {
+    // See `Epg::ready`.
+    pub ready: bool,
+    // Stats of each channel in the last run of the update-schedules job.
+    pub eit_collection: Vec<EitCollectionStats>,
+    // Stats of each channel in the last run of the scan-services job.
+    pub service_scan: Vec<ServiceScanStats>,
+    // Services having sections which failed the CRC check.
+    pub invalid_sections: Vec<InvalidSectionStats>,
+}
+
+#[derive(Clone)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvalidSectionStats {
+    pub service_id: MirakurunServiceId,
+    pub num_sections: usize,
+}
+
+#[derive(Clone, PartialEq)]
+#[derive(Deserialize, Serialize)]
+pub struct EpgChannel {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub channel_type: ChannelType,
+    pub channel: String,
+    #[serde(default)]
+    pub extra_args: String,
+    pub services: Vec<ServiceId>,
+    pub excluded_services: Vec<ServiceId>,
+}
+
+impl fmt::Display for EpgChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.channel_type, self.channel)
+    }
+}
+
+impl From<ChannelConfig> for EpgChannel {
+    fn from(config: ChannelConfig) -> Self {
+        EpgChannel {
+            name: config.name,
+            channel_type: config.channel_type,
+            channel: config.channel,
+            extra_args: config.extra_args,
+            services: config.services,
+            excluded_services: config.excluded_services,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EpgService {
+    pub nid: NetworkId,
+    pub tsid: TransportStreamId,
+    pub sid: ServiceId,
+    #[serde(rename = "type")]
+    pub service_type: u16,
+    #[serde(default)]
+    pub logo_id: i16,
+    #[serde(default)]
+    pub remote_control_key_id: u16,
+    pub name: String,
+    // The network name in NIT, which is `None` if the service scanner doesn't
+    // output it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_name: Option<String>,
+    pub channel: EpgChannel,
+}
+
+impl EpgService {
+    pub fn triple(&self) -> ServiceTriple {
+        ServiceTriple::new(self.nid, self.tsid, self.sid)
+    }
+
+    fn is_exportable(&self) -> bool {
+        if !self.channel.services.is_empty() {
+            if !self.channel.services.contains(&self.sid) {
+                return false;
+            }
+        }
+        if self.channel.excluded_services.contains(&self.sid) {
+            return false;
+        }
+        true
+    }
+}
+
+impl Into<MirakurunChannelService> for EpgService {
+    fn into(self) -> MirakurunChannelService {
+        MirakurunChannelService {
+            id: self.triple().into(),
+            service_id: self.sid,
+            network_id: self.nid,
+            name: self.name,
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EpgNetwork {
+    pub network_id: NetworkId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+// Timestamps of changes of programs, which are used for delta queries.
+//
+// Changes are tracked only in memory.  All programs are treated as added at
+// startup.
+#[derive(Default)]
+struct EpgChangeLog {
+    modified: HashMap<EventQuad, DateTime<Jst>>,
+    removed: HashMap<EventQuad, DateTime<Jst>>,
+    // Removals before this time are unknown.
+    tracked_since: Option<DateTime<Jst>>,
+}
+
+impl EpgChangeLog {
+    // Records of removed programs are kept for a day.
+    const REMOVED_RETENTION_HOURS: i64 = 24;
+
+    fn update(
+        &mut self,
+        old_programs: &HashMap<EventId, EpgProgram>,
+        new_programs: &HashMap<EventId, EpgProgram>,
+        timestamp: DateTime<Jst>,
+    ) {
+        if self.tracked_since.is_none() {
+            self.tracked_since = Some(timestamp);
+        }
+        for (eid, program) in new_programs.iter() {
+            if old_programs.get(eid) != Some(program) {
+                self.modified.insert(program.quad, timestamp);
+                self.removed.remove(&program.quad);
+            }
+        }
+        for (eid, program) in old_programs.iter() {
+            if !new_programs.contains_key(eid) {
+                self.modified.remove(&program.quad);
+                self.removed.insert(program.quad, timestamp);
+            }
+        }
+    }
+
+    // Returns false if programs may have been removed after `since` without
+    // being recorded.
+    fn is_tracked_since(&self, since: DateTime<Jst>) -> bool {
+        self.tracked_since.map_or(true, |tracked_since| since >= tracked_since)
+    }
+
+    fn is_modified(&self, quad: &EventQuad, since: DateTime<Jst>) -> bool {
+        match self.modified.get(quad) {
+            Some(modified_at) => *modified_at > since,
+            None => false,
+        }
+    }
+
+    // Sorted by program IDs for stable results.
+    fn collect_removed(&self, since: DateTime<Jst>) -> Vec<EventQuad> {
+        let mut removed: Vec<EventQuad> = self.removed.iter()
+            .filter(|(_, removed_at)| **removed_at > since)
+            .map(|(quad, _)| *quad)
+            .collect();
+        removed.sort_by_key(|quad| MirakurunProgramId::from(*quad).value());
+        removed
+    }
+
+    fn purge(&mut self, timestamp: DateTime<Jst>) {
+        let expiry = timestamp - Duration::hours(Self::REMOVED_RETENTION_HOURS);
+        self.removed.retain(|_, removed_at| *removed_at >= expiry);
+        self.tracked_since = self.tracked_since
+            .map(|tracked_since| tracked_since.max(expiry));
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct EpgProgram {
+    pub quad: EventQuad,
+    pub start_at: DateTime<Jst>,
+    pub duration: Duration,
+    pub scrambled: bool,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub extended: Option<IndexMap<String, String>>,
+    pub videos: Vec<EpgVideoInfo>,  // in the order of component descriptors
+    pub audios: Vec<EpgAudioInfo>,  // in the order of component descriptors
+    pub genres: Option<Vec<EpgGenre>>,
+}
+
+impl EpgProgram {
+    pub fn new(quad: EventQuad) -> Self {
+        Self {
+            quad: quad,
+            start_at: Jst.timestamp(0, 0),
+            duration: Duration::minutes(0),
+            scrambled: false,
+            name: None,
+            description: None,
+            extended: None,
+            videos: Vec::new(),
+            audios: Vec::new(),
+            genres: None,
+        }
+    }
+
+    // The first component is treated as the primary component.
+    pub fn video(&self) -> Option<EpgVideoInfo> {
+        self.videos.first().cloned()
+    }
+
+    // The first component is treated as the primary component.
+    pub fn audio(&self) -> Option<EpgAudioInfo> {
+        self.audios.first().cloned()
+    }
+
+    fn end_at(&self) -> DateTime<Jst> {
+        self.start_at + self.duration
+    }
+
+    fn update(&mut self, event: &EitEvent) {
+        self.start_at = event.start_time.clone();
+        self.duration = event.duration.clone();
+        self.scrambled = event.scrambled;
+        let mut videos = Vec::new();
+        let mut audios = Vec::new();
+        for desc in event.descriptors.iter() {
+            match desc {
+                EitDescriptor::ShortEvent { event_name, text } => {
+                    self.name = Some(event_name.clone());
+                    self.description = Some(text.clone());
+                }
+                EitDescriptor::Component {
+                    stream_content, component_type, component_tag } => {
+                    videos.push(EpgVideoInfo::new(
+                        *stream_content, *component_type, *component_tag));
+                }
+                EitDescriptor::AudioComponent {
+                    component_type, sampling_rate, component_tag } => {
+                    audios.push(EpgAudioInfo::new(
+                        *component_type, *sampling_rate, *component_tag));
+                }
+                EitDescriptor::Content { nibbles } => {
+                    self.genres = Some(nibbles.iter()
+                                       .map(|nibble| EpgGenre::new(*nibble))
+                                       .collect());
+                }
+                EitDescriptor::ExtendedEvent { items } => {
+                    let mut map = IndexMap::new();
+                    map.extend(items.clone());
+                    self.extended = Some(map);
+                }
+                // Not used at this point.
+                EitDescriptor::EventGroup { .. } => (),
+            }
+        }
+        // Component descriptors may be contained only in some of sections.
+        if !videos.is_empty() {
+            self.videos = videos;
+        }
+        if !audios.is_empty() {
+            self.audios = audios;
+        }
+    }
+}
+
+// A rule for finding programs.  Conditions not specified are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct EpgProgramRule {
+    // Contained in the name or the description.
+    pub keyword: Option<String>,
+    // Matched with `lv1` of genres.
+    pub genre: Option<u8>,
+    pub service_id: Option<MirakurunServiceId>,
+    pub start_after: Option<DateTime<Jst>>,  // inclusive
+    pub start_before: Option<DateTime<Jst>>,  // exclusive
+}
+
+impl EpgProgramRule {
+    pub fn matches(&self, program: &EpgProgram) -> bool {
+        if let Some(ref keyword) = self.keyword {
+            let found = [&program.name, &program.description].iter()
+                .filter_map(|text| text.as_ref())
+                .any(|text| text.contains(keyword.as_str()));
+            if !found {
+                return false;
+            }
+        }
+        if let Some(genre) = self.genre {
+            let found = program.genres.iter()
+                .flatten()
+                .any(|g| g.lv1 == genre);
+            if !found {
+                return false;
+            }
+        }
+        if let Some(service_id) = self.service_id {
+            let triple = ServiceTriple::from(program.quad);
+            if MirakurunServiceId::from(triple) != service_id {
+                return false;
+            }
+        }
+        if let Some(start_after) = self.start_after {
+            if program.start_at < start_after {
+                return false;
+            }
+        }
+        if let Some(start_before) = self.start_before {
+            if program.start_at >= start_before {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Date, TimeZone};
+
+    #[test]
+    fn test_update_services() {
+        fn create_service(
+            name: &str, triple: ServiceTriple, channel: EpgChannel
+        ) -> EpgService {
+            EpgService {
+                nid: triple.nid(),
+                tsid: triple.tsid(),
+                sid: triple.sid(),
+                service_type: 1,
+                logo_id: 0,
+                remote_control_key_id: 0,
+                name: name.to_string(),
+                network_name: None,
+                channel,
+            }
+        }
+
+        let mut epg = Epg::new(Arc::new(Default::default()));
+
+        let ch1 = EpgChannel {
+            name: "ch1".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "1".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        };
+
+        let ch2 = EpgChannel {
+            name: "ch2".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "2".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        };
+
+        let triple1_1 = ServiceTriple::from((1, 1, 1));
+        let triple1_2 = ServiceTriple::from((1, 1, 2));
+        let triple2_3 = ServiceTriple::from((2, 1, 3));
+        let triple2_4 = ServiceTriple::from((2, 1, 4));
+
+        // initial update
+
+        let results = vec![
+            (
+                ch1.clone(),
+                Some(indexmap::indexmap!{
+                    triple1_1 =>
+                        create_service("sv1", triple1_1, ch1.clone()),
+                    triple1_2 =>
+                        create_service("sv2", triple1_2, ch1.clone()),
+                }),
+            ),
+            (
+                ch2.clone(),
+                Some(indexmap::indexmap!{
+                    triple2_3 =>
+                        create_service("sv3", triple2_3, ch2.clone()),
+                    triple2_4 =>
+                        create_service("sv4", triple2_4, ch2.clone()),
+                }),
+            ),
+        ];
+
+        epg.update_services(results);
+        {
+            let iter = epg.services.values().map(|sv| &sv.name);
+            assert!(iter.eq(["sv1", "sv2", "sv3", "sv4"].iter()));
+        }
+
+        // update with a complete list
+
+        let results = vec![
+            (
+                ch1.clone(),
+                Some(indexmap::indexmap!{
+                    triple1_1 =>
+                        create_service("sv1", triple1_1, ch1.clone()),
+                    triple1_2 =>
+                        create_service("sv2", triple1_2, ch1.clone()),
+                }),
+            ),
+            (
+                ch2.clone(),
+                Some(indexmap::indexmap!{
+                    triple2_3 =>
+                        create_service("sv3", triple2_3, ch2.clone()),
+                    triple2_4 =>
+                        create_service("sv4", triple2_4, ch2.clone()),
+                }),
+            ),
+        ];
+
+        epg.update_services(results);
+        {
+            let iter = epg.services.values().map(|sv| &sv.name);
+            assert!(iter.eq(["sv1", "sv2", "sv3", "sv4"].iter()));
+        }
+
+        // update with an incomplete list
+
+        let results = vec![
+            (ch1.clone(), None),
+            (
+                ch2.clone(),
+                Some(indexmap::indexmap!{
+                    triple2_3 =>
+                        create_service("sv3", triple2_3, ch2.clone()),
+                    triple2_4 =>
+                        create_service("sv4", triple2_4, ch2.clone()),
+                }),
+            ),
+        ];
+
+        epg.update_services(results);
+        {
+            let iter = epg.services.values().map(|sv| &sv.name);
+            assert!(iter.eq(["sv1", "sv2", "sv3", "sv4"].iter()));
+        }
+
+
+        // update with a new service
+
+        let results = vec![
+            (
+                ch1.clone(),
+                Some(indexmap::indexmap!{
+                    triple1_1 =>
+                        create_service("sv1.new", triple1_1, ch1.clone()),
+                }),
+            ),
+            (ch2.clone(), None),
+        ];
+
+        epg.update_services(results);
+        {
+            let iter = epg.services.values().map(|sv| &sv.name);
+            assert!(iter.eq(["sv1.new", "sv3", "sv4"].iter()));
+        }
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let config: Config = serde_yaml::from_str(r#"
+            channels:
+              - name: ch1
+                type: GR
+                channel: '1'
+              - name: ch2
+                type: GR
+                channel: '2'
+        "#).unwrap();
+        let mut epg = Epg::new(Arc::new(config.clone()));
+
+        let channels: Vec<EpgChannel> = config.channels.iter()
+            .cloned()
+            .map(EpgChannel::from)
+            .collect();
+        for (i, ch) in channels.iter().enumerate() {
+            let triple = ServiceTriple::from((1, 1, i as u16 + 1));
+            epg.services.insert(triple, EpgService {
+                nid: triple.nid(),
+                tsid: triple.tsid(),
+                sid: triple.sid(),
+                service_type: 1,
+                logo_id: 0,
+                remote_control_key_id: 0,
+                name: ch.name.clone(),
+                network_name: None,
+                channel: ch.clone(),
+            });
+        }
+
+        // Services in unchanged channels are kept.
+        epg.reload_config(Arc::new(config.clone()));
+        assert_eq!(epg.services.len(), 2);
+
+        // Services in a changed channel are dropped.
+        let mut new_config = config.clone();
+        new_config.channels[1].extra_args = "--lnb 15".to_string();
+        epg.reload_config(Arc::new(new_config.clone()));
+        {
+            let iter = epg.services.values().map(|sv| &sv.name);
+            assert!(iter.eq(["ch1"].iter()));
+        }
+
+        // Services in a disabled channel are dropped.
+        new_config.channels[0].disabled = true;
+        epg.reload_config(Arc::new(new_config));
+        assert!(epg.services.is_empty());
+    }
+
+    #[test]
+    fn test_merge_clocks() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
+
+        let mut channels = Vec::new();
+        for &(sid, channel) in [(1, "1"), (2, "2"), (3, "3")].iter() {
+            let ch = EpgChannel {
+                name: channel.to_string(),
+                channel_type: ChannelType::GR,
+                channel: channel.to_string(),
+                extra_args: "".to_string(),
+                services: vec![],
+                excluded_services: vec![],
+            };
+            let triple = ServiceTriple::from((1, 1, sid));
+            epg.services.insert(triple, EpgService {
+                nid: triple.nid(),
+                tsid: triple.tsid(),
+                sid: triple.sid(),
+                service_type: 1,
+                logo_id: 0,
+                remote_control_key_id: 0,
+                name: "sv".to_string(),
+                network_name: None,
+                channel: ch.clone(),
+            });
+            epg.clocks.insert(
+                triple, Clock { pcr: 0, time: 0, synced_at: None });
+            channels.push(ch);
+        }
+
+        let triple = ServiceTriple::from((1, 1, 1));
+        let results = vec![
+            (
+                channels[0].clone(),
+                Some(vec![
+                    (triple, Clock { pcr: 1, time: 1, synced_at: None }),
+                ].into_iter().collect()),
+            ),
+            (channels[1].clone(), None),
+        ];
+
+        epg.merge_clocks(results);
+        assert_eq!(epg.clocks.len(), 3);
+        assert_eq!(epg.clocks[&ServiceTriple::from((1, 1, 1))].pcr, 1);
+        // Failed to synchronize.
+        assert_eq!(epg.clocks[&ServiceTriple::from((1, 1, 2))].pcr, 0);
+        // Not contained in the results.
+        assert_eq!(epg.clocks[&ServiceTriple::from((1, 1, 3))].pcr, 0);
+    }
+
+    #[test]
+    fn test_collect_clock_offsets() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
+        assert!(epg.collect_clock_offsets().is_empty());
+
+        epg.clocks.insert((2, 1, 1).into(), Clock {
+            pcr: 0, time: 2_000, synced_at: Some(1_000),
+        });
+        epg.clocks.insert((1, 1, 1).into(), Clock {
+            pcr: 0, time: 1_000, synced_at: Some(1_500),
+        });
+        // The latest clock in the network is used.
+        epg.clocks.insert((1, 1, 2).into(), Clock {
+            pcr: 0, time: 3_000, synced_at: Some(3_200),
+        });
+        // Clocks loaded from an old cache are ignored.
+        epg.clocks.insert((3, 1, 1).into(), Clock {
+            pcr: 0, time: 0, synced_at: None,
+        });
+
+        assert_eq!(epg.collect_clock_offsets(), vec![
+            ClockOffset { nid: 1.into(), synced_at: 3_200, offset: -200 },
+            ClockOffset { nid: 2.into(), synced_at: 1_000, offset: 1_000 },
+        ]);
+    }
+
+    #[test]
+    fn test_find_service() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
+
+        let ch = EpgChannel {
+            name: "ch".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "1".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        };
+
+        for &triple in [(1, 1, 1), (1, 1, 2), (1, 2, 2)].iter() {
+            let triple = ServiceTriple::from(triple);
+            epg.services.insert(triple, EpgService {
+                nid: triple.nid(),
+                tsid: triple.tsid(),
+                sid: triple.sid(),
+                service_type: 1,
+                logo_id: 0,
+                remote_control_key_id: 0,
+                name: "sv".to_string(),
+                network_name: None,
+                channel: ch.clone(),
+            });
+        }
+
+        let result = epg.find_service(1.into(), 1.into());
+        assert!(result.is_ok());
+        assert!(result.unwrap().triple() == ServiceTriple::from((1, 1, 1)));
+
+        // Ambiguous.
+        let result = epg.find_service(1.into(), 2.into());
+        assert!(matches!(result, Err(Error::BadRequest(_))));
+
+        let result = epg.find_service(1.into(), 3.into());
+        assert!(matches!(result, Err(Error::ServiceNotFound)));
+    }
+
+    #[test]
+    fn test_find_program() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
+
+        let ch = EpgChannel {
+            name: "ch".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "1".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        };
+
+        // Services having the same NID and SID.
+        for &(triple, eids) in [
+            ((1, 1, 2), &[1u16][..]),
+            ((1, 2, 2), &[1u16, 2][..]),
+        ].iter() {
+            let triple = ServiceTriple::from(triple);
+            epg.services.insert(triple, EpgService {
+                nid: triple.nid(),
+                tsid: triple.tsid(),
+                sid: triple.sid(),
+                service_type: 1,
+                logo_id: 0,
+                remote_control_key_id: 0,
+                name: "sv".to_string(),
+                network_name: None,
+                channel: ch.clone(),
+            });
+            let mut schedule = EpgSchedule::new(triple);
+            for &eid in eids.iter() {
+                let quad = EventQuad::from((triple, EventId::from(eid)));
+                schedule.programs.insert(eid.into(), EpgProgram::new(quad));
+            }
+            epg.schedules.insert(triple, schedule);
+        }
+
+        // Ambiguous, the first one is used.
+        let result = epg.find_program(1.into(), 2.into(), 1.into());
+        assert_eq!(result.ok().map(|program| program.quad),
+                   Some(EventQuad::new(
+                       1.into(), 1.into(), 2.into(), 1.into())));
+
+        let result = epg.find_program(1.into(), 2.into(), 2.into());
+        assert_eq!(result.ok().map(|program| program.quad),
+                   Some(EventQuad::new(
+                       1.into(), 2.into(), 2.into(), 2.into())));
+
+        let result = epg.find_program(1.into(), 2.into(), 3.into());
+        assert!(matches!(result, Err(Error::ProgramNotFound)));
+    }
+
+    #[test]
+    fn test_collect_networks() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
+
+        let ch = EpgChannel {
+            name: "ch".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "1".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        };
+
+        let services = [
+            ((2, 1, 1), None),
+            ((2, 1, 2), Some("network2")),
+            ((1, 1, 1), Some("network1")),
+            ((3, 1, 1), None),
+        ];
+        for &(triple, network_name) in services.iter() {
+            let triple = ServiceTriple::from(triple);
+            epg.services.insert(triple, EpgService {
+                nid: triple.nid(),
+                tsid: triple.tsid(),
+                sid: triple.sid(),
+                service_type: 1,
+                logo_id: 0,
+                remote_control_key_id: 0,
+                name: "sv".to_string(),
+                network_name: network_name.map(|name| name.to_string()),
+                channel: ch.clone(),
+            });
+        }
+
+        let networks = epg.collect_networks();
+        assert_eq!(networks.len(), 3);
+        assert_eq!(networks[0].network_id, 2.into());
+        assert_eq!(networks[0].name, Some("network2".to_string()));
+        assert_eq!(networks[1].network_id, 1.into());
+        assert_eq!(networks[1].name, Some("network1".to_string()));
+        assert_eq!(networks[2].network_id, 3.into());
+        assert_eq!(networks[2].name, None);
+    }
+
+    #[test]
+    fn test_import_xmltv_programmes() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
+
+        let ch = EpgChannel {
+            name: "ch".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "1".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        };
+
+        for &triple in [(1, 1, 1), (1, 1, 2)].iter() {
+            let triple = ServiceTriple::from(triple);
+            epg.services.insert(triple, EpgService {
+                nid: triple.nid(),
+                tsid: triple.tsid(),
+                sid: triple.sid(),
+                service_type: 1,
+                logo_id: 0,
+                remote_control_key_id: 0,
+                name: "sv".to_string(),
+                network_name: None,
+                channel: ch.clone(),
+            });
+        }
+
+        // The service (1, 1, 2) already has a collected program.
+        let triple = ServiceTriple::from((1, 1, 2));
+        let mut schedule = EpgSchedule::new(triple);
+        schedule.programs.insert(
+            10.into(), EpgProgram::new((triple, EventId::from(10)).into()));
+        epg.schedules.insert(triple, schedule);
+
+        let channels = vec![
+            ("ch1".to_string(), MirakurunServiceId::from(100001)),
+            ("ch2".to_string(), MirakurunServiceId::from(100002)),
+            ("ch3".to_string(), MirakurunServiceId::from(100003)),
+        ].into_iter().collect();
+
+        let start = Jst.ymd(2020, 1, 1).and_hms(12, 0, 0);
+        let mut programmes: Vec<XmltvProgramme> =
+            ["ch1", "ch1", "ch2", "ch3", "ch4"].iter()
+                .map(|channel| XmltvProgramme {
+                    channel: channel.to_string(),
+                    start,
+                    stop: start + Duration::minutes(30),
+                    title: Some("title".to_string()),
+                    desc: None,
+                })
+                .collect();
+
+        programmes.push(XmltvProgramme {
+            channel: "ch1".to_string(),
+            start,
+            stop: start - Duration::minutes(1),
+            title: Some("negative duration".to_string()),
+            desc: None,
+        });
+
+        let num_programs = epg.import_xmltv_programmes(&channels, programmes);
+        assert_eq!(num_programs, 2);
+        // ch3 is not mapped to any service.
+        assert!(epg.xmltv_pending);
+
+        let triple = ServiceTriple::from((1, 1, 1));
+        let programs = &epg.schedules[&triple].programs;
+        assert_eq!(programs.len(), 2);
+        assert!(programs.contains_key(&EventId::from(0xF001)));
+        let program = &programs[&EventId::from(0xF000)];
+        assert_eq!(program.start_at, start);
+        assert_eq!(program.duration, Duration::minutes(30));
+        assert_eq!(program.name, Some("title".to_string()));
+
+        let triple = ServiceTriple::from((1, 1, 2));
+        let programs = &epg.schedules[&triple].programs;
+        assert_eq!(programs.len(), 1);
+        assert!(programs.contains_key(&EventId::from(10)));
+
+        // Programs are imported only once for each service.
+        let programmes = vec![XmltvProgramme {
+            channel: "ch1".to_string(),
+            start,
+            stop: start + Duration::minutes(30),
+            title: None,
+            desc: None,
+        }];
+        let num_programs = epg.import_xmltv_programmes(&channels, programmes);
+        assert_eq!(num_programs, 0);
+    }
+
+    #[test]
+    fn test_next_xmltv_eid() {
+        let triple = ServiceTriple::from((1, 1, 1));
+        let mut schedule = EpgSchedule::new(triple);
+        // Use all IDs except for the first 3 IDs in the range so that the
+        // search starts near the end of the range.
+        for eid in 0xF003..=0xFFFEu16 {
+            let eid = EventId::from(eid);
+            schedule.programs.insert(
+                eid, EpgProgram::new(EventQuad::from((triple, eid))));
+        }
+
+        let mut assigned = Vec::new();
+        while let Some(eid) = Epg::next_xmltv_eid(&schedule) {
+            assert!(eid.value() >= 0xF000);
+            assert!(eid.value() != 0xFFFF);
+            assert!(!schedule.programs.contains_key(&eid));
+            schedule.programs.insert(
+                eid, EpgProgram::new(EventQuad::from((triple, eid))));
+            assigned.push(eid.value());
+        }
+        // Wrapped around to the beginning of the range.
+        assert_eq!(assigned, vec![0xF000, 0xF001, 0xF002]);
+        assert_eq!(schedule.programs.len(), 0xFFF);
+    }
+
+    #[test]
+    fn test_import_xmltv_programmes_before_scan() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
+
+        let channels = vec![
+            ("ch1".to_string(), MirakurunServiceId::from(100001)),
+        ].into_iter().collect();
+
+        let start = Jst.ymd(2020, 1, 1).and_hms(12, 0, 0);
+        let programme = XmltvProgramme {
+            channel: "ch1".to_string(),
+            start,
+            stop: start + Duration::minutes(30),
+            title: None,
+            desc: None,
+        };
+
+        // No service has been scanned yet.
+        let num_programs = epg.import_xmltv_programmes(
+            &channels, vec![programme.clone()]);
+        assert_eq!(num_programs, 0);
+        assert!(epg.xmltv_pending);
+
+        let ch = EpgChannel {
+            name: "ch".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "1".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        };
+        let triple = ServiceTriple::from((1, 1, 1));
+        epg.services.insert(triple, EpgService {
+            nid: triple.nid(),
+            tsid: triple.tsid(),
+            sid: triple.sid(),
+            service_type: 1,
+            logo_id: 0,
+            remote_control_key_id: 0,
+            name: "sv".to_string(),
+            network_name: None,
+            channel: ch,
+        });
+
+        let num_programs = epg.import_xmltv_programmes(
+            &channels, vec![programme]);
+        assert_eq!(num_programs, 1);
+        assert!(!epg.xmltv_pending);
+    }
+
+    #[test]
+    fn test_collect_all_programs() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
+
+        for &sid in [3, 1, 2].iter() {
+            let triple = ServiceTriple::from((1, 2, sid));
+            let mut schedule = EpgSchedule::new(triple);
+            for &eid in [3, 1, 2].iter() {
+                let program =
+                    EpgProgram::new((triple, EventId::from(eid)).into());
+                schedule.programs.insert(eid.into(), program);
+            }
+            epg.schedules.insert(triple, schedule);
+        }
+
+        let ids: Vec<(u16, u16)> = epg.collect_all_programs().iter()
+            .map(|program| {
+                (program.quad.sid().value(), program.quad.eid().value())
+            })
+            .collect();
+        assert_eq!(ids, vec![
+            (1, 1), (1, 2), (1, 3),
+            (2, 1), (2, 2), (2, 3),
+            (3, 1), (3, 2), (3, 3),
+        ]);
+    }
 
     #[test]
-    fn test_update_services() {
-        fn create_service(
-            name: &str, triple: ServiceTriple, channel: EpgChannel
-        ) -> EpgService {
-            EpgService {
-                nid: triple.nid(),
-                tsid: triple.tsid(),
-                sid: triple.sid(),
-                service_type: 1,
-                logo_id: 0,
-                remote_control_key_id: 0,
-                name: name.to_string(),
-                channel,
+    fn test_find_next_program() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
+
+        let now = Jst.ymd(2020, 1, 1).and_hms(12, 0, 0);
+        let triple = ServiceTriple::from((1, 2, 3));
+        let mut schedule = EpgSchedule::new(triple);
+        for &(eid, hours, name) in [
+            (1, -1, "news"), (2, 1, "drama"), (3, 2, "news"), (4, 3, "news"),
+        ].iter() {
+            let mut program =
+                EpgProgram::new((triple, EventId::from(eid)).into());
+            program.start_at = now + Duration::hours(hours);
+            program.duration = Duration::minutes(30);
+            program.name = Some(name.to_string());
+            if eid == 4 {
+                program.genres = Some(vec![EpgGenre::new((7, 0, 0, 0))]);
             }
+            schedule.programs.insert(eid.into(), program);
         }
+        epg.schedules.insert(triple, schedule);
 
+        let find = |rule: EpgProgramRule| {
+            epg.find_next_program(&rule, now)
+                .map(|program| program.quad.eid().value())
+        };
+
+        assert_eq!(find(Default::default()), Some(2));
+        assert_eq!(find(EpgProgramRule {
+            keyword: Some("news".to_string()),
+            ..Default::default()
+        }), Some(3));
+        assert_eq!(find(EpgProgramRule {
+            genre: Some(7),
+            ..Default::default()
+        }), Some(4));
+        assert_eq!(find(EpgProgramRule {
+            keyword: Some("news".to_string()),
+            start_after: Some(now + Duration::hours(3)),
+            ..Default::default()
+        }), Some(4));
+        assert_eq!(find(EpgProgramRule {
+            keyword: Some("news".to_string()),
+            start_before: Some(now + Duration::hours(2)),
+            ..Default::default()
+        }), None);
+        assert_eq!(find(EpgProgramRule {
+            service_id: Some(MirakurunServiceId::from(ServiceTriple::from(
+                (1, 2, 4)))),
+            ..Default::default()
+        }), None);
+        assert_eq!(find(EpgProgramRule {
+            keyword: Some("sports".to_string()),
+            ..Default::default()
+        }), None);
+    }
+
+    #[test]
+    fn test_search_programs() {
         let mut epg = Epg::new(Arc::new(Default::default()));
 
-        let ch1 = EpgChannel {
-            name: "ch1".to_string(),
-            channel_type: ChannelType::GR,
-            channel: "1".to_string(),
-            extra_args: "".to_string(),
-            services: vec![],
-            excluded_services: vec![],
-        };
+        let now = Jst.ymd(2020, 1, 1).and_hms(12, 0, 0);
+        for &(sid, eid, hours, name, genre) in [
+            (1, 1, 2, "news", 1), (1, 2, 0, "drama", 3), (2, 1, 1, "news", 1),
+            (2, 2, 0, "sports", 2), (3, 1, 0, "news", 1),
+        ].iter() {
+            let triple = ServiceTriple::from((1, 2, sid));
+            let mut program =
+                EpgProgram::new((triple, EventId::from(eid)).into());
+            program.start_at = now + Duration::hours(hours);
+            program.duration = Duration::minutes(30);
+            program.name = Some(name.to_string());
+            program.description = Some(format!("{} description", name));
+            program.genres = Some(vec![EpgGenre::new((genre, 0, 0, 0))]);
+            epg.schedules.entry(triple)
+                .or_insert_with(|| EpgSchedule::new(triple))
+                .programs.insert(eid.into(), program);
+        }
 
-        let ch2 = EpgChannel {
-            name: "ch2".to_string(),
-            channel_type: ChannelType::GR,
-            channel: "2".to_string(),
-            extra_args: "".to_string(),
-            services: vec![],
-            excluded_services: vec![],
+        let search = |rule: EpgProgramRule, offset, limit| {
+            epg.search_programs(&rule, offset, limit).iter()
+                .map(|program| {
+                    (program.quad.sid().value(), program.quad.eid().value())
+                })
+                .collect::<Vec<(u16, u16)>>()
         };
 
-        let triple1_1 = ServiceTriple::from((1, 1, 1));
-        let triple1_2 = ServiceTriple::from((1, 1, 2));
-        let triple2_3 = ServiceTriple::from((2, 1, 3));
-        let triple2_4 = ServiceTriple::from((2, 1, 4));
-
-        // initial update
+        assert_eq!(search(Default::default(), 0, 10),
+                   vec![(1, 2), (2, 2), (3, 1), (2, 1), (1, 1)]);
+        assert_eq!(search(Default::default(), 1, 2), vec![(2, 2), (3, 1)]);
+        assert_eq!(search(Default::default(), 5, 10), vec![]);
+        assert_eq!(search(EpgProgramRule {
+            keyword: Some("news".to_string()),
+            ..Default::default()
+        }, 0, 10), vec![(3, 1), (2, 1), (1, 1)]);
+        assert_eq!(search(EpgProgramRule {
+            keyword: Some("drama desc".to_string()),
+            ..Default::default()
+        }, 0, 10), vec![(1, 2)]);
+        assert_eq!(search(EpgProgramRule {
+            genre: Some(2),
+            ..Default::default()
+        }, 0, 10), vec![(2, 2)]);
+        assert_eq!(search(EpgProgramRule {
+            keyword: Some("news".to_string()),
+            start_after: Some(now + Duration::hours(1)),
+            start_before: Some(now + Duration::hours(2)),
+            ..Default::default()
+        }, 0, 10), vec![(2, 1)]);
+    }
 
-        let results = vec![
-            (
-                ch1.clone(),
-                Some(indexmap::indexmap!{
-                    triple1_1 =>
-                        create_service("sv1", triple1_1, ch1.clone()),
-                    triple1_2 =>
-                        create_service("sv2", triple1_2, ch1.clone()),
-                }),
-            ),
-            (
-                ch2.clone(),
-                Some(indexmap::indexmap!{
-                    triple2_3 =>
-                        create_service("sv3", triple2_3, ch2.clone()),
-                    triple2_4 =>
-                        create_service("sv4", triple2_4, ch2.clone()),
-                }),
-            ),
-        ];
+    #[test]
+    fn test_find_onair_programs() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
 
-        epg.update_services(results);
-        {
-            let iter = epg.services.values().map(|sv| &sv.name);
-            assert!(iter.eq(["sv1", "sv2", "sv3", "sv4"].iter()));
+        let now = Jst.ymd(2020, 1, 1).and_hms(12, 0, 0);
+        for &(sid, eid, minutes, genre) in [
+            (1, 1, -30, 1), (1, 2, 0, 1), (2, 1, -60, 1), (2, 2, -10, 2),
+            (3, 1, 10, 1),
+        ].iter() {
+            let triple = ServiceTriple::from((1, 2, sid));
+            let mut program =
+                EpgProgram::new((triple, EventId::from(eid)).into());
+            program.start_at = now + Duration::minutes(minutes);
+            program.duration = Duration::minutes(30);
+            program.genres = Some(vec![EpgGenre::new((genre, 0, 0, 0))]);
+            epg.schedules.entry(triple)
+                .or_insert_with(|| EpgSchedule::new(triple))
+                .programs.insert(eid.into(), program);
         }
 
-        // update with a complete list
+        let find = |genre| {
+            epg.find_onair_programs(genre, now).iter()
+                .map(|program| {
+                    (program.quad.sid().value(), program.quad.eid().value())
+                })
+                .collect::<Vec<(u16, u16)>>()
+        };
 
-        let results = vec![
-            (
-                ch1.clone(),
-                Some(indexmap::indexmap!{
-                    triple1_1 =>
-                        create_service("sv1", triple1_1, ch1.clone()),
-                    triple1_2 =>
-                        create_service("sv2", triple1_2, ch1.clone()),
-                }),
-            ),
-            (
-                ch2.clone(),
-                Some(indexmap::indexmap!{
-                    triple2_3 =>
-                        create_service("sv3", triple2_3, ch2.clone()),
-                    triple2_4 =>
-                        create_service("sv4", triple2_4, ch2.clone()),
-                }),
-            ),
-        ];
+        assert_eq!(find(None), vec![(1, 2), (2, 2)]);
+        assert_eq!(find(Some(1)), vec![(1, 2)]);
+        assert_eq!(find(Some(2)), vec![(2, 2)]);
+        assert!(find(Some(3)).is_empty());
+    }
 
-        epg.update_services(results);
-        {
-            let iter = epg.services.values().map(|sv| &sv.name);
-            assert!(iter.eq(["sv1", "sv2", "sv3", "sv4"].iter()));
+    #[test]
+    fn test_find_programs_at() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
+
+        // 20:00 tonight.
+        let at = Jst.ymd(2020, 1, 1).and_hms(20, 0, 0);
+        for &(sid, eid, minutes, duration) in [
+            (1, 1, -30, 30), (1, 2, 0, 60),
+            // Overlapping programs.
+            (2, 1, -60, 120), (2, 2, -10, 30),
+            (3, 1, 10, 30),
+            // An overnight event started in the previous day.
+            (4, 1, -21 * 60, 24 * 60),
+        ].iter() {
+            let triple = ServiceTriple::from((1, 2, sid));
+            let mut program =
+                EpgProgram::new((triple, EventId::from(eid)).into());
+            program.start_at = at + Duration::minutes(minutes);
+            program.duration = Duration::minutes(duration);
+            epg.schedules.entry(triple)
+                .or_insert_with(|| EpgSchedule::new(triple))
+                .programs.insert(eid.into(), program);
         }
 
-        // update with an incomplete list
+        let find = |at| {
+            epg.find_programs_at(at).iter()
+                .map(|program| {
+                    (program.quad.sid().value(), program.quad.eid().value())
+                })
+                .collect::<Vec<(u16, u16)>>()
+        };
 
-        let results = vec![
-            (ch1.clone(), None),
-            (
-                ch2.clone(),
-                Some(indexmap::indexmap!{
-                    triple2_3 =>
-                        create_service("sv3", triple2_3, ch2.clone()),
-                    triple2_4 =>
-                        create_service("sv4", triple2_4, ch2.clone()),
-                }),
-            ),
-        ];
+        assert_eq!(find(at), vec![(1, 2), (2, 2), (4, 1)]);
+        assert_eq!(find(at + Duration::minutes(30)),
+                   vec![(1, 2), (2, 1), (3, 1), (4, 1)]);
+        assert_eq!(find(at - Duration::days(1)), vec![]);
+    }
 
-        epg.update_services(results);
-        {
-            let iter = epg.services.values().map(|sv| &sv.name);
-            assert!(iter.eq(["sv1", "sv2", "sv3", "sv4"].iter()));
+    #[test]
+    fn test_collect_guide() {
+        let mut epg = Epg::new(Arc::new(Default::default()));
+
+        let midnight = Jst.ymd(2020, 1, 1).and_hms(0, 0, 0);
+        // The 4th service is not exportable.
+        for sid in 1..=4 {
+            let triple = ServiceTriple::from((1, 2, sid));
+            epg.services.insert(triple, EpgService {
+                nid: triple.nid(),
+                tsid: triple.tsid(),
+                sid: triple.sid(),
+                service_type: 1,
+                logo_id: 0,
+                remote_control_key_id: 0,
+                name: "sv".to_string(),
+                network_name: None,
+                channel: EpgChannel {
+                    name: "ch".to_string(),
+                    channel_type: ChannelType::GR,
+                    channel: "ch".to_string(),
+                    extra_args: "".to_string(),
+                    services: vec![],
+                    excluded_services: vec![4.into()],
+                },
+            });
+        }
+        for &(sid, eid, minutes, duration) in [
+            // An overnight event started in the previous day.
+            (1, 1, -30, 60), (1, 2, 30, 60),
+            // Ended at the midnight.
+            (2, 1, -60, 60),
+            (2, 2, 23 * 60, 60),
+            // Started in the next day.
+            (2, 3, 24 * 60, 60),
+        ].iter() {
+            let triple = ServiceTriple::from((1, 2, sid));
+            let mut program =
+                EpgProgram::new((triple, EventId::from(eid)).into());
+            program.start_at = midnight + Duration::minutes(minutes);
+            program.duration = Duration::minutes(duration);
+            epg.schedules.entry(triple)
+                .or_insert_with(|| EpgSchedule::new(triple))
+                .programs.insert(eid.into(), program);
         }
 
+        let collect = |services: Option<&[MirakurunServiceId]>,
+                       offset: usize,
+                       limit: usize| {
+            epg.collect_guide(midnight, services, offset, limit).iter()
+                .map(|(sv, programs)| {
+                    (sv.sid.value(), programs.iter()
+                     .map(|program| program.quad.eid().value())
+                     .collect::<Vec<u16>>())
+                })
+                .collect::<Vec<(u16, Vec<u16>)>>()
+        };
 
-        // update with a new service
+        assert_eq!(collect(None, 0, 10),
+                   vec![(1, vec![1, 2]), (2, vec![2]), (3, vec![])]);
+        let services = [MirakurunServiceId::new(1.into(), 2.into())];
+        assert_eq!(collect(Some(&services), 0, 10), vec![(2, vec![2])]);
 
-        let results = vec![
-            (
-                ch1.clone(),
-                Some(indexmap::indexmap!{
-                    triple1_1 =>
-                        create_service("sv1.new", triple1_1, ch1.clone()),
-                }),
-            ),
-            (ch2.clone(), None),
-        ];
+        // Paging.
+        assert_eq!(collect(None, 0, 2), vec![(1, vec![1, 2]), (2, vec![2])]);
+        assert_eq!(collect(None, 2, 2), vec![(3, vec![])]);
+        assert_eq!(collect(None, 3, 2), vec![]);
+    }
 
-        epg.update_services(results);
-        {
-            let iter = epg.services.values().map(|sv| &sv.name);
-            assert!(iter.eq(["sv1.new", "sv3", "sv4"].iter()));
-        }
+    #[test]
+    fn test_epg_change_log() {
+        let mut changes = EpgChangeLog::default();
+
+        let triple = ServiceTriple::from((1, 2, 3));
+        let create_programs = |names: &[(u16, &str)]| {
+            names.iter()
+                .map(|&(eid, name)| {
+                    let mut program =
+                        EpgProgram::new((triple, EventId::from(eid)).into());
+                    program.name = Some(name.to_string());
+                    (EventId::from(eid), program)
+                })
+                .collect::<HashMap<EventId, EpgProgram>>()
+        };
+        let collect_modified = |changes: &EpgChangeLog, since| {
+            let mut eids: Vec<u16> = [1, 2, 3].iter()
+                .map(|&eid| EventQuad::from((triple, EventId::from(eid))))
+                .filter(|quad| changes.is_modified(quad, since))
+                .map(|quad| quad.eid().value())
+                .collect();
+            eids.sort();
+            eids
+        };
+        let collect_removed = |changes: &EpgChangeLog, since| {
+            changes.collect_removed(since).iter()
+                .map(|quad| quad.eid().value())
+                .collect::<Vec<u16>>()
+        };
+
+        let t0 = Jst.ymd(2020, 1, 1).and_hms(0, 0, 0);
+        let t1 = t0 + Duration::minutes(10);
+        let t2 = t1 + Duration::minutes(10);
+
+        assert!(changes.is_tracked_since(t0));
+
+        let programs1 = create_programs(&[(1, "a"), (2, "b")]);
+        changes.update(&HashMap::new(), &programs1, t1);
+        // Removals before the first update are unknown.
+        assert!(!changes.is_tracked_since(t0));
+        assert!(changes.is_tracked_since(t1));
+        assert_eq!(collect_modified(&changes, t0), vec![1, 2]);
+        assert!(collect_modified(&changes, t1).is_empty());
+
+        // The program 1 is unchanged, the program 2 is modified, the program 3
+        // is added.
+        let programs2 = create_programs(&[(1, "a"), (2, "c"), (3, "d")]);
+        changes.update(&programs1, &programs2, t2);
+        assert_eq!(collect_modified(&changes, t0), vec![1, 2, 3]);
+        assert_eq!(collect_modified(&changes, t1), vec![2, 3]);
+        assert!(collect_removed(&changes, t0).is_empty());
+
+        let programs3 = create_programs(&[(3, "d")]);
+        changes.update(&programs2, &programs3, t2 + Duration::minutes(10));
+        assert_eq!(collect_modified(&changes, t0), vec![3]);
+        assert_eq!(collect_removed(&changes, t2), vec![1, 2]);
+
+        // Records of removed programs are purged after the retention period.
+        changes.purge(t2 + Duration::hours(24));
+        assert_eq!(collect_removed(&changes, t0), vec![1, 2]);
+        assert!(changes.is_tracked_since(t1));
+        changes.purge(t2 + Duration::hours(25));
+        assert!(collect_removed(&changes, t0).is_empty());
+        assert!(!changes.is_tracked_since(t2));
+        assert!(changes.is_tracked_since(t2 + Duration::hours(1)));
     }
 
     #[test]
@@ -1395,6 +3584,241 @@ mod tests {
         assert_eq!(epg.schedules[&triple].overnight_events.len(), 0);
     }
 
+    #[test]
+    fn test_epg_flush_schedules_interleaved() {
+        let triple_a = ServiceTriple::from((1, 2, 3));
+        let triple_b = ServiceTriple::from((1, 2, 4));
+
+        let mut epg = Epg::new(Arc::new(Default::default()));
+        epg.services.insert(
+            triple_a, create_epg_service(triple_a, ChannelType::GR));
+        epg.services.insert(
+            triple_b, create_epg_service(triple_b, ChannelType::GR));
+
+        epg.update_schedules(vec![create_eit_section(triple_a, 1)]);
+        epg.update_schedules(vec![create_eit_section(triple_b, 2)]);
+
+        epg.flush_schedules(vec![triple_a]);
+        assert!(epg.schedules[&triple_a].programs.contains_key(&1.into()));
+        assert!(epg.schedules[&triple_b].programs.is_empty());
+
+        epg.update_schedules(vec![create_eit_section(triple_a, 3)]);
+
+        epg.flush_schedules(vec![triple_b]);
+        assert_eq!(epg.schedules[&triple_a].programs.len(), 1);
+        assert!(epg.schedules[&triple_a].programs.contains_key(&1.into()));
+        assert_eq!(epg.schedules[&triple_b].programs.len(), 1);
+        assert!(epg.schedules[&triple_b].programs.contains_key(&2.into()));
+
+        epg.flush_schedules(vec![triple_a]);
+        assert_eq!(epg.schedules[&triple_a].programs.len(), 1);
+        assert!(epg.schedules[&triple_a].programs.contains_key(&3.into()));
+        assert_eq!(epg.schedules[&triple_b].programs.len(), 1);
+        assert!(epg.schedules[&triple_b].programs.contains_key(&2.into()));
+
+        // Flushing for an unknown service must not affect others.
+        epg.flush_schedules(vec![ServiceTriple::from((9, 9, 9))]);
+        assert_eq!(epg.schedules[&triple_a].programs.len(), 1);
+        assert_eq!(epg.schedules[&triple_b].programs.len(), 1);
+    }
+
+    #[test]
+    fn test_epg_remove_stale_sections() {
+        let triple_a = ServiceTriple::from((1, 2, 3));
+        let triple_b = ServiceTriple::from((1, 2, 4));
+
+        let mut section_a_31 = create_eit_section(triple_a, 1);
+        section_a_31.version_number = 31;
+        let mut section_a_0 = create_eit_section(triple_a, 2);
+        section_a_0.version_number = 0;
+        let mut section_b_31 = create_eit_section(triple_b, 3);
+        section_b_31.version_number = 31;
+        let mut section_a_1 = create_eit_section(triple_a, 4);
+        section_a_1.version_number = 1;
+        section_a_1.section_number = 0x01;
+        section_a_1.segment_last_section_number = 0x01;
+
+        // Version 0 is newer than version 31 after the rollover.
+        let sections = Epg::remove_stale_sections(vec![
+            section_a_0, section_a_31, section_b_31, section_a_1]);
+        let eids: Vec<u16> = sections.iter()
+            .map(|section| section.events[0].event_id.value())
+            .collect();
+        assert_eq!(eids, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_epg_update_schedules_version_rollover() {
+        let triple = ServiceTriple::from((1, 2, 3));
+
+        let mut epg = Epg::new(Arc::new(Default::default()));
+        epg.services.insert(
+            triple, create_epg_service(triple, ChannelType::GR));
+
+        let mut section_31 = create_eit_section(triple, 1);
+        section_31.version_number = 31;
+        let mut section_0 = create_eit_section(triple, 2);
+        section_0.version_number = 0;
+
+        epg.update_schedules(vec![section_0, section_31]);
+        epg.flush_schedules(vec![triple]);
+        assert_eq!(epg.schedules[&triple].programs.len(), 1);
+        assert!(epg.schedules[&triple].programs.contains_key(&2.into()));
+
+        // A section in a later batch always replaces the stored one.
+        let mut section_31 = create_eit_section(triple, 3);
+        section_31.version_number = 31;
+        epg.update_schedules(vec![section_31]);
+        epg.flush_schedules(vec![triple]);
+        assert_eq!(epg.schedules[&triple].programs.len(), 1);
+        assert!(epg.schedules[&triple].programs.contains_key(&3.into()));
+    }
+
+    #[test]
+    fn test_epg_update_schedules_invalid_sections() {
+        let triple = ServiceTriple::from((1, 2, 3));
+
+        let create_sections = || {
+            let mut invalid = create_eit_section(triple, 1);
+            invalid.crc_valid = Some(false);
+            let mut valid = create_eit_section(triple, 2);
+            valid.section_number = 0x01;
+            valid.segment_last_section_number = 0x01;
+            valid.crc_valid = Some(true);
+            let mut unknown = create_eit_section(triple, 3);
+            unknown.section_number = 0x02;
+            unknown.segment_last_section_number = 0x02;
+            vec![invalid, valid, unknown]
+        };
+
+        // Invalid sections are kept by default.
+        let mut epg = Epg::new(Arc::new(Default::default()));
+        epg.services.insert(
+            triple, create_epg_service(triple, ChannelType::GR));
+        epg.update_schedules(create_sections());
+        epg.flush_schedules(vec![triple]);
+        assert_eq!(epg.schedules[&triple].programs.len(), 3);
+        let stats = epg.collect_invalid_section_stats();
+        assert_eq!(stats.len(), 1);
+        assert!(stats[0].service_id == triple.into());
+        assert_eq!(stats[0].num_sections, 1);
+
+        let mut config = Config::default();
+        config.epg.drop_invalid_sections = true;
+        let mut epg = Epg::new(Arc::new(config));
+        epg.services.insert(
+            triple, create_epg_service(triple, ChannelType::GR));
+        epg.update_schedules(create_sections());
+        epg.flush_schedules(vec![triple]);
+        assert_eq!(epg.schedules[&triple].programs.len(), 2);
+        assert!(!epg.schedules[&triple].programs.contains_key(&1.into()));
+        assert_eq!(epg.collect_invalid_section_stats()[0].num_sections, 1);
+
+        // An invalid section with a newer version never removes a valid
+        // section in the same batch.
+        let mut config = Config::default();
+        config.epg.drop_invalid_sections = true;
+        let mut epg = Epg::new(Arc::new(config));
+        epg.services.insert(
+            triple, create_epg_service(triple, ChannelType::GR));
+        let mut valid = create_eit_section(triple, 1);
+        valid.version_number = 1;
+        valid.crc_valid = Some(true);
+        let mut invalid = create_eit_section(triple, 2);
+        invalid.version_number = 2;
+        invalid.crc_valid = Some(false);
+        epg.update_schedules(vec![valid, invalid]);
+        epg.flush_schedules(vec![triple]);
+        assert_eq!(epg.schedules[&triple].programs.len(), 1);
+        assert!(epg.schedules[&triple].programs.contains_key(&1.into()));
+    }
+
+    #[test]
+    fn test_epg_update_schedules_present_following() {
+        let triple = ServiceTriple::from((1, 2, 3));
+
+        let mut epg = Epg::new(Arc::new(Default::default()));
+        epg.services.insert(
+            triple, create_epg_service(triple, ChannelType::GR));
+
+        let schedule = create_eit_section(triple, 1);
+        let mut present = create_eit_section(triple, 1);
+        present.table_id = 0x4E;
+        present.events[0].duration = Duration::minutes(60);
+        let mut following = create_eit_section(triple, 2);
+        following.table_id = 0x4E;
+        following.section_number = 0x01;
+
+        epg.update_schedules(vec![schedule, present, following]);
+        epg.flush_schedules(vec![triple]);
+        let programs = &epg.schedules[&triple].programs;
+        assert_eq!(programs.len(), 2);
+        // Events in EIT[p/f] take precedence.
+        assert_eq!(programs[&EventId::from(1)].duration,
+                   Duration::minutes(60));
+        assert!(programs.contains_key(&2.into()));
+
+        // The following event becomes the present event.
+        let mut present = create_eit_section(triple, 2);
+        present.table_id = 0x4E;
+        present.version_number = 2;
+        let mut following = create_eit_section(triple, 3);
+        following.table_id = 0x4E;
+        following.section_number = 0x01;
+        following.version_number = 2;
+        epg.update_schedules(vec![present, following]);
+        epg.flush_schedules(vec![triple]);
+        let programs = &epg.schedules[&triple].programs;
+        assert_eq!(programs.len(), 3);
+        // The event in EIT[schedule] is used.
+        assert_eq!(programs[&EventId::from(1)].duration,
+                   Duration::minutes(30));
+    }
+
+    #[test]
+    fn test_is_newer_version() {
+        assert!(is_newer_version(2, 1));
+        assert!(!is_newer_version(1, 2));
+        assert!(!is_newer_version(1, 1));
+        // Rollover.
+        assert!(is_newer_version(0, 31));
+        assert!(!is_newer_version(31, 0));
+        assert!(is_newer_version(3, 30));
+        // Boundaries of the window.
+        assert!(is_newer_version(15, 0));
+        assert!(!is_newer_version(16, 0));
+        assert!(is_newer_version(14, 31));
+        assert!(!is_newer_version(15, 31));
+    }
+
+    #[test]
+    fn test_collect_fresh_services() {
+        let triple_a = ServiceTriple::from((1, 2, 3));
+        let triple_b = ServiceTriple::from((1, 2, 4));
+
+        let mut epg = Epg::new(Arc::new(Default::default()));
+        epg.services.insert(
+            triple_a, create_epg_service(triple_a, ChannelType::GR));
+        epg.services.insert(
+            triple_b, create_epg_service(triple_b, ChannelType::GR));
+        epg.prepare_schedules(Jst::now());
+
+        let now = Jst::now();
+        assert!(epg.collect_fresh_services(now).is_empty());
+
+        epg.mark_schedules_collected(&[triple_a], now);
+        assert!(epg.collect_fresh_services(now) == vec![triple_a]);
+        assert!(
+            epg.collect_fresh_services(now - Duration::hours(1)) ==
+                vec![triple_a]);
+        assert!(
+            epg.collect_fresh_services(now + Duration::seconds(1)).is_empty());
+
+        // Unknown services are ignored.
+        epg.mark_schedules_collected(&[ServiceTriple::from((9, 9, 9))], now);
+        assert!(epg.collect_fresh_services(now) == vec![triple_a]);
+    }
+
     #[test]
     fn test_epg_schedule_update() {
         let triple = ServiceTriple::from((1, 2, 3));
@@ -1410,6 +3834,7 @@ mod tests {
             segment_last_section_number: 0x00,
             version_number: 1,
             events: Vec::new(),
+            crc_valid: None,
         });
         assert!(sched.tables[0].is_some());
     }
@@ -1463,6 +3888,7 @@ mod tests {
             segment_last_section_number: 0x00,
             version_number: 1,
             events: Vec::new(),
+            crc_valid: None,
         });
         assert!(table.segments[0].sections[0].is_some());
     }
@@ -1491,6 +3917,7 @@ mod tests {
             segment_last_section_number: 0x01,
             version_number: 1,
             events: Vec::new(),
+            crc_valid: None,
         });
         assert!(segment.sections[0].is_none());
         assert!(segment.sections[1].is_some());
@@ -1505,6 +3932,7 @@ mod tests {
             segment_last_section_number: 0x00,
             version_number: 1,
             events: Vec::new(),
+            crc_valid: None,
         });
         assert!(segment.sections[0].is_some());
         assert!(segment.sections[1].is_none());
@@ -1581,6 +4009,145 @@ mod tests {
             Jst.ymd(2019, 10, 15).and_hms(0, 0, 0)));
     }
 
+    #[test]
+    fn test_eit_event_is_overnight_event_zero_duration() {
+        let event = EitEvent {
+            event_id: 0.into(),
+            start_time: Jst.ymd(2019, 10, 14).and_hms(0, 0, 0),
+            duration: Duration::zero(),
+            scrambled: false,
+            descriptors: Vec::new(),
+        };
+        assert!(!event.is_overnight_event(
+            Jst.ymd(2019, 10, 14).and_hms(0, 0, 0)));
+        assert!(!event.is_overnight_event(
+            Jst.ymd(2019, 10, 15).and_hms(0, 0, 0)));
+    }
+
+    #[test]
+    fn test_handle_zero_duration_programs() {
+        let create_programs = || {
+            let mut programs = HashMap::new();
+            for (eid, hour, minutes) in [(1, 0, 0), (2, 1, 30), (3, 2, 60),
+                                         (4, 3, 0)].iter() {
+                let mut program = EpgProgram::new((0, 0, 0, *eid).into());
+                program.start_at = Jst.ymd(2019, 10, 13).and_hms(*hour, 0, 0);
+                program.duration = Duration::minutes(*minutes);
+                programs.insert(EventId::from(*eid), program);
+            }
+            programs
+        };
+
+        let mut programs = create_programs();
+        EpgSchedule::handle_zero_duration_programs(
+            &mut programs, ZeroDurationEvents::Keep);
+        assert_eq!(programs.len(), 4);
+        assert_eq!(programs[&EventId::from(1)].duration, Duration::zero());
+
+        let mut programs = create_programs();
+        EpgSchedule::handle_zero_duration_programs(
+            &mut programs, ZeroDurationEvents::Skip);
+        assert_eq!(programs.len(), 2);
+        assert!(!programs.contains_key(&EventId::from(1)));
+        assert!(!programs.contains_key(&EventId::from(4)));
+
+        let mut programs = create_programs();
+        EpgSchedule::handle_zero_duration_programs(
+            &mut programs, ZeroDurationEvents::Fill);
+        assert_eq!(programs.len(), 4);
+        assert_eq!(programs[&EventId::from(1)].duration, Duration::hours(1));
+        assert_eq!(programs[&EventId::from(2)].duration, Duration::minutes(30));
+        // No following program.
+        assert_eq!(programs[&EventId::from(4)].duration, Duration::zero());
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = std::env::temp_dir().join(format!(
+            "mirakc-epg-cache-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let json_path = dir.join("data.json");
+
+        let data: HashMap<String, u32> =
+            vec![("a".to_string(), 1)].into_iter().collect();
+        Epg::save_cache(&json_path, &data).unwrap();
+        assert!(!dir.join("data.json.tmp").exists());
+        let loaded: Option<HashMap<String, u32>> =
+            Epg::load_cache(&json_path).unwrap();
+        assert_eq!(loaded, Some(data));
+
+        // Saved in an old format without the version.
+        std::fs::write(&json_path, r#"{"a":1}"#).unwrap();
+        let loaded: Option<HashMap<String, u32>> =
+            Epg::load_cache(&json_path).unwrap();
+        assert!(loaded.is_none());
+
+        // Saved with another version.
+        std::fs::write(&json_path, format!(
+            r#"{{"version":{},"data":{{"a":"1"}}}}"#, CACHE_VERSION + 1))
+            .unwrap();
+        let loaded: Option<HashMap<String, u32>> =
+            Epg::load_cache(&json_path).unwrap();
+        assert!(loaded.is_none());
+
+        // Partially written.
+        std::fs::write(&json_path, format!(
+            r#"{{"version":{},"data":{{"a":"#, CACHE_VERSION)).unwrap();
+        let loaded: Option<HashMap<String, u32>> =
+            Epg::load_cache(&json_path).unwrap();
+        assert!(loaded.is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_epg_program_update_components() {
+        let mut program = EpgProgram::new((0, 0, 0, 1).into());
+        assert!(program.video().is_none());
+        assert!(program.audio().is_none());
+
+        let event = EitEvent {
+            event_id: 1.into(),
+            start_time: Jst.ymd(2019, 10, 13).and_hms(0, 0, 0),
+            duration: Duration::minutes(30),
+            scrambled: false,
+            descriptors: vec![
+                EitDescriptor::Component {
+                    stream_content: 1,
+                    component_type: 0xB3,
+                    component_tag: Some(0x00),
+                },
+                EitDescriptor::Component {
+                    stream_content: 1,
+                    component_type: 0xB3,
+                    component_tag: Some(0x01),
+                },
+                EitDescriptor::AudioComponent {
+                    component_type: 3,
+                    sampling_rate: 7,
+                    component_tag: Some(0x10),
+                },
+            ],
+        };
+        program.update(&event);
+        assert_eq!(program.videos.len(), 2);
+        assert_eq!(program.video().unwrap().component_tag, Some(0x00));
+        assert_eq!(program.audios.len(), 1);
+        assert_eq!(program.audio().unwrap().component_tag, Some(0x10));
+
+        // Components are kept if the event has no component descriptor.
+        let event = EitEvent {
+            event_id: 1.into(),
+            start_time: Jst.ymd(2019, 10, 13).and_hms(0, 0, 0),
+            duration: Duration::minutes(30),
+            scrambled: false,
+            descriptors: vec![],
+        };
+        program.update(&event);
+        assert_eq!(program.videos.len(), 2);
+        assert_eq!(program.audios.len(), 1);
+    }
+
     fn create_epg_service(
         triple: ServiceTriple,
         channel_type: ChannelType
@@ -1593,6 +4160,7 @@ mod tests {
             logo_id: 0,
             remote_control_key_id: 0,
             name: "Service".to_string(),
+            network_name: None,
             channel: EpgChannel {
                 name: "Ch".to_string(),
                 channel_type,
@@ -1604,6 +4172,29 @@ mod tests {
         }
     }
 
+    fn create_eit_section(triple: ServiceTriple, eid: u16) -> EitSection {
+        EitSection {
+            original_network_id: triple.nid(),
+            transport_stream_id: triple.tsid(),
+            service_id: triple.sid(),
+            table_id: 0x50,
+            section_number: 0x00,
+            last_section_number: 0xF8,
+            segment_last_section_number: 0x00,
+            version_number: 1,
+            events: vec![
+                EitEvent {
+                    event_id: eid.into(),
+                    start_time: Jst::now(),
+                    duration: Duration::minutes(30),
+                    scrambled: false,
+                    descriptors: Vec::new(),
+                },
+            ],
+            crc_valid: None,
+        }
+    }
+
     fn create_epg_schedule(triple: ServiceTriple) -> EpgSchedule {
         EpgSchedule::new(triple)
     }
@@ -4,10 +4,12 @@ use std::io;
 use actix;
 use failure::Fail;
 use mustache;
+use quick_xml;
 use serde_json;
 use serde_yaml;
 
 use crate::command_util;
+use crate::models::ChannelType;
 
 #[derive(Debug, Fail)]
 pub enum Error {
@@ -15,26 +17,54 @@ pub enum Error {
     StreamingTimedOut,
     #[fail(display = "Tuner unavailable")]
     TunerUnavailable,
+    #[fail(display = "Tuner startup timed out")]
+    TunerStartupTimedOut,
+    #[fail(display = "Tuner not found")]
+    TunerNotFound,
+    #[fail(display = "No tuner supports {}", 0)]
+    UnsupportedChannelType(ChannelType),
     #[fail(display = "Channel not found")]
     ChannelNotFound,
     #[fail(display = "Service not found")]
     ServiceNotFound,
     #[fail(display = "Clock not synced")]
     ClockNotSynced,
+    #[fail(display = "EPG not ready")]
+    EpgNotReady,
     #[fail(display = "Program not found")]
     ProgramNotFound,
+    #[fail(display = "Changes no longer tracked")]
+    ChangesNotTracked,
+    #[fail(display = "Recording not found")]
+    RecordingNotFound,
+    #[fail(display = "Recording rule not found")]
+    RecordingRuleNotFound,
+    #[fail(display = "Thumbnail not found")]
+    ThumbnailNotFound,
+    #[fail(display = "Job not found")]
+    JobNotFound,
+    #[fail(display = "Filter not found")]
+    FilterNotFound,
     #[fail(display = "Session not found")]
     SessionNotFound,
     #[fail(display = "Access denied")]
     AccessDenied,
+    #[fail(display = "Bad request: {}", 0)]
+    BadRequest(String),
+    #[fail(display = "Too many filters: {} > {}", 0, 1)]
+    TooManyFilters(usize, usize),
     #[fail(display = "Command failed: {}", 0)]
     CommandFailed(command_util::Error),
+    #[fail(display = "HTTP request failed: {}", 0)]
+    HttpRequestFailed(String),
     #[fail(display = "std::io::error: {}", 0)]
     IoError(io::Error),
     #[fail(display = "JSON error: {}", 0)]
     JsonError(serde_json::Error),
     #[fail(display = "YAML error: {}", 0)]
     YamlError(serde_yaml::Error),
+    #[fail(display = "XML error: {}", 0)]
+    XmlError(quick_xml::Error),
     #[fail(display = "Mailbox error: {}", 0)]
     MailboxError(actix::MailboxError),
     #[fail(display = "Mustache error: {}", 0)]
@@ -45,6 +75,36 @@ pub enum Error {
     TokioSyncBroadcastError(tokio::sync::broadcast::RecvError),
 }
 
+impl Error {
+    // Returns true if the same operation may succeed when retried later.
+    // Errors caused by misconfiguration such as a bad command template are
+    // never retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::TunerUnavailable |
+            Error::TunerStartupTimedOut |
+            Error::StreamingTimedOut |
+            Error::IoError(_) => true,
+            Error::CommandFailed(command_util::Error::UnableToSpawn(_, err)) =>
+                Self::is_retryable_io_error(err),
+            Error::CommandFailed(
+                command_util::Error::AsyncIoRegistrationFailure(_)) => true,
+            _ => false,
+        }
+    }
+
+    // A command which doesn't exist or isn't executable never succeeds.
+    // Other errors such as EBUSY may be transient.
+    fn is_retryable_io_error(err: &io::Error) -> bool {
+        match err.kind() {
+            io::ErrorKind::NotFound |
+            io::ErrorKind::PermissionDenied |
+            io::ErrorKind::InvalidInput => false,
+            _ => true,
+        }
+    }
+}
+
 impl From<command_util::Error> for Error {
     fn from(err: command_util::Error) -> Self {
         Self::CommandFailed(err)
@@ -69,6 +129,12 @@ impl From<serde_yaml::Error> for Error {
     }
 }
 
+impl From<quick_xml::Error> for Error {
+    fn from(err: quick_xml::Error) -> Self {
+        Self::XmlError(err)
+    }
+}
+
 impl From<actix::MailboxError> for Error {
     fn from(err: actix::MailboxError) -> Self {
         Self::MailboxError(err)
@@ -98,3 +164,28 @@ impl From<tokio::sync::broadcast::RecvError> for Error {
         Self::TokioSyncBroadcastError(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(Error::TunerUnavailable.is_retryable());
+        assert!(Error::TunerStartupTimedOut.is_retryable());
+        assert!(Error::IoError(io::Error::from_raw_os_error(libc::EBUSY))
+                .is_retryable());
+        assert!(Error::CommandFailed(command_util::Error::UnableToSpawn(
+            "cmd".to_string(), io::Error::from_raw_os_error(libc::EBUSY)))
+                .is_retryable());
+
+        assert!(!Error::ChannelNotFound.is_retryable());
+        assert!(!Error::CommandFailed(command_util::Error::UnableToParse(
+            "cmd".to_string())).is_retryable());
+        assert!(!Error::CommandFailed(command_util::Error::UnableToSpawn(
+            "cmd".to_string(), io::ErrorKind::NotFound.into()))
+                .is_retryable());
+        let err = mustache::compile_str("{{").unwrap_err();
+        assert!(!Error::MustacheError(err).is_retryable());
+    }
+}
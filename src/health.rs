@@ -0,0 +1,147 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::prelude::*;
+use log;
+
+use crate::config::Config;
+use crate::datetime_ext::*;
+use crate::epg::*;
+use crate::error::Error;
+use crate::recording::*;
+use crate::tuner::*;
+
+pub fn start(
+    config: Arc<Config>,
+    tuner_manager: Addr<TunerManager>,
+    epg: Addr<Epg>,
+    recording_manager: Addr<RecordingManager>,
+) -> Addr<HealthChecker> {
+    HealthChecker::new(config, tuner_manager, epg, recording_manager).start()
+}
+
+// A health checker periodically updates the health file while the event loop
+// and core actors are responsive.
+//
+// The health file is not updated when any of core actors doesn't respond
+// within `health.timeout`.  A process supervisor can restart mirakc when the
+// modification time of the file becomes stale.
+pub struct HealthChecker {
+    config: Arc<Config>,
+    tuner_manager: Addr<TunerManager>,
+    epg: Addr<Epg>,
+    recording_manager: Addr<RecordingManager>,
+    checking: bool,
+}
+
+impl HealthChecker {
+    pub fn new(
+        config: Arc<Config>,
+        tuner_manager: Addr<TunerManager>,
+        epg: Addr<Epg>,
+        recording_manager: Addr<RecordingManager>,
+    ) -> Self {
+        HealthChecker {
+            config,
+            tuner_manager,
+            epg,
+            recording_manager,
+            checking: false,
+        }
+    }
+
+    fn check(&mut self, path: String, ctx: &mut Context<Self>) {
+        if self.checking {
+            log::warn!("The previous health check has not finished yet");
+            return;
+        }
+        self.checking = true;
+
+        let timeout = Duration::from_millis(self.config.health.timeout);
+        let fut = Self::ping(self.tuner_manager.clone(), self.epg.clone(),
+                             self.recording_manager.clone());
+
+        actix::fut::wrap_future::<_, Self>(async move {
+            match tokio::time::timeout(timeout, fut).await {
+                Ok(Ok(())) => {
+                    if let Err(err) = Self::touch(&path).await {
+                        log::error!("Failed to update {}: {}", path, err);
+                    }
+                }
+                Ok(Err(err)) => log::error!("Health check failed: {}", err),
+                Err(_) => log::error!("Health check failed: No response \
+                                       from core actors within {}ms",
+                                      timeout.as_millis()),
+            }
+        })
+            .then(|_, act, _| {
+                act.checking = false;
+                actix::fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    async fn ping(
+        tuner_manager: Addr<TunerManager>,
+        epg: Addr<Epg>,
+        recording_manager: Addr<RecordingManager>,
+    ) -> Result<(), Error> {
+        tuner_manager.send(QueryTunersMessage).await?;
+        epg.send(QueryEpgStatusMessage).await??;
+        recording_manager.send(QueryRecordingSchedulesMessage).await?;
+        Ok(())
+    }
+
+    // Writing the file updates its modification time.
+    async fn touch(path: &str) -> Result<(), Error> {
+        tokio::fs::write(path, format!("{}\n", Jst::now().to_rfc3339()))
+            .await?;
+        log::trace!("Updated the health file: {}", path);
+        Ok(())
+    }
+}
+
+impl Actor for HealthChecker {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        log::debug!("Started");
+        let path = match self.config.health.file {
+            Some(ref path) => path.clone(),
+            None => return,
+        };
+        log::info!("Update {} every {}ms while healthy",
+                   path, self.config.health.interval);
+        self.check(path.clone(), ctx);
+        let interval = Duration::from_millis(self.config.health.interval);
+        ctx.run_interval(interval, move |act, ctx| {
+            act.check(path.clone(), ctx);
+        });
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        log::debug!("Stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_touch() {
+        let path = std::env::temp_dir().join(format!(
+            "mirakc-health-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        HealthChecker::touch(path).await.unwrap();
+        let modified = std::fs::metadata(path).unwrap().modified().unwrap();
+
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        HealthChecker::touch(path).await.unwrap();
+        assert!(std::fs::metadata(path).unwrap().modified().unwrap() >
+                modified);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,20 +1,31 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use chrono::DateTime;
 use cron;
 use humantime;
 use log;
-use tokio::sync::Semaphore;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use crate::clock_synchronizer::ClockSynchronizer;
-use crate::config::Config;
+use crate::command_util::{self, CommandPipeline};
+use crate::config::{Config, JobConfig, RetryConfig};
+use crate::config_reloader::ReloadConfigMessage;
 use crate::datetime_ext::*;
 use crate::eit_feeder::*;
 use crate::epg::*;
+use crate::error::Error;
+use crate::models::{ServiceId, TunerUser, TunerUserInfo};
+use crate::mpeg_ts_stream::{MpegTsStream, MpegTsStreamId};
+use crate::recording::*;
+use crate::retry;
 use crate::service_scanner::ServiceScanner;
 use crate::tuner::*;
 
@@ -23,8 +34,10 @@ pub fn start(
     tuner_manager: Addr<TunerManager>,
     epg: Addr<Epg>,
     eit_feeder: Addr<EitFeeder>,
+    recording_manager: Addr<RecordingManager>,
 ) -> Addr<JobManager> {
-    JobManager::new(config, tuner_manager, epg, eit_feeder).start()
+    JobManager::new(
+        config, tuner_manager, epg, eit_feeder, recording_manager).start()
 }
 
 struct Job {
@@ -53,16 +66,47 @@ impl Job {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 enum JobKind {
     ScanServices,
     SyncClocks,
     UpdateSchedules,
+    UpdateRecentSchedules,
 }
 
 impl JobKind {
+    const ALL: [JobKind; 4] = [
+        JobKind::ScanServices,
+        JobKind::SyncClocks,
+        JobKind::UpdateSchedules,
+        JobKind::UpdateRecentSchedules,
+    ];
+
     fn create(self, semaphore: Arc<Semaphore>) -> Job {
         Job::new(self, semaphore)
     }
+
+    fn from_name(name: &str) -> Result<Self, Error> {
+        use JobKind::*;
+        match name {
+            "scan-services" => Ok(ScanServices),
+            "sync-clocks" => Ok(SyncClocks),
+            "update-schedules" => Ok(UpdateSchedules),
+            "update-recent-schedules" => Ok(UpdateRecentSchedules),
+            _ => Err(Error::JobNotFound),
+        }
+    }
+
+    fn config(self, config: &Config) -> Option<&JobConfig> {
+        use JobKind::*;
+        match self {
+            ScanServices => Some(&config.jobs.scan_services),
+            SyncClocks => Some(&config.jobs.sync_clocks),
+            UpdateSchedules => Some(&config.jobs.update_schedules),
+            UpdateRecentSchedules =>
+                config.jobs.update_recent_schedules.as_ref(),
+        }
+    }
 }
 
 impl fmt::Display for JobKind {
@@ -72,19 +116,121 @@ impl fmt::Display for JobKind {
             ScanServices => write!(f, "scan-services"),
             SyncClocks => write!(f, "sync-clocks"),
             UpdateSchedules => write!(f, "update-schedules"),
+            UpdateRecentSchedules => write!(f, "update-recent-schedules"),
         }
     }
 }
 
+// Settings shared by jobs which process channels one by one.  Each job holds a
+// context and uses it for streaming each channel.
+#[derive(Clone)]
+pub struct JobContext {
+    label: String,
+    retry: RetryConfig,
+    tuner_semaphore: Option<Arc<Semaphore>>,
+}
+
+impl JobContext {
+    // `label` is used as the name of the tuner user.  No retry and no limit on
+    // tuners by default.
+    pub fn new(label: &str) -> Self {
+        JobContext {
+            label: label.to_string(),
+            retry: RetryConfig::no_retry(),
+            tuner_semaphore: None,
+        }
+    }
+
+    // Overrides the default label with `jobs.*.label` if specified.
+    pub fn with_label(mut self, label: Option<String>) -> Self {
+        if let Some(label) = label {
+            self.label = label;
+        }
+        self
+    }
+
+    // Transient failures in acquiring a tuner and spawning the command are
+    // retried for each channel.
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    // Each channel is processed after acquiring a permit from `semaphore`, so
+    // that the number of tuners used by jobs is limited.
+    pub fn with_tuner_semaphore(mut self, semaphore: Arc<Semaphore>) -> Self {
+        self.tuner_semaphore = Some(semaphore);
+        self
+    }
+
+    // The permit must be held while processing a channel.
+    pub async fn acquire_tuner(&self) -> Option<SemaphorePermit<'_>> {
+        match self.tuner_semaphore {
+            Some(ref semaphore) => Some(semaphore.acquire().await),
+            None => None,
+        }
+    }
+
+    pub fn make_user(
+        &self,
+        expected_end_at: Option<DateTime<Jst>>,
+    ) -> TunerUser {
+        TunerUser {
+            info: TunerUserInfo::Job { name: self.label.clone() },
+            priority: (-1).into(),
+            expected_end_at,
+            drop_policy: Default::default(),
+            timeshift: None,
+        }
+    }
+
+    pub async fn start_streaming(
+        &self,
+        channel: &EpgChannel,
+        user: &TunerUser,
+        stream_manager: &Recipient<StartStreamingMessage>,
+    ) -> Result<MpegTsStream, Error> {
+        retry::retry(&self.retry, &channel.name, || {
+            let fut = stream_manager.send(StartStreamingMessage {
+                channel: channel.clone(),
+                user: user.clone(),
+            });
+            async move { fut.await? }
+        }).await
+    }
+
+    pub async fn spawn_pipeline(
+        &self,
+        channel: &EpgChannel,
+        commands: Vec<String>,
+        stream_id: MpegTsStreamId,
+    ) -> Result<CommandPipeline<MpegTsStreamId>, Error> {
+        retry::retry(&self.retry, &channel.name, || {
+            let result = command_util::spawn_pipeline(
+                commands.clone(), stream_id);
+            async move { result.map_err(Error::from) }
+        }).await
+    }
+}
+
 pub struct JobManager {
     config: Arc<Config>,
     semaphore: Arc<Semaphore>,  // job concurrency
+    // Shared by all jobs in order to limit the number of tuners used by them.
+    // A permit is acquired for each channel processed in a job.
+    tuner_semaphore: Arc<Semaphore>,
     scanning_services: bool,
     synchronizing_clocks: bool,
     updating_schedules: bool,
+    updating_recent_schedules: bool,
+    // Saved in epg.cache-dir when jobs.persist-paused is enabled.
+    paused_jobs: HashSet<JobKind>,
+    // Kept for rescheduling jobs when the config is reloaded.
+    scheduled_jobs: HashMap<JobKind, SpawnHandle>,
     tuner_manager: Addr<TunerManager>,
     epg: Addr<Epg>,
     eit_feeder: Addr<EitFeeder>,
+    recording_manager: Addr<RecordingManager>,
 }
 
 impl JobManager {
@@ -93,19 +239,34 @@ impl JobManager {
         tuner_manager: Addr<TunerManager>,
         epg: Addr<Epg>,
         eit_feeder: Addr<EitFeeder>,
+        recording_manager: Addr<RecordingManager>,
     ) -> Self {
+        let tuner_semaphore =
+            Arc::new(Semaphore::new(config.jobs.max_tuners.max(1)));
         JobManager {
             config,
             semaphore: Arc::new(Semaphore::new(1)),
+            tuner_semaphore,
             scanning_services: false,
             synchronizing_clocks: false,
             updating_schedules: false,
+            updating_recent_schedules: false,
+            paused_jobs: HashSet::new(),
+            scheduled_jobs: HashMap::new(),
             tuner_manager,
             epg,
             eit_feeder,
+            recording_manager,
         }
     }
 
+    fn create_context(&self, label: &str, job: &JobConfig) -> JobContext {
+        JobContext::new(label)
+            .with_label(job.label.clone())
+            .with_retry(self.config.jobs.retry.clone())
+            .with_tuner_semaphore(self.tuner_semaphore.clone())
+    }
+
     fn calc_next_scheduled_datetime(&self, schedule: &str) -> DateTime<Jst> {
         cron::Schedule::from_str(schedule)
             .unwrap()
@@ -115,8 +276,97 @@ impl JobManager {
             .unwrap()
     }
 
+    // Paused jobs are not invoked by the timer, but they can be invoked
+    // manually.
+    fn is_paused(&self, kind: JobKind) -> bool {
+        if self.paused_jobs.contains(&kind) {
+            log::info!("{}: Paused, skip", kind);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn pause_job(&mut self, name: &str) -> Result<(), Error> {
+        let kind = JobKind::from_name(name)?;
+        if self.paused_jobs.insert(kind) {
+            log::info!("{}: Paused", kind);
+            self.save_paused_jobs();
+        }
+        Ok(())
+    }
+
+    fn resume_job(&mut self, name: &str) -> Result<(), Error> {
+        let kind = JobKind::from_name(name)?;
+        if self.paused_jobs.remove(&kind) {
+            log::info!("{}: Resumed", kind);
+            self.save_paused_jobs();
+        }
+        Ok(())
+    }
+
+    fn paused_jobs_path(&self) -> Option<PathBuf> {
+        if !self.config.jobs.persist_paused {
+            return None;
+        }
+        match self.config.epg.cache_dir {
+            Some(ref cache_dir) =>
+                Some(PathBuf::from(cache_dir).join("paused-jobs.json")),
+            None => {
+                log::warn!("No epg.cache-dir specified, \
+                            paused jobs are not persisted");
+                None
+            }
+        }
+    }
+
+    fn load_paused_jobs(&mut self) -> Result<(), Error> {
+        let json_path = match self.paused_jobs_path() {
+            Some(json_path) => json_path,
+            None => return Ok(()),
+        };
+        log::debug!("Loading paused jobs from {}...", json_path.display());
+        let file = match File::open(&json_path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                log::debug!("No paused jobs saved in {}", json_path.display());
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let names: Vec<String> =
+            serde_json::from_reader(BufReader::new(file))?;
+        for name in names.iter() {
+            match JobKind::from_name(name) {
+                Ok(kind) => {
+                    log::info!("{}: Paused", kind);
+                    self.paused_jobs.insert(kind);
+                }
+                Err(_) => log::warn!("{}: Unknown job, ignored", name),
+            }
+        }
+        Ok(())
+    }
+
+    fn save_paused_jobs(&self) {
+        let json_path = match self.paused_jobs_path() {
+            Some(json_path) => json_path,
+            None => return,
+        };
+        log::debug!("Saving paused jobs into {}...", json_path.display());
+        let names: Vec<String> = self.paused_jobs
+            .iter()
+            .map(|kind| kind.to_string())
+            .collect();
+        if let Err(err) = save_json(&json_path, &names) {
+            log::error!("Failed to save paused jobs: {}", err);
+        }
+    }
+
     fn scan_services(&mut self, ctx: &mut Context<Self>) {
-        self.invoke_scan_services(ctx);
+        if !self.is_paused(JobKind::ScanServices) {
+            self.invoke_scan_services(ctx);
+        }
         self.schedule_scan_services(ctx);
     }
 
@@ -130,35 +380,53 @@ impl JobManager {
 
         let scanner = ServiceScanner::new(
             self.config.jobs.scan_services.command.clone(),
-            self.collect_enabled_channels(),
-            self.tuner_manager.clone().recipient());
+            self.collect_scan_targets(),
+            self.config.jobs.scan_services.timeout.map(Duration::from_millis),
+            self.tuner_manager.clone().recipient())
+            .with_context(self.create_context(
+                ServiceScanner::LABEL, &self.config.jobs.scan_services));
 
         let job = JobKind::ScanServices.create(self.semaphore.clone())
             .perform(scanner.scan_services());
 
         actix::fut::wrap_future::<_, Self>(job)
-            .then(|results, act, _| {
+            .then(|(results, stats), act, _| {
                 act.epg.do_send(UpdateServicesMessage { results });
+                act.epg.do_send(UpdateServiceScanStatsMessage { stats });
                 act.scanning_services = false;
                 actix::fut::ready(())
             })
             .spawn(ctx);
     }
 
-    fn schedule_scan_services(&self, ctx: &mut Context<Self>) {
+    fn schedule_scan_services(&mut self, ctx: &mut Context<Self>) {
         let datetime = self.calc_next_scheduled_datetime(
             &self.config.jobs.scan_services.schedule);
         log::info!("scan-services: Scheduled for {}", datetime);
         let interval = (datetime - Jst::now()).to_std().unwrap();
-        ctx.run_later(interval, Self::scan_services);
+        let handle = ctx.run_later(interval, Self::scan_services);
+        self.scheduled_jobs.insert(JobKind::ScanServices, handle);
     }
 
     fn sync_clocks(&mut self, ctx: &mut Context<Self>) {
-        self.invoke_sync_clocks(ctx);
+        if !self.is_paused(JobKind::SyncClocks) {
+            self.invoke_sync_clocks(ctx);
+        }
         self.schedule_sync_clocks(ctx);
     }
 
     fn invoke_sync_clocks(&mut self, ctx: &mut Context<Self>) {
+        let channels = self.collect_enabled_channels();
+        self.invoke_sync_clocks_for(channels, false, ctx);
+    }
+
+    // Clocks of channels not specified are kept in Epg when `scoped` is true.
+    fn invoke_sync_clocks_for(
+        &mut self,
+        channels: Vec<EpgChannel>,
+        scoped: bool,
+        ctx: &mut Context<Self>,
+    ) {
         if self.synchronizing_clocks {
             log::warn!("sync-clocks: Already running, skip");
             return;
@@ -168,35 +436,67 @@ impl JobManager {
 
         let sync = ClockSynchronizer::new(
             self.config.jobs.sync_clocks.command.clone(),
-            self.collect_enabled_channels(),
-            self.tuner_manager.clone().recipient());
+            channels,
+            self.tuner_manager.clone().recipient())
+            .with_context(self.create_context(
+                ClockSynchronizer::LABEL, &self.config.jobs.sync_clocks));
 
         let job = JobKind::SyncClocks.create(self.semaphore.clone())
             .perform(sync.sync_clocks());
 
         actix::fut::wrap_future::<_, Self>(job)
-            .then(|results, act, _| {
-                act.epg.do_send(UpdateClocksMessage { results });
+            .then(move |results, act, _| {
+                if scoped {
+                    act.epg.do_send(MergeClocksMessage { results });
+                } else {
+                    act.epg.do_send(UpdateClocksMessage { results });
+                }
                 act.synchronizing_clocks = false;
                 actix::fut::ready(())
             })
             .spawn(ctx);
     }
 
-    fn schedule_sync_clocks(&self, ctx: &mut Context<Self>) {
+    // Channels are specified with their `channel` properties.
+    fn sync_clocks_of(
+        &mut self,
+        channels: Vec<String>,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), Error> {
+        let targets: Vec<EpgChannel> = self.collect_enabled_channels()
+            .into_iter()
+            .filter(|target| channels.contains(&target.channel))
+            .collect();
+        if targets.is_empty() {
+            return Err(Error::ChannelNotFound);
+        }
+        log::info!("sync-clocks: Synchronizing {} channels", targets.len());
+        self.invoke_sync_clocks_for(targets, true, ctx);
+        Ok(())
+    }
+
+    fn schedule_sync_clocks(&mut self, ctx: &mut Context<Self>) {
         let datetime = self.calc_next_scheduled_datetime(
             &self.config.jobs.sync_clocks.schedule);
         log::info!("sync-clocks: Scheduled for {}", datetime);
         let interval = (datetime - Jst::now()).to_std().unwrap();
-        ctx.run_later(interval, Self::sync_clocks);
+        let handle = ctx.run_later(interval, Self::sync_clocks);
+        self.scheduled_jobs.insert(JobKind::SyncClocks, handle);
     }
 
     fn update_schedules(&mut self, ctx: &mut Context<Self>) {
-        self.invoke_update_schedules(ctx);
+        if !self.is_paused(JobKind::UpdateSchedules) {
+            self.invoke_update_schedules(false, ctx);
+        }
         self.schedule_update_schedules(ctx);
     }
 
-    fn invoke_update_schedules(&mut self, ctx: &mut Context<Self>) {
+    // `jobs.update-schedules.fresh-window` is ignored if `force` is `true`.
+    fn invoke_update_schedules(
+        &mut self,
+        force: bool,
+        ctx: &mut Context<Self>,
+    ) {
         if self.updating_schedules {
             log::warn!("update-schedules: Already running, skip");
             return;
@@ -205,15 +505,24 @@ impl JobManager {
         self.updating_schedules = true;
 
         let eit_feeder = self.eit_feeder.clone();
+        let msg = FeedEitSectionsMessage {
+            job: self.config.jobs.update_schedules.clone(),
+            force,
+            context: self.create_context(
+                EitCollector::LABEL, &self.config.jobs.update_schedules),
+        };
 
         let job = JobKind::UpdateSchedules.create(self.semaphore.clone())
             .perform(async move {
-                eit_feeder.send(FeedEitSectionsMessage).await?
+                eit_feeder.send(msg).await?
             });
 
         actix::fut::wrap_future::<_, Self>(job)
             .then(|_, act, _| {
                 act.epg.do_send(SaveSchedulesMessage);
+                // Epg processes flush requests sent during the job before
+                // the query from the recording manager.
+                act.recording_manager.do_send(ApplyRecordingRulesMessage);
                 act.updating_schedules = false;
                 actix::fut::ready(())
             })
@@ -225,7 +534,99 @@ impl JobManager {
             &self.config.jobs.update_schedules.schedule);
         log::info!("update-schedules: Scheduled for {}", datetime);
         let interval = (datetime - Jst::now()).to_std().unwrap();
-        ctx.run_later(interval, Self::update_schedules);
+        let handle = ctx.run_later(interval, Self::update_schedules);
+        self.scheduled_jobs.insert(JobKind::UpdateSchedules, handle);
+    }
+
+    fn update_recent_schedules(&mut self, ctx: &mut Context<Self>) {
+        if !self.is_paused(JobKind::UpdateRecentSchedules) {
+            self.invoke_update_recent_schedules(ctx);
+        }
+        self.schedule_update_recent_schedules(ctx);
+    }
+
+    fn invoke_update_recent_schedules(&mut self, ctx: &mut Context<Self>) {
+        let config = match self.config.jobs.update_recent_schedules {
+            Some(ref config) => config.clone(),
+            None => return,
+        };
+
+        if self.updating_recent_schedules {
+            log::warn!("update-recent-schedules: Already running, skip");
+            return;
+        }
+
+        self.updating_recent_schedules = true;
+
+        let eit_feeder = self.eit_feeder.clone();
+        let msg = FeedEitSectionsMessage {
+            context: self.create_context(EitCollector::LABEL, &config),
+            job: config,
+            force: false,
+        };
+
+        let job = JobKind::UpdateRecentSchedules
+            .create(self.semaphore.clone())
+            .perform(async move {
+                eit_feeder.send(msg).await?
+            });
+
+        actix::fut::wrap_future::<_, Self>(job)
+            .then(|_, act, _| {
+                act.epg.do_send(SaveSchedulesMessage);
+                act.recording_manager.do_send(ApplyRecordingRulesMessage);
+                act.updating_recent_schedules = false;
+                actix::fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    fn schedule_update_recent_schedules(&mut self, ctx: &mut Context<Self>) {
+        let schedule = match self.config.jobs.update_recent_schedules {
+            Some(ref config) => &config.schedule,
+            None => return,
+        };
+        let datetime = self.calc_next_scheduled_datetime(schedule);
+        log::info!("update-recent-schedules: Scheduled for {}", datetime);
+        let interval = (datetime - Jst::now()).to_std().unwrap();
+        let handle = ctx.run_later(interval, Self::update_recent_schedules);
+        self.scheduled_jobs.insert(JobKind::UpdateRecentSchedules, handle);
+    }
+
+    // Jobs already running are not affected.  The new config is used from the
+    // next invocation.
+    fn reload_config(&mut self, config: Arc<Config>, ctx: &mut Context<Self>) {
+        let old_config = std::mem::replace(&mut self.config, config);
+
+        for &kind in JobKind::ALL.iter() {
+            let old_schedule =
+                kind.config(&old_config).map(|job| &job.schedule);
+            let new_schedule =
+                kind.config(&self.config).map(|job| &job.schedule);
+            if old_schedule != new_schedule {
+                log::info!("{}: Schedule changed, reschedule", kind);
+                self.reschedule(kind, ctx);
+            }
+        }
+
+        // Services in new channels are available after scanning.
+        if old_config.channels != self.config.channels {
+            log::info!("scan-services: Channels changed, invoke now");
+            self.invoke_scan_services(ctx);
+        }
+    }
+
+    fn reschedule(&mut self, kind: JobKind, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.scheduled_jobs.remove(&kind) {
+            ctx.cancel_future(handle);
+        }
+        match kind {
+            JobKind::ScanServices => self.schedule_scan_services(ctx),
+            JobKind::SyncClocks => self.schedule_sync_clocks(ctx),
+            JobKind::UpdateSchedules => self.schedule_update_schedules(ctx),
+            JobKind::UpdateRecentSchedules =>
+                self.schedule_update_recent_schedules(ctx),
+        }
     }
 
     fn collect_enabled_channels(&self) -> Vec<EpgChannel> {
@@ -237,6 +638,18 @@ impl JobManager {
             .map(EpgChannel::from)
             .collect()
     }
+
+    fn collect_scan_targets(&self) -> Vec<(EpgChannel, Vec<ServiceId>)> {
+        self.config
+            .channels
+            .iter()
+            .filter(|config| !config.disabled)
+            .map(|config| {
+                let expected_services = config.expected_services.clone();
+                (EpgChannel::from(config.clone()), expected_services)
+            })
+            .collect()
+    }
 }
 
 impl Actor for JobManager {
@@ -244,9 +657,16 @@ impl Actor for JobManager {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         log::debug!("Started");
+        if let Err(err) = self.load_paused_jobs() {
+            log::error!("Failed to load paused jobs: {}", err);
+        }
         self.scan_services(ctx);
         self.sync_clocks(ctx);
         self.update_schedules(ctx);
+        // Optional, not scheduled unless defined in the config.
+        if self.config.jobs.update_recent_schedules.is_some() {
+            self.update_recent_schedules(ctx);
+        }
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
@@ -254,6 +674,21 @@ impl Actor for JobManager {
     }
 }
 
+// reload config
+
+impl Handler<ReloadConfigMessage> for JobManager {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ReloadConfigMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.reload_config(msg.config, ctx);
+    }
+}
+
 // invoke scan services
 
 struct InvokeScanServicesMessage;
@@ -310,7 +745,8 @@ impl Handler<InvokeSyncClocksMessage> for JobManager {
 
 // invoke update schedules
 
-struct InvokeUpdateSchedulesMessage;
+// Used for running the update-schedules job manually.
+pub struct InvokeUpdateSchedulesMessage;
 
 impl fmt::Display for InvokeUpdateSchedulesMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -331,6 +767,218 @@ impl Handler<InvokeUpdateSchedulesMessage> for JobManager {
         ctx: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
-        self.invoke_update_schedules(ctx);
+        self.invoke_update_schedules(true, ctx);
+    }
+}
+
+// sync clocks
+
+pub struct SyncClocksMessage {
+    // All enabled channels are synchronized if `None`.
+    pub channels: Option<Vec<String>>,
+}
+
+impl fmt::Display for SyncClocksMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.channels {
+            Some(ref channels) =>
+                write!(f, "SyncClocks of {}", channels.join(",")),
+            None => write!(f, "SyncClocks"),
+        }
+    }
+}
+
+impl Message for SyncClocksMessage {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<SyncClocksMessage> for JobManager {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: SyncClocksMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        match msg.channels {
+            Some(channels) => self.sync_clocks_of(channels, ctx),
+            None => {
+                self.invoke_sync_clocks(ctx);
+                Ok(())
+            }
+        }
+    }
+}
+
+// pause job
+
+pub struct PauseJobMessage {
+    pub name: String,
+}
+
+impl fmt::Display for PauseJobMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PauseJob {}", self.name)
+    }
+}
+
+impl Message for PauseJobMessage {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<PauseJobMessage> for JobManager {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: PauseJobMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.pause_job(&msg.name)
+    }
+}
+
+// resume job
+
+pub struct ResumeJobMessage {
+    pub name: String,
+}
+
+impl fmt::Display for ResumeJobMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ResumeJob {}", self.name)
+    }
+}
+
+impl Message for ResumeJobMessage {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ResumeJobMessage> for JobManager {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: ResumeJobMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.resume_job(&msg.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::*;
+    use crate::named_filters::NamedFilters;
+
+    fn create_manager(config: Config) -> JobManager {
+        let config = Arc::new(config);
+        let tuner_manager = crate::tuner::start(config.clone());
+        let epg = crate::epg::start(config.clone());
+        let eit_feeder = crate::eit_feeder::start(
+            config.clone(), tuner_manager.clone(), epg.clone());
+        let recording_manager = crate::recording::start(
+            config.clone(), tuner_manager.clone(), epg.clone(),
+            Arc::new(NamedFilters::new(config.clone())));
+        JobManager::new(
+            config, tuner_manager, epg, eit_feeder, recording_manager)
+    }
+
+    #[actix_rt::test]
+    async fn test_create_context_label() {
+        let mut config = Config::default();
+        config.jobs.sync_clocks.label = Some("sync-clocks-gr".to_string());
+        let manager = create_manager(config);
+
+        let context = manager.create_context(
+            ClockSynchronizer::LABEL, &manager.config.jobs.sync_clocks);
+        assert_matches!(context.make_user(None).info, TunerUserInfo::Job {
+            ref name
+        } => assert_eq!(name, "sync-clocks-gr"));
+
+        let context = manager.create_context(
+            ServiceScanner::LABEL, &manager.config.jobs.scan_services);
+        assert_matches!(context.make_user(None).info, TunerUserInfo::Job {
+            ref name
+        } => assert_eq!(name, ServiceScanner::LABEL));
+    }
+
+    #[actix_rt::test]
+    async fn test_create_context_tuner_semaphore() {
+        let mut config = Config::default();
+        config.jobs.max_tuners = 1;
+        let manager = create_manager(config);
+
+        // Contexts of all jobs share the same semaphore.
+        let sync_clocks = manager.create_context(
+            ClockSynchronizer::LABEL, &manager.config.jobs.sync_clocks);
+        let scan_services = manager.create_context(
+            ServiceScanner::LABEL, &manager.config.jobs.scan_services);
+
+        let permit = sync_clocks.acquire_tuner().await;
+        assert!(permit.is_some());
+        let result = tokio::time::timeout(
+            Duration::from_millis(100), scan_services.acquire_tuner()).await;
+        assert!(result.is_err());
+
+        drop(permit);
+        let result = tokio::time::timeout(
+            Duration::from_millis(100), scan_services.acquire_tuner()).await;
+        assert_matches!(result, Ok(Some(_)));
+    }
+
+    #[actix_rt::test]
+    async fn test_persist_paused_jobs() {
+        let dir = std::env::temp_dir().join(format!(
+            "mirakc-job-paused-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let json_path = dir.join("paused-jobs.json");
+
+        let mut config = Config::default();
+        config.epg.cache_dir = Some(dir.to_str().unwrap().to_string());
+        config.jobs.persist_paused = true;
+
+        let mut manager = create_manager(config.clone());
+        assert_matches!(manager.pause_job("sync-clocks"), Ok(()));
+        assert!(json_path.exists());
+
+        let mut manager = create_manager(config.clone());
+        assert_matches!(manager.load_paused_jobs(), Ok(()));
+        assert!(manager.paused_jobs.contains(&JobKind::SyncClocks));
+        assert_eq!(manager.paused_jobs.len(), 1);
+
+        assert_matches!(manager.resume_job("sync-clocks"), Ok(()));
+        let mut manager = create_manager(config);
+        assert_matches!(manager.load_paused_jobs(), Ok(()));
+        assert!(manager.paused_jobs.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_persist_paused_jobs_disabled() {
+        let dir = std::env::temp_dir().join(format!(
+            "mirakc-job-paused-disabled-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let json_path = dir.join("paused-jobs.json");
+
+        let mut config = Config::default();
+        config.epg.cache_dir = Some(dir.to_str().unwrap().to_string());
+        config.jobs.persist_paused = false;
+
+        let mut manager = create_manager(config.clone());
+        assert_matches!(manager.pause_job("sync-clocks"), Ok(()));
+        assert!(manager.paused_jobs.contains(&JobKind::SyncClocks));
+        assert!(!json_path.exists());
+
+        let mut manager = create_manager(config);
+        assert_matches!(manager.load_paused_jobs(), Ok(()));
+        assert!(manager.paused_jobs.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
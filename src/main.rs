@@ -1,22 +1,42 @@
+mod access_log;
 mod airtime_tracker;
+mod archiver;
+mod arib_genres;
 mod broadcaster;
 mod chunk_stream;
 mod clock_synchronizer;
 mod command_util;
 mod config;
+mod config_reloader;
 mod datetime_ext;
 mod eit_feeder;
 mod epg;
 mod error;
+mod health;
 //mod fs_util;
 mod job;
+mod metrics;
 mod models;
 mod mpeg_ts_stream;
+mod named_filters;
+mod pcr_indexer;
+mod pid_analyzer;
+mod program_enricher;
+mod psi_cache;
+mod recording;
+mod retry;
+mod service_filter;
 mod service_scanner;
+mod stream_stats;
 mod tokio_snippet;
 mod tracing_ext;
+mod thumbnail;
 mod tuner;
 mod web;
+mod ws_relay;
+mod xmltv;
+
+use std::sync::Arc;
 
 use clap;
 
@@ -39,8 +59,8 @@ async fn main() -> Result<(), Error> {
                  "Path to a configuration file in a YAML format.\n\
                   \n\
                   The MIRAKC_CONFIG environment variable is used if this \
-                  option is not specified.  Its value has to be an absolute \
-                  path.\n\
+                  option is not specified.  A relative path is resolved \
+                  against the current directory.\n\
                   \n\
                   See README.md for details of the YAML format."))
         .arg(clap::Arg::with_name("log-format")
@@ -51,6 +71,16 @@ async fn main() -> Result<(), Error> {
              .possible_values(&["text", "json"])
              .default_value("text")
              .help("Logging format"))
+        .arg(clap::Arg::with_name("allow-no-tuners")
+             .long("allow-no-tuners")
+             .help("Start even if no tuner is enabled")
+             .long_help(
+                 "Start even if no tuner is enabled.\n\
+                  \n\
+                  By default, mirakc exits if no tuner is enabled in the \
+                  configuration file.  This option is useful when mirakc is \
+                  used only for serving EPG data imported from other \
+                  sources."))
         .get_matches();
 
     init_tracing(args.value_of("log-format").unwrap());
@@ -60,6 +90,12 @@ async fn main() -> Result<(), Error> {
 
     let config = config::load(config_path);
 
+    if !config.has_enabled_tuners() && !args.is_present("allow-no-tuners") {
+        panic!("No tuner is enabled in {}, specify --allow-no-tuners if this \
+                is intended", config_path);
+    }
+
+
     let tuner_manager = tuner::start(config.clone());
 
     let epg = epg::start(config.clone());
@@ -67,10 +103,34 @@ async fn main() -> Result<(), Error> {
     let eit_feeder = eit_feeder::start(
         config.clone(), tuner_manager.clone(), epg.clone());
 
-    let _job_manager = job::start(
-        config.clone(), tuner_manager.clone(), epg.clone(), eit_feeder.clone());
+    let named_filters =
+        Arc::new(named_filters::NamedFilters::new(config.clone()));
+
+    let recording_manager = recording::start(
+        config.clone(), tuner_manager.clone(), epg.clone(),
+        named_filters.clone());
+
+    let job_manager = job::start(
+        config.clone(), tuner_manager.clone(), epg.clone(), eit_feeder.clone(),
+        recording_manager.clone());
+
+    let _archiver = archiver::start(config.clone(), tuner_manager.clone());
+
+    let _config_reloader = config_reloader::start(
+        config_path.to_string(), config.clone(), tuner_manager.clone(),
+        epg.clone(), eit_feeder.clone(), job_manager.clone());
+
+    let _health_checker = health::start(
+        config.clone(), tuner_manager.clone(), epg.clone(),
+        recording_manager.clone());
+
+    web::serve(config.clone(), tuner_manager.clone(), epg.clone(),
+               recording_manager.clone(), job_manager.clone(),
+               named_filters.clone()).await?;
 
-    web::serve(config.clone(), tuner_manager.clone(), epg.clone()).await?;
+    // The web server stops on SIGINT or SIGTERM.  Save schedules which may
+    // have been updated after the last save.
+    let _ = epg.send(epg::SaveSchedulesMessage).await;
 
     Ok(())
 }
@@ -0,0 +1,221 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::epg::EpgStatus;
+use crate::models::MirakurunTuner;
+
+// Metrics exported from the /metrics endpoint in the Prometheus text format.
+//
+// Counters are updated by actors without messaging.  Other metrics are
+// collected from the existing stats of actors when the endpoint is requested,
+// which never acquires any tuner.
+
+static DROPPED_CHUNKS: AtomicU64 = AtomicU64::new(0);
+static DROPPED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+// Called by broadcasters when a chunk is dropped for a subscriber.
+pub fn add_dropped_chunk(len: usize) {
+    DROPPED_CHUNKS.fetch_add(1, Ordering::Relaxed);
+    DROPPED_BYTES.fetch_add(len as u64, Ordering::Relaxed);
+}
+
+pub fn render(tuners: &[MirakurunTuner], epg_status: &EpgStatus) -> String {
+    let mut writer = MetricsWriter::default();
+
+    writer.header("mirakc_tuners", "gauge",
+                  "Number of available tuners");
+    writer.sample("mirakc_tuners", &[],
+                  tuners.iter().filter(|tuner| tuner.is_available).count());
+
+    writer.header("mirakc_tuners_in_use", "gauge",
+                  "Number of tuners in use");
+    writer.sample("mirakc_tuners_in_use", &[],
+                  tuners.iter().filter(|tuner| tuner.is_using).count());
+
+    writer.header("mirakc_broadcaster_subscribers", "gauge",
+                  "Number of subscribers of the broadcaster of each tuner");
+    for tuner in tuners.iter().filter(|tuner| tuner.is_using) {
+        writer.sample("mirakc_broadcaster_subscribers",
+                      &[("tuner", &tuner.name)], tuner.users.len());
+    }
+
+    writer.header("mirakc_broadcaster_dropped_chunks_total", "counter",
+                  "Number of chunks dropped by broadcasters");
+    writer.sample("mirakc_broadcaster_dropped_chunks_total", &[],
+                  DROPPED_CHUNKS.load(Ordering::Relaxed));
+
+    writer.header("mirakc_broadcaster_dropped_bytes_total", "counter",
+                  "Number of bytes dropped by broadcasters");
+    writer.sample("mirakc_broadcaster_dropped_bytes_total", &[],
+                  DROPPED_BYTES.load(Ordering::Relaxed));
+
+    writer.header("mirakc_eit_sections_collected", "gauge",
+                  "Number of EIT sections collected in the last run");
+    writer.sample("mirakc_eit_sections_collected", &[],
+                  epg_status.eit_collection.iter()
+                  .map(|stats| stats.num_sections)
+                  .sum::<usize>());
+
+    writer.header("mirakc_eit_collection_duration_seconds", "gauge",
+                  "Time spent for collecting EIT sections in each channel \
+                   in the last run");
+    for stats in epg_status.eit_collection.iter() {
+        let channel_type = stats.channel_type.to_string();
+        let seconds = stats.duration.num_milliseconds() as f64 / 1000.0;
+        writer.sample("mirakc_eit_collection_duration_seconds",
+                      &[("channel_type", &channel_type),
+                        ("channel", &stats.channel),
+                        ("name", &stats.channel_name)],
+                      seconds);
+    }
+
+    writer.header("mirakc_service_scan_duration_seconds", "gauge",
+                  "Time spent for scanning services in each channel in the \
+                   last run");
+    for stats in epg_status.service_scan.iter() {
+        let channel_type = stats.channel_type.to_string();
+        let seconds = stats.duration.num_milliseconds() as f64 / 1000.0;
+        writer.sample("mirakc_service_scan_duration_seconds",
+                      &[("channel_type", &channel_type),
+                        ("channel", &stats.channel),
+                        ("name", &stats.channel_name)],
+                      seconds);
+    }
+
+    writer.0
+}
+
+#[derive(Default)]
+struct MetricsWriter(String);
+
+impl MetricsWriter {
+    fn header(&mut self, name: &str, metric_type: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, metric_type);
+    }
+
+    fn sample<T: std::fmt::Display>(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: T,
+    ) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, Self::escape(v)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+
+    // See "Text format details" in the Prometheus documents.
+    fn escape(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datetime_ext::Jst;
+    use crate::eit_feeder::EitCollectionStats;
+    use crate::models::*;
+    use crate::service_scanner::ServiceScanStats;
+
+    #[test]
+    fn test_render() {
+        let tuners = vec![
+            create_tuner("free", vec![]),
+            create_tuner("busy", vec!["user1", "user2"]),
+        ];
+        let epg_status = EpgStatus {
+            ready: true,
+            eit_collection: vec![
+                EitCollectionStats {
+                    channel_name: "ch\"1\"".to_string(),
+                    channel_type: ChannelType::GR,
+                    channel: "27".to_string(),
+                    started_at: Jst::now(),
+                    duration: chrono::Duration::milliseconds(1500),
+                    num_sections: 10,
+                },
+                EitCollectionStats {
+                    channel_name: "ch2".to_string(),
+                    channel_type: ChannelType::BS,
+                    channel: "BS1_0".to_string(),
+                    started_at: Jst::now(),
+                    duration: chrono::Duration::seconds(3),
+                    num_sections: 5,
+                },
+            ],
+            service_scan: vec![
+                ServiceScanStats {
+                    channel_name: "ch2".to_string(),
+                    channel_type: ChannelType::BS,
+                    channel: "BS1_0".to_string(),
+                    started_at: Jst::now(),
+                    duration: chrono::Duration::milliseconds(250),
+                    num_services: Some(2),
+                },
+            ],
+            invalid_sections: vec![],
+        };
+
+        let text = render(&tuners, &epg_status);
+        let lines: Vec<&str> = text.lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect();
+        assert!(lines.contains(&"mirakc_tuners 2"));
+        assert!(lines.contains(&"mirakc_tuners_in_use 1"));
+        assert!(lines.contains(
+            &r#"mirakc_broadcaster_subscribers{tuner="busy"} 2"#));
+        assert!(!text.contains(r#"tuner="free""#));
+        assert!(lines.contains(&"mirakc_eit_sections_collected 15"));
+        assert!(lines.contains(&concat!(
+            r#"mirakc_eit_collection_duration_seconds{channel_type="GR","#,
+            r#"channel="27",name="ch\"1\""} 1.5"#)));
+        assert!(lines.contains(&concat!(
+            r#"mirakc_eit_collection_duration_seconds{channel_type="BS","#,
+            r#"channel="BS1_0",name="ch2"} 3"#)));
+        assert!(lines.contains(&concat!(
+            r#"mirakc_service_scan_duration_seconds{channel_type="BS","#,
+            r#"channel="BS1_0",name="ch2"} 0.25"#)));
+        assert!(text.contains(
+            "# TYPE mirakc_broadcaster_dropped_chunks_total counter\n"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(MetricsWriter::escape(r#"a\b"c"#), r#"a\\b\"c"#);
+        assert_eq!(MetricsWriter::escape("a\nb"), r#"a\nb"#);
+    }
+
+    fn create_tuner(name: &str, users: Vec<&str>) -> MirakurunTuner {
+        MirakurunTuner {
+            index: 0,
+            name: name.to_string(),
+            channel_types: vec![ChannelType::GR],
+            command: None,
+            pid: None,
+            users: users.iter().map(|id| MirakurunTunerUser {
+                id: id.to_string(),
+                agent: None,
+                priority: 0,
+                expected_end_at: None,
+            }).collect(),
+            is_available: true,
+            is_remote: false,
+            is_free: users.is_empty(),
+            is_using: !users.is_empty(),
+            is_fault: false,
+            priority: 0,
+            expected_free_at: None,
+            signal_stats: None,
+        }
+    }
+}
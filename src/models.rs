@@ -4,6 +4,7 @@ use chrono::{DateTime, Duration};
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
+use crate::config::DropPolicy;
 use crate::datetime_ext::{serde_jst, serde_duration_in_millis, Jst};
 use crate::epg::{EpgChannel, EpgService, EpgProgram};
 use crate::mpeg_ts_stream::MpegTsStreamId;
@@ -12,6 +13,11 @@ use crate::mpeg_ts_stream::MpegTsStreamId;
 #[derive(Deserialize, Serialize)]
 pub enum ChannelType { GR, BS, CS, SKY }
 
+impl ChannelType {
+    pub const ALL: [ChannelType; 4] =
+        [ChannelType::GR, ChannelType::BS, ChannelType::CS, ChannelType::SKY];
+}
+
 impl fmt::Display for ChannelType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
@@ -216,9 +222,13 @@ pub struct Clock {
     pub pcr: i64,
     // UNIX time in ms
     pub time: i64,
+    // UNIX time in ms of the system clock when the clock was synchronized.
+    // The sync-clocks job fills it if the command doesn't output it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub synced_at: Option<i64>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EpgVideoInfo {
@@ -227,15 +237,22 @@ pub struct EpgVideoInfo {
     pub resolution: Option<String>,
     pub stream_content: u8,
     pub component_type: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub component_tag: Option<u8>,
 }
 
 impl EpgVideoInfo {
-    pub fn new(stream_content: u8, component_type: u8) -> EpgVideoInfo {
+    pub fn new(
+        stream_content: u8,
+        component_type: u8,
+        component_tag: Option<u8>,
+    ) -> EpgVideoInfo {
         EpgVideoInfo {
             video_type: Self::get_video_type(stream_content),
             resolution: Self::get_resolution(component_type),
             stream_content,
             component_type,
+            component_tag,
         }
     }
 
@@ -264,19 +281,26 @@ impl EpgVideoInfo {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EpgAudioInfo {
     pub sampling_rate: i32,
     pub component_type: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub component_tag: Option<u8>,
 }
 
 impl EpgAudioInfo {
-    pub fn new(component_type: u8, sampling_rate: u8) -> EpgAudioInfo {
+    pub fn new(
+        component_type: u8,
+        sampling_rate: u8,
+        component_tag: Option<u8>,
+    ) -> EpgAudioInfo {
         EpgAudioInfo {
             component_type,
             sampling_rate: Self::get_sampling_rate(sampling_rate),
+            component_tag,
         }
     }
 
@@ -293,7 +317,7 @@ impl EpgAudioInfo {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EpgGenre {
@@ -376,12 +400,24 @@ impl fmt::Display for TunerUserPriority {
 pub struct TunerUser {
     pub info: TunerUserInfo,
     pub priority: TunerUserPriority,
+    // `None` means that the user doesn't know when it stops using the tuner.
+    pub expected_end_at: Option<DateTime<Jst>>,
+    pub drop_policy: DropPolicy,
+    // Starts streaming from chunks kept in the timeshift buffer of the
+    // broadcaster for this duration.
+    pub timeshift: Option<std::time::Duration>,
 }
 
 impl TunerUser {
     pub fn get_model(&self) -> MirakurunTunerUser {
         let (id, agent) = self.info.get_model();
-        MirakurunTunerUser { id, agent, priority: self.priority.0 }
+        MirakurunTunerUser {
+            id,
+            agent,
+            priority: self.priority.0,
+            expected_end_at: self.expected_end_at
+                .map(|datetime| datetime.timestamp_millis()),
+        }
     }
 }
 
@@ -426,6 +462,12 @@ impl fmt::Display for MirakurunServiceId {
     }
 }
 
+impl From<u64> for MirakurunServiceId {
+    fn from(value: u64) -> Self {
+        MirakurunServiceId(value)
+    }
+}
+
 impl From<(NetworkId, ServiceId)> for MirakurunServiceId {
     fn from((nid, sid): (NetworkId, ServiceId)) -> Self {
         Self::new(nid, sid)
@@ -465,6 +507,10 @@ impl MirakurunProgramId {
                 + sid.value() as u64 * Self::MAGIC_NUMBER + eid.value() as u64)
     }
 
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
     pub fn nid(&self) -> NetworkId {
         NetworkId::from(
             (self.0 / (Self::MAGIC_NUMBER * Self::MAGIC_NUMBER)) as u16)
@@ -508,6 +554,24 @@ pub struct MirakurunTuner {
     pub is_free: bool,
     pub is_using: bool,
     pub is_fault: bool,
+    // mirakc extensions
+    pub priority: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_free_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal_stats: Option<TunerSignalStats>,
+}
+
+// Signal statistics parsed from the stderr of a tuner command.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunerSignalStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snr: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ber: Option<f64>,
+    pub updated_at: i64,  // UNIX time in milliseconds
 }
 
 #[derive(Debug)]
@@ -518,6 +582,9 @@ pub struct MirakurunTunerUser {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub agent: Option<String>,
     pub priority: i32,
+    // mirakc extension
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_end_at: Option<i64>,
 }
 
 #[derive(Debug)]
@@ -618,6 +685,11 @@ pub struct MirakurunProgram {
     pub audio: Option<EpgAudioInfo>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub genres: Option<Vec<EpgGenre>>,
+    // mirakc extensions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub videos: Vec<EpgVideoInfo>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audios: Vec<EpgAudioInfo>,
 }
 
 impl From<EpgProgram> for MirakurunProgram {
@@ -633,9 +705,122 @@ impl From<EpgProgram> for MirakurunProgram {
             name: program.name,
             description: program.description,
             extended: program.extended,
-            video: program.video,
-            audio: program.audio,
+            video: program.video(),
+            audio: program.audio(),
+            genres: program.genres,
+            videos: program.videos,
+            audios: program.audios,
+        }
+    }
+}
+
+// A program in the exact shape of Mirakurun's program object.
+//
+// mirakc extensions are removed and properties are renamed or converted so
+// that clients targeting Mirakurun can use it unchanged.  Some properties are
+// approximated because mirakc doesn't keep the original information:
+//
+// * `audios[].isMain` is true only for the first audio component
+// * `audios[].langs` is always empty
+// * `audios[].componentTag` is omitted if the EIT command doesn't output it
+// * `relatedItems` and `series` are never included
+#[derive(Clone, Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MirakurunCompatProgram {
+    pub id: MirakurunProgramId,
+    pub event_id: EventId,
+    pub service_id: ServiceId,
+    pub network_id: NetworkId,
+    #[serde(with = "serde_jst")]
+    pub start_at: DateTime<Jst>,
+    #[serde(with = "serde_duration_in_millis")]
+    pub duration: Duration,
+    pub is_free: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub genres: Option<Vec<EpgGenre>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video: Option<MirakurunCompatVideo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audios: Option<Vec<MirakurunCompatAudio>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extended: Option<IndexMap<String, String>>,
+}
+
+impl From<MirakurunProgram> for MirakurunCompatProgram {
+    fn from(program: MirakurunProgram) -> Self {
+        let audios = if program.audios.is_empty() {
+            None
+        } else {
+            Some(program.audios.into_iter()
+                 .enumerate()
+                 .map(|(i, audio)| MirakurunCompatAudio::new(audio, i == 0))
+                 .collect())
+        };
+        Self {
+            id: program.id,
+            event_id: program.event_id,
+            service_id: program.service_id,
+            network_id: program.network_id,
+            start_at: program.start_at,
+            duration: program.duration,
+            is_free: program.is_free,
+            name: program.name,
+            description: program.description,
             genres: program.genres,
+            video: program.video.map(MirakurunCompatVideo::from),
+            audios,
+            extended: program.extended,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MirakurunCompatVideo {
+    #[serde(rename = "type")]
+    pub video_type: Option<String>,
+    pub resolution: Option<String>,
+    pub stream_content: u8,
+    pub component_type: u8,
+}
+
+impl From<EpgVideoInfo> for MirakurunCompatVideo {
+    fn from(video: EpgVideoInfo) -> Self {
+        Self {
+            video_type: video.video_type,
+            resolution: video.resolution,
+            stream_content: video.stream_content,
+            component_type: video.component_type,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MirakurunCompatAudio {
+    pub component_type: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub component_tag: Option<u8>,
+    pub is_main: bool,
+    pub sampling_rate: i32,
+    pub langs: Vec<String>,
+}
+
+impl MirakurunCompatAudio {
+    fn new(audio: EpgAudioInfo, is_main: bool) -> Self {
+        Self {
+            component_type: audio.component_type,
+            component_tag: audio.component_tag,
+            is_main,
+            sampling_rate: audio.sampling_rate,
+            langs: Vec::new(),
         }
     }
 }
@@ -713,4 +898,44 @@ mod tests {
         assert_eq!(sid, id.sid());
         assert_eq!(eid, id.eid());
     }
+
+    #[test]
+    fn test_mirakurun_compat_program() {
+        let mut program = EpgProgram::new((1, 2, 3, 4).into());
+        program.videos = vec![EpgVideoInfo::new(0x01, 0xB3, Some(0))];
+        program.audios = vec![
+            EpgAudioInfo::new(0x03, 7, Some(0x10)),
+            EpgAudioInfo::new(0x02, 7, None),
+        ];
+
+        let program =
+            MirakurunCompatProgram::from(MirakurunProgram::from(program));
+        assert_eq!(serde_json::to_value(&program).unwrap(), serde_json::json!({
+            "id": 1_00003_00004u64,
+            "eventId": 4,
+            "serviceId": 3,
+            "networkId": 1,
+            "startAt": 0,
+            "duration": 0,
+            "isFree": true,
+            "video": {
+                "type": "mpeg2",
+                "resolution": "1080i",
+                "streamContent": 1,
+                "componentType": 0xB3,
+            },
+            "audios": [{
+                "componentType": 3,
+                "componentTag": 0x10,
+                "isMain": true,
+                "samplingRate": 48000,
+                "langs": [],
+            }, {
+                "componentType": 2,
+                "isMain": false,
+                "samplingRate": 48000,
+                "langs": [],
+            }],
+        }));
+    }
 }
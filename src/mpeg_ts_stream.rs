@@ -1,3 +1,4 @@
+use std::future::Future;
 use std::io;
 use std::pin::Pin;
 
@@ -5,15 +6,22 @@ use actix::prelude::*;
 use bytes::Bytes;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::stream::{Stream, StreamExt};
+use tokio::sync::oneshot;
 
 use crate::broadcaster::BroadcasterStream;
-use crate::tuner::StopStreamingMessage;
+use crate::epg::{EpgChannel, QueryChannelMessage};
+use crate::error::Error;
+use crate::models::{ServiceId, TunerUser};
+use crate::service_filter::ServiceFilter;
+use crate::tuner::{FailoverStreamingMessage, StopStreamingMessage};
 pub use crate::tuner::TunerSubscriptionId as MpegTsStreamId;
 
 pub struct MpegTsStream {
     id: MpegTsStreamId,
     stream: BroadcasterStream,
     stop_trigger: Option<MpegTsStreamStopTrigger>,
+    failover: Option<Box<MpegTsStreamFailover>>,
+    service_filter: Option<ServiceFilter>,
 }
 
 impl MpegTsStream {
@@ -24,10 +32,32 @@ impl MpegTsStream {
     ) -> Self {
         MpegTsStream {
             id, stream,
-            stop_trigger: Some(MpegTsStreamStopTrigger::new(id, recipient))
+            stop_trigger: Some(MpegTsStreamStopTrigger::new(id, recipient)),
+            failover: None,
+            service_filter: None,
         }
     }
 
+    // When the tuner fails in the middle of streaming, the stream is resumed
+    // on another tuner for the same channel.  See `MpegTsStreamFailover` for
+    // details.
+    pub fn enable_failover(
+        &mut self,
+        channel: EpgChannel,
+        user: TunerUser,
+        recipient: Recipient<FailoverStreamingMessage>,
+        channel_resolver: Recipient<QueryChannelMessage>,
+    ) {
+        self.failover = Some(Box::new(MpegTsStreamFailover::new(
+            channel, user, recipient, channel_resolver)));
+    }
+
+    // Only TS packets of the service are streamed.  This is applied before any
+    // filter in a filter pipeline.  See `ServiceFilter` for details.
+    pub fn enable_service_filter(&mut self, sid: ServiceId) {
+        self.service_filter = Some(ServiceFilter::new(sid.value()));
+    }
+
     pub fn id(&self) -> MpegTsStreamId {
         self.id
     }
@@ -42,16 +72,189 @@ impl MpegTsStream {
     {
         pipe(self, writer).await;
     }
+
+    fn poll_next_unfiltered(
+        &mut self,
+        cx: &mut std::task::Context
+    ) -> std::task::Poll<Option<io::Result<Bytes>>> {
+        use std::task::Poll;
+
+        loop {
+            if let Some(ref mut failover) = self.failover {
+                if let Some(ref mut receiver) = failover.receiver {
+                    let result = match Pin::new(receiver).poll(cx) {
+                        Poll::Ready(result) => result,
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    failover.receiver = None;
+                    match result {
+                        Ok(Ok(Ok(mut stream))) => {
+                            log::info!("{}: Resumed on {}", self.id, stream.id);
+                            failover.stop_trigger = stream.take_stop_trigger();
+                            failover.resync = true;
+                            if let Some(ref mut filter) = self.service_filter {
+                                filter.reset();
+                            }
+                            self.id = stream.id;
+                            self.stream = stream.stream;
+                        }
+                        Ok(Ok(Err(err))) => {
+                            log::error!("{}: Failover failed: {}",
+                                        self.id, err);
+                            return Poll::Ready(None);
+                        }
+                        Ok(Err(err)) => {
+                            log::error!("{}: Failover failed: {}",
+                                        self.id, err);
+                            return Poll::Ready(None);
+                        }
+                        Err(_) => {
+                            log::error!("{}: Failover canceled", self.id);
+                            return Poll::Ready(None);
+                        }
+                    }
+                }
+            }
+
+            // The stream simply ends when the tuner is stopped normally, e.g.
+            // preempted by another user.
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(Err(err))) => match self.failover {
+                    Some(ref mut failover) if failover.remaining > 0 => {
+                        log::warn!("{}: Tuner failed, failover...: {}",
+                                   self.id, err);
+                        failover.start(self.id);
+                    }
+                    _ => return Poll::Ready(Some(Err(err))),
+                },
+                Poll::Ready(Some(Ok(chunk))) => match self.failover {
+                    Some(ref mut failover) if failover.resync => {
+                        let pos = MpegTsStreamFailover::find_pat(&chunk);
+                        if let Some(pos) = pos {
+                            failover.resync = false;
+                            return Poll::Ready(Some(Ok(chunk.slice(pos..))));
+                        }
+                    }
+                    _ => return Poll::Ready(Some(Ok(chunk))),
+                },
+                poll => return poll,
+            }
+        }
+    }
 }
 
 impl Stream for MpegTsStream {
     type Item = io::Result<Bytes>;
 
     fn poll_next(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut std::task::Context
     ) -> std::task::Poll<Option<Self::Item>> {
-        Pin::new(&mut self.stream).poll_next(cx)
+        use std::task::Poll;
+
+        let this = self.get_mut();
+        loop {
+            let chunk = match this.poll_next_unfiltered(cx) {
+                Poll::Ready(Some(Ok(chunk))) => chunk,
+                poll => return poll,
+            };
+            match this.service_filter {
+                Some(ref mut filter) => {
+                    let chunk = filter.feed(&chunk);
+                    if !chunk.is_empty() {
+                        return Poll::Ready(Some(Ok(Bytes::from(chunk))));
+                    }
+                    // Wait for packets of the service.
+                }
+                None => return Poll::Ready(Some(Ok(chunk))),
+            }
+        }
+    }
+}
+
+// failover
+//
+// The failed subscription is stopped and a new subscription is started on
+// another tuner.  The downstream of the stream like a filter pipeline and a
+// client connection is kept.
+//
+// The current definition of the channel is used for the new subscription.
+// The failover fails if the channel has been removed or disabled.
+//
+// Packets from the new tuner are discarded until a TS packet which starts a
+// PAT section comes, so that the downstream receives fresh PSI before other
+// packets.  The continuity of TS packets is broken at that point.
+
+type FailoverResult = Result<Result<MpegTsStream, Error>, MailboxError>;
+
+struct MpegTsStreamFailover {
+    channel: EpgChannel,
+    user: TunerUser,
+    recipient: Recipient<FailoverStreamingMessage>,
+    channel_resolver: Recipient<QueryChannelMessage>,
+    remaining: usize,
+    receiver: Option<oneshot::Receiver<FailoverResult>>,
+    // A stop trigger of the current subscription after a failover.
+    stop_trigger: Option<MpegTsStreamStopTrigger>,
+    resync: bool,
+}
+
+impl MpegTsStreamFailover {
+    const MAX_FAILOVERS: usize = 3;
+    const PACKET_SIZE: usize = 188;
+    const SYNC_BYTE: u8 = 0x47;
+
+    fn new(
+        channel: EpgChannel,
+        user: TunerUser,
+        recipient: Recipient<FailoverStreamingMessage>,
+        channel_resolver: Recipient<QueryChannelMessage>,
+    ) -> Self {
+        MpegTsStreamFailover {
+            channel,
+            user,
+            recipient,
+            channel_resolver,
+            remaining: Self::MAX_FAILOVERS,
+            receiver: None,
+            stop_trigger: None,
+            resync: false,
+        }
+    }
+
+    fn start(&mut self, id: MpegTsStreamId) {
+        self.remaining -= 1;
+        let query = QueryChannelMessage {
+            channel_type: self.channel.channel_type,
+            channel: self.channel.channel.clone(),
+        };
+        let user = self.user.clone();
+        let recipient = self.recipient.clone();
+        let channel_resolver = self.channel_resolver.clone();
+        let (sender, receiver) = oneshot::channel();
+        actix::spawn(async move {
+            let result = match channel_resolver.send(query).await {
+                Ok(Ok(channel)) => {
+                    let msg = FailoverStreamingMessage { id, channel, user };
+                    recipient.send(msg).await
+                }
+                Ok(Err(err)) => Ok(Err(err)),
+                Err(err) => Err(err),
+            };
+            let _ = sender.send(result);
+        });
+        self.receiver = Some(receiver);
+    }
+
+    // Returns the position of the first TS packet which starts a PAT section.
+    fn find_pat(data: &[u8]) -> Option<usize> {
+        (0..data.len().saturating_sub(2)).find(|&i| {
+            data[i] == Self::SYNC_BYTE &&
+                data[i + 1] & 0x40 != 0 &&  // payload_unit_start_indicator
+                data[i + 1] & 0x1F == 0 && data[i + 2] == 0 &&  // PID
+                data.get(i + Self::PACKET_SIZE)
+                    .map_or(true, |&b| b == Self::SYNC_BYTE)
+        })
     }
 }
 
@@ -170,6 +373,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::{ChannelType, TunerUserInfo};
 
     #[actix_rt::test]
     async fn test_pipe() {
@@ -189,6 +393,104 @@ mod tests {
         let _ = handle.await.unwrap();
     }
 
+    #[actix_rt::test]
+    async fn test_failover() {
+        let (mut tx1, failed, stream1) =
+            BroadcasterStream::new_for_failure_test();
+        let (mut tx2, stream2) = BroadcasterStream::new_for_test();
+
+        let addr = FailoverTestActor::new(Some(stream2)).start();
+
+        let mut stream = MpegTsStream::new(
+            Default::default(), stream1, addr.clone().recipient());
+        stream.enable_failover(
+            create_channel(), create_user(), addr.clone().recipient(),
+            addr.clone().recipient());
+
+        let result = tx1.send(Bytes::from("hello")).await;
+        assert!(result.is_ok());
+        assert_eq!(stream.next().await.unwrap().unwrap(), "hello");
+
+        // The tuner fails.
+        failed.store(true, std::sync::atomic::Ordering::SeqCst);
+        drop(tx1);
+
+        let mut pat = vec![0xFF; 188];
+        pat[0] = 0x47;
+        pat[1] = 0x40;
+        pat[2] = 0x00;
+        let mut data = vec![0xFF, 0xFF];
+        data.extend_from_slice(&pat);
+        let result = tx2.send(Bytes::from("junk")).await;
+        assert!(result.is_ok());
+        let result = tx2.send(Bytes::from(data)).await;
+        assert!(result.is_ok());
+        assert_eq!(stream.next().await.unwrap().unwrap(), pat);
+
+        // The current definition of the channel has been used.
+        let channel = addr.send(TakeFailoverChannel).await.unwrap();
+        assert_eq!(channel.unwrap().extra_args, "--updated");
+
+        // The stream ends when the tuner stops normally.
+        drop(tx2);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_failover_not_started_on_normal_end() {
+        let (tx1, stream1) = BroadcasterStream::new_for_test();
+        let (_tx2, stream2) = BroadcasterStream::new_for_test();
+
+        let addr = FailoverTestActor::new(Some(stream2)).start();
+
+        let mut stream = MpegTsStream::new(
+            Default::default(), stream1, addr.clone().recipient());
+        stream.enable_failover(
+            create_channel(), create_user(), addr.clone().recipient(),
+            addr.clone().recipient());
+
+        // The tuner is stopped normally, e.g. preempted by another user.
+        drop(tx1);
+        assert!(stream.next().await.is_none());
+
+        let channel = addr.send(TakeFailoverChannel).await.unwrap();
+        assert!(channel.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_failover_no_more_tuner() {
+        let (tx1, failed, stream1) = BroadcasterStream::new_for_failure_test();
+
+        let addr = FailoverTestActor::new(None).start();
+
+        let mut stream = MpegTsStream::new(
+            Default::default(), stream1, addr.clone().recipient());
+        stream.enable_failover(
+            create_channel(), create_user(), addr.clone().recipient(),
+            addr.clone().recipient());
+
+        failed.store(true, std::sync::atomic::Ordering::SeqCst);
+        drop(tx1);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn test_find_pat() {
+        let mut data = vec![0xFF; 188 * 2];
+        data[0] = 0x47;
+        data[188] = 0x47;
+        assert_eq!(MpegTsStreamFailover::find_pat(&data), None);
+        data[189] = 0x40;
+        assert_eq!(MpegTsStreamFailover::find_pat(&data), None);
+        data[190] = 0x00;
+        assert_eq!(MpegTsStreamFailover::find_pat(&data), Some(188));
+        // The next packet must start with the sync byte if it exists.
+        data[1] = 0x40;
+        data[2] = 0x00;
+        data[188] = 0xFF;
+        assert_eq!(MpegTsStreamFailover::find_pat(&data), None);
+    }
+
     struct TestActor;
 
     impl Actor for TestActor {
@@ -205,6 +507,106 @@ mod tests {
         ) -> Self::Result {}
     }
 
+    // Provides a stream only once.
+    struct FailoverTestActor {
+        stream: Option<BroadcasterStream>,
+        // The channel in the last `FailoverStreamingMessage`.
+        channel: Option<EpgChannel>,
+    }
+
+    impl FailoverTestActor {
+        fn new(stream: Option<BroadcasterStream>) -> Self {
+            FailoverTestActor { stream, channel: None }
+        }
+    }
+
+    impl Actor for FailoverTestActor {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<StopStreamingMessage> for FailoverTestActor {
+        type Result = ();
+
+        fn handle(
+            &mut self,
+            _: StopStreamingMessage,
+            _: &mut Self::Context,
+        ) -> Self::Result {}
+    }
+
+    impl Handler<FailoverStreamingMessage> for FailoverTestActor {
+        type Result = Result<MpegTsStream, Error>;
+
+        fn handle(
+            &mut self,
+            msg: FailoverStreamingMessage,
+            ctx: &mut Self::Context,
+        ) -> Self::Result {
+            self.channel = Some(msg.channel);
+            match self.stream.take() {
+                Some(stream) => Ok(MpegTsStream::new(
+                    Default::default(), stream, ctx.address().recipient())),
+                None => Err(Error::TunerUnavailable),
+            }
+        }
+    }
+
+    // Returns the channel updated after the stream started.
+    impl Handler<QueryChannelMessage> for FailoverTestActor {
+        type Result = Result<EpgChannel, Error>;
+
+        fn handle(
+            &mut self,
+            _: QueryChannelMessage,
+            _: &mut Self::Context,
+        ) -> Self::Result {
+            let mut channel = create_channel();
+            channel.extra_args = "--updated".to_string();
+            Ok(channel)
+        }
+    }
+
+    struct TakeFailoverChannel;
+
+    impl Message for TakeFailoverChannel {
+        type Result = Option<EpgChannel>;
+    }
+
+    impl Handler<TakeFailoverChannel> for FailoverTestActor {
+        type Result = Option<EpgChannel>;
+
+        fn handle(
+            &mut self,
+            _: TakeFailoverChannel,
+            _: &mut Self::Context,
+        ) -> Self::Result {
+            self.channel.take()
+        }
+    }
+
+    fn create_channel() -> EpgChannel {
+        EpgChannel {
+            name: "".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "1".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        }
+    }
+
+    fn create_user() -> TunerUser {
+        TunerUser {
+            info: TunerUserInfo::Job {
+                name: "test".to_string(),
+            },
+            priority: 0.into(),
+            expected_end_at: None,
+            drop_policy: Default::default(),
+            timeshift: None,
+        }
+    }
+
     struct TestWriter {
         buf: Vec<u8>,
         expected: &'static [u8],
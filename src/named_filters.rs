@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::config::{Config, FilterConfig, PostFilterConfig};
+use crate::error::Error;
+
+// Named filters changed at runtime with the /api/filters endpoints.
+//
+// Changes are kept only in memory as overrides of `pre-filters` and
+// `post-filters` in the config.  The config file is never modified.
+//
+// A filter pipeline is built from named filters when a stream request comes or
+// a recording starts.  So, changes affect only subsequent requests and
+// recordings, and streams already started keep using the filters at that time
+// until they end.
+//
+// An instance is created in `main()` and shared with the web endpoints and the
+// recording manager.
+pub struct NamedFilters {
+    config: Arc<Config>,
+    overrides: RwLock<Overrides>,
+}
+
+// `None` means that the filter has been removed.
+#[derive(Default)]
+struct Overrides {
+    pre_filters: HashMap<String, Option<FilterConfig>>,
+    post_filters: HashMap<String, Option<PostFilterConfig>>,
+}
+
+impl NamedFilters {
+    pub fn new(config: Arc<Config>) -> Self {
+        NamedFilters { config, overrides: Default::default() }
+    }
+
+    pub fn pre_filters(&self) -> HashMap<String, FilterConfig> {
+        merge(&self.config.pre_filters,
+              &self.overrides.read().unwrap().pre_filters)
+    }
+
+    pub fn post_filters(&self) -> HashMap<String, PostFilterConfig> {
+        merge(&self.config.post_filters,
+              &self.overrides.read().unwrap().post_filters)
+    }
+
+    pub fn put_pre_filter(
+        &self,
+        name: &str,
+        filter: FilterConfig,
+    ) -> Result<(), Error> {
+        validate(&filter.command)?;
+        log::info!("Put pre-filter({}): {}", name, filter.command);
+        self.overrides.write().unwrap().pre_filters
+            .insert(name.to_string(), Some(filter));
+        Ok(())
+    }
+
+    pub fn put_post_filter(
+        &self,
+        name: &str,
+        filter: PostFilterConfig,
+    ) -> Result<(), Error> {
+        validate(&filter.command)?;
+        log::info!("Put post-filter({}): {}", name, filter.command);
+        self.overrides.write().unwrap().post_filters
+            .insert(name.to_string(), Some(filter));
+        Ok(())
+    }
+
+    pub fn remove_pre_filter(&self, name: &str) -> Result<(), Error> {
+        let mut overrides = self.overrides.write().unwrap();
+        remove(&self.config.pre_filters, &mut overrides.pre_filters, name)?;
+        log::info!("Removed pre-filter({})", name);
+        Ok(())
+    }
+
+    pub fn remove_post_filter(&self, name: &str) -> Result<(), Error> {
+        let mut overrides = self.overrides.write().unwrap();
+        remove(&self.config.post_filters, &mut overrides.post_filters, name)?;
+        log::info!("Removed post-filter({})", name);
+        Ok(())
+    }
+}
+
+fn merge<T: Clone>(
+    filters: &HashMap<String, T>,
+    overrides: &HashMap<String, Option<T>>,
+) -> HashMap<String, T> {
+    let mut filters = filters.clone();
+    for (name, filter) in overrides.iter() {
+        match filter {
+            Some(filter) => filters.insert(name.clone(), filter.clone()),
+            None => filters.remove(name),
+        };
+    }
+    filters
+}
+
+fn remove<T>(
+    filters: &HashMap<String, T>,
+    overrides: &mut HashMap<String, Option<T>>,
+    name: &str,
+) -> Result<(), Error> {
+    let exists = match overrides.get(name) {
+        Some(filter) => filter.is_some(),
+        None => filters.contains_key(name),
+    };
+    if !exists {
+        return Err(Error::FilterNotFound);
+    }
+    overrides.insert(name.to_string(), None);
+    Ok(())
+}
+
+// Only the syntax of the template is checked.  Variables used in the template
+// depend on the stream request.
+fn validate(command: &str) -> Result<(), Error> {
+    mustache::compile_str(command)
+        .map(|_| ())
+        .map_err(|err| Error::BadRequest(
+            format!("Invalid command template: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::*;
+
+    #[test]
+    fn test_merge() {
+        let mut filters = HashMap::new();
+        filters.insert("a".to_string(), 1);
+        filters.insert("b".to_string(), 2);
+
+        let mut overrides = HashMap::new();
+        overrides.insert("b".to_string(), None);
+        overrides.insert("c".to_string(), Some(3));
+        overrides.insert("d".to_string(), None);
+
+        let merged = merge(&filters, &overrides);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged["a"], 1);
+        assert_eq!(merged["c"], 3);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut filters = HashMap::new();
+        filters.insert("a".to_string(), 1);
+
+        let mut overrides = HashMap::new();
+        overrides.insert("b".to_string(), Some(2));
+
+        assert_matches!(remove(&filters, &mut overrides, "a"), Ok(()));
+        assert_matches!(remove(&filters, &mut overrides, "a"),
+                        Err(Error::FilterNotFound));
+        assert_matches!(remove(&filters, &mut overrides, "b"), Ok(()));
+        assert_matches!(remove(&filters, &mut overrides, "c"),
+                        Err(Error::FilterNotFound));
+        assert!(merge(&filters, &overrides).is_empty());
+    }
+
+    #[test]
+    fn test_put_pre_filter() {
+        let mut config = Config::default();
+        config.pre_filters.insert("a".to_string(), FilterConfig {
+            command: "a".to_string(),
+            process_limits: None,
+        });
+        let named_filters = NamedFilters::new(Arc::new(config));
+
+        let result = named_filters.put_pre_filter(
+            "a", FilterConfig {
+                command: "b {{sid}}".to_string(),
+                process_limits: None,
+            });
+        assert_matches!(result, Ok(()));
+        assert_eq!(named_filters.pre_filters()["a"].command, "b {{sid}}");
+
+        let result = named_filters.put_pre_filter(
+            "a", FilterConfig {
+                command: "c {{sid".to_string(),
+                process_limits: None,
+            });
+        assert_matches!(result, Err(Error::BadRequest(_)));
+        assert_eq!(named_filters.pre_filters()["a"].command, "b {{sid}}");
+
+        let result = named_filters.remove_pre_filter("a");
+        assert_matches!(result, Ok(()));
+        assert!(!named_filters.pre_filters().contains_key("a"));
+
+        // Other instances are not affected.
+        let config = named_filters.config.clone();
+        assert_eq!(NamedFilters::new(config).pre_filters()["a"].command, "a");
+    }
+}
@@ -0,0 +1,168 @@
+use chrono::DateTime;
+use serde::Serialize;
+
+use crate::datetime_ext::*;
+
+// A PCR indexer extracts PCR values from TS packets and makes index entries
+// which map PCR values to byte offsets in a TS stream.
+//
+// An index entry is made when the PCR advances more than `interval` since the
+// last index entry, or when a PCR discontinuity is detected.  The timestamp of
+// an index entry is the wall-clock time when the packet is processed.
+pub struct PcrIndexer {
+    offset: u64,
+    buf: Vec<u8>,
+    interval: u64,  // in 27MHz
+    last_pcr: Option<u64>,
+}
+
+impl PcrIndexer {
+    const PACKET_SIZE: usize = 188;
+    const SYNC_BYTE: u8 = 0x47;
+    const PCR_HZ: u64 = 27_000_000;
+
+    pub fn new(interval_millis: u64) -> Self {
+        PcrIndexer {
+            offset: 0,
+            buf: Vec::with_capacity(Self::PACKET_SIZE * 2),
+            interval: interval_millis * Self::PCR_HZ / 1000,
+            last_pcr: None,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Vec<PcrIndexEntry> {
+        let mut entries = Vec::new();
+
+        self.buf.extend_from_slice(data);
+
+        let mut pos = 0;
+        while self.buf.len() - pos >= Self::PACKET_SIZE {
+            if self.buf[pos] != Self::SYNC_BYTE {
+                // Resync.
+                pos += 1;
+                continue;
+            }
+            let packet = &self.buf[pos..(pos + Self::PACKET_SIZE)];
+            if let Some(pcr) = Self::parse_pcr(packet) {
+                let offset = self.offset + pos as u64;
+                let needed = match self.last_pcr {
+                    // Discontinuity including wrap-around.
+                    Some(last_pcr) if pcr < last_pcr => true,
+                    Some(last_pcr) => pcr - last_pcr >= self.interval,
+                    None => true,
+                };
+                if needed {
+                    entries.push(PcrIndexEntry {
+                        offset,
+                        pcr,
+                        timestamp: Jst::now(),
+                    });
+                    self.last_pcr = Some(pcr);
+                }
+            }
+            pos += Self::PACKET_SIZE;
+        }
+
+        self.buf.drain(..pos);
+        self.offset += pos as u64;
+
+        entries
+    }
+
+    fn parse_pcr(packet: &[u8]) -> Option<u64> {
+        let adaptation_field_control = (packet[3] >> 4) & 0x03;
+        if adaptation_field_control & 0x02 == 0 {
+            return None;  // no adaptation field
+        }
+        let adaptation_field_length = packet[4] as usize;
+        if adaptation_field_length < 7 {
+            return None;
+        }
+        if packet[5] & 0x10 == 0 {
+            return None;  // no PCR
+        }
+        let base = (packet[6] as u64) << 25 |
+            (packet[7] as u64) << 17 |
+            (packet[8] as u64) << 9 |
+            (packet[9] as u64) << 1 |
+            (packet[10] as u64) >> 7;
+        let ext = ((packet[10] as u64) & 0x01) << 8 | (packet[11] as u64);
+        Some(base * 300 + ext)
+    }
+}
+
+#[derive(Clone, Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PcrIndexEntry {
+    pub offset: u64,
+    pub pcr: u64,
+    #[serde(with = "serde_jst")]
+    pub timestamp: DateTime<Jst>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pcr() {
+        assert_eq!(PcrIndexer::parse_pcr(&create_packet(None)), None);
+        assert_eq!(PcrIndexer::parse_pcr(&create_packet(Some(0))), Some(0));
+        let pcr = 12345 * 300 + 67;
+        assert_eq!(PcrIndexer::parse_pcr(&create_packet(Some(pcr))), Some(pcr));
+    }
+
+    #[test]
+    fn test_feed() {
+        let mut indexer = PcrIndexer::new(1000);
+        let hz = PcrIndexer::PCR_HZ;
+
+        let mut data = Vec::new();
+        data.extend(create_packet(Some(0)));
+        data.extend(create_packet(None));
+        data.extend(create_packet(Some(hz / 2)));
+        data.extend(create_packet(Some(hz)));
+        data.extend(create_packet(Some(0)));  // discontinuity
+
+        // Feed data split at a position which is not aligned to packets.
+        let entries = indexer.feed(&data[..300]);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].offset, 0);
+        assert_eq!(entries[0].pcr, 0);
+
+        let entries = indexer.feed(&data[300..]);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].offset, 188 * 3);
+        assert_eq!(entries[0].pcr, hz);
+        assert_eq!(entries[1].offset, 188 * 4);
+        assert_eq!(entries[1].pcr, 0);
+    }
+
+    fn create_packet(pcr: Option<u64>) -> Vec<u8> {
+        let mut packet = vec![0xFF; PcrIndexer::PACKET_SIZE];
+        packet[0] = PcrIndexer::SYNC_BYTE;
+        packet[1] = 0x01;
+        packet[2] = 0x00;
+        match pcr {
+            Some(pcr) => {
+                let base = pcr / 300;
+                let ext = pcr % 300;
+                packet[3] = 0x30;  // adaptation field and payload
+                packet[4] = 7;
+                packet[5] = 0x10;  // PCR flag
+                packet[6] = (base >> 25) as u8;
+                packet[7] = (base >> 17) as u8;
+                packet[8] = (base >> 9) as u8;
+                packet[9] = (base >> 1) as u8;
+                packet[10] = ((base & 0x01) << 7) as u8 | 0x7E |
+                    ((ext >> 8) & 0x01) as u8;
+                packet[11] = ext as u8;
+            }
+            None => {
+                packet[3] = 0x10;  // payload only
+            }
+        }
+        packet
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::Serialize;
+
+// A PID analyzer counts bytes of TS packets for each PID in order to compute
+// the bitrate breakdown of a TS stream.
+pub struct PidAnalyzer {
+    buf: Vec<u8>,
+    bytes: BTreeMap<u16, u64>,
+}
+
+impl PidAnalyzer {
+    const PACKET_SIZE: usize = 188;
+    const SYNC_BYTE: u8 = 0x47;
+
+    pub fn new() -> Self {
+        PidAnalyzer {
+            buf: Vec::with_capacity(Self::PACKET_SIZE * 2),
+            bytes: BTreeMap::new(),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+
+        let mut pos = 0;
+        while self.buf.len() - pos >= Self::PACKET_SIZE {
+            if self.buf[pos] != Self::SYNC_BYTE {
+                // Resync.
+                pos += 1;
+                continue;
+            }
+            let pid = ((self.buf[pos + 1] as u16) & 0x1F) << 8 |
+                (self.buf[pos + 2] as u16);
+            *self.bytes.entry(pid).or_insert(0) += Self::PACKET_SIZE as u64;
+            pos += Self::PACKET_SIZE;
+        }
+
+        self.buf.drain(..pos);
+    }
+
+    pub fn report(&self, duration: Duration) -> PidBitrateReport {
+        let millis = duration.as_millis().max(1) as u64;
+        let pids: Vec<PidBitrate> = self.bytes.iter()
+            .map(|(&pid, &bytes)| PidBitrate {
+                pid,
+                bytes,
+                bytes_per_sec: bytes * 1000 / millis,
+            })
+            .collect();
+        let total_bytes: u64 = pids.iter().map(|pid| pid.bytes).sum();
+        PidBitrateReport {
+            duration: millis,
+            total_bytes,
+            total_bytes_per_sec: total_bytes * 1000 / millis,
+            pids,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PidBitrateReport {
+    pub duration: u64,  // in milliseconds
+    pub total_bytes: u64,
+    pub total_bytes_per_sec: u64,
+    pub pids: Vec<PidBitrate>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PidBitrate {
+    pub pid: u16,
+    pub bytes: u64,
+    pub bytes_per_sec: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_feed() {
+        let mut analyzer = PidAnalyzer::new();
+
+        let mut data = Vec::new();
+        data.extend(create_packet(0x0000));
+        data.extend(create_packet(0x0100));
+        data.extend(create_packet(0x0100));
+        data.extend(create_packet(0x1FFF));
+
+        // Feed data split at a position which is not aligned to packets.
+        analyzer.feed(&data[..300]);
+        analyzer.feed(&data[300..]);
+
+        let report = analyzer.report(Duration::from_secs(2));
+        assert_eq!(report.duration, 2000);
+        assert_eq!(report.total_bytes, 188 * 4);
+        assert_eq!(report.total_bytes_per_sec, 188 * 2);
+        assert_eq!(report.pids.len(), 3);
+        assert_eq!(report.pids[0].pid, 0x0000);
+        assert_eq!(report.pids[0].bytes, 188);
+        assert_eq!(report.pids[1].pid, 0x0100);
+        assert_eq!(report.pids[1].bytes, 188 * 2);
+        assert_eq!(report.pids[1].bytes_per_sec, 188);
+        assert_eq!(report.pids[2].pid, 0x1FFF);
+    }
+
+    fn create_packet(pid: u16) -> Vec<u8> {
+        let mut packet = vec![0xFF; PidAnalyzer::PACKET_SIZE];
+        packet[0] = PidAnalyzer::SYNC_BYTE;
+        packet[1] = (pid >> 8) as u8 & 0x1F;
+        packet[2] = pid as u8;
+        packet[3] = 0x10;  // payload only
+        packet
+    }
+}
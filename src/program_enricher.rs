@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::models::MirakurunProgram;
+
+// Enriches programs served by the Web API with external metadata.
+//
+// The mapping file is a JSON object like below:
+//
+//   {
+//     "programs": {
+//       "327360102400001": { "description": "...", "extended": { ... } }
+//     },
+//     "titles": {
+//       "Title": { "description": "...", "extended": { ... } }
+//     }
+//   }
+//
+// A program is looked up by its program ID first, and then by its title.
+// Fields in the mapping are used only for filling missing fields, so that the
+// broadcast data always takes precedence.  Programs stored in the EPG are never
+// modified.
+//
+// The mapping file is reloaded when its modification time or size changes.
+// The file is accessed outside the lock, so that requests are never serialized
+// by the file I/O.
+pub struct ProgramEnricher {
+    path: Option<String>,
+    state: Mutex<EnricherState>,
+}
+
+type Signature = Option<(SystemTime, u64)>;
+
+#[derive(Default)]
+struct EnricherState {
+    signature: Signature,
+    mapping: Arc<EnrichmentMapping>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnrichmentMapping {
+    #[serde(default)]
+    programs: HashMap<u64, EnrichmentEntry>,
+    #[serde(default)]
+    titles: HashMap<String, EnrichmentEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnrichmentEntry {
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    extended: IndexMap<String, String>,
+}
+
+impl ProgramEnricher {
+    pub fn new(path: Option<String>) -> Self {
+        ProgramEnricher {
+            path,
+            state: Mutex::new(Default::default()),
+        }
+    }
+
+    pub fn enrich(&self, program: MirakurunProgram) -> MirakurunProgram {
+        self.enrich_all(vec![program]).pop().unwrap()
+    }
+
+    pub fn enrich_all(
+        &self,
+        mut programs: Vec<MirakurunProgram>,
+    ) -> Vec<MirakurunProgram> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return programs,
+        };
+
+        let mapping = self.load_if_changed(path);
+
+        for program in programs.iter_mut() {
+            if let Some(entry) = mapping.find(program) {
+                entry.apply(program);
+            }
+        }
+        programs
+    }
+
+    // The lock is held only while reading or updating the state.
+    fn load_if_changed(&self, path: &str) -> Arc<EnrichmentMapping> {
+        let result = fs::metadata(path);
+
+        let (current, mapping) = {
+            let state = self.state.lock().unwrap();
+            (state.signature, state.mapping.clone())
+        };
+
+        let signature = match result {
+            Ok(metadata) => metadata.modified().ok()
+                .map(|modified| (modified, metadata.len())),
+            Err(err) => {
+                if current.is_some() {
+                    log::error!("Failed to access {}: {}", path, err);
+                    self.state.lock().unwrap().signature = None;
+                }
+                return mapping;
+            }
+        };
+        if signature.is_none() || signature == current {
+            return mapping;
+        }
+
+        let result = EnrichmentMapping::load(path);
+
+        let mut state = self.state.lock().unwrap();
+        if state.signature != current {
+            // Another request has already reloaded the file.
+            return state.mapping.clone();
+        }
+        // Update the signature even when the load fails in order to avoid
+        // logging the same error for every request.
+        state.signature = signature;
+        match result {
+            Ok(mapping) => {
+                log::info!("Loaded {}: {} programs, {} titles", path,
+                           mapping.programs.len(), mapping.titles.len());
+                state.mapping = Arc::new(mapping);
+            }
+            Err(err) => {
+                log::error!("Failed to load {}, keep the previous mapping: {}",
+                            path, err);
+            }
+        }
+        state.mapping.clone()
+    }
+}
+
+impl EnrichmentMapping {
+    fn load(path: &str) -> Result<Self, Error> {
+        let json = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    fn find(&self, program: &MirakurunProgram) -> Option<&EnrichmentEntry> {
+        self.programs.get(&program.id.value())
+            .or_else(|| {
+                program.name.as_ref()
+                    .and_then(|name| self.titles.get(name))
+            })
+    }
+}
+
+impl EnrichmentEntry {
+    fn apply(&self, program: &mut MirakurunProgram) {
+        if program.description.is_none() {
+            program.description = self.description.clone();
+        }
+        if self.extended.is_empty() {
+            return;
+        }
+        let extended = program.extended.get_or_insert_with(IndexMap::new);
+        for (key, value) in self.extended.iter() {
+            if !extended.contains_key(key) {
+                extended.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epg::EpgProgram;
+
+    #[test]
+    fn test_enrich() {
+        let path = std::env::temp_dir().join(format!(
+            "mirakc-enrichment-test-{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        let enricher = ProgramEnricher::new(Some(path.clone()));
+
+        // No mapping file.
+        let program = enricher.enrich(create_program(1, Some("title")));
+        assert_eq!(program.description, None);
+
+        fs::write(&path, r#"{
+            "programs": {
+                "1": { "description": "by id" }
+            },
+            "titles": {
+                "title": {
+                    "description": "by title",
+                    "extended": { "cast": "someone" }
+                }
+            }
+        }"#).unwrap();
+
+        // The program ID takes precedence.
+        let program = enricher.enrich(create_program(1, Some("title")));
+        assert_eq!(program.description, Some("by id".to_string()));
+        assert_eq!(program.extended, None);
+
+        let program = enricher.enrich(create_program(2, Some("title")));
+        assert_eq!(program.description, Some("by title".to_string()));
+        assert_eq!(program.extended.unwrap()["cast"], "someone");
+
+        // Unmatched programs are returned as-is.
+        let program = enricher.enrich(create_program(2, None));
+        assert_eq!(program.description, None);
+        assert_eq!(program.extended, None);
+
+        // The broadcast data takes precedence.
+        let mut program = create_program(2, Some("title"));
+        program.description = Some("broadcast".to_string());
+        program.extended = Some(
+            vec![("cast".to_string(), "broadcast".to_string())]
+                .into_iter().collect());
+        let program = enricher.enrich(program);
+        assert_eq!(program.description, Some("broadcast".to_string()));
+        assert_eq!(program.extended.unwrap()["cast"], "broadcast");
+
+        // Reloaded when the file changes.
+        fs::write(&path, r#"{
+            "titles": { "title": { "description": "new" } }
+        }"#).unwrap();
+        let program = enricher.enrich(create_program(1, Some("title")));
+        assert_eq!(program.description, Some("new".to_string()));
+
+        // The previous mapping is kept when the file is broken.
+        fs::write(&path, "{").unwrap();
+        let program = enricher.enrich(create_program(1, Some("title")));
+        assert_eq!(program.description, Some("new".to_string()));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_enrich_disabled() {
+        let enricher = ProgramEnricher::new(None);
+        let program = enricher.enrich(create_program(1, Some("title")));
+        assert_eq!(program.description, None);
+    }
+
+    fn create_program(eid: u16, name: Option<&str>) -> MirakurunProgram {
+        let mut program = EpgProgram::new((0, 0, 0, eid).into());
+        program.name = name.map(|name| name.to_string());
+        program.into()
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+// A PSI cache keeps the latest PAT and PMT packets in a TS stream so that they
+// can be sent to a subscriber joining an in-progress stream before other
+// packets.  The subscriber can start decoding without waiting for the next PAT
+// and PMT.
+//
+// Only a section which fits in a single TS packet is cached.  The cached
+// packet of a PID is the latest packet of the PID in the stream, so the
+// continuity counter is kept consistent when the subscriber receives the next
+// packet of the PID.
+//
+// The cache can also reinsert copies of the cached packets into the stream
+// periodically.  A copy is inserted only while the original is the latest
+// packet of its PID, and at most once.  So the copy is treated as a duplicate
+// packet and doesn't break the continuity counter.
+pub struct PsiCache {
+    buf: Vec<u8>,  // an incomplete packet at the end of the last chunk
+    pmt_pids: Vec<u16>,
+    packets: HashMap<u16, Vec<u8>>,  // latest packets which can be sent
+    reinsertion_interval: Option<Duration>,
+    last_reinserted: Instant,
+    reinserted: HashSet<u16>,  // PIDs of packets which have been reinserted
+}
+
+impl PsiCache {
+    const PACKET_SIZE: usize = 188;
+    const SYNC_BYTE: u8 = 0x47;
+    const PAT_PID: u16 = 0x0000;
+
+    // Copies of the cached packets are never inserted into the stream if
+    // `reinsertion_interval` is `None`.
+    pub fn new(reinsertion_interval: Option<Duration>) -> Self {
+        PsiCache {
+            buf: Vec::with_capacity(Self::PACKET_SIZE),
+            pmt_pids: Vec::new(),
+            packets: HashMap::new(),
+            reinsertion_interval,
+            last_reinserted: Instant::now(),
+            reinserted: HashSet::new(),
+        }
+    }
+
+    // Updates the cache with a chunk like `feed()`, and returns the chunk.
+    //
+    // When the reinsertion interval has elapsed, copies of the cached packets
+    // are inserted at the first packet boundary in the chunk.
+    pub fn feed_chunk(&mut self, chunk: Bytes) -> Bytes {
+        match self.reinsertion_interval {
+            Some(interval) if self.last_reinserted.elapsed() >= interval => (),
+            _ => {
+                self.feed(&chunk);
+                return chunk;
+            }
+        }
+
+        let boundary = if self.buf.is_empty() {
+            0
+        } else {
+            Self::PACKET_SIZE - self.buf.len()
+        };
+        if boundary > chunk.len() ||
+            (boundary < chunk.len() && chunk[boundary] != Self::SYNC_BYTE) {
+            // Try again with the next chunk.
+            self.feed(&chunk);
+            return chunk;
+        }
+
+        self.feed(&chunk[..boundary]);
+        let copies = self.take_copies();
+        self.feed(&chunk[boundary..]);
+        if copies.is_empty() {
+            return chunk;
+        }
+
+        log::trace!("Reinserted {} PSI packets",
+                    copies.len() / Self::PACKET_SIZE);
+        self.last_reinserted = Instant::now();
+        let mut data = Vec::with_capacity(chunk.len() + copies.len());
+        data.extend_from_slice(&chunk[..boundary]);
+        data.extend_from_slice(&copies);
+        data.extend_from_slice(&chunk[boundary..]);
+        Bytes::from(data)
+    }
+
+    // Updates the cache with TS packets in a chunk.  A chunk may not be
+    // aligned to the packet size.
+    pub fn feed(&mut self, data: &[u8]) {
+        let mut data = data;
+        if !self.buf.is_empty() {
+            let n = (Self::PACKET_SIZE - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() < Self::PACKET_SIZE {
+                return;
+            }
+            let buf = std::mem::take(&mut self.buf);
+            if buf[0] == Self::SYNC_BYTE {
+                self.update(&buf);
+                self.buf = buf;
+                self.buf.clear();
+            } else {
+                // Resync from the next byte.
+                let mut rest = buf[1..].to_vec();
+                rest.extend_from_slice(data);
+                self.buf = Vec::with_capacity(Self::PACKET_SIZE);
+                return self.feed(&rest);
+            }
+        }
+
+        let mut pos = 0;
+        while data.len() - pos >= Self::PACKET_SIZE {
+            if data[pos] != Self::SYNC_BYTE {
+                // Resync.
+                pos += 1;
+                continue;
+            }
+            self.update(&data[pos..(pos + Self::PACKET_SIZE)]);
+            pos += Self::PACKET_SIZE;
+        }
+        self.buf.extend_from_slice(&data[pos..]);
+    }
+
+    // Returns the PAT packet followed by the PMT packets, or `None` if no PAT
+    // has been cached.
+    pub fn packets(&self) -> Option<Bytes> {
+        let pat = self.packets.get(&Self::PAT_PID)?;
+        let mut packets = pat.clone();
+        for pid in self.pmt_pids.iter() {
+            if let Some(pmt) = self.packets.get(pid) {
+                packets.extend_from_slice(pmt);
+            }
+        }
+        Some(Bytes::from(packets))
+    }
+
+    // Returns copies of the cached packets which have not been reinserted.
+    fn take_copies(&mut self) -> Vec<u8> {
+        let mut copies = Vec::new();
+        let pids = std::iter::once(Self::PAT_PID)
+            .chain(self.pmt_pids.iter().cloned());
+        for pid in pids {
+            if self.reinserted.contains(&pid) {
+                continue;
+            }
+            if let Some(packet) = self.packets.get(&pid) {
+                copies.extend_from_slice(packet);
+                self.reinserted.insert(pid);
+            }
+        }
+        copies
+    }
+
+    fn update(&mut self, packet: &[u8]) {
+        let pid = ((packet[1] as u16 & 0x1F) << 8) | packet[2] as u16;
+        if pid != Self::PAT_PID && !self.pmt_pids.contains(&pid) {
+            return;
+        }
+        self.reinserted.remove(&pid);
+        match Self::parse_single_section(packet) {
+            Some(section) => {
+                if pid == Self::PAT_PID {
+                    if let Some(pmt_pids) = Self::parse_pat(section) {
+                        self.pmt_pids = pmt_pids;
+                        let pmt_pids = &self.pmt_pids;
+                        self.packets.retain(|pid, _| {
+                            *pid == Self::PAT_PID || pmt_pids.contains(pid)
+                        });
+                    }
+                }
+                self.packets.insert(pid, packet.to_vec());
+            }
+            None => {
+                self.packets.remove(&pid);
+            }
+        }
+    }
+
+    // Returns a section which starts in the packet and ends in the packet.
+    fn parse_single_section(packet: &[u8]) -> Option<&[u8]> {
+        if packet[1] & 0x80 != 0 {
+            return None;  // transport error
+        }
+        if packet[1] & 0x40 == 0 {
+            return None;  // no section starts in the packet
+        }
+        let adaptation_field_control = (packet[3] >> 4) & 0x03;
+        if adaptation_field_control & 0x01 == 0 {
+            return None;  // no payload
+        }
+        let mut pos = 4;
+        if adaptation_field_control & 0x02 != 0 {
+            pos += 1 + packet[4] as usize;
+        }
+        if pos >= Self::PACKET_SIZE {
+            return None;
+        }
+        pos += 1 + packet[pos] as usize;  // pointer_field
+        if pos + 3 > Self::PACKET_SIZE {
+            return None;
+        }
+        let section_length =
+            ((packet[pos + 1] as usize & 0x0F) << 8) | packet[pos + 2] as usize;
+        let end = pos + 3 + section_length;
+        if end > Self::PACKET_SIZE {
+            return None;
+        }
+        Some(&packet[pos..end])
+    }
+
+    fn parse_pat(section: &[u8]) -> Option<Vec<u16>> {
+        // table_id + section_length + 5 bytes + CRC_32
+        const MIN_SIZE: usize = 12;
+        if section[0] != 0x00 || section.len() < MIN_SIZE {
+            return None;
+        }
+        let end = section.len() - 4;
+        let mut pmt_pids = Vec::new();
+        let mut pos = 8;
+        while pos + 4 <= end {
+            let program_number =
+                ((section[pos] as u16) << 8) | section[pos + 1] as u16;
+            let pid = ((section[pos + 2] as u16 & 0x1F) << 8) |
+                section[pos + 3] as u16;
+            // program_number 0 is used for the NIT.
+            if program_number != 0 {
+                pmt_pids.push(pid);
+            }
+            pos += 4;
+        }
+        Some(pmt_pids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_single_section() {
+        let packet = create_pat_packet(&[0x0101]);
+        let section = PsiCache::parse_single_section(&packet).unwrap();
+        assert_eq!(section[0], 0x00);
+        assert_eq!(section.len(), 16);
+
+        // Not a start of a section.
+        let mut packet = create_pat_packet(&[0x0101]);
+        packet[1] &= !0x40;
+        assert!(PsiCache::parse_single_section(&packet).is_none());
+
+        // A section continues to the next packet.
+        let mut packet = create_pat_packet(&[0x0101]);
+        packet[6] = 0xB0;
+        packet[7] = 0xFF;
+        assert!(PsiCache::parse_single_section(&packet).is_none());
+    }
+
+    #[test]
+    fn test_parse_pat() {
+        let packet = create_pat_packet(&[0x0101, 0x0102]);
+        let section = PsiCache::parse_single_section(&packet).unwrap();
+        assert_eq!(PsiCache::parse_pat(section), Some(vec![0x0101, 0x0102]));
+    }
+
+    #[test]
+    fn test_feed() {
+        let mut cache = PsiCache::new(None);
+        assert_eq!(cache.packets(), None);
+
+        let pat = create_pat_packet(&[0x0101]);
+        let pmt = create_pmt_packet(0x0101);
+        let other = create_packet(0x0111);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&other);
+        data.extend_from_slice(&pat);
+        data.extend_from_slice(&pmt);
+        data.extend_from_slice(&other);
+
+        // Feed data split at positions which are not aligned to packets.
+        cache.feed(&data[..300]);
+        assert_eq!(cache.packets(), None);
+        cache.feed(&data[300..400]);
+        assert_eq!(cache.packets(), Some(Bytes::from(pat.clone())));
+        cache.feed(&data[400..]);
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&pat);
+        expected.extend_from_slice(&pmt);
+        assert_eq!(cache.packets(), Some(Bytes::from(expected)));
+
+        // A PMT which doesn't fit in a single packet is removed.
+        let mut pmt = create_pmt_packet(0x0101);
+        pmt[6] = 0xB0;
+        pmt[7] = 0xFF;
+        cache.feed(&pmt);
+        assert_eq!(cache.packets(), Some(Bytes::from(pat.clone())));
+    }
+
+    #[test]
+    fn test_feed_resync() {
+        let mut cache = PsiCache::new(None);
+
+        let pat = create_pat_packet(&[0x0101]);
+
+        let mut data = vec![0x00; 10];
+        data.extend_from_slice(&pat);
+        cache.feed(&data[..100]);
+        cache.feed(&data[100..]);
+        assert_eq!(cache.packets(), Some(Bytes::from(pat)));
+    }
+
+    #[test]
+    fn test_feed_chunk() {
+        let mut cache = PsiCache::new(Some(Duration::from_secs(60)));
+
+        let pat = create_pat_packet(&[0x0101]);
+        let pmt = create_pmt_packet(0x0101);
+        let other = create_packet(0x0111);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&pat);
+        data.extend_from_slice(&pmt);
+        data.extend_from_slice(&other[..100]);
+        let chunk = Bytes::from(data);
+        assert_eq!(cache.feed_chunk(chunk.clone()), chunk);
+
+        // Copies are inserted at the first packet boundary in the chunk.
+        cache.last_reinserted -= Duration::from_secs(60);
+        let mut data = Vec::new();
+        data.extend_from_slice(&other[100..]);
+        data.extend_from_slice(&other);
+        let chunk = cache.feed_chunk(Bytes::from(data));
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&other[100..]);
+        expected.extend_from_slice(&pat);
+        expected.extend_from_slice(&pmt);
+        expected.extend_from_slice(&other);
+        assert_eq!(chunk, Bytes::from(expected));
+
+        // A copy is inserted at most once.
+        cache.last_reinserted -= Duration::from_secs(60);
+        let chunk = Bytes::from(other.clone());
+        assert_eq!(cache.feed_chunk(chunk.clone()), chunk);
+
+        // A copy of a new packet can be inserted.
+        cache.feed(&pat);
+        let chunk = cache.feed_chunk(Bytes::from(other.clone()));
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&pat);
+        expected.extend_from_slice(&other);
+        assert_eq!(chunk, Bytes::from(expected));
+    }
+
+    fn create_packet(pid: u16) -> Vec<u8> {
+        let mut packet = vec![0xFF; PsiCache::PACKET_SIZE];
+        packet[0] = PsiCache::SYNC_BYTE;
+        packet[1] = (pid >> 8) as u8 & 0x1F;
+        packet[2] = pid as u8;
+        packet[3] = 0x10;  // payload only
+        packet
+    }
+
+    fn create_section_packet(pid: u16, table_id: u8, body: &[u8]) -> Vec<u8> {
+        let mut packet = create_packet(pid);
+        packet[1] |= 0x40;  // payload_unit_start_indicator
+        packet[4] = 0;  // pointer_field
+        packet[5] = table_id;
+        let section_length = 5 + body.len() + 4;
+        packet[6] = 0xB0 | (section_length >> 8) as u8;
+        packet[7] = section_length as u8;
+        packet[8..13].copy_from_slice(&[0x00, 0x01, 0xC1, 0x00, 0x00]);
+        packet[13..(13 + body.len())].copy_from_slice(body);
+        // CRC_32 is not checked.
+        packet[(13 + body.len())..(17 + body.len())].copy_from_slice(&[0; 4]);
+        packet
+    }
+
+    fn create_pat_packet(pmt_pids: &[u16]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (i, pid) in pmt_pids.iter().enumerate() {
+            let program_number = i as u16 + 1;
+            body.push((program_number >> 8) as u8);
+            body.push(program_number as u8);
+            body.push(0xE0 | (pid >> 8) as u8);
+            body.push(*pid as u8);
+        }
+        create_section_packet(PsiCache::PAT_PID, 0x00, &body)
+    }
+
+    fn create_pmt_packet(pid: u16) -> Vec<u8> {
+        // PCR_PID + program_info_length, no stream
+        create_section_packet(pid, 0x02, &[0xE1, 0x11, 0xF0, 0x00])
+    }
+}
@@ -0,0 +1,2153 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use actix::prelude::*;
+use chrono::{DateTime, Duration};
+use log;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::command_util;
+use crate::config::{Config, ProcessLimitsConfig, RecordingConflictPolicy};
+use crate::datetime_ext::*;
+use crate::epg::*;
+use crate::error::Error;
+use crate::models::*;
+use crate::named_filters::NamedFilters;
+use crate::pcr_indexer::PcrIndexer;
+use crate::tuner::*;
+
+pub fn start(
+    config: Arc<Config>,
+    tuner_manager: Addr<TunerManager>,
+    epg: Addr<Epg>,
+    named_filters: Arc<NamedFilters>,
+) -> Addr<RecordingManager> {
+    RecordingManager::new(config, tuner_manager, epg, named_filters).start()
+}
+
+pub struct RecordingManager {
+    config: Arc<Config>,
+    tuner_manager: Addr<TunerManager>,
+    epg: Addr<Epg>,
+    // Used for finding the next occurrence of a program.  Replaced with a mock
+    // in tests.
+    next_program_query: Recipient<QueryNextProgramMessage>,
+    named_filters: Arc<NamedFilters>,
+    schedules: HashMap<MirakurunProgramId, RecordingSchedule>,
+    recordings: HashMap<MirakurunProgramId, Recording>,
+    rules: Vec<RecordingRule>,  // sorted by ID
+}
+
+impl RecordingManager {
+    const LABEL: &'static str = "recorder";
+
+    const RETRY_HOOK_LABEL: &'static str = "recorder-retry-hook";
+
+    const RETRY_WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+    // Start streaming a little before the program starts.  Packets before the
+    // start time are discarded by the program-filter.
+    const PREP_SECS: i64 = 15;
+
+    // 32 KiB, the same as the chunk size of the broadcaster.
+    const CHUNK_SIZE: usize = 4096 * 8;
+
+    const PCR_INDEX_INTERVAL: u64 = 1000;  // 1s
+
+    pub fn new(
+        config: Arc<Config>,
+        tuner_manager: Addr<TunerManager>,
+        epg: Addr<Epg>,
+        named_filters: Arc<NamedFilters>,
+    ) -> Self {
+        RecordingManager {
+            config,
+            tuner_manager,
+            next_program_query: epg.clone().recipient(),
+            epg,
+            named_filters,
+            schedules: HashMap::new(),
+            recordings: HashMap::new(),
+            rules: Vec::new(),
+        }
+    }
+
+    // Recording requires the program-filter in order to extract the TS packets
+    // of the program.
+    fn is_enabled(&self) -> bool {
+        self.config.recorder.records_dir.is_some() &&
+            !self.config.filters.program_filter.command.is_empty()
+    }
+
+    fn load_recordings(&mut self) -> Result<(), Error> {
+        match self.config.recorder.records_dir {
+            Some(ref records_dir) => {
+                let json_path =
+                    PathBuf::from(records_dir).join("recordings.json");
+                log::debug!("Loading recordings from {}...",
+                            json_path.display());
+                let file = match File::open(&json_path) {
+                    Ok(file) => file,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                        log::debug!("No recordings saved in {}",
+                                    json_path.display());
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                let reader = BufReader::new(file);
+                self.recordings = serde_json::from_reader(reader)?;
+                log::info!("Loaded {} recordings", self.recordings.len());
+            }
+            None => {
+                log::warn!("No recorder.records-dir specified, \
+                            skip to load recordings");
+            }
+        }
+        Ok(())
+    }
+
+    fn save_recordings(&self) -> Result<(), Error> {
+        match self.config.recorder.records_dir {
+            Some(ref records_dir) => {
+                let json_path =
+                    PathBuf::from(records_dir).join("recordings.json");
+                log::debug!("Saving recordings into {}...",
+                            json_path.display());
+                save_json(&json_path, &self.recordings)?;
+                log::info!("Saved {} recordings", self.recordings.len());
+            }
+            None => {
+                log::warn!("No recorder.records-dir specified, \
+                            skip to save recordings");
+            }
+        }
+        Ok(())
+    }
+
+    fn load_rules(&mut self) -> Result<(), Error> {
+        match self.config.recorder.records_dir {
+            Some(ref records_dir) => {
+                let json_path = PathBuf::from(records_dir).join("rules.json");
+                if !json_path.exists() {
+                    log::debug!("No rules saved in {}", json_path.display());
+                    return Ok(());
+                }
+                log::debug!("Loading rules from {}...", json_path.display());
+                let reader = BufReader::new(File::open(&json_path)?);
+                self.rules = serde_json::from_reader(reader)?;
+                self.rules.sort_by_key(|rule| rule.id);
+                log::info!("Loaded {} rules", self.rules.len());
+            }
+            None => {
+                log::warn!("No recorder.records-dir specified, \
+                            skip to load rules");
+            }
+        }
+        Ok(())
+    }
+
+    fn save_rules(&self) -> Result<(), Error> {
+        match self.config.recorder.records_dir {
+            Some(ref records_dir) => {
+                let json_path = PathBuf::from(records_dir).join("rules.json");
+                log::debug!("Saving rules into {}...", json_path.display());
+                save_json(&json_path, &self.rules)?;
+                log::info!("Saved {} rules", self.rules.len());
+            }
+            None => {
+                log::warn!("No recorder.records-dir specified, \
+                            skip to save rules");
+            }
+        }
+        Ok(())
+    }
+
+    fn add_rule(
+        &mut self,
+        spec: RecordingRuleSpec,
+        ctx: &mut Context<Self>,
+    ) -> Result<RecordingRule, Error> {
+        if !self.is_enabled() {
+            return Err(Error::AccessDenied);
+        }
+        spec.validate()?;
+
+        let id = self.rules.last().map(|rule| rule.id + 1).unwrap_or(1);
+        let rule = RecordingRule { id, spec };
+        self.rules.push(rule.clone());
+        self.save_rules()?;
+        log::info!("Added rule#{}", id);
+
+        self.apply_rules(ctx);
+        Ok(rule)
+    }
+
+    fn update_rule(
+        &mut self,
+        id: u32,
+        spec: RecordingRuleSpec,
+        ctx: &mut Context<Self>,
+    ) -> Result<RecordingRule, Error> {
+        spec.validate()?;
+
+        let rule = match self.rules.iter_mut().find(|rule| rule.id == id) {
+            Some(rule) => {
+                rule.spec = spec;
+                rule.clone()
+            }
+            None => return Err(Error::RecordingRuleNotFound),
+        };
+        self.save_rules()?;
+        log::info!("Updated rule#{}", id);
+
+        self.apply_rules(ctx);
+        Ok(rule)
+    }
+
+    // Schedules already added by the rule are kept.
+    fn remove_rule(&mut self, id: u32) -> Result<(), Error> {
+        match self.rules.iter().position(|rule| rule.id == id) {
+            Some(index) => {
+                self.rules.remove(index);
+            }
+            None => return Err(Error::RecordingRuleNotFound),
+        }
+        self.save_rules()?;
+        log::info!("Removed rule#{}", id);
+        Ok(())
+    }
+
+    fn apply_rules(&mut self, ctx: &mut Context<Self>) {
+        if self.rules.is_empty() {
+            return;
+        }
+
+        let epg = self.epg.clone();
+        let fut = async move {
+            epg.send(QueryProgramsMessage).await?
+        };
+
+        actix::fut::wrap_future::<_, Self>(fut)
+            .then(|result, act, ctx| {
+                match result {
+                    Ok(programs) => {
+                        let n = act.schedule_by_rules(programs, ctx);
+                        log::info!("Scheduled {} programs by rules", n);
+                    }
+                    Err(err) => {
+                        log::error!("Failed to apply rules: {}", err);
+                    }
+                }
+                actix::fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    // Rules are applied in descending order of priority, so that a rule with a
+    // higher priority wins a conflict.  Returns the number of added schedules.
+    //
+    // Conflicted schedules added by rules are re-evaluated every time.
+    fn schedule_by_rules(
+        &mut self,
+        programs: Vec<EpgProgram>,
+        ctx: &mut Context<Self>,
+    ) -> usize {
+        let now = Jst::now();
+
+        self.schedules.retain(|_, schedule| {
+            schedule.rule_id.is_none() ||
+                schedule.state != RecordingScheduleState::Conflicted
+        });
+
+        let mut programs: Vec<EpgProgram> = programs.into_iter()
+            .filter(|program| program.start_at > now)
+            .collect();
+        programs.sort_by_key(|program| {
+            let id = MirakurunProgramId::from(program.quad);
+            (program.start_at, id.value())
+        });
+
+        let mut rules = self.rules.clone();
+        rules.sort_by_key(|rule| (Reverse(rule.spec.priority), rule.id));
+
+        let mut num_scheduled = 0;
+        for rule in rules.iter() {
+            let program_rule = rule.spec.to_program_rule();
+            for program in programs.iter() {
+                if !program_rule.matches(program) {
+                    continue;
+                }
+                // A program is scheduled at most once.
+                let program_id = MirakurunProgramId::from(program.quad);
+                if self.schedules.contains_key(&program_id) ||
+                    self.recordings.contains_key(&program_id) {
+                    continue;
+                }
+                match self.add_schedule(
+                    program.clone(), Some(rule.id), rule.spec.max_retries,
+                    ctx) {
+                    Ok(schedule) => {
+                        if schedule.state == RecordingScheduleState::Scheduled {
+                            num_scheduled += 1;
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("Rule#{}: Failed to schedule program#{}: \
+                                     {}", rule.id, program_id, err);
+                    }
+                }
+            }
+        }
+        num_scheduled
+    }
+
+    // Returns `Ok(())` if the program can be scheduled without exceeding the
+    // number of enabled tuners.  Schedules which lose the conflict according
+    // to `recorder.conflict-policy` are marked as conflicted if needed.
+    // Otherwise, returns the reason why the program cannot be scheduled.
+    fn make_room(
+        &mut self,
+        program: &EpgProgram,
+        priority: i32,
+    ) -> Result<(), String> {
+        let losers = self.find_losers(program, priority)?;
+        let policy = self.config.recorder.conflict_policy;
+        let winner = MirakurunProgramId::from(program.quad);
+        for program_id in losers.into_iter() {
+            log::warn!("Schedule for program#{} conflicted with program#{} \
+                        ({:?})", program_id, winner, policy);
+            if let Some(schedule) = self.schedules.get_mut(&program_id) {
+                schedule.state = RecordingScheduleState::Conflicted;
+                schedule.conflict = Some(format!(
+                    "Conflicted with program#{}", winner.value()));
+            }
+        }
+        Ok(())
+    }
+
+    // Returns schedules which have to lose the conflict in order to schedule
+    // the program, without changing any schedule.  Returns the reason if the
+    // program cannot be scheduled.  Schedules added manually never lose.
+    //
+    // The number of overlapping schedules is counted conservatively.  Types of
+    // channels supported by each tuner are not taken into account.
+    fn find_losers(
+        &self,
+        program: &EpgProgram,
+        priority: i32,
+    ) -> Result<Vec<MirakurunProgramId>, String> {
+        let num_tuners = self.config.tuners.iter()
+            .filter(|config| !config.disabled)
+            .count();
+        let end_at = program.start_at + program.duration;
+
+        let overlapped: Vec<&RecordingSchedule> = self.schedules.values()
+            .filter(|schedule| {
+                schedule.state != RecordingScheduleState::Conflicted
+            })
+            .filter(|schedule| {
+                schedule.start_at < end_at &&
+                    program.start_at < schedule.start_at + schedule.duration
+            })
+            .collect();
+        if overlapped.len() < num_tuners {
+            return Ok(Vec::new());
+        }
+
+        let policy = self.config.recorder.conflict_policy;
+        let mut candidates: Vec<&RecordingSchedule> = overlapped.iter()
+            .filter(|schedule| {
+                schedule.state == RecordingScheduleState::Scheduled
+            })
+            .filter(|schedule| match (policy, schedule.rule_id) {
+                (_, None) => false,
+                (RecordingConflictPolicy::Priority, Some(id)) =>
+                    self.rule_priority(id) < priority,
+                (RecordingConflictPolicy::StartTime, Some(_)) =>
+                    schedule.start_at > program.start_at,
+                (RecordingConflictPolicy::RejectNew, Some(_)) => false,
+            })
+            .cloned()
+            .collect();
+        let num_removals = overlapped.len() + 1 - num_tuners;
+        if candidates.len() < num_removals {
+            let mut ids: Vec<u64> = overlapped.iter()
+                .map(|schedule| schedule.program_id.value())
+                .collect();
+            ids.sort();
+            let ids: Vec<String> = ids.iter()
+                .map(|id| format!("program#{}", id))
+                .collect();
+            return Err(format!("Conflicted with {}", ids.join(", ")));
+        }
+
+        // Schedules which lose first come first.
+        match policy {
+            RecordingConflictPolicy::Priority => {
+                candidates.sort_by_key(|schedule| {
+                    let rule_priority =
+                        self.rule_priority(schedule.rule_id.unwrap());
+                    (rule_priority, Reverse(schedule.program_id.value()))
+                });
+            }
+            _ => {
+                candidates.sort_by_key(|schedule| {
+                    (Reverse(schedule.start_at),
+                     Reverse(schedule.program_id.value()))
+                });
+            }
+        }
+        Ok(candidates.into_iter()
+           .take(num_removals)
+           .map(|schedule| schedule.program_id)
+           .collect())
+    }
+
+    // Returns upcoming programs which the rule would schedule, without
+    // scheduling anything.  The conflict of each program is evaluated against
+    // the current schedules, not against the other programs in the result.
+    // Current schedules which would lose the conflict are reported as well.
+    fn preview_rule(
+        &self,
+        spec: &RecordingRuleSpec,
+        programs: Vec<EpgProgram>,
+    ) -> Result<Vec<RecordingRulePreview>, Error> {
+        if !self.is_enabled() {
+            return Err(Error::AccessDenied);
+        }
+        spec.validate()?;
+
+        let now = Jst::now();
+        let program_rule = spec.to_program_rule();
+        let mut programs: Vec<EpgProgram> = programs.into_iter()
+            .filter(|program| program.start_at > now)
+            .filter(|program| program_rule.matches(program))
+            .collect();
+        programs.sort_by_key(|program| {
+            let id = MirakurunProgramId::from(program.quad);
+            (program.start_at, id.value())
+        });
+
+        Ok(programs.into_iter()
+           .map(|program| {
+               let program_id = MirakurunProgramId::from(program.quad);
+               let scheduled = self.schedules.contains_key(&program_id) ||
+                   self.recordings.contains_key(&program_id);
+               let (conflict, conflicts) = if scheduled {
+                   (None, Vec::new())
+               } else {
+                   match self.find_losers(&program, spec.priority) {
+                       Ok(losers) => (None, losers),
+                       Err(reason) => (Some(reason), Vec::new()),
+                   }
+               };
+               RecordingRulePreview {
+                   program_id,
+                   name: program.name,
+                   start_at: program.start_at,
+                   duration: program.duration,
+                   scheduled,
+                   conflict,
+                   conflicts,
+               }
+           })
+           .collect())
+    }
+
+    // Rules already removed have the lowest priority.
+    fn rule_priority(&self, id: u32) -> i32 {
+        self.rules.iter()
+            .find(|rule| rule.id == id)
+            .map(|rule| rule.spec.priority)
+            .unwrap_or(i32::MIN)
+    }
+
+    // Recordings whose files have been removed externally are removed from the
+    // list.  Returns the removed recordings.
+    fn check_recordings(&mut self) -> Vec<Recording> {
+        let missing: Vec<MirakurunProgramId> = self.recordings.values()
+            .filter(|recording| !recording.path.exists())
+            .map(|recording| recording.program_id)
+            .collect();
+        if missing.is_empty() {
+            log::debug!("All recordings exist");
+            return Vec::new();
+        }
+
+        let removed: Vec<Recording> = missing.iter()
+            .filter_map(|program_id| self.recordings.remove(program_id))
+            .collect();
+        for recording in removed.iter() {
+            log::warn!("Removed recording of program#{}: {} not found",
+                       recording.program_id, recording.path.display());
+        }
+        if let Err(err) = self.save_recordings() {
+            log::error!("Failed to save recordings: {}", err);
+        }
+        removed
+    }
+
+    // Removes recordings which ended more than `recorder.retention` ago.
+    fn prune_recordings(&mut self, now: DateTime<Jst>) -> Vec<Recording> {
+        if self.config.recorder.retention == 0 {
+            return Vec::new();
+        }
+        let retention =
+            Duration::milliseconds(self.config.recorder.retention as i64);
+        let expired: Vec<MirakurunProgramId> = self.recordings.values()
+            .filter(|recording| {
+                recording.start_at + recording.duration + retention < now
+            })
+            .map(|recording| recording.program_id)
+            .collect();
+        if expired.is_empty() {
+            log::debug!("No expired recordings");
+            return Vec::new();
+        }
+
+        let removed: Vec<Recording> = expired.iter()
+            .filter_map(|program_id| self.recordings.remove(program_id))
+            .collect();
+        for recording in removed.iter() {
+            if self.config.recorder.delete_expired_files {
+                Self::delete_recording_files(recording);
+            }
+            log::info!("Pruned expired recording of program#{}: {}",
+                       recording.program_id.value(),
+                       recording.path.display());
+        }
+        log::info!("Pruned {} expired recordings", removed.len());
+        if let Err(err) = self.save_recordings() {
+            log::error!("Failed to save recordings: {}", err);
+        }
+        removed
+    }
+
+    fn delete_recording_files(recording: &Recording) {
+        let paths = std::iter::once(&recording.path)
+            .chain(recording.index_path.iter());
+        for path in paths {
+            if let Err(err) = std::fs::remove_file(path) {
+                log::error!("Failed to delete {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    fn add_schedule(
+        &mut self,
+        program: EpgProgram,
+        rule_id: Option<u32>,
+        max_retries: u32,
+        ctx: &mut Context<Self>,
+    ) -> Result<RecordingSchedule, Error> {
+        if !self.is_enabled() {
+            return Err(Error::AccessDenied);
+        }
+
+        let program_id = MirakurunProgramId::from(program.quad);
+
+        if let Some(schedule) = self.schedules.get(&program_id) {
+            log::debug!("Already scheduled for program#{}", program_id);
+            return Ok(schedule.clone());
+        }
+
+        let now = Jst::now();
+        if program.start_at + program.duration <= now {
+            log::warn!("Program#{} already ended", program_id);
+            return Err(Error::ProgramNotFound);
+        }
+
+        // Schedules added manually have the highest priority.
+        let priority = rule_id
+            .map(|id| self.rule_priority(id))
+            .unwrap_or(i32::MAX);
+        let (state, conflict) = match self.make_room(&program, priority) {
+            Ok(_) => (RecordingScheduleState::Scheduled, None),
+            Err(reason) => {
+                log::warn!("Program#{}: {}", program_id, reason);
+                (RecordingScheduleState::Conflicted, Some(reason))
+            }
+        };
+
+        let schedule = RecordingSchedule {
+            program_id,
+            start_at: program.start_at,
+            duration: program.duration,
+            state,
+            rule_id,
+            conflict,
+            max_retries,
+            retries: 0,
+        };
+        self.schedules.insert(program_id, schedule.clone());
+
+        // `to_std()` fails if the program has already started.  Start
+        // recording immediately in this case.
+        let start_at = program.start_at - Duration::seconds(Self::PREP_SECS);
+        let delay = (start_at - now).to_std().unwrap_or_default();
+        ctx.run_later(delay, move |act, ctx| {
+            act.start_recording(program_id, ctx);
+        });
+        log::info!("Scheduled recording for program#{} at {}",
+                   program_id, program.start_at);
+
+        Ok(schedule)
+    }
+
+    fn start_recording(
+        &mut self,
+        program_id: MirakurunProgramId,
+        ctx: &mut Context<Self>,
+    ) {
+        let records_dir = match self.config.recorder.records_dir {
+            Some(ref records_dir) => records_dir,
+            None => return,
+        };
+
+        match self.schedules.get_mut(&program_id) {
+            Some(schedule) if
+                schedule.state == RecordingScheduleState::Conflicted => {
+                log::warn!("Schedule for program#{} conflicted, skip",
+                           program_id);
+                if let Some(schedule) = self.schedules.remove(&program_id) {
+                    self.retry_with_next_occurrence(schedule, ctx);
+                }
+                return;
+            }
+            // A schedule re-added after conflicted has multiple timers.
+            Some(schedule) if
+                schedule.state == RecordingScheduleState::Recording => {
+                log::debug!("Already recording program#{}, skip", program_id);
+                return;
+            }
+            Some(schedule) =>
+                schedule.state = RecordingScheduleState::Recording,
+            None => {
+                log::debug!("No schedule for program#{}, skip", program_id);
+                return;
+            }
+        }
+
+        let path = PathBuf::from(records_dir)
+            .join(format!("{}.m2ts", program_id.value()));
+
+        log::info!("Start recording program#{} into {}...",
+                   program_id, path.display());
+
+        let fut = Self::record(
+            self.config.clone(), self.tuner_manager.clone(), self.epg.clone(),
+            self.named_filters.clone(), program_id, path);
+
+        actix::fut::wrap_future::<_, Self>(fut)
+            .then(move |result, act, ctx| {
+                let schedule = act.schedules.remove(&program_id);
+                match result {
+                    Ok(recording) => {
+                        log::info!("Recorded program#{} into {}",
+                                   program_id, recording.path.display());
+                        act.recordings.insert(program_id, recording);
+                        if let Err(err) = act.save_recordings() {
+                            log::error!("Failed to save recordings: {}", err);
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("Failed to record program#{}: {}",
+                                    program_id, err);
+                        if let (Error::TunerUnavailable, Some(schedule)) =
+                            (err, schedule) {
+                            act.retry_with_next_occurrence(schedule, ctx);
+                        }
+                    }
+                }
+                actix::fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    // Schedules the next occurrence of a program which couldn't be recorded
+    // because no tuner was available at its start time, or because the
+    // schedule had lost a conflict.
+    //
+    // For a schedule added by a rule, the next occurrence is the next program
+    // matching the rule.  Otherwise, it's the next program having the same
+    // name in the same service.
+    fn retry_with_next_occurrence(
+        &mut self,
+        schedule: RecordingSchedule,
+        ctx: &mut Context<Self>,
+    ) {
+        if schedule.retries >= schedule.max_retries {
+            if schedule.max_retries > 0 {
+                log::warn!("Program#{}: Gave up retrying after {} retries",
+                           schedule.program_id, schedule.retries);
+            }
+            return;
+        }
+
+        let program_rule = schedule.rule_id
+            .and_then(|id| self.rules.iter().find(|rule| rule.id == id))
+            .map(|rule| rule.spec.to_program_rule());
+        let epg = self.epg.clone();
+        let next_program_query = self.next_program_query.clone();
+        let program_id = schedule.program_id;
+        let start_after = schedule.start_at + schedule.duration;
+        let fut = async move {
+            let program_rule = match program_rule {
+                Some(program_rule) => program_rule,
+                None => {
+                    let program = epg.send(QueryProgramMessage::ByNidSidEid {
+                        nid: program_id.nid(),
+                        sid: program_id.sid(),
+                        eid: program_id.eid(),
+                    }).await??;
+                    if program.name.is_none() {
+                        return Ok(None);
+                    }
+                    EpgProgramRule {
+                        keyword: program.name,
+                        service_id: Some(program.quad.into()),
+                        ..Default::default()
+                    }
+                }
+            };
+            next_program_query.send(QueryNextProgramMessage {
+                rule: EpgProgramRule {
+                    start_after: Some(start_after),
+                    ..program_rule
+                },
+            }).await?
+        };
+
+        actix::fut::wrap_future::<_, Self>(fut)
+            .then(move |result, act, ctx| {
+                match result {
+                    Ok(Some(program)) => {
+                        let next_id = MirakurunProgramId::from(program.quad);
+                        match act.add_schedule(
+                            program, schedule.rule_id, schedule.max_retries,
+                            ctx) {
+                            Ok(_) => {
+                                let retries = schedule.retries + 1;
+                                if let Some(next) =
+                                    act.schedules.get_mut(&next_id) {
+                                    next.retries = retries;
+                                }
+                                log::info!("Program#{}: Retry with program#{} \
+                                            ({}/{})", program_id, next_id,
+                                           retries, schedule.max_retries);
+                                act.run_retry_hook(
+                                    program_id, next_id, retries,
+                                    schedule.max_retries);
+                            }
+                            Err(err) => {
+                                log::error!("Program#{}: Failed to schedule \
+                                             program#{}: {}", program_id,
+                                            next_id, err);
+                            }
+                        }
+                    }
+                    Ok(None) => {
+                        log::warn!("Program#{}: No next occurrence found",
+                                   program_id);
+                    }
+                    Err(err) => {
+                        log::error!("Program#{}: Failed to find the next \
+                                     occurrence: {}", program_id, err);
+                    }
+                }
+                actix::fut::ready(())
+            })
+            .spawn(ctx);
+    }
+
+    fn run_retry_hook(
+        &self,
+        program_id: MirakurunProgramId,
+        next_program_id: MirakurunProgramId,
+        retries: u32,
+        max_retries: u32,
+    ) {
+        if let Some(ref url) = self.config.recorder.retry_webhook {
+            let url = url.clone();
+            let event = RecordingRetryEvent {
+                program_id,
+                next_program_id,
+                retries,
+                max_retries,
+            };
+            actix::spawn(async move {
+                if let Err(err) = Self::post_retry_event(&url, &event).await {
+                    log::error!("Failed to post the retry event to {}: {}",
+                                url, err);
+                }
+            });
+        }
+
+        let hook = &self.config.recorder.retry_hook;
+        if hook.is_empty() {
+            return;
+        }
+
+        let cmd = match Self::make_retry_hook_command(
+            hook, program_id, next_program_id, retries, max_retries) {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                log::error!("Failed to make the retry hook command: {}", err);
+                return;
+            }
+        };
+
+        actix::spawn(async move {
+            if let Err(err) = Self::run_retry_hook_command(cmd).await {
+                log::error!("Failed to run the retry hook: {}", err);
+            }
+        });
+    }
+
+    async fn post_retry_event(
+        url: &str,
+        event: &RecordingRetryEvent,
+    ) -> Result<(), Error> {
+        let res = actix_web::client::Client::new()
+            .post(url)
+            .timeout(std::time::Duration::from_secs(
+                Self::RETRY_WEBHOOK_TIMEOUT_SECS))
+            .send_json(event)
+            .await
+            .map_err(|err| Error::HttpRequestFailed(err.to_string()))?;
+        if !res.status().is_success() {
+            return Err(Error::HttpRequestFailed(res.status().to_string()));
+        }
+        Ok(())
+    }
+
+    fn make_retry_hook_command(
+        hook: &str,
+        program_id: MirakurunProgramId,
+        next_program_id: MirakurunProgramId,
+        retries: u32,
+        max_retries: u32,
+    ) -> Result<String, Error> {
+        let template = mustache::compile_str(hook)?;
+        let data = mustache::MapBuilder::new()
+            .insert("program_id", &program_id.value())?
+            .insert("next_program_id", &next_program_id.value())?
+            .insert("retries", &retries)?
+            .insert("max_retries", &max_retries)?
+            .build();
+        Ok(template.render_data_to_string(&data)?)
+    }
+
+    async fn run_retry_hook_command(cmd: String) -> Result<(), Error> {
+        let mut pipeline = command_util::spawn_pipeline(
+            vec![cmd], Self::RETRY_HOOK_LABEL)?;
+        let (input, mut output) = pipeline.take_endpoints()?;
+        drop(input);
+
+        // Wait until the command exits.
+        let mut buf = Vec::new();
+        output.read_to_end(&mut buf).await?;
+        Ok(())
+    }
+
+    // TODO: The following implementation has code clones similar to
+    //       `get_program_stream()` in web.rs.
+    async fn record(
+        config: Arc<Config>,
+        tuner_manager: Addr<TunerManager>,
+        epg: Addr<Epg>,
+        named_filters: Arc<NamedFilters>,
+        program_id: MirakurunProgramId,
+        path: PathBuf,
+    ) -> Result<Recording, Error> {
+        let program = epg.send(QueryProgramMessage::ByNidSidEid {
+            nid: program_id.nid(),
+            sid: program_id.sid(),
+            eid: program_id.eid(),
+        }).await??;
+
+        let service = epg.send(QueryServiceMessage::ByTriple {
+            triple: program.quad.into(),
+        }).await??;
+
+        let clock = epg.send(QueryClockMessage {
+            triple: service.triple(),
+        }).await??;
+
+        let data = mustache::MapBuilder::new()
+            .insert_str("channel_name", &service.channel.name)
+            .insert("channel_type", &service.channel.channel_type)?
+            .insert_str("channel", &service.channel.channel)
+            .insert("sid", &program.quad.sid().value())?
+            .insert("eid", &program.quad.eid().value())?
+            .insert("clock_pcr", &clock.pcr)?
+            .insert("clock_time", &clock.time)?
+            .build();
+
+        let filters = make_record_filters(
+            &config, &named_filters, &service, &data)?;
+
+        let user = TunerUser {
+            info: TunerUserInfo::Job { name: Self::LABEL.to_string() },
+            priority: 1.into(),
+            expected_end_at: Some(program.start_at + program.duration),
+            drop_policy: config.recorder.drop_policy,
+            timeshift: None,
+        };
+
+        let stream = tuner_manager.send(StartStreamingMessage {
+            channel: service.channel.clone(),
+            user
+        }).await??;
+
+        let mut pipeline = command_util::spawn_pipeline_with_command_limits(
+            filters, stream.id(), &config.filters.process_limits)?;
+        let (input, mut output) = pipeline.take_endpoints()?;
+        let handle = tokio::spawn(stream.pipe(input));
+
+        let index_path = if config.recorder.pcr_index {
+            Some(path.with_extension("index.jsonl"))
+        } else {
+            None
+        };
+
+        let result = Self::write_files(
+            &mut output, &path, index_path.as_ref()).await;
+
+        // Explicitly dropping the output of the pipeline is needed.  The output
+        // holds the child processes and it kills them when dropped.
+        drop(output);
+        drop(pipeline);
+        let _ = handle.await;
+
+        let size = result?;
+        log::debug!("Wrote {} bytes into {}", size, path.display());
+
+        Ok(Recording {
+            program_id,
+            path,
+            index_path,
+            start_at: program.start_at,
+            duration: program.duration,
+            size,
+        })
+    }
+
+    async fn write_files<R>(
+        reader: &mut R,
+        path: &PathBuf,
+        index_path: Option<&PathBuf>,
+    ) -> Result<u64, Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut file = tokio::fs::File::create(path).await?;
+
+        let mut index = match index_path {
+            Some(index_path) => {
+                let index_file = tokio::fs::File::create(index_path).await?;
+                Some((PcrIndexer::new(Self::PCR_INDEX_INTERVAL), index_file))
+            }
+            None => None,
+        };
+
+        let mut size = 0;
+        let mut buf = vec![0; Self::CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            let data = &buf[..n];
+            file.write_all(data).await?;
+            if let Some((ref mut indexer, ref mut index_file)) = index {
+                for entry in indexer.feed(data).into_iter() {
+                    let mut json = serde_json::to_vec(&entry)?;
+                    json.push(b'\n');
+                    index_file.write_all(&json).await?;
+                }
+            }
+            size += n as u64;
+        }
+
+        Ok(size)
+    }
+}
+
+// Default filters of the service are applied like streaming.  Named filters
+// changed at runtime are used as well.
+fn make_record_filters(
+    config: &Config,
+    named_filters: &NamedFilters,
+    service: &EpgService,
+    data: &mustache::Data,
+) -> Result<Vec<(String, Option<ProcessLimitsConfig>)>, Error> {
+    let pre_filters = named_filters.pre_filters();
+    let post_filters = named_filters.post_filters();
+    let channel = &service.channel;
+    let id = MirakurunServiceId::from(service.triple());
+    let mut commands = Vec::new();
+    for name in config.default_pre_filters(
+        channel.channel_type, &channel.channel, Some(id)) {
+        match pre_filters.get(&name) {
+            Some(filter) =>
+                commands.push((&filter.command, &filter.process_limits)),
+            None => log::warn!("No such pre-filter: {}", name),
+        }
+    }
+    let service_filter = &config.filters.service_filter;
+    commands.push(
+        (&service_filter.command, &service_filter.process_limits));
+    let program_filter = &config.filters.program_filter;
+    commands.push(
+        (&program_filter.command, &program_filter.process_limits));
+    for name in config.default_post_filters(
+        channel.channel_type, &channel.channel, Some(id)) {
+        match post_filters.get(&name) {
+            Some(filter) =>
+                commands.push((&filter.command, &filter.process_limits)),
+            None => log::warn!("No such post-filter: {}", name),
+        }
+    }
+
+    let mut filters = Vec::new();
+    for (command, limits) in commands.into_iter() {
+        let template = mustache::compile_str(command)?;
+        let filter = template.render_data_to_string(data)?;
+        let filter = filter.trim();
+        if !filter.is_empty() {
+            filters.push((filter.to_string(), limits.clone()));
+        }
+    }
+    Ok(filters)
+}
+
+// Writes into a temporary file and then renames it in order to keep the
+// previous file intact when the process is killed while writing.
+pub(crate) fn save_json<T>(json_path: &Path, data: &T) -> Result<(), Error>
+where
+    T: Serialize,
+{
+    let tmp_path = json_path.with_extension("json.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, data)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+    std::fs::rename(&tmp_path, json_path)?;
+    Ok(())
+}
+
+impl Actor for RecordingManager {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        log::debug!("Started");
+        if let Err(err) = self.load_recordings() {
+            log::error!("Failed to load recordings: {}", err);
+        }
+        if let Err(err) = self.load_rules() {
+            log::error!("Failed to load rules: {}", err);
+        }
+        self.apply_rules(ctx);
+        if self.config.recorder.check_interval > 0 {
+            self.check_recordings();
+            self.prune_recordings(Jst::now());
+            let interval = std::time::Duration::from_millis(
+                self.config.recorder.check_interval);
+            ctx.run_interval(interval, |act, _| {
+                act.check_recordings();
+                act.prune_recordings(Jst::now());
+            });
+        }
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        log::debug!("Stopped");
+    }
+}
+
+// query recording schedules
+
+pub struct QueryRecordingSchedulesMessage;
+
+impl fmt::Display for QueryRecordingSchedulesMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryRecordingSchedules")
+    }
+}
+
+impl Message for QueryRecordingSchedulesMessage {
+    type Result = Result<Vec<RecordingSchedule>, Error>;
+}
+
+impl Handler<QueryRecordingSchedulesMessage> for RecordingManager {
+    type Result = Result<Vec<RecordingSchedule>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryRecordingSchedulesMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        let mut schedules: Vec<RecordingSchedule> =
+            self.schedules.values().cloned().collect();
+        schedules.sort_by_key(|schedule| schedule.start_at);
+        Ok(schedules)
+    }
+}
+
+// add recording schedule
+
+pub struct AddRecordingScheduleMessage {
+    pub program: EpgProgram,
+    pub max_retries: u32,
+}
+
+impl fmt::Display for AddRecordingScheduleMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AddRecordingSchedule for {}",
+               MirakurunProgramId::from(self.program.quad))
+    }
+}
+
+impl Message for AddRecordingScheduleMessage {
+    type Result = Result<RecordingSchedule, Error>;
+}
+
+impl Handler<AddRecordingScheduleMessage> for RecordingManager {
+    type Result = Result<RecordingSchedule, Error>;
+
+    fn handle(
+        &mut self,
+        msg: AddRecordingScheduleMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.add_schedule(msg.program, None, msg.max_retries, ctx)
+    }
+}
+
+// query recordings
+
+pub struct QueryRecordingsMessage;
+
+impl fmt::Display for QueryRecordingsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryRecordings")
+    }
+}
+
+impl Message for QueryRecordingsMessage {
+    type Result = Result<Vec<Recording>, Error>;
+}
+
+impl Handler<QueryRecordingsMessage> for RecordingManager {
+    type Result = Result<Vec<Recording>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryRecordingsMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        let mut recordings: Vec<Recording> =
+            self.recordings.values().cloned().collect();
+        recordings.sort_by_key(|recording| recording.start_at);
+        Ok(recordings)
+    }
+}
+
+// query recording
+
+pub struct QueryRecordingMessage {
+    pub program_id: MirakurunProgramId,
+}
+
+impl fmt::Display for QueryRecordingMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryRecording for {}", self.program_id)
+    }
+}
+
+impl Message for QueryRecordingMessage {
+    type Result = Result<Recording, Error>;
+}
+
+impl Handler<QueryRecordingMessage> for RecordingManager {
+    type Result = Result<Recording, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryRecordingMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.recordings
+            .get(&msg.program_id)
+            .cloned()
+            .ok_or(Error::RecordingNotFound)
+    }
+}
+
+// check recordings
+
+pub struct CheckRecordingsMessage;
+
+impl fmt::Display for CheckRecordingsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CheckRecordings")
+    }
+}
+
+impl Message for CheckRecordingsMessage {
+    type Result = Result<Vec<Recording>, Error>;
+}
+
+impl Handler<CheckRecordingsMessage> for RecordingManager {
+    type Result = Result<Vec<Recording>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: CheckRecordingsMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        let mut removed = self.check_recordings();
+        removed.extend(self.prune_recordings(Jst::now()));
+        Ok(removed)
+    }
+}
+
+// query recording rules
+
+pub struct QueryRecordingRulesMessage;
+
+impl fmt::Display for QueryRecordingRulesMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryRecordingRules")
+    }
+}
+
+impl Message for QueryRecordingRulesMessage {
+    type Result = Result<Vec<RecordingRule>, Error>;
+}
+
+impl Handler<QueryRecordingRulesMessage> for RecordingManager {
+    type Result = Result<Vec<RecordingRule>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryRecordingRulesMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        Ok(self.rules.clone())
+    }
+}
+
+// add recording rule
+
+pub struct AddRecordingRuleMessage {
+    pub spec: RecordingRuleSpec,
+}
+
+impl fmt::Display for AddRecordingRuleMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AddRecordingRule {:?}", self.spec)
+    }
+}
+
+impl Message for AddRecordingRuleMessage {
+    type Result = Result<RecordingRule, Error>;
+}
+
+impl Handler<AddRecordingRuleMessage> for RecordingManager {
+    type Result = Result<RecordingRule, Error>;
+
+    fn handle(
+        &mut self,
+        msg: AddRecordingRuleMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.add_rule(msg.spec, ctx)
+    }
+}
+
+// update recording rule
+
+pub struct UpdateRecordingRuleMessage {
+    pub id: u32,
+    pub spec: RecordingRuleSpec,
+}
+
+impl fmt::Display for UpdateRecordingRuleMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UpdateRecordingRule#{} {:?}", self.id, self.spec)
+    }
+}
+
+impl Message for UpdateRecordingRuleMessage {
+    type Result = Result<RecordingRule, Error>;
+}
+
+impl Handler<UpdateRecordingRuleMessage> for RecordingManager {
+    type Result = Result<RecordingRule, Error>;
+
+    fn handle(
+        &mut self,
+        msg: UpdateRecordingRuleMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.update_rule(msg.id, msg.spec, ctx)
+    }
+}
+
+// remove recording rule
+
+pub struct RemoveRecordingRuleMessage {
+    pub id: u32,
+}
+
+impl fmt::Display for RemoveRecordingRuleMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RemoveRecordingRule#{}", self.id)
+    }
+}
+
+impl Message for RemoveRecordingRuleMessage {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<RemoveRecordingRuleMessage> for RecordingManager {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: RemoveRecordingRuleMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.remove_rule(msg.id)
+    }
+}
+
+// apply recording rules
+
+pub struct ApplyRecordingRulesMessage;
+
+impl fmt::Display for ApplyRecordingRulesMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ApplyRecordingRules")
+    }
+}
+
+impl Message for ApplyRecordingRulesMessage {
+    type Result = ();
+}
+
+impl Handler<ApplyRecordingRulesMessage> for RecordingManager {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ApplyRecordingRulesMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.apply_rules(ctx);
+    }
+}
+
+// preview recording rule
+
+pub struct PreviewRecordingRuleMessage {
+    pub spec: RecordingRuleSpec,
+    pub programs: Vec<EpgProgram>,
+}
+
+impl fmt::Display for PreviewRecordingRuleMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PreviewRecordingRule {:?}", self.spec)
+    }
+}
+
+impl Message for PreviewRecordingRuleMessage {
+    type Result = Result<Vec<RecordingRulePreview>, Error>;
+}
+
+impl Handler<PreviewRecordingRuleMessage> for RecordingManager {
+    type Result = Result<Vec<RecordingRulePreview>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: PreviewRecordingRuleMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.preview_rule(&msg.spec, msg.programs)
+    }
+}
+
+// models
+
+#[derive(Clone, Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingSchedule {
+    pub program_id: MirakurunProgramId,
+    #[serde(with = "serde_jst")]
+    pub start_at: DateTime<Jst>,
+    #[serde(with = "serde_duration_in_millis")]
+    pub duration: Duration,
+    pub state: RecordingScheduleState,
+    // Specified if the schedule was added by a rule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule_id: Option<u32>,
+    // The reason why the schedule is conflicted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflict: Option<String>,
+    // The maximum number of retries with the next occurrence of the program
+    // when no tuner is available at the start time.
+    pub max_retries: u32,
+    // The number of retries so far.
+    pub retries: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingScheduleState {
+    Scheduled,
+    Recording,
+    // Not recorded because no tuner is available.
+    Conflicted,
+}
+
+#[derive(Clone, Debug)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Recording {
+    pub program_id: MirakurunProgramId,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub index_path: Option<PathBuf>,
+    #[serde(with = "serde_jst")]
+    pub start_at: DateTime<Jst>,
+    #[serde(with = "serde_duration_in_millis")]
+    pub duration: Duration,
+    pub size: u64,
+}
+
+// Conditions not specified are ignored, but at least one condition is
+// required.
+#[derive(Clone, Debug, Default)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingRuleSpec {
+    // A rule having a larger value wins a conflict.
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genre: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_id: Option<MirakurunServiceId>,
+    // See `RecordingSchedule::max_retries`.
+    #[serde(default)]
+    pub max_retries: u32,
+}
+
+impl RecordingRuleSpec {
+    fn validate(&self) -> Result<(), Error> {
+        if self.keyword.is_none() && self.genre.is_none() &&
+            self.service_id.is_none() {
+            return Err(Error::BadRequest(
+                "keyword, genre or serviceId must be specified".to_string()));
+        }
+        Ok(())
+    }
+
+    fn to_program_rule(&self) -> EpgProgramRule {
+        EpgProgramRule {
+            keyword: self.keyword.clone(),
+            genre: self.genre,
+            service_id: self.service_id,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingRule {
+    pub id: u32,
+    #[serde(flatten)]
+    pub spec: RecordingRuleSpec,
+}
+
+// A program which a recording rule would schedule.
+#[derive(Clone, Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingRulePreview {
+    pub program_id: MirakurunProgramId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(with = "serde_jst")]
+    pub start_at: DateTime<Jst>,
+    #[serde(with = "serde_duration_in_millis")]
+    pub duration: Duration,
+    // True if the program has already been scheduled or recorded.
+    pub scheduled: bool,
+    // The reason why the program would be conflicted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflict: Option<String>,
+    // Current schedules which would be conflicted with the program.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<MirakurunProgramId>,
+}
+
+// A JSON object posted to `recorder.retry-webhook`.
+#[derive(Clone, Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RecordingRetryEvent {
+    program_id: MirakurunProgramId,
+    next_program_id: MirakurunProgramId,
+    retries: u32,
+    max_retries: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::*;
+
+    #[actix_rt::test]
+    async fn test_add_schedule() {
+        let mut config = create_config(1);
+        config.recorder.records_dir = Some("/tmp".to_string());
+        let manager = create_manager(config);
+
+        let mut program = EpgProgram::new(create_quad(4));
+        program.start_at = Jst::now() + Duration::hours(1);
+        program.duration = Duration::minutes(30);
+        let result = manager.send(AddRecordingScheduleMessage {
+            program: program.clone(),
+            max_retries: 0,
+        }).await.unwrap();
+        assert_matches!(result, Ok(schedule) => {
+            assert_eq!(schedule.program_id, program.quad.into());
+            assert_eq!(schedule.state, RecordingScheduleState::Scheduled);
+        });
+
+        // Scheduling the same program again is not an error.
+        let result = manager.send(AddRecordingScheduleMessage {
+            program: program.clone(),
+            max_retries: 0,
+        }).await.unwrap();
+        assert_matches!(result, Ok(_));
+
+        let schedules = manager.send(QueryRecordingSchedulesMessage)
+            .await.unwrap().unwrap();
+        assert_eq!(schedules.len(), 1);
+
+        // Programs already ended cannot be recorded.
+        let mut program = EpgProgram::new(create_quad(5));
+        program.start_at = Jst::now() - Duration::hours(1);
+        program.duration = Duration::minutes(30);
+        let result = manager.send(AddRecordingScheduleMessage {
+            program,
+            max_retries: 0,
+        }).await.unwrap();
+        assert_matches!(result, Err(Error::ProgramNotFound));
+
+        // A program overlapping with the schedule is marked as conflicted.
+        let mut program = EpgProgram::new(create_quad(6));
+        program.start_at = Jst::now() + Duration::hours(1);
+        program.duration = Duration::minutes(30);
+        let result = manager.send(AddRecordingScheduleMessage {
+            program,
+            max_retries: 0,
+        }).await.unwrap();
+        assert_matches!(result, Ok(schedule) => {
+            assert_eq!(schedule.state, RecordingScheduleState::Conflicted);
+            assert_eq!(schedule.conflict,
+                       Some(format!("Conflicted with program#{}",
+                                    MirakurunProgramId::from(create_quad(4))
+                                    .value())));
+        });
+
+        let schedules = manager.send(QueryRecordingSchedulesMessage)
+            .await.unwrap().unwrap();
+        assert_eq!(schedules.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_add_schedule_with_retries() {
+        let mut config = create_config(1);
+        config.recorder.records_dir = Some("/tmp".to_string());
+        let manager = create_manager(config);
+
+        let mut program = EpgProgram::new(create_quad(4));
+        program.start_at = Jst::now() + Duration::hours(1);
+        program.duration = Duration::minutes(30);
+        let result = manager.send(AddRecordingScheduleMessage {
+            program,
+            max_retries: 3,
+        }).await.unwrap();
+        assert_matches!(result, Ok(schedule) => {
+            assert_eq!(schedule.max_retries, 3);
+            assert_eq!(schedule.retries, 0);
+        });
+    }
+
+    #[actix_rt::test]
+    async fn test_retry_with_next_occurrence() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Use an empty directory so that no rule is loaded at startup.
+        let dir = std::env::temp_dir().join(format!(
+            "mirakc-recording-retry-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // No tuner is available.  So, every schedule is conflicted.
+        let mut config = create_config(0);
+        config.recorder.records_dir =
+            Some(dir.to_str().unwrap().to_string());
+        let config = Arc::new(config);
+        let tuner_manager = crate::tuner::start(config.clone());
+        let epg = crate::epg::start(config.clone());
+        let mut manager = RecordingManager::new(
+            config.clone(), tuner_manager, epg,
+            Arc::new(NamedFilters::new(config)));
+
+        // The next occurrence has already started so that it's processed
+        // immediately.
+        let queries = Arc::new(AtomicUsize::new(0));
+        let queries_in_mock = queries.clone();
+        let mock = actix::actors::mocker::Mocker::<Epg>::mock(
+            Box::new(move |msg, _ctx| {
+                assert!(msg.downcast_ref::<QueryNextProgramMessage>()
+                        .is_some());
+                let n = queries_in_mock.fetch_add(1, Ordering::SeqCst);
+                let mut program = EpgProgram::new(create_quad(11 + n as u16));
+                program.start_at = Jst::now() - Duration::minutes(1);
+                program.duration = Duration::minutes(30);
+                let result: Result<Option<EpgProgram>, Error> =
+                    Ok(Some(program));
+                Box::<Option<Result<Option<EpgProgram>, Error>>>::new(
+                    Some(result))
+            })).start();
+        manager.next_program_query = mock.recipient();
+
+        let mut program = EpgProgram::new(create_quad(10));
+        program.start_at = Jst::now() - Duration::minutes(1);
+        program.duration = Duration::minutes(30);
+        let manager = RecordingManager::create(move |ctx| {
+            // The rule is added after the manager started so that conflicted
+            // schedules are not removed when rules are applied at startup.
+            let delay = std::time::Duration::from_millis(0);
+            ctx.run_later(delay, move |act, ctx| {
+                act.rules.push(RecordingRule {
+                    id: 1,
+                    spec: RecordingRuleSpec {
+                        max_retries: 2,
+                        ..Default::default()
+                    },
+                });
+                let schedule = act.add_schedule(program, Some(1), 2, ctx);
+                assert_matches!(schedule, Ok(schedule) => {
+                    assert_eq!(schedule.state,
+                               RecordingScheduleState::Conflicted);
+                });
+            });
+            manager
+        });
+
+        tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+
+        // Retried twice, and then gave up.
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        let schedules = manager.send(QueryRecordingSchedulesMessage)
+            .await.unwrap().unwrap();
+        assert!(schedules.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_retry_event() {
+        let event = RecordingRetryEvent {
+            program_id: create_quad(4).into(),
+            next_program_id: create_quad(5).into(),
+            retries: 1,
+            max_retries: 3,
+        };
+        assert_eq!(serde_json::to_value(&event).unwrap(), serde_json::json!({
+            "programId": MirakurunProgramId::from(create_quad(4)).value(),
+            "nextProgramId": MirakurunProgramId::from(create_quad(5)).value(),
+            "retries": 1,
+            "maxRetries": 3,
+        }));
+    }
+
+    #[test]
+    fn test_make_retry_hook_command() {
+        let program_id = MirakurunProgramId::from(create_quad(4));
+        let next_program_id = MirakurunProgramId::from(create_quad(5));
+        let result = RecordingManager::make_retry_hook_command(
+            "notify {{program_id}} {{next_program_id}} \
+             {{retries}}/{{max_retries}}",
+            program_id, next_program_id, 1, 3);
+        assert_matches!(result, Ok(cmd) => {
+            assert_eq!(cmd, format!("notify {} {} 1/3",
+                                    program_id.value(),
+                                    next_program_id.value()));
+        });
+    }
+
+    #[actix_rt::test]
+    async fn test_add_schedule_disabled() {
+        // Disabled by default.
+        let manager = create_manager(Config::default());
+
+        let mut program = EpgProgram::new(create_quad(4));
+        program.start_at = Jst::now() + Duration::hours(1);
+        program.duration = Duration::minutes(30);
+        let result = manager.send(AddRecordingScheduleMessage {
+            program,
+            max_retries: 0,
+        }).await.unwrap();
+        assert_matches!(result, Err(Error::AccessDenied));
+    }
+
+    #[actix_rt::test]
+    async fn test_query_recording() {
+        let manager = create_manager(Config::default());
+
+        let result = manager.send(QueryRecordingMessage {
+            program_id: MirakurunProgramId::new(1.into(), 3.into(), 4.into()),
+        }).await.unwrap();
+        assert_matches!(result, Err(Error::RecordingNotFound));
+    }
+
+    #[actix_rt::test]
+    async fn test_check_recordings() {
+        let config = Arc::new(Config::default());
+        let tuner_manager = crate::tuner::start(config.clone());
+        let epg = crate::epg::start(config.clone());
+        let mut manager = RecordingManager::new(
+            config.clone(), tuner_manager, epg,
+            Arc::new(NamedFilters::new(config)));
+
+        let existing = create_recording(1, "/dev/null");
+        let missing = create_recording(2, "/path/to/missing.m2ts");
+        manager.recordings.insert(existing.program_id, existing);
+        manager.recordings.insert(missing.program_id, missing);
+
+        let removed = manager.check_recordings();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].program_id, create_quad(2).into());
+        assert_eq!(manager.recordings.len(), 1);
+        assert!(manager.recordings.contains_key(&create_quad(1).into()));
+
+        assert!(manager.check_recordings().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_prune_recordings() {
+        let dir = std::env::temp_dir().join(format!(
+            "mirakc-recording-prune-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let expired_path = dir.join("expired.m2ts");
+        std::fs::write(&expired_path, b"").unwrap();
+
+        let mut config = Config::default();
+        config.recorder.retention = 24 * 60 * 60 * 1000;  // 1d
+        config.recorder.delete_expired_files = true;
+        let config = Arc::new(config);
+        let tuner_manager = crate::tuner::start(config.clone());
+        let epg = crate::epg::start(config.clone());
+        let mut manager = RecordingManager::new(
+            config.clone(), tuner_manager, epg,
+            Arc::new(NamedFilters::new(config)));
+
+        let now = Jst::now();
+        let mut expired = create_recording(1, expired_path.to_str().unwrap());
+        expired.start_at = now - Duration::days(2);
+        let mut retained = create_recording(2, "/dev/null");
+        retained.start_at = now - Duration::hours(12);
+        manager.recordings.insert(expired.program_id, expired);
+        manager.recordings.insert(retained.program_id, retained);
+
+        let removed = manager.prune_recordings(now);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].program_id, create_quad(1).into());
+        assert_eq!(manager.recordings.len(), 1);
+        assert!(manager.recordings.contains_key(&create_quad(2).into()));
+        assert!(!expired_path.exists());
+
+        assert!(manager.prune_recordings(now).is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_add_rule() {
+        // Disabled by default.
+        let manager = create_manager(Config::default());
+        let result = manager.send(AddRecordingRuleMessage {
+            spec: RecordingRuleSpec {
+                keyword: Some("news".to_string()),
+                ..Default::default()
+            },
+        }).await.unwrap();
+        assert_matches!(result, Err(Error::AccessDenied));
+
+        let mut config = Config::default();
+        config.recorder.records_dir = Some("/tmp".to_string());
+        let manager = create_manager(config);
+
+        // At least one condition is required.
+        let result = manager.send(AddRecordingRuleMessage {
+            spec: Default::default(),
+        }).await.unwrap();
+        assert_matches!(result, Err(Error::BadRequest(_)));
+
+        let result = manager.send(RemoveRecordingRuleMessage {
+            id: 1,
+        }).await.unwrap();
+        assert_matches!(result, Err(Error::RecordingRuleNotFound));
+    }
+
+    #[actix_rt::test]
+    async fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!(
+            "mirakc-recording-save-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut config = Config::default();
+        config.recorder.records_dir = Some(dir.to_str().unwrap().to_string());
+        let config = Arc::new(config);
+        let tuner_manager = crate::tuner::start(config.clone());
+        let epg = crate::epg::start(config.clone());
+        let mut manager = RecordingManager::new(
+            config.clone(), tuner_manager, epg,
+            Arc::new(NamedFilters::new(config)));
+
+        // Nothing has been saved yet.
+        assert_matches!(manager.load_recordings(), Ok(()));
+        assert!(manager.recordings.is_empty());
+        assert_matches!(manager.load_rules(), Ok(()));
+        assert!(manager.rules.is_empty());
+
+        manager.rules.push(RecordingRule {
+            id: 1,
+            spec: RecordingRuleSpec {
+                keyword: Some("news".to_string()),
+                ..Default::default()
+            },
+        });
+        assert_matches!(manager.save_recordings(), Ok(()));
+        assert_matches!(manager.save_rules(), Ok(()));
+        assert!(dir.join("recordings.json").exists());
+        assert!(!dir.join("recordings.json.tmp").exists());
+        assert!(dir.join("rules.json").exists());
+        assert!(!dir.join("rules.json.tmp").exists());
+
+        manager.rules.clear();
+        assert_matches!(manager.load_recordings(), Ok(()));
+        assert!(manager.recordings.is_empty());
+        assert_matches!(manager.load_rules(), Ok(()));
+        assert_eq!(manager.rules.len(), 1);
+        assert_eq!(manager.rules[0].id, 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_make_room() {
+        let config = Arc::new(create_config(1));
+        let tuner_manager = crate::tuner::start(config.clone());
+        let epg = crate::epg::start(config.clone());
+        let mut manager = RecordingManager::new(
+            config.clone(), tuner_manager, epg,
+            Arc::new(NamedFilters::new(config)));
+
+        manager.rules.push(RecordingRule {
+            id: 1,
+            spec: RecordingRuleSpec { priority: 1, ..Default::default() },
+        });
+
+        let start_at = Jst::now() + Duration::hours(1);
+        manager.schedules.insert(
+            create_quad(1).into(), create_schedule(1, start_at, Some(1)));
+
+        // Not overlapped.
+        let mut program = EpgProgram::new(create_quad(2));
+        program.start_at = start_at + Duration::minutes(30);
+        program.duration = Duration::minutes(30);
+        assert_matches!(manager.make_room(&program, 0), Ok(_));
+        assert_eq!(manager.schedules.len(), 1);
+
+        // Overlapped with a schedule having a higher priority.
+        let mut program = EpgProgram::new(create_quad(3));
+        program.start_at = start_at + Duration::minutes(10);
+        program.duration = Duration::minutes(30);
+        assert_matches!(manager.make_room(&program, 1), Err(_));
+        assert_eq!(manager.schedules.len(), 1);
+
+        // Overlapped with a schedule having a lower priority.  The schedule is
+        // marked as conflicted instead of being removed.
+        assert_matches!(manager.make_room(&program, 2), Ok(_));
+        assert_eq!(manager.schedules.len(), 1);
+        let schedule = &manager.schedules[&create_quad(1).into()];
+        assert_eq!(schedule.state, RecordingScheduleState::Conflicted);
+        assert!(schedule.conflict.is_some());
+
+        // Conflicted schedules are not counted.
+        assert_matches!(manager.make_room(&program, 0), Ok(_));
+
+        // Schedules added manually are never removed.
+        manager.schedules.insert(
+            create_quad(1).into(), create_schedule(1, start_at, None));
+        assert_matches!(manager.make_room(&program, 2), Err(_));
+        assert_eq!(manager.schedules[&create_quad(1).into()].state,
+                   RecordingScheduleState::Scheduled);
+    }
+
+    #[actix_rt::test]
+    async fn test_make_room_with_policies() {
+        let start_at = Jst::now() + Duration::hours(1);
+
+        let mut program = EpgProgram::new(create_quad(3));
+        program.start_at = start_at + Duration::minutes(10);
+        program.duration = Duration::minutes(30);
+
+        let mut config = create_config(1);
+        config.recorder.conflict_policy = RecordingConflictPolicy::StartTime;
+        let config = Arc::new(config);
+        let tuner_manager = crate::tuner::start(config.clone());
+        let epg = crate::epg::start(config.clone());
+        let mut manager = RecordingManager::new(
+            config.clone(), tuner_manager, epg,
+            Arc::new(NamedFilters::new(config)));
+
+        // Starting earlier wins regardless of priorities.
+        manager.schedules.insert(
+            create_quad(1).into(), create_schedule(1, start_at, Some(1)));
+        assert_matches!(manager.make_room(&program, i32::MAX), Err(_));
+
+        manager.schedules.insert(
+            create_quad(1).into(),
+            create_schedule(1, start_at + Duration::minutes(20), Some(1)));
+        assert_matches!(manager.make_room(&program, i32::MIN), Ok(_));
+        assert_eq!(manager.schedules[&create_quad(1).into()].state,
+                   RecordingScheduleState::Conflicted);
+
+        let mut config = create_config(1);
+        config.recorder.conflict_policy = RecordingConflictPolicy::RejectNew;
+        let config = Arc::new(config);
+        let tuner_manager = crate::tuner::start(config.clone());
+        let epg = crate::epg::start(config.clone());
+        let mut manager = RecordingManager::new(
+            config.clone(), tuner_manager, epg,
+            Arc::new(NamedFilters::new(config)));
+
+        // Schedules already added always win.
+        manager.schedules.insert(
+            create_quad(1).into(),
+            create_schedule(1, start_at + Duration::minutes(20), Some(1)));
+        assert_matches!(manager.make_room(&program, i32::MAX), Err(_));
+        assert_eq!(manager.schedules[&create_quad(1).into()].state,
+                   RecordingScheduleState::Scheduled);
+    }
+
+    #[actix_rt::test]
+    async fn test_preview_rule() {
+        let mut config = create_config(1);
+        config.recorder.records_dir = Some("/tmp".to_string());
+        let config = Arc::new(config);
+        let tuner_manager = crate::tuner::start(config.clone());
+        let epg = crate::epg::start(config.clone());
+        let mut manager = RecordingManager::new(
+            config.clone(), tuner_manager, epg,
+            Arc::new(NamedFilters::new(config)));
+
+        let start_at = Jst::now() + Duration::hours(1);
+        manager.schedules.insert(
+            create_quad(1).into(), create_schedule(1, start_at, None));
+
+        let mut programs = Vec::new();
+        for (eid, minutes, name) in &[(1, 0, "news"), (2, 10, "news"),
+                                      (3, 60, "news"), (4, 60, "drama"),
+                                      (5, -120, "news")] {
+            let mut program = EpgProgram::new(create_quad(*eid));
+            program.name = Some(name.to_string());
+            program.start_at = start_at + Duration::minutes(*minutes);
+            program.duration = Duration::minutes(30);
+            programs.push(program);
+        }
+
+        let spec = RecordingRuleSpec {
+            keyword: Some("news".to_string()),
+            ..Default::default()
+        };
+        let previews = manager.preview_rule(&spec, programs.clone()).unwrap();
+        assert_eq!(previews.len(), 3);
+        assert_eq!(previews[0].program_id,
+                   MirakurunProgramId::from(create_quad(1)));
+        assert!(previews[0].scheduled);
+        assert!(previews[0].conflict.is_none());
+        assert_eq!(previews[1].program_id,
+                   MirakurunProgramId::from(create_quad(2)));
+        assert!(!previews[1].scheduled);
+        assert!(previews[1].conflict.is_some());
+        assert_eq!(previews[2].program_id,
+                   MirakurunProgramId::from(create_quad(3)));
+        assert!(!previews[2].scheduled);
+        assert!(previews[2].conflict.is_none());
+        assert!(previews[2].conflicts.is_empty());
+
+        // Nothing is scheduled.
+        assert_eq!(manager.schedules.len(), 1);
+
+        // A schedule added by a rule having a lower priority would lose.
+        manager.schedules.insert(
+            create_quad(1).into(), create_schedule(1, start_at, Some(1)));
+        let programs = vec![programs[1].clone()];
+        let spec = RecordingRuleSpec {
+            keyword: Some("news".to_string()),
+            priority: 1,
+            ..Default::default()
+        };
+        let previews = manager.preview_rule(&spec, programs).unwrap();
+        assert_eq!(previews.len(), 1);
+        assert!(previews[0].conflict.is_none());
+        assert_eq!(previews[0].conflicts,
+                   vec![MirakurunProgramId::from(create_quad(1))]);
+        assert_eq!(manager.schedules.len(), 1);
+
+        let result = manager.preview_rule(&Default::default(), Vec::new());
+        assert_matches!(result, Err(Error::BadRequest(_)));
+    }
+
+    #[test]
+    fn test_make_record_filters() {
+        let mut config = serde_yaml::from_str::<Config>(r#"
+            channels:
+              - name: ch
+                type: GR
+                channel: '1'
+            pre-filters:
+              pre:
+                command: pre
+            post-filters:
+              post:
+                command: post
+            filters:
+              service-default-filters:
+                - services: [100003]  # NID=1, SID=3
+                  pre-filters: [pre]
+                  post-filters: [post]
+        "#).unwrap();
+        config.filters.service_filter = Default::default();
+        config.filters.program_filter = Default::default();
+        let config = Arc::new(config);
+        let named_filters = NamedFilters::new(config.clone());
+        let data = mustache::MapBuilder::new().build();
+
+        let mut service = EpgService {
+            nid: 1.into(),
+            tsid: 2.into(),
+            sid: 3.into(),
+            service_type: 1,
+            logo_id: 0,
+            remote_control_key_id: 0,
+            name: "service".to_string(),
+            network_name: None,
+            channel: EpgChannel::from(config.channels[0].clone()),
+        };
+        assert_matches!(
+            make_record_filters(&config, &named_filters, &service, &data),
+            Ok(filters) => {
+                assert_eq!(filters, vec![
+                    ("pre".to_string(), None),
+                    ("post".to_string(), None),
+                ]);
+            });
+
+        // The same SID in another network.
+        service.nid = 2.into();
+        assert_matches!(
+            make_record_filters(&config, &named_filters, &service, &data),
+            Ok(filters) => assert!(filters.is_empty()));
+    }
+
+    fn create_config(num_tuners: usize) -> Config {
+        let mut config = Config::default();
+        for i in 0..num_tuners {
+            config.tuners.push(serde_yaml::from_str(&format!(r#"
+                name: tuner{}
+                types: [GR]
+                command: open-tuner
+            "#, i)).unwrap());
+        }
+        config
+    }
+
+    fn create_manager(config: Config) -> Addr<RecordingManager> {
+        let config = Arc::new(config);
+        let tuner_manager = crate::tuner::start(config.clone());
+        let epg = crate::epg::start(config.clone());
+        RecordingManager::new(
+            config.clone(), tuner_manager, epg,
+            Arc::new(NamedFilters::new(config))).start()
+    }
+
+    fn create_quad(eid: u16) -> EventQuad {
+        EventQuad::new(1.into(), 2.into(), 3.into(), eid.into())
+    }
+
+    fn create_schedule(
+        eid: u16,
+        start_at: DateTime<Jst>,
+        rule_id: Option<u32>,
+    ) -> RecordingSchedule {
+        RecordingSchedule {
+            program_id: create_quad(eid).into(),
+            start_at,
+            duration: Duration::minutes(30),
+            state: RecordingScheduleState::Scheduled,
+            rule_id,
+            conflict: None,
+            max_retries: 0,
+            retries: 0,
+        }
+    }
+
+    fn create_recording(eid: u16, path: &str) -> Recording {
+        Recording {
+            program_id: create_quad(eid).into(),
+            path: path.into(),
+            index_path: None,
+            start_at: Jst::now(),
+            duration: Duration::minutes(30),
+            size: 0,
+        }
+    }
+}
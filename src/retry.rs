@@ -0,0 +1,99 @@
+use std::future::Future;
+use std::time::Duration;
+
+use humantime;
+use log;
+
+use crate::config::RetryConfig;
+use crate::error::Error;
+
+// Performs `op` until it succeeds or fails with a non-retryable error.  It's
+// performed up to `max_attempts` times, and the interval between attempts is
+// doubled on each retry up to `max_interval`.
+//
+// `channel_name` is used only for logging.
+pub async fn retry<T, F, Fut>(
+    config: &RetryConfig,
+    channel_name: &str,
+    mut op: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let max_attempts = config.max_attempts.max(1);
+    let max_interval = Duration::from_millis(config.max_interval);
+    let mut interval =
+        Duration::from_millis(config.initial_interval).min(max_interval);
+    let mut attempts = 1;
+    loop {
+        match op().await {
+            Err(err) if err.is_retryable() && attempts < max_attempts => {
+                log::warn!("Failed in {}: {}, retry in {} ({}/{})",
+                           channel_name, err,
+                           humantime::format_duration(interval),
+                           attempts, max_attempts);
+                tokio::time::delay_for(interval).await;
+                interval = (interval * 2).min(max_interval);
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[actix_rt::test]
+    async fn test_retry() {
+        let config = RetryConfig {
+            max_attempts: 3,
+            initial_interval: 0,
+            max_interval: 0,
+        };
+
+        // Succeeds after retries.
+        let attempts = Cell::new(0);
+        let result = retry(&config, "ch", || {
+            attempts.set(attempts.get() + 1);
+            let result = if attempts.get() < 3 {
+                Err(Error::TunerUnavailable)
+            } else {
+                Ok(attempts.get())
+            };
+            async move { result }
+        }).await;
+        assert_eq!(result.unwrap(), 3);
+
+        // Gives up after `max_attempts`.
+        let attempts = Cell::new(0);
+        let result: Result<(), Error> = retry(&config, "ch", || {
+            attempts.set(attempts.get() + 1);
+            async { Err(Error::TunerUnavailable) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+
+        // Never retries a non-retryable error.
+        let attempts = Cell::new(0);
+        let result: Result<(), Error> = retry(&config, "ch", || {
+            attempts.set(attempts.get() + 1);
+            async { Err(Error::ChannelNotFound) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+
+        // `no_retry()` performs the operation only once.
+        let config = RetryConfig::no_retry();
+        let attempts = Cell::new(0);
+        let result: Result<(), Error> = retry(&config, "ch", || {
+            attempts.set(attempts.get() + 1);
+            async { Err(Error::TunerUnavailable) }
+        }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
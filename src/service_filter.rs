@@ -0,0 +1,440 @@
+use std::collections::HashSet;
+
+// A built-in service filter which extracts TS packets of a single service
+// from a TS stream containing multiple services.
+//
+// The following packets are retained:
+//
+//   * PAT rewritten so that it contains only the service
+//   * PMT of the service
+//   * PCR, elementary streams and ECM listed in the PMT
+//   * EIT
+//
+// Other packets are dropped.  Packets of the service are dropped until the PAT
+// and the PMT come.
+//
+// Only PAT and PMT sections which fit in a single TS packet are processed.
+// Unlike `mirakc-arib filter-service`, the service filter doesn't wait for
+// the start of the first program and doesn't stop at the end of the service.
+pub struct ServiceFilter {
+    buf: Vec<u8>,
+    sid: u16,
+    pmt_pid: Option<u16>,
+    pids: HashSet<u16>,  // PIDs listed in the PMT
+}
+
+impl ServiceFilter {
+    const PACKET_SIZE: usize = 188;
+    const SYNC_BYTE: u8 = 0x47;
+    const PAT_PID: u16 = 0x0000;
+    const EIT_PID: u16 = 0x0012;
+    const CA_DESCRIPTOR_TAG: u8 = 0x09;
+
+    pub fn new(sid: u16) -> Self {
+        ServiceFilter {
+            buf: Vec::with_capacity(Self::PACKET_SIZE * 2),
+            sid,
+            pmt_pid: None,
+            pids: HashSet::new(),
+        }
+    }
+
+    // Returns TS packets of the service aligned to the packet size.  The
+    // remaining bytes are kept until the next call.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        self.buf.extend_from_slice(data);
+
+        let mut output = Vec::with_capacity(self.buf.len());
+        let mut packet = [0; Self::PACKET_SIZE];
+        let mut pos = 0;
+        while self.buf.len() - pos >= Self::PACKET_SIZE {
+            if self.buf[pos] != Self::SYNC_BYTE {
+                // Resync.
+                pos += 1;
+                continue;
+            }
+            packet.copy_from_slice(&self.buf[pos..(pos + Self::PACKET_SIZE)]);
+            self.filter(&mut packet, &mut output);
+            pos += Self::PACKET_SIZE;
+        }
+
+        self.buf.drain(..pos);
+
+        output
+    }
+
+    // Discards the remaining bytes of an incomplete packet.  Used when the
+    // source of the stream changes.
+    pub fn reset(&mut self) {
+        self.buf.clear();
+    }
+
+    fn filter(&mut self, packet: &mut [u8], output: &mut Vec<u8>) {
+        let pid = ((packet[1] as u16 & 0x1F) << 8) | packet[2] as u16;
+        if pid == Self::PAT_PID {
+            if self.update_pat(packet) {
+                output.extend_from_slice(packet);
+            }
+        } else if Some(pid) == self.pmt_pid {
+            self.update_pmt(packet);
+            output.extend_from_slice(packet);
+        } else if pid == Self::EIT_PID || self.pids.contains(&pid) {
+            output.extend_from_slice(packet);
+        }
+    }
+
+    // Rewrites the PAT packet in place.  Returns false if the packet should be
+    // dropped.
+    fn update_pat(&mut self, packet: &mut [u8]) -> bool {
+        let (tsid, version, pmt_pid) = match Self::parse_single_section(packet)
+            .and_then(|section| self.parse_pat(section)) {
+            Some(pat) => pat,
+            None => return false,
+        };
+
+        if self.pmt_pid != Some(pmt_pid) {
+            log::debug!("Service#{}: PMT PID: {:04X}", self.sid, pmt_pid);
+            self.pmt_pid = Some(pmt_pid);
+            self.pids.clear();
+        }
+
+        let mut section = Vec::with_capacity(16);
+        section.push(0x00);  // table_id
+        section.extend_from_slice(&[0xB0, 13]);  // section_length
+        section.extend_from_slice(&tsid.to_be_bytes());
+        section.push(version);
+        section.extend_from_slice(&[0x00, 0x00]);  // section numbers
+        section.extend_from_slice(&self.sid.to_be_bytes());
+        section.extend_from_slice(&(0xE000 | pmt_pid).to_be_bytes());
+        let crc = crc32(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+
+        // Keep the header including the continuity counter.  An adaptation
+        // field is removed.
+        packet[3] = (packet[3] & 0xCF) | 0x10;  // payload only
+        packet[4] = 0;  // pointer_field
+        packet[5..(5 + section.len())].copy_from_slice(&section);
+        for byte in packet[(5 + section.len())..].iter_mut() {
+            *byte = 0xFF;
+        }
+        true
+    }
+
+    fn update_pmt(&mut self, packet: &[u8]) {
+        let section = match Self::parse_single_section(packet) {
+            Some(section) => section,
+            None => return,
+        };
+        if let Some(pids) = self.parse_pmt(section) {
+            if pids != self.pids {
+                log::debug!("Service#{}: PIDs: {:04X?}", self.sid, pids);
+                self.pids = pids;
+            }
+        }
+    }
+
+    // Returns a section which starts in the packet and ends in the packet.
+    fn parse_single_section(packet: &[u8]) -> Option<&[u8]> {
+        if packet[1] & 0x80 != 0 {
+            return None;  // transport error
+        }
+        if packet[1] & 0x40 == 0 {
+            return None;  // no section starts in the packet
+        }
+        let adaptation_field_control = (packet[3] >> 4) & 0x03;
+        if adaptation_field_control & 0x01 == 0 {
+            return None;  // no payload
+        }
+        let mut pos = 4;
+        if adaptation_field_control & 0x02 != 0 {
+            pos += 1 + packet[4] as usize;
+        }
+        if pos >= Self::PACKET_SIZE {
+            return None;
+        }
+        pos += 1 + packet[pos] as usize;  // pointer_field
+        if pos + 3 > Self::PACKET_SIZE {
+            return None;
+        }
+        let section_length =
+            ((packet[pos + 1] as usize & 0x0F) << 8) | packet[pos + 2] as usize;
+        let end = pos + 3 + section_length;
+        if end > Self::PACKET_SIZE {
+            return None;
+        }
+        Some(&packet[pos..end])
+    }
+
+    // Returns the transport_stream_id, the version byte and the PMT PID of the
+    // service.
+    fn parse_pat(&self, section: &[u8]) -> Option<(u16, u8, u16)> {
+        // table_id + section_length + 5 bytes + CRC_32
+        const MIN_SIZE: usize = 12;
+        if section[0] != 0x00 || section.len() < MIN_SIZE {
+            return None;
+        }
+        let tsid = ((section[3] as u16) << 8) | section[4] as u16;
+        let version = section[5];
+        let end = section.len() - 4;
+        let mut pos = 8;
+        while pos + 4 <= end {
+            let program_number =
+                ((section[pos] as u16) << 8) | section[pos + 1] as u16;
+            let pid = ((section[pos + 2] as u16 & 0x1F) << 8) |
+                section[pos + 3] as u16;
+            if program_number == self.sid {
+                return Some((tsid, version, pid));
+            }
+            pos += 4;
+        }
+        None
+    }
+
+    // Returns PIDs of PCR, elementary streams and ECM.
+    fn parse_pmt(&self, section: &[u8]) -> Option<HashSet<u16>> {
+        // table_id + section_length + 9 bytes + CRC_32
+        const MIN_SIZE: usize = 16;
+        if section[0] != 0x02 || section.len() < MIN_SIZE {
+            return None;
+        }
+        let program_number = ((section[3] as u16) << 8) | section[4] as u16;
+        if program_number != self.sid {
+            return None;
+        }
+        let end = section.len() - 4;
+
+        let mut pids = HashSet::new();
+        let pcr_pid = ((section[8] as u16 & 0x1F) << 8) | section[9] as u16;
+        pids.insert(pcr_pid);
+
+        let program_info_length =
+            ((section[10] as usize & 0x0F) << 8) | section[11] as usize;
+        let mut pos = 12;
+        if pos + program_info_length > end {
+            return None;
+        }
+        Self::collect_ecm_pids(
+            &section[pos..(pos + program_info_length)], &mut pids);
+        pos += program_info_length;
+
+        while pos + 5 <= end {
+            let pid = ((section[pos + 1] as u16 & 0x1F) << 8) |
+                section[pos + 2] as u16;
+            let es_info_length =
+                ((section[pos + 3] as usize & 0x0F) << 8) |
+                section[pos + 4] as usize;
+            pos += 5;
+            if pos + es_info_length > end {
+                return None;
+            }
+            pids.insert(pid);
+            Self::collect_ecm_pids(
+                &section[pos..(pos + es_info_length)], &mut pids);
+            pos += es_info_length;
+        }
+
+        Some(pids)
+    }
+
+    fn collect_ecm_pids(descriptors: &[u8], pids: &mut HashSet<u16>) {
+        let mut pos = 0;
+        while pos + 2 <= descriptors.len() {
+            let tag = descriptors[pos];
+            let len = descriptors[pos + 1] as usize;
+            let body = &descriptors[(pos + 2)..];
+            if tag == Self::CA_DESCRIPTOR_TAG && len >= 4 && body.len() >= 4 {
+                pids.insert(((body[2] as u16 & 0x1F) << 8) | body[3] as u16);
+            }
+            pos += 2 + len;
+        }
+    }
+}
+
+// CRC-32/MPEG-2 used in PSI sections.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0x0376_E6E7);
+        // A section followed by its CRC_32 always results in 0.
+        let mut section = vec![0x00, 0xB0, 0x0D, 0x7F, 0xE0, 0xC1, 0x00, 0x00,
+                               0x04, 0x00, 0xE1, 0xF0];
+        let crc = crc32(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+        assert_eq!(crc32(&section), 0);
+    }
+
+    #[test]
+    fn test_feed() {
+        let mut filter = ServiceFilter::new(2);
+
+        let pat = create_pat_packet(&[(1, 0x0101), (2, 0x0102)]);
+        let pmt1 = create_pmt_packet(0x0101, 1, 0x0111, &[0x0111]);
+        let pmt2 = create_pmt_packet(0x0102, 2, 0x0121, &[0x0121, 0x0122]);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&create_packet(0x0121));  // before PAT/PMT
+        data.extend_from_slice(&pat);
+        data.extend_from_slice(&pmt1);
+        data.extend_from_slice(&pmt2);
+        data.extend_from_slice(&create_packet(0x0111));
+        data.extend_from_slice(&create_packet(0x0121));
+        data.extend_from_slice(&create_packet(0x0122));
+        data.extend_from_slice(&create_packet(0x0012));
+        data.extend_from_slice(&create_packet(0x1FFF));
+
+        // Feed data split at a position which is not aligned to packets.
+        let mut output = filter.feed(&data[..300]);
+        output.extend(filter.feed(&data[300..]));
+
+        let packets: Vec<&[u8]> =
+            output.chunks(ServiceFilter::PACKET_SIZE).collect();
+        assert_eq!(packets.len(), 5);
+
+        // The PAT contains only the service.
+        let section = ServiceFilter::parse_single_section(packets[0]).unwrap();
+        assert_eq!(section.len(), 16);
+        assert_eq!(&section[8..12], &[0x00, 0x02, 0xE1, 0x02]);
+        assert_eq!(crc32(section), 0);
+
+        assert_eq!(packets[1], &pmt2[..]);
+        assert_eq!(packets[2], &create_packet(0x0121)[..]);
+        assert_eq!(packets[3], &create_packet(0x0122)[..]);
+        assert_eq!(packets[4], &create_packet(0x0012)[..]);
+    }
+
+    #[test]
+    fn test_feed_separate_pcr_pid() {
+        let mut filter = ServiceFilter::new(1);
+
+        // PCR is carried on a PID other than elementary streams.
+        let pat = create_pat_packet(&[(1, 0x0101)]);
+        let pmt = create_pmt_packet(0x0101, 1, 0x01FF, &[0x0111, 0x0112]);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&pat);
+        data.extend_from_slice(&pmt);
+        data.extend_from_slice(&create_packet(0x01FF));
+        data.extend_from_slice(&create_packet(0x0111));
+        data.extend_from_slice(&create_packet(0x0112));
+        data.extend_from_slice(&create_packet(0x0113));
+
+        let output = filter.feed(&data);
+        let packets: Vec<&[u8]> =
+            output.chunks(ServiceFilter::PACKET_SIZE).collect();
+        assert_eq!(packets.len(), 5);
+        assert_eq!(packets[1], &pmt[..]);
+        assert_eq!(packets[2], &create_packet(0x01FF)[..]);
+        assert_eq!(packets[3], &create_packet(0x0111)[..]);
+        assert_eq!(packets[4], &create_packet(0x0112)[..]);
+    }
+
+    #[test]
+    fn test_feed_unknown_service() {
+        let mut filter = ServiceFilter::new(3);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&create_pat_packet(&[(1, 0x0101)]));
+        data.extend_from_slice(&create_pmt_packet(0x0101, 1, 0x0111, &[]));
+        data.extend_from_slice(&create_packet(0x0111));
+
+        assert!(filter.feed(&data).is_empty());
+    }
+
+    #[test]
+    fn test_parse_pmt() {
+        let filter = ServiceFilter::new(1);
+
+        let mut packet = create_pmt_packet(0x0101, 1, 0x01FF, &[0x0111]);
+        // CA_descriptor for ECM in the first loop.
+        packet[16] = 6;  // program_info_length
+        let mut body = vec![0x09, 0x04, 0x00, 0x05, 0xE9, 0x01];
+        body.extend_from_slice(&packet[17..22]);  // elementary stream
+        packet[7] += 6;  // section_length
+        packet[17..(17 + body.len())].copy_from_slice(&body);
+
+        let section = ServiceFilter::parse_single_section(&packet).unwrap();
+        let pids = filter.parse_pmt(section).unwrap();
+        let mut pids: Vec<u16> = pids.into_iter().collect();
+        pids.sort();
+        assert_eq!(pids, vec![0x0111, 0x01FF, 0x0901]);
+
+        // Another service.
+        let filter = ServiceFilter::new(2);
+        assert!(filter.parse_pmt(section).is_none());
+    }
+
+    fn create_packet(pid: u16) -> Vec<u8> {
+        let mut packet = vec![0xFF; ServiceFilter::PACKET_SIZE];
+        packet[0] = ServiceFilter::SYNC_BYTE;
+        packet[1] = (pid >> 8) as u8 & 0x1F;
+        packet[2] = pid as u8;
+        packet[3] = 0x10;  // payload only
+        packet
+    }
+
+    fn create_section_packet(
+        pid: u16,
+        table_id: u8,
+        id: u16,
+        body: &[u8],
+    ) -> Vec<u8> {
+        let mut packet = create_packet(pid);
+        packet[1] |= 0x40;  // payload_unit_start_indicator
+        packet[4] = 0;  // pointer_field
+        packet[5] = table_id;
+        let section_length = 5 + body.len() + 4;
+        packet[6] = 0xB0 | (section_length >> 8) as u8;
+        packet[7] = section_length as u8;
+        packet[8] = (id >> 8) as u8;
+        packet[9] = id as u8;
+        packet[10..13].copy_from_slice(&[0xC1, 0x00, 0x00]);
+        packet[13..(13 + body.len())].copy_from_slice(body);
+        // CRC_32 is not checked.
+        packet[(13 + body.len())..(17 + body.len())].copy_from_slice(&[0; 4]);
+        packet
+    }
+
+    fn create_pat_packet(programs: &[(u16, u16)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (program_number, pid) in programs.iter() {
+            body.extend_from_slice(&program_number.to_be_bytes());
+            body.extend_from_slice(&(0xE000 | pid).to_be_bytes());
+        }
+        create_section_packet(ServiceFilter::PAT_PID, 0x00, 1, &body)
+    }
+
+    fn create_pmt_packet(
+        pid: u16,
+        sid: u16,
+        pcr_pid: u16,
+        es_pids: &[u16],
+    ) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(0xE000 | pcr_pid).to_be_bytes());
+        body.extend_from_slice(&[0xF0, 0x00]);  // program_info_length
+        for es_pid in es_pids.iter() {
+            body.push(0x02);  // stream_type
+            body.extend_from_slice(&(0xE000 | es_pid).to_be_bytes());
+            body.extend_from_slice(&[0xF0, 0x00]);  // ES_info_length
+        }
+        create_section_packet(pid, 0x02, sid, &body)
+    }
+}
@@ -1,22 +1,27 @@
+use std::time::Duration;
+
 use actix::prelude::*;
+use chrono::DateTime;
 use failure::Error;
 use indexmap::IndexMap;
 use log;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json;
-use tokio::io::AsyncReadExt;
-
-#[cfg(test)]
-use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::command_util;
+use crate::datetime_ext::*;
 use crate::epg::*;
+use crate::job::JobContext;
 use crate::models::*;
 use crate::tuner::*;
 
 pub struct ServiceScanner {
     command: String,
-    channels: Vec<EpgChannel>,
+    // Each channel is paired with SIDs of services expected to be found in it.
+    // See `channels[].expected-services` in docs/config.md.
+    channels: Vec<(EpgChannel, Vec<ServiceId>)>,
+    timeout: Option<Duration>,
+    context: JobContext,
     stream_manager: Recipient<StartStreamingMessage>,
 }
 
@@ -24,26 +29,47 @@ pub struct ServiceScanner {
 //       ClockSynchronizer and EitCollector.
 
 impl ServiceScanner {
-    const LABEL: &'static str = "service-scanner";
+    pub const LABEL: &'static str = "service-scanner";
 
     pub fn new(
         command: String,
-        channels: Vec<EpgChannel>,
+        channels: Vec<(EpgChannel, Vec<ServiceId>)>,
+        timeout: Option<Duration>,
         stream_manager: Recipient<StartStreamingMessage>,
     ) -> Self {
-        ServiceScanner { command, channels, stream_manager }
+        ServiceScanner {
+            command,
+            channels,
+            timeout,
+            context: JobContext::new(Self::LABEL),
+            stream_manager,
+        }
+    }
+
+    // `JobContext::new(Self::LABEL)` is used by default.
+    pub fn with_context(mut self, context: JobContext) -> Self {
+        self.context = context;
+        self
     }
 
+    // Returns stats of each channel together with results.
     pub async fn scan_services(
         self
-    ) -> Vec<(EpgChannel, Option<IndexMap<ServiceTriple, EpgService>>)> {
+    ) -> (Vec<(EpgChannel, Option<IndexMap<ServiceTriple, EpgService>>)>,
+          Vec<ServiceScanStats>) {
         log::debug!("Scanning services...");
 
         let mut results = Vec::new();
-        for channel in self.channels.iter() {
+        let mut stats = Vec::new();
+        for (channel, expected_services) in self.channels.iter() {
+            let _permit = self.context.acquire_tuner().await;
+            let started_at = Jst::now();
             let result = match Self::scan_services_in_channel(
-                &channel, &self.command, &self.stream_manager).await {
+                &channel, expected_services, &self.command, self.timeout,
+                &self.context, &self.stream_manager).await {
                 Ok(services) => {
+                    log::info!("Found {} services in {}",
+                               services.len(), channel.name);
                     let mut map = IndexMap::new();
                     for service in services.into_iter() {
                         map.insert(service.triple(), service.clone());
@@ -56,30 +82,37 @@ impl ServiceScanner {
                     None
                 }
             };
+            stats.push(ServiceScanStats {
+                channel_name: channel.name.clone(),
+                channel_type: channel.channel_type,
+                channel: channel.channel.clone(),
+                started_at,
+                duration: Jst::now() - started_at,
+                num_services: result.as_ref().map(|services| services.len()),
+            });
             results.push((channel.clone(), result));
         }
 
         log::debug!("Scanned {} channels", self.channels.len());
 
-        results
+        (results, stats)
     }
 
     async fn scan_services_in_channel(
         channel: &EpgChannel,
+        expected_services: &[ServiceId],
         command: &str,
+        timeout: Option<Duration>,
+        context: &JobContext,
         stream_manager: &Recipient<StartStreamingMessage>,
     ) -> Result<Vec<EpgService>, Error> {
         log::debug!("Scanning services in {}...", channel.name);
 
-        let user = TunerUser {
-            info: TunerUserInfo::Job { name: Self::LABEL.to_string() },
-            priority: (-1).into(),
-        };
-
-        let stream = stream_manager.send(StartStreamingMessage {
-            channel: channel.clone(),
-            user
-        }).await??;
+        let user = context.make_user(timeout
+            .and_then(|timeout| chrono::Duration::from_std(timeout).ok())
+            .map(|timeout| Jst::now() + timeout));
+        let stream =
+            context.start_streaming(channel, &user, stream_manager).await?;
 
         let template = mustache::compile_str(command)?;
         let data = mustache::MapBuilder::new()
@@ -88,15 +121,29 @@ impl ServiceScanner {
             .build();
         let cmd = template.render_data_to_string(&data)?;
 
-        let mut pipeline = command_util::spawn_pipeline(
-            vec![cmd], stream.id())?;
+        let mut pipeline =
+            context.spawn_pipeline(channel, vec![cmd], stream.id()).await?;
 
         let (input, mut output) = pipeline.take_endpoints().unwrap();
 
         let handle = tokio::spawn(stream.pipe(input));
 
-        let mut buf = Vec::new();
-        output.read_to_end(&mut buf).await?;
+        let mut parser = ServiceParser::new();
+        let read = Self::read_output(
+            &mut output, &mut parser, expected_services, &channel.name);
+        let timed_out = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, read).await {
+                Ok(result) => {
+                    result?;
+                    false
+                }
+                Err(_) => true,
+            },
+            None => {
+                read.await?;
+                false
+            }
+        };
 
         // Explicitly dropping the output of the pipeline is needed.  The output
         // holds the child processes and it kills them when dropped.
@@ -106,16 +153,152 @@ impl ServiceScanner {
         // streaming in the next iteration.
         let _ = handle.await;
 
-        failure::ensure!(buf.len() > 0, "No service, maybe out of service");
+        if timed_out && parser.is_empty() {
+            // Treat the channel as having no service.  Its services will be
+            // removed.
+            log::warn!("Timed out without any output in {}, \
+                        maybe out of service", channel.name);
+            return Ok(Vec::new());
+        }
 
-        let services: Vec<TsService> = serde_json::from_slice(&buf)?;
-        log::debug!("Found {} services in {}", services.len(), channel.name);
+        failure::ensure!(!parser.is_empty(),
+                         "No service, maybe out of service");
+
+        let services = match parser.finish() {
+            Ok(services) => services,
+            Err(_) if timed_out => {
+                // Some services may be lost.  Keep the previous result.
+                failure::bail!("Timed out with partial output");
+            }
+            Err(err) => return Err(err),
+        };
+
+        for sid in expected_services.iter() {
+            if !services.iter().any(|sv| sv.sid == *sid) {
+                log::warn!("{} not found in {}", sid, channel.name);
+            }
+        }
 
         Ok(services
            .into_iter()
            .map(|sv| EpgService::from((channel, &sv)))
            .collect())
     }
+
+    async fn read_output<R>(
+        output: &mut R,
+        parser: &mut ServiceParser,
+        expected_services: &[ServiceId],
+        channel_name: &str,
+    ) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut chunk = [0; 4096];
+        loop {
+            let n = output.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            parser.feed(&chunk[..n])?;
+            if parser.is_closed() {
+                break;
+            }
+            // Stop reading once all expected services have been found,
+            // without waiting for the command to terminate.
+            if !expected_services.is_empty() &&
+                expected_services.iter().all(|sid| parser.contains(*sid)) {
+                log::debug!("Found all {} expected services in {}",
+                            expected_services.len(), channel_name);
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Parses service objects in the output of the command as soon as each of them
+// arrives.  The output is either a JSON array or JSON lines.
+struct ServiceParser {
+    buf: Vec<u8>,
+    services: Vec<TsService>,
+    received: bool,
+    opened: bool,
+    closed: bool,
+}
+
+impl ServiceParser {
+    fn new() -> Self {
+        ServiceParser {
+            buf: Vec::new(),
+            services: Vec::new(),
+            received: false,
+            opened: false,
+            closed: false,
+        }
+    }
+
+    // Returns true if nothing has been received.
+    fn is_empty(&self) -> bool {
+        !self.received
+    }
+
+    // Returns true if the end of the JSON array has been received.
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn contains(&self, sid: ServiceId) -> bool {
+        self.services.iter().any(|sv| sv.sid == sid)
+    }
+
+    // Only an incomplete object is kept in the buffer.  So, each byte is
+    // parsed at most a few times even if the object is split into many chunks.
+    fn feed(&mut self, data: &[u8]) -> Result<(), serde_json::Error> {
+        self.received = true;
+        self.buf.extend_from_slice(data);
+
+        let mut pos = 0;
+        while pos < self.buf.len() && !self.closed {
+            match self.buf[pos] {
+                b'[' if !self.opened && self.services.is_empty() => {
+                    self.opened = true;
+                    pos += 1;
+                }
+                b']' if self.opened => {
+                    self.closed = true;
+                    pos += 1;
+                }
+                b',' if self.opened => pos += 1,
+                b' ' | b'\t' | b'\r' | b'\n' => pos += 1,
+                _ => {
+                    let mut iter =
+                        serde_json::Deserializer::from_slice(&self.buf[pos..])
+                        .into_iter::<TsService>();
+                    match iter.next() {
+                        Some(Ok(service)) => {
+                            self.services.push(service);
+                            pos += iter.byte_offset();
+                        }
+                        Some(Err(err)) if err.is_eof() => break,
+                        Some(Err(err)) => return Err(err),
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        self.buf.drain(..pos);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Vec<TsService>, Error> {
+        failure::ensure!(self.opened == self.closed,
+                         "Unterminated JSON array");
+        failure::ensure!(self.buf.iter().all(u8::is_ascii_whitespace),
+                         "Incomplete service object");
+        Ok(self.services)
+    }
 }
 
 #[derive(Clone, Deserialize)]
@@ -132,6 +315,9 @@ struct TsService {
     #[serde(default)]
     remote_control_key_id: u16,
     name: String,
+    // Taken from NIT.  Older versions of the scanner command don't output it.
+    #[serde(default)]
+    network_name: Option<String>,
 }
 
 impl From<(&EpgChannel, &TsService)> for EpgService {
@@ -144,11 +330,29 @@ impl From<(&EpgChannel, &TsService)> for EpgService {
             logo_id: sv.logo_id,
             remote_control_key_id: sv.remote_control_key_id,
             name: sv.name.clone(),
+            // Treat an empty name as no name.
+            network_name: sv.network_name.clone()
+                .filter(|name| !name.is_empty()),
             channel: ch.clone(),
         }
     }
 }
 
+#[derive(Clone)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceScanStats {
+    pub channel_name: String,
+    pub channel_type: ChannelType,
+    pub channel: String,
+    #[serde(with = "serde_jst")]
+    pub started_at: DateTime<Jst>,
+    #[serde(with = "serde_duration_in_millis")]
+    pub duration: chrono::Duration,
+    // `None` if failed.
+    pub num_services: Option<usize>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -173,14 +377,15 @@ mod tests {
             }
         })).start();
 
-        let channels = vec![EpgChannel {
+        let channel = EpgChannel {
             name: "channel".to_string(),
             channel_type: ChannelType::GR,
             channel: "0".to_string(),
             extra_args: "".to_string(),
             services: vec![],
             excluded_services: vec![],
-        }];
+        };
+        let channels = vec![(channel.clone(), vec![])];
 
         let expected = vec![TsService {
             nid: 1.into(),
@@ -190,21 +395,135 @@ mod tests {
             logo_id: 0,
             remote_control_key_id: 1,
             name: "service".to_string(),
+            network_name: None,
         }];
 
         let cmd = format!(
             "echo '{}'", serde_json::to_string(&expected).unwrap());
         let scan = ServiceScanner::new(
-            cmd, channels.clone(), mock.clone().recipient());
-        let results = scan.scan_services().await;
+            cmd, channels.clone(), None, mock.clone().recipient());
+        let (results, stats) = scan.scan_services().await;
         assert!(results[0].1.is_some());
         assert_eq!(results[0].1.as_ref().unwrap().len(), 1);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].channel_name, channel.name);
+        assert_eq!(stats[0].num_services, Some(1));
 
         // Emulate out of services by using `false`
         let cmd = "false".to_string();
         let scan = ServiceScanner::new(
-            cmd, channels.clone(), mock.clone().recipient());
-        let results = scan.scan_services().await;
+            cmd, channels.clone(), None, mock.clone().recipient());
+        let (results, stats) = scan.scan_services().await;
         assert!(results[0].1.is_none());
+        assert_eq!(stats[0].num_services, None);
+
+        // Stop scanning before the command terminates if all expected services
+        // have been found.
+        let script = format!(
+            "echo {}; sleep 10",
+            shell_words::quote(&serde_json::to_string(&expected[0]).unwrap()));
+        let cmd = format!("sh -c {}", shell_words::quote(&script));
+        let channels = vec![(channel.clone(), vec![3.into()])];
+        let scan = ServiceScanner::new(
+            cmd, channels, None, mock.clone().recipient());
+        let results = tokio::time::timeout(
+            std::time::Duration::from_secs(5), scan.scan_services()).await;
+        assert!(results.is_ok());
+        let (results, _) = results.unwrap();
+        assert!(results[0].1.is_some());
+        assert_eq!(results[0].1.as_ref().unwrap().len(), 1);
+
+        // Timed out without any output.  The command is killed without waiting
+        // for it to terminate.
+        let cmd = "sleep 10".to_string();
+        let channels = vec![(channel.clone(), vec![])];
+        let scan = ServiceScanner::new(
+            cmd, channels, Some(Duration::from_millis(100)),
+            mock.clone().recipient());
+        let results = tokio::time::timeout(
+            std::time::Duration::from_secs(5), scan.scan_services()).await;
+        assert!(results.is_ok());
+        let (results, _) = results.unwrap();
+        assert!(results[0].1.is_some());
+        assert!(results[0].1.as_ref().unwrap().is_empty());
+
+        // Timed out with partial output.
+        let cmd = "sh -c 'echo [; sleep 10'".to_string();
+        let channels = vec![(channel.clone(), vec![])];
+        let scan = ServiceScanner::new(
+            cmd, channels, Some(Duration::from_millis(100)),
+            mock.clone().recipient());
+        let (results, _) = scan.scan_services().await;
+        assert!(results[0].1.is_none());
+    }
+
+    #[test]
+    fn test_service_parser() {
+        let a = r#"{"nid":1,"tsid":2,"sid":3,"type":1,"name":"a"}"#;
+        let b = r#"{"nid":1,"tsid":2,"sid":4,"type":1,"name":"b"}"#;
+
+        // A JSON array split into chunks.
+        let json = format!("[\n  {},\n  {}\n]\n", a, b);
+        let (head, tail) = json.split_at(20);
+        let mut parser = ServiceParser::new();
+        assert!(parser.is_empty());
+        parser.feed(head.as_bytes()).unwrap();
+        assert!(!parser.is_empty());
+        assert!(!parser.contains(3.into()));
+        parser.feed(&tail.as_bytes()[..40]).unwrap();
+        assert!(parser.contains(3.into()));
+        assert!(!parser.contains(4.into()));
+        assert!(!parser.is_closed());
+        parser.feed(&tail.as_bytes()[40..]).unwrap();
+        assert!(parser.contains(4.into()));
+        assert!(parser.is_closed());
+        assert_eq!(parser.finish().unwrap().len(), 2);
+
+        // JSON lines.
+        let mut parser = ServiceParser::new();
+        parser.feed(format!("{}\n{}", a, &b[..10]).as_bytes()).unwrap();
+        assert!(parser.contains(3.into()));
+        parser.feed(format!("{}\n", &b[10..]).as_bytes()).unwrap();
+        assert!(parser.contains(4.into()));
+        assert!(!parser.is_closed());
+        assert_eq!(parser.finish().unwrap().len(), 2);
+
+        // Incomplete outputs.
+        let mut parser = ServiceParser::new();
+        parser.feed(b"[").unwrap();
+        assert!(parser.finish().is_err());
+
+        let mut parser = ServiceParser::new();
+        parser.feed(&a.as_bytes()[..10]).unwrap();
+        assert!(parser.finish().is_err());
+
+        // Broken outputs.
+        let mut parser = ServiceParser::new();
+        assert!(parser.feed(b"[x]").is_err());
+    }
+
+    #[test]
+    fn test_network_name() {
+        let channel = EpgChannel {
+            name: "channel".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "0".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        };
+
+        let json = r#"[
+            {"nid":1,"tsid":2,"sid":3,"type":1,"name":"a","networkName":"n"},
+            {"nid":1,"tsid":2,"sid":4,"type":1,"name":"b","networkName":""},
+            {"nid":1,"tsid":2,"sid":5,"type":1,"name":"c"}
+        ]"#;
+        let services: Vec<TsService> = serde_json::from_str(json).unwrap();
+        let services: Vec<EpgService> = services.iter()
+            .map(|sv| EpgService::from((&channel, sv)))
+            .collect();
+        assert_eq!(services[0].network_name, Some("n".to_string()));
+        assert_eq!(services[1].network_name, None);
+        assert_eq!(services[2].network_name, None);
     }
 }
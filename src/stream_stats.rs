@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::DateTime;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::datetime_ext::*;
+
+// Statistics of streams served to clients.
+//
+// Statistics of a stream remain queryable for a retention period after the
+// stream ends, so that a client can query them after it has received the whole
+// stream.
+static STREAM_STATS: Lazy<Mutex<StreamStatsTable>> =
+    Lazy::new(Default::default);
+
+pub fn register(retention: Duration) -> StreamStatsHandle {
+    STREAM_STATS.lock().unwrap().register(retention)
+}
+
+pub fn query(id: u64) -> Option<StreamStats> {
+    STREAM_STATS.lock().unwrap().query(id)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamStats {
+    pub id: u64,
+    #[serde(with = "serde_jst")]
+    pub started_at: DateTime<Jst>,
+    pub duration: u64,  // in milliseconds
+    pub bytes: u64,
+    pub chunks: u64,
+    pub active: bool,
+}
+
+// Counters are updated without locking the table.
+pub struct StreamStatsHandle {
+    id: u64,
+    counter: Arc<Counter>,
+}
+
+impl StreamStatsHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn add_chunk(&self, len: usize) {
+        self.counter.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.counter.chunks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for StreamStatsHandle {
+    fn drop(&mut self) {
+        STREAM_STATS.lock().unwrap().finish(self.id);
+    }
+}
+
+#[derive(Default)]
+struct Counter {
+    bytes: AtomicU64,
+    chunks: AtomicU64,
+}
+
+#[derive(Default)]
+struct StreamStatsTable {
+    last_id: u64,
+    entries: HashMap<u64, Entry>,
+}
+
+struct Entry {
+    started_at: DateTime<Jst>,
+    started: Instant,
+    ended: Option<Instant>,
+    retention: Duration,
+    counter: Arc<Counter>,
+}
+
+impl StreamStatsTable {
+    fn register(&mut self, retention: Duration) -> StreamStatsHandle {
+        self.purge(Instant::now());
+        self.last_id += 1;
+        let counter = Arc::new(Counter::default());
+        self.entries.insert(self.last_id, Entry {
+            started_at: Jst::now(),
+            started: Instant::now(),
+            ended: None,
+            retention,
+            counter: counter.clone(),
+        });
+        StreamStatsHandle { id: self.last_id, counter }
+    }
+
+    fn finish(&mut self, id: u64) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            entry.ended = Some(Instant::now());
+        }
+    }
+
+    fn query(&mut self, id: u64) -> Option<StreamStats> {
+        self.purge(Instant::now());
+        self.entries.get(&id).map(|entry| {
+            let ended = entry.ended.unwrap_or_else(Instant::now);
+            StreamStats {
+                id,
+                started_at: entry.started_at,
+                duration: (ended - entry.started).as_millis() as u64,
+                bytes: entry.counter.bytes.load(Ordering::Relaxed),
+                chunks: entry.counter.chunks.load(Ordering::Relaxed),
+                active: entry.ended.is_none(),
+            }
+        })
+    }
+
+    fn purge(&mut self, now: Instant) {
+        self.entries.retain(|_, entry| match entry.ended {
+            Some(ended) => now - ended < entry.retention,
+            None => true,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_stats_table() {
+        let mut table = StreamStatsTable::default();
+
+        let handle = table.register(Duration::from_secs(60));
+        handle.add_chunk(10);
+        handle.add_chunk(20);
+
+        let stats = table.query(handle.id()).unwrap();
+        assert_eq!(stats.bytes, 30);
+        assert_eq!(stats.chunks, 2);
+        assert!(stats.active);
+
+        table.finish(handle.id());
+        let stats = table.query(handle.id()).unwrap();
+        assert!(!stats.active);
+
+        // Purged after the retention period.
+        table.purge(Instant::now() + Duration::from_secs(60));
+        assert!(table.query(handle.id()).is_none());
+
+        // Don't touch the global table in the drop handler.
+        std::mem::forget(handle);
+    }
+
+    #[test]
+    fn test_stream_stats_table_keeps_active_streams() {
+        let mut table = StreamStatsTable::default();
+
+        let handle = table.register(Duration::from_secs(0));
+        table.purge(Instant::now() + Duration::from_secs(60));
+        assert!(table.query(handle.id()).is_some());
+
+        std::mem::forget(handle);
+    }
+}
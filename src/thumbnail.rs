@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::ThumbnailsConfig;
+use crate::epg::EpgChannel;
+use crate::error::Error;
+
+// Thumbnails of programs are stored in `epg.thumbnails.dir` as files named
+// `<program-id>.<ext>`.  mirakc never creates the files by itself.  The files
+// are created by the command specified in `epg.thumbnails.command`, which runs
+// as a filter in front of the command collecting EIT sections in each channel.
+
+const EXTENSIONS: [&'static str; 4] = ["jpg", "png", "gif", "webp"];
+
+pub fn find_thumbnail(dir: &str, id: u64) -> Option<PathBuf> {
+    EXTENSIONS.iter()
+        .map(|ext| Path::new(dir).join(format!("{}.{}", id, ext)))
+        .find(|path| path.is_file())
+}
+
+// Removes thumbnails of programs not contained in `ids`.  Other files in the
+// directory are kept.
+pub fn prune_thumbnails(dir: &str, ids: &HashSet<u64>) -> io::Result<usize> {
+    let mut num_removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let id = match parse_thumbnail_path(&path) {
+            Some(id) => id,
+            None => continue,
+        };
+        if ids.contains(&id) {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(_) => (),
+            // Already removed by another task.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        }
+        log::debug!("Removed {}", path.display());
+        num_removed += 1;
+    }
+    Ok(num_removed)
+}
+
+fn parse_thumbnail_path(path: &Path) -> Option<u64> {
+    let ext = path.extension()?.to_str()?;
+    if !EXTENSIONS.contains(&ext) {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+// Returns a filter command extracting thumbnails from TS packets in `channel`,
+// or `None` if no command is specified.  The filter receives the same TS
+// packets as the command collecting EIT sections, and it must output the TS
+// packets read from `stdin` to `stdout` as they are.
+pub fn make_filter(
+    config: &ThumbnailsConfig,
+    channel: &EpgChannel,
+) -> Result<Option<String>, Error> {
+    if config.command.is_empty() {
+        return Ok(None);
+    }
+    let template = mustache::compile_str(&config.command)?;
+    let data = mustache::MapBuilder::new()
+        .insert_str("dir", &config.dir)
+        .insert_str("channel_name", &channel.name)
+        .insert("channel_type", &channel.channel_type)?
+        .insert_str("channel", &channel.channel)
+        .insert("sids", &channel.services)?
+        .insert("xsids", &channel.excluded_services)?
+        .build();
+    Ok(Some(template.render_data_to_string(&data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ChannelType;
+
+    #[test]
+    fn test_find_thumbnail() {
+        let dir = create_dir("find");
+        fs::write(dir.join("1.png"), "").unwrap();
+        fs::create_dir(dir.join("2.jpg")).unwrap();
+
+        let dir_str = dir.to_str().unwrap();
+        assert_eq!(find_thumbnail(dir_str, 1), Some(dir.join("1.png")));
+        // Not a file.
+        assert_eq!(find_thumbnail(dir_str, 2), None);
+        assert_eq!(find_thumbnail(dir_str, 3), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune_thumbnails() {
+        let dir = create_dir("prune");
+        for name in &["1.jpg", "2.png", "3.webp", "x.jpg", "4.txt"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        let ids = vec![1, 3].into_iter().collect();
+        let result = prune_thumbnails(dir.to_str().unwrap(), &ids);
+        assert_eq!(result.unwrap(), 1);
+
+        assert!(dir.join("1.jpg").exists());
+        assert!(!dir.join("2.png").exists());
+        assert!(dir.join("3.webp").exists());
+        // Files not looking like thumbnails are kept.
+        assert!(dir.join("x.jpg").exists());
+        assert!(dir.join("4.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_make_filter() {
+        let channel = EpgChannel {
+            name: "channel".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "27".to_string(),
+            extra_args: "".to_string(),
+            services: vec![1.into(), 2.into()],
+            excluded_services: vec![3.into()],
+        };
+
+        let config = ThumbnailsConfig {
+            dir: "/thumbnails".to_string(),
+            command: "extract --output={{dir}} --channel={{channel_type}}/\
+                      {{channel}}{{#sids}} --sids={{.}}{{/sids}}\
+                      {{#xsids}} --xsids={{.}}{{/xsids}}".to_string(),
+        };
+        assert_eq!(
+            make_filter(&config, &channel).unwrap(),
+            Some("extract --output=/thumbnails --channel=GR/27 --sids=1 \
+                  --sids=2 --xsids=3".to_string()));
+
+        let config = ThumbnailsConfig {
+            dir: "/thumbnails".to_string(),
+            command: "".to_string(),
+        };
+        assert_eq!(make_filter(&config, &channel).unwrap(), None);
+    }
+
+    fn create_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "mirakc-thumbnail-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+}
@@ -1,14 +1,25 @@
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::fmt;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
 
 use actix::prelude::*;
 use log;
 use mustache;
+use regex::Regex;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 
 use crate::broadcaster::*;
-use crate::command_util::{spawn_pipeline, CommandPipeline};
-use crate::config::{Config, TunerConfig};
+use crate::command_util::{
+    spawn_pipeline_with_limits, spawn_pipeline_with_stderr, CommandPipeline,
+    CommandPipelineOutput,
+};
+use crate::config::{
+    BroadcasterConfig, Config, DropPolicy, ProcessLimitsConfig, TunerConfig,
+    TunerSharingPolicy, ZeroReadPolicy,
+};
+use crate::datetime_ext::Jst;
 use crate::epg::EpgChannel;
 use crate::error::Error;
 use crate::models::*;
@@ -49,21 +60,128 @@ impl fmt::Display for TunerSubscriptionId {
     }
 }
 
+// Human-readable labels of identifiers used in log messages.
+//
+// The identifiers above are Copy types and cannot hold labels.  So, labels are
+// stored in a side table owned by TunerManager and shared with its sessions.
+// The table exists only when `server.log-session-label` is enabled.
+#[derive(Clone, Default)]
+struct SharedIdLabels(Option<Arc<RwLock<IdLabels>>>);
+
+impl SharedIdLabels {
+    fn new(enabled: bool) -> Self {
+        if enabled {
+            SharedIdLabels(Some(Arc::new(RwLock::new(Default::default()))))
+        } else {
+            SharedIdLabels(None)
+        }
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut IdLabels),
+    {
+        if let Some(ref labels) = self.0 {
+            if let Ok(mut labels) = labels.write() {
+                f(&mut *labels);
+            }
+        }
+    }
+
+    // Formats `id` with its label if it exists.
+    fn session(&self, id: TunerSessionId) -> String {
+        let label = self.0.as_ref()
+            .and_then(|labels| labels.read().ok())
+            .and_then(|labels| labels.sessions.get(&id.tuner_index).cloned());
+        match label {
+            Some(label) => format!("{}[{}]", id, label),
+            None => id.to_string(),
+        }
+    }
+
+    // Formats `id` with its label if it exists.
+    fn subscription(&self, id: TunerSubscriptionId) -> String {
+        let key = (id.session_id.tuner_index, id.serial_number);
+        let label = self.0.as_ref()
+            .and_then(|labels| labels.read().ok())
+            .and_then(|labels| labels.subscriptions.get(&key).cloned());
+        match label {
+            Some(label) => format!("{}[{}]", id, label),
+            None => id.to_string(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct IdLabels {
+    sessions: HashMap<usize, String>,
+    subscriptions: HashMap<(usize, u32), String>,
+}
+
+impl IdLabels {
+
+    fn set_session_label(&mut self, id: TunerSessionId, channel: &EpgChannel) {
+        self.sessions.insert(id.tuner_index, Self::make_channel_label(channel));
+    }
+
+    fn remove_session_label(&mut self, id: TunerSessionId) {
+        self.sessions.remove(&id.tuner_index);
+        self.subscriptions.retain(|(index, _), _| *index != id.tuner_index);
+    }
+
+    fn set_subscription_label(
+        &mut self,
+        id: TunerSubscriptionId,
+        channel: &EpgChannel,
+        user: &TunerUser,
+    ) {
+        let label = format!("{}/{}", Self::make_channel_label(channel),
+                            Self::make_user_label(user));
+        self.subscriptions.insert(
+            (id.session_id.tuner_index, id.serial_number), label);
+    }
+
+    fn remove_subscription_label(&mut self, id: TunerSubscriptionId) {
+        self.subscriptions.remove(
+            &(id.session_id.tuner_index, id.serial_number));
+    }
+
+    fn make_channel_label(channel: &EpgChannel) -> String {
+        format!("{}{}", channel.channel_type, channel.channel)
+    }
+
+    fn make_user_label(user: &TunerUser) -> String {
+        match user.info {
+            TunerUserInfo::Job { ref name } => format!("job:{}", name),
+            TunerUserInfo::Tracker { .. } => "tracker".to_string(),
+            TunerUserInfo::Web { remote: Some(ref remote), .. } =>
+                format!("web:{}", remote),
+            TunerUserInfo::Web { remote: None, .. } => "web".to_string(),
+        }
+    }
+}
+
 // tuner manager
 
 pub struct TunerManager {
     config: Arc<Config>,
     tuners: Vec<Tuner>,
+    labels: SharedIdLabels,
 }
 
 struct TunerSubscription {
     id: TunerSubscriptionId,
     broadcaster: Addr<Broadcaster>,
+    // Specified only for the subscription which activated the tuner.
+    startup_timeout: Option<Duration>,
+    drop_policy: DropPolicy,
+    timeshift: Option<Duration>,
 }
 
 impl TunerManager {
     pub fn new(config: Arc<Config>) -> Self {
-        TunerManager { config, tuners: Vec::new() }
+        let labels = SharedIdLabels::new(config.server.log_session_label);
+        TunerManager { config, tuners: Vec::new(), labels }
     }
 
     fn load_tuners(&mut self) {
@@ -73,16 +191,21 @@ impl TunerManager {
             .iter()
             .filter(|config| !config.disabled)
             .enumerate()
-            .map(|(i, config)| Tuner::new(i, config))
+            .map(|(i, config)| {
+                Tuner::new(i, config, &self.config.broadcaster)
+                    .with_labels(self.labels.clone())
+            })
             .collect();
         log::info!("Loaded {} tuners", tuners.len());
         self.tuners = tuners;
     }
 
+    // A tuner specified by `excluded` is never used.
     fn activate_tuner(
         &mut self,
         channel: EpgChannel,
         user: TunerUser,
+        excluded: Option<usize>,
     ) -> Result<TunerSubscription, Error> {
         if let TunerUserInfo::Tracker { stream_id } = user.info {
             let tuner = &mut self.tuners[stream_id.session_id.tuner_index];
@@ -92,19 +215,38 @@ impl TunerManager {
             return Err(Error::TunerUnavailable);
         }
 
-        let found = self.tuners
-            .iter_mut()
-            .find(|tuner| tuner.is_reuseable(&channel));
-        if let Some(tuner) = found {
-            log::info!("tuner#{}: Reuse tuner already activated for {}",
-                       tuner.index, channel);
-            return Ok(tuner.subscribe(user));
-        }
+        self.check_channel_type(&channel)?;
 
-        let found = self.tuners
+        // Messages are handled one by one, and a tuner is activated before
+        // the next message is handled.  So, identical requests coming at the
+        // same time are coalesced into a single session here, without locking.
+        // Don't defer the activation to a future.  Otherwise, the requests
+        // would race for a tuner.
+        let reusable = self.tuners
             .iter()
-            .position(|tuner| tuner.is_available_for(&channel));
-        if let Some(index) = found {
+            .filter(|tuner| Some(tuner.index) != excluded)
+            .find(|tuner| tuner.is_reuseable(&channel))
+            .map(|tuner| tuner.index);
+
+        // Tuners having higher priorities are used preferentially.
+        let available = self.tuners
+            .iter()
+            .filter(|tuner| Some(tuner.index) != excluded)
+            .filter(|tuner| tuner.is_available_for(&channel))
+            .max_by_key(|tuner| (tuner.priority, Reverse(tuner.index)))
+            .map(|tuner| tuner.index);
+
+        if let Some(index) = reusable {
+            if !self.prefers_available_tuner(index, available) {
+                log::info!("tuner#{}: Reuse tuner already activated for {}",
+                           index, channel);
+                return Ok(self.tuners[index].subscribe(user));
+            }
+            log::info!("tuner#{}: Not reused for {}, prefer tuner having a \
+                        higher priority", index, channel);
+        }
+
+        if let Some(index) = available {
             log::info!("tuner#{}: Activate for {}", index, channel);
             let filters = self.make_filter_commands(index, &channel)?;
             let tuner = &mut self.tuners[index];
@@ -116,13 +258,18 @@ impl TunerManager {
         // a tuner used by a low priority user.
         let found = self.tuners
             .iter()
+            .filter(|tuner| Some(tuner.index) != excluded)
             .filter(|tuner| tuner.is_supported_type(&channel))
-            .position(|tuner| tuner.can_grab(user.priority));
+            .filter(|tuner| self.can_preempt(tuner, user.priority))
+            .max_by_key(|tuner| (tuner.priority, Reverse(tuner.index)))
+            .map(|tuner| tuner.index);
         if let Some(index) = found {
             log::info!("tuner#{}: Grab tuner, rectivate for {}",
                        index, channel);
             let filters = self.make_filter_commands(index, &channel)?;
             let tuner = &mut self.tuners[index];
+            // Subscribers of the current session receive the end of the
+            // stream when the session is dropped.
             tuner.deactivate();
             tuner.activate(channel, filters)?;
             return Ok(tuner.subscribe(user));
@@ -132,6 +279,142 @@ impl TunerManager {
         Err(Error::TunerUnavailable)
     }
 
+    // Returns true if the available tuner should be activated instead of
+    // sharing the reusable tuner.
+    fn prefers_available_tuner(
+        &self,
+        reusable: usize,
+        available: Option<usize>,
+    ) -> bool {
+        let priority = self.tuners[reusable].priority;
+        match self.config.tuner_sharing.policy {
+            TunerSharingPolicy::PreferShare => false,
+            TunerSharingPolicy::PreferPreferredTuner => available
+                .map_or(false, |index| self.tuners[index].priority > priority),
+        }
+    }
+
+    // Preemption can be disabled in the config.  In this case, a tuner is
+    // never taken over even by a user having the grab priority.
+    fn can_preempt(&self, tuner: &Tuner, priority: TunerUserPriority) -> bool {
+        self.config.tuner_sharing.preemption && tuner.can_grab(priority)
+    }
+
+    // Tuners which don't support the channel type are never used for the
+    // channel.  Fail fast if no tuner supports it, so that the client can
+    // distinguish it from a temporary shortage of tuners.
+    fn check_channel_type(&self, channel: &EpgChannel) -> Result<(), Error> {
+        if self.tuners.iter().any(|tuner| tuner.is_supported_type(channel)) {
+            return Ok(());
+        }
+        log::warn!("No tuner supports {}", channel.channel_type);
+        Err(Error::UnsupportedChannelType(channel.channel_type))
+    }
+
+    // Checks whether `activate_tuner()` would succeed without changing the
+    // state of any tuner.
+    fn check_availability(
+        &self,
+        channel: &EpgChannel,
+        user: &TunerUser,
+    ) -> Result<(), Error> {
+        self.check_channel_type(channel)?;
+
+        let available = self.tuners.iter().any(|tuner| {
+            tuner.is_reuseable(channel) || tuner.is_available_for(channel) ||
+                (tuner.is_supported_type(channel) &&
+                 self.can_preempt(tuner, user.priority))
+        });
+        if available {
+            Ok(())
+        } else {
+            Err(Error::TunerUnavailable)
+        }
+    }
+
+    // The priority is changed only in memory.  The config file is never
+    // modified.
+    fn set_tuner_priority(
+        &mut self,
+        name: &str,
+        priority: i32,
+    ) -> Result<MirakurunTuner, Error> {
+        match self.tuners.iter_mut().find(|tuner| tuner.name == name) {
+            Some(tuner) => {
+                log::info!("tuner#{}: Change priority from {} to {}",
+                           tuner.index, tuner.priority, priority);
+                tuner.priority = priority;
+                Ok(tuner.get_model())
+            }
+            None => Err(Error::TunerNotFound),
+        }
+    }
+
+    fn start_streaming(
+        &mut self,
+        channel: EpgChannel,
+        user: TunerUser,
+        excluded: Option<usize>,
+        stop_streaming: Recipient<StopStreamingMessage>,
+    ) -> ActorResponse<Self, MpegTsStream, Error> {
+        let subscription = match self.activate_tuner(channel, user, excluded) {
+            Ok(broadcaster) => broadcaster,
+            Err(err) => return ActorResponse::reply(Err(Error::from(err))),
+        };
+
+        let id = subscription.id;
+        let fut = actix::fut::wrap_future::<_, Self>(
+            Self::subscribe(subscription, stop_streaming))
+            .map(move |result, act, ctx| {
+                match result {
+                    Ok(_) => log::info!("{}: Started streaming", id),
+                    Err(Error::TunerStartupTimedOut) => {
+                        log::error!("{}: No packet from the tuner command \
+                                     at startup", id);
+                        act.deactivate_tuner(id);
+                    }
+                    Err(_) => {
+                        log::error!("{}: Broadcaster may have stopped", id);
+                        act.deactivate_tuner(id);
+                    }
+                }
+                result.map(|stream| {
+                    MpegTsStream::new(id, stream, ctx.address().recipient())
+                })
+            });
+
+        ActorResponse::r#async(fut)
+    }
+
+    // Some tuner commands block on opening a device held by another process.
+    // The tuner is deactivated in order to kill such a command if no packet
+    // comes within the startup timeout.
+    async fn subscribe(
+        subscription: TunerSubscription,
+        stop_streaming: Recipient<StopStreamingMessage>,
+    ) -> Result<BroadcasterStream, Error> {
+        let mut stream = match subscription.timeshift {
+            Some(offset) => subscription.broadcaster.send(SubscribeFromMessage {
+                id: subscription.id,
+                drop_policy: subscription.drop_policy,
+                offset,
+                stop_streaming: Some(stop_streaming),
+            }).await?,
+            None => subscription.broadcaster.send(SubscribeMessage {
+                id: subscription.id,
+                drop_policy: subscription.drop_policy,
+                stop_streaming: Some(stop_streaming),
+            }).await?,
+        };
+        if let Some(timeout) = subscription.startup_timeout {
+            if tokio::time::timeout(timeout, stream.wait_for_data())
+                .await.is_err() {
+                return Err(Error::TunerStartupTimedOut);
+            }
+        }
+        Ok(stream)
+    }
+
     fn deactivate_tuner(&mut self, id: TunerSubscriptionId) {
         log::info!("tuner#{}: Deactivate", id.session_id.tuner_index);
         self.tuners[id.session_id.tuner_index].deactivate();
@@ -142,6 +425,22 @@ impl TunerManager {
         let _ = self.tuners[id.session_id.tuner_index].stop_streaming(id);
     }
 
+    // Deactivates tuners used for the channels.  Streams of their subscribers
+    // end normally like the end of a program.  Returns the number of
+    // deactivated tuners.
+    fn stop_sessions(&mut self, channels: &[EpgChannel]) -> usize {
+        let mut num_stopped = 0;
+        for tuner in self.tuners.iter_mut() {
+            if channels.iter().any(|channel| tuner.is_reuseable(channel)) {
+                log::info!("tuner#{}: Deactivate due to changes of the channel",
+                           tuner.index);
+                tuner.deactivate();
+                num_stopped += 1;
+            }
+        }
+        num_stopped
+    }
+
     fn make_filter_commands(
         &self,
         tuner_index: usize,
@@ -221,6 +520,66 @@ impl Handler<QueryTunersMessage> for TunerManager {
     }
 }
 
+// check tuner availability
+
+pub struct CheckTunerAvailabilityMessage {
+    pub channel: EpgChannel,
+    pub user: TunerUser,
+}
+
+impl fmt::Display for CheckTunerAvailabilityMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CheckTunerAvailability {} for {}", self.channel, self.user)
+    }
+}
+
+impl Message for CheckTunerAvailabilityMessage {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<CheckTunerAvailabilityMessage> for TunerManager {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: CheckTunerAvailabilityMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.check_availability(&msg.channel, &msg.user)
+    }
+}
+
+// set tuner priority
+
+pub struct SetTunerPriorityMessage {
+    pub name: String,
+    pub priority: i32,
+}
+
+impl fmt::Display for SetTunerPriorityMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SetTunerPriority of {} to {}", self.name, self.priority)
+    }
+}
+
+impl Message for SetTunerPriorityMessage {
+    type Result = Result<MirakurunTuner, Error>;
+}
+
+impl Handler<SetTunerPriorityMessage> for TunerManager {
+    type Result = Result<MirakurunTuner, Error>;
+
+    fn handle(
+        &mut self,
+        msg: SetTunerPriorityMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.set_tuner_priority(&msg.name, msg.priority)
+    }
+}
+
 // start streaming
 
 pub struct StartStreamingMessage {
@@ -244,36 +603,48 @@ impl Handler<StartStreamingMessage> for TunerManager {
     fn handle(
         &mut self,
         msg: StartStreamingMessage,
-        _: &mut Self::Context,
+        ctx: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
+        self.start_streaming(
+            msg.channel, msg.user, None, ctx.address().recipient())
+    }
+}
 
-        let subscription = match self.activate_tuner(msg.channel, msg.user) {
-            Ok(broadcaster) => broadcaster,
-            Err(err) => return ActorResponse::reply(Err(Error::from(err))),
-        };
+// failover streaming
 
-        let fut = actix::fut::wrap_future::<_, Self>(
-            subscription.broadcaster.send(SubscribeMessage {
-                id: subscription.id
-            }))
-            .map(move |result, act, ctx| {
-                if result.is_ok() {
-                    log::info!("{}: Started streaming", subscription.id);
-                } else {
-                    log::error!("{}: Broadcaster may have stopped",
-                                subscription.id);
-                    act.deactivate_tuner(subscription.id);
-                }
-                result
-                    .map(|stream| {
-                        MpegTsStream::new(
-                            subscription.id, stream, ctx.address().recipient())
-                    })
-                    .map_err(Error::from)
-            });
+// Stops the subscription of a failed tuner, and starts streaming on another
+// tuner.  The failed tuner is never used for the new subscription.
+pub struct FailoverStreamingMessage {
+    pub id: TunerSubscriptionId,
+    pub channel: EpgChannel,
+    pub user: TunerUser,
+}
 
-        ActorResponse::r#async(fut)
+impl fmt::Display for FailoverStreamingMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FailoverStreaming {} for {} to {}",
+               self.id, self.channel, self.user)
+    }
+}
+
+impl Message for FailoverStreamingMessage {
+    type Result = Result<MpegTsStream, Error>;
+}
+
+impl Handler<FailoverStreamingMessage> for TunerManager {
+    type Result = ActorResponse<Self, MpegTsStream, Error>;
+
+    fn handle(
+        &mut self,
+        msg: FailoverStreamingMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        let failed = msg.id.session_id.tuner_index;
+        self.stop_streaming(msg.id);
+        self.start_streaming(
+            msg.channel, msg.user, Some(failed), ctx.address().recipient())
     }
 }
 
@@ -306,6 +677,36 @@ impl Handler<StopStreamingMessage> for TunerManager {
     }
 }
 
+// stop sessions
+
+// Sent when the definitions of the channels have been changed.
+pub struct StopSessionsMessage {
+    pub channels: Vec<EpgChannel>,
+}
+
+impl fmt::Display for StopSessionsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StopSessions of {} channels", self.channels.len())
+    }
+}
+
+impl Message for StopSessionsMessage {
+    type Result = ();
+}
+
+impl Handler<StopSessionsMessage> for TunerManager {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: StopSessionsMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.stop_sessions(&msg.channels);
+    }
+}
+
 // tuner
 
 struct Tuner {
@@ -314,6 +715,15 @@ struct Tuner {
     channel_types: Vec<ChannelType>,
     command: String,
     time_limit: u64,
+    drop_log_interval: u64,
+    priority: i32,
+    signal_stats_pattern: Option<Regex>,
+    zero_read_policy: ZeroReadPolicy,
+    max_restarts: usize,
+    startup_timeout: u64,
+    process_limits: ProcessLimitsConfig,
+    broadcaster_config: BroadcasterConfig,
+    labels: SharedIdLabels,
     activity: TunerActivity,
 }
 
@@ -321,6 +731,7 @@ impl Tuner {
     fn new(
         index: usize,
         config: &TunerConfig,
+        broadcaster_config: &BroadcasterConfig,
     ) -> Self {
         Tuner {
             index,
@@ -328,10 +739,29 @@ impl Tuner {
             channel_types: config.channel_types.clone(),
             command: config.command.clone(),
             time_limit: config.time_limit,
+            drop_log_interval: config.drop_log_interval,
+            priority: config.priority,
+            signal_stats_pattern: config.signal_stats_pattern.as_ref()
+                .map(|pattern| Regex::new(pattern).unwrap_or_else(|err| {
+                    panic!("Invalid signal-stats-pattern for {}: {}",
+                           config.name, err);
+                })),
+            zero_read_policy: config.zero_read_policy,
+            max_restarts: config.max_restarts,
+            startup_timeout: config.startup_timeout,
+            process_limits: config.process_limits.clone(),
+            broadcaster_config: broadcaster_config.clone(),
+            labels: Default::default(),
             activity: TunerActivity::Inactive,
         }
     }
 
+    // Labels are not recorded by default.
+    fn with_labels(mut self, labels: SharedIdLabels) -> Self {
+        self.labels = labels;
+        self
+    }
+
     fn is_active(&self) -> bool {
         self.activity.is_active()
     }
@@ -363,7 +793,11 @@ impl Tuner {
     ) -> Result<(), Error> {
         let command = self.make_command(&channel)?;
         self.activity.activate(
-            self.index, channel, command, filters, self.time_limit)
+            self.index, channel, command, filters, self.time_limit,
+            self.drop_log_interval, self.signal_stats_pattern.clone(),
+            self.zero_read_policy, self.max_restarts, self.startup_timeout,
+            self.process_limits.clone(), &self.broadcaster_config,
+            self.labels.clone())
     }
 
     fn deactivate(&mut self) {
@@ -386,7 +820,8 @@ impl Tuner {
     }
 
     fn get_model(&self) -> MirakurunTuner {
-        let (command, pid, users) = self.activity.get_models();
+        let (command, pid, users, signal_stats) = self.activity.get_models();
+        let expected_free_at = Self::calc_expected_free_at(&users);
 
         MirakurunTuner {
             index: self.index,
@@ -400,9 +835,21 @@ impl Tuner {
             is_free: self.is_available(),
             is_using: !self.is_available(),
             is_fault: false,
+            priority: self.priority,
+            expected_free_at,
+            signal_stats,
         }
     }
 
+    // The tuner is expected to be free when all users end.  Returns `None` if
+    // there is a user which doesn't know when it ends.
+    fn calc_expected_free_at(users: &[MirakurunTunerUser]) -> Option<i64> {
+        users.iter()
+            .map(|user| user.expected_end_at)
+            .collect::<Option<Vec<i64>>>()
+            .and_then(|end_times| end_times.into_iter().max())
+    }
+
     fn make_command(&self, channel: &EpgChannel) -> Result<String, Error> {
         let template = mustache::compile_str(&self.command)?;
         let data = mustache::MapBuilder::new()
@@ -430,11 +877,22 @@ impl TunerActivity {
         command: String,
         filters: Vec<String>,
         time_limit: u64,
+        drop_log_interval: u64,
+        signal_stats_pattern: Option<Regex>,
+        zero_read_policy: ZeroReadPolicy,
+        max_restarts: usize,
+        startup_timeout: u64,
+        process_limits: ProcessLimitsConfig,
+        broadcaster_config: &BroadcasterConfig,
+        labels: SharedIdLabels,
     ) -> Result<(), Error> {
         match self {
             Self::Inactive => {
                 let session = TunerSession::new(
-                    tuner_index, channel, command, filters, time_limit)?;
+                    tuner_index, channel, command, filters, time_limit,
+                    drop_log_interval, signal_stats_pattern,
+                    zero_read_policy, max_restarts, startup_timeout,
+                    process_limits, broadcaster_config, labels)?;
                 *self = Self::Active(session);
                 Ok(())
             }
@@ -488,16 +946,22 @@ impl TunerActivity {
         }
     }
 
-    fn get_models(
-        &self
-    ) -> (Option<String>, Option<u32>, Vec<MirakurunTunerUser>) {
+    fn get_models(&self) -> TunerModels {
         match self {
-            Self::Inactive => (None, None, Vec::new()),
+            Self::Inactive => (None, None, Vec::new(), None),
             Self::Active(session) => session.get_models(),
         }
     }
 }
 
+// command, pid, users and signal stats
+type TunerModels = (
+    Option<String>,
+    Option<u32>,
+    Vec<MirakurunTunerUser>,
+    Option<TunerSignalStats>,
+);
+
 // session
 
 struct TunerSession {
@@ -505,10 +969,16 @@ struct TunerSession {
     channel: EpgChannel,
     command: String,
     // Used for closing the tuner in order to take over the right to use it.
-    pipeline: CommandPipeline<TunerSessionId>,
+    //
+    // Replaced with a new one when the tuner command is restarted.
+    pipeline: Arc<Mutex<CommandPipeline<TunerSessionId>>>,
     broadcaster: Addr<Broadcaster>,
     subscribers: HashMap<u32, TunerUser>,
     next_serial_number: u32,
+    // Updated by a task reading the stderr of the tuner command.
+    signal_stats: Arc<Mutex<Option<TunerSignalStats>>>,
+    startup_timeout: Option<Duration>,
+    labels: SharedIdLabels,
 }
 
 impl TunerSession {
@@ -518,24 +988,108 @@ impl TunerSession {
         command: String,
         mut filters: Vec<String>,
         time_limit: u64,
+        drop_log_interval: u64,
+        signal_stats_pattern: Option<Regex>,
+        zero_read_policy: ZeroReadPolicy,
+        max_restarts: usize,
+        startup_timeout: u64,
+        process_limits: ProcessLimitsConfig,
+        broadcaster_config: &BroadcasterConfig,
+        labels: SharedIdLabels,
     ) -> Result<TunerSession, Error> {
         let mut commands = vec![command.clone()];
         commands.append(&mut filters);
         let id = TunerSessionId { tuner_index };
-        let mut pipeline = spawn_pipeline(commands, id)?;
-        let (_, output) = pipeline.take_endpoints()?;
+        let signal_stats = Arc::new(Mutex::new(None));
+        let (pipeline, output) = Self::start_pipeline(
+            id, commands.clone(), &process_limits,
+            signal_stats_pattern.clone(), signal_stats.clone())?;
+        let pipeline = Arc::new(Mutex::new(pipeline));
+        let restarter = TunerCommandRestarter {
+            id,
+            commands,
+            process_limits,
+            signal_stats_pattern,
+            signal_stats: signal_stats.clone(),
+            pipeline: Arc::downgrade(&pipeline),
+        };
         let broadcaster = Broadcaster::create(|ctx| {
-            Broadcaster::new(id.clone(), output, time_limit, ctx)
+            // The exit status of the tuner command is checked even if it's
+            // never restarted, so that a failure of the tuner is reported to
+            // subscribers.
+            Broadcaster::new(
+                id.clone(), output, time_limit, drop_log_interval,
+                zero_read_policy, broadcaster_config, ctx)
+                .with_restarter(Box::new(restarter), max_restarts)
         });
 
-        log::info!("{}: Activated with {}", id, channel);
+        labels.update(|labels| labels.set_session_label(id, &channel));
+
+        log::info!("{}: Activated with {}", labels.session(id), channel);
+
+        let startup_timeout = match startup_timeout {
+            0 => None,
+            timeout => Some(Duration::from_millis(timeout)),
+        };
 
         Ok(TunerSession {
             id, channel, command, pipeline, broadcaster,
-            subscribers: HashMap::new(), next_serial_number: 1
+            subscribers: HashMap::new(), next_serial_number: 1, signal_stats,
+            startup_timeout, labels,
         })
     }
 
+    fn start_pipeline(
+        id: TunerSessionId,
+        commands: Vec<String>,
+        process_limits: &ProcessLimitsConfig,
+        signal_stats_pattern: Option<Regex>,
+        signal_stats: Arc<Mutex<Option<TunerSignalStats>>>,
+    ) -> Result<TunerPipeline, Error> {
+        let mut pipeline = match signal_stats_pattern {
+            Some(pattern) => {
+                let mut pipeline = spawn_pipeline_with_stderr(
+                    commands, id, process_limits)?;
+                if let Some(stderr) = pipeline.take_stderr()? {
+                    tokio::spawn(Self::track_signal_stats(
+                        id, stderr, pattern, signal_stats));
+                }
+                pipeline
+            }
+            None => spawn_pipeline_with_limits(commands, id, process_limits)?,
+        };
+        let (_, output) = pipeline.take_endpoints()?;
+        Ok((pipeline, output))
+    }
+
+    // Reads lines from the stderr of the tuner command until EOF, and keeps
+    // the latest signal statistics.
+    async fn track_signal_stats<R>(
+        id: TunerSessionId,
+        stderr: R,
+        pattern: Regex,
+        signal_stats: Arc<Mutex<Option<TunerSignalStats>>>,
+    )
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut reader = BufReader::new(stderr);
+        let mut data = Vec::with_capacity(256);
+        // See spawn_process() in the command_util module for the reason why
+        // read_until() is used instead of read_line().
+        while let Ok(n) = reader.read_until(0x0A, &mut data).await {
+            if n == 0 {  // EOF
+                break;
+            }
+            let line = String::from_utf8_lossy(&data);
+            log::trace!("{}: {}", id, line.trim_end());
+            if let Some(stats) = parse_signal_stats(&pattern, &line) {
+                *signal_stats.lock().unwrap() = Some(stats);
+            }
+            data.clear();
+        }
+    }
+
     fn is_reuseable(&self, channel: &EpgChannel) -> bool {
         self.channel.channel_type == channel.channel_type &&
             self.channel.channel == channel.channel
@@ -546,10 +1100,27 @@ impl TunerSession {
         self.next_serial_number += 1;
 
         let id = TunerSubscriptionId::new(self.id, serial_number);
-        log::info!("{}: Subscribed: {}", id, user);
+        self.labels.update(|labels| {
+            labels.set_subscription_label(id, &self.channel, &user)
+        });
+        log::info!("{}: Subscribed: {}", self.labels.subscription(id), user);
+        let drop_policy = user.drop_policy;
+        let timeshift = user.timeshift;
         self.subscribers.insert(serial_number, user);
 
-        TunerSubscription { id, broadcaster: self.broadcaster.clone() }
+        // Only the first subscriber waits for the tuner command to start.
+        let startup_timeout = match serial_number {
+            1 => self.startup_timeout,
+            _ => None,
+        };
+
+        TunerSubscription {
+            id,
+            broadcaster: self.broadcaster.clone(),
+            startup_timeout,
+            drop_policy,
+            timeshift,
+        }
     }
 
     fn can_grab(&self, priority: TunerUserPriority) -> bool {
@@ -567,29 +1138,98 @@ impl TunerSession {
                        id.session_id);
             return Err(Error::SessionNotFound);
         }
+        let label = self.labels.subscription(id);
         match self.subscribers.remove(&id.serial_number) {
-            Some(user) => log::info!("{}: Unsubscribed: {}", id, user),
-            None => log::warn!("{}: Not subscribed", id),
+            Some(user) => log::info!("{}: Unsubscribed: {}", label, user),
+            // The broadcaster may have closed the subscription before the
+            // client drops its stream.
+            None => log::debug!("{}: Not subscribed", label),
         }
+        self.labels.update(|labels| labels.remove_subscription_label(id));
         self.broadcaster.do_send(UnsubscribeMessage { id });
         Ok(self.subscribers.len())
     }
 
-    fn get_models(
-        &self
-    ) -> (Option<String>, Option<u32>, Vec<MirakurunTunerUser>) {
+    fn get_models(&self) -> TunerModels {
         (
             Some(self.command.clone()),
-            Some(self.pipeline.pids().as_slice().first().cloned().unwrap()),
+            Some(self.pipeline.lock().unwrap()
+                 .pids().as_slice().first().cloned().unwrap()),
             self.subscribers.values().map(|user| user.get_model()).collect(),
+            self.signal_stats.lock().unwrap().clone(),
         )
     }
 }
 
 impl Drop for TunerSession {
     fn drop(&mut self) {
-        log::info!("{}: Deactivated", self.id);
+        // Stop the broadcaster explicitly.  Otherwise, remaining subscribers
+        // would wait until the killed tuner command closes its output.
+        self.broadcaster.do_send(StopMessage);
+        log::info!("{}: Deactivated", self.labels.session(self.id));
+        let id = self.id;
+        self.labels.update(|labels| labels.remove_session_label(id));
+    }
+}
+
+type TunerPipeline = (
+    CommandPipeline<TunerSessionId>,
+    CommandPipelineOutput<TunerSessionId>,
+);
+
+// Respawns the tuner command of a session.
+//
+// The restarter holds a weak reference to the pipeline so that the tuner
+// command is never restarted after the session is deactivated.
+struct TunerCommandRestarter {
+    id: TunerSessionId,
+    commands: Vec<String>,
+    process_limits: ProcessLimitsConfig,
+    signal_stats_pattern: Option<Regex>,
+    signal_stats: Arc<Mutex<Option<TunerSignalStats>>>,
+    pipeline: Weak<Mutex<CommandPipeline<TunerSessionId>>>,
+}
+
+impl SourceRestarter for TunerCommandRestarter {
+    fn has_failed(&mut self) -> Option<bool> {
+        match self.pipeline.upgrade() {
+            Some(pipeline) => pipeline.lock().unwrap().has_failed(),
+            None => Some(false),
+        }
+    }
+
+    fn restart(&mut self) -> Result<Box<dyn AsyncRead + Unpin>, Error> {
+        let pipeline = match self.pipeline.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return Err(Error::SessionNotFound),
+        };
+        let (new_pipeline, output) = TunerSession::start_pipeline(
+            self.id, self.commands.clone(), &self.process_limits,
+            self.signal_stats_pattern.clone(), self.signal_stats.clone())?;
+        // The old pipeline is killed when it's dropped.
+        *pipeline.lock().unwrap() = new_pipeline;
+        log::info!("{}: Restarted the tuner command", self.id);
+        Ok(Box::new(output))
+    }
+}
+
+// Returns `None` if the line doesn't match with the pattern, or none of the
+// `snr` and `ber` named groups is captured as a number.
+fn parse_signal_stats(pattern: &Regex, line: &str) -> Option<TunerSignalStats> {
+    let captures = pattern.captures(line)?;
+    let parse = |name| {
+        captures.name(name).and_then(|m| m.as_str().parse::<f64>().ok())
+    };
+    let snr = parse("snr");
+    let ber = parse("ber");
+    if snr.is_none() && ber.is_none() {
+        return None;
     }
+    Some(TunerSignalStats {
+        snr,
+        ber,
+        updated_at: Jst::now().timestamp_millis(),
+    })
 }
 
 #[cfg(test)]
@@ -601,7 +1241,7 @@ mod tests {
     #[actix_rt::test]
     async fn test_tuner_is_active() {
         let config = create_config("true".to_string());
-        let mut tuner = Tuner::new(0, &config);
+        let mut tuner = Tuner::new(0, &config, &Default::default());
 
         assert!(!tuner.is_active());
 
@@ -620,7 +1260,7 @@ mod tests {
     async fn test_tuner_activate() {
         {
             let config = create_config("true".to_string());
-            let mut tuner = Tuner::new(0, &config);
+            let mut tuner = Tuner::new(0, &config, &Default::default());
             let result = tuner.activate(create_channel("1"), vec![]);
             assert!(result.is_ok());
             tokio::task::yield_now().await;
@@ -628,7 +1268,7 @@ mod tests {
 
         {
             let config = create_config("cmd '".to_string());
-            let mut tuner = Tuner::new(0, &config);
+            let mut tuner = Tuner::new(0, &config, &Default::default());
             let result = tuner.activate(create_channel("1"), vec![]);
             assert_matches!(result, Err(Error::CommandFailed(
                 CommandUtilError::UnableToParse(_))));
@@ -637,7 +1277,7 @@ mod tests {
 
         {
             let config = create_config("no-such-command".to_string());
-            let mut tuner = Tuner::new(0, &config);
+            let mut tuner = Tuner::new(0, &config, &Default::default());
             let result = tuner.activate(create_channel("1"), vec![]);
             assert_matches!(result, Err(Error::CommandFailed(
                 CommandUtilError::UnableToSpawn(..))));
@@ -648,7 +1288,7 @@ mod tests {
     #[actix_rt::test]
     async fn test_tuner_stop_streaming() {
         let config = create_config("true".to_string());
-        let mut tuner = Tuner::new(1, &config);
+        let mut tuner = Tuner::new(1, &config, &Default::default());
         let result = tuner.stop_streaming(Default::default());
         assert_matches!(result, Err(Error::SessionNotFound));
 
@@ -657,6 +1297,9 @@ mod tests {
         let subscription = tuner.subscribe(TunerUser {
             info: TunerUserInfo::Web { remote: None, agent: None },
             priority: 0.into(),
+            expected_end_at: None,
+            drop_policy: Default::default(),
+            timeshift: None,
         });
 
         let result = tuner.stop_streaming(Default::default());
@@ -668,10 +1311,27 @@ mod tests {
         tokio::task::yield_now().await;
     }
 
+    #[actix_rt::test]
+    async fn test_tuner_subscribe_timeshift() {
+        let config = create_config("true".to_string());
+        let mut tuner = Tuner::new(0, &config, &Default::default());
+        tuner.activate(create_channel("1"), vec![]).unwrap();
+
+        let subscription = tuner.subscribe(create_user(0.into()));
+        assert_eq!(subscription.timeshift, None);
+
+        let mut user = create_user(0.into());
+        user.timeshift = Some(Duration::from_secs(10));
+        let subscription = tuner.subscribe(user);
+        assert_eq!(subscription.timeshift, Some(Duration::from_secs(10)));
+
+        tokio::task::yield_now().await;
+    }
+
     #[actix_rt::test]
     async fn test_tuner_can_grab() {
         let config = create_config("true".to_string());
-        let mut tuner = Tuner::new(0, &config);
+        let mut tuner = Tuner::new(0, &config, &Default::default());
         assert!(tuner.can_grab(0.into()));
 
         tuner.activate(create_channel("1"), vec![]).unwrap();
@@ -702,7 +1362,7 @@ mod tests {
     #[actix_rt::test]
     async fn test_tuner_reactivate() {
         let config = create_config("true".to_string());
-        let mut tuner = Tuner::new(0, &config);
+        let mut tuner = Tuner::new(0, &config, &Default::default());
         tuner.activate(create_channel("1"), vec![]).ok();
 
         tokio::task::yield_now().await;
@@ -714,6 +1374,514 @@ mod tests {
         tokio::task::yield_now().await;
     }
 
+    #[test]
+    fn test_calc_expected_free_at() {
+        fn create_user_model(
+            expected_end_at: Option<i64>,
+        ) -> MirakurunTunerUser {
+            MirakurunTunerUser {
+                id: "test".to_string(),
+                agent: None,
+                priority: 0,
+                expected_end_at,
+            }
+        }
+
+        assert_eq!(Tuner::calc_expected_free_at(&[]), None);
+        assert_eq!(Tuner::calc_expected_free_at(&[
+            create_user_model(Some(1)),
+            create_user_model(Some(2)),
+        ]), Some(2));
+        assert_eq!(Tuner::calc_expected_free_at(&[
+            create_user_model(Some(1)),
+            create_user_model(None),
+        ]), None);
+    }
+
+    #[test]
+    fn test_id_labels() {
+        let session_id = TunerSessionId { tuner_index: 1 };
+        let subscription_id = TunerSubscriptionId::new(session_id, 2);
+        let user = TunerUser {
+            info: TunerUserInfo::Web {
+                remote: Some("192.168.1.5".to_string()),
+                agent: None,
+            },
+            priority: 0.into(),
+            expected_end_at: None,
+            drop_policy: Default::default(),
+            timeshift: None,
+        };
+
+        let mut labels: IdLabels = Default::default();
+        labels.set_session_label(session_id, &create_channel("27"));
+        labels.set_subscription_label(
+            subscription_id, &create_channel("27"), &user);
+        assert_eq!(labels.sessions.get(&1), Some(&"GR27".to_string()));
+        assert_eq!(labels.subscriptions.get(&(1, 2)),
+                   Some(&"GR27/web:192.168.1.5".to_string()));
+
+        labels.remove_subscription_label(subscription_id);
+        assert!(labels.subscriptions.is_empty());
+
+        labels.set_subscription_label(
+            subscription_id, &create_channel("27"), &user);
+        labels.remove_session_label(session_id);
+        assert!(labels.sessions.is_empty());
+        assert!(labels.subscriptions.is_empty());
+
+        assert_eq!(IdLabels::make_user_label(&create_user(0.into())),
+                   "job:test");
+
+        // Labels are never recorded when disabled.
+        let labels = SharedIdLabels::new(false);
+        labels.update(|labels| {
+            labels.set_session_label(session_id, &create_channel("27"))
+        });
+        assert_eq!(labels.session(session_id), "tuner#1");
+
+        let labels = SharedIdLabels::new(true);
+        labels.update(|labels| {
+            labels.set_session_label(session_id, &create_channel("27"));
+            labels.set_subscription_label(
+                subscription_id, &create_channel("27"), &user);
+        });
+        assert_eq!(labels.session(session_id), "tuner#1[GR27]");
+        assert_eq!(labels.subscription(subscription_id),
+                   "tuner#1.2[GR27/web:192.168.1.5]");
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_priority() {
+        let mut config = Config::default();
+        config.tuners = vec![
+            create_config("true".to_string()),
+            create_config("true".to_string()),
+            create_config("true".to_string()),
+        ];
+        config.tuners[1].name = "tuner1".to_string();
+        config.tuners[2].name = "tuner2".to_string();
+
+        let mut manager = TunerManager::new(Arc::new(config));
+        manager.load_tuners();
+
+        let result = manager.set_tuner_priority("tuner2", 1);
+        assert_matches!(result, Ok(tuner) => {
+            assert_eq!(tuner.index, 2);
+            assert_eq!(tuner.priority, 1);
+        });
+
+        let result = manager.set_tuner_priority("unknown", 1);
+        assert_matches!(result, Err(Error::TunerNotFound));
+
+        let subscription = manager.activate_tuner(
+            create_channel("1"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 2);
+
+        // Tuners having the same priority are used in the config order.
+        let subscription = manager.activate_tuner(
+            create_channel("2"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 0);
+
+        tokio::task::yield_now().await;
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_prefer_share() {
+        let mut config = Config::default();
+        config.tuners = vec![
+            create_config("true".to_string()),
+            create_config("true".to_string()),
+        ];
+        config.tuners[0].name = "tuner0".to_string();
+        config.tuners[1].name = "tuner1".to_string();
+
+        let mut manager = TunerManager::new(Arc::new(config));
+        manager.load_tuners();
+
+        let subscription = manager.activate_tuner(
+            create_channel("1"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 0);
+
+        // The active tuner is shared even though a tuner having a higher
+        // priority is available.
+        manager.set_tuner_priority("tuner1", 1).unwrap();
+        let subscription = manager.activate_tuner(
+            create_channel("1"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 0);
+
+        tokio::task::yield_now().await;
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_stop_sessions() {
+        use futures::stream::StreamExt;
+
+        let mut config = Config::default();
+        config.tuners = vec![
+            create_config("sleep 10".to_string()),
+            create_config("sleep 10".to_string()),
+        ];
+
+        let mut manager = TunerManager::new(Arc::new(config));
+        manager.load_tuners();
+
+        let subscription = manager.activate_tuner(
+            create_channel("1"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 0);
+        let mut stream = subscription.broadcaster.send(SubscribeMessage {
+            id: subscription.id,
+            drop_policy: Default::default(),
+            stop_streaming: None,
+        }).await.unwrap();
+
+        let subscription = manager.activate_tuner(
+            create_channel("2"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 1);
+
+        assert_eq!(manager.stop_sessions(&[create_channel("3")]), 0);
+
+        // Only the session of the changed channel is stopped.
+        assert_eq!(manager.stop_sessions(&[create_channel("1")]), 1);
+        assert!(!manager.tuners[0].is_active());
+        assert!(manager.tuners[1].is_active());
+
+        // The stream of the subscriber ends normally.
+        assert!(stream.next().await.is_none());
+
+        tokio::task::yield_now().await;
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_stop_sessions_message() {
+        use futures::stream::StreamExt;
+
+        let mut config = Config::default();
+        config.tuners = vec![
+            create_config("sleep 10".to_string()),
+            create_config("sleep 10".to_string()),
+        ];
+
+        let manager = TunerManager::new(Arc::new(config)).start();
+
+        let mut stream1 = manager.send(StartStreamingMessage {
+            channel: create_channel("1"),
+            user: create_user(0.into()),
+        }).await.unwrap().unwrap();
+        let _stream2 = manager.send(StartStreamingMessage {
+            channel: create_channel("2"),
+            user: create_user(0.into()),
+        }).await.unwrap().unwrap();
+
+        manager.send(StopSessionsMessage {
+            channels: vec![create_channel("1"), create_channel("3")],
+        }).await.unwrap();
+
+        // Only the tuner used for the changed channel is deactivated.
+        let tuners = manager.send(QueryTunersMessage).await.unwrap().unwrap();
+        assert!(tuners[0].is_free);
+        assert!(!tuners[1].is_free);
+        assert!(stream1.next().await.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_prefer_preferred_tuner() {
+        let mut config = Config::default();
+        config.tuners = vec![
+            create_config("true".to_string()),
+            create_config("true".to_string()),
+        ];
+        config.tuners[0].name = "tuner0".to_string();
+        config.tuners[1].name = "tuner1".to_string();
+        config.tuner_sharing.policy = TunerSharingPolicy::PreferPreferredTuner;
+
+        let mut manager = TunerManager::new(Arc::new(config));
+        manager.load_tuners();
+
+        let subscription = manager.activate_tuner(
+            create_channel("1"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 0);
+
+        // The active tuner is shared if no tuner has a higher priority.
+        let subscription = manager.activate_tuner(
+            create_channel("1"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 0);
+
+        // The tuner having a higher priority is activated.
+        manager.set_tuner_priority("tuner1", 1).unwrap();
+        let subscription = manager.activate_tuner(
+            create_channel("1"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 1);
+
+        // Then it's shared.
+        let subscription = manager.activate_tuner(
+            create_channel("1"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 1);
+
+        tokio::task::yield_now().await;
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_channel_types() {
+        let mut config = Config::default();
+        config.tuners = vec![
+            create_config("true".to_string()),
+            create_config("true".to_string()),
+        ];
+        config.tuners[0].channel_types = vec![ChannelType::BS];
+        config.tuners[0].name = "bs".to_string();
+        // The BS-only tuner is preferred if it supports the channel type.
+        config.tuners[0].priority = 1;
+        config.tuners[1].name = "gr".to_string();
+
+        let mut manager = TunerManager::new(Arc::new(config));
+        manager.load_tuners();
+
+        // A GR request never selects the BS-only tuner.
+        let subscription = manager.activate_tuner(
+            create_channel("1"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 1);
+
+        // Even when the BS-only tuner is free.
+        let result = manager.activate_tuner(
+            create_channel("2"), create_user(0.into()), None);
+        assert_matches!(result.err(), Some(Error::TunerUnavailable));
+
+        let mut channel = create_channel("101");
+        channel.channel_type = ChannelType::BS;
+        let subscription = manager.activate_tuner(
+            channel, create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 0);
+
+        let mut channel = create_channel("CS2");
+        channel.channel_type = ChannelType::CS;
+        let result = manager.activate_tuner(
+            channel, create_user(0.into()), None);
+        assert_matches!(result.err(),
+                        Some(Error::UnsupportedChannelType(ChannelType::CS)));
+
+        tokio::task::yield_now().await;
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_excluded() {
+        let mut config = Config::default();
+        config.tuners = vec![
+            create_config("true".to_string()),
+            create_config("true".to_string()),
+        ];
+
+        let mut manager = TunerManager::new(Arc::new(config));
+        manager.load_tuners();
+
+        let subscription = manager.activate_tuner(
+            create_channel("1"), create_user(0.into()), None).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 0);
+
+        // The excluded tuner is not reused even for the same channel.
+        let subscription = manager.activate_tuner(
+            create_channel("1"), create_user(0.into()), Some(0)).unwrap();
+        assert_eq!(subscription.id.session_id.tuner_index, 1);
+
+        let result = manager.activate_tuner(
+            create_channel("2"), create_user(0.into()), Some(1));
+        assert_matches!(result.err(), Some(Error::TunerUnavailable));
+
+        tokio::task::yield_now().await;
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_coalesce_identical_requests() {
+        let mut config = Config::default();
+        config.tuners = vec![create_config("sleep 1".to_string())];
+
+        let manager = TunerManager::new(Arc::new(config)).start();
+
+        // All requests are queued before any of them is handled.
+        let results = futures::future::join_all((0..4).map(|_| {
+            manager.send(StartStreamingMessage {
+                channel: create_channel("1"),
+                user: create_user(0.into()),
+            })
+        })).await;
+
+        let streams: Vec<MpegTsStream> = results.into_iter()
+            .map(|result| result.unwrap().unwrap())
+            .collect();
+        let mut serial_numbers: Vec<u32> = streams.iter()
+            .map(|stream| stream.id())
+            .inspect(|id| assert!(id.session_id == streams[0].id().session_id))
+            .map(|id| id.serial_number)
+            .collect();
+        serial_numbers.sort();
+        assert_eq!(serial_numbers, vec![1, 2, 3, 4]);
+
+        // The tuner is shared and cannot be used for another channel.
+        let result = manager.send(StartStreamingMessage {
+            channel: create_channel("2"),
+            user: create_user(0.into()),
+        }).await.unwrap();
+        assert_matches!(result.err(), Some(Error::TunerUnavailable));
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_startup_timeout() {
+        let mut tuner_config = create_config("sleep 10".to_string());
+        tuner_config.startup_timeout = 100;
+        let mut config = Config::default();
+        config.tuners = vec![tuner_config];
+
+        let manager = TunerManager::new(Arc::new(config)).start();
+
+        let result = manager.send(StartStreamingMessage {
+            channel: create_channel("1"),
+            user: create_user(0.into()),
+        }).await.unwrap();
+        assert_matches!(result.err(), Some(Error::TunerStartupTimedOut));
+
+        // The tuner has been deactivated.
+        let tuners = manager.send(QueryTunersMessage).await.unwrap().unwrap();
+        assert!(tuners[0].is_free);
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_preemption() {
+        use futures::stream::StreamExt;
+
+        let mut config = Config::default();
+        config.tuners = vec![create_config("sleep 10".to_string())];
+
+        let manager = TunerManager::new(Arc::new(config.clone())).start();
+
+        let mut stream = manager.send(StartStreamingMessage {
+            channel: create_channel("1"),
+            user: create_user((-1).into()),
+        }).await.unwrap().unwrap();
+
+        // A higher priority user takes over the tuner, and the stream of the
+        // preempted user ends.
+        let result = manager.send(StartStreamingMessage {
+            channel: create_channel("2"),
+            user: create_user(0.into()),
+        }).await.unwrap();
+        assert!(result.is_ok());
+        assert!(stream.next().await.is_none());
+
+        config.tuner_sharing.preemption = false;
+        let manager = TunerManager::new(Arc::new(config)).start();
+
+        let _stream = manager.send(StartStreamingMessage {
+            channel: create_channel("1"),
+            user: create_user((-1).into()),
+        }).await.unwrap().unwrap();
+
+        let result = manager.send(StartStreamingMessage {
+            channel: create_channel("2"),
+            user: create_user(TunerUserPriority::GRAB),
+        }).await.unwrap();
+        assert_matches!(result, Err(Error::TunerUnavailable));
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_check_availability() {
+        let mut config = Config::default();
+        config.tuners = vec![create_config("true".to_string())];
+
+        let mut manager = TunerManager::new(Arc::new(config));
+        manager.load_tuners();
+
+        let mut channel = create_channel("1");
+        assert!(manager.check_availability(
+            &channel, &create_user(0.into())).is_ok());
+        // No tuner is activated.
+        assert!(manager.tuners[0].is_available());
+
+        let _subscription = manager.activate_tuner(
+            create_channel("1"), create_user(1.into()), None).unwrap();
+
+        // The active tuner can be reused.
+        assert!(manager.check_availability(
+            &channel, &create_user(0.into())).is_ok());
+
+        // The active tuner cannot be grabbed by a lower priority user.
+        channel.channel = "2".to_string();
+        assert_matches!(
+            manager.check_availability(&channel, &create_user(0.into())),
+            Err(Error::TunerUnavailable));
+        assert!(manager.check_availability(
+            &channel, &create_user(2.into())).is_ok());
+
+        // No tuner supports the channel type.
+        channel.channel_type = ChannelType::BS;
+        assert_matches!(
+            manager.check_availability(&channel, &create_user(2.into())),
+            Err(Error::UnsupportedChannelType(ChannelType::BS)));
+
+        tokio::task::yield_now().await;
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_multiple_service_streams() {
+        use tokio::io::AsyncReadExt;
+
+        let mut config = Config::default();
+        config.tuners = vec![
+            create_config("sh -c 'sleep 1; printf \"1\\n2\\n\"'".to_string()),
+        ];
+
+        let manager = TunerManager::new(Arc::new(config)).start();
+
+        // Services in the same channel are streamed from a single session.
+        let results = futures::future::join_all((0..2).map(|_| {
+            manager.send(StartStreamingMessage {
+                channel: create_channel("1"),
+                user: create_user(0.into()),
+            })
+        })).await;
+        let streams: Vec<MpegTsStream> = results.into_iter()
+            .map(|result| result.unwrap().unwrap())
+            .collect();
+        assert!(streams[0].id().session_id == streams[1].id().session_id);
+        assert!(streams[0].id() != streams[1].id());
+
+        // Each stream is processed in its own filter pipeline like a
+        // service-filter.
+        let sids = vec!["1", "2"];
+        let outputs = futures::future::join_all(
+            streams.into_iter().zip(sids).map(|(stream, sid)| async move {
+                let filter = format!("grep -x {}", sid);
+                let mut pipeline = spawn_pipeline_with_limits(
+                    vec![filter], stream.id(), &Default::default()).unwrap();
+                let (input, mut output) = pipeline.take_endpoints().unwrap();
+                let handle = tokio::spawn(stream.pipe(input));
+                let mut buf = Vec::new();
+                output.read_to_end(&mut buf).await.unwrap();
+                let _ = handle.await;
+                String::from_utf8(buf).unwrap()
+            })).await;
+        assert_eq!(outputs, vec!["1\n", "2\n"]);
+    }
+
+    #[test]
+    fn test_parse_signal_stats() {
+        let pattern =
+            Regex::new(r"SNR: (?P<snr>[0-9.]+)dB, BER: (?P<ber>[0-9.e-]+)")
+            .unwrap();
+
+        let stats = parse_signal_stats(
+            &pattern, "SNR: 20.5dB, BER: 1.2e-5\n").unwrap();
+        assert_eq!(stats.snr, Some(20.5));
+        assert_eq!(stats.ber, Some(1.2e-5));
+
+        assert!(parse_signal_stats(&pattern, "SNR: 20.5dB\n").is_none());
+        assert!(parse_signal_stats(&pattern, "SNR: xdB, BER: y\n").is_none());
+
+        let pattern = Regex::new(r"SNR: (?P<snr>[0-9.]+)").unwrap();
+        let stats = parse_signal_stats(&pattern, "SNR: 20.5\n").unwrap();
+        assert_eq!(stats.snr, Some(20.5));
+        assert_eq!(stats.ber, None);
+    }
+
     fn create_config(command: String) -> TunerConfig {
         TunerConfig {
             name: String::new(),
@@ -721,6 +1889,13 @@ mod tests {
             command,
             time_limit: 10 * 1000,
             disabled: false,
+            priority: 0,
+            drop_log_interval: 10 * 1000,
+            signal_stats_pattern: None,
+            zero_read_policy: ZeroReadPolicy::Eof,
+            max_restarts: 0,
+            startup_timeout: 0,
+            process_limits: Default::default(),
         }
     }
 
@@ -738,7 +1913,10 @@ mod tests {
     fn create_user(priority: TunerUserPriority) -> TunerUser {
         TunerUser {
             info: TunerUserInfo::Job { name: "test".to_string() },
-            priority
+            priority,
+            expected_end_at: None,
+            drop_policy: Default::default(),
+            timeshift: None,
         }
     }
 }
@@ -2,29 +2,49 @@ use std::collections::HashMap;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::pin::Pin;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use actix_files;
+use actix_web_actors::ws;
 use actix_service;
 use actix_web::{self, FromRequest};
 use bytes::Bytes;
+use chrono::{DateTime, TimeZone};
 use futures;
 use futures::stream::{Stream, StreamExt};
+use humantime;
 use serde::{Deserialize, Serialize};
+use serde_json;
 use serde_qs;
+use tokio::io::AsyncReadExt;
 use tokio::sync::mpsc;
 
+use crate::access_log::AccessLogWriter;
 use crate::airtime_tracker;
-use crate::chunk_stream::ChunkStream;
+use crate::arib_genres;
+use crate::chunk_stream::{ChunkStream, CoalescedChunkStream,
+                          JitterBufferStream};
 use crate::command_util::*;
-use crate::config::{Config, ServerAddr, FilterConfig, PostFilterConfig};
+use crate::config::{Config, DropPolicy, ServerAddr, FallbackChannelConfig,
+                    FilterConfig, PostFilterConfig, ProcessLimitsConfig,
+                    ServiceDecodeFilterConfig};
+use crate::datetime_ext::Jst;
 use crate::error::Error;
 use crate::epg::*;
+use crate::job::*;
+use crate::metrics;
 use crate::models::*;
 use crate::mpeg_ts_stream::*;
+use crate::named_filters::NamedFilters;
+use crate::pid_analyzer::PidAnalyzer;
+use crate::program_enricher::ProgramEnricher;
+use crate::thumbnail;
+use crate::stream_stats::{self, StreamStatsHandle};
+use crate::recording::*;
 use crate::tuner::*;
+use crate::ws_relay::WsRelay;
 
 #[cfg(not(test))]
 type TunerManagerActor = TunerManager;
@@ -36,23 +56,54 @@ type EpgActor = Epg;
 #[cfg(test)]
 type EpgActor = actix::actors::mocker::Mocker<Epg>;
 
+#[cfg(not(test))]
+type RecordingManagerActor = RecordingManager;
+#[cfg(test)]
+type RecordingManagerActor = actix::actors::mocker::Mocker<RecordingManager>;
+
+#[cfg(not(test))]
+type JobManagerActor = JobManager;
+#[cfg(test)]
+type JobManagerActor = actix::actors::mocker::Mocker<JobManager>;
+
 pub async fn serve(
     config: Arc<Config>,
     tuner_manager: Addr<TunerManager>,
     epg: Addr<Epg>,
+    recording_manager: Addr<RecordingManager>,
+    job_manager: Addr<JobManager>,
+    named_filters: Arc<NamedFilters>,
 ) -> Result<(), Error> {
     let server_config = config.server.clone();
+    // Shared by all workers.
+    let access_log = server_config.access_log.as_ref()
+        .map(AccessLogWriter::spawn);
+    let program_enricher =
+        Arc::new(ProgramEnricher::new(config.epg.enrichment_file.clone()));
+    // Versions of the commands are collected after the server starts.
+    let version_info = Arc::new(RwLock::new(VersionInfo::new(vec![])));
+    let version_info_ = version_info.clone();
+    let commands = VersionInfo::command_names(&config);
     let mut server = actix_web::HttpServer::new(
         move || {
             actix_web::App::new()
                 .data(config.clone())
                 .data(tuner_manager.clone())
                 .data(epg.clone())
-                .wrap(actix_web::middleware::Logger::default())
+                .data(recording_manager.clone())
+                .data(job_manager.clone())
+                .data(named_filters.clone())
+                .data(program_enricher.clone())
+                .data(version_info.clone())
+                .wrap(actix_web::middleware::Condition::new(
+                    access_log.is_none(),
+                    actix_web::middleware::Logger::default()))
+                .wrap(AccessLog(access_log.clone()))
                 .wrap(actix_web::middleware::DefaultHeaders::new()
                       .header("Server", server_name()))
                 .wrap(AccessControl)
                 .service(create_api_service())
+                .service(get_metrics)
         });
     for addr in server_config.addrs.iter() {
         server = match addr {
@@ -60,6 +111,7 @@ pub async fn serve(
             ServerAddr::Unix(path) => server.bind_uds(path.as_str())?,
         };
     }
+    actix::spawn(VersionInfo::collect(version_info_, commands));
     server
         .keep_alive(0)  // disable keep-alive
         .workers(server_config.workers)
@@ -76,6 +128,12 @@ fn server_name() -> String {
 
 type ApiResult = Result<actix_web::HttpResponse, Error>;
 
+const MAX_SERVICES_PER_REQUEST: usize = 32;
+const MAX_PROGRAMS_PER_SEARCH: usize = 1000;
+
+// Limits the size of each page of /api/guide.
+const MAX_GUIDE_SERVICES: usize = 128;
+
 #[derive(Serialize)]
 struct ErrorBody {
     pub code: u16,
@@ -83,16 +141,361 @@ struct ErrorBody {
     pub errors: Vec<u8>,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ServicePrograms {
+    service_id: MirakurunServiceId,
+    programs: Vec<FormattedProgram>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GuideService {
+    service: MirakurunService,
+    programs: Vec<FormattedProgram>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChangedPrograms {
+    programs: Vec<FormattedProgram>,
+    removed: Vec<MirakurunProgramId>,
+}
+
+// A program serialized in the format specified by the client.
+//
+// `startAt` and `duration` are serialized in milliseconds by default.  With
+// `TimeFormat::Iso`, `startAt` is serialized as a RFC 3339 string in JST and
+// `duration` is serialized as a ISO 8601 duration.  With `resolve_genres`,
+// each genre has `lv1Name` and `lv2Name` decoded from the ARIB genre table.
+// Programs in the EPG are never changed.
+struct FormattedProgram {
+    program: MirakurunProgram,
+    format: ProgramFormat,
+}
+
+impl FormattedProgram {
+    fn new(program: MirakurunProgram, format: ProgramFormat) -> Self {
+        FormattedProgram { program, format }
+    }
+
+    fn all(
+        programs: Vec<MirakurunProgram>,
+        format: ProgramFormat,
+    ) -> Vec<Self> {
+        programs.into_iter()
+            .map(|program| Self::new(program, format))
+            .collect()
+    }
+}
+
+impl Serialize for FormattedProgram {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if self.format.time_format == TimeFormat::EpochMs &&
+            !self.format.resolve_genres {
+            return self.program.serialize(serializer);
+        }
+        let mut value = serde_json::to_value(&self.program)
+            .map_err(serde::ser::Error::custom)?;
+        if let Some(props) = value.as_object_mut() {
+            if self.format.time_format == TimeFormat::Iso {
+                props.insert("startAt".to_string(),
+                             self.program.start_at.to_rfc3339().into());
+                props.insert("duration".to_string(),
+                             self.program.duration.to_string().into());
+            }
+            if self.format.resolve_genres {
+                if let Some(ref genres) = self.program.genres {
+                    let genres: Vec<serde_json::Value> = genres.iter()
+                        .map(|genre| serde_json::json!({
+                            "lv1": genre.lv1,
+                            "lv2": genre.lv2,
+                            "un1": genre.un1,
+                            "un2": genre.un2,
+                            "lv1Name": arib_genres::lv1_name(genre.lv1),
+                            "lv2Name": arib_genres::lv2_name(
+                                genre.lv1, genre.lv2),
+                        }))
+                        .collect();
+                    props.insert("genres".to_string(), genres.into());
+                }
+            }
+        }
+        value.serialize(serializer)
+    }
+}
+
+// Versions of external commands are collected only once after the server
+// starts.  So, the server must be restarted after updating them.
+#[derive(Clone)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VersionInfo {
+    crate_name: &'static str,
+    version: &'static str,
+    // Embedded at build time if the MIRAKC_GIT_COMMIT environment variable is
+    // defined.
+    git_commit: Option<&'static str>,
+    commands: Vec<CommandVersion>,
+}
+
+impl VersionInfo {
+    const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn new(commands: Vec<CommandVersion>) -> Self {
+        VersionInfo {
+            crate_name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: option_env!("MIRAKC_GIT_COMMIT"),
+            commands,
+        }
+    }
+
+    // The commands are queried concurrently.
+    async fn collect(info: Arc<RwLock<VersionInfo>>, names: Vec<String>) {
+        let commands = futures::future::join_all(
+            names.into_iter().map(|name| async move {
+                let version = Self::query_version(&name).await;
+                log::info!("Version of {}: {}",
+                           name, version.as_deref().unwrap_or("unknown"));
+                CommandVersion { name, version }
+            })).await;
+        info.write().unwrap().commands = commands;
+    }
+
+    // Program names of the job commands.  Each command is a template, but the
+    // first word never contains template variables in practice.
+    fn command_names(config: &Config) -> Vec<String> {
+        let jobs = &config.jobs;
+        let mut names: Vec<String> = [
+            Some(&jobs.scan_services),
+            Some(&jobs.sync_clocks),
+            Some(&jobs.update_schedules),
+            jobs.update_recent_schedules.as_ref(),
+        ]
+            .iter()
+            .filter_map(|job| *job)
+            .filter_map(|job| job.command.split_whitespace().next())
+            .map(|name| name.to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    // The first line of the output from `<name> --version`.  `None` is
+    // returned if the command fails or doesn't respond in time.
+    async fn query_version(name: &str) -> Option<String> {
+        let output = tokio::process::Command::new(name)
+            .arg("--version")
+            .stdin(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        let output = match tokio::time::timeout(
+            Self::COMMAND_TIMEOUT, output).await {
+            Ok(Ok(output)) if output.status.success() => output,
+            Ok(Ok(output)) => {
+                log::warn!("{} --version exited with {}", name, output.status);
+                return None;
+            }
+            Ok(Err(err)) => {
+                log::warn!("Failed to run {} --version: {}", name, err);
+                return None;
+            }
+            Err(_) => {
+                log::warn!("{} --version timed out", name);
+                return None;
+            }
+        };
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .next()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+    }
+}
+
+#[derive(Clone)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CommandVersion {
+    name: String,
+    version: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Capabilities {
+    channel_types: Vec<ChannelType>,
+    pre_filters: Vec<NamedFilterCapability>,
+    post_filters: Vec<NamedFilterCapability>,
+    // Tuners are always implemented with external commands at this point.
+    tuner_sources: Vec<&'static str>,
+}
+
+impl Capabilities {
+    fn new(named_filters: &NamedFilters) -> Self {
+        // A pre-filter outputs TS packets.
+        let mut pre_filters: Vec<NamedFilterCapability> =
+            named_filters.pre_filters()
+            .keys()
+            .map(|name| NamedFilterCapability {
+                name: name.clone(),
+                content_type: "video/MP2T".to_string(),
+            })
+            .collect();
+        pre_filters.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut post_filters: Vec<NamedFilterCapability> =
+            named_filters.post_filters()
+            .iter()
+            .map(|(name, filter)| NamedFilterCapability {
+                name: name.clone(),
+                content_type: filter.content_type.clone()
+                    .unwrap_or_else(|| "video/MP2T".to_string()),
+            })
+            .collect();
+        post_filters.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Capabilities {
+            channel_types: ChannelType::ALL.to_vec(),
+            pre_filters,
+            post_filters,
+            tuner_sources: vec!["command"],
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NamedFilterCapability {
+    name: String,
+    content_type: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NamedFilterList {
+    pre_filters: Vec<NamedFilter>,
+    post_filters: Vec<NamedFilter>,
+}
+
+impl NamedFilterList {
+    fn new(named_filters: &NamedFilters) -> Self {
+        let mut pre_filters: Vec<NamedFilter> =
+            named_filters.pre_filters()
+            .into_iter()
+            .map(|(name, filter)| NamedFilter {
+                name,
+                command: filter.command,
+                content_type: None,
+            })
+            .collect();
+        pre_filters.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut post_filters: Vec<NamedFilter> =
+            named_filters.post_filters()
+            .into_iter()
+            .map(|(name, filter)| NamedFilter {
+                name,
+                command: filter.command,
+                content_type: filter.content_type,
+            })
+            .collect();
+        post_filters.sort_by(|a, b| a.name.cmp(&b.name));
+
+        NamedFilterList { pre_filters, post_filters }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NamedFilter {
+    name: String,
+    command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TimeInfo {
+    system_time: i64,
+    networks: Vec<NetworkTime>,
+}
+
+impl TimeInfo {
+    fn new(system_time: i64, offsets: Vec<ClockOffset>) -> Self {
+        let networks = offsets.into_iter()
+            .map(|offset| NetworkTime {
+                network_id: offset.nid,
+                broadcast_time: system_time + offset.offset,
+                offset: offset.offset,
+                synced_at: offset.synced_at,
+            })
+            .collect();
+        TimeInfo { system_time, networks }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NetworkTime {
+    network_id: NetworkId,
+    // Estimated from the system time and the offset.
+    broadcast_time: i64,
+    offset: i64,
+    synced_at: i64,
+}
+
 impl actix_web::ResponseError for Error {
     fn error_response(&self) -> actix_web::HttpResponse {
         match *self {
+            Error::BadRequest(_) =>
+                actix_web::HttpResponse::BadRequest().json(ErrorBody {
+                    code: actix_web::http::StatusCode::BAD_REQUEST.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            // Filters specified by the client exceed the limit.
+            Error::TooManyFilters(..) =>
+                actix_web::HttpResponse::BadRequest().json(ErrorBody {
+                    code: actix_web::http::StatusCode::BAD_REQUEST.as_u16(),
+                    reason: Some("Too many filters"),
+                    errors: Vec::new(),
+                }),
             Error::StreamingTimedOut =>
                 actix_web::HttpResponse::RequestTimeout().json(ErrorBody {
                     code: actix_web::http::StatusCode::REQUEST_TIMEOUT.as_u16(),
                     reason: None,
                     errors: Vec::new(),
                 }),
+            // Same as Mirakurun.
             Error::TunerUnavailable =>
+                actix_web::HttpResponse::ServiceUnavailable().json(ErrorBody {
+                    code: actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+                        .as_u16(),
+                    reason: Some("Tuner Resource Unavailable"),
+                    errors: Vec::new(),
+                }),
+            Error::TunerStartupTimedOut =>
+                actix_web::HttpResponse::ServiceUnavailable().json(ErrorBody {
+                    code: actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+                        .as_u16(),
+                    reason: Some("The tuner command didn't start in time"),
+                    errors: Vec::new(),
+                }),
+            Error::UnsupportedChannelType(_) =>
+                actix_web::HttpResponse::NotFound().json(ErrorBody {
+                    code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
+                    reason: Some("No tuner supports the channel type"),
+                    errors: Vec::new(),
+                }),
+            Error::TunerNotFound =>
                 actix_web::HttpResponse::NotFound().json(ErrorBody {
                     code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
                     reason: None,
@@ -116,6 +519,56 @@ impl actix_web::ResponseError for Error {
                     reason: None,
                     errors: Vec::new(),
                 }),
+            Error::RecordingNotFound =>
+                actix_web::HttpResponse::NotFound().json(ErrorBody {
+                    code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            Error::ThumbnailNotFound =>
+                actix_web::HttpResponse::NotFound().json(ErrorBody {
+                    code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            Error::RecordingRuleNotFound =>
+                actix_web::HttpResponse::NotFound().json(ErrorBody {
+                    code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            Error::FilterNotFound =>
+                actix_web::HttpResponse::NotFound().json(ErrorBody {
+                    code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            Error::JobNotFound =>
+                actix_web::HttpResponse::NotFound().json(ErrorBody {
+                    code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            Error::SessionNotFound =>
+                actix_web::HttpResponse::NotFound().json(ErrorBody {
+                    code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            // The client has to fetch all programs again.
+            Error::ChangesNotTracked =>
+                actix_web::HttpResponse::Gone().json(ErrorBody {
+                    code: actix_web::http::StatusCode::GONE.as_u16(),
+                    reason: Some("Changes are no longer tracked"),
+                    errors: Vec::new(),
+                }),
+            Error::EpgNotReady =>
+                actix_web::HttpResponse::ServiceUnavailable().json(ErrorBody {
+                    code: actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+                        .as_u16(),
+                    reason: Some("No schedule has been collected yet"),
+                    errors: Vec::new(),
+                }),
             Error::AccessDenied =>
                 actix_web::HttpResponse::Forbidden().json(ErrorBody {
                     code: actix_web::http::StatusCode::FORBIDDEN.as_u16(),
@@ -136,19 +589,63 @@ impl actix_web::ResponseError for Error {
 fn create_api_service() -> impl actix_web::dev::HttpServiceFactory {
     actix_web::web::scope("/api")
         .service(get_version)
+        .service(get_version_details)
         .service(get_status)
+        .service(get_capabilities)
+        .service(get_time)
         .service(get_channels)
+        .service(get_networks)
         .service(get_services)
+        // Must be registered before get_service.
+        .service(get_multi_service_stream)
+        .service(head_multi_service_stream)
         .service(get_service)
         .service(get_programs)
+        // Must be registered before get_program.
+        .service(get_next_program)
         .service(get_program)
+        .service(get_program_thumbnail)
+        .service(get_onair_programs)
+        .service(get_guide)
+        .service(get_genres)
+        .service(get_mirakurun_programs)
+        .service(get_mirakurun_program)
         .service(get_tuners)
+        .service(set_tuner_priority)
+        .service(get_filters)
+        .service(put_filter)
+        .service(delete_filter)
         .service(get_channel_stream)
+        .service(head_channel_stream)
         .service(get_channel_service_stream)
+        .service(head_channel_service_stream)
         .service(get_service_stream)
+        .service(head_service_stream)
+        .service(get_ws_channel_stream)
+        .service(get_ws_service_stream)
         .service(get_program_stream)
+        .service(head_program_stream)
+        .service(get_recording_schedules)
+        .service(create_recording_schedule)
+        .service(get_recording_rules)
+        .service(create_recording_rule)
+        .service(preview_recording_rule)
+        .service(update_recording_rule)
+        .service(delete_recording_rule)
+        .service(get_recordings)
+        .service(check_recordings)
+        .service(get_recording_stream)
+        .service(get_epg_status)
+        .service(get_session_stats)
+        .service(run_sync_clocks)
+        .service(run_update_schedules)
+        .service(pause_job)
+        .service(resume_job)
         .service(get_iptv_playlist)
         .service(get_docs)
+        .service(get_debug_channel_psi)
+        .service(get_debug_channel_bitrate)
+        .service(get_debug_tune_stream)
 }
 
 #[actix_web::get("/version")]
@@ -156,12 +653,36 @@ async fn get_version() -> ApiResult {
     Ok(actix_web::HttpResponse::Ok().json(env!("CARGO_PKG_VERSION")))
 }
 
+#[actix_web::get("/version/details")]
+async fn get_version_details(
+    version_info: actix_web::web::Data<Arc<RwLock<VersionInfo>>>,
+) -> ApiResult {
+    let version_info = version_info.read().unwrap().clone();
+    Ok(actix_web::HttpResponse::Ok().json(version_info))
+}
+
 #[actix_web::get("/status")]
 async fn get_status() -> ApiResult {
     Ok(actix_web::HttpResponse::Ok()
        .content_type("application/json").body("{}"))
 }
 
+#[actix_web::get("/capabilities")]
+async fn get_capabilities(
+    named_filters: actix_web::web::Data<Arc<NamedFilters>>,
+) -> ApiResult {
+    Ok(actix_web::HttpResponse::Ok().json(Capabilities::new(&named_filters)))
+}
+
+#[actix_web::get("/time")]
+async fn get_time(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+) -> ApiResult {
+    epg.send(QueryClockOffsetsMessage).await?
+        .map(|offsets| TimeInfo::new(Jst::now().timestamp_millis(), offsets))
+        .map(|time| actix_web::HttpResponse::Ok().json(time))
+}
+
 #[actix_web::get("/channels")]
 async fn get_channels(
     epg: actix_web::web::Data<Addr<EpgActor>>,
@@ -170,6 +691,14 @@ async fn get_channels(
         .map(|channels| actix_web::HttpResponse::Ok().json(channels))
 }
 
+#[actix_web::get("/networks")]
+async fn get_networks(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+) -> ApiResult {
+    epg.send(QueryNetworksMessage).await?
+        .map(|networks| actix_web::HttpResponse::Ok().json(networks))
+}
+
 #[actix_web::get("/services")]
 async fn get_services(
     epg: actix_web::web::Data<Addr<EpgActor>>,
@@ -184,28 +713,274 @@ async fn get_services(
 async fn get_service(
     epg: actix_web::web::Data<Addr<EpgActor>>,
     path: actix_web::web::Path<ServicePath>,
+    query: actix_web::web::Query<ServiceQuery>,
 ) -> ApiResult {
-    epg.send(QueryServiceMessage::ByNidSid {
-        nid: path.id.nid(),
-        sid: path.id.sid(),
-    }).await?
+    epg.send(query.make_message(path.id)).await?
         .map(MirakurunService::from)
         .map(|service| actix_web::HttpResponse::Ok().json(service))
 }
 
+// The `x-mirakc-epg-ready` header tells clients whether an empty list means
+// that no schedule has been collected yet.
 #[actix_web::get("/programs")]
 async fn get_programs(
+    config: actix_web::web::Data<Arc<Config>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    program_enricher: actix_web::web::Data<Arc<ProgramEnricher>>,
+    query: actix_web::web::Query<ProgramsQuery>,
+    format: actix_web::web::Query<ProgramFormat>,
+) -> ApiResult {
+    let ready = epg.send(QueryEpgReadyMessage).await??;
+    if !ready && config.epg.unavailable_until_ready {
+        return Err(Error::EpgNotReady);
+    }
+    let mut res =
+        query_programs(epg, program_enricher, query, format.into_inner())
+        .await?;
+    res.headers_mut().insert(
+        actix_web::http::HeaderName::from_static("x-mirakc-epg-ready"),
+        actix_web::http::HeaderValue::from_static(
+            if ready { "true" } else { "false" }));
+    Ok(res)
+}
+
+async fn query_programs(
     epg: actix_web::web::Data<Addr<EpgActor>>,
+    program_enricher: actix_web::web::Data<Arc<ProgramEnricher>>,
+    query: actix_web::web::Query<ProgramsQuery>,
+    format: ProgramFormat,
 ) -> ApiResult {
+
+    if query.is_search() {
+        if query.services.is_some() || query.changed_since.is_some() ||
+            query.at.is_some() {
+            return Err(Error::BadRequest(
+                "Search queries cannot be combined with other queries"
+                    .to_string()));
+        }
+        return search_programs(epg, program_enricher, &query, format).await;
+    }
+
+    if let Some(changed_since) = query.changed_since {
+        if query.services.is_some() || query.at.is_some() {
+            return Err(Error::BadRequest(
+                "The changed_since query cannot be combined with other queries"
+                    .to_string()));
+        }
+        return get_changed_programs(
+            epg, program_enricher, changed_since, format).await;
+    }
+
+    if let Some(at) = query.at {
+        if query.services.is_some() {
+            return Err(Error::BadRequest(
+                "The services and at queries cannot be combined".to_string()));
+        }
+        let at = jst_from_millis("at", at)?;
+        return epg.send(QueryProgramsAtMessage { at }).await?
+            .map(|programs| programs.into_iter()
+                 .map(MirakurunProgram::from)
+                 .collect::<Vec<MirakurunProgram>>())
+            .map(|programs| program_enricher.enrich_all(programs))
+            .map(|programs| FormattedProgram::all(programs, format))
+            .map(|programs| actix_web::HttpResponse::Ok().json(programs));
+    }
+
+    if let Some(ref services) = query.services {
+        return get_programs_by_services(
+            epg, program_enricher, services.clone(), format).await;
+    }
+
     epg.send(QueryProgramsMessage).await?
         .map(|programs| programs.into_iter()
              .map(MirakurunProgram::from).collect::<Vec<MirakurunProgram>>())
+        .map(|programs| program_enricher.enrich_all(programs))
+        .map(|programs| FormattedProgram::all(programs, format))
+        .map(|programs| actix_web::HttpResponse::Ok().json(programs))
+}
+
+async fn search_programs(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    program_enricher: actix_web::web::Data<Arc<ProgramEnricher>>,
+    query: &ProgramsQuery,
+    format: ProgramFormat,
+) -> ApiResult {
+    let limit = query.limit.unwrap_or(MAX_PROGRAMS_PER_SEARCH);
+    if limit > MAX_PROGRAMS_PER_SEARCH {
+        return Err(Error::BadRequest(format!(
+            "No more than {} programs can be requested",
+            MAX_PROGRAMS_PER_SEARCH)));
+    }
+
+    epg.send(SearchProgramsMessage {
+        rule: query.search_rule()?,
+        offset: query.offset.unwrap_or(0),
+        limit,
+    }).await?
+        .map(|programs| programs.into_iter()
+             .map(MirakurunProgram::from).collect::<Vec<MirakurunProgram>>())
+        .map(|programs| program_enricher.enrich_all(programs))
+        .map(|programs| FormattedProgram::all(programs, format))
+        .map(|programs| actix_web::HttpResponse::Ok().json(programs))
+}
+
+async fn get_programs_by_services(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    program_enricher: actix_web::web::Data<Arc<ProgramEnricher>>,
+    services: Vec<MirakurunServiceId>,
+    format: ProgramFormat,
+) -> ApiResult {
+    if services.len() > MAX_SERVICES_PER_REQUEST {
+        return Err(Error::BadRequest(format!(
+            "No more than {} services can be specified",
+            MAX_SERVICES_PER_REQUEST)));
+    }
+
+    epg.send(QueryProgramsByServicesMessage { services }).await?
+        .map(|results| results.into_iter()
+             .map(|(service_id, programs)| ServicePrograms {
+                 service_id,
+                 programs: FormattedProgram::all(
+                     program_enricher.enrich_all(programs.into_iter()
+                         .map(MirakurunProgram::from).collect()),
+                     format),
+             })
+             .collect::<Vec<ServicePrograms>>())
+        .map(|results| actix_web::HttpResponse::Ok().json(results))
+}
+
+async fn get_changed_programs(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    program_enricher: actix_web::web::Data<Arc<ProgramEnricher>>,
+    changed_since: i64,
+    format: ProgramFormat,
+) -> ApiResult {
+    let since = jst_from_millis("changed_since", changed_since)?;
+    epg.send(QueryChangedProgramsMessage { since }).await?
+        .map(|(programs, removed)| ChangedPrograms {
+            programs: FormattedProgram::all(
+                program_enricher.enrich_all(programs.into_iter()
+                    .map(MirakurunProgram::from).collect()),
+                format),
+            removed: removed.into_iter()
+                .map(MirakurunProgramId::from).collect(),
+        })
+        .map(|changes| actix_web::HttpResponse::Ok().json(changes))
+}
+
+#[actix_web::get("/programs/next")]
+async fn get_next_program(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    program_enricher: actix_web::web::Data<Arc<ProgramEnricher>>,
+    query: actix_web::web::Query<NextProgramQuery>,
+    format: actix_web::web::Query<ProgramFormat>,
+) -> ApiResult {
+    let format = format.into_inner();
+    let rule = query.into_inner().into_rule()?;
+    epg.send(QueryNextProgramMessage { rule }).await??
+        .map(MirakurunProgram::from)
+        .map(|program| program_enricher.enrich(program))
+        .map(|program| FormattedProgram::new(program, format))
+        .map(|program| actix_web::HttpResponse::Ok().json(program))
+        .ok_or(Error::ProgramNotFound)
+}
+
+#[actix_web::get("/onair")]
+async fn get_onair_programs(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    program_enricher: actix_web::web::Data<Arc<ProgramEnricher>>,
+    query: actix_web::web::Query<OnAirQuery>,
+    format: actix_web::web::Query<ProgramFormat>,
+) -> ApiResult {
+    let format = format.into_inner();
+    epg.send(QueryOnAirProgramsMessage {
+        genre: query.genre,
+    }).await?
+        .map(|programs| programs.into_iter()
+             .map(MirakurunProgram::from)
+             .collect::<Vec<MirakurunProgram>>())
+        .map(|programs| program_enricher.enrich_all(programs))
+        .map(|programs| FormattedProgram::all(programs, format))
         .map(|programs| actix_web::HttpResponse::Ok().json(programs))
 }
 
 #[actix_web::get("/programs/{id}")]
 async fn get_program(
     epg: actix_web::web::Data<Addr<EpgActor>>,
+    program_enricher: actix_web::web::Data<Arc<ProgramEnricher>>,
+    path: actix_web::web::Path<ProgramPath>,
+    format: actix_web::web::Query<ProgramFormat>,
+) -> ApiResult {
+    let format = format.into_inner();
+    epg.send(QueryProgramMessage::ByNidSidEid {
+        nid: path.id.nid(),
+        sid: path.id.sid(),
+        eid: path.id.eid(),
+    }).await?
+        .map(MirakurunProgram::from)
+        .map(|program| program_enricher.enrich(program))
+        .map(|program| FormattedProgram::new(program, format))
+        .map(|program| actix_web::HttpResponse::Ok().json(program))
+}
+
+#[actix_web::get("/guide")]
+async fn get_guide(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    program_enricher: actix_web::web::Data<Arc<ProgramEnricher>>,
+    query: actix_web::web::Query<GuideQuery>,
+    format: actix_web::web::Query<ProgramFormat>,
+) -> ApiResult {
+    let format = format.into_inner();
+    let query = query.into_inner();
+    let midnight = query.midnight()?;
+    let limit = query.limit()?;
+    epg.send(QueryGuideMessage {
+        midnight,
+        services: query.services,
+        offset: query.offset,
+        limit,
+    }).await?
+        .map(|guide| guide.into_iter()
+             .map(|(service, programs)| GuideService {
+                 service: service.into(),
+                 programs: FormattedProgram::all(
+                     program_enricher.enrich_all(programs.into_iter()
+                         .map(MirakurunProgram::from).collect()),
+                     format),
+             })
+             .collect::<Vec<GuideService>>())
+        .map(|guide| actix_web::HttpResponse::Ok().json(guide))
+}
+
+#[actix_web::get("/genres")]
+async fn get_genres() -> ApiResult {
+    Ok(actix_web::HttpResponse::Ok().json(arib_genres::GENRES))
+}
+
+// Programs in the exact shape of Mirakurun's program object.  mirakc
+// extensions such as the time_format query parameter are not supported.
+#[actix_web::get("/mirakurun/programs")]
+async fn get_mirakurun_programs(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    program_enricher: actix_web::web::Data<Arc<ProgramEnricher>>,
+    query: actix_web::web::Query<MirakurunProgramsQuery>,
+) -> ApiResult {
+    epg.send(QueryProgramsMessage).await?
+        .map(|programs| programs.into_iter()
+             .filter(|program| query.matches(program))
+             .map(MirakurunProgram::from)
+             .collect::<Vec<MirakurunProgram>>())
+        .map(|programs| program_enricher.enrich_all(programs))
+        .map(|programs| programs.into_iter()
+             .map(MirakurunCompatProgram::from)
+             .collect::<Vec<MirakurunCompatProgram>>())
+        .map(|programs| actix_web::HttpResponse::Ok().json(programs))
+}
+
+#[actix_web::get("/mirakurun/programs/{id}")]
+async fn get_mirakurun_program(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    program_enricher: actix_web::web::Data<Arc<ProgramEnricher>>,
     path: actix_web::web::Path<ProgramPath>,
 ) -> ApiResult {
     epg.send(QueryProgramMessage::ByNidSidEid {
@@ -214,9 +989,36 @@ async fn get_program(
         eid: path.id.eid(),
     }).await?
         .map(MirakurunProgram::from)
+        .map(|program| program_enricher.enrich(program))
+        .map(MirakurunCompatProgram::from)
         .map(|program| actix_web::HttpResponse::Ok().json(program))
 }
 
+#[actix_web::get("/programs/{id}/thumbnail")]
+async fn get_program_thumbnail(
+    config: actix_web::web::Data<Arc<Config>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    path: actix_web::web::Path<ProgramPath>,
+) -> Result<actix_files::NamedFile, Error> {
+    let program = epg.send(QueryProgramMessage::ByNidSidEid {
+        nid: path.id.nid(),
+        sid: path.id.sid(),
+        eid: path.id.eid(),
+    }).await??;
+
+    let dir = match config.epg.thumbnails {
+        Some(ref config) => &config.dir,
+        None => return Err(Error::ThumbnailNotFound),
+    };
+
+    let id = MirakurunProgramId::from(program.quad).value();
+    match thumbnail::find_thumbnail(dir, id) {
+        // The content type is determined from the extension.
+        Some(path) => Ok(actix_files::NamedFile::open(path)?),
+        None => Err(Error::ThumbnailNotFound),
+    }
+}
+
 #[actix_web::get("/tuners")]
 async fn get_tuners(
     tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>
@@ -225,41 +1027,145 @@ async fn get_tuners(
         .map(|tuners| actix_web::HttpResponse::Ok().json(tuners))
 }
 
-#[actix_web::get("/channels/{channel_type}/{channel}/stream")]
-async fn get_channel_stream(
-    config: actix_web::web::Data<Arc<Config>>,
+#[actix_web::put("/tuners/{name}/priority")]
+async fn set_tuner_priority(
     tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
-    epg: actix_web::web::Data<Addr<EpgActor>>,
-    path: actix_web::web::Path<ChannelPath>,
-    user: TunerUser,
-    filter_setting: FilterSetting,
+    path: actix_web::web::Path<TunerPath>,
+    query: actix_web::web::Query<UpdateQuery>,
+    body: actix_web::web::Json<TunerPriorityBody>,
 ) -> ApiResult {
-    let channel = epg.send(QueryChannelMessage {
-        channel_type: path.channel_type,
-        channel: path.channel.clone(),
-    }).await??;
+    query.validate()?;
 
-    let data = mustache::MapBuilder::new()
-        .insert_str("channel_name", &channel.name)
-        .insert("channel_type", &channel.channel_type)?
-        .insert_str("channel", &channel.channel)
-        .build();
+    tuner_manager.send(SetTunerPriorityMessage {
+        name: path.name.clone(),
+        priority: body.priority,
+    }).await?
+        .map(|tuner| actix_web::HttpResponse::Ok().json(tuner))
+}
 
-    let mut builder = FilterPipelineBuilder::new(data);
-    builder.add_pre_filters(
-        &config.pre_filters, &filter_setting.pre_filters)?;
-    if filter_setting.decode {
-        builder.add_decode_filter(&config.filters.decode_filter)?;
+#[actix_web::get("/filters")]
+async fn get_filters(
+    req: actix_web::HttpRequest,
+    named_filters: actix_web::web::Data<Arc<NamedFilters>>,
+) -> ApiResult {
+    // Commands of filters may contain secrets like credentials.
+    if !is_admin_request(&req) {
+        return Err(Error::AccessDenied);
     }
-    builder.add_post_filters(
-        &config.post_filters, &filter_setting.post_filters)?;
-    let (filters, content_type) = builder.build();
 
-    let stream = tuner_manager.send(StartStreamingMessage {
-        channel, user
-    }).await??;
+    Ok(actix_web::HttpResponse::Ok()
+       .json(NamedFilterList::new(&named_filters)))
+}
+
+#[actix_web::put("/filters/{kind}/{name}")]
+async fn put_filter(
+    req: actix_web::HttpRequest,
+    config: actix_web::web::Data<Arc<Config>>,
+    named_filters: actix_web::web::Data<Arc<NamedFilters>>,
+    path: actix_web::web::Path<FilterPath>,
+    query: actix_web::web::Query<UpdateQuery>,
+    body: actix_web::web::Json<NamedFilterBody>,
+) -> ApiResult {
+    if !config.filters.runtime_update || !is_admin_request(&req) {
+        return Err(Error::AccessDenied);
+    }
+
+    query.validate()?;
+
+    let body = body.into_inner();
+    match path.kind {
+        FilterKind::PreFilters => {
+            if body.content_type.is_some() {
+                return Err(Error::BadRequest(
+                    "contentType is not allowed for pre-filters".to_string()));
+            }
+            named_filters.put_pre_filter(&path.name, FilterConfig {
+                command: body.command.clone(),
+                process_limits: None,
+            })?;
+        }
+        FilterKind::PostFilters => {
+            named_filters.put_post_filter(&path.name, PostFilterConfig {
+                command: body.command.clone(),
+                content_type: body.content_type.clone(),
+                process_limits: None,
+            })?;
+        }
+    }
+
+    Ok(actix_web::HttpResponse::Ok().json(NamedFilter {
+        name: path.name.clone(),
+        command: body.command,
+        content_type: body.content_type,
+    }))
+}
+
+#[actix_web::delete("/filters/{kind}/{name}")]
+async fn delete_filter(
+    req: actix_web::HttpRequest,
+    config: actix_web::web::Data<Arc<Config>>,
+    named_filters: actix_web::web::Data<Arc<NamedFilters>>,
+    path: actix_web::web::Path<FilterPath>,
+    query: actix_web::web::Query<UpdateQuery>,
+) -> ApiResult {
+    if !config.filters.runtime_update || !is_admin_request(&req) {
+        return Err(Error::AccessDenied);
+    }
+
+    query.validate()?;
+
+    match path.kind {
+        FilterKind::PreFilters =>
+            named_filters.remove_pre_filter(&path.name)?,
+        FilterKind::PostFilters =>
+            named_filters.remove_post_filter(&path.name)?,
+    }
+
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
+
+#[actix_web::get("/channels/{channel_type}/{channel}/stream")]
+async fn get_channel_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    path: actix_web::web::Path<ChannelPath>,
+    user: TunerUser,
+    filter_setting: FilterSetting,
+) -> ApiResult {
+    let (channel, filters, content_type) = prepare_channel_stream(
+        &config, epg.get_ref(), &path, &filter_setting).await?;
+
+    let (channel, mut stream) = start_streaming_with_fallback(
+        &config, tuner_manager.get_ref(), epg.get_ref(), channel,
+        user.clone()).await?;
+
+    if filter_setting.failover {
+        stream.enable_failover(
+            channel, user, tuner_manager.get_ref().clone().recipient(),
+            epg.get_ref().clone().recipient());
+    }
+
+    streaming(&config, stream, filters, content_type, None,
+              filter_setting.duration).await
+}
+
+#[actix_web::head("/channels/{channel_type}/{channel}/stream")]
+async fn head_channel_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    path: actix_web::web::Path<ChannelPath>,
+    user: TunerUser,
+    filter_setting: FilterSetting,
+) -> ApiResult {
+    let (channel, _, content_type) = prepare_channel_stream(
+        &config, epg.get_ref(), &path, &filter_setting).await?;
+
+    check_tuner_availability_with_fallback(
+        &config, tuner_manager.get_ref(), epg.get_ref(), channel, user).await?;
 
-    streaming(&config, stream, filters, content_type, None).await
+    Ok(stream_head_response(content_type))
 }
 
 #[actix_web::get("/channels/{channel_type}/{channel}/services/{sid}/stream")]
@@ -276,8 +1182,34 @@ async fn get_channel_service_stream(
         channel: path.channel.clone(),
     }).await??;
 
+    let nid = find_channel_service_nid(
+        epg.get_ref(), &channel, path.sid).await?;
+
     do_get_service_stream(
-        config, tuner_manager, channel, path.sid, user, filter_setting).await
+        config, tuner_manager, epg, channel, path.sid, nid, user,
+        filter_setting).await
+}
+
+#[actix_web::head("/channels/{channel_type}/{channel}/services/{sid}/stream")]
+async fn head_channel_service_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    path: actix_web::web::Path<ChannelServicePath>,
+    user: TunerUser,
+    filter_setting: FilterSetting,
+) -> ApiResult {
+    let channel = epg.send(QueryChannelMessage {
+        channel_type: path.channel_type,
+        channel: path.channel.clone(),
+    }).await??;
+
+    let nid = find_channel_service_nid(
+        epg.get_ref(), &channel, path.sid).await?;
+
+    do_head_service_stream(
+        config, tuner_manager, epg, channel, path.sid, nid, user,
+        filter_setting).await
 }
 
 #[actix_web::get("/services/{id}/stream")]
@@ -286,76 +1218,176 @@ async fn get_service_stream(
     tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
     epg: actix_web::web::Data<Addr<EpgActor>>,
     path: actix_web::web::Path<ServicePath>,
+    query: actix_web::web::Query<ServiceQuery>,
     user: TunerUser,
     filter_setting: FilterSetting,
 ) -> ApiResult {
-    let service = epg.send(QueryServiceMessage::ByNidSid {
-        nid: path.id.nid(),
-        sid: path.id.sid(),
-    }).await??;
+    let service = epg.send(query.make_message(path.id)).await??;
 
     do_get_service_stream(
-        config, tuner_manager, service.channel, service.sid, user,
-        filter_setting).await
+        config, tuner_manager, epg, service.channel, service.sid,
+        Some(service.nid), user, filter_setting).await
 }
 
-#[actix_web::get("/programs/{id}/stream")]
-async fn get_program_stream(
+#[actix_web::head("/services/{id}/stream")]
+async fn head_service_stream(
     config: actix_web::web::Data<Arc<Config>>,
     tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
     epg: actix_web::web::Data<Addr<EpgActor>>,
-    path: actix_web::web::Path<ProgramPath>,
+    path: actix_web::web::Path<ServicePath>,
+    query: actix_web::web::Query<ServiceQuery>,
     user: TunerUser,
     filter_setting: FilterSetting,
 ) -> ApiResult {
-    let program = epg.send(QueryProgramMessage::ByNidSidEid {
-        nid: path.id.nid(),
-        sid: path.id.sid(),
-        eid: path.id.eid(),
-    }).await??;
+    let service = epg.send(query.make_message(path.id)).await??;
 
-    let service = epg.send(QueryServiceMessage::ByNidSid {
-        nid: path.id.nid(),
-        sid: path.id.sid(),
-    }).await??;
+    do_head_service_stream(
+        config, tuner_manager, epg, service.channel, service.sid,
+        Some(service.nid), user, filter_setting).await
+}
 
-    let clock = epg.send(QueryClockMessage {
-        triple: service.triple(),
-    }).await??;
+#[actix_web::get("/ws/channels/{channel_type}/{channel}/stream")]
+async fn get_ws_channel_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    path: actix_web::web::Path<ChannelPath>,
+    user: TunerUser,
+    filter_setting: FilterSetting,
+    req: actix_web::HttpRequest,
+    payload: actix_web::web::Payload,
+) -> ApiResult {
+    let builder = ws_handshake(&req)?;
 
-    let data = mustache::MapBuilder::new()
-        .insert_str("channel_name", &service.channel.name)
-        .insert("channel_type", &service.channel.channel_type)?
-        .insert_str("channel", &service.channel.channel)
-        .insert("sid", &program.quad.sid().value())?
-        .insert("eid", &program.quad.eid().value())?
-        .insert("clock_pcr", &clock.pcr)?
-        .insert("clock_time", &clock.time)?
-        .build();
+    let (channel, filters, _) = prepare_channel_stream(
+        &config, epg.get_ref(), &path, &filter_setting).await?;
 
-    let mut builder = FilterPipelineBuilder::new(data);
-    builder.add_pre_filters(&config.pre_filters, &filter_setting.pre_filters)?;
-    builder.add_service_filter(&config.filters.service_filter)?;
-    if filter_setting.decode {
-        builder.add_decode_filter(&config.filters.decode_filter)?;
+    let (channel, mut stream) = start_streaming_with_fallback(
+        &config, tuner_manager.get_ref(), epg.get_ref(), channel,
+        user.clone()).await?;
+
+    if filter_setting.failover {
+        stream.enable_failover(
+            channel, user, tuner_manager.get_ref().clone().recipient(),
+            epg.get_ref().clone().recipient());
     }
-    builder.add_program_filter(&config.filters.program_filter)?;
-    builder.add_post_filters(
-        &config.post_filters, &filter_setting.post_filters)?;
-    let (filters, content_type) = builder.build();
 
-    let stream = tuner_manager.send(StartStreamingMessage {
-        channel: service.channel.clone(),
-        user
-    }).await??;
+    ws_streaming(&config, builder, stream, filters, filter_setting.duration,
+                 payload).await
+}
+
+#[actix_web::get("/ws/services/{id}/stream")]
+async fn get_ws_service_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    path: actix_web::web::Path<ServicePath>,
+    query: actix_web::web::Query<ServiceQuery>,
+    user: TunerUser,
+    filter_setting: FilterSetting,
+    req: actix_web::HttpRequest,
+    payload: actix_web::web::Payload,
+) -> ApiResult {
+    let builder = ws_handshake(&req)?;
+
+    let service = epg.send(query.make_message(path.id)).await??;
+
+    let (filters, _) = prepare_service_stream(
+        &config, &service.channel, service.sid, Some(service.nid),
+        &filter_setting)?;
+
+    let stream = start_service_stream(
+        &config, &tuner_manager, &epg, service.channel, service.sid, user,
+        &filter_setting).await?;
+
+    ws_streaming(&config, builder, stream, filters, filter_setting.duration,
+                 payload).await
+}
+
+#[actix_web::get("/services/stream")]
+async fn get_multi_service_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    query: actix_web::web::Query<MultiServiceStreamQuery>,
+    user: TunerUser,
+    filter_setting: FilterSetting,
+) -> ApiResult {
+    let (channel, filters, content_type) = prepare_multi_service_stream(
+        &config, epg.get_ref(), query.into_inner(), &filter_setting).await?;
+
+    let (channel, mut stream) = start_streaming_with_fallback(
+        &config, tuner_manager.get_ref(), epg.get_ref(), channel,
+        user.clone()).await?;
+
+    if filter_setting.failover {
+        stream.enable_failover(
+            channel, user, tuner_manager.get_ref().clone().recipient(),
+            epg.get_ref().clone().recipient());
+    }
+
+    streaming(&config, stream, filters, content_type, None,
+              filter_setting.duration).await
+}
+
+#[actix_web::head("/services/stream")]
+async fn head_multi_service_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    query: actix_web::web::Query<MultiServiceStreamQuery>,
+    user: TunerUser,
+    filter_setting: FilterSetting,
+) -> ApiResult {
+    let (channel, _, content_type) = prepare_multi_service_stream(
+        &config, epg.get_ref(), query.into_inner(), &filter_setting).await?;
+
+    check_tuner_availability_with_fallback(
+        &config, tuner_manager.get_ref(), epg.get_ref(), channel, user).await?;
+
+    Ok(stream_head_response(content_type))
+}
 
-    let stop_trigger = airtime_tracker::track_airtime(
-        &config.recorder.track_airtime_command, &service.channel, &program,
-        stream.id(), tuner_manager.get_ref().clone(), epg.get_ref().clone()
-    ).await?;
+#[actix_web::get("/programs/{id}/stream")]
+async fn get_program_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    path: actix_web::web::Path<ProgramPath>,
+    mut user: TunerUser,
+    filter_setting: FilterSetting,
+) -> ApiResult {
+    let (program, service, filters, content_type) = prepare_program_stream(
+        &config, epg.get_ref(), &path, &filter_setting).await?;
+
+    let end_at = program.start_at + program.duration;
+    user.expected_end_at = Some(end_at);
+
+    let (channel, stream) = start_streaming_with_fallback(
+        &config, tuner_manager.get_ref(), epg.get_ref(),
+        service.channel.clone(), user).await?;
+
+    // The airtime of the program cannot be tracked on the fallback channel.
+    // The stream stops at the scheduled end time instead.
+    let (stop_trigger, duration) = if channel == service.channel {
+        let stop_trigger = airtime_tracker::track_airtime(
+            &config.recorder.track_airtime_command, &service.channel,
+            &program, stream.id(), tuner_manager.get_ref().clone(),
+            epg.get_ref().clone()
+        ).await?;
+        (stop_trigger, filter_setting.duration)
+    } else {
+        let remaining = (end_at - Jst::now()).to_std().unwrap_or_default();
+        let duration = match filter_setting.duration {
+            Some(duration) => duration.min(remaining),
+            None => remaining,
+        };
+        (None, Some(duration))
+    };
 
     let result =
-        streaming(&config, stream, filters, content_type, stop_trigger).await;
+        streaming(&config, stream, filters, content_type, stop_trigger,
+                  duration).await;
 
     match result {
         Err(Error::ProgramNotFound) =>
@@ -366,6 +1398,201 @@ async fn get_program_stream(
     result
 }
 
+#[actix_web::head("/programs/{id}/stream")]
+async fn head_program_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    path: actix_web::web::Path<ProgramPath>,
+    user: TunerUser,
+    filter_setting: FilterSetting,
+) -> ApiResult {
+    let (_, service, _, content_type) = prepare_program_stream(
+        &config, epg.get_ref(), &path, &filter_setting).await?;
+
+    check_tuner_availability_with_fallback(
+        &config, tuner_manager.get_ref(), epg.get_ref(), service.channel,
+        user).await?;
+
+    Ok(stream_head_response(content_type))
+}
+
+#[actix_web::get("/recording/schedules")]
+async fn get_recording_schedules(
+    recording_manager: actix_web::web::Data<Addr<RecordingManagerActor>>,
+) -> ApiResult {
+    recording_manager.send(QueryRecordingSchedulesMessage).await?
+        .map(|schedules| actix_web::HttpResponse::Ok().json(schedules))
+}
+
+#[actix_web::post("/recording/schedules")]
+async fn create_recording_schedule(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    recording_manager: actix_web::web::Data<Addr<RecordingManagerActor>>,
+    body: actix_web::web::Json<RecordingScheduleBody>,
+) -> ApiResult {
+    let program = epg.send(QueryProgramMessage::ByNidSidEid {
+        nid: body.program_id.nid(),
+        sid: body.program_id.sid(),
+        eid: body.program_id.eid(),
+    }).await??;
+
+    recording_manager.send(AddRecordingScheduleMessage {
+        program,
+        max_retries: body.max_retries,
+    }).await?
+        .map(|schedule| actix_web::HttpResponse::Created().json(schedule))
+}
+
+#[actix_web::get("/recording/rules")]
+async fn get_recording_rules(
+    recording_manager: actix_web::web::Data<Addr<RecordingManagerActor>>,
+) -> ApiResult {
+    recording_manager.send(QueryRecordingRulesMessage).await?
+        .map(|rules| actix_web::HttpResponse::Ok().json(rules))
+}
+
+#[actix_web::post("/recording/rules")]
+async fn create_recording_rule(
+    recording_manager: actix_web::web::Data<Addr<RecordingManagerActor>>,
+    body: actix_web::web::Json<RecordingRuleSpec>,
+) -> ApiResult {
+    recording_manager.send(AddRecordingRuleMessage {
+        spec: body.into_inner(),
+    }).await?
+        .map(|rule| actix_web::HttpResponse::Created().json(rule))
+}
+
+// Returns upcoming programs which the rule would schedule.  Nothing is
+// scheduled.
+#[actix_web::post("/recording/rules/preview")]
+async fn preview_recording_rule(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    recording_manager: actix_web::web::Data<Addr<RecordingManagerActor>>,
+    body: actix_web::web::Json<RecordingRuleSpec>,
+) -> ApiResult {
+    let programs = epg.send(QueryProgramsMessage).await??;
+    recording_manager.send(PreviewRecordingRuleMessage {
+        spec: body.into_inner(),
+        programs,
+    }).await?
+        .map(|previews| actix_web::HttpResponse::Ok().json(previews))
+}
+
+#[actix_web::put("/recording/rules/{id}")]
+async fn update_recording_rule(
+    recording_manager: actix_web::web::Data<Addr<RecordingManagerActor>>,
+    path: actix_web::web::Path<RecordingRulePath>,
+    body: actix_web::web::Json<RecordingRuleSpec>,
+) -> ApiResult {
+    recording_manager.send(UpdateRecordingRuleMessage {
+        id: path.id,
+        spec: body.into_inner(),
+    }).await?
+        .map(|rule| actix_web::HttpResponse::Ok().json(rule))
+}
+
+#[actix_web::delete("/recording/rules/{id}")]
+async fn delete_recording_rule(
+    recording_manager: actix_web::web::Data<Addr<RecordingManagerActor>>,
+    path: actix_web::web::Path<RecordingRulePath>,
+) -> ApiResult {
+    recording_manager.send(RemoveRecordingRuleMessage {
+        id: path.id,
+    }).await?
+        .map(|_| actix_web::HttpResponse::NoContent().finish())
+}
+
+#[actix_web::get("/recordings")]
+async fn get_recordings(
+    recording_manager: actix_web::web::Data<Addr<RecordingManagerActor>>,
+) -> ApiResult {
+    recording_manager.send(QueryRecordingsMessage).await?
+        .map(|recordings| actix_web::HttpResponse::Ok().json(recordings))
+}
+
+#[actix_web::post("/recordings/check")]
+async fn check_recordings(
+    recording_manager: actix_web::web::Data<Addr<RecordingManagerActor>>,
+) -> ApiResult {
+    recording_manager.send(CheckRecordingsMessage).await?
+        .map(|removed| actix_web::HttpResponse::Ok().json(removed))
+}
+
+#[actix_web::get("/recordings/{id}/stream")]
+async fn get_recording_stream(
+    recording_manager: actix_web::web::Data<Addr<RecordingManagerActor>>,
+    path: actix_web::web::Path<ProgramPath>,
+) -> Result<actix_files::NamedFile, Error> {
+    let recording = recording_manager.send(QueryRecordingMessage {
+        program_id: path.id,
+    }).await??;
+
+    // NamedFile supports Range requests.
+    Ok(actix_files::NamedFile::open(&recording.path)?)
+}
+
+#[actix_web::get("/epg/status")]
+async fn get_epg_status(
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+) -> ApiResult {
+    epg.send(QueryEpgStatusMessage).await?
+        .map(|status| actix_web::HttpResponse::Ok().json(status))
+}
+
+#[actix_web::post("/jobs/sync-clocks/run")]
+async fn run_sync_clocks(
+    job_manager: actix_web::web::Data<Addr<JobManagerActor>>,
+    query: actix_web::web::Query<SyncClocksQuery>,
+) -> ApiResult {
+    let channels = query.channel.as_ref().map(|channel| {
+        channel.split(',')
+            .map(|channel| channel.trim().to_string())
+            .collect()
+    });
+    job_manager.send(SyncClocksMessage { channels }).await?
+        .map(|_| actix_web::HttpResponse::Accepted().finish())
+}
+
+#[actix_web::post("/jobs/update-schedules/run")]
+async fn run_update_schedules(
+    job_manager: actix_web::web::Data<Addr<JobManagerActor>>,
+) -> ApiResult {
+    job_manager.send(InvokeUpdateSchedulesMessage).await?;
+    Ok(actix_web::HttpResponse::Accepted().finish())
+}
+
+#[actix_web::post("/jobs/{name}/pause")]
+async fn pause_job(
+    job_manager: actix_web::web::Data<Addr<JobManagerActor>>,
+    path: actix_web::web::Path<JobPath>,
+) -> ApiResult {
+    job_manager.send(PauseJobMessage {
+        name: path.name.clone(),
+    }).await?
+        .map(|_| actix_web::HttpResponse::NoContent().finish())
+}
+
+#[actix_web::post("/jobs/{name}/resume")]
+async fn resume_job(
+    job_manager: actix_web::web::Data<Addr<JobManagerActor>>,
+    path: actix_web::web::Path<JobPath>,
+) -> ApiResult {
+    job_manager.send(ResumeJobMessage {
+        name: path.name.clone(),
+    }).await?
+        .map(|_| actix_web::HttpResponse::NoContent().finish())
+}
+
+#[actix_web::get("/sessions/{id}/stats")]
+async fn get_session_stats(
+    path: actix_web::web::Path<SessionPath>,
+) -> ApiResult {
+    stream_stats::query(path.id)
+        .ok_or(Error::SessionNotFound)
+        .map(|stats| actix_web::HttpResponse::Ok().json(stats))
+}
+
 #[actix_web::get("/iptv/playlist")]
 async fn get_iptv_playlist(
     req: actix_web::HttpRequest,
@@ -402,712 +1629,3134 @@ async fn get_docs(
     Ok(actix_files::NamedFile::open(&config.mirakurun.openapi_json)?)
 }
 
-async fn do_get_service_stream(
-    config: actix_web::web::Data<Arc<Config>>,
+// Served outside the /api scope so that it can be scraped at the default path
+// of Prometheus.  Tuners are never acquired.
+#[actix_web::get("/metrics")]
+async fn get_metrics(
     tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
-    channel: EpgChannel,
-    sid: ServiceId,
-    user: TunerUser,
-    filter_setting: FilterSetting,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
 ) -> ApiResult {
+    let tuners = tuner_manager.send(QueryTunersMessage).await??;
+    let epg_status = epg.send(QueryEpgStatusMessage).await??;
+    Ok(actix_web::HttpResponse::Ok()
+       .content_type("text/plain; version=0.0.4")
+       .body(metrics::render(&tuners, &epg_status)))
+}
+
+#[actix_web::get("/debug/channels/{channel_type}/{channel}/psi")]
+async fn get_debug_channel_psi(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    path: actix_web::web::Path<ChannelPath>,
+    user: TunerUser,
+) -> ApiResult {
+    if !config.debug.enabled || config.debug.psi_filter.command.is_empty() {
+        return Err(Error::AccessDenied);
+    }
+
+    let channel = epg.send(QueryChannelMessage {
+        channel_type: path.channel_type,
+        channel: path.channel.clone(),
+    }).await??;
+
     let data = mustache::MapBuilder::new()
         .insert_str("channel_name", &channel.name)
         .insert("channel_type", &channel.channel_type)?
         .insert_str("channel", &channel.channel)
-        .insert("sid", &sid.value())?
         .build();
 
-    let mut builder = FilterPipelineBuilder::new(data);
-    builder.add_pre_filters(
-        &config.pre_filters, &filter_setting.pre_filters)?;
-    builder.add_service_filter(&config.filters.service_filter)?;
-    if filter_setting.decode {
-        builder.add_decode_filter(&config.filters.decode_filter)?;
-    }
-    builder.add_post_filters(
-        &config.post_filters, &filter_setting.post_filters)?;
-    let (filters, content_type) = builder.build();
+    let builder = FilterPipelineBuilder::new(data);
+    let filter = builder.make_filter(&config.debug.psi_filter.command)?;
 
     let stream = tuner_manager.send(StartStreamingMessage {
         channel, user
     }).await??;
 
-    streaming(&config, stream, filters, content_type, None).await
-}
+    let mut pipeline = spawn_pipeline(vec![filter], stream.id())?;
+    let (input, mut output) = pipeline.take_endpoints()?;
+    let handle = tokio::spawn(stream.pipe(input));
 
-async fn streaming(
-    config: &Config,
-    mut stream: MpegTsStream,
-    filters: Vec<String>,
-    content_type: String,
-    stop_trigger: Option<MpegTsStreamStopTrigger>,
-) -> ApiResult {
-    if filters.is_empty() {
-        do_streaming(
-            stream, content_type, config.server.stream_time_limit).await
-    } else {
-        log::debug!("Streaming with filters: {:?}", filters);
+    let mut buf = Vec::new();
+    let result = tokio::time::timeout(
+        Duration::from_millis(config.debug.time_limit),
+        output.read_to_end(&mut buf)).await;
 
-        let stop_trigger2 = stream.take_stop_trigger();
+    // Release the tuner as soon as possible.
+    drop(output);
+    drop(pipeline);
+    let _ = handle.await;
 
-        let mut pipeline = spawn_pipeline(filters, stream.id())?;
+    match result {
+        Ok(result) => result?,
+        Err(_) => return Err(Error::StreamingTimedOut),
+    };
 
-        let (input, output) = pipeline.take_endpoints()?;
+    let tables: serde_json::Value = serde_json::from_slice(&buf)?;
+    Ok(actix_web::HttpResponse::Ok().json(tables))
+}
 
-        let stream_id = stream.id();
-        actix::spawn(async {
-            let _ = stream.pipe(input).await;
-        });
+#[actix_web::get("/debug/channels/{channel_type}/{channel}/bitrate")]
+async fn get_debug_channel_bitrate(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    path: actix_web::web::Path<ChannelPath>,
+    user: TunerUser,
+) -> ApiResult {
+    if !config.debug.enabled {
+        return Err(Error::AccessDenied);
+    }
 
-        // Use a MPSC channel as a buffer.
-        //
-        // The command pipeline often breaks when reading stops for a few
-        // seconds.
-        let mut stream = ChunkStream::new(
-            output, config.server.stream_chunk_size);
-        let (mut sender, receiver) =
-            mpsc::channel(config.server.stream_max_chunks);
-        actix::spawn(async move {
-            while let Some(result) = stream.next().await {
-                if let Ok(chunk) = result {
-                    log::trace!("{}: Received a filtered chunk of {} bytes",
-                                stream_id, chunk.len());
-                    // The task yields if the buffer is full.
-                    if let Err(_) = sender.send(Ok(chunk)).await {
-                        log::debug!("{}: Disconnected by client", stream_id);
-                        break;
-                    }
-                } else {
-                    log::error!("{}: Error, stop streaming", stream_id);
-                    break;
-                }
+    let channel = epg.send(QueryChannelMessage {
+        channel_type: path.channel_type,
+        channel: path.channel.clone(),
+    }).await??;
+
+    let mut stream = tuner_manager.send(StartStreamingMessage {
+        channel, user
+    }).await??;
 
-                // Always yield for sending the chunk to the client quickly.
-                //
-                // The async task never yields voluntarily and can starve other
-                // tasks waiting on the same executor.  For avoiding the
-                // starvation, the task has to yields within a short term.
-                //
-                // Theoretically, one 32 KiB chunk comes every 10 ms.  This
-                // period is a long enough time in the CPU time point of view.
-                // Therefore, the async task simply yields at the end of every
-                // iteration.
-                tokio::task::yield_now().await;
+    // Sample TS packets until `debug.time-limit` passes.
+    let mut analyzer = PidAnalyzer::new();
+    let start = std::time::Instant::now();
+    let result = tokio::time::timeout(
+        Duration::from_millis(config.debug.time_limit),
+        async {
+            while let Some(chunk) = stream.next().await {
+                analyzer.feed(&chunk?);
             }
+            Ok::<(), io::Error>(())
+        }).await;
+    let elapsed = start.elapsed();
 
-            drop(pipeline);
-        });
+    // Release the tuner as soon as possible.
+    drop(stream);
 
-        do_streaming(
-            MpegTsStreamTerminator::new(receiver, [stop_trigger, stop_trigger2]),
-            content_type, config.server.stream_time_limit).await
+    if let Ok(result) = result {
+        // The stream ended before the sample window closed.
+        result?;
     }
+
+    Ok(actix_web::HttpResponse::Ok().json(analyzer.report(elapsed)))
 }
 
-async fn do_streaming<S>(
-    stream: S,
-    content_type: String,
-    time_limit: u64,
-) -> ApiResult
-where
-    // actix_web::dev::HttpResponseBuilder::streaming() requires 'static...
-    S: Stream<Item = io::Result<Bytes>> + Unpin + 'static,
-{
-    // No data is sent to the client until the first TS packet comes from the
-    // streaming pipeline.
-    let mut peekable = stream.peekable();
-    let fut = Pin::new(&mut peekable).peek();
-    match tokio::time::timeout(Duration::from_millis(time_limit), fut).await {
-        Ok(None) => {
-            // No packets come from the pipeline, maybe the program has been
-            // canceled.
-            Err(Error::ProgramNotFound)
-        }
-        Err(_) => {
-            Err(Error::StreamingTimedOut)
-        }
-        Ok(_) =>  {
-            // Send the response headers and start streaming.
-            Ok(actix_web::HttpResponse::Ok()
-               .force_close()
-               .set_header("cache-control", "no-store")
-               .set_header("content-type", content_type)
-               .streaming(peekable))
-        }
+// Tunes a physical channel which may not be defined in the config, and streams
+// the raw TS packets.  Services of the channel are never resolved.
+#[actix_web::get("/debug/tune/{channel_type}/{channel}/stream")]
+async fn get_debug_tune_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    path: actix_web::web::Path<ChannelPath>,
+    query: actix_web::web::Query<DebugTuneQuery>,
+    user: TunerUser,
+) -> ApiResult {
+    if !config.debug.enabled {
+        return Err(Error::AccessDenied);
     }
+
+    let channel = EpgChannel {
+        name: path.channel.clone(),
+        channel_type: path.channel_type,
+        channel: path.channel.clone(),
+        extra_args: query.extra_args.clone(),
+        services: Vec::new(),
+        excluded_services: Vec::new(),
+    };
+
+    let stream = tuner_manager.send(StartStreamingMessage {
+        channel, user
+    }).await??;
+
+    // The tuner is released when the client disconnects.
+    streaming(&config, stream, Vec::new(), "video/MP2T".to_string(), None,
+              None).await
 }
 
-// extractors
+async fn prepare_channel_stream(
+    config: &Config,
+    epg: &Addr<EpgActor>,
+    path: &ChannelPath,
+    filter_setting: &FilterSetting,
+) -> Result<(EpgChannel, FilterCommands, String), Error> {
+    let channel = epg.send(QueryChannelMessage {
+        channel_type: path.channel_type,
+        channel: path.channel.clone(),
+    }).await??;
 
-#[derive(Deserialize)]
-struct ChannelPath {
-    channel_type: ChannelType,
-    channel: String,
+    let data = mustache::MapBuilder::new()
+        .insert_str("channel_name", &channel.name)
+        .insert("channel_type", &channel.channel_type)?
+        .insert_str("channel", &channel.channel)
+        .build();
+
+    let mut builder = FilterPipelineBuilder::new(data);
+    builder.add_pre_filters(
+        &filter_setting.pre_filter_defs,
+        &filter_setting.pre_filters_with_defaults(config, &channel, None))?;
+    if filter_setting.decode {
+        builder.add_decode_filter(&config.filters.decode_filter)?;
+    }
+    builder.add_post_filters(
+        &filter_setting.post_filter_defs,
+        &filter_setting.post_filters_with_defaults(config, &channel, None))?;
+    if filter_setting.output == StreamOutput::Fmp4 {
+        builder.add_fmp4_filter(&config.filters.fmp4_filter)?;
+    }
+    let (filters, content_type) =
+        builder.build(config.filters.max_pipeline_stages)?;
+
+    Ok((channel, filters, content_type))
 }
 
-#[derive(Deserialize)]
-struct ChannelServicePath {
-    channel_type: ChannelType,
-    channel: String,
+async fn do_get_service_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    channel: EpgChannel,
     sid: ServiceId,
+    nid: Option<NetworkId>,
+    user: TunerUser,
+    filter_setting: FilterSetting,
+) -> ApiResult {
+    let (filters, content_type) = prepare_service_stream(
+        &config, &channel, sid, nid, &filter_setting)?;
+
+    let stream = start_service_stream(
+        &config, &tuner_manager, &epg, channel, sid, user,
+        &filter_setting).await?;
+
+    streaming(&config, stream, filters, content_type, None,
+              filter_setting.duration).await
 }
 
-#[derive(Deserialize)]
-struct ServicePath {
-    id: MirakurunServiceId,
+async fn do_head_service_stream(
+    config: actix_web::web::Data<Arc<Config>>,
+    tuner_manager: actix_web::web::Data<Addr<TunerManagerActor>>,
+    epg: actix_web::web::Data<Addr<EpgActor>>,
+    channel: EpgChannel,
+    sid: ServiceId,
+    nid: Option<NetworkId>,
+    user: TunerUser,
+    filter_setting: FilterSetting,
+) -> ApiResult {
+    let (_, content_type) = prepare_service_stream(
+        &config, &channel, sid, nid, &filter_setting)?;
+
+    check_tuner_availability_with_fallback(
+        &config, tuner_manager.get_ref(), epg.get_ref(), channel, user).await?;
+
+    Ok(stream_head_response(content_type))
 }
 
-#[derive(Deserialize)]
-struct ProgramPath {
-    id: MirakurunProgramId,
+async fn start_service_stream(
+    config: &Config,
+    tuner_manager: &Addr<TunerManagerActor>,
+    epg: &Addr<EpgActor>,
+    channel: EpgChannel,
+    sid: ServiceId,
+    user: TunerUser,
+    filter_setting: &FilterSetting,
+) -> Result<MpegTsStream, Error> {
+    let (channel, mut stream) = start_streaming_with_fallback(
+        config, tuner_manager, epg, channel, user.clone()).await?;
+
+    if filter_setting.failover {
+        stream.enable_failover(
+            channel, user, tuner_manager.clone().recipient(),
+            epg.clone().recipient());
+    }
+
+    if config.filters.builtin_service_filter {
+        stream.enable_service_filter(sid);
+    }
+
+    Ok(stream)
 }
 
-// actix-web uses the serde_urlencoded crate for parsing the query in an URL.
-// Unfortunately, the Vec support is out of scope for the serde_urlencoded
-// crate and it's suggested to use the serde_qs crate.
-//
-// * nox/serde_urlencoded/issues/46
-//
-// actix-web tried to replace the serde_urlencoded crate with the serde_qs
-// crate:
-//
-// * actix/actix-web/issues/38
-// * actix/actix-web/issues/1211
-//
-// but the owner decided not to do that finally.  The reason is unclear at least
-// for me.
-//
-// Actually, the serde_qs crate works well with actix-web without any
-// difficulty as you can see in code below.
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-struct FilterSetting {
-    #[serde(default)]
-    #[serde(deserialize_with = "deserialize_stream_decode_query")]
-    decode: bool,  // default: false
-    #[serde(default)]
-    pre_filters: Vec<String>,  // default: empty
-    #[serde(default)]
-    post_filters: Vec<String>,  // default: empty
+// The path of /channels/{channel_type}/{channel}/services/{sid}/stream doesn't
+// contain the NID.  The NID is taken from the service found in the channel in
+// order to select default filters of the service.
+async fn find_channel_service_nid(
+    epg: &Addr<EpgActor>,
+    channel: &EpgChannel,
+    sid: ServiceId,
+) -> Result<Option<NetworkId>, Error> {
+    let services = epg.send(QueryServicesMessage).await??;
+    Ok(services.iter()
+       .find(|sv| {
+           sv.sid == sid &&
+               sv.channel.channel_type == channel.channel_type &&
+               sv.channel.channel == channel.channel
+       })
+       .map(|sv| sv.nid))
 }
 
-impl FromRequest for FilterSetting {
-    type Error = actix_web::Error;
-    type Future = futures::future::Ready<Result<Self, Self::Error>>;
-    type Config = ();
+// Default filters of the service are selected only when the NID is known.
+fn prepare_service_stream(
+    config: &Config,
+    channel: &EpgChannel,
+    sid: ServiceId,
+    nid: Option<NetworkId>,
+    filter_setting: &FilterSetting,
+) -> Result<(FilterCommands, String), Error> {
+    let service = nid.map(|nid| MirakurunServiceId::from((nid, sid)));
+    let data = mustache::MapBuilder::new()
+        .insert_str("channel_name", &channel.name)
+        .insert("channel_type", &channel.channel_type)?
+        .insert_str("channel", &channel.channel)
+        .insert("sid", &sid.value())?
+        .build();
 
-    fn from_request(
-        req: &actix_web::HttpRequest,
-        _: &mut actix_web::dev::Payload
-    ) -> Self::Future {
-        match serde_qs::from_str::<FilterSetting>(req.query_string()) {
-            Ok(query) => futures::future::ok(query),
-            Err(err) => futures::future::err(failure::format_err!(
-                "Failed to parse the query string: {}", err).into()),
+    let service_decode_filter =
+        config.filters.find_service_decode_filter(sid);
+
+    let mut builder = FilterPipelineBuilder::new(data);
+    builder.add_pre_filters(
+        &filter_setting.pre_filter_defs,
+        &filter_setting.pre_filters_with_defaults(config, channel, service))?;
+    if filter_setting.decode {
+        if let Some(config) = service_decode_filter {
+            builder.add_service_decode_filter(config)?;
         }
     }
+    // The built-in service filter is applied to the stream from the tuner.
+    // See `start_service_stream()`.
+    if !config.filters.builtin_service_filter {
+        builder.add_service_filter(&config.filters.service_filter)?;
+    }
+    if filter_setting.decode && service_decode_filter.is_none() {
+        builder.add_decode_filter(&config.filters.decode_filter)?;
+    }
+    builder.add_post_filters(
+        &filter_setting.post_filter_defs,
+        &filter_setting.post_filters_with_defaults(config, channel, service))?;
+    if filter_setting.output == StreamOutput::Fmp4 {
+        builder.add_fmp4_filter(&config.filters.fmp4_filter)?;
+    }
+
+    builder.build(config.filters.max_pipeline_stages)
 }
 
-fn deserialize_stream_decode_query<'de, D>(deserializer: D) -> Result<bool, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    if s == "0" || s == "false" {
-        return Ok(false);
+async fn prepare_multi_service_stream(
+    config: &Config,
+    epg: &Addr<EpgActor>,
+    query: MultiServiceStreamQuery,
+    filter_setting: &FilterSetting,
+) -> Result<(EpgChannel, FilterCommands, String), Error> {
+    let ids = match query.services {
+        Some(ids) if !ids.is_empty() => ids,
+        _ => return Err(Error::BadRequest(
+            "The services query is required".to_string())),
+    };
+    if ids.len() > MAX_SERVICES_PER_REQUEST {
+        return Err(Error::BadRequest(format!(
+            "No more than {} services can be specified",
+            MAX_SERVICES_PER_REQUEST)));
     }
-    if s == "1" || s == "true" {
-        return Ok(true);
+
+    let mut services = Vec::with_capacity(ids.len());
+    for id in ids.into_iter() {
+        services.push(epg.send(QueryServiceMessage::ByNidSid {
+            nid: id.nid(),
+            sid: id.sid(),
+        }).await??);
     }
-    Err(serde::de::Error::custom(
-        "The value of the decode query must be 0, 1, false or true"))
-}
 
-impl FromRequest for TunerUser {
-    type Error = actix_web::Error;
-    type Future = futures::future::Ready<Result<Self, Self::Error>>;
-    type Config = ();
+    // All services must be delivered in a single TS from a tuner.
+    let channel = services[0].channel.clone();
+    let same_channel = services.iter().all(|service| {
+        service.channel.channel_type == channel.channel_type &&
+            service.channel.channel == channel.channel
+    });
+    if !same_channel {
+        return Err(Error::BadRequest(
+            "Services must belong to the same channel".to_string()));
+    }
 
-    fn from_request(
-        req: &actix_web::HttpRequest,
-        _: &mut actix_web::dev::Payload
-    ) -> Self::Future {
-        let remote = req
-            .connection_info()
-            .remote()
-            .map(|v| v.to_string());
+    if config.filters.multi_service_filter.command.is_empty() {
+        return Err(Error::AccessDenied);
+    }
 
-        let agent = req
-            .headers()
-            .get_all(actix_web::http::header::USER_AGENT)
-            .last()
-            .map(|value| {
-                value.to_str().ok().map_or(String::new(), |s| s.to_string())
-            });
+    let sids: Vec<u16> = services.iter()
+        .map(|service| service.sid.value())
+        .collect();
 
-        let info = TunerUserInfo::Web { remote, agent };
+    let data = mustache::MapBuilder::new()
+        .insert_str("channel_name", &channel.name)
+        .insert("channel_type", &channel.channel_type)?
+        .insert_str("channel", &channel.channel)
+        .insert("sids", &sids)?
+        .build();
 
-        let priority = req.headers().get_all("x-mirakurun-priority")
-            .filter_map(|value| value.to_str().ok())
-            .filter_map(|value| value.parse::<i32>().ok())
-            .max()
-            .map(|value| value.max(0))
-            .map(TunerUserPriority::from)
-            .unwrap_or_default();
-
-        futures::future::ok(TunerUser { info, priority })
+    let mut builder = FilterPipelineBuilder::new(data);
+    builder.add_pre_filters(
+        &filter_setting.pre_filter_defs,
+        &filter_setting.pre_filters_with_defaults(config, &channel, None))?;
+    builder.add_multi_service_filter(&config.filters.multi_service_filter)?;
+    if filter_setting.decode {
+        builder.add_decode_filter(&config.filters.decode_filter)?;
     }
-}
+    builder.add_post_filters(
+        &filter_setting.post_filter_defs,
+        &filter_setting.post_filters_with_defaults(config, &channel, None))?;
+    if filter_setting.output == StreamOutput::Fmp4 {
+        builder.add_fmp4_filter(&config.filters.fmp4_filter)?;
+    }
+    let (filters, content_type) =
+        builder.build(config.filters.max_pipeline_stages)?;
 
-// middleware
+    Ok((channel, filters, content_type))
+}
 
-struct AccessControl;
+async fn prepare_program_stream(
+    config: &Config,
+    epg: &Addr<EpgActor>,
+    path: &ProgramPath,
+    filter_setting: &FilterSetting,
+) -> Result<(EpgProgram, EpgService, FilterCommands, String), Error> {
+    let program = epg.send(QueryProgramMessage::ByNidSidEid {
+        nid: path.id.nid(),
+        sid: path.id.sid(),
+        eid: path.id.eid(),
+    }).await??;
 
-impl<S, B> actix_service::Transform<S> for AccessControl
-where
-    S: actix_service::Service<Request = actix_web::dev::ServiceRequest,
-                              Response = actix_web::dev::ServiceResponse<B>,
-                              Error = actix_web::Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Request = actix_web::dev::ServiceRequest;
-    type Response = actix_web::dev::ServiceResponse<B>;
-    type Error = actix_web::Error;
-    type InitError = ();
-    type Transform = AccessControlMiddleware<S>;
-    type Future =
-        futures::future::Ready<Result<Self::Transform, Self::InitError>>;
+    let service = epg.send(QueryServiceMessage::ByTriple {
+        triple: program.quad.into(),
+    }).await??;
 
-    fn new_transform(&self, service: S) -> Self::Future {
-        futures::future::ok(AccessControlMiddleware(service))
-    }
-}
+    let clock = epg.send(QueryClockMessage {
+        triple: service.triple(),
+    }).await??;
 
-struct AccessControlMiddleware<S>(S);
+    let data = mustache::MapBuilder::new()
+        .insert_str("channel_name", &service.channel.name)
+        .insert("channel_type", &service.channel.channel_type)?
+        .insert_str("channel", &service.channel.channel)
+        .insert("sid", &program.quad.sid().value())?
+        .insert("eid", &program.quad.eid().value())?
+        .insert("clock_pcr", &clock.pcr)?
+        .insert("clock_time", &clock.time)?
+        .insert("video_component_tag",
+                &filter_setting.video_component_tag(&program))?
+        .insert("audio_component_tag",
+                &filter_setting.audio_component_tag(&program))?
+        .build();
 
-impl<S, B> actix_service::Service for AccessControlMiddleware<S>
-where
-    S: actix_service::Service<Request = actix_web::dev::ServiceRequest,
-                              Response = actix_web::dev::ServiceResponse<B>,
-                              Error = actix_web::Error>,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Request = actix_web::dev::ServiceRequest;
-    type Response = actix_web::dev::ServiceResponse<B>;
-    type Error = actix_web::Error;
-    type Future = Pin<Box<dyn futures::future::Future<
-            Output = Result<Self::Response, Self::Error>>>>;
+    let service_decode_filter =
+        config.filters.find_service_decode_filter(service.sid);
 
-    fn poll_ready(
-        &mut self,
-        cx: &mut std::task::Context<'_>
-    ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.0.poll_ready(cx)
+    let mut builder = FilterPipelineBuilder::new(data);
+    builder.add_pre_filters(
+        &filter_setting.pre_filter_defs,
+        &filter_setting.pre_filters_with_defaults(
+            config, &service.channel, Some(service.triple().into())))?;
+    if filter_setting.decode {
+        if let Some(config) = service_decode_filter {
+            builder.add_service_decode_filter(config)?;
+        }
+    }
+    builder.add_service_filter(&config.filters.service_filter)?;
+    if filter_setting.decode && service_decode_filter.is_none() {
+        builder.add_decode_filter(&config.filters.decode_filter)?;
+    }
+    builder.add_program_filter(&config.filters.program_filter)?;
+    builder.add_post_filters(
+        &filter_setting.post_filter_defs,
+        &filter_setting.post_filters_with_defaults(
+            config, &service.channel, Some(service.triple().into())))?;
+    if filter_setting.output == StreamOutput::Fmp4 {
+        builder.add_fmp4_filter(&config.filters.fmp4_filter)?;
     }
+    let (filters, content_type) =
+        builder.build(config.filters.max_pipeline_stages)?;
 
-    fn call(&mut self, req: actix_web::dev::ServiceRequest) -> Self::Future {
-        // Take no account of HTTP Forwarded and X-Forwarded-For headers.
-        let ip = req.peer_addr().map(|socket| socket.ip());
+    Ok((program, service, filters, content_type))
+}
 
-        let allowed = match ip {
-            Some(ip) => is_private_ip_addr(ip),
-            None => true,  // UNIX domain socket
-        };
+fn find_fallback_channel<'a>(
+    config: &'a Config,
+    channel: &EpgChannel,
+) -> Option<&'a FallbackChannelConfig> {
+    config.channels.iter()
+        .filter(|ch| !ch.disabled)
+        .find(|ch| {
+            ch.channel_type == channel.channel_type &&
+                ch.channel == channel.channel
+        })
+        .and_then(|ch| ch.fallback.as_ref())
+}
 
-        if allowed {
-            Box::pin(self.0.call(req))
-        } else {
-            Box::pin(futures::future::ok(req.error_response(
-                actix_web::error::ErrorForbidden(Error::AccessDenied))))
+// Used for all streaming endpoints.  Returns the stream together with the
+// channel actually used, which is the fallback channel if the channel cannot
+// be streamed.
+async fn start_streaming_with_fallback(
+    config: &Config,
+    tuner_manager: &Addr<TunerManagerActor>,
+    epg: &Addr<EpgActor>,
+    channel: EpgChannel,
+    user: TunerUser,
+) -> Result<(EpgChannel, MpegTsStream), Error> {
+    let fallback = find_fallback_channel(config, &channel).cloned();
+
+    let result = tuner_manager.send(StartStreamingMessage {
+        channel: channel.clone(),
+        user: user.clone(),
+    }).await?;
+
+    match (result, fallback) {
+        (Ok(stream), _) => Ok((channel, stream)),
+        (Err(err), Some(fallback)) => {
+            log::warn!("Failed to start streaming for {}: {}, \
+                        fallback to {}/{}", channel, err,
+                       fallback.channel_type, fallback.channel);
+            let channel = epg.send(QueryChannelMessage {
+                channel_type: fallback.channel_type,
+                channel: fallback.channel,
+            }).await??;
+            let stream = tuner_manager.send(StartStreamingMessage {
+                channel: channel.clone(),
+                user,
+            }).await??;
+            Ok((channel, stream))
         }
+        (Err(err), None) => Err(err),
     }
 }
 
-fn is_private_ip_addr(ip: IpAddr) -> bool {
-    // TODO: IpAddr::is_global() is a nightly-only API at this point.
-    match ip {
-        IpAddr::V4(ip) => is_private_ipv4_addr(ip),
-        IpAddr::V6(ip) => is_private_ipv6_addr(ip),
+// Same as `start_streaming_with_fallback()` but no tuner is activated.
+async fn check_tuner_availability_with_fallback(
+    config: &Config,
+    tuner_manager: &Addr<TunerManagerActor>,
+    epg: &Addr<EpgActor>,
+    channel: EpgChannel,
+    user: TunerUser,
+) -> Result<(), Error> {
+    let fallback = find_fallback_channel(config, &channel).cloned();
+
+    let result = tuner_manager.send(CheckTunerAvailabilityMessage {
+        channel, user: user.clone(),
+    }).await?;
+
+    match (result, fallback) {
+        (Err(_), Some(fallback)) => {
+            let channel = epg.send(QueryChannelMessage {
+                channel_type: fallback.channel_type,
+                channel: fallback.channel,
+            }).await??;
+            tuner_manager.send(CheckTunerAvailabilityMessage {
+                channel, user
+            }).await?
+        }
+        (result, _) => result,
     }
 }
 
-fn is_private_ipv4_addr(ip: Ipv4Addr) -> bool {
-    ip.is_loopback() || ip.is_private() || ip.is_link_local()
+// Returns the response headers which the corresponding GET request would
+// return, without tuning.  The body is always empty.
+fn stream_head_response(content_type: String) -> actix_web::HttpResponse {
+    actix_web::HttpResponse::Ok()
+        .set_header("cache-control", "no-store")
+        .set_header("content-type", content_type)
+        .finish()
 }
 
-fn is_private_ipv6_addr(ip: Ipv6Addr) -> bool {
-    // TODO: Support only IPv4-compatible and IPv4-mapped addresses at this
-    //       moment.
-    match ip.to_ipv4() {
-        Some(ip) => is_private_ipv4_addr(ip),
-        None => false,
-    }
+async fn streaming(
+    config: &Config,
+    stream: MpegTsStream,
+    filters: FilterCommands,
+    content_type: String,
+    stop_trigger: Option<MpegTsStreamStopTrigger>,
+    duration: Option<Duration>,
+) -> ApiResult {
+    let (stream, stats_id) = start_streaming(
+        config, stream, filters, stop_trigger, duration)?;
+    do_streaming(
+        stream, content_type, config.server.stream_time_limit, stats_id).await
 }
 
-// filters
+// Relays chunks to a WebSocket client instead of the response body.
+//
+// `builder` is the response builder returned from `ws_handshake()`.  Like
+// `do_streaming()`, the handshake response is sent after the first TS packet
+// comes from the streaming pipeline.
+async fn ws_streaming(
+    config: &Config,
+    mut builder: actix_web::dev::HttpResponseBuilder,
+    stream: MpegTsStream,
+    filters: FilterCommands,
+    duration: Option<Duration>,
+    payload: actix_web::web::Payload,
+) -> ApiResult {
+    let (stream, stats_id) = start_streaming(
+        config, stream, filters, None, duration)?;
+    let stream =
+        wait_for_first_chunk(stream, config.server.stream_time_limit).await?;
+    if let Some(id) = stats_id {
+        builder.set_header("x-mirakc-session-id", id.to_string());
+    }
+    Ok(builder.streaming(
+        ws::WebsocketContext::create(WsRelay::new(stream), payload)))
+}
 
-struct FilterPipelineBuilder {
-    data: mustache::Data,
-    filters: Vec<String>,
-    content_type: String,
+// The handshake has to be done before starting streaming so that a tuner is
+// never activated for a request which is not a WebSocket upgrade.
+fn ws_handshake(
+    req: &actix_web::HttpRequest,
+) -> Result<actix_web::dev::HttpResponseBuilder, Error> {
+    ws::handshake(req).map_err(|err| Error::BadRequest(err.to_string()))
 }
 
-impl FilterPipelineBuilder {
-    fn new(data: mustache::Data) -> Self {
-        FilterPipelineBuilder {
-            data,
-            filters: Vec::new(),
-            content_type: "video/MP2T".to_string(),
-        }
-    }
+type ChunkReceiver = Box<dyn Stream<Item = io::Result<Bytes>> + Unpin>;
 
-    fn build(self) -> (Vec<String>, String) {
-        (self.filters, self.content_type)
-    }
+// Starts relaying chunks from the streaming pipeline, and returns the
+// receiving end of the relay together with the ID of the stream statistics.
+fn start_streaming(
+    config: &Config,
+    mut stream: MpegTsStream,
+    filters: FilterCommands,
+    stop_trigger: Option<MpegTsStreamStopTrigger>,
+    duration: Option<Duration>,
+) -> Result<(ChunkReceiver, Option<u64>), Error> {
+    let stream_id = stream.id();
+    let idle_timeout = if config.server.stream_idle_timeout > 0 {
+        Some(Duration::from_millis(config.server.stream_idle_timeout))
+    } else {
+        None
+    };
+    let coalesce_size = config.server.stream_coalesce_size;
+    let coalesce_timeout =
+        Duration::from_millis(config.server.stream_coalesce_timeout);
+    let jitter_buffer_size = config.server.stream_jitter_buffer_size;
+    let jitter_buffer_time =
+        Duration::from_millis(config.server.stream_jitter_buffer_time);
+
+    let stats = if config.server.stream_stats_retention > 0 {
+        Some(stream_stats::register(
+            Duration::from_millis(config.server.stream_stats_retention)))
+    } else {
+        None
+    };
+    let stats_id = stats.as_ref().map(|stats| stats.id());
 
-    fn add_pre_filters(
-        &mut self,
-        pre_filters: &HashMap<String, FilterConfig>,
-        names: &Vec<String>
-    ) -> Result<(), Error> {
-        for name in names.iter() {
-            if pre_filters.contains_key(name) {
-                self.add_pre_filter(&pre_filters[name], name)?;
-            } else {
-                log::warn!("No such pre-filter: {}", name);
-            }
-        }
-        Ok(())
-    }
+    // Use a MPSC channel as a buffer.
+    //
+    // The command pipeline often breaks when reading stops for a few seconds.
+    let (sender, receiver) = mpsc::channel(config.server.stream_max_chunks);
 
-    fn add_pre_filter(
-        &mut self,
-        config: &FilterConfig,
-        name: &str,
-    ) -> Result<(), Error> {
-        let filter = self.make_filter(&config.command)?;
-        if filter.is_empty() {
-            log::warn!("pre-filter({}) not valid", name);
-        } else {
-            self.filters.push(filter);
-        }
-        Ok(())
-    }
+    if filters.is_empty() {
+        let stream = JitterBufferStream::new(
+            stream, jitter_buffer_size, jitter_buffer_time);
+        let stream = CoalescedChunkStream::new(
+            stream, coalesce_size, coalesce_timeout);
+        actix::spawn(async move {
+            limit_duration(stream_id, duration, relay_chunks(
+                stream_id, stream, sender, idle_timeout, stats)).await;
+        });
 
-    fn add_service_filter(
-        &mut self,
-        config: &FilterConfig,
-    ) -> Result<(), Error> {
-        let filter = self.make_filter(&config.command)?;
-        if filter.is_empty() {
-            log::warn!("service-filter not valid");
-        } else {
-            self.filters.push(filter);
-        }
-        Ok(())
-    }
+        Ok((Box::new(MpegTsStreamTerminator::new(receiver, stop_trigger)),
+            stats_id))
+    } else {
+        log::debug!("Streaming with filters: {:?}", filters);
 
-    fn add_decode_filter(
-        &mut self,
-        config: &FilterConfig
-    ) -> Result<(), Error> {
-        let filter = self.make_filter(&config.command)?;
-        if filter.is_empty() {
-            log::warn!("decode-filter not valid");
-        } else {
-            self.filters.push(filter);
-        }
-        Ok(())
+        let stop_trigger2 = stream.take_stop_trigger();
+
+        let mut pipeline = spawn_pipeline_with_command_limits(
+            filters, stream.id(), &config.filters.process_limits)?;
+
+        let (input, output) = pipeline.take_endpoints()?;
+
+        actix::spawn(async {
+            let _ = stream.pipe(input).await;
+        });
+
+        let stream = ChunkStream::new(output, config.server.stream_chunk_size);
+        let stream = JitterBufferStream::new(
+            stream, jitter_buffer_size, jitter_buffer_time);
+        let stream = CoalescedChunkStream::new(
+            stream, coalesce_size, coalesce_timeout);
+        actix::spawn(async move {
+            limit_duration(stream_id, duration, relay_chunks(
+                stream_id, stream, sender, idle_timeout, stats)).await;
+            drop(pipeline);
+        });
+
+        Ok((Box::new(MpegTsStreamTerminator::new(
+            receiver, [stop_trigger, stop_trigger2])), stats_id))
     }
+}
 
-    fn add_program_filter(
-        &mut self,
-        config: &FilterConfig,
-    ) -> Result<(), Error> {
-        let filter = self.make_filter(&config.command)?;
-        if filter.is_empty() {
-            log::warn!("program-filter not valid");
+// Relays chunks to a client.
+//
+// The client is disconnected when it doesn't consume any chunk for
+// `idle_timeout` if specified.  This is needed for reclaiming resources held
+// by a client which stops reading without closing the connection.  Such a
+// client cannot be detected by the time limit of a tuner which is applied to
+// incoming data from the tuner.
+//
+// The stream is treated as ended in the statistics when this function returns.
+async fn relay_chunks<S>(
+    stream_id: MpegTsStreamId,
+    mut stream: S,
+    mut sender: mpsc::Sender<io::Result<Bytes>>,
+    idle_timeout: Option<Duration>,
+    stats: Option<StreamStatsHandle>,
+)
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    while let Some(result) = stream.next().await {
+        if let Ok(chunk) = result {
+            let len = chunk.len();
+            log::trace!("{}: Received a chunk of {} bytes", stream_id, len);
+            // The task yields if the buffer is full.
+            let result = match idle_timeout {
+                Some(idle_timeout) => tokio::time::timeout(
+                    idle_timeout, sender.send(Ok(chunk))).await,
+                None => Ok(sender.send(Ok(chunk)).await),
+            };
+            match result {
+                Ok(Ok(_)) => {
+                    if let Some(ref stats) = stats {
+                        stats.add_chunk(len);
+                    }
+                }
+                Ok(Err(_)) => {
+                    log::debug!("{}: Disconnected by client", stream_id);
+                    break;
+                }
+                Err(_) => {
+                    log::warn!("{}: No data consumed by client for {}, \
+                                disconnect", stream_id,
+                               humantime::format_duration(
+                                   idle_timeout.unwrap_or_default()));
+                    break;
+                }
+            }
         } else {
-            self.filters.push(filter);
+            log::error!("{}: Error, stop streaming", stream_id);
+            break;
         }
-        Ok(())
+
+        // Always yield for sending the chunk to the client quickly.
+        //
+        // The async task never yields voluntarily and can starve other
+        // tasks waiting on the same executor.  For avoiding the
+        // starvation, the task has to yields within a short term.
+        //
+        // Theoretically, one 32 KiB chunk comes every 10 ms.  This
+        // period is a long enough time in the CPU time point of view.
+        // Therefore, the async task simply yields at the end of every
+        // iteration.
+        tokio::task::yield_now().await;
     }
+}
 
-    fn add_post_filters(
-        &mut self,
-        post_filters: &HashMap<String, PostFilterConfig>,
-        names: &Vec<String>
-    ) -> Result<(), Error> {
-        for name in names.iter() {
-            if post_filters.contains_key(name) {
-                self.add_post_filter(&post_filters[name], name)?;
-            } else {
-                log::warn!("No such post-filter: {}", name);
+// Stops relaying chunks when `duration` elapses.
+//
+// The sender is dropped together with the relay, so that the HTTP response
+// ends and the tuner is released when the stop trigger is dropped.
+async fn limit_duration<F>(
+    stream_id: MpegTsStreamId,
+    duration: Option<Duration>,
+    relay: F,
+)
+where
+    F: futures::Future<Output = ()>,
+{
+    match duration {
+        Some(duration) => {
+            if tokio::time::timeout(duration, relay).await.is_err() {
+                log::info!("{}: Reached the duration limit {}, \
+                            stop streaming", stream_id,
+                           humantime::format_duration(duration));
             }
         }
-        Ok(())
+        None => relay.await,
     }
+}
 
-    fn add_post_filter(
-        &mut self,
-        config: &PostFilterConfig,
-        name: &str,
-    ) -> Result<(), Error> {
-        let filter = self.make_filter(&config.command)?;
-        if filter.is_empty() {
-            log::warn!("post-filter({}) not valid", name);
-        } else {
-            self.filters.push(filter);
-            if let Some(content_type) = config.content_type.as_ref() {
-                self.content_type = content_type.clone();
-            }
+async fn do_streaming<S>(
+    stream: S,
+    content_type: String,
+    time_limit: u64,
+    stats_id: Option<u64>,
+) -> ApiResult
+where
+    // actix_web::dev::HttpResponseBuilder::streaming() requires 'static...
+    S: Stream<Item = io::Result<Bytes>> + Unpin + 'static,
+{
+    // No data is sent to the client until the first TS packet comes from the
+    // streaming pipeline.
+    let stream = wait_for_first_chunk(stream, time_limit).await?;
+
+    // Send the response headers and start streaming.
+    let mut builder = actix_web::HttpResponse::Ok();
+    builder
+        .force_close()
+        .set_header("cache-control", "no-store")
+        .set_header("content-type", content_type);
+    if let Some(id) = stats_id {
+        builder.set_header("x-mirakc-session-id", id.to_string());
+    }
+    Ok(builder.streaming(stream))
+}
+
+// Waits for the first chunk from the streaming pipeline for `time_limit`
+// milliseconds.  The chunk is kept in the returned stream.
+async fn wait_for_first_chunk<S>(
+    stream: S,
+    time_limit: u64,
+) -> Result<futures::stream::Peekable<S>, Error>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    let mut peekable = stream.peekable();
+    let fut = Pin::new(&mut peekable).peek();
+    match tokio::time::timeout(Duration::from_millis(time_limit), fut).await {
+        Ok(None) => {
+            // No packets come from the pipeline, maybe the program has been
+            // canceled.
+            Err(Error::ProgramNotFound)
+        }
+        Err(_) => {
+            Err(Error::StreamingTimedOut)
+        }
+        Ok(_) => Ok(peekable),
+    }
+}
+
+// extractors
+
+#[derive(Deserialize)]
+struct ChannelPath {
+    channel_type: ChannelType,
+    channel: String,
+}
+
+#[derive(Deserialize)]
+struct ChannelServicePath {
+    channel_type: ChannelType,
+    channel: String,
+    sid: ServiceId,
+}
+
+#[derive(Deserialize)]
+struct ServicePath {
+    id: MirakurunServiceId,
+}
+
+#[derive(Deserialize)]
+struct ServiceQuery {
+    // Required only when services having the same NID and SID exist.
+    #[serde(default)]
+    tsid: Option<u16>,  // default: None
+}
+
+impl ServiceQuery {
+    fn make_message(&self, id: MirakurunServiceId) -> QueryServiceMessage {
+        match self.tsid {
+            Some(tsid) => QueryServiceMessage::ByTriple {
+                triple: (id.nid(), tsid.into(), id.sid()).into(),
+            },
+            None => QueryServiceMessage::ByNidSid {
+                nid: id.nid(),
+                sid: id.sid(),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NextProgramQuery {
+    #[serde(default)]
+    keyword: Option<String>,
+    #[serde(default)]
+    genre: Option<u8>,
+    #[serde(default)]
+    service_id: Option<MirakurunServiceId>,
+    #[serde(default)]
+    start_after: Option<i64>,  // UNIX time in milliseconds
+    #[serde(default)]
+    start_before: Option<i64>,  // UNIX time in milliseconds
+}
+
+impl NextProgramQuery {
+    fn into_rule(self) -> Result<EpgProgramRule, Error> {
+        Ok(EpgProgramRule {
+            keyword: self.keyword,
+            genre: self.genre,
+            service_id: self.service_id,
+            start_after: self.start_after
+                .map(|ms| jst_from_millis("startAfter", ms)).transpose()?,
+            start_before: self.start_before
+                .map(|ms| jst_from_millis("startBefore", ms)).transpose()?,
+        })
+    }
+}
+
+// chrono panics when a UNIX time is out of range.  Query parameters must be
+// converted with this function instead of `Jst.timestamp_millis()`.
+fn jst_from_millis(name: &str, ms: i64) -> Result<DateTime<Jst>, Error> {
+    Jst.timestamp_millis_opt(ms).single()
+        .ok_or_else(|| Error::BadRequest(
+            format!("The {} query is out of range", name)))
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum TimeFormat {
+    EpochMs,
+    Iso,
+}
+
+impl Default for TimeFormat {
+    fn default() -> Self {
+        TimeFormat::EpochMs
+    }
+}
+
+// Query parameters changing the representation of programs.
+#[derive(Clone, Copy, Deserialize)]
+struct ProgramFormat {
+    #[serde(default)]
+    time_format: TimeFormat,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool_query")]
+    resolve_genres: bool,  // default: false
+}
+
+#[derive(Deserialize)]
+struct OnAirQuery {
+    #[serde(default)]
+    genre: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct ProgramPath {
+    id: MirakurunProgramId,
+}
+
+#[derive(Deserialize)]
+struct GuideQuery {
+    date: chrono::NaiveDate,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_service_ids")]
+    services: Option<Vec<MirakurunServiceId>>,  // default: None
+    #[serde(default)]
+    offset: usize,
+    #[serde(default)]
+    limit: Option<usize>,  // default: MAX_GUIDE_SERVICES
+}
+
+impl GuideQuery {
+    // Dates at the edges of the range of `NaiveDate` are rejected because the
+    // day cannot be represented as a range of `DateTime<Jst>`.
+    fn midnight(&self) -> Result<DateTime<Jst>, Error> {
+        match (self.date.pred_opt(), self.date.succ_opt()) {
+            (Some(_), Some(_)) => Ok(Jst.from_local_date(&self.date)
+                                     .unwrap().and_hms(0, 0, 0)),
+            _ => Err(Error::BadRequest(
+                format!("Date out of range: {}", self.date))),
+        }
+    }
+
+    fn limit(&self) -> Result<usize, Error> {
+        match self.limit {
+            None => Ok(MAX_GUIDE_SERVICES),
+            Some(limit) if limit > 0 && limit <= MAX_GUIDE_SERVICES =>
+                Ok(limit),
+            Some(_) => Err(Error::BadRequest(format!(
+                "The limit must be between 1 and {}", MAX_GUIDE_SERVICES))),
+        }
+    }
+}
+
+// Query parameters supported by Mirakurun's /api/programs.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MirakurunProgramsQuery {
+    #[serde(default)]
+    network_id: Option<NetworkId>,
+    #[serde(default)]
+    service_id: Option<ServiceId>,
+    #[serde(default)]
+    event_id: Option<EventId>,
+}
+
+impl MirakurunProgramsQuery {
+    fn matches(&self, program: &EpgProgram) -> bool {
+        self.network_id.map_or(true, |nid| nid == program.quad.nid()) &&
+            self.service_id.map_or(true, |sid| sid == program.quad.sid()) &&
+            self.event_id.map_or(true, |eid| eid == program.quad.eid())
+    }
+}
+
+#[derive(Deserialize)]
+struct MultiServiceStreamQuery {
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_service_ids")]
+    services: Option<Vec<MirakurunServiceId>>,  // required
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DebugTuneQuery {
+    #[serde(default)]
+    extra_args: String,  // default: empty
+}
+
+#[derive(Deserialize)]
+struct ProgramsQuery {
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_service_ids")]
+    services: Option<Vec<MirakurunServiceId>>,  // default: None
+    #[serde(default)]
+    changed_since: Option<i64>,  // UNIX time in milliseconds
+    #[serde(default)]
+    at: Option<i64>,  // UNIX time in milliseconds
+    // Search conditions.
+    #[serde(default)]
+    q: Option<String>,
+    #[serde(default)]
+    genre: Option<u8>,
+    #[serde(default)]
+    start_after: Option<i64>,  // UNIX time in milliseconds
+    #[serde(default)]
+    start_before: Option<i64>,  // UNIX time in milliseconds
+    #[serde(default)]
+    offset: Option<usize>,  // default: 0
+    #[serde(default)]
+    limit: Option<usize>,  // default: MAX_PROGRAMS_PER_SEARCH
+}
+
+impl ProgramsQuery {
+    fn is_search(&self) -> bool {
+        self.q.is_some() || self.genre.is_some() ||
+            self.start_after.is_some() || self.start_before.is_some() ||
+            self.offset.is_some() || self.limit.is_some()
+    }
+
+    fn search_rule(&self) -> Result<EpgProgramRule, Error> {
+        Ok(EpgProgramRule {
+            keyword: self.q.clone(),
+            genre: self.genre,
+            service_id: None,
+            start_after: self.start_after
+                .map(|ms| jst_from_millis("start_after", ms)).transpose()?,
+            start_before: self.start_before
+                .map(|ms| jst_from_millis("start_before", ms)).transpose()?,
+        })
+    }
+}
+
+fn deserialize_service_ids<'de, D>(
+    deserializer: D
+) -> Result<Option<Vec<MirakurunServiceId>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.split(',')
+        .map(|id| id.trim().parse::<u64>().map(MirakurunServiceId::from))
+        .collect::<Result<Vec<MirakurunServiceId>, _>>()
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom(
+            "The value of the services query must be a comma-separated list \
+             of service IDs"))
+}
+
+#[derive(Deserialize)]
+struct TunerPath {
+    name: String,
+}
+
+// Tuner groups are not supported, and a body containing other properties like
+// `groups` is rejected.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TunerPriorityBody {
+    priority: i32,
+}
+
+#[derive(Deserialize)]
+struct FilterPath {
+    kind: FilterKind,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum FilterKind {
+    PreFilters,
+    PostFilters,
+}
+
+// A query for endpoints which change the config at runtime.
+#[derive(Deserialize)]
+struct UpdateQuery {
+    #[serde(default)]
+    persist: bool,
+}
+
+impl UpdateQuery {
+    // Writing changes back to the config file is not supported.  Rewriting a
+    // YAML file loses comments and formatting in it, and the config file is
+    // often mounted read-only in a container.
+    fn validate(&self) -> Result<(), Error> {
+        if self.persist {
+            return Err(Error::BadRequest(
+                "Persisting changes to the config file is not supported"
+                    .to_string()));
         }
         Ok(())
     }
+}
 
-    fn make_filter(&self, command: &str) -> Result<String, Error> {
-        let template = mustache::compile_str(command)?;
-        Ok(template.render_data_to_string(&self.data)?.trim().to_string())
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+struct NamedFilterBody {
+    command: String,
+    #[serde(default)]
+    content_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JobPath {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct SyncClocksQuery {
+    // A comma-separated list of channels.
+    #[serde(default)]
+    channel: Option<String>,  // default: None
+}
+
+#[derive(Deserialize)]
+struct SessionPath {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct RecordingRulePath {
+    id: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecordingScheduleBody {
+    program_id: MirakurunProgramId,
+    #[serde(default)]
+    max_retries: u32,
+}
+
+// actix-web uses the serde_urlencoded crate for parsing the query in an URL.
+// Unfortunately, the Vec support is out of scope for the serde_urlencoded
+// crate and it's suggested to use the serde_qs crate.
+//
+// * nox/serde_urlencoded/issues/46
+//
+// actix-web tried to replace the serde_urlencoded crate with the serde_qs
+// crate:
+//
+// * actix/actix-web/issues/38
+// * actix/actix-web/issues/1211
+//
+// but the owner decided not to do that finally.  The reason is unclear at least
+// for me.
+//
+// Actually, the serde_qs crate works well with actix-web without any
+// difficulty as you can see in code below.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct FilterSetting {
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool_query")]
+    decode: bool,  // default: false
+    #[serde(default)]
+    pre_filters: Vec<String>,  // default: empty
+    #[serde(default)]
+    post_filters: Vec<String>,  // default: empty
+    #[serde(default)]
+    video_tag: Option<u8>,  // default: the primary component
+    #[serde(default)]
+    audio_tag: Option<u8>,  // default: the primary component
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_bool_query")]
+    failover: bool,  // default: false
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_stream_duration_query")]
+    duration: Option<Duration>,  // default: unlimited
+    #[serde(default)]
+    output: StreamOutput,  // default: ts
+    // Definitions of named filters at the time of the request.
+    #[serde(skip)]
+    pre_filter_defs: HashMap<String, FilterConfig>,
+    #[serde(skip)]
+    post_filter_defs: HashMap<String, PostFilterConfig>,
+}
+
+// A named output mode applied at the end of a filter pipeline.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum StreamOutput {
+    // TS packets as they are.
+    Ts,
+    // Fragmented MP4 which can be fed into Media Source Extensions.
+    Fmp4,
+}
+
+impl Default for StreamOutput {
+    fn default() -> Self {
+        StreamOutput::Ts
     }
 }
 
-// tests
+impl FilterSetting {
+    fn video_component_tag(&self, program: &EpgProgram) -> Option<u8> {
+        self.video_tag
+            .or_else(|| program.video().and_then(|info| info.component_tag))
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::net::SocketAddr;
-    use actix_http;
-    use assert_matches::*;
-    use crate::broadcaster::BroadcasterStream;
+    fn audio_component_tag(&self, program: &EpgProgram) -> Option<u8> {
+        self.audio_tag
+            .or_else(|| program.audio().and_then(|info| info.component_tag))
+    }
 
-    async fn request(req: actix_http::Request) -> actix_web::HttpResponse {
-        let mut app = actix_web::test::init_service(
-            actix_web::App::new()
-                .data(config_for_test())
-                .data(tuner_manager_for_test())
-                .data(epg_for_test())
-                .wrap(AccessControl)
-                .service(create_api_service())).await;
-        actix_web::test::call_service(&mut app, req).await.into()
+    // Default pre-filters are applied before pre-filters in the query.
+    fn pre_filters_with_defaults(
+        &self,
+        config: &Config,
+        channel: &EpgChannel,
+        service: Option<MirakurunServiceId>,
+    ) -> Vec<String> {
+        let mut names = config.default_pre_filters(
+            channel.channel_type, &channel.channel, service);
+        names.extend_from_slice(&self.pre_filters);
+        names
     }
 
-    async fn get(uri: &str) -> actix_web::HttpResponse {
-        let req = actix_web::test::TestRequest::with_uri(uri)
-            .method(actix_web::http::Method::GET)
-            .to_request();
-        request(req).await
+    // Default post-filters are applied after post-filters in the query.
+    fn post_filters_with_defaults(
+        &self,
+        config: &Config,
+        channel: &EpgChannel,
+        service: Option<MirakurunServiceId>,
+    ) -> Vec<String> {
+        let mut names = self.post_filters.clone();
+        names.extend(config.default_post_filters(
+            channel.channel_type, &channel.channel, service));
+        names
+    }
+}
+
+impl FromRequest for FilterSetting {
+    type Error = actix_web::Error;
+    type Future = futures::future::Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _: &mut actix_web::dev::Payload
+    ) -> Self::Future {
+        match serde_qs::from_str::<FilterSetting>(req.query_string()) {
+            Ok(mut query) => {
+                let named_filters =
+                    req.app_data::<actix_web::web::Data<Arc<NamedFilters>>>();
+                if let Some(named_filters) = named_filters {
+                    query.pre_filter_defs = named_filters.pre_filters();
+                    query.post_filter_defs = named_filters.post_filters();
+                }
+                futures::future::ok(query)
+            }
+            Err(err) => futures::future::err(
+                actix_web::error::ErrorBadRequest(format!(
+                    "Failed to parse the query string: {}", err))),
+        }
+    }
+}
+
+// Used for boolean queries like `decode`.
+fn deserialize_bool_query<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    if s == "0" || s == "false" {
+        return Ok(false);
+    }
+    if s == "1" || s == "true" {
+        return Ok(true);
+    }
+    Err(serde::de::Error::custom(
+        "The value of a boolean query must be 0, 1, false or true"))
+}
+
+fn deserialize_stream_duration_query<'de, D>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match humantime::parse_duration(&s) {
+        Ok(duration) if duration > Duration::from_secs(0) => Ok(Some(duration)),
+        _ => Err(serde::de::Error::custom(
+            "The value of the duration query must be a positive duration \
+             like 30m")),
+    }
+}
+
+// Other query parameters are ignored.
+#[derive(Deserialize)]
+struct TimeshiftQuery {
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_stream_timeshift_query")]
+    timeshift: Option<Duration>,  // default: live
+}
+
+fn deserialize_stream_timeshift_query<'de, D>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s)
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom(
+            "The value of the timeshift query must be a duration like 30s"))
+}
+
+impl FromRequest for TunerUser {
+    type Error = actix_web::Error;
+    type Future = futures::future::Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _: &mut actix_web::dev::Payload
+    ) -> Self::Future {
+        let remote = req
+            .connection_info()
+            .remote()
+            .map(|v| v.to_string());
+
+        let agent = req
+            .headers()
+            .get_all(actix_web::http::header::USER_AGENT)
+            .last()
+            .map(|value| {
+                value.to_str().ok().map_or(String::new(), |s| s.to_string())
+            });
+
+        let info = TunerUserInfo::Web { remote, agent };
+
+        let priority = req.headers().get_all("x-mirakurun-priority")
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| value.parse::<i32>().ok())
+            .max()
+            .map(|value| value.max(0))
+            .map(TunerUserPriority::from)
+            .unwrap_or_default();
+
+        // `block` is not allowed because a slow client would stall other
+        // clients sharing the tuner.
+        let drop_policy = req.headers().get_all("x-mirakc-drop-policy")
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| match value.trim() {
+                "drop-chunk" => Some(DropPolicy::DropChunk),
+                "close-stream" => Some(DropPolicy::CloseStream),
+                _ => None,
+            })
+            .last()
+            .unwrap_or_default();
+
+        let timeshift =
+            match serde_qs::from_str::<TimeshiftQuery>(req.query_string()) {
+                Ok(query) => query.timeshift,
+                Err(err) => return futures::future::err(
+                    actix_web::error::ErrorBadRequest(format!(
+                        "Failed to parse the query string: {}", err))),
+            };
+
+        futures::future::ok(TunerUser {
+            info,
+            priority,
+            expected_end_at: None,
+            drop_policy,
+            timeshift,
+        })
+    }
+}
+
+// middleware
+
+struct AccessControl;
+
+impl<S, B> actix_service::Transform<S> for AccessControl
+where
+    S: actix_service::Service<Request = actix_web::dev::ServiceRequest,
+                              Response = actix_web::dev::ServiceResponse<B>,
+                              Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = actix_web::dev::ServiceRequest;
+    type Response = actix_web::dev::ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = AccessControlMiddleware<S>;
+    type Future =
+        futures::future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        futures::future::ok(AccessControlMiddleware(service))
+    }
+}
+
+struct AccessControlMiddleware<S>(S);
+
+impl<S, B> actix_service::Service for AccessControlMiddleware<S>
+where
+    S: actix_service::Service<Request = actix_web::dev::ServiceRequest,
+                              Response = actix_web::dev::ServiceResponse<B>,
+                              Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = actix_web::dev::ServiceRequest;
+    type Response = actix_web::dev::ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn futures::future::Future<
+            Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: actix_web::dev::ServiceRequest) -> Self::Future {
+        // Take no account of HTTP Forwarded and X-Forwarded-For headers.
+        let ip = req.peer_addr().map(|socket| socket.ip());
+
+        let allowed = match ip {
+            Some(ip) => is_private_ip_addr(ip),
+            None => true,  // UNIX domain socket
+        };
+
+        if allowed {
+            Box::pin(self.0.call(req))
+        } else {
+            Box::pin(futures::future::ok(req.error_response(
+                actix_web::error::ErrorForbidden(Error::AccessDenied))))
+        }
+    }
+}
+
+// Writes access logs into a file specified with `server.access-log`.  Does
+// nothing if it's not specified.
+//
+// An access log is written when the response starts.  So, the elapsed time of
+// a streaming response doesn't include the streaming time.  The file is written
+// in a dedicated thread so that file I/O never blocks workers.
+struct AccessLog(Option<AccessLogWriter>);
+
+impl<S, B> actix_service::Transform<S> for AccessLog
+where
+    S: actix_service::Service<Request = actix_web::dev::ServiceRequest,
+                              Response = actix_web::dev::ServiceResponse<B>,
+                              Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = actix_web::dev::ServiceRequest;
+    type Response = actix_web::dev::ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = AccessLogMiddleware<S>;
+    type Future =
+        futures::future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        futures::future::ok(AccessLogMiddleware(service, self.0.clone()))
+    }
+}
+
+struct AccessLogMiddleware<S>(S, Option<AccessLogWriter>);
+
+impl<S, B> actix_service::Service for AccessLogMiddleware<S>
+where
+    S: actix_service::Service<Request = actix_web::dev::ServiceRequest,
+                              Response = actix_web::dev::ServiceResponse<B>,
+                              Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = actix_web::dev::ServiceRequest;
+    type Response = actix_web::dev::ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn futures::future::Future<
+            Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: actix_web::dev::ServiceRequest) -> Self::Future {
+        let writer = match self.1 {
+            Some(ref writer) => writer.clone(),
+            None => return Box::pin(self.0.call(req)),
+        };
+
+        let started = Instant::now();
+        let remote = req.connection_info().remote()
+            .unwrap_or("-").to_string();
+        let request_line = format!(
+            "{} {} {:?}", req.method(), req.uri(), req.version());
+        let header = |name| {
+            req.headers().get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("-")
+                .to_string()
+        };
+        let referer = header(actix_web::http::header::REFERER);
+        let user_agent = header(actix_web::http::header::USER_AGENT);
+
+        let fut = self.0.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            // Similar to the default format of actix_web::middleware::Logger,
+            // but a timestamp is added and the response size is omitted.
+            let line = format!(
+                "{} [{}] \"{}\" {} \"{}\" \"{}\" {:.6}",
+                remote, Jst::now().format("%d/%b/%Y:%H:%M:%S %z"),
+                request_line, res.status().as_u16(), referer, user_agent,
+                started.elapsed().as_secs_f64());
+            writer.write_line(line);
+            Ok(res)
+        })
+    }
+}
+
+fn is_private_ip_addr(ip: IpAddr) -> bool {
+    // TODO: IpAddr::is_global() is a nightly-only API at this point.
+    match ip {
+        IpAddr::V4(ip) => is_private_ipv4_addr(ip),
+        IpAddr::V6(ip) => is_private_ipv6_addr(ip),
+    }
+}
+
+fn is_private_ipv4_addr(ip: Ipv4Addr) -> bool {
+    ip.is_loopback() || ip.is_private() || ip.is_link_local()
+}
+
+fn is_private_ipv6_addr(ip: Ipv6Addr) -> bool {
+    // TODO: Support only IPv4-compatible and IPv4-mapped addresses at this
+    //       moment.
+    match ip.to_ipv4() {
+        Some(ip) => is_private_ipv4_addr(ip),
+        None => false,
+    }
+}
+
+// Administrative endpoints are allowed only for requests from the local host.
+// Like `AccessControlMiddleware`, HTTP Forwarded and X-Forwarded-For headers
+// are not taken into account.
+fn is_admin_request(req: &actix_web::HttpRequest) -> bool {
+    match req.peer_addr().map(|socket| socket.ip()) {
+        Some(IpAddr::V4(ip)) => ip.is_loopback(),
+        Some(IpAddr::V6(ip)) => ip.is_loopback() ||
+            ip.to_ipv4().map_or(false, |ip| ip.is_loopback()),
+        None => true,  // UNIX domain socket
+    }
+}
+
+// filters
+
+// Commands of filters in a pipeline.  Each filter may have its own process
+// limits which are used instead of `filters.process-limits`.
+type FilterCommands = Vec<(String, Option<ProcessLimitsConfig>)>;
+
+struct FilterPipelineBuilder {
+    data: mustache::Data,
+    filters: FilterCommands,
+    content_type: String,
+}
+
+impl FilterPipelineBuilder {
+    fn new(data: mustache::Data) -> Self {
+        FilterPipelineBuilder {
+            data,
+            filters: Vec::new(),
+            content_type: "video/MP2T".to_string(),
+        }
+    }
+
+    // Filters are checked before a tuner is activated so that a request with
+    // too many filters never consumes a tuner.
+    fn build(
+        self,
+        max_stages: usize,
+    ) -> Result<(FilterCommands, String), Error> {
+        if self.filters.len() > max_stages {
+            return Err(Error::TooManyFilters(self.filters.len(), max_stages));
+        }
+        Ok((self.filters, self.content_type))
+    }
+
+    fn add_pre_filters(
+        &mut self,
+        pre_filters: &HashMap<String, FilterConfig>,
+        names: &Vec<String>
+    ) -> Result<(), Error> {
+        for name in names.iter() {
+            if pre_filters.contains_key(name) {
+                self.add_pre_filter(&pre_filters[name], name)?;
+            } else {
+                log::warn!("No such pre-filter: {}", name);
+            }
+        }
+        Ok(())
+    }
+
+    fn add_pre_filter(
+        &mut self,
+        config: &FilterConfig,
+        name: &str,
+    ) -> Result<(), Error> {
+        let filter = self.make_filter(&config.command)?;
+        if filter.is_empty() {
+            log::warn!("pre-filter({}) not valid", name);
+        } else {
+            self.filters.push((filter, config.process_limits.clone()));
+        }
+        Ok(())
+    }
+
+    // A service-specific decode filter is applied just after pre-filters
+    // instead of the decode-filter.
+    fn add_service_decode_filter(
+        &mut self,
+        config: &ServiceDecodeFilterConfig,
+    ) -> Result<(), Error> {
+        let filter = self.make_filter(&config.command)?;
+        if filter.is_empty() {
+            log::warn!("service-decode-filter not valid");
+        } else {
+            self.filters.push((filter, None));
+        }
+        Ok(())
+    }
+
+    fn add_service_filter(
+        &mut self,
+        config: &FilterConfig,
+    ) -> Result<(), Error> {
+        let filter = self.make_filter(&config.command)?;
+        if filter.is_empty() {
+            log::warn!("service-filter not valid");
+        } else {
+            self.filters.push((filter, config.process_limits.clone()));
+        }
+        Ok(())
+    }
+
+    fn add_multi_service_filter(
+        &mut self,
+        config: &FilterConfig,
+    ) -> Result<(), Error> {
+        let filter = self.make_filter(&config.command)?;
+        if filter.is_empty() {
+            log::warn!("multi-service-filter not valid");
+        } else {
+            self.filters.push((filter, config.process_limits.clone()));
+        }
+        Ok(())
+    }
+
+    fn add_decode_filter(
+        &mut self,
+        config: &FilterConfig
+    ) -> Result<(), Error> {
+        let filter = self.make_filter(&config.command)?;
+        if filter.is_empty() {
+            log::warn!("decode-filter not valid");
+        } else {
+            self.filters.push((filter, config.process_limits.clone()));
+        }
+        Ok(())
+    }
+
+    fn add_program_filter(
+        &mut self,
+        config: &FilterConfig,
+    ) -> Result<(), Error> {
+        let filter = self.make_filter(&config.command)?;
+        if filter.is_empty() {
+            log::warn!("program-filter not valid");
+        } else {
+            self.filters.push((filter, config.process_limits.clone()));
+        }
+        Ok(())
+    }
+
+    fn add_post_filters(
+        &mut self,
+        post_filters: &HashMap<String, PostFilterConfig>,
+        names: &Vec<String>
+    ) -> Result<(), Error> {
+        for name in names.iter() {
+            if post_filters.contains_key(name) {
+                self.add_post_filter(&post_filters[name], name)?;
+            } else {
+                log::warn!("No such post-filter: {}", name);
+            }
+        }
+        Ok(())
+    }
+
+    fn add_post_filter(
+        &mut self,
+        config: &PostFilterConfig,
+        name: &str,
+    ) -> Result<(), Error> {
+        let filter = self.make_filter(&config.command)?;
+        if filter.is_empty() {
+            log::warn!("post-filter({}) not valid", name);
+        } else {
+            self.filters.push((filter, config.process_limits.clone()));
+            if let Some(content_type) = config.content_type.as_ref() {
+                self.content_type = content_type.clone();
+            }
+        }
+        Ok(())
+    }
+
+    // The fmp4-filter is applied after post-filters.  So, post-filters must
+    // output TS packets when they are used together.
+    fn add_fmp4_filter(
+        &mut self,
+        config: &FilterConfig,
+    ) -> Result<(), Error> {
+        let filter = self.make_filter(&config.command)?;
+        if filter.is_empty() {
+            log::warn!("fmp4-filter not valid");
+        } else {
+            self.filters.push((filter, config.process_limits.clone()));
+            self.content_type = "video/mp4".to_string();
+        }
+        Ok(())
+    }
+
+    fn make_filter(&self, command: &str) -> Result<String, Error> {
+        let template = mustache::compile_str(command)?;
+        Ok(template.render_data_to_string(&self.data)?.trim().to_string())
+    }
+}
+
+// tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use actix_http;
+    use assert_matches::*;
+    use crate::broadcaster::BroadcasterStream;
+    use crate::config::{
+        ChannelConfig, ServiceDefaultFiltersConfig, ThumbnailsConfig};
+
+    async fn request(req: actix_http::Request) -> actix_web::HttpResponse {
+        request_with(req, config_for_test(), epg_for_test()).await
+    }
+
+    async fn request_with(
+        req: actix_http::Request,
+        config: Arc<Config>,
+        epg: Addr<EpgActor>,
+    ) -> actix_web::HttpResponse {
+        let mut app = actix_web::test::init_service(
+            actix_web::App::new()
+                .data(config.clone())
+                .data(tuner_manager_for_test())
+                .data(epg)
+                .data(recording_manager_for_test())
+                .data(job_manager_for_test())
+                .data(Arc::new(NamedFilters::new(config)))
+                .data(Arc::new(ProgramEnricher::new(None)))
+                .data(Arc::new(RwLock::new(VersionInfo::new(vec![]))))
+                .wrap(AccessControl)
+                .service(create_api_service())
+                .service(get_metrics)).await;
+        actix_web::test::call_service(&mut app, req).await.into()
+    }
+
+    async fn get(uri: &str) -> actix_web::HttpResponse {
+        let req = actix_web::test::TestRequest::with_uri(uri)
+            .method(actix_web::http::Method::GET)
+            .to_request();
+        request(req).await
+    }
+
+    async fn head(uri: &str) -> actix_web::HttpResponse {
+        let req = actix_web::test::TestRequest::with_uri(uri)
+            .method(actix_web::http::Method::HEAD)
+            .to_request();
+        request(req).await
+    }
+
+    async fn post(uri: &str) -> actix_web::HttpResponse {
+        let req = actix_web::test::TestRequest::with_uri(uri)
+            .method(actix_web::http::Method::POST)
+            .to_request();
+        request(req).await
+    }
+
+    async fn post_json(
+        uri: &str,
+        json: serde_json::Value,
+    ) -> actix_web::HttpResponse {
+        let req = actix_web::test::TestRequest::with_uri(uri)
+            .method(actix_web::http::Method::POST)
+            .set_json(&json)
+            .to_request();
+        request(req).await
+    }
+
+    async fn put_json(
+        uri: &str,
+        json: serde_json::Value,
+    ) -> actix_web::HttpResponse {
+        let req = actix_web::test::TestRequest::with_uri(uri)
+            .method(actix_web::http::Method::PUT)
+            .set_json(&json)
+            .to_request();
+        request(req).await
+    }
+
+    async fn delete(uri: &str) -> actix_web::HttpResponse {
+        let req = actix_web::test::TestRequest::with_uri(uri)
+            .method(actix_web::http::Method::DELETE)
+            .to_request();
+        request(req).await
+    }
+
+    async fn get_ws(uri: &str) -> actix_web::HttpResponse {
+        let req = actix_web::test::TestRequest::with_uri(uri)
+            .method(actix_web::http::Method::GET)
+            .header("upgrade", "websocket")
+            .header("connection", "upgrade")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .to_request();
+        request(req).await
+    }
+
+    async fn get_with_peer_addr(
+        uri: &str,
+        addr: SocketAddr
+    ) -> actix_web::HttpResponse {
+        let req = actix_web::test::TestRequest::with_uri(uri)
+            .method(actix_web::http::Method::GET)
+            .peer_addr(addr)
+            .to_request();
+        request(req).await
+    }
+
+    #[actix_rt::test]
+    async fn test_get_unknown() {
+        let res = get("/api/unknown").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_version() {
+        let res = get("/api/version").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_version_details() {
+        let res = get("/api/version/details").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_version_info_collect() {
+        let info = Arc::new(RwLock::new(VersionInfo::new(vec![])));
+        VersionInfo::collect(info.clone(), vec![
+            "false".to_string(),
+            "/no/such/command".to_string(),
+        ]).await;
+        let value = serde_json::to_value(&*info.read().unwrap()).unwrap();
+        assert_eq!(value["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(value["commands"], serde_json::json!([
+            { "name": "false", "version": null },
+            { "name": "/no/such/command", "version": null },
+        ]));
+    }
+
+    #[test]
+    fn test_version_info_command_names() {
+        let mut config = Config::default();
+        config.jobs.scan_services.command = "scan {{#sids}}".to_string();
+        config.jobs.sync_clocks.command = "  clock --sids".to_string();
+        config.jobs.update_schedules.command = "scan eits".to_string();
+        assert_eq!(VersionInfo::command_names(&config), vec!["clock", "scan"]);
+
+        config.jobs.update_recent_schedules = Some(crate::config::JobConfig {
+            command: "recent".to_string(),
+            ..config.jobs.update_schedules.clone()
+        });
+        assert_eq!(VersionInfo::command_names(&config),
+                   vec!["clock", "recent", "scan"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_version_info_query_version() {
+        assert_eq!(VersionInfo::query_version("false").await, None);
+        assert_eq!(VersionInfo::query_version("/no/such/command").await, None);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_status() {
+        let res = get("/api/status").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_capabilities() {
+        let res = get("/api/capabilities").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let mut config = Config::default();
+        config.pre_filters.insert("b".to_string(), FilterConfig {
+            command: "b".to_string(),
+            process_limits: None,
+        });
+        config.pre_filters.insert("a".to_string(), FilterConfig {
+            command: "a".to_string(),
+            process_limits: None,
+        });
+        config.post_filters.insert("ts".to_string(), PostFilterConfig {
+            command: "ts".to_string(),
+            content_type: None,
+            process_limits: None,
+        });
+        config.post_filters.insert("mp4".to_string(), PostFilterConfig {
+            command: "mp4".to_string(),
+            content_type: Some("video/mp4".to_string()),
+            process_limits: None,
+        });
+
+        let caps = Capabilities::new(&NamedFilters::new(Arc::new(config)));
+        assert_eq!(caps.channel_types.len(), 4);
+        assert_eq!(caps.pre_filters.iter()
+                   .map(|filter| filter.name.as_str())
+                   .collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(caps.post_filters.iter()
+                   .map(|filter| (filter.name.as_str(),
+                                  filter.content_type.as_str()))
+                   .collect::<Vec<_>>(),
+                   vec![("mp4", "video/mp4"), ("ts", "video/MP2T")]);
+        assert_eq!(caps.tuner_sources, vec!["command"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_time() {
+        let res = get("/api/time").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let time = TimeInfo::new(1_000, vec![ClockOffset {
+            nid: 1.into(),
+            synced_at: 500,
+            offset: -200,
+        }]);
+        assert_eq!(serde_json::to_value(&time).unwrap(), serde_json::json!({
+            "systemTime": 1_000,
+            "networks": [{
+                "networkId": 1,
+                "broadcastTime": 800,
+                "offset": -200,
+                "syncedAt": 500,
+            }],
+        }));
+    }
+
+    #[actix_rt::test]
+    async fn test_get_channels() {
+        let res = get("/api/channels").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_networks() {
+        let res = get("/api/networks").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_services() {
+        let res = get("/api/services").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_service() {
+        let res = get("/api/services/1").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/services/0").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = get("/api/services/1?tsid=1").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/services/1?tsid=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        // Ambiguous without the TSID.
+        let res = get("/api/services/4").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/services/4?tsid=1").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_programs() {
+        let res = get("/api/programs").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("x-mirakc-epg-ready").unwrap(), "true");
+    }
+
+    #[actix_rt::test]
+    async fn test_get_programs_epg_not_ready() {
+        let req = actix_web::test::TestRequest::with_uri("/api/programs")
+            .to_request();
+        let res = request_with(
+            req, config_for_test(), epg_for_test_with_ready(false)).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("x-mirakc-epg-ready").unwrap(), "false");
+
+        let mut config = (*config_for_test()).clone();
+        config.epg.unavailable_until_ready = true;
+        let req = actix_web::test::TestRequest::with_uri("/api/programs")
+            .to_request();
+        let res = request_with(
+            req, Arc::new(config.clone()), epg_for_test_with_ready(false))
+            .await;
+        assert!(res.status() ==
+                actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+
+        // The EPG is ready.
+        let req = actix_web::test::TestRequest::with_uri("/api/programs")
+            .to_request();
+        let res = request_with(
+            req, Arc::new(config), epg_for_test_with_ready(true)).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("x-mirakc-epg-ready").unwrap(), "true");
+    }
+
+    #[actix_rt::test]
+    async fn test_get_changed_programs() {
+        let res = get("/api/programs?changed_since=1577836800000").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/programs?changed_since=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/programs?changed_since=9223372036854775807").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        // Older than the retention window.
+        let res = get("/api/programs?changed_since=0").await;
+        assert!(res.status() == actix_web::http::StatusCode::GONE);
+
+        let res = get("/api/programs?changed_since=0&services=1").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/programs?changed_since=0&at=0").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_programs_at() {
+        let res = get("/api/programs?at=1577880000000").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/programs?at=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/programs?at=-9223372036854775808").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/programs?at=0&services=1").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_search_programs() {
+        let res = get("/api/programs?q=news&genre=1").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/programs?start_after=1577836800000\
+                       &start_before=1577923200000&offset=10&limit=10").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/programs?genre=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/programs?start_after=9223372036854775807").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/programs?start_before=-9223372036854775808").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get(&format!("/api/programs?q=news&limit={}",
+                               MAX_PROGRAMS_PER_SEARCH + 1)).await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/programs?q=news&services=1").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/programs?q=news&changed_since=0").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/programs?q=news&at=0").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_programs_by_services() {
+        let res = get("/api/programs?services=1,2").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/programs?services=1,x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let ids: Vec<String> = (1..=(MAX_SERVICES_PER_REQUEST + 1))
+            .map(|id| id.to_string())
+            .collect();
+        let res = get(&format!("/api/programs?services={}", ids.join(",")))
+            .await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_program() {
+        let res = get("/api/programs/1").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/programs/0").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_guide() {
+        let res = get("/api/guide?date=2020-01-01").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/guide?date=2020-01-01&services=1,2").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/guide").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/guide?date=20200101").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/guide?date=2020-01-01&offset=128&limit=128").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/guide?date=2020-01-01&limit=0").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/guide?date=2020-01-01&limit=129").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_guide_query_midnight() {
+        let query = GuideQuery {
+            date: chrono::NaiveDate::from_ymd(2020, 1, 1),
+            services: None,
+            offset: 0,
+            limit: None,
+        };
+        assert_eq!(query.midnight().unwrap(),
+                   Jst.ymd(2020, 1, 1).and_hms(0, 0, 0));
+
+        let query = GuideQuery { date: chrono::naive::MAX_DATE, ..query };
+        assert_matches!(query.midnight(), Err(Error::BadRequest(_)));
+
+        let query = GuideQuery { date: chrono::naive::MIN_DATE, ..query };
+        assert_matches!(query.midnight(), Err(Error::BadRequest(_)));
+    }
+
+    #[actix_rt::test]
+    async fn test_get_mirakurun_programs() {
+        let res = get("/api/mirakurun/programs").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/mirakurun/programs?networkId=1&serviceId=2").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/mirakurun/programs?eventId=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_mirakurun_program() {
+        let res = get("/api/mirakurun/programs/1").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/mirakurun/programs/0").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_next_program() {
+        let res = get("/api/programs/next?keyword=news&genre=0").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/programs/next?keyword=none").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = get("/api/programs/next?startAfter=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/programs/next?startAfter=9223372036854775807")
+            .await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/programs/next?startBefore=-9223372036854775808")
+            .await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_onair_programs() {
+        let res = get("/api/onair").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/onair?genre=1").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/onair?genre=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_programs_time_format() {
+        for time_format in &["epoch_ms", "iso"] {
+            let res = get(format!("/api/programs?time_format={}",
+                                  time_format).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::OK);
+
+            let res = get(format!("/api/programs/1?time_format={}",
+                                  time_format).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::OK);
+
+            let res = get(format!("/api/onair?time_format={}",
+                                  time_format).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::OK);
+        }
+
+        let res = get("/api/programs?time_format=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_programs_resolve_genres() {
+        for resolve_genres in &["0", "1", "false", "true"] {
+            let res = get(format!("/api/programs?resolve_genres={}",
+                                  resolve_genres).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::OK);
+
+            let res = get(format!("/api/programs/1?resolve_genres={}",
+                                  resolve_genres).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::OK);
+        }
+
+        let res = get("/api/programs?resolve_genres=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_formatted_program() {
+        let mut program = EpgProgram::new((0, 0, 0, 1).into());
+        program.start_at = Jst.ymd(2020, 1, 1).and_hms(0, 0, 0);
+        program.duration = chrono::Duration::minutes(30);
+        let program = MirakurunProgram::from(program);
+
+        let format = ProgramFormat {
+            time_format: TimeFormat::EpochMs,
+            resolve_genres: false,
+        };
+        let value = serde_json::to_value(
+            FormattedProgram::new(program.clone(), format)).unwrap();
+        assert_eq!(value["startAt"], 1577804400000i64);
+        assert_eq!(value["duration"], 1800000);
+
+        let format = ProgramFormat {
+            time_format: TimeFormat::Iso,
+            resolve_genres: false,
+        };
+        let value = serde_json::to_value(
+            FormattedProgram::new(program, format)).unwrap();
+        assert_eq!(value["startAt"], "2020-01-01T00:00:00+09:00");
+        assert_eq!(value["duration"], "PT1800S");
+        assert_eq!(value["eventId"], 1);
+    }
+
+    #[test]
+    fn test_formatted_program_resolve_genres() {
+        let mut program = EpgProgram::new((0, 0, 0, 1).into());
+        program.genres = Some(vec![
+            EpgGenre::new((0x1, 0x1, 0, 0)),
+            EpgGenre::new((0xC, 0x0, 0, 0)),
+        ]);
+        let program = MirakurunProgram::from(program);
+
+        let format = ProgramFormat {
+            time_format: TimeFormat::EpochMs,
+            resolve_genres: true,
+        };
+        let value = serde_json::to_value(
+            FormattedProgram::new(program, format)).unwrap();
+        assert_eq!(value["genres"][0]["lv1"], 0x1);
+        assert_eq!(value["genres"][0]["lv2"], 0x1);
+        assert_eq!(value["genres"][0]["lv1Name"], "スポーツ");
+        assert_eq!(value["genres"][0]["lv2Name"], "野球");
+        assert!(value["genres"][1]["lv1Name"].is_null());
+        assert!(value["genres"][1]["lv2Name"].is_null());
+        assert_eq!(value["eventId"], 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_genres() {
+        let res = get("/api/genres").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_program_thumbnail() {
+        // epg.thumbnails is not specified.
+        let res = get("/api/programs/100001/thumbnail").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = get("/api/programs/0/thumbnail").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let dir = std::env::temp_dir().join(format!(
+            "mirakc-web-thumbnail-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("100001.jpg"), b"jpeg").unwrap();
+
+        let mut config = (*config_for_test()).clone();
+        config.epg.thumbnails = Some(ThumbnailsConfig {
+            dir: dir.to_str().unwrap().to_string(),
+            command: "".to_string(),
+        });
+        let config = Arc::new(config);
+
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/programs/100001/thumbnail").to_request();
+        let res = request_with(req, config.clone(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "image/jpeg");
+
+        // No thumbnail for the program.
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/programs/100002/thumbnail").to_request();
+        let res = request_with(req, config.clone(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_get_tuners() {
+        let res = get("/api/tuners").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_set_tuner_priority() {
+        let res = put_json("/api/tuners/tuner/priority",
+                           serde_json::json!({ "priority": 1 })).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = put_json("/api/tuners/unknown/priority",
+                           serde_json::json!({ "priority": 1 })).await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = put_json("/api/tuners/tuner/priority?persist=true",
+                           serde_json::json!({ "priority": 1 })).await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = put_json("/api/tuners/tuner/priority",
+                           serde_json::json!({
+                               "priority": 1,
+                               "groups": ["night"],
+                           })).await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_filters() {
+        let res = get("/api/filters").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get_with_peer_addr(
+            "/api/filters", "127.0.0.1:10000".parse().unwrap()).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+
+        // Not allowed for requests from other hosts.
+        let res = get_with_peer_addr(
+            "/api/filters", "192.168.0.1:10000".parse().unwrap()).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_put_filter() {
+        // Not allowed unless filters.runtime-update is enabled.
+        let res = put_json("/api/filters/pre-filters/a",
+                           serde_json::json!({ "command": "a" })).await;
+        assert!(res.status() == actix_web::http::StatusCode::FORBIDDEN);
+
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/filters/pre-filters/a")
+            .method(actix_web::http::Method::PUT)
+            .set_json(&serde_json::json!({ "command": "a" }))
+            .to_request();
+        let mut res = request_with(
+            req, filters_config_for_test(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(response_json(&mut res), serde_json::json!({
+            "name": "a",
+            "command": "a",
+        }));
+
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/filters/post-filters/a")
+            .method(actix_web::http::Method::PUT)
+            .set_json(&serde_json::json!({
+                "command": "a",
+                "contentType": "video/x-a",
+            }))
+            .to_request();
+        let res = request_with(
+            req, filters_config_for_test(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        // contentType is allowed only for post-filters.
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/filters/pre-filters/a")
+            .method(actix_web::http::Method::PUT)
+            .set_json(&serde_json::json!({
+                "command": "a",
+                "contentType": "video/x-a",
+            }))
+            .to_request();
+        let res = request_with(
+            req, filters_config_for_test(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/filters/pre-filters/a?persist=true")
+            .method(actix_web::http::Method::PUT)
+            .set_json(&serde_json::json!({ "command": "a" }))
+            .to_request();
+        let res = request_with(
+            req, filters_config_for_test(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        // Not allowed for requests from other hosts.
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/filters/pre-filters/a")
+            .method(actix_web::http::Method::PUT)
+            .peer_addr("192.168.0.1:10000".parse().unwrap())
+            .set_json(&serde_json::json!({ "command": "a" }))
+            .to_request();
+        let res = request_with(
+            req, filters_config_for_test(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_delete_filter() {
+        // Not allowed unless filters.runtime-update is enabled.
+        let res = delete("/api/filters/pre-filters/a").await;
+        assert!(res.status() == actix_web::http::StatusCode::FORBIDDEN);
+
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/filters/post-filters/default")
+            .method(actix_web::http::Method::DELETE)
+            .to_request();
+        let res = request_with(
+            req, filters_config_for_test(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::NO_CONTENT);
+
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/filters/pre-filters/unknown")
+            .method(actix_web::http::Method::DELETE)
+            .to_request();
+        let res = request_with(
+            req, filters_config_for_test(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_channel_stream() {
+        let res = get("/api/channels/GR/ch/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/channels/GR/0/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = get("/api/channels/WOWOW/ch/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = get("/api/channels/GR/xx/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = get("/api/channels/GR/busy/stream").await;
+        assert!(res.status() ==
+                actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+
+        let res = get("/api/channels/GR/ch/stream?timeshift=10s").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/channels/GR/ch/stream?timeshift=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let decode_values = [0, 1];
+
+        let valid_pairs =
+            ["GR", "BS", "CS", "SKY"].iter().zip(&decode_values);
+        for (channel_type, decode) in valid_pairs {
+            let res = get(format!("/api/channels/{}/ch/stream?decode={}",
+                                  channel_type, decode).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::OK);
+        }
+
+        for decode in &decode_values {
+            let res = get(format!("/api/channels/WOWOW/ch/stream?decode={}",
+                                  decode).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_get_channel_service_stream() {
+        let res = get("/api/channels/GR/ch/services/1/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/channels/GR/0/services/1/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = get("/api/channels/WOWOW/ch/services/1/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let decode_values = [0, 1];
+
+        let valid_pairs =
+            ["GR", "BS", "CS", "SKY"].iter().zip(&decode_values);
+        for (channel_type, decode) in valid_pairs {
+            let res = get(format!(
+                "/api/channels/{}/ch/services/1/stream?decode={}",
+                channel_type, decode).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::OK);
+        }
+
+        for decode in &decode_values {
+            let res = get(format!(
+                "/api/channels/WOWOW/ch/services/1/stream?decode={}",
+                decode).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+        }
+
+        for decode in &decode_values {
+            let res = get(format!(
+                "/api/channels/WOWOW/ch/services/2/stream?decode={}",
+                decode).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_get_service_stream() {
+        let res = get("/api/services/1/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/services/0/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let decode_values = [0, 1];
+
+        for decode in &decode_values {
+            let res = get(format!("/api/services/1/stream?decode={}",
+                                  decode).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::OK);
+        }
+
+        for decode in &decode_values {
+            let res = get(format!("/api/services/0/stream?decode={}",
+                                  decode).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+        }
+
+        for decode in &decode_values {
+            let res = get(format!("/api/services/2/stream?decode={}",
+                                  decode).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+        }
+        for failover in &["0", "1", "false", "true"] {
+            let res = get(format!("/api/services/1/stream?failover={}",
+                                  failover).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::OK);
+        }
+
+        let res = get("/api/services/1/stream?duration=30m").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/services/1/stream?duration=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_ws_stream() {
+        let res = get_ws("/api/ws/channels/GR/ch/stream").await;
+        assert!(res.status() ==
+                actix_web::http::StatusCode::SWITCHING_PROTOCOLS);
+
+        let res = get_ws("/api/ws/services/1/stream").await;
+        assert!(res.status() ==
+                actix_web::http::StatusCode::SWITCHING_PROTOCOLS);
+
+        let res = get_ws("/api/ws/services/0/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        // Not a WebSocket upgrade request.
+        let res = get("/api/ws/channels/GR/ch/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/ws/services/1/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_multi_service_stream() {
+        let res = get("/api/services/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/services/stream?services=x").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        // Different channels.
+        let res = get("/api/services/stream?services=1,2").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = get("/api/services/stream?services=1,0").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        // The multi-service-filter is not configured.
+        let res = get("/api/services/stream?services=1").await;
+        assert!(res.status() == actix_web::http::StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_program_stream() {
+        let res = get("/api/programs/100001/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/programs/0/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let decode_values = [0, 1];
+
+        for decode in &decode_values {
+            let res = get(format!("/api/programs/100001/stream?decode={}",
+                                  decode).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::OK);
+        }
+
+        for decode in &decode_values {
+            let res = get(format!("/api/programs/0/stream?decode={}",
+                                  decode).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+        }
+
+        for decode in &decode_values {
+            let res = get(format!("/api/programs/200001/stream?decode={}",
+                                  decode).as_str()).await;
+            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_head_stream() {
+        let res = head("/api/channels/GR/ch/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "video/MP2T");
+
+        let res = head("/api/channels/GR/0/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = head("/api/channels/GR/xx/stream").await;
+        assert!(res.status() ==
+                actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+
+        let res = head("/api/channels/GR/ch/services/1/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = head("/api/services/1/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = head("/api/services/0/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = head("/api/services/2/stream").await;
+        assert!(res.status() ==
+                actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+
+        let res = head("/api/services/stream?services=1,0").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = head("/api/services/stream?services=1").await;
+        assert!(res.status() == actix_web::http::StatusCode::FORBIDDEN);
+
+        let res = head("/api/programs/100001/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = head("/api/programs/0/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_stream_fallback() {
+        // The channel "busy-fallback" falls back to the channel "ch".
+        let res = get("/api/channels/GR/busy-fallback/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/channels/GR/busy-fallback/services/3/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/services/3/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/programs/300001/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = head("/api/channels/GR/busy-fallback/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = head("/api/services/3/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = head("/api/programs/300001/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        // No fallback is configured for the channel "busy".
+        let res = get("/api/channels/GR/busy/stream").await;
+        assert!(res.status() ==
+                actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+
+        let res = head("/api/channels/GR/busy/stream").await;
+        assert!(res.status() ==
+                actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_rt::test]
+    async fn test_head_stream_fmp4_output() {
+        let res = head("/api/channels/GR/ch/stream?output=fmp4").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "video/mp4");
+
+        let res = head("/api/services/1/stream?output=fmp4").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "video/mp4");
+
+        let res = head("/api/programs/100001/stream?output=fmp4").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "video/mp4");
+
+        let res = head("/api/services/1/stream?output=ts").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "video/MP2T");
+
+        let res = head("/api/services/1/stream?output=webm").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_default_filters() {
+        // NID=1, SID=1
+        let res = head("/api/services/100001/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(),
+                   "video/x-default");
+
+        // The NID is taken from the service found in the channel.
+        let res = head("/api/channels/GR/ch/services/1/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(),
+                   "video/x-default");
+
+        // NID=1, SID=1, EID=1
+        let res = head("/api/programs/10000100001/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(),
+                   "video/x-default");
+
+        // The same SID in another network.
+        let res = head("/api/services/1/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "video/MP2T");
+
+        let res = head("/api/programs/100001/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "video/MP2T");
+
+        // Not applied to the channel stream.
+        let res = head("/api/channels/GR/ch/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "video/MP2T");
+
+        // Not applied to recorded files.
+        let res = get("/api/recordings/1/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_ne!(res.headers().get("content-type").unwrap(),
+                   "video/x-default");
+    }
+
+    #[actix_rt::test]
+    async fn test_builtin_service_filter() {
+        let mut config = (*config_for_test()).clone();
+        config.filters.builtin_service_filter = true;
+        let config = Arc::new(config);
+
+        // Only packets of the service are streamed.
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/services/5/stream").to_request();
+        let mut res = request_with(req, config.clone(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "video/MP2T");
+        let data = response_bytes(&mut res).await;
+        let pids: Vec<u16> = data.chunks(188)
+            .map(|packet| ((packet[1] as u16 & 0x1F) << 8) | packet[2] as u16)
+            .collect();
+        assert_eq!(pids, vec![0x0000, 0x0101, 0x0111]);
+
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/channels/GR/mux/services/5/stream").to_request();
+        let mut res = request_with(req, config.clone(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(response_bytes(&mut res).await.len(), 188 * 3);
+
+        // Not applied to the channel stream.
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/channels/GR/mux/stream").to_request();
+        let mut res = request_with(req, config.clone(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(response_bytes(&mut res).await, mux_for_test());
+
+        // Disabled by default.
+        let mut res = get("/api/services/5/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(response_bytes(&mut res).await, mux_for_test());
+    }
+
+    #[test]
+    fn test_filter_pipeline_builder_fmp4_filter() {
+        let data = mustache::MapBuilder::new().build();
+        let mut builder = FilterPipelineBuilder::new(data);
+        builder.add_fmp4_filter(&FilterConfig {
+            command: "remux".to_string(),
+            process_limits: None,
+        }).unwrap();
+        assert_eq!(builder.build(1).unwrap(),
+                   (vec![("remux".to_string(), None)],
+                    "video/mp4".to_string()));
+
+        // The content type is kept when the filter is disabled.
+        let data = mustache::MapBuilder::new().build();
+        let mut builder = FilterPipelineBuilder::new(data);
+        builder.add_fmp4_filter(&Default::default()).unwrap();
+        assert_eq!(builder.build(1).unwrap(),
+                   (vec![], "video/MP2T".to_string()));
+    }
+
+    #[test]
+    fn test_filter_pipeline_builder_too_many_filters() {
+        let data = mustache::MapBuilder::new().build();
+        let mut builder = FilterPipelineBuilder::new(data);
+        builder.add_decode_filter(&FilterConfig {
+            command: "decode".to_string(),
+            process_limits: None,
+        }).unwrap();
+        builder.add_fmp4_filter(&FilterConfig {
+            command: "remux".to_string(),
+            process_limits: None,
+        }).unwrap();
+        assert_matches!(builder.build(1), Err(Error::TooManyFilters(2, 1)));
     }
 
-    async fn get_with_peer_addr(
-        uri: &str,
-        addr: SocketAddr
-    ) -> actix_web::HttpResponse {
-        let req = actix_web::test::TestRequest::with_uri(uri)
-            .method(actix_web::http::Method::GET)
-            .peer_addr(addr)
-            .to_request();
-        request(req).await
+    #[actix_rt::test]
+    async fn test_get_recording_schedules() {
+        let res = get("/api/recording/schedules").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
     }
 
     #[actix_rt::test]
-    async fn test_get_unknown() {
-        let res = get("/api/unknown").await;
+    async fn test_create_recording_schedule() {
+        let res = post_json("/api/recording/schedules",
+                            serde_json::json!({ "programId": 1 })).await;
+        assert!(res.status() == actix_web::http::StatusCode::CREATED);
+
+        let res = post_json("/api/recording/schedules",
+                            serde_json::json!({ "programId": 0 })).await;
         assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
     }
 
     #[actix_rt::test]
-    async fn test_get_version() {
-        let res = get("/api/version").await;
+    async fn test_get_recording_rules() {
+        let res = get("/api/recording/rules").await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
     }
 
     #[actix_rt::test]
-    async fn test_get_status() {
-        let res = get("/api/status").await;
-        assert!(res.status() == actix_web::http::StatusCode::OK);
+    async fn test_create_recording_rule() {
+        let res = post_json("/api/recording/rules",
+                            serde_json::json!({ "keyword": "news" })).await;
+        assert!(res.status() == actix_web::http::StatusCode::CREATED);
     }
 
     #[actix_rt::test]
-    async fn test_get_channels() {
-        let res = get("/api/channels").await;
+    async fn test_preview_recording_rule() {
+        let res = post_json("/api/recording/rules/preview",
+                            serde_json::json!({ "keyword": "news" })).await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = post_json("/api/recording/rules/preview",
+                            serde_json::json!({})).await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
     }
 
     #[actix_rt::test]
-    async fn test_get_services() {
-        let res = get("/api/services").await;
+    async fn test_update_recording_rule() {
+        let res = put_json("/api/recording/rules/1",
+                           serde_json::json!({ "keyword": "news" })).await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = put_json("/api/recording/rules/0",
+                           serde_json::json!({ "keyword": "news" })).await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
     }
 
     #[actix_rt::test]
-    async fn test_get_service() {
-        let res = get("/api/services/1").await;
-        assert!(res.status() == actix_web::http::StatusCode::OK);
+    async fn test_delete_recording_rule() {
+        let res = delete("/api/recording/rules/1").await;
+        assert!(res.status() == actix_web::http::StatusCode::NO_CONTENT);
 
-        let res = get("/api/services/0").await;
+        let res = delete("/api/recording/rules/0").await;
         assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
     }
 
     #[actix_rt::test]
-    async fn test_get_programs() {
-        let res = get("/api/programs").await;
+    async fn test_get_recordings() {
+        let res = get("/api/recordings").await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
     }
 
     #[actix_rt::test]
-    async fn test_get_program() {
-        let res = get("/api/programs/1").await;
+    async fn test_check_recordings() {
+        let res = post("/api/recordings/check").await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
 
-        let res = get("/api/programs/0").await;
+    #[actix_rt::test]
+    async fn test_get_recording_stream() {
+        let res = get("/api/recordings/1/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/recordings/0/stream").await;
         assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
     }
 
     #[actix_rt::test]
-    async fn test_get_tuners() {
-        let res = get("/api/tuners").await;
+    async fn test_get_epg_status() {
+        let res = get("/api/epg/status").await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
     }
 
     #[actix_rt::test]
-    async fn test_get_channel_stream() {
-        let res = get("/api/channels/GR/ch/stream").await;
+    async fn test_get_metrics() {
+        let res = get("/metrics").await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(),
+                   "text/plain; version=0.0.4");
+    }
 
-        let res = get("/api/channels/GR/0/stream").await;
-        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    #[actix_rt::test]
+    async fn test_run_sync_clocks() {
+        let res = post("/api/jobs/sync-clocks/run").await;
+        assert!(res.status() == actix_web::http::StatusCode::ACCEPTED);
 
-        let res = get("/api/channels/WOWOW/ch/stream").await;
-        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+        let res = post("/api/jobs/sync-clocks/run?channel=27,26").await;
+        assert!(res.status() == actix_web::http::StatusCode::ACCEPTED);
 
-        let res = get("/api/channels/GR/xx/stream").await;
+        let res = post("/api/jobs/sync-clocks/run?channel=0").await;
         assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
 
-        let decode_values = [0, 1];
+    #[actix_rt::test]
+    async fn test_run_update_schedules() {
+        let res = post("/api/jobs/update-schedules/run").await;
+        assert!(res.status() == actix_web::http::StatusCode::ACCEPTED);
+    }
 
-        let valid_pairs =
-            ["GR", "BS", "CS", "SKY"].iter().zip(&decode_values);
-        for (channel_type, decode) in valid_pairs {
-            let res = get(format!("/api/channels/{}/ch/stream?decode={}",
-                                  channel_type, decode).as_str()).await;
-            assert!(res.status() == actix_web::http::StatusCode::OK);
-        }
+    #[actix_rt::test]
+    async fn test_pause_job() {
+        let res = post("/api/jobs/update-schedules/pause").await;
+        assert!(res.status() == actix_web::http::StatusCode::NO_CONTENT);
 
-        for decode in &decode_values {
-            let res = get(format!("/api/channels/WOWOW/ch/stream?decode={}",
-                                  decode).as_str()).await;
-            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
-        }
+        let res = post("/api/jobs/unknown/pause").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
     }
 
     #[actix_rt::test]
-    async fn test_get_channel_service_stream() {
-        let res = get("/api/channels/GR/ch/services/1/stream").await;
-        assert!(res.status() == actix_web::http::StatusCode::OK);
+    async fn test_resume_job() {
+        let res = post("/api/jobs/update-schedules/resume").await;
+        assert!(res.status() == actix_web::http::StatusCode::NO_CONTENT);
 
-        let res = get("/api/channels/GR/0/services/1/stream").await;
+        let res = post("/api/jobs/unknown/resume").await;
         assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
 
-        let res = get("/api/channels/WOWOW/ch/services/1/stream").await;
+    #[actix_rt::test]
+    async fn test_get_session_stats() {
+        let res = get("/api/sessions/0/stats").await;
         assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
 
-        let decode_values = [0, 1];
-
-        let valid_pairs =
-            ["GR", "BS", "CS", "SKY"].iter().zip(&decode_values);
-        for (channel_type, decode) in valid_pairs {
-            let res = get(format!(
-                "/api/channels/{}/ch/services/1/stream?decode={}",
-                channel_type, decode).as_str()).await;
-            assert!(res.status() == actix_web::http::StatusCode::OK);
-        }
-
-        for decode in &decode_values {
-            let res = get(format!(
-                "/api/channels/WOWOW/ch/services/1/stream?decode={}",
-                decode).as_str()).await;
-            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
-        }
+        let handle = stream_stats::register(Duration::from_secs(60));
+        handle.add_chunk(10);
+        let uri = format!("/api/sessions/{}/stats", handle.id());
 
-        for decode in &decode_values {
-            let res = get(format!(
-                "/api/channels/WOWOW/ch/services/2/stream?decode={}",
-                decode).as_str()).await;
-            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
-        }
+        let mut res = get(&uri).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        let stats = response_json(&mut res);
+        assert_eq!(stats["id"], handle.id());
+        assert_eq!(stats["bytes"], 10);
+        assert_eq!(stats["chunks"], 1);
+        assert_eq!(stats["active"], true);
+
+        // Still queryable within the retention period after the stream ends.
+        drop(handle);
+        let mut res = get(&uri).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(response_json(&mut res)["active"], false);
     }
 
     #[actix_rt::test]
-    async fn test_get_service_stream() {
-        let res = get("/api/services/1/stream").await;
+    async fn test_get_iptv_playlist() {
+        let res = get("/api/iptv/playlist").await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
 
-        let res = get("/api/services/0/stream").await;
-        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
-
-        let decode_values = [0, 1];
-
-        for decode in &decode_values {
-            let res = get(format!("/api/services/1/stream?decode={}",
-                                  decode).as_str()).await;
-            assert!(res.status() == actix_web::http::StatusCode::OK);
-        }
-
-        for decode in &decode_values {
-            let res = get(format!("/api/services/0/stream?decode={}",
-                                  decode).as_str()).await;
-            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
-        }
-
-        for decode in &decode_values {
-            let res = get(format!("/api/services/2/stream?decode={}",
-                                  decode).as_str()).await;
-            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
-        }
+    #[actix_rt::test]
+    async fn test_get_docs() {
+        let res = get("/api/docs").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
     }
 
     #[actix_rt::test]
-    async fn test_get_program_stream() {
-        let res = get("/api/programs/100001/stream").await;
+    async fn test_get_debug_channel_psi() {
+        // Disabled by default.
+        let res = get("/api/debug/channels/GR/ch/psi").await;
+        assert!(res.status() == actix_web::http::StatusCode::FORBIDDEN);
+
+        // The output of debug.psi-filter is returned as JSON.
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/debug/channels/GR/ch/psi").to_request();
+        let mut res =
+            request_with(req, debug_config_for_test(), epg_for_test()).await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(response_json(&mut res), serde_json::json!({ "pat": [] }));
 
-        let res = get("/api/programs/0/stream").await;
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/debug/channels/GR/0/psi").to_request();
+        let res =
+            request_with(req, debug_config_for_test(), epg_for_test()).await;
         assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
-
-        let decode_values = [0, 1];
-
-        for decode in &decode_values {
-            let res = get(format!("/api/programs/100001/stream?decode={}",
-                                  decode).as_str()).await;
-            assert!(res.status() == actix_web::http::StatusCode::OK);
-        }
-
-        for decode in &decode_values {
-            let res = get(format!("/api/programs/0/stream?decode={}",
-                                  decode).as_str()).await;
-            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
-        }
-
-        for decode in &decode_values {
-            let res = get(format!("/api/programs/200001/stream?decode={}",
-                                  decode).as_str()).await;
-            assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
-        }
     }
 
     #[actix_rt::test]
-    async fn test_get_iptv_playlist() {
-        let res = get("/api/iptv/playlist").await;
+    async fn test_get_debug_channel_bitrate() {
+        // Disabled by default.
+        let res = get("/api/debug/channels/GR/ch/bitrate").await;
+        assert!(res.status() == actix_web::http::StatusCode::FORBIDDEN);
+
+        // The stream ends before debug.time-limit passes.
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/debug/channels/GR/ts/bitrate").to_request();
+        let mut res =
+            request_with(req, debug_config_for_test(), epg_for_test()).await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
+        let report = response_json(&mut res);
+        assert_eq!(report["totalBytes"], 188);
+        assert_eq!(report["pids"].as_array().unwrap().len(), 1);
+        assert_eq!(report["pids"][0]["pid"], 0x0100);
+        assert_eq!(report["pids"][0]["bytes"], 188);
+
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/debug/channels/GR/busy/bitrate").to_request();
+        let res =
+            request_with(req, debug_config_for_test(), epg_for_test()).await;
+        assert!(
+            res.status() == actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
     }
 
     #[actix_rt::test]
-    async fn test_get_docs() {
-        let res = get("/api/docs").await;
+    async fn test_get_debug_tune_stream() {
+        // Disabled by default.
+        let res = get("/api/debug/tune/GR/27/stream").await;
+        assert!(res.status() == actix_web::http::StatusCode::FORBIDDEN);
+
+        let res = get("/api/debug/tune/GR/27/stream?extra-args=--lnb").await;
+        assert!(res.status() == actix_web::http::StatusCode::FORBIDDEN);
+
+        // The tuner manager mock streams "ch" without querying the EPG.
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/debug/tune/GR/ch/stream").to_request();
+        let res =
+            request_with(req, debug_config_for_test(), epg_for_test()).await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "video/MP2T");
+
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/debug/tune/GR/ch/stream?extra-args=--lnb").to_request();
+        let res =
+            request_with(req, debug_config_for_test(), epg_for_test()).await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let req = actix_web::test::TestRequest::with_uri(
+            "/api/debug/tune/GR/busy/stream").to_request();
+        let res =
+            request_with(req, debug_config_for_test(), epg_for_test()).await;
+        assert!(
+            res.status() == actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
     }
 
     #[actix_rt::test]
@@ -1133,14 +4782,49 @@ mod tests {
     #[actix_rt::test]
     async fn test_do_streaming() {
         let result = do_streaming(
-            futures::stream::empty(), "video/MP2T".to_string(), 1000).await;
+            futures::stream::empty(), "video/MP2T".to_string(), 1000,
+            None).await;
         assert_matches!(result, Err(Error::ProgramNotFound));
 
         let result = do_streaming(
-            futures::stream::pending(), "video/MP2T".to_string(), 1).await;
+            futures::stream::pending(), "video/MP2T".to_string(), 1,
+            None).await;
         assert_matches!(result, Err(Error::StreamingTimedOut));
     }
 
+    #[actix_rt::test]
+    async fn test_relay_chunks() {
+        let stream = futures::stream::iter(vec![
+            Ok(Bytes::from("1")),
+            Ok(Bytes::from("2")),
+            Ok(Bytes::from("3")),
+        ]);
+
+        // The receiver never consumes chunks.
+        let (sender, _receiver) = mpsc::channel(1);
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            relay_chunks(MpegTsStreamId::new(Default::default(), 1),
+                         stream, sender, Some(Duration::from_millis(10)),
+                         None)).await;
+        assert!(result.is_ok());
+
+        let stream = futures::stream::iter(vec![
+            Ok(Bytes::from("1")),
+            Ok(Bytes::from("2")),
+            Ok(Bytes::from("3")),
+        ]);
+        let (sender, _receiver) = mpsc::channel(1);
+
+        // Never disconnected when the idle timeout is disabled.
+        let result = tokio::time::timeout(
+            Duration::from_millis(100),
+            relay_chunks(MpegTsStreamId::new(Default::default(), 1),
+                         stream, sender, None, None)).await;
+        assert!(result.is_err());
+    }
+
     #[actix_rt::test]
     async fn test_filter_setting() {
         async fn do_test(
@@ -1255,6 +4939,33 @@ mod tests {
             assert_eq!(v.post_filters.len(), 1);
             assert_eq!(v.post_filters[0], "b".to_string());
         });
+
+        let mut program = EpgProgram::new((0, 0, 0, 1).into());
+        program.videos.push(EpgVideoInfo::new(1, 0xB3, Some(0x00)));
+        program.audios.push(EpgAudioInfo::new(3, 7, Some(0x10)));
+
+        assert_matches!(do_test("").await, Ok(v) => {
+            assert_eq!(v.video_component_tag(&program), Some(0x00));
+            assert_eq!(v.audio_component_tag(&program), Some(0x10));
+        });
+
+        assert_matches!(do_test("?video-tag=1&audio-tag=17").await, Ok(v) => {
+            assert_eq!(v.video_component_tag(&program), Some(0x01));
+            assert_eq!(v.audio_component_tag(&program), Some(0x11));
+        });
+
+        assert_matches!(do_test("?video-tag=x").await, Err(_));
+
+        assert_matches!(do_test("").await, Ok(v) => {
+            assert_eq!(v.duration, None);
+        });
+
+        assert_matches!(do_test("?duration=30m").await, Ok(v) => {
+            assert_eq!(v.duration, Some(Duration::from_secs(1800)));
+        });
+
+        assert_matches!(do_test("?duration=x").await, Err(_));
+        assert_matches!(do_test("?duration=0s").await, Err(_));
     }
 
     fn config_for_test() -> Arc<Config> {
@@ -1266,28 +4977,145 @@ mod tests {
         config.recorder.track_airtime_command = "true".to_string();
         // "/dev/null" is enough to test
         config.mirakurun.openapi_json = "/dev/null".to_string();
+        // The tuner manager mock cannot stream "busy*" channels
+        config.channels.push(ChannelConfig {
+            name: "busy-fallback".to_string(),
+            channel_type: ChannelType::GR,
+            channel: "busy-fallback".to_string(),
+            extra_args: "".to_string(),
+            services: vec![],
+            excluded_services: vec![],
+            disabled: false,
+            fallback: Some(FallbackChannelConfig {
+                channel_type: ChannelType::GR,
+                channel: "ch".to_string(),
+            }),
+            epg_priority: 0,
+            expected_services: vec![],
+            pre_filters: vec![],
+            post_filters: vec![],
+        });
+        // Default filters of the service with NID=1 and SID=1
+        config.post_filters.insert("default".to_string(), PostFilterConfig {
+            command: "cat".to_string(),
+            content_type: Some("video/x-default".to_string()),
+            process_limits: None,
+        });
+        config.filters.service_default_filters.push(
+            ServiceDefaultFiltersConfig {
+                services: vec![100001.into()],
+                pre_filters: vec![],
+                post_filters: vec!["default".to_string()],
+            });
+
+        Arc::new(config)
+    }
+
+    fn filters_config_for_test() -> Arc<Config> {
+        let mut config = (*config_for_test()).clone();
+        config.filters.runtime_update = true;
+        Arc::new(config)
+    }
 
+    fn debug_config_for_test() -> Arc<Config> {
+        let mut config = (*config_for_test()).clone();
+        config.debug.enabled = true;
+        config.debug.psi_filter.command =
+            r#"echo '{"pat":[]}'"#.to_string();
         Arc::new(config)
     }
 
+    async fn response_bytes(res: &mut actix_web::HttpResponse) -> Vec<u8> {
+        let mut body = res.take_body();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.next().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        data
+    }
+
+    fn response_json(res: &mut actix_web::HttpResponse) -> serde_json::Value {
+        use actix_web::dev::{Body, ResponseBody};
+        match res.take_body() {
+            ResponseBody::Body(Body::Bytes(bytes)) |
+            ResponseBody::Other(Body::Bytes(bytes)) =>
+                serde_json::from_slice(&bytes).unwrap(),
+            _ => panic!("Unexpected body"),
+        }
+    }
+
     fn tuner_manager_for_test() -> Addr<TunerManagerActor> {
         TunerManagerActor::mock(Box::new(|msg, ctx| {
             if let Some(_) = msg.downcast_ref::<QueryTunersMessage>() {
                 Box::<Option<Result<Vec<MirakurunTuner>, Error>>>::new(
                     Some(Ok(Vec::new())))
+            } else if let Some(msg) =
+                msg.downcast_ref::<SetTunerPriorityMessage>() {
+                let result = if msg.name == "tuner" {
+                    Ok(MirakurunTuner {
+                        index: 0,
+                        name: msg.name.clone(),
+                        channel_types: vec![ChannelType::GR],
+                        command: None,
+                        pid: None,
+                        users: Vec::new(),
+                        is_available: true,
+                        is_remote: false,
+                        is_free: true,
+                        is_using: false,
+                        is_fault: false,
+                        priority: msg.priority,
+                        expected_free_at: None,
+                        signal_stats: None,
+                    })
+                } else {
+                    Err(Error::TunerNotFound)
+                };
+                Box::<Option<Result<MirakurunTuner, Error>>>::new(Some(result))
             } else if let Some(msg) = msg.downcast_ref::<StartStreamingMessage>() {
-                if msg.channel.channel == "ch" {
+                if msg.channel.channel.starts_with("busy") {
+                    Box::<Option<Result<MpegTsStream, Error>>>::new(
+                        Some(Err(Error::TunerUnavailable)))
+                } else if msg.channel.channel == "ch" {
                     let (mut tx, stream) = BroadcasterStream::new_for_test();
                     let _ = tx.try_send(Bytes::from("hi"));
                     let result = Ok(MpegTsStream::new(
                         Default::default(), stream, ctx.address().recipient()));
                     Box::<Option<Result<MpegTsStream, Error>>>::new(Some(result))
+                } else if msg.channel.channel == "mux" {
+                    let (mut tx, stream) = BroadcasterStream::new_for_test();
+                    let _ = tx.try_send(Bytes::from(mux_for_test()));
+                    let result = Ok(MpegTsStream::new(
+                        Default::default(), stream, ctx.address().recipient()));
+                    Box::<Option<Result<MpegTsStream, Error>>>::new(Some(result))
+                } else if msg.channel.channel == "ts" {
+                    // A single TS packet of PID 0x0100.
+                    let mut packet = vec![0; 188];
+                    packet[0] = 0x47;
+                    packet[1] = 0x01;
+                    let (mut tx, stream) = BroadcasterStream::new_for_test();
+                    let _ = tx.try_send(Bytes::from(packet));
+                    let result = Ok(MpegTsStream::new(
+                        Default::default(), stream, ctx.address().recipient()));
+                    Box::<Option<Result<MpegTsStream, Error>>>::new(Some(result))
                 } else {
                     let (_, stream) = BroadcasterStream::new_for_test();
                     let result = Ok(MpegTsStream::new(
                         Default::default(), stream, ctx.address().recipient()));
                     Box::<Option<Result<MpegTsStream, Error>>>::new(Some(result))
                 }
+            } else if let Some(msg) =
+                msg.downcast_ref::<CheckTunerAvailabilityMessage>() {
+                let result = if msg.channel.channel == "ch" {
+                    Ok(())
+                } else {
+                    Err(Error::TunerUnavailable)
+                };
+                Box::<Option<Result<(), Error>>>::new(Some(result))
+            } else if let Some(_) =
+                msg.downcast_ref::<FailoverStreamingMessage>() {
+                Box::<Option<Result<MpegTsStream, Error>>>::new(
+                    Some(Err(Error::TunerUnavailable)))
             } else if let Some(_) = msg.downcast_ref::<StopStreamingMessage>() {
                 Box::<Option<()>>::new(Some(()))
             } else {
@@ -1296,8 +5124,56 @@ mod tests {
         })).start()
     }
 
+    // A TS stream containing two services: SID#5 and SID#6.  CRC_32 of each
+    // section is not checked.
+    fn mux_for_test() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend(ts_packet_for_test(0x0000, &[
+            0x00, 0xB0, 17, 0x00, 0x01, 0xC1, 0x00, 0x00,
+            0x00, 0x05, 0xE1, 0x01,  // SID#5 => PMT#0101
+            0x00, 0x06, 0xE1, 0x02,  // SID#6 => PMT#0102
+            0x00, 0x00, 0x00, 0x00,
+        ]));
+        data.extend(ts_packet_for_test(0x0101, &[
+            0x02, 0xB0, 18, 0x00, 0x05, 0xC1, 0x00, 0x00,
+            0xE1, 0x11, 0xF0, 0x00,  // PCR#0111
+            0x02, 0xE1, 0x11, 0xF0, 0x00,  // ES#0111
+            0x00, 0x00, 0x00, 0x00,
+        ]));
+        data.extend(ts_packet_for_test(0x0102, &[
+            0x02, 0xB0, 18, 0x00, 0x06, 0xC1, 0x00, 0x00,
+            0xE1, 0x21, 0xF0, 0x00,  // PCR#0121
+            0x02, 0xE1, 0x21, 0xF0, 0x00,  // ES#0121
+            0x00, 0x00, 0x00, 0x00,
+        ]));
+        data.extend(ts_packet_for_test(0x0111, &[]));
+        data.extend(ts_packet_for_test(0x0121, &[]));
+        data.extend(ts_packet_for_test(0x1FFF, &[]));
+        data
+    }
+
+    // A section starts in the packet if `section` is not empty.
+    fn ts_packet_for_test(pid: u16, section: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0xFF; 188];
+        packet[0] = 0x47;
+        packet[1] = (pid >> 8) as u8 & 0x1F;
+        packet[2] = pid as u8;
+        packet[3] = 0x10;  // payload only
+        if !section.is_empty() {
+            packet[1] |= 0x40;  // payload_unit_start_indicator
+            packet[4] = 0;  // pointer_field
+            packet[5..(5 + section.len())].copy_from_slice(section);
+        }
+        packet
+    }
+
     fn epg_for_test() -> Addr<EpgActor> {
-        EpgActor::mock(Box::new(|msg, _| {
+        epg_for_test_with_ready(true)
+    }
+
+    // `ready` is returned for QueryEpgReadyMessage.
+    fn epg_for_test_with_ready(ready: bool) -> Addr<EpgActor> {
+        EpgActor::mock(Box::new(move |msg, _| {
             if let Some(_) = msg.downcast_ref::<QueryChannelsMessage>() {
                 Box::<Option<Result<Vec<MirakurunChannel>, Error>>>::new(
                     Some(Ok(Vec::new())))
@@ -1317,48 +5193,114 @@ mod tests {
                 Box::<Option<Result<EpgChannel, Error>>>::new(Some(result))
             } else if let Some(_) = msg.downcast_ref::<QueryServicesMessage>() {
                 Box::<Option<Result<Vec<EpgService>, Error>>>::new(
+                    Some(Ok(vec![EpgService {
+                        nid: 1.into(),
+                        tsid: 0.into(),
+                        sid: 1.into(),
+                        service_type: 1,
+                        logo_id: 0,
+                        remote_control_key_id: 0,
+                        name: "test".to_string(),
+                        network_name: None,
+                        channel: EpgChannel {
+                            name: "test".to_string(),
+                            channel_type: ChannelType::GR,
+                            channel: "ch".to_string(),
+                            extra_args: "".to_string(),
+                            services: Vec::new(),
+                            excluded_services: Vec::new(),
+                        },
+                    }])))
+            } else if let Some(_) = msg.downcast_ref::<QueryNetworksMessage>() {
+                Box::<Option<Result<Vec<EpgNetwork>, Error>>>::new(
                     Some(Ok(Vec::new())))
             } else if let Some(msg) = msg.downcast_ref::<QueryServiceMessage>() {
-                let result = match msg {
-                    QueryServiceMessage::ByNidSid { nid, sid } => {
-                        if sid.value() == 0 {
-                            Err(Error::ServiceNotFound)
-                        } else {
-                            let channel = if sid.value() == 1 {
-                                "ch"
-                            } else {
-                                ""
-                            };
-                            Ok(EpgService {
-                                nid: *nid,
-                                tsid: 0.into(),
-                                sid: *sid,
-                                service_type: 1,
-                                logo_id: 0,
-                                remote_control_key_id: 0,
-                                name: "test".to_string(),
-                                channel: EpgChannel {
-                                    name: "test".to_string(),
-                                    channel_type: ChannelType::GR,
-                                    channel: channel.to_string(),
-                                    extra_args: "".to_string(),
-                                    services: Vec::new(),
-                                    excluded_services: Vec::new(),
-                                },
-                            })
-                        }
-                    }
+                let (nid, tsid, sid) = match msg {
+                    QueryServiceMessage::ByNidSid { nid, sid } =>
+                        (*nid, 0.into(), *sid),
+                    QueryServiceMessage::ByTriple { triple } =>
+                        (triple.nid(), triple.tsid(), triple.sid()),
+                };
+                let result = if sid.value() == 0 {
+                    Err(Error::ServiceNotFound)
+                } else if sid.value() == 4 &&
+                    matches!(msg, QueryServiceMessage::ByNidSid { .. }) {
+                    // Multiple services have the same NID and SID.
+                    Err(Error::BadRequest("Multiple services".to_string()))
+                } else {
+                    let channel = match sid.value() {
+                        1 => "ch",
+                        3 => "busy-fallback",
+                        5 => "mux",
+                        _ => "",
+                    };
+                    Ok(EpgService {
+                        nid,
+                        tsid,
+                        sid,
+                        service_type: 1,
+                        logo_id: 0,
+                        remote_control_key_id: 0,
+                        name: "test".to_string(),
+                        network_name: None,
+                        channel: EpgChannel {
+                            name: "test".to_string(),
+                            channel_type: ChannelType::GR,
+                            channel: channel.to_string(),
+                            extra_args: "".to_string(),
+                            services: Vec::new(),
+                            excluded_services: Vec::new(),
+                        },
+                    })
                 };
                 Box::<Option<Result<EpgService, Error>>>::new(Some(result))
             } else if let Some(msg) = msg.downcast_ref::<QueryClockMessage>() {
                 let result = match msg.triple.sid().value() {
                     0 => Err(Error::ClockNotSynced),
-                    _ => Ok(Clock { pcr: 0, time: 0 }),
+                    _ => Ok(Clock { pcr: 0, time: 0, synced_at: None }),
                 };
                 Box::<Option<Result<Clock, Error>>>::new(Some(result))
+            } else if let Some(_) =
+                msg.downcast_ref::<QueryClockOffsetsMessage>() {
+                Box::<Option<Result<Vec<ClockOffset>, Error>>>::new(
+                    Some(Ok(vec![ClockOffset {
+                        nid: 1.into(),
+                        synced_at: 0,
+                        offset: 100,
+                    }])))
             } else if let Some(_) = msg.downcast_ref::<QueryProgramsMessage>() {
                 Box::<Option<Result<Vec<EpgProgram>, Error>>>::new(
                     Some(Ok(Vec::new())))
+            } else if let Some(_) =
+                msg.downcast_ref::<QueryProgramsAtMessage>() {
+                Box::<Option<Result<Vec<EpgProgram>, Error>>>::new(
+                    Some(Ok(Vec::new())))
+            } else if let Some(_) =
+                msg.downcast_ref::<SearchProgramsMessage>() {
+                Box::<Option<Result<Vec<EpgProgram>, Error>>>::new(
+                    Some(Ok(Vec::new())))
+            } else if let Some(msg) =
+                msg.downcast_ref::<QueryChangedProgramsMessage>() {
+                let result: Result<(Vec<EpgProgram>, Vec<EventQuad>), Error> =
+                    if msg.since < Jst.ymd(2020, 1, 1).and_hms(0, 0, 0) {
+                        Err(Error::ChangesNotTracked)
+                    } else {
+                        let removed = vec![EventQuad::from(
+                            (ServiceTriple::from((0, 0, 1)),
+                             EventId::from(1)))];
+                        Ok((Vec::new(), removed))
+                    };
+                Box::new(Some(result))
+            } else if let Some(msg) =
+                msg.downcast_ref::<QueryProgramsByServicesMessage>() {
+                let results = msg.services.iter()
+                    .map(|id| (*id, Vec::new()))
+                    .collect();
+                Box::<Option<Result<Vec<(MirakurunServiceId, Vec<EpgProgram>)>,
+                                    Error>>>::new(Some(Ok(results)))
+            } else if let Some(_) = msg.downcast_ref::<QueryGuideMessage>() {
+                Box::<Option<Result<Vec<(EpgService, Vec<EpgProgram>)>,
+                                    Error>>>::new(Some(Ok(Vec::new())))
             } else if let Some(msg) = msg.downcast_ref::<QueryProgramMessage>() {
                 let result = match msg {
                     QueryProgramMessage::ByNidSidEid { nid, sid, eid } => {
@@ -1371,6 +5313,28 @@ mod tests {
                     }
                 };
                 Box::<Option<Result<EpgProgram, Error>>>::new(Some(result))
+            } else if let Some(msg) =
+                msg.downcast_ref::<QueryNextProgramMessage>() {
+                let result = match msg.rule.keyword {
+                    Some(ref keyword) if keyword == "none" => None,
+                    _ => Some(EpgProgram::new((0, 0, 0, 1).into())),
+                };
+                Box::<Option<Result<Option<EpgProgram>, Error>>>::new(
+                    Some(Ok(result)))
+            } else if let Some(_) =
+                msg.downcast_ref::<QueryOnAirProgramsMessage>() {
+                Box::<Option<Result<Vec<EpgProgram>, Error>>>::new(
+                    Some(Ok(Vec::new())))
+            } else if let Some(_) = msg.downcast_ref::<QueryEpgReadyMessage>() {
+                Box::<Option<Result<bool, Error>>>::new(Some(Ok(ready)))
+            } else if let Some(_) = msg.downcast_ref::<QueryEpgStatusMessage>() {
+                Box::<Option<Result<EpgStatus, Error>>>::new(
+                    Some(Ok(EpgStatus {
+                        ready: true,
+                        eit_collection: Vec::new(),
+                        service_scan: Vec::new(),
+                        invalid_sections: Vec::new(),
+                    })))
             } else if let Some(_) = msg.downcast_ref::<RemoveAirtimeMessage>() {
                 Box::<Option<()>>::new(Some(()))
             } else {
@@ -1378,4 +5342,117 @@ mod tests {
             }
         })).start()
     }
+    fn recording_manager_for_test() -> Addr<RecordingManagerActor> {
+        RecordingManagerActor::mock(Box::new(|msg, _| {
+            if let Some(_) = msg.downcast_ref::<QueryRecordingSchedulesMessage>() {
+                Box::<Option<Result<Vec<RecordingSchedule>, Error>>>::new(
+                    Some(Ok(Vec::new())))
+            } else if let Some(msg) =
+                msg.downcast_ref::<AddRecordingScheduleMessage>() {
+                let result = Ok(RecordingSchedule {
+                    program_id: msg.program.quad.into(),
+                    start_at: msg.program.start_at,
+                    duration: msg.program.duration,
+                    state: RecordingScheduleState::Scheduled,
+                    rule_id: None,
+                    conflict: None,
+                    max_retries: msg.max_retries,
+                    retries: 0,
+                });
+                Box::<Option<Result<RecordingSchedule, Error>>>::new(
+                    Some(result))
+            } else if let Some(_) =
+                msg.downcast_ref::<QueryRecordingRulesMessage>() {
+                Box::<Option<Result<Vec<RecordingRule>, Error>>>::new(
+                    Some(Ok(Vec::new())))
+            } else if let Some(msg) =
+                msg.downcast_ref::<AddRecordingRuleMessage>() {
+                let result = Ok(RecordingRule {
+                    id: 1,
+                    spec: msg.spec.clone(),
+                });
+                Box::<Option<Result<RecordingRule, Error>>>::new(
+                    Some(result))
+            } else if let Some(msg) =
+                msg.downcast_ref::<PreviewRecordingRuleMessage>() {
+                let result = if msg.spec.keyword.is_none() {
+                    Err(Error::BadRequest("no condition".to_string()))
+                } else {
+                    Ok(Vec::<RecordingRulePreview>::new())
+                };
+                Box::<Option<Result<Vec<RecordingRulePreview>, Error>>>::new(
+                    Some(result))
+            } else if let Some(msg) =
+                msg.downcast_ref::<UpdateRecordingRuleMessage>() {
+                let result = if msg.id == 0 {
+                    Err(Error::RecordingRuleNotFound)
+                } else {
+                    Ok(RecordingRule { id: msg.id, spec: msg.spec.clone() })
+                };
+                Box::<Option<Result<RecordingRule, Error>>>::new(
+                    Some(result))
+            } else if let Some(msg) =
+                msg.downcast_ref::<RemoveRecordingRuleMessage>() {
+                let result = if msg.id == 0 {
+                    Err(Error::RecordingRuleNotFound)
+                } else {
+                    Ok(())
+                };
+                Box::<Option<Result<(), Error>>>::new(Some(result))
+            } else if let Some(_) = msg.downcast_ref::<QueryRecordingsMessage>() {
+                Box::<Option<Result<Vec<Recording>, Error>>>::new(
+                    Some(Ok(Vec::new())))
+            } else if let Some(_) = msg.downcast_ref::<CheckRecordingsMessage>() {
+                Box::<Option<Result<Vec<Recording>, Error>>>::new(
+                    Some(Ok(Vec::new())))
+            } else if let Some(msg) = msg.downcast_ref::<QueryRecordingMessage>() {
+                let result = if msg.program_id.value() == 0 {
+                    Err(Error::RecordingNotFound)
+                } else {
+                    Ok(Recording {
+                        program_id: msg.program_id,
+                        // "/dev/null" is enough to test
+                        path: "/dev/null".into(),
+                        index_path: None,
+                        start_at: Jst::now(),
+                        duration: chrono::Duration::minutes(30),
+                        size: 0,
+                    })
+                };
+                Box::<Option<Result<Recording, Error>>>::new(Some(result))
+            } else {
+                unimplemented!();
+            }
+        })).start()
+    }
+
+    fn job_manager_for_test() -> Addr<JobManagerActor> {
+        JobManagerActor::mock(Box::new(|msg, _| {
+            if let Some(msg) = msg.downcast_ref::<PauseJobMessage>() {
+                let result = match msg.name.as_str() {
+                    "unknown" => Err(Error::JobNotFound),
+                    _ => Ok(()),
+                };
+                Box::<Option<Result<(), Error>>>::new(Some(result))
+            } else if let Some(msg) = msg.downcast_ref::<ResumeJobMessage>() {
+                let result = match msg.name.as_str() {
+                    "unknown" => Err(Error::JobNotFound),
+                    _ => Ok(()),
+                };
+                Box::<Option<Result<(), Error>>>::new(Some(result))
+            } else if let Some(msg) = msg.downcast_ref::<SyncClocksMessage>() {
+                let result = match msg.channels {
+                    Some(ref channels) if channels.contains(&"0".to_string()) =>
+                        Err(Error::ChannelNotFound),
+                    _ => Ok(()),
+                };
+                Box::<Option<Result<(), Error>>>::new(Some(result))
+            } else if let Some(_) =
+                msg.downcast_ref::<InvokeUpdateSchedulesMessage>() {
+                Box::<Option<()>>::new(Some(()))
+            } else {
+                unimplemented!();
+            }
+        })).start()
+    }
 }
@@ -0,0 +1,183 @@
+use std::io;
+
+use actix09::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web_actors::ws;
+use bytes::Bytes;
+use tokio::stream::Stream;
+
+// WsRelay relays chunks of TS packets to a WebSocket client as binary frames.
+//
+// Messages from the client are handled only for control frames, and data
+// frames are ignored.  The relay stops when the client sends a close frame or
+// closes the connection.  The upstream is dropped together with the relay so
+// that the tuner is released.
+//
+// The context of the relay is polled only when the response body is polled.
+// So, the relay never reads chunks much faster than the client receives them.
+pub struct WsRelay<S> {
+    upstream: Option<S>,
+}
+
+impl<S> WsRelay<S> {
+    pub fn new(upstream: S) -> Self {
+        WsRelay { upstream: Some(upstream) }
+    }
+}
+
+impl<S> Actor for WsRelay<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin + 'static,
+{
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(upstream) = self.upstream.take() {
+            ctx.add_stream(upstream);
+        }
+    }
+}
+
+// Chunks from the upstream.
+impl<S> StreamHandler<io::Result<Bytes>> for WsRelay<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin + 'static,
+{
+    fn handle(&mut self, chunk: io::Result<Bytes>, ctx: &mut Self::Context) {
+        match chunk {
+            Ok(chunk) => ctx.binary(chunk),
+            Err(err) => {
+                log::error!("Stopped relaying: {}", err);
+                ctx.close(Some(ws::CloseCode::Error.into()));
+                ctx.stop();
+            }
+        }
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseCode::Normal.into()));
+        ctx.stop();
+    }
+}
+
+// Messages from the client.
+impl<S> StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsRelay<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin + 'static,
+{
+    fn handle(
+        &mut self,
+        msg: Result<ws::Message, ws::ProtocolError>,
+        ctx: &mut Self::Context,
+    ) {
+        match msg {
+            Ok(ws::Message::Ping(data)) => ctx.pong(&data),
+            Ok(ws::Message::Close(_)) => {
+                log::debug!("Closed by client");
+                ctx.close(Some(ws::CloseCode::Normal.into()));
+                ctx.stop();
+            }
+            Ok(_) => (),
+            Err(err) => {
+                log::error!("Broken frame from client: {}", err);
+                ctx.close(Some(ws::CloseCode::Protocol.into()));
+                ctx.stop();
+            }
+        }
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        log::debug!("Disconnected by client");
+        ctx.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_http::ws::{OpCode, Parser};
+    use bytes::BytesMut;
+    use futures::stream::{self, StreamExt};
+
+    type PayloadItem = Result<Bytes, actix_http::error::PayloadError>;
+
+    #[actix_rt::test]
+    async fn test_relay_chunks() {
+        let upstream = stream::iter(vec![
+            Ok(Bytes::from("ts")),
+        ]);
+        let frames = relay(upstream, create_pending_payload()).await;
+        assert_eq!(frames, vec![
+            0x82, 0x02, b't', b's',
+            // Normal closure (1000).
+            0x88, 0x02, 0x03, 0xE8,
+        ]);
+    }
+
+    #[actix_rt::test]
+    async fn test_relay_upstream_error() {
+        let upstream = stream::iter(vec![
+            Err(io::Error::new(io::ErrorKind::Other, "error")),
+        ]);
+        let frames = relay(upstream, create_pending_payload()).await;
+        assert_eq!(frames, vec![
+            // Internal error (1011).
+            0x88, 0x02, 0x03, 0xF3,
+        ]);
+    }
+
+    #[actix_rt::test]
+    async fn test_relay_closed_by_client() {
+        let frames = relay(
+            stream::pending(),
+            create_payload(OpCode::Close, Bytes::new())).await;
+        assert_eq!(frames, vec![
+            0x88, 0x02, 0x03, 0xE8,
+        ]);
+    }
+
+    #[actix_rt::test]
+    async fn test_relay_ping() {
+        let frames = relay(
+            stream::pending(),
+            create_payload(OpCode::Ping, Bytes::from("p"))).await;
+        // The connection has been closed after the ping.
+        assert_eq!(frames, vec![
+            0x8A, 0x01, b'p',
+        ]);
+    }
+
+    #[actix_rt::test]
+    async fn test_relay_disconnected() {
+        let frames = relay(
+            stream::pending(),
+            stream::iter(Vec::<PayloadItem>::new())).await;
+        assert!(frames.is_empty());
+    }
+
+    // Frames may be merged into a single chunk.
+    async fn relay<S, P>(upstream: S, payload: P) -> Vec<u8>
+    where
+        S: Stream<Item = io::Result<Bytes>> + Unpin + 'static,
+        P: Stream<Item = PayloadItem> + 'static,
+    {
+        ws::WebsocketContext::create(WsRelay::new(upstream), payload)
+            .map(|chunk| chunk.unwrap())
+            .collect::<Vec<Bytes>>()
+            .await
+            .concat()
+    }
+
+    fn create_pending_payload() -> stream::Pending<PayloadItem> {
+        stream::pending()
+    }
+
+    fn create_payload(
+        op: OpCode,
+        data: Bytes,
+    ) -> stream::Iter<std::vec::IntoIter<PayloadItem>> {
+        // Frames from a client must be masked.
+        let mut frame = BytesMut::new();
+        Parser::write_message(&mut frame, data, op, true, true);
+        stream::iter(vec![Ok(frame.freeze())])
+    }
+}
@@ -0,0 +1,158 @@
+use chrono::DateTime;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::datetime_ext::*;
+use crate::error::Error;
+
+// A minimal XMLTV parser which extracts only properties of programmes used in
+// EpgProgram.
+//
+// See http://wiki.xmltv.org/index.php/XMLTVFormat for details of the format.
+pub fn parse(xml: &str) -> Result<Vec<XmltvProgramme>, Error> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut programmes = Vec::new();
+    let mut programme: Option<XmltvProgramme> = None;
+    let mut text: Option<String> = None;
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(ref e) if e.name() == b"programme" => {
+                programme = XmltvProgramme::new(&reader, e)?;
+            }
+            Event::Text(ref e) => {
+                text = Some(e.unescape_and_decode(&reader)?);
+            }
+            Event::End(ref e) => {
+                match (e.name(), programme.as_mut()) {
+                    (b"programme", Some(_)) => {
+                        programmes.push(programme.take().unwrap());
+                    }
+                    (b"title", Some(programme)) => {
+                        if programme.title.is_none() {
+                            programme.title = text.take();
+                        }
+                    }
+                    (b"desc", Some(programme)) => {
+                        if programme.desc.is_none() {
+                            programme.desc = text.take();
+                        }
+                    }
+                    _ => (),
+                }
+                text = None;
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    Ok(programmes)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct XmltvProgramme {
+    pub channel: String,
+    pub start: DateTime<Jst>,
+    pub stop: DateTime<Jst>,
+    pub title: Option<String>,
+    pub desc: Option<String>,
+}
+
+impl XmltvProgramme {
+    // Returns `None` if the programme doesn't have required attributes.
+    fn new(
+        reader: &Reader<&[u8]>,
+        e: &BytesStart,
+    ) -> Result<Option<Self>, Error> {
+        let mut channel = None;
+        let mut start = None;
+        let mut stop = None;
+        for attr in e.attributes() {
+            let attr = attr?;
+            let value = attr.unescape_and_decode_value(reader)?;
+            match attr.key {
+                b"channel" => channel = Some(value),
+                b"start" => start = Self::parse_datetime(&value),
+                b"stop" => stop = Self::parse_datetime(&value),
+                _ => (),
+            }
+        }
+        match (channel, start, stop) {
+            (Some(channel), Some(start), Some(stop)) => {
+                Ok(Some(XmltvProgramme {
+                    channel, start, stop, title: None, desc: None,
+                }))
+            }
+            _ => {
+                log::warn!("Skip a programme without channel, start or stop");
+                Ok(None)
+            }
+        }
+    }
+
+    fn parse_datetime(s: &str) -> Option<DateTime<Jst>> {
+        // The timezone may be omitted, but mirakc requires it in order to
+        // avoid ambiguity.
+        DateTime::parse_from_str(s, "%Y%m%d%H%M%S %z")
+            .map(|dt| dt.with_timezone(&Jst))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse() {
+        let programmes = parse(r#"
+            <?xml version="1.0" encoding="UTF-8"?>
+            <tv>
+              <channel id="ch1"><display-name>CH1</display-name></channel>
+              <programme start="20200101120000 +0900"
+                         stop="20200101123000 +0900" channel="ch1">
+                <title lang="ja">Title &amp; 1</title>
+                <desc lang="ja">Desc 1</desc>
+                <category>news</category>
+              </programme>
+              <programme start="20200101033000 +0000"
+                         stop="20200101040000 +0000" channel="ch1">
+                <title>Title 2</title>
+              </programme>
+              <programme start="20200101130000 +0900" channel="ch1">
+                <title>No stop</title>
+              </programme>
+            </tv>
+        "#).unwrap();
+
+        assert_eq!(programmes, vec![
+            XmltvProgramme {
+                channel: "ch1".to_string(),
+                start: Jst.ymd(2020, 1, 1).and_hms(12, 0, 0),
+                stop: Jst.ymd(2020, 1, 1).and_hms(12, 30, 0),
+                title: Some("Title & 1".to_string()),
+                desc: Some("Desc 1".to_string()),
+            },
+            XmltvProgramme {
+                channel: "ch1".to_string(),
+                start: Jst.ymd(2020, 1, 1).and_hms(12, 30, 0),
+                stop: Jst.ymd(2020, 1, 1).and_hms(13, 0, 0),
+                title: Some("Title 2".to_string()),
+                desc: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_datetime() {
+        assert_eq!(XmltvProgramme::parse_datetime("20200101120000 +0900"),
+                   Some(Jst.ymd(2020, 1, 1).and_hms(12, 0, 0)));
+        assert_eq!(XmltvProgramme::parse_datetime("20200101120000"), None);
+    }
+}